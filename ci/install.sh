@@ -24,6 +24,8 @@ install_javascript_stuff() {
 main() {
     install_javascript_stuff
 
+    cargo install cbindgen --version 0.15.0
+
     export PATH="$PATH:$HOME/bin"
 }
 
@@ -18,6 +18,14 @@ main() {
     cargo clippy --workspace -- -D warnings
     cargo build --workspace --verbose --all-targets
     cargo test --workspace
+
+    # The checked-in header of the C API must match its Rust code.
+    make c_header
+    git diff --exit-code point_viewer_c/include/point_viewer.h
+
+    # The decoding and query code of the root crate needs to stay usable from the web viewer.
+    rustup target add wasm32-unknown-unknown
+    cargo check -p point_viewer --lib --no-default-features --target wasm32-unknown-unknown
 }
 
 main
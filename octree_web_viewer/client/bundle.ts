@@ -0,0 +1,142 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+'use strict';
+
+import * as THREE from 'three';
+
+// A node in the manifest of a bundle, see 'bundle.rs' in the server.
+interface BundleNode {
+    id: string;
+    min: number[];
+    edge_length: number;
+    num_points: number;
+    offset: number;
+    length: number;
+}
+
+// The manifest that 'octree_bundle.js' of an exported bundle sets.
+export interface BundleManifest {
+    octree_id: string;
+    view_settings: any;
+    // The container file, or null if 'octree_data.js' embeds it.
+    data: string | null;
+    // Parents come before their children.
+    nodes: BundleNode[];
+}
+
+// Returns the manifest if the page is an exported bundle instead of being served by the server.
+export function bundleManifest(): BundleManifest | undefined {
+    return (window as any).OCTREE_BUNDLE;
+}
+
+function clamp(value: number, min: number, max: number): number {
+    return Math.min(Math.max(value, min), max);
+}
+
+// An exported octree, which the client reads without a server.
+export class Bundle {
+    public view: DataView;
+    private nodes: { [id: string]: BundleNode } = {};
+
+    constructor(public manifest: BundleManifest, public data: ArrayBuffer) {
+        this.view = new DataView(data);
+        for (const node of manifest.nodes) {
+            this.nodes[node.id] = node;
+        }
+    }
+
+    public static load(manifest: BundleManifest): Promise<Bundle> {
+        const embedded: string | undefined = (window as any).OCTREE_BUNDLE_DATA;
+        if (embedded !== undefined) {
+            const binary = atob(embedded);
+            const bytes = new Uint8Array(binary.length);
+            for (let i = 0; i < binary.length; i++) {
+                bytes[i] = binary.charCodeAt(i);
+            }
+            return Promise.resolve(new Bundle(manifest, bytes.buffer));
+        }
+        return window
+            .fetch(manifest.data)
+            .then((response) => response.arrayBuffer())
+            .then((data) => new Bundle(manifest, data));
+    }
+
+    // The offset of the data of a node in 'data', or undefined if the node has no points.
+    public offset(nodeId: string): number | undefined {
+        const node = this.nodes[nodeId];
+        return node !== undefined && node.length > 0 ? node.offset : undefined;
+    }
+
+    // The nodes in view with their screen-space error in pixels as priority, the largest first.
+    // This is the selection of '/visible_nodes', see 'ScreenSpaceError' in the Rust crate: nodes
+    // with a smaller error than 'pixelThreshold' are left out together with their children.
+    public visibleNodes(
+        matrix: THREE.Matrix4,
+        width: number,
+        height: number,
+        pixelThreshold: number
+    ): { id: string; priority: number }[] {
+        const frustum = new THREE.Frustum().setFromProjectionMatrix(matrix);
+        const reached = new Set<string>();
+        const visible = [];
+        for (const node of this.manifest.nodes) {
+            const parentId = node.id.length > 1 ? node.id.slice(0, -1) : undefined;
+            if (parentId !== undefined && !reached.has(parentId)) {
+                continue;
+            }
+            const min = new THREE.Vector3().fromArray(node.min);
+            const box = new THREE.Box3(
+                min,
+                min.clone().addScalar(node.edge_length)
+            );
+            if (!frustum.intersectsBox(box)) {
+                continue;
+            }
+            const error = this.screenSpaceError(box, matrix, width, height);
+            if (error < pixelThreshold) {
+                continue;
+            }
+            reached.add(node.id);
+            if (node.num_points > 0) {
+                visible.push({ id: node.id, priority: error });
+            }
+        }
+        return visible.sort((a, b) => b.priority - a.priority);
+    }
+
+    // Projects the corners of 'box' and returns the square root of the area of their bounding
+    // rectangle in pixels, clipped to the view.
+    private screenSpaceError(
+        box: THREE.Box3,
+        matrix: THREE.Matrix4,
+        width: number,
+        height: number
+    ): number {
+        const rect = new THREE.Box3();
+        for (let corner = 0; corner < 8; corner++) {
+            const p = new THREE.Vector3(
+                corner & 1 ? box.max.x : box.min.x,
+                corner & 2 ? box.max.y : box.min.y,
+                corner & 4 ? box.max.z : box.min.z
+            ).applyMatrix4(matrix);
+            rect.expandByPoint(
+                new THREE.Vector3(clamp(p.x, -1, 1), clamp(p.y, -1, 1), clamp(p.z, 0, 1))
+            );
+        }
+        const size = rect.getSize(new THREE.Vector3());
+        // Clip space spans 2 units in x and y.
+        return Math.sqrt(size.x * size.y * (width / 2) * (height / 2));
+    }
+}
@@ -35,6 +35,9 @@ export class FirstPersonController {
   private thetaDelta: number;
   private phiDelta: number;
 
+  // The axis that points up in the point cloud, which the camera turns around.
+  public up = new THREE.Vector3(0, 0, 1);
+
   constructor(private object: THREE.Object3D, private domElement: Element) {
     this.moveSpeed = 0.2;
     this.mouseState = MouseState.NONE;
@@ -103,7 +106,7 @@ export class FirstPersonController {
 
       this.object.updateMatrix();
 
-      let rot = new THREE.Matrix4().makeRotationZ(this.thetaDelta);
+      let rot = new THREE.Matrix4().makeRotationAxis(this.up, this.thetaDelta);
       let res = new THREE.Matrix4().multiplyMatrices(rot, this.object.matrix);
       this.object.quaternion.setFromRotationMatrix(res);
 
@@ -16,23 +16,62 @@
 
 import * as THREE from 'three';
 import { GUI } from 'dat.gui';
+import { Bundle, bundleManifest } from './bundle';
 import { FirstPersonController } from './control';
 import { OctreeViewer } from './octree_viewer';
+import { Minimap } from './minimap';
+import { loadOverlays } from './overlays';
+import { SessionLog } from './session_log';
+import { XrController, XR_POINT_BUDGET } from './xr';
+import { VRButton } from 'three/examples/jsm/webxr/VRButton';
+
+// Time that every frame of a capture over time is shown during playback.
+const FRAME_PLAYBACK_MS = 2000;
+
+// The settings that the viewer starts with, as returned by '/view_settings'.
+interface ViewSettings {
+    camera: { translation: number[]; rotation: number[] } | null;
+    up_axis: string;
+    color_mode: string;
+    point_budget: number | null;
+}
 
 class App {
     private camera: THREE.PerspectiveCamera;
+    // In WebXR sessions the headset controls the camera pose, so we move the dolly instead.
+    private dolly: THREE.Group;
+    private xrController: XrController;
+    private vrButton: HTMLElement;
     private scene: THREE.Scene;
     private controller: FirstPersonController;
     private viewer: OctreeViewer;
+    // The overview in the corner of the page, which needs the server, see 'addMinimap'.
+    private minimap: Minimap | undefined;
     private renderer: THREE.WebGLRenderer;
+    // The point budget outside of WebXR sessions, which the view settings can set.
+    private defaultPointBudget: number = Infinity;
+    private colorBySourceControl: dat.GUIController;
     private lastFrustumUpdateTime: number;
     private lastMoveTime: number;
+    // Playback of captures over time, see 'addTimeControls'.
+    private frameTimestamps: number[] = [];
+    private frameControl: dat.GUIController;
+    private timestampControl: dat.GUIController;
+    private lastFrameTime: number = 0;
+    public playing: boolean = false;
+    public timestamp: string = 'all';
     private needsRender: boolean;
     private octreeIdControl: dat.GUIController;
     private gui: dat.GUI;
     private guiRenderControls: dat.GUI;
     public octreeId: string;  // octree identifier
     private renderArea: HTMLElement;
+    // The exported octree if the page is a bundle, see 'export_web_bundle'.
+    private bundle: Bundle | undefined;
+    // Outlives the octrees that are viewed in the session, see 'addSessionControls'.
+    private sessionLog: SessionLog;
+    // Where the camera was when its last move was logged.
+    private loggedCameraPosition = new THREE.Vector3();
 
     private fetchDefaultOctreeId(): Promise<string> {
         const request = new Request(
@@ -53,7 +92,7 @@ class App {
     private initOctreeViewer(octreeId: string) {
         this.viewer = new OctreeViewer(this.scene, () => {
             this.needsRender = true;
-        }, octreeId);
+        }, octreeId, this.bundle);
     }
 
     private addControls() {
@@ -63,29 +102,231 @@ class App {
             .name('Point size')
             .onChange(() => {
                 this.needsRender = true;
-            });
+            })
+            .onFinishChange(() => this.sessionLog.feature('point_size'));
         this.guiRenderControls
             .add(this.viewer.material.uniforms['alpha'], 'value', 0, 1)
             .name('Transparency')
             .onChange(() => {
                 this.viewer.alphaChanged();
                 this.needsRender = true;
-            });
+            })
+            .onFinishChange(() => this.sessionLog.feature('transparency'));
         this.guiRenderControls
             .add(this.viewer.material.uniforms['gamma'], 'value')
             .name('Gamma')
             .onChange(() => {
                 this.needsRender = true;
-            });
+            })
+            .onFinishChange(() => this.sessionLog.feature('gamma'));
+        this.guiRenderControls
+            .add(this.viewer, 'pixelThreshold', 0.5, 50)
+            .name('Detail threshold (px)')
+            .onChange(() => {
+                // Force a reload of the visible nodes.
+                this.lastFrustumUpdateTime = 0;
+                this.needsRender = true;
+            })
+            .onFinishChange(() => this.sessionLog.feature('pixel_threshold'));
         this.guiRenderControls
-            .add(this.viewer, 'maxLevelToDisplay', 0, 7)
-            .name('Moving details')
-            .step(1)
+            .add(this.viewer, 'movingPixelThreshold', 0, 1000)
+            .name('Moving threshold (px)')
             .onChange(() => {
                 this.needsRender = true;
+            })
+            .onFinishChange(() => this.sessionLog.feature('moving_pixel_threshold'));
+        // Bundles only have the colors of the points and no frames.
+        if (this.bundle !== undefined) {
+            return;
+        }
+        this.colorBySourceControl = this.guiRenderControls
+            .add(this.viewer, 'colorBySource')
+            .name('Color by source')
+            .onChange(() => {
+                this.sessionLog.feature('color_by_source');
+                this.viewer.reloadNodes();
+                // Force a reload of the visible nodes.
+                this.lastFrustumUpdateTime = 0;
+                this.needsRender = true;
+            });
+        this.addDeviationControls();
+        this.addTimeControls();
+        this.addOverlays();
+    }
+
+    // Draws the vector overlays of the server with a checkbox per layer.
+    private addOverlays() {
+        const octreeId = this.octreeId;
+        loadOverlays().then((layers) => {
+            // The octree might have been switched while waiting.
+            if (layers.length === 0 || octreeId !== this.octreeId) {
+                return;
+            }
+            const overlayControls = this.guiRenderControls.addFolder('Overlays');
+            for (const layer of layers) {
+                this.scene.add(layer);
+                overlayControls
+                    .add(layer, 'visible')
+                    .name(layer.name)
+                    .onChange(() => {
+                        this.sessionLog.feature('overlays');
+                        this.needsRender = true;
+                    });
+            }
+            this.needsRender = true;
+        });
+    }
+
+    // Compares the points with another octree, e.g. a scan of a construction site with the design.
+    private addDeviationControls() {
+        const reloadNodes = () => {
+            this.sessionLog.feature('deviation');
+            this.viewer.reloadNodes();
+            // Force a reload of the visible nodes.
+            this.lastFrustumUpdateTime = 0;
+            this.needsRender = true;
+        };
+        const deviationControls = this.guiRenderControls.addFolder('Deviation');
+        deviationControls
+            .add(this.viewer, 'deviationReference')
+            .name('Compare with')
+            .onFinishChange(reloadNodes);
+        deviationControls
+            .add(this.viewer, 'maxDeviation', 0.01, 1)
+            .name('Max deviation (m)')
+            .onFinishChange(() => {
+                if (this.viewer.deviationReference !== '') {
+                    reloadNodes();
+                }
             });
     }
 
+    // Adds a frame slider and playback for octrees built with timestamps.
+    private addTimeControls() {
+        const octreeId = this.octreeId;
+        const request = new Request(`/frames/${octreeId}/`, {
+            method: 'GET',
+            credentials: 'same-origin',
+        });
+        window
+            .fetch(request)
+            .then((response) => response.json())
+            .then((timestamps: number[]) => {
+                // The octree might have been switched while waiting.
+                if (timestamps.length < 2 || octreeId !== this.octreeId) {
+                    return;
+                }
+                this.frameTimestamps = timestamps;
+                const timeControls = this.guiRenderControls.addFolder('Time');
+                this.frameControl = timeControls
+                    .add(this.viewer, 'frame', -1, timestamps.length - 1)
+                    .name('Frame (-1: all)')
+                    .step(1)
+                    .onChange(() => this.frameChanged());
+                timeControls
+                    .add(this, 'playing')
+                    .name('Play')
+                    .onChange(() => this.sessionLog.feature('playback'));
+                this.timestampControl = timeControls
+                    .add(this, 'timestamp')
+                    .name('Timestamp');
+            });
+    }
+
+    // Applies the camera, up axis, color mode and point budget stored with the octree.
+    private applyViewSettings() {
+        if (this.bundle !== undefined) {
+            this.applySettings(this.bundle.manifest.view_settings);
+            return;
+        }
+        const octreeId = this.octreeId;
+        const request = new Request(`/view_settings/${octreeId}/`, {
+            method: 'GET',
+            credentials: 'same-origin',
+        });
+        window
+            .fetch(request)
+            .then((response) => response.json())
+            .then((settings: ViewSettings) => {
+                // The octree might have been switched while waiting.
+                if (octreeId === this.octreeId) {
+                    this.applySettings(settings);
+                }
+            });
+    }
+
+    private applySettings(settings: ViewSettings) {
+        if (settings.up_axis === 'y') {
+            this.controller.up.set(0, 1, 0);
+            // The overview looks down the z axis, which is not up in this octree.
+            this.removeMinimap();
+        }
+        if (settings.camera !== null) {
+            this.camera.position.fromArray(settings.camera.translation);
+            this.camera.quaternion.fromArray(settings.camera.rotation);
+            this.camera.updateMatrix();
+            this.camera.updateMatrixWorld(true);
+            this.loggedCameraPosition.copy(this.camera.position);
+            if (this.minimap !== undefined) {
+                this.minimap.update();
+            }
+        }
+        if (settings.point_budget !== null) {
+            this.defaultPointBudget = settings.point_budget;
+            this.viewer.pointBudget = settings.point_budget;
+        }
+        if (settings.color_mode === 'source_id') {
+            this.viewer.colorBySource = true;
+            this.colorBySourceControl.updateDisplay();
+            this.viewer.reloadNodes();
+        }
+        // Force a reload of the visible nodes.
+        this.lastFrustumUpdateTime = 0;
+        this.needsRender = true;
+    }
+
+    // Shows the overview of the octree with the frustum of the camera in the corner of the page.
+    // Clicking it moves the camera there.
+    private addMinimap() {
+        if (this.bundle !== undefined) {
+            return;
+        }
+        this.minimap = new Minimap(this.octreeId, this.camera, (x, y) => {
+            this.sessionLog.feature('minimap');
+            this.camera.position.x = x;
+            this.camera.position.y = y;
+            this.camera.updateMatrix();
+            this.camera.updateMatrixWorld(true);
+            this.loggedCameraPosition.copy(this.camera.position);
+            this.minimap.update();
+            // Force a reload of the visible nodes.
+            this.lastMoveTime = performance.now();
+            this.lastFrustumUpdateTime = 0;
+            this.needsRender = true;
+        });
+    }
+
+    private removeMinimap() {
+        if (this.minimap !== undefined) {
+            this.minimap.dispose();
+            this.minimap = undefined;
+        }
+    }
+
+    private frameChanged() {
+        // Playback changes the frame as well.
+        if (!this.playing) {
+            this.sessionLog.feature('frame');
+        }
+        this.timestamp =
+            this.viewer.frame >= 0 ? String(this.frameTimestamps[this.viewer.frame]) : 'all';
+        this.timestampControl.updateDisplay();
+        this.viewer.reloadNodes();
+        // Force a reload of the visible nodes.
+        this.lastFrustumUpdateTime = 0;
+        this.needsRender = true;
+    }
+
     private getViewPortSize(): [number, number] {
         let width = this.renderArea.clientWidth;
         let height = this.renderArea.clientHeight;
@@ -109,7 +350,9 @@ class App {
 
     private initScene() {
         this.scene = new THREE.Scene();
-        this.scene.add(this.camera);
+        this.dolly = new THREE.Group();
+        this.dolly.add(this.camera);
+        this.scene.add(this.dolly);
     }
 
     private initRenderer() {
@@ -125,12 +368,52 @@ class App {
         this.needsRender = true;
         this.lastFrustumUpdateTime = 0;
         this.lastMoveTime = 0;
+        this.initXr();
+        this.renderer.setAnimationLoop(() => this.animate());
+    }
+
+    private initXr() {
+        this.renderer.xr.enabled = true;
+        this.xrController = new XrController(
+            this.renderer,
+            this.dolly,
+            this.camera
+        );
+        this.renderer.xr.addEventListener('sessionstart', () => {
+            this.sessionLog.feature('xr');
+            // Start the session where the desktop camera was, at true scale.
+            this.dolly.position.copy(this.camera.position);
+            this.dolly.quaternion.copy(this.camera.quaternion);
+            this.dolly.updateMatrixWorld(true);
+            this.viewer.pointBudget = XR_POINT_BUDGET;
+            this.lastFrustumUpdateTime = 0;
+        });
+        this.renderer.xr.addEventListener('sessionend', () => {
+            this.dolly.position.set(0, 0, 0);
+            this.dolly.quaternion.set(0, 0, 0, 1);
+            this.dolly.updateMatrixWorld(true);
+            this.viewer.pointBudget = this.defaultPointBudget;
+            this.lastFrustumUpdateTime = 0;
+            this.needsRender = true;
+        });
+        XrController.isSupported().then((supported) => {
+            if (supported) {
+                this.vrButton = VRButton.createButton(this.renderer);
+                document.body.appendChild(this.vrButton);
+            }
+        });
     }
 
     private cleanup() {
         // TODO(negin-z): block requests from the viewer that is going to be replaced
         this.removeControls();
+        this.removeMinimap();
+        if (this.vrButton) {
+            document.body.removeChild(this.vrButton);
+            this.vrButton = undefined;
+        }
         if (this.renderer) {
+            this.renderer.setAnimationLoop(null);
             this.renderArea.removeChild(this.renderer.domElement);
             this.renderer.dispose();
         }
@@ -144,11 +427,33 @@ class App {
 
     private resetOctree() {
         this.cleanup();
+        this.sessionLog.datasetLoaded(this.octreeId);
         this.initCamera();
+        this.loggedCameraPosition.copy(this.camera.position);
         this.initScene();
         this.initRenderer();
         this.initOctreeViewer(this.octreeId);
+        this.playing = false;
+        this.frameTimestamps = [];
+        this.timestamp = 'all';
+        this.defaultPointBudget = Infinity;
         this.addControls();
+        this.addMinimap();
+        this.applyViewSettings();
+    }
+
+    // Records the session in the browser once the user turns it on, and exports it as a file.
+    private addSessionControls() {
+        this.sessionLog = new SessionLog();
+        const sessionControls = this.gui.addFolder('Session');
+        sessionControls
+            .add(this.sessionLog, 'recording')
+            .name('Record session')
+            .onChange(() => {
+                // The log starts with the octree that is open.
+                this.sessionLog.datasetLoaded(this.octreeId);
+            });
+        sessionControls.add(this.sessionLog, 'export').name('Export session log');
     }
 
     private setOctreeId = (newOctreeId: string) => {
@@ -157,7 +462,6 @@ class App {
 
     private run = () => {
         this.resetOctree();
-        this.animate();
     }
 
     public init() {
@@ -170,11 +474,23 @@ class App {
                 .add(this, 'octreeId')
                 .name('Point Cloud ID')
                 .onFinishChange(this.run);
+        this.addSessionControls();
 
-        // TODO(negin-z): error handling
-        this.fetchDefaultOctreeId()
-            .then(this.setOctreeId)
-            .then(this.run);
+        const manifest = bundleManifest();
+        if (manifest !== undefined) {
+            Bundle.load(manifest)
+                .then((bundle) => {
+                    this.bundle = bundle;
+                    return manifest.octree_id;
+                })
+                .then(this.setOctreeId)
+                .then(this.run);
+        } else {
+            // TODO(negin-z): error handling
+            this.fetchDefaultOctreeId()
+                .then(this.setOctreeId)
+                .then(this.run);
+        }
 
         window.addEventListener('resize', () => this.onWindowResize(), false);
     }
@@ -189,13 +505,26 @@ class App {
     }
 
     public animate() {
-        requestAnimationFrame(() => this.animate());
-
         const time = performance.now();
-        if (this.controller.update()) {
+        if (this.playing && time - this.lastFrameTime > FRAME_PLAYBACK_MS) {
+            this.lastFrameTime = time;
+            this.frameControl.setValue((this.viewer.frame + 1) % this.frameTimestamps.length);
+        }
+        const presenting = this.renderer.xr.isPresenting;
+        const moved = presenting
+            ? this.xrController.update()
+            : this.controller.update();
+        if (moved) {
             this.lastMoveTime = time;
             this.viewer.setMoving(true);
             this.needsRender = true;
+            if (this.minimap !== undefined && !presenting) {
+                this.minimap.update();
+            }
+            if (!presenting) {
+                this.sessionLog.moved(this.camera.position.distanceTo(this.loggedCameraPosition));
+                this.loggedCameraPosition.copy(this.camera.position);
+            }
         }
         if (time - this.lastMoveTime > 250) {
             this.viewer.setMoving(false);
@@ -205,9 +534,12 @@ class App {
             time - this.lastFrustumUpdateTime > 250) {
             this.camera.updateMatrixWorld(false);
             this.lastFrustumUpdateTime = time;
+            const camera = presenting
+                ? this.renderer.xr.getCamera(this.camera)
+                : this.camera;
             const matrix = new THREE.Matrix4().multiplyMatrices(
-                this.camera.projectionMatrix,
-                this.camera.matrixWorldInverse
+                camera.projectionMatrix,
+                camera.matrixWorldInverse
             );
             this.viewer.frustumChanged(
                 matrix,
@@ -216,7 +548,8 @@ class App {
             );
         }
 
-        if (this.needsRender) {
+        // The headset pose changes every frame, so we always need to render in XR.
+        if (this.needsRender || presenting) {
             this.needsRender = false;
             // TODO(hrapp): delete invisible nodes and free memory again.
             this.renderer.render(this.scene, this.camera);
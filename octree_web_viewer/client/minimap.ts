@@ -0,0 +1,131 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+'use strict';
+
+import * as THREE from 'three';
+
+// Size of the longer edge of the minimap in pixels.
+const MINIMAP_SIZE = 256;
+
+// Length of the edges of the frustum drawn on the minimap, in pixels of the minimap.
+const FRUSTUM_LENGTH = 30;
+
+// A top-down overview of the whole octree in the bottom left corner of the page, as rendered by
+// '/overview', with the frustum of the camera on it. Clicking the minimap moves the camera above
+// the clicked place, keeping its height and direction.
+export class Minimap {
+    private canvas: HTMLCanvasElement;
+    private image: HTMLImageElement | undefined;
+    // Where the image is in the coordinates of the octree, see the headers of '/overview'.
+    private minX = 0;
+    private maxY = 0;
+    private pixelSize = 1;
+
+    constructor(
+        octreeId: string,
+        private camera: THREE.PerspectiveCamera,
+        onTeleport: (x: number, y: number) => void
+    ) {
+        this.canvas = document.createElement('canvas');
+        this.canvas.style.cssText =
+            'position: absolute; left: 10px; bottom: 10px; border: 1px solid white;' +
+            ' cursor: crosshair; display: none;';
+        this.canvas.addEventListener('click', (event) => {
+            if (this.image === undefined) {
+                return;
+            }
+            const rect = this.canvas.getBoundingClientRect();
+            const column = event.clientX - rect.left;
+            const row = event.clientY - rect.top;
+            onTeleport(this.minX + column * this.pixelSize, this.maxY - row * this.pixelSize);
+        });
+        document.body.appendChild(this.canvas);
+
+        const request = new Request(`/overview/${octreeId}/?size=${MINIMAP_SIZE}`, {
+            method: 'GET',
+            credentials: 'same-origin',
+        });
+        window
+            .fetch(request)
+            .then((response) => {
+                this.minX = Number(response.headers.get('X-Overview-Min-X'));
+                this.maxY = Number(response.headers.get('X-Overview-Max-Y'));
+                this.pixelSize = Number(response.headers.get('X-Overview-Pixel-Size'));
+                return response.blob();
+            })
+            .then((blob) => {
+                const image = new Image();
+                image.onload = () => {
+                    this.image = image;
+                    this.canvas.width = image.width;
+                    this.canvas.height = image.height;
+                    this.canvas.style.display = 'block';
+                    this.update();
+                };
+                image.src = URL.createObjectURL(blob);
+            });
+    }
+
+    // The pixel of the minimap at 'position' in the coordinates of the octree.
+    private toPixel(position: THREE.Vector3): [number, number] {
+        return [
+            (position.x - this.minX) / this.pixelSize,
+            (this.maxY - position.y) / this.pixelSize,
+        ];
+    }
+
+    // Draws the overview and the frustum of the camera, which is projected onto the minimap from
+    // above, e.g. a wedge for a camera that looks horizontally.
+    public update() {
+        if (this.image === undefined) {
+            return;
+        }
+        const context = this.canvas.getContext('2d');
+        context.drawImage(this.image, 0, 0);
+
+        const position = this.camera.getWorldPosition(new THREE.Vector3());
+        const corners = [[-1, -1], [1, -1], [1, 1], [-1, 1]].map(([x, y]) => {
+            const direction = new THREE.Vector3(x, y, 1)
+                .unproject(this.camera)
+                .sub(position)
+                .normalize();
+            return this.toPixel(
+                position.clone().addScaledVector(direction, FRUSTUM_LENGTH * this.pixelSize)
+            );
+        });
+        const [x, y] = this.toPixel(position);
+        context.strokeStyle = 'white';
+        context.lineWidth = 1.5;
+        context.beginPath();
+        for (const [cornerX, cornerY] of corners) {
+            context.moveTo(x, y);
+            context.lineTo(cornerX, cornerY);
+        }
+        context.moveTo(corners[3][0], corners[3][1]);
+        for (const [cornerX, cornerY] of corners) {
+            context.lineTo(cornerX, cornerY);
+        }
+        context.stroke();
+        context.fillStyle = 'white';
+        context.fillRect(x - 2, y - 2, 4, 4);
+    }
+
+    public dispose() {
+        document.body.removeChild(this.canvas);
+        if (this.image !== undefined) {
+            URL.revokeObjectURL(this.image.src);
+        }
+    }
+}
@@ -15,6 +15,7 @@
 'use strict';
 
 import * as THREE from 'three';
+import { Bundle } from './bundle';
 
 const KEY_L = 'L'.charCodeAt(0);
 
@@ -68,35 +69,67 @@ function matrixToString(m: THREE.Matrix4): string {
     ].join(',');
 }
 
+// The columns of a node, the positions or colors are undefined if the server left them out.
 class NodeRenderData {
     constructor(
         public min: THREE.Vector3,
         public edgeLength: number,
-        public position: Float32Array | Uint16Array | Uint8Array,
+        public numPoints: number,
+        public position: Float32Array | Uint16Array | Uint8Array | undefined,
         public normalizePosition: boolean,
-        public color: Uint8Array
+        public color: Uint8Array | undefined
     ) { }
 }
 
+// Status of a node in a '/nodes_batch' response, see 'get_nodes_batch' in the server.
+const NODE_STATUS_OK = 0;
+
+// Flags of the columns in the data of a node, see 'write_node_data' in the server.
+const COLUMN_POSITION = 1;
+const COLUMN_COLOR = 2;
+
+// Nodes are shown in this color until their colors are loaded.
+const PLACEHOLDER_COLOR = 160;
+
+function alignTo8(numBytes: number): number {
+    return numBytes % 8 == 0 ? numBytes : numBytes + 8 - numBytes % 8;
+}
+
 class NodeLoader {
     public load(
         scene: THREE.Scene,
         material: THREE.ShaderMaterial,
         nodes: NodeData[],
-        octreeId: string
+        octreeId: string,
+        colorBySource: boolean,
+        deviationReference: string,
+        maxDeviation: number,
+        frame: number,
+        columns: string,
+        signal: AbortSignal
     ): Promise<void> {
-        let query: string[] = [];
-
-        for (const node of nodes) {
-            query.push(`"${node.nodeName}"`);
-        }
+        const query = nodes.map((node) => ({ id: node.nodeName, priority: node.priority }));
         const headers = new Headers();
         headers.append('Content-Type', 'application/json; charset=UTF-8');
-        const request = new Request(`/nodes_data/${octreeId}/`, {
+        const parameters = [];
+        if (deviationReference !== '') {
+            parameters.push('color_by=deviation');
+            parameters.push(`reference=${encodeURIComponent(deviationReference)}`);
+            parameters.push(`max_deviation=${maxDeviation}`);
+        } else if (colorBySource) {
+            parameters.push('color_by=source_id');
+        }
+        if (frame >= 0) {
+            parameters.push(`frame=${frame}`);
+        }
+        parameters.push(`columns=${columns}`);
+        const queryString = `?${parameters.join('&')}`;
+        const request = new Request(`/nodes_batch/${octreeId}/${queryString}`, {
             method: 'POST',
-            body: '[' + query.join(',') + ']',
+            body: JSON.stringify(query),
             headers: headers,
             credentials: 'same-origin',
+            signal: signal,
         });
 
         return window
@@ -104,90 +137,122 @@ class NodeLoader {
             .then((data) => data.arrayBuffer())
             .then((data) => {
                 let view = new DataView(data);
-                let currentEntry = 0;
-                let numBytesRead = 0;
-                while (nodes[currentEntry] !== undefined) {
-                    let min_x = view.getFloat64(numBytesRead, true /* littleEndian */);
-                    numBytesRead += 8;
-                    let min_y = view.getFloat64(numBytesRead, true /* littleEndian */);
-                    numBytesRead += 8;
-                    let min_z = view.getFloat64(numBytesRead, true /* littleEndian */);
-                    numBytesRead += 8;
-                    let edgeLength = view.getFloat64(
-                        numBytesRead,
-                        true /* littleEndian */
-                    );
-                    numBytesRead += 8;
-
-                    const numPoints = view.getUint32(
-                        numBytesRead,
-                        true /* littleEndian */
-                    );
-                    numBytesRead += 4;
-
-                    const bytesPerCoordinate = view.getUint8(numBytesRead);
-                    numBytesRead += 1;
-                    if (numBytesRead % 8 != 0) {
-                        numBytesRead += 8 - numBytesRead % 8;
+                const numNodes = view.getUint32(0, true /* littleEndian */);
+                let numBytesRead = 8;
+                for (let currentEntry = 0; currentEntry < numNodes; currentEntry++) {
+                    const idLength = view.getUint16(numBytesRead, true /* littleEndian */);
+                    const status = view.getUint8(numBytesRead + 2);
+                    const dataLength = view.getUint32(numBytesRead + 4, true /* littleEndian */);
+                    numBytesRead = alignTo8(numBytesRead + 8 + idLength);
+                    if (status == NODE_STATUS_OK) {
+                        let render_data = this.parseNode(data, view, numBytesRead);
+                        nodes[currentEntry].onDataLoaded(scene, material, render_data);
+                    } else {
+                        console.log('Could not load node ', nodes[currentEntry].nodeName);
                     }
+                    numBytesRead += dataLength;
+                }
+            });
+    }
 
-                    let position: Float32Array | Uint16Array | Uint8Array;
-                    let normalizePosition: boolean;
-                    switch (bytesPerCoordinate) {
-                        case 8:
-                            // Float64Array is not supported, so we need to convert it.
-                            position = Float32Array.from(new Float64Array(data, numBytesRead, numPoints * 3));
-                            normalizePosition = false;
-                            break;
-                        case 4:
-                            position = new Float32Array(data, numBytesRead, numPoints * 3);
-                            normalizePosition = false;
-                            break;
-
-                        case 2:
-                            position = new Uint16Array(data, numBytesRead, numPoints * 3);
-                            normalizePosition = true;
-                            break;
-
-                        case 1:
-                            position = new Uint8Array(data, numBytesRead, numPoints * 3);
-                            normalizePosition = true;
-                            break;
-
-                        default:
-                            console.log('Invalid bytesPerCoordinate: ', bytesPerCoordinate);
-                    }
-                    numBytesRead += numPoints * bytesPerCoordinate * 3;
-                    if (numBytesRead % 8 != 0) {
-                        numBytesRead += 8 - numBytesRead % 8;
-                    }
+    // Reads the nodes from an exported bundle instead of requesting them from the server.
+    public loadFromBundle(
+        scene: THREE.Scene,
+        material: THREE.ShaderMaterial,
+        nodes: NodeData[],
+        bundle: Bundle
+    ): Promise<void> {
+        for (const node of nodes) {
+            const offset = bundle.offset(node.nodeName);
+            if (offset === undefined) {
+                console.log('Could not load node ', node.nodeName);
+                continue;
+            }
+            node.onDataLoaded(scene, material, this.parseNode(bundle.data, bundle.view, offset));
+        }
+        return Promise.resolve();
+    }
 
-                    let color = new Uint8Array(data, numBytesRead, numPoints * 3);
-                    numBytesRead += numPoints * 3;
-                    if (numBytesRead % 8 != 0) {
-                        numBytesRead += 8 - numBytesRead % 8;
-                    }
+    private parseNode(data: ArrayBuffer, view: DataView, numBytesRead: number): NodeRenderData {
+        let min_x = view.getFloat64(numBytesRead, true /* littleEndian */);
+        numBytesRead += 8;
+        let min_y = view.getFloat64(numBytesRead, true /* littleEndian */);
+        numBytesRead += 8;
+        let min_z = view.getFloat64(numBytesRead, true /* littleEndian */);
+        numBytesRead += 8;
+        let edgeLength = view.getFloat64(numBytesRead, true /* littleEndian */);
+        numBytesRead += 8;
+
+        const numPoints = view.getUint32(numBytesRead, true /* littleEndian */);
+        numBytesRead += 4;
+
+        const bytesPerCoordinate = view.getUint8(numBytesRead);
+        const columns = view.getUint8(numBytesRead + 1);
+        numBytesRead = alignTo8(numBytesRead + 2);
+
+        let position: Float32Array | Uint16Array | Uint8Array | undefined;
+        let normalizePosition = false;
+        switch (columns & COLUMN_POSITION ? bytesPerCoordinate : 0) {
+            case 0:
+                break;
+
+            case 8:
+                // Float64Array is not supported, so we need to convert it.
+                position = Float32Array.from(new Float64Array(data, numBytesRead, numPoints * 3));
+                normalizePosition = false;
+                break;
+            case 4:
+                position = new Float32Array(data, numBytesRead, numPoints * 3);
+                normalizePosition = false;
+                break;
+
+            case 2:
+                position = new Uint16Array(data, numBytesRead, numPoints * 3);
+                normalizePosition = true;
+                break;
+
+            case 1:
+                position = new Uint8Array(data, numBytesRead, numPoints * 3);
+                normalizePosition = true;
+                break;
+
+            default:
+                console.log('Invalid bytesPerCoordinate: ', bytesPerCoordinate);
+        }
+        if (position !== undefined) {
+            numBytesRead = alignTo8(numBytesRead + numPoints * bytesPerCoordinate * 3);
+        }
 
-                    let render_data = new NodeRenderData(
-                        new THREE.Vector3(min_x, min_y, min_z),
-                        edgeLength,
-                        position,
-                        normalizePosition,
-                        color
-                    );
-                    let node = nodes[currentEntry];
-                    node.onDataLoaded(scene, material, render_data);
-                    currentEntry += 1;
-                }
-            });
+        let color: Uint8Array | undefined;
+        if (columns & COLUMN_COLOR) {
+            color = new Uint8Array(data, numBytesRead, numPoints * 3);
+        }
+        return new NodeRenderData(
+            new THREE.Vector3(min_x, min_y, min_z),
+            edgeLength,
+            numPoints,
+            position,
+            normalizePosition,
+            color
+        );
     }
 }
 
 class NodeData {
     public threePoints: THREE.Points;
+    public numPoints: number;
+    public withinBudget: boolean;
+    // The screen-space error of the node in pixels, which the server reads nodes by.
+    public priority: number;
+    // Whether the colors of the points were loaded, which happens after their positions.
+    public hasColor: boolean;
 
     constructor(public nodeName: string) {
         this.threePoints = undefined;
+        this.numPoints = 0;
+        this.withinBudget = true;
+        this.priority = 0;
+        this.hasColor = false;
     }
 
     public isUpToDate(): boolean {
@@ -200,9 +265,26 @@ class NodeData {
         nodeRenderData: NodeRenderData
     ) {
         // If this node contains no points.
-        if (nodeRenderData.position.length === 0 || this.isUpToDate()) {
+        if (nodeRenderData.numPoints === 0) {
+            return;
+        }
+        if (this.isUpToDate()) {
+            if (nodeRenderData.color !== undefined && !this.hasColor) {
+                this.threePoints.geometry.setAttribute(
+                    'color',
+                    new THREE.BufferAttribute(nodeRenderData.color, 3)
+                );
+                this.hasColor = true;
+            }
+            return;
+        }
+        if (nodeRenderData.position === undefined) {
             return;
         }
+        this.hasColor = nodeRenderData.color !== undefined;
+        const color = this.hasColor
+            ? nodeRenderData.color
+            : new Uint8Array(nodeRenderData.numPoints * 3).fill(PLACEHOLDER_COLOR);
 
         const geometry = new THREE.BufferGeometry();
         // itemSize = 3 because there are 3 values (components) per vertex.
@@ -216,7 +298,7 @@ class NodeData {
         );
         geometry.setAttribute(
             'color',
-            new THREE.BufferAttribute(nodeRenderData.color, 3)
+            new THREE.BufferAttribute(color, 3)
         );
 
         // THREE can no longer figure out the bounding box or the bounding sphere of
@@ -249,6 +331,8 @@ class NodeData {
             gamma: commonMaterial.uniforms['gamma'],
         };
         this.threePoints = new THREE.Points(geometry, material);
+        this.threePoints.visible = this.withinBudget;
+        this.numPoints = nodeRenderData.numPoints;
         scene.add(this.threePoints);
     }
 }
@@ -257,16 +341,41 @@ export class OctreeViewer {
     // TODO(hrapp): These are only public, so we can wire up DAT to affect
     // material.size. If DAT supports callbacks, we can encapsulate this nicer.
     public material: THREE.ShaderMaterial;
-    public maxLevelToDisplay: number;
+    // Nodes with a smaller screen-space error in pixels are not loaded. The server computes the
+    // error of every node, see 'ScreenSpaceError' in the Rust crate.
+    public pixelThreshold: number;
+    // While moving, nodes with a smaller screen-space error in pixels are hidden.
+    public movingPixelThreshold: number;
+    // Maximum number of points to display. Nodes are filled in the order the server returns
+    // them, i.e. the largest nodes on screen first.
+    public pointBudget: number;
+    // Colors the points by the scan they come from. Requires an octree built with source ids.
+    public colorBySource: boolean;
+    // Colors the points by their distance from the octree with this id, red above and blue below
+    // it, unless empty. Takes precedence over 'colorBySource'.
+    public deviationReference: string;
+    // The distance in meters that gets the most saturated colors.
+    public maxDeviation: number;
+    // Index of the frame to show for captures over time, or -1 to show all frames at once.
+    public frame: number;
 
     private loadedData: { [key: string]: NodeData } = {};
     private nodeLoader: NodeLoader;
-    private batches: NodeData[][] = [];
-    private currentlyLoading: number;
+    // Batches of nodes to load with the columns to request. The positions of all nodes in view are
+    // requested before their colors, so the geometry shows up as fast as possible.
+    private batches: { nodes: NodeData[]; columns: string }[] = [];
+    // Batches that are being loaded, so that they can be cancelled when the view changes.
+    private loading: { nodes: NodeData[]; controller: AbortController }[] = [];
     private useTransparency: boolean;
+    private moving: boolean;
 
-
-    constructor(private scene: THREE.Scene, private onNewNodeData: () => void, private octreeId: string) {
+    // Without a server, the nodes are selected and read from 'bundle'.
+    constructor(
+        private scene: THREE.Scene,
+        private onNewNodeData: () => void,
+        private octreeId: string,
+        private bundle?: Bundle
+    ) {
         this.material = new THREE.ShaderMaterial({
             uniforms: {
                 size: { value: 2 },
@@ -277,10 +386,16 @@ export class OctreeViewer {
             fragmentShader: FRAGMENT_SHADER,
         });
         this.useTransparency = false;
-        this.maxLevelToDisplay = 3;
+        this.pixelThreshold = 1;
+        this.movingPixelThreshold = 200;
+        this.pointBudget = Infinity;
+        this.colorBySource = false;
+        this.deviationReference = '';
+        this.maxDeviation = 0.1;
+        this.frame = -1;
+        this.moving = false;
 
         this.nodeLoader = new NodeLoader();
-        this.currentlyLoading = 0;
     }
 
     public alphaChanged() {
@@ -296,12 +411,34 @@ export class OctreeViewer {
         this.useTransparency = newUseTransparency;
     }
 
+    // Drops all loaded nodes, so that they are loaded again with new colors or another frame.
+    public reloadNodes() {
+        for (const batch of this.loading) {
+            batch.controller.abort();
+        }
+        this.batches = [];
+        for (const nodeId of Object.keys(this.loadedData)) {
+            const threePoints = this.loadedData[nodeId].threePoints;
+            if (threePoints !== undefined) {
+                this.scene.remove(threePoints);
+                threePoints.geometry.dispose();
+                (threePoints.material as THREE.Material).dispose();
+            }
+        }
+        this.loadedData = {};
+    }
+
     public frustumChanged(matrix: THREE.Matrix4, width: number, height: number) {
+        if (this.bundle !== undefined) {
+            this.nodesUpdate(
+                this.bundle.visibleNodes(matrix, width, height, this.pixelThreshold)
+            );
+            return;
+        }
         // ThreeJS is column major.
         const request = new Request(
-            `/visible_nodes/${this.octreeId}/?width=${width}&height=${height}&matrix=${matrixToString(
-                matrix
-            )}`,
+            `/visible_nodes/${this.octreeId}/?priorities=true&width=${width}&height=${height}` +
+                `&pixel_threshold=${this.pixelThreshold}&matrix=${matrixToString(matrix)}`,
             {
                 method: 'GET',
                 credentials: 'same-origin',
@@ -317,52 +454,99 @@ export class OctreeViewer {
     }
 
     public setMoving(moving: boolean) {
+        this.moving = moving;
+        this.updateVisibility();
+    }
+
+    private updateVisibility() {
         for (const nodeId of Object.keys(this.loadedData)) {
-            const threePoints = this.loadedData[nodeId].threePoints;
-            if (threePoints !== undefined) {
-                // If we are moving, only show points above a certain depth. Otherwise, show them all.
-                if (moving) {
-                    threePoints.visible = nodeId.length <= this.maxLevelToDisplay;
-                } else {
-                    threePoints.visible = true;
-                }
+            const node = this.loadedData[nodeId];
+            if (node.threePoints !== undefined) {
+                // If we are moving, only show the nodes that are large on screen. Otherwise, show
+                // them all.
+                node.threePoints.visible =
+                    node.withinBudget &&
+                    (!this.moving || node.priority >= this.movingPixelThreshold);
             }
         }
     }
 
-    private nodesUpdate(nodeIds: string[]) {
+    private nodesUpdate(visibleNodes: { id: string; priority: number }[]) {
         const start = performance.now();
         this.batches = [];
-        let currentBatch: NodeData[] = [];
-        for (let nodeId of nodeIds) {
-            let node = this.getOrCreate(nodeId);
-            if (node.isUpToDate()) {
+        let withoutPosition: NodeData[] = [];
+        let withoutColor: NodeData[] = [];
+        let numPointsInBudget = 0;
+        let wanted = new Set<NodeData>();
+        for (let visibleNode of visibleNodes) {
+            let node = this.getOrCreate(visibleNode.id);
+            node.priority = visibleNode.priority;
+            node.withinBudget = numPointsInBudget < this.pointBudget;
+            numPointsInBudget += node.numPoints;
+            if (!node.withinBudget || (node.isUpToDate() && node.hasColor)) {
                 continue;
             }
-
-            currentBatch.push(node);
-            if (currentBatch.length > 50) {
-                this.batches.push(currentBatch);
-                currentBatch = [];
-            }
+            wanted.add(node);
+            (node.isUpToDate() ? withoutColor : withoutPosition).push(node);
         }
-        if (currentBatch.length > 0) {
-            this.batches.push(currentBatch);
+        // Bundles contain all columns of a node, so their nodes always come with colors.
+        this.pushBatches(withoutPosition, 'position');
+        this.pushBatches(withoutColor, 'color');
+        // Batches of nodes that went out of view only delay the ones that are in view.
+        for (const batch of this.loading) {
+            if (!batch.nodes.some((node) => wanted.has(node))) {
+                batch.controller.abort();
+            }
         }
+        this.updateVisibility();
         this.handleNextBatch();
         console.log(`nodeUpdate took ${performance.now() - start}ms.`);
     }
 
+    private pushBatches(nodes: NodeData[], columns: string) {
+        for (let i = 0; i < nodes.length; i += 51) {
+            this.batches.push({ nodes: nodes.slice(i, i + 51), columns: columns });
+        }
+    }
+
     private handleNextBatch() {
-        if (this.batches.length == 0 || this.currentlyLoading > 2) {
+        if (this.batches.length == 0 || this.loading.length > 2) {
             return;
         }
-        this.currentlyLoading += 1;
-        this.nodeLoader
-            .load(this.scene, this.material, this.batches.shift(), this.octreeId)
+        const next = this.batches.shift();
+        const batch = { nodes: next.nodes, controller: new AbortController() };
+        this.loading.push(batch);
+        const loaded =
+            this.bundle !== undefined
+                ? this.nodeLoader.loadFromBundle(
+                      this.scene,
+                      this.material,
+                      batch.nodes,
+                      this.bundle
+                  )
+                : this.nodeLoader.load(
+                      this.scene,
+                      this.material,
+                      batch.nodes,
+                      this.octreeId,
+                      this.colorBySource,
+                      this.deviationReference,
+                      this.maxDeviation,
+                      this.frame,
+                      next.columns,
+                      batch.controller.signal
+                  );
+        loaded
+            .then(
+                () => this.onNewNodeData(),
+                (error) => {
+                    if (error.name !== 'AbortError') {
+                        console.log('Could not load nodes: ', error);
+                    }
+                }
+            )
             .then(() => {
-                this.currentlyLoading -= 1;
-                this.onNewNodeData();
+                this.loading.splice(this.loading.indexOf(batch), 1);
                 this.handleNextBatch();
             });
     }
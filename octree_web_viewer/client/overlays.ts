@@ -0,0 +1,63 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+'use strict';
+
+import * as THREE from 'three';
+
+// A layer of the vector overlays of the server, as returned by '/overlays'.
+interface OverlayLayer {
+    name: string;
+    polylines: { points: number[][]; closed: boolean }[];
+}
+
+// The lines of 'layer' in the color of 'category_color' of the server for 'index'.
+function layerLines(layer: OverlayLayer, index: number): THREE.LineSegments {
+    // Positions are 32 bit floats on the GPU, so the vertices are relative to the first point of
+    // the layer to keep them precise in large coordinates.
+    const origin =
+        layer.polylines.length > 0 ? layer.polylines[0].points[0] : [0, 0, 0];
+    const positions: number[] = [];
+    for (const polyline of layer.polylines) {
+        const points = polyline.points;
+        const numSegments = polyline.closed ? points.length : points.length - 1;
+        for (let i = 0; i < numSegments; i++) {
+            for (const p of [points[i], points[(i + 1) % points.length]]) {
+                positions.push(p[0] - origin[0], p[1] - origin[1], p[2] - origin[2]);
+            }
+        }
+    }
+    const geometry = new THREE.BufferGeometry();
+    geometry.setAttribute('position', new THREE.Float32BufferAttribute(positions, 3));
+    const color = new THREE.Color().setHSL((index * 0.381966011250105) % 1, 1, 0.5);
+    const lines = new THREE.LineSegments(
+        geometry,
+        new THREE.LineBasicMaterial({ color: color })
+    );
+    lines.position.fromArray(origin);
+    lines.name = layer.name;
+    return lines;
+}
+
+// Loads the overlays of the server with a line object per layer, named like the layer.
+export function loadOverlays(): Promise<THREE.LineSegments[]> {
+    const request = new Request('/overlays/', {
+        method: 'GET',
+        credentials: 'same-origin',
+    });
+    return window
+        .fetch(request)
+        .then((response) => response.json())
+        .then((layers: OverlayLayer[]) => layers.map(layerLines));
+}
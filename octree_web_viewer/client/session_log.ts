@@ -0,0 +1,98 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+'use strict';
+
+// Moves of the camera that are closer in time than this are logged as one navigation.
+const NAVIGATION_GAP_S = 1;
+
+// Keeps a log of the session in the browser in the format of 'point_viewer::session_log', while
+// 'recording' is on: the datasets that are opened, the features that are used and how far the
+// camera moves, but no positions. Nothing is sent anywhere; 'export' downloads the log, e.g. for
+// 'point_cloud_tool session-summary'.
+export class SessionLog {
+    public recording = false;
+    private lines: string[] = [];
+    private start = performance.now();
+    // The start and end time and the distance of the navigation that is not logged yet.
+    private navigation: [number, number, number] | undefined;
+
+    constructor() {
+        this.record({ event: 'start', viewer: 'web_viewer' });
+    }
+
+    private nowS(): number {
+        return (performance.now() - this.start) / 1000;
+    }
+
+    private write(timeS: number, event: object) {
+        this.lines.push(JSON.stringify({ time_s: timeS, ...event }));
+    }
+
+    private writeNavigation() {
+        if (this.navigation !== undefined) {
+            const [startS, endS, distance] = this.navigation;
+            this.write(startS, {
+                event: 'navigation',
+                distance: distance,
+                duration_s: endS - startS,
+            });
+            this.navigation = undefined;
+        }
+    }
+
+    // The start of the session is always logged, so that exported logs can be summed up.
+    private record(event: object) {
+        this.writeNavigation();
+        this.write(this.nowS(), event);
+    }
+
+    public datasetLoaded(octreeId: string) {
+        if (this.recording) {
+            this.record({ event: 'dataset_loaded', name: octreeId });
+        }
+    }
+
+    public feature(name: string) {
+        if (this.recording) {
+            this.record({ event: 'feature', name: name });
+        }
+    }
+
+    public moved(distance: number) {
+        if (!this.recording) {
+            return;
+        }
+        const nowS = this.nowS();
+        if (this.navigation !== undefined && nowS - this.navigation[1] < NAVIGATION_GAP_S) {
+            this.navigation[1] = nowS;
+            this.navigation[2] += distance;
+            return;
+        }
+        this.writeNavigation();
+        this.navigation = [nowS, nowS, distance];
+    }
+
+    // Downloads the log up to now as 'session.jsonl'.
+    public export() {
+        this.writeNavigation();
+        const lines = this.lines.concat([JSON.stringify({ time_s: this.nowS(), event: 'end' })]);
+        const blob = new Blob([lines.join('\n') + '\n'], { type: 'application/x-ndjson' });
+        const link = document.createElement('a');
+        link.href = URL.createObjectURL(blob);
+        link.download = 'session.jsonl';
+        link.click();
+        URL.revokeObjectURL(link.href);
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+'use strict';
+
+import * as THREE from 'three';
+
+// Headsets render two eyes at a high frame rate, so we show far less points than on a desktop.
+export const XR_POINT_BUDGET = 1500000;
+
+// Turning smoothly in VR makes a lot of people sick, so we only turn in fixed steps.
+const SNAP_TURN_ANGLE = Math.PI / 6;
+// Thumbstick deflection that counts as a deliberate input.
+const THUMBSTICK_THRESHOLD = 0.6;
+// Moving at a constant, slow speed without acceleration is the most comfortable.
+const MOVE_SPEED_METERS_PER_SECOND = 1.5;
+
+// The TypeScript DOM library does not know about WebXR yet.
+type XrSession = any;
+
+// Locomotion for WebXR sessions. The headset pose is controlled by the user's head, so we move
+// the whole world relative to them by moving the 'dolly' the camera is attached to.
+// The left thumbstick moves in the direction the user is looking at, the right thumbstick
+// snap-turns.
+export class XrController {
+  private snapTurnArmed: boolean;
+  private lastUpdateTime: number;
+
+  constructor(
+    private renderer: THREE.WebGLRenderer,
+    private dolly: THREE.Object3D,
+    private camera: THREE.Camera
+  ) {
+    this.snapTurnArmed = true;
+    this.lastUpdateTime = performance.now();
+  }
+
+  public static isSupported(): Promise<boolean> {
+    const xr = (<any>navigator).xr;
+    if (xr === undefined) {
+      return Promise.resolve(false);
+    }
+    return xr.isSessionSupported('immersive-vr');
+  }
+
+  // Returns true if the dolly moved.
+  public update(): boolean {
+    const time = performance.now();
+    const dt = (time - this.lastUpdateTime) / 1000;
+    this.lastUpdateTime = time;
+
+    const session: XrSession = this.renderer.xr.getSession();
+    if (!session) {
+      return false;
+    }
+
+    let changed = false;
+    for (const source of session.inputSources) {
+      if (!source.gamepad || source.gamepad.axes.length < 4) {
+        continue;
+      }
+      // The 'xr-standard' mapping puts the thumbstick on axes 2 and 3.
+      const x = source.gamepad.axes[2];
+      const y = source.gamepad.axes[3];
+      if (source.handedness === 'right') {
+        changed = this.snapTurn(x) || changed;
+      } else {
+        changed = this.move(x, y, dt) || changed;
+      }
+    }
+    return changed;
+  }
+
+  private snapTurn(x: number): boolean {
+    if (Math.abs(x) < THUMBSTICK_THRESHOLD) {
+      this.snapTurnArmed = true;
+      return false;
+    }
+    if (!this.snapTurnArmed) {
+      return false;
+    }
+    // Require the thumbstick to go back to the center before turning again.
+    this.snapTurnArmed = false;
+    this.dolly.rotateOnWorldAxis(
+      new THREE.Vector3(0, 0, 1),
+      -Math.sign(x) * SNAP_TURN_ANGLE
+    );
+    this.dolly.updateMatrixWorld(true);
+    return true;
+  }
+
+  private move(x: number, y: number, dt: number): boolean {
+    if (Math.abs(x) < THUMBSTICK_THRESHOLD && Math.abs(y) < THUMBSTICK_THRESHOLD) {
+      return false;
+    }
+    const forward = new THREE.Vector3();
+    this.camera.getWorldDirection(forward);
+    const right = new THREE.Vector3()
+      .crossVectors(forward, this.camera.up)
+      .normalize();
+    const step = MOVE_SPEED_METERS_PER_SECOND * dt;
+    this.dolly.position.addScaledVector(forward, -y * step);
+    this.dolly.position.addScaledVector(right, x * step);
+    this.dolly.updateMatrixWorld(true);
+    return true;
+  }
+}
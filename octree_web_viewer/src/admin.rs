@@ -0,0 +1,168 @@
+//! Probes for orchestrators like Kubernetes, and an admin API to operate the server without
+//! restarting it. The admin API requires the 'admin_token' of the settings as bearer token.
+
+use crate::catalog::Dataset;
+use crate::metrics;
+use crate::state::{AppState, CachedOctreeInfo};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The paths of the probes, which are exempt from the limits of the clients.
+pub const PROBE_PATHS: [&str; 2] = ["/healthz", "/readyz"];
+
+#[derive(Deserialize)]
+pub struct EvictInfo {
+    /// Evicts only this octree instead of all of them.
+    octree_id: Option<String>,
+}
+
+/// A dataset of the catalog with its state in the cache.
+#[derive(Serialize)]
+struct DatasetStatus<'a> {
+    #[serde(flatten)]
+    dataset: &'a Dataset,
+    cached: bool,
+    version: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct EvictReply {
+    evicted: usize,
+}
+
+#[derive(Serialize)]
+struct CacheLookups {
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Serialize)]
+struct CacheStats {
+    octrees: Vec<CachedOctreeInfo>,
+    octree_lookups: CacheLookups,
+    /// Number of datasets and age in seconds of the catalog, if it was scanned.
+    catalog_datasets: Option<usize>,
+    catalog_age_secs: Option<u64>,
+}
+
+fn json_response(reply: &impl serde::Serialize) -> HttpResponse {
+    match serde_json::to_string(reply) {
+        Ok(reply) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(reply),
+        Err(err) => HttpResponse::InternalServerError().json(err.to_string()),
+    }
+}
+
+/// Returns the response to send instead if the request does not carry the admin token.
+fn authorize(state: &AppState, req: &HttpRequest) -> Result<(), HttpResponse> {
+    let admin_token = match state.settings().admin_token {
+        Some(admin_token) => admin_token,
+        None => {
+            return Err(HttpResponse::NotFound()
+                .json("The admin API is disabled, set 'admin_token' to enable it."))
+        }
+    };
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let authorized = bearer.map_or(false, |bearer| {
+        constant_time_eq(bearer.trim().as_bytes(), admin_token.as_bytes())
+    });
+    if authorized {
+        Ok(())
+    } else {
+        Err(HttpResponse::Unauthorized().json("Invalid admin token."))
+    }
+}
+
+/// Compares in time that depends only on the lengths, so the token cannot be guessed byte by
+/// byte from response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Liveness probe: the server answers requests.
+pub fn get_health() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain").body("ok")
+}
+
+/// Readiness probe: the served octree can be loaded. This also puts it into the cache.
+pub fn get_ready(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    match state.load_octree(state.get_init_id()) {
+        Ok(_) => HttpResponse::Ok().content_type("text/plain").body("ok"),
+        Err(err) => HttpResponse::ServiceUnavailable().json(err.to_string()),
+    }
+}
+
+/// All datasets of the catalog and whether they are in the cache.
+pub fn get_admin_datasets((state, req): (web::Data<Arc<AppState>>, HttpRequest)) -> HttpResponse {
+    if let Err(response) = authorize(&state, &req) {
+        return response;
+    }
+    let catalog = match state.catalog() {
+        Ok(catalog) => catalog,
+        Err(err) => return HttpResponse::from_error(err.into()),
+    };
+    let cached: HashMap<_, _> = state
+        .cached_octrees()
+        .into_iter()
+        .map(|octree| (octree.id, octree.version))
+        .collect();
+    let datasets: Vec<_> = catalog
+        .datasets()
+        .iter()
+        .map(|dataset| DatasetStatus {
+            dataset,
+            cached: cached.contains_key(&dataset.id),
+            version: cached.get(&dataset.id).copied().flatten(),
+        })
+        .collect();
+    json_response(&datasets)
+}
+
+pub fn get_admin_cache((state, req): (web::Data<Arc<AppState>>, HttpRequest)) -> HttpResponse {
+    if let Err(response) = authorize(&state, &req) {
+        return response;
+    }
+    let catalog = state.cached_catalog();
+    json_response(&CacheStats {
+        octrees: state.cached_octrees(),
+        octree_lookups: CacheLookups {
+            hits: metrics::cache_lookups("octree", true),
+            misses: metrics::cache_lookups("octree", false),
+        },
+        catalog_datasets: catalog.as_ref().map(|catalog| catalog.datasets().len()),
+        catalog_age_secs: catalog.as_ref().map(|catalog| catalog.age_secs()),
+    })
+}
+
+/// Reloads the settings from the config file.
+pub fn post_admin_reload((state, req): (web::Data<Arc<AppState>>, HttpRequest)) -> HttpResponse {
+    if let Err(response) = authorize(&state, &req) {
+        return response;
+    }
+    match state.reload_settings() {
+        Ok(()) => HttpResponse::Ok().json("Reloaded the settings."),
+        Err(err) => HttpResponse::from_error(err.into()),
+    }
+}
+
+pub fn post_admin_evict(
+    (state, req, evict_query): (web::Data<Arc<AppState>>, HttpRequest, web::Query<EvictInfo>),
+) -> HttpResponse {
+    if let Err(response) = authorize(&state, &req) {
+        return response;
+    }
+    let num_evicted = state.evict(evict_query.octree_id.as_deref());
+    json_response(&EvictReply {
+        evicted: num_evicted,
+    })
+}
@@ -1,15 +1,89 @@
 use crate::backend_error::PointsViewerError;
+use crate::catalog::Region;
+use crate::metrics;
+use crate::policy::NodeFilter;
 use crate::state::AppState;
-use actix_web::{dev::BodyEncoding, http::ContentEncoding, web, HttpResponse};
+use crate::thumbnail;
+use actix_web::error::BlockingError;
+use actix_web::http::{header, ContentEncoding};
+use actix_web::{dev::BodyEncoding, web, HttpRequest, HttpResponse};
 use byteorder::{LittleEndian, WriteBytesExt};
+use fnv::FnvHasher;
+use image::{DynamicImage, ImageOutputFormat};
+use log::debug;
 use nalgebra::Matrix4;
-use point_viewer::octree::{self, Octree};
+use point_viewer::color::ColorSpace;
+use point_viewer::octree::{
+    self, render_overview_with_min_detail, DeviationOptions, NodeColumns, NodeId, Octree,
+    OverviewKind, ScreenSpaceError,
+};
+use std::cmp::Ordering;
+use std::hash::Hasher;
 use std::str::FromStr;
 use std::sync::Arc;
 
 #[derive(Deserialize)]
 pub struct Info {
     matrix: String,
+    /// Return the nodes as objects with their id and their screen-space error in pixels as
+    /// 'priority'.
+    #[serde(default)]
+    priorities: bool,
+    /// Size of the viewport in pixels, see 'ScreenSpaceError'. Defaults to a full HD screen.
+    width: Option<f64>,
+    height: Option<f64>,
+    /// Leave out the nodes with a smaller screen-space error. Defaults to
+    /// 'DEFAULT_PIXEL_THRESHOLD'.
+    pixel_threshold: Option<f64>,
+    /// Leave out the nodes whose points all face away from the camera, see
+    /// 'Octree::is_back_facing'.
+    #[serde(default)]
+    backface_culling: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailInfo {
+    matrix: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct OverviewInfo {
+    /// 'density' or 'elevation', see 'OverviewKind'.
+    kind: Option<String>,
+    /// The size of the longer edge of the image.
+    size: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct DatasetsInfo {
+    /// 'min_x,min_y,max_x,max_y' or 'min_x,min_y,min_z,max_x,max_y,max_z'.
+    bbox: Option<String>,
+    crs: Option<String>,
+}
+
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+const MAX_THUMBNAIL_SIZE: u32 = 2048;
+
+/// Parses a comma separated, column major 4x4 matrix.
+fn parse_matrix(matrix: &str) -> Result<Matrix4<f64>, PointsViewerError> {
+    let e = matrix
+        .split(',')
+        .map(|s| s.parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|err| PointsViewerError::BadRequest(format!("Parsing Error: {}", err)))?;
+    // matrix size check
+    if 16 == e.len() {
+        Ok(Matrix4::new(
+            e[0], e[1], e[2], e[3], e[4], e[5], e[6], e[7], e[8], e[9], e[10], e[11], e[12], e[13],
+            e[14], e[15],
+        ))
+    } else {
+        Err(PointsViewerError::BadRequest(
+            "Parsing Error: Expected matrix with 16 elements".to_string(),
+        ))
+    }
 }
 
 /// Method that returns visible nodes
@@ -23,39 +97,45 @@ pub fn get_visible_nodes(
     match get_octree_from_state(&octree_id.into_inner(), &state) {
         Err(err) => HttpResponse::from_error(err.into()),
         Ok(octree) => {
-            let matrix = {
-                // Entries are column major.
-                let e: Vec<f64> = matrix_query
-                    .matrix
-                    .split(',')
-                    .map(|s| s.parse::<f64>().unwrap())
-                    .collect();
-                // matrix size check
-                if 16 == e.len() {
-                    Matrix4::new(
-                        e[0], e[1], e[2], e[3], e[4], e[5], e[6], e[7], e[8], e[9], e[10], e[11],
-                        e[12], e[13], e[14], e[15],
-                    )
-                } else {
-                    return HttpResponse::from_error(
-                        PointsViewerError::BadRequest(
-                            "Parsing Error: Expected matrix with 16 elements".to_string(),
-                        )
-                        .into(),
-                    );
-                }
+            let matrix = match parse_matrix(&matrix_query.matrix) {
+                Ok(matrix) => matrix,
+                Err(err) => return HttpResponse::from_error(err.into()),
             };
 
-            let visible_nodes = octree.get_visible_nodes(&matrix);
+            let mut metric = ScreenSpaceError::new(
+                matrix_query.width.unwrap_or(octree::DEFAULT_VIEWPORT_WIDTH),
+                matrix_query
+                    .height
+                    .unwrap_or(octree::DEFAULT_VIEWPORT_HEIGHT),
+            );
+            if let Some(pixel_threshold) = matrix_query.pixel_threshold {
+                metric = metric.with_pixel_threshold(pixel_threshold);
+            }
+            let mut visible_nodes =
+                octree.get_visible_nodes_with_screen_space_error(&matrix, &metric);
+            if matrix_query.backface_culling {
+                if let Some(eye) = octree::camera_position(&matrix) {
+                    visible_nodes.retain(|(id, _)| !octree.is_back_facing(id, &eye));
+                }
+            }
             let mut reply = String::from("[");
             let visible_nodes_string = visible_nodes
                 .iter()
-                .map(|id| format!("\"{}\"", id))
+                .map(|(id, screen_space_error)| {
+                    if matrix_query.priorities {
+                        // JSON has no infinity.
+                        let priority = screen_space_error.min(std::f64::MAX);
+                        format!("{{\"id\":\"{}\",\"priority\":{}}}", id, priority)
+                    } else {
+                        format!("\"{}\"", id)
+                    }
+                })
                 .collect::<Vec<_>>()
                 .join(",");
             reply.push_str(&visible_nodes_string);
             reply.push(']');
 
+            metrics::record_request("visible_nodes", reply.len());
             HttpResponse::Ok()
                 .content_type("application/json")
                 .body(reply)
@@ -74,6 +154,94 @@ fn pad(input: &mut Vec<u8>) {
     }
 }
 
+/// Appends the binary representation of a single node to 'reply_blob'. This is the layout the
+/// client parses in 'NodeLoader'. The byte after the bytes per coordinate flags the 'columns'
+/// that follow: 1 for the positions and 2 for the colors. The other columns are left out.
+pub(crate) fn write_node_data(
+    reply_blob: &mut Vec<u8>,
+    node_data: &mut octree::NodeData,
+    columns: NodeColumns,
+) {
+    // Write the bounding box information.
+    let min = node_data.meta.bounding_cube.min();
+    reply_blob.write_f64::<LittleEndian>(min.x).unwrap();
+    reply_blob.write_f64::<LittleEndian>(min.y).unwrap();
+    reply_blob.write_f64::<LittleEndian>(min.z).unwrap();
+    reply_blob
+        .write_f64::<LittleEndian>(node_data.meta.bounding_cube.edge_length())
+        .unwrap();
+
+    // Number of points.
+    reply_blob
+        .write_u32::<LittleEndian>(node_data.meta.num_points as u32)
+        .unwrap();
+
+    // Position encoding.
+    let bytes_per_coordinate = node_data.meta.position_encoding.bytes_per_coordinate();
+    reply_blob.write_u8(bytes_per_coordinate as u8).unwrap();
+    reply_blob
+        .write_u8(u8::from(columns.position) | u8::from(columns.color) << 1)
+        .unwrap();
+    pad(reply_blob);
+
+    if columns.position {
+        assert!(
+            bytes_per_coordinate * node_data.meta.num_points as usize * 3
+                == node_data.position.len()
+        );
+        reply_blob.append(&mut node_data.position);
+        pad(reply_blob);
+    }
+
+    if columns.color {
+        assert!(node_data.meta.num_points as usize * 3 == node_data.color.len());
+        reply_blob.append(&mut node_data.color);
+        pad(reply_blob);
+    }
+}
+
+/// Parses the comma separated columns of a '/nodes_batch' request, e.g. "position".
+fn parse_columns(columns: &str) -> Result<NodeColumns, PointsViewerError> {
+    let mut parsed = NodeColumns {
+        position: false,
+        color: false,
+    };
+    for column in columns.split(',') {
+        match column.trim() {
+            "position" => parsed.position = true,
+            "color" => parsed.color = true,
+            column => {
+                return Err(PointsViewerError::BadRequest(format!(
+                    "Unknown column '{}'.",
+                    column
+                )))
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// The ETag of a node is a hash of the served data, so it changes whenever the node does, e.g.
+/// when the octree is rebuilt in place.
+fn node_etag(reply_blob: &[u8]) -> String {
+    // FNV is used instead of the std hasher, because its output is stable across builds and
+    // therefore across server restarts.
+    let mut hasher = FnvHasher::default();
+    hasher.write(reply_blob);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Returns true if any of the ETags in the 'If-None-Match' header of the request matches 'etag'.
+/// 'If-None-Match' uses the weak comparison, so 'W/' prefixes are ignored.
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
 fn get_octree_from_state(
     octree_id: impl AsRef<str>,
     state: &web::Data<Arc<AppState>>,
@@ -86,19 +254,33 @@ fn get_octree_from_state(
     })
 }
 
+/// The filter of the access policy that applies to the client of 'req', see
+/// 'AppState::node_filter'.
+fn node_filter_for(
+    state: &AppState,
+    octree_id: &str,
+    req: &HttpRequest,
+) -> Option<Arc<dyn NodeFilter>> {
+    let quotas = state.quotas();
+    let client = quotas.identify(req);
+    state.node_filter(octree_id, quotas.name(&client))
+}
+
 /// Asynchronous Handler to get Node Data
 pub async fn get_nodes_data(
-    (octree_id, state, nodes): (
+    (octree_id, state, nodes, req): (
         web::Path<String>,
         web::Data<Arc<AppState>>,
         web::Json<Vec<String>>,
+        HttpRequest,
     ),
 ) -> HttpResponse {
     let start = time::Instant::now();
     let data: Vec<String> = web::Json::into_inner(nodes);
-    let nodes_to_load = data
+    let nodes_to_load: Vec<octree::NodeId> = data
         .into_iter()
-        .map(|e| octree::NodeId::from_str(e.as_str()).unwrap());
+        .map(|e| octree::NodeId::from_str(e.as_str()).unwrap())
+        .collect();
 
     // So this is godawful: We need to get data to the GPU without JavaScript herp-derping with
     // it - because that will stall interaction. The straight forward approach would be to ship
@@ -111,10 +293,19 @@ pub async fn get_nodes_data(
 
     let mut num_nodes_fetched = 0;
     let mut num_points = 0;
-    let octree: Arc<octree::Octree> =
-        get_octree_from_state(&octree_id.into_inner(), &state).unwrap();
-    for node_id in nodes_to_load {
-        let mut node_data = match octree.get_node_data(&node_id) {
+    let octree_id = octree_id.into_inner();
+    let octree: Arc<octree::Octree> = get_octree_from_state(&octree_id, &state).unwrap();
+    let filter = node_filter_for(&state, &octree_id, &req);
+    // All nodes are requested at once, so that the data provider can read them concurrently.
+    let timer = metrics::NODE_READ_SECONDS.start_timer();
+    let nodes_data = octree.get_nodes_data(&nodes_to_load);
+    timer.observe_duration();
+    for (node_id, node_data) in nodes_to_load.iter().zip(nodes_data) {
+        let node_data = node_data.map(|mut node_data| {
+            srgb_colors(&octree, &mut node_data);
+            node_data
+        });
+        let mut node_data = match filter_node(filter.as_deref(), node_id, node_data) {
             Ok(node_data) => node_data,
             Err(_) => {
                 return HttpResponse::from_error(
@@ -127,45 +318,18 @@ pub async fn get_nodes_data(
             }
         };
 
-        // Write the bounding box information.
-        let min = node_data.meta.bounding_cube.min();
-        reply_blob.write_f64::<LittleEndian>(min.x).unwrap();
-        reply_blob.write_f64::<LittleEndian>(min.y).unwrap();
-        reply_blob.write_f64::<LittleEndian>(min.z).unwrap();
-        reply_blob
-            .write_f64::<LittleEndian>(node_data.meta.bounding_cube.edge_length())
-            .unwrap();
-
-        // Number of points.
-        reply_blob
-            .write_u32::<LittleEndian>(node_data.meta.num_points as u32)
-            .unwrap();
-
-        // Position encoding.
-        let bytes_per_coordinate = node_data.meta.position_encoding.bytes_per_coordinate();
-        reply_blob.write_u8(bytes_per_coordinate as u8).unwrap();
-        assert!(
-            bytes_per_coordinate * node_data.meta.num_points as usize * 3
-                == node_data.position.len()
-        );
-        assert!(node_data.meta.num_points as usize * 3 == node_data.color.len());
-        pad(&mut reply_blob);
-
-        reply_blob.append(&mut node_data.position);
-        pad(&mut reply_blob);
-
-        reply_blob.append(&mut node_data.color);
-        pad(&mut reply_blob);
+        write_node_data(&mut reply_blob, &mut node_data, NodeColumns::ALL);
 
         num_nodes_fetched += 1;
         num_points += node_data.meta.num_points;
     }
 
     let duration_ms = start.elapsed().as_seconds_f64() * 1_000.;
-    eprintln!(
+    debug!(
         "Got {} nodes with {} points ({}ms).",
         num_nodes_fetched, num_points, duration_ms
     );
+    metrics::record_request("nodes_data", reply_blob.len());
 
     HttpResponse::Ok()
         .content_type("application/octet-stream")
@@ -175,3 +339,681 @@ pub async fn get_nodes_data(
         .encoding(ContentEncoding::Identity)
         .body(reply_blob)
 }
+
+/// Handler to get the data of a single node. In contrast to 'get_nodes_data', the URL of a node
+/// is stable, so the response can be cached by browsers and CDNs and is revalidated using ETags.
+pub fn get_node_data(
+    (path, state, req): (
+        web::Path<(String, String)>,
+        web::Data<Arc<AppState>>,
+        HttpRequest,
+    ),
+) -> HttpResponse {
+    let (octree_id, node_id) = path.into_inner();
+    let octree = match get_octree_from_state(&octree_id, &state) {
+        Ok(octree) => octree,
+        Err(err) => return HttpResponse::from_error(err.into()),
+    };
+    let node_id = match NodeId::from_str(&node_id) {
+        Ok(node_id) => node_id,
+        Err(_) => {
+            return HttpResponse::from_error(
+                PointsViewerError::BadRequest(format!("Invalid node id {}.", node_id)).into(),
+            );
+        }
+    };
+    let filter = node_filter_for(&state, &octree_id, &req);
+    let timer = metrics::NODE_READ_SECONDS.start_timer();
+    let node_data = octree.get_node_data(&node_id);
+    timer.observe_duration();
+    let node_data = node_data.map(|mut node_data| {
+        srgb_colors(&octree, &mut node_data);
+        node_data
+    });
+    let mut node_data = match filter_node(filter.as_deref(), &node_id, node_data) {
+        Ok(node_data) => node_data,
+        Err(_) => {
+            return HttpResponse::from_error(
+                PointsViewerError::NotFound(format!("Could not get node {}.", node_id)).into(),
+            );
+        }
+    };
+    let mut reply_blob = Vec::<u8>::new();
+    write_node_data(&mut reply_blob, &mut node_data, NodeColumns::ALL);
+
+    // The node has to be read to compute its ETag, a match only saves sending it.
+    let etag = node_etag(&reply_blob);
+    // Shared caches must not hand the node data of one client to another.
+    let cache_control = format!(
+        "{}, max-age={}",
+        if state.filters_nodes() {
+            "private"
+        } else {
+            "public"
+        },
+        state.cache_max_age()
+    );
+    let not_modified = etag_matches(&req, &etag);
+    metrics::record_cache_lookup("node", not_modified);
+    if not_modified {
+        metrics::record_request("node_data", 0);
+        return HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .finish();
+    }
+
+    metrics::record_request("node_data", reply_blob.len());
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, cache_control)
+        .encoding(ContentEncoding::Identity)
+        .body(reply_blob)
+}
+
+/// Handler that lists the octrees next to the served one as JSON, optionally only those that
+/// intersect 'bbox' and have the coordinate reference system 'crs'.
+pub fn get_datasets(
+    (state, datasets_query): (web::Data<Arc<AppState>>, web::Query<DatasetsInfo>),
+) -> HttpResponse {
+    let region = match datasets_query.bbox.as_ref().map(|bbox| Region::parse(bbox)) {
+        Some(Ok(region)) => Some(region),
+        Some(Err(message)) => {
+            return HttpResponse::from_error(PointsViewerError::BadRequest(message).into())
+        }
+        None => None,
+    };
+    let catalog = match state.catalog() {
+        Ok(catalog) => catalog,
+        Err(err) => return HttpResponse::from_error(err.into()),
+    };
+    let datasets = match &region {
+        Some(region) => catalog.intersecting(region),
+        None => catalog.datasets().iter().collect(),
+    };
+    let datasets: Vec<_> = datasets
+        .into_iter()
+        .filter(|dataset| match &datasets_query.crs {
+            Some(crs) => dataset.crs.as_ref() == Some(crs),
+            None => true,
+        })
+        .collect();
+    let reply = match serde_json::to_string(&datasets) {
+        Ok(reply) => reply,
+        Err(err) => {
+            return HttpResponse::from_error(
+                PointsViewerError::InternalServerError(err.to_string()).into(),
+            )
+        }
+    };
+
+    metrics::record_request("datasets", reply.len());
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(reply)
+}
+
+/// Handler that renders the octree as seen through 'matrix' into a PNG. Without a matrix, an
+/// overview of the whole octree is rendered.
+pub async fn get_thumbnail(
+    (octree_id, state, thumbnail_query, req): (
+        web::Path<String>,
+        web::Data<Arc<AppState>>,
+        web::Query<ThumbnailInfo>,
+        HttpRequest,
+    ),
+) -> HttpResponse {
+    let octree_id = octree_id.into_inner();
+    let octree = match get_octree_from_state(&octree_id, &state) {
+        Ok(octree) => octree,
+        Err(err) => return HttpResponse::from_error(err.into()),
+    };
+    let width = thumbnail_query.width.unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+    let height = thumbnail_query.height.unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+    if width == 0 || height == 0 || width > MAX_THUMBNAIL_SIZE || height > MAX_THUMBNAIL_SIZE {
+        return HttpResponse::from_error(
+            PointsViewerError::BadRequest(format!(
+                "Thumbnail size must be between 1 and {} pixels.",
+                MAX_THUMBNAIL_SIZE
+            ))
+            .into(),
+        );
+    }
+    let matrix = match &thumbnail_query.matrix {
+        Some(matrix) => match parse_matrix(matrix) {
+            Ok(matrix) => matrix,
+            Err(err) => return HttpResponse::from_error(err.into()),
+        },
+        None => thumbnail::default_overview_matrix(&octree, width, height),
+    };
+
+    // Rendering reads up to the thumbnail point budget, so it runs on the thread pool for
+    // blocking work instead of stalling the other requests of this worker.
+    let point_budget = state.thumbnail_point_budget();
+    let filter = node_filter_for(&state, &octree_id, &req);
+    let png = web::block(move || -> Result<Vec<u8>, PointsViewerError> {
+        let image = thumbnail::render(
+            &octree,
+            &matrix,
+            width,
+            height,
+            point_budget,
+            filter.as_deref(),
+        )?;
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .map_err(|err| PointsViewerError::InternalServerError(err.to_string()))?;
+        Ok(png)
+    })
+    .await;
+    match png {
+        Ok(png) => {
+            metrics::record_request("thumbnail", png.len());
+            HttpResponse::Ok().content_type("image/png").body(png)
+        }
+        Err(BlockingError::Error(err)) => HttpResponse::from_error(err.into()),
+        Err(BlockingError::Canceled) => HttpResponse::from_error(
+            PointsViewerError::InternalServerError("Rendering was canceled.".to_string()).into(),
+        ),
+    }
+}
+
+/// Handler that renders a top-down density or elevation map of the whole octree into a PNG, see
+/// 'render_overview'. The headers 'X-Overview-Min-X', 'X-Overview-Max-Y' and
+/// 'X-Overview-Pixel-Size' place the image in the coordinates of the octree, e.g. for a minimap.
+/// It shows no details that the access policy of the client hides.
+pub fn get_overview(
+    (octree_id, state, overview_query, req): (
+        web::Path<String>,
+        web::Data<Arc<AppState>>,
+        web::Query<OverviewInfo>,
+        HttpRequest,
+    ),
+) -> HttpResponse {
+    let octree_id = octree_id.into_inner();
+    let octree = match get_octree_from_state(&octree_id, &state) {
+        Ok(octree) => octree,
+        Err(err) => return HttpResponse::from_error(err.into()),
+    };
+    let kind = match overview_query
+        .kind
+        .as_deref()
+        .unwrap_or("density")
+        .parse::<OverviewKind>()
+    {
+        Ok(kind) => kind,
+        Err(err) => return HttpResponse::from_error(PointsViewerError::BadRequest(err).into()),
+    };
+    let size = overview_query.size.unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+    if size == 0 || size > MAX_THUMBNAIL_SIZE {
+        return HttpResponse::from_error(
+            PointsViewerError::BadRequest(format!(
+                "Overview size must be between 1 and {} pixels.",
+                MAX_THUMBNAIL_SIZE
+            ))
+            .into(),
+        );
+    }
+
+    let min_detail = node_filter_for(&state, &octree_id, &req).map_or(0., |f| f.min_detail());
+    let overview = match render_overview_with_min_detail(&octree, size, kind, min_detail) {
+        Ok(overview) => overview,
+        Err(err) => return HttpResponse::from_error(PointsViewerError::from(err).into()),
+    };
+    let (min_x, max_y, pixel_size) = (overview.min_x(), overview.max_y(), overview.pixel_size());
+    let mut png = Vec::new();
+    if let Err(err) =
+        DynamicImage::ImageRgba8(overview.image).write_to(&mut png, ImageOutputFormat::Png)
+    {
+        return HttpResponse::from_error(
+            PointsViewerError::InternalServerError(err.to_string()).into(),
+        );
+    }
+
+    metrics::record_request("overview", png.len());
+    HttpResponse::Ok()
+        .content_type("image/png")
+        .header("X-Overview-Min-X", min_x.to_string())
+        .header("X-Overview-Max-Y", max_y.to_string())
+        .header("X-Overview-Pixel-Size", pixel_size.to_string())
+        .body(png)
+}
+
+/// Maximum size of the JSON body of a '/nodes_batch' request, enough for tens of thousands of
+/// node ids.
+pub const MAX_NODES_BATCH_REQUEST_BYTES: usize = 1 << 20;
+
+/// Number of nodes that a '/nodes_batch' request reads at once.
+const NODES_PER_READ: usize = 16;
+
+/// A node in a '/nodes_batch' request: its id, or its id and the priority of reading it, e.g. its
+/// screen-space error. Nodes with a higher priority are read first; the default is 0.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum NodeRequest {
+    Id(String),
+    WithPriority { id: String, priority: f64 },
+}
+
+impl NodeRequest {
+    fn id(&self) -> &str {
+        match self {
+            NodeRequest::Id(id) | NodeRequest::WithPriority { id, .. } => id,
+        }
+    }
+
+    fn priority(&self) -> f64 {
+        match self {
+            NodeRequest::Id(_) => 0.,
+            NodeRequest::WithPriority { priority, .. } => *priority,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NodesBatchInfo {
+    /// 'source_id' colors the points by the input file they come from instead of their color,
+    /// 'deviation' by their distance from the octree 'reference'.
+    color_by: Option<String>,
+    /// The octree to compare with for 'color_by=deviation'.
+    reference: Option<String>,
+    /// The deviation in meters that gets the most saturated colors, see
+    /// 'DeviationOptions::max_distance'.
+    max_deviation: Option<f64>,
+    /// Only returns the points of the frame with this index, for octrees built with timestamps.
+    frame: Option<u32>,
+    /// Comma separated columns to return, 'position' and 'color' by default. Clients can request
+    /// the positions of nodes first and their colors later.
+    columns: Option<String>,
+}
+
+/// Converts the colors of 'node_data' to sRGB, which the client shows, see 'Octree::color_space'.
+pub(crate) fn srgb_colors(octree: &Octree, node_data: &mut octree::NodeData) {
+    octree
+        .color_space()
+        .convert_u8(ColorSpace::Srgb, &mut node_data.color);
+}
+
+/// How 'get_nodes_batch' colors the points.
+#[derive(Clone)]
+enum PointColors {
+    Original,
+    Source,
+    /// By the deviation from the points of another octree.
+    Deviation(Arc<Octree>, DeviationOptions),
+}
+
+/// Replaces the colors of 'node_data' with the colors of its source ids, see
+/// 'Octree::get_node_source_colors'.
+fn color_by_source(
+    octree: &Octree,
+    node_id: &NodeId,
+    node_data: point_viewer::errors::Result<octree::NodeData>,
+) -> point_viewer::errors::Result<octree::NodeData> {
+    let mut node_data = node_data?;
+    node_data.color = octree.get_node_source_colors(node_id)?;
+    Ok(node_data)
+}
+
+/// Replaces the colors of 'node_data' with the colors of its deviation from 'reference', see
+/// 'Octree::get_node_deviation_colors'.
+fn color_by_deviation(
+    octree: &Octree,
+    node_id: &NodeId,
+    node_data: point_viewer::errors::Result<octree::NodeData>,
+    reference: &Octree,
+    options: &DeviationOptions,
+) -> point_viewer::errors::Result<octree::NodeData> {
+    let mut node_data = node_data?;
+    node_data.color = octree.get_node_deviation_colors(node_id, reference, options)?;
+    Ok(node_data)
+}
+
+/// Keeps only the points of the frame 'frame_index' in 'node_data', see 'Octree::retain_frame'.
+fn retain_frame(
+    octree: &Octree,
+    node_id: &NodeId,
+    node_data: point_viewer::errors::Result<octree::NodeData>,
+    frame_index: u32,
+) -> point_viewer::errors::Result<octree::NodeData> {
+    let mut node_data = node_data?;
+    octree.retain_frame(node_id, &mut node_data, frame_index)?;
+    Ok(node_data)
+}
+
+/// Applies the 'filter' of the access policy of the request to 'node_data', see 'NodeFilter'.
+fn filter_node(
+    filter: Option<&dyn NodeFilter>,
+    node_id: &NodeId,
+    node_data: point_viewer::errors::Result<octree::NodeData>,
+) -> point_viewer::errors::Result<octree::NodeData> {
+    let mut node_data = node_data?;
+    if let Some(filter) = filter {
+        filter.filter(node_id, &mut node_data)?;
+    }
+    Ok(node_data)
+}
+
+/// Handler that returns the timestamps of the frames of an octree built with timestamps as a
+/// JSON array. The index of a timestamp is the 'frame' parameter of '/nodes_batch'.
+pub fn get_frames(
+    (octree_id, state): (web::Path<String>, web::Data<Arc<AppState>>),
+) -> HttpResponse {
+    let octree = match get_octree_from_state(&octree_id.into_inner(), &state) {
+        Ok(octree) => octree,
+        Err(err) => return HttpResponse::from_error(err.into()),
+    };
+    let reply = match serde_json::to_string(octree.frame_timestamps()) {
+        Ok(reply) => reply,
+        Err(err) => {
+            return HttpResponse::from_error(
+                PointsViewerError::InternalServerError(err.to_string()).into(),
+            )
+        }
+    };
+
+    metrics::record_request("frames", reply.len());
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(reply)
+}
+
+/// Handler that returns the layers of the vector overlays of the server as JSON, see
+/// 'point_viewer::overlay'. Every layer has a 'name' and 'polylines' with their 'points' as
+/// '[x, y, z]' and whether they are 'closed'.
+pub fn get_overlays(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let reply = match serde_json::to_string(&**state.overlays()) {
+        Ok(reply) => reply,
+        Err(err) => {
+            return HttpResponse::from_error(
+                PointsViewerError::InternalServerError(err.to_string()).into(),
+            )
+        }
+    };
+
+    metrics::record_request("overlays", reply.len());
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(reply)
+}
+
+/// The pose of a camera, with the rotation as a quaternion '[x, y, z, w]'.
+#[derive(Serialize)]
+pub(crate) struct CameraPose {
+    translation: [f64; 3],
+    rotation: [f64; 4],
+}
+
+/// The JSON of 'ViewSettings'. Settings that are not set are null.
+#[derive(Serialize)]
+pub(crate) struct ViewSettingsReply {
+    pub camera: Option<CameraPose>,
+    pub up_axis: &'static str,
+    pub color_mode: &'static str,
+    pub point_budget: Option<u64>,
+}
+
+impl ViewSettingsReply {
+    pub fn new(octree: &Octree) -> Self {
+        let view_settings = octree.view_settings();
+        ViewSettingsReply {
+            camera: view_settings.octree_from_camera.map(|camera| {
+                let (t, q) = (camera.translation.vector, camera.rotation.coords);
+                CameraPose {
+                    translation: [t.x, t.y, t.z],
+                    rotation: [q.x, q.y, q.z, q.w],
+                }
+            }),
+            up_axis: view_settings.up_axis.name(),
+            color_mode: view_settings.color_mode.name(),
+            point_budget: view_settings.point_budget,
+        }
+    }
+}
+
+/// Handler that returns the settings that the client starts with for an octree as JSON, see
+/// 'Octree::view_settings'.
+pub fn get_view_settings(
+    (octree_id, state): (web::Path<String>, web::Data<Arc<AppState>>),
+) -> HttpResponse {
+    let octree = match get_octree_from_state(&octree_id.into_inner(), &state) {
+        Ok(octree) => octree,
+        Err(err) => return HttpResponse::from_error(err.into()),
+    };
+    let view_settings = ViewSettingsReply::new(&octree);
+    let reply = match serde_json::to_string(&view_settings) {
+        Ok(reply) => reply,
+        Err(err) => {
+            return HttpResponse::from_error(
+                PointsViewerError::InternalServerError(err.to_string()).into(),
+            )
+        }
+    };
+
+    metrics::record_request("view_settings", reply.len());
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(reply)
+}
+
+/// Status of a node in a '/nodes_batch' response.
+#[derive(Clone, Copy)]
+enum NodeStatus {
+    Ok = 0,
+    InvalidId = 1,
+    NotFound = 2,
+}
+
+/// Asynchronous handler to get the data of many nodes in one response. Unlike in 'get_nodes_data',
+/// every node is framed with its id, status and length, so nodes that cannot be loaded do not fail
+/// the request, and clients can dispatch nodes without parsing them. The nodes are read in the
+/// order of their priority, see 'NodeRequest'. With 'color_by=source_id', the points are colored
+/// by their source id, with 'color_by=deviation&reference=<octree_id>' by their signed distance
+/// from the points of another octree, and with 'frame=<index>', only the points of this frame are
+/// returned.
+/// 'columns=position' or 'columns=color' only returns one of the columns. All values of the
+/// response are little endian:
+///
+/// - The number of nodes as u32, padded to 8 bytes.
+/// - For every requested node, in request order: the length of its id as u16, its status as u8,
+///   one byte of padding and the length of its data as u32, followed by the id, padded to 8 bytes,
+///   and the data in the layout of 'write_node_data', which is empty unless the status is 'Ok'.
+pub async fn get_nodes_batch(
+    (octree_id, state, nodes, batch_query, req): (
+        web::Path<String>,
+        web::Data<Arc<AppState>>,
+        web::Json<Vec<NodeRequest>>,
+        web::Query<NodesBatchInfo>,
+        HttpRequest,
+    ),
+) -> HttpResponse {
+    let start = time::Instant::now();
+    let point_colors = match batch_query.color_by.as_deref() {
+        None => PointColors::Original,
+        Some("source_id") => PointColors::Source,
+        Some("deviation") => {
+            let reference_id = match batch_query.reference.as_deref() {
+                Some(reference_id) => reference_id,
+                None => {
+                    return HttpResponse::from_error(
+                        PointsViewerError::BadRequest(
+                            "Coloring by deviation needs a 'reference' octree.".to_string(),
+                        )
+                        .into(),
+                    )
+                }
+            };
+            // The colors would reveal the points of the reference that its policy filters out.
+            if node_filter_for(&state, reference_id, &req).is_some() {
+                return HttpResponse::Forbidden().json(
+                    "The access policy of the reference octree does not allow comparing with it.",
+                );
+            }
+            let reference = match get_octree_from_state(reference_id, &state) {
+                Ok(reference) => reference,
+                Err(err) => return HttpResponse::from_error(err.into()),
+            };
+            let mut options = DeviationOptions::default();
+            if let Some(max_deviation) = batch_query.max_deviation {
+                if !max_deviation.is_finite() || max_deviation <= 0. {
+                    return HttpResponse::from_error(
+                        PointsViewerError::BadRequest(
+                            "'max_deviation' must be positive.".to_string(),
+                        )
+                        .into(),
+                    );
+                }
+                options.max_distance = max_deviation;
+            }
+            PointColors::Deviation(reference, options)
+        }
+        Some(color_by) => {
+            return HttpResponse::from_error(
+                PointsViewerError::BadRequest(format!("Cannot color by '{}'.", color_by)).into(),
+            )
+        }
+    };
+    let frame = batch_query.frame;
+    let columns = match batch_query.columns.as_deref().map(parse_columns) {
+        None => NodeColumns::ALL,
+        Some(Ok(columns)) => columns,
+        Some(Err(err)) => return HttpResponse::from_error(err.into()),
+    };
+    let requests: Vec<NodeRequest> = web::Json::into_inner(nodes);
+    let octree_id = octree_id.into_inner();
+    let octree = match get_octree_from_state(&octree_id, &state) {
+        Ok(octree) => octree,
+        Err(err) => return HttpResponse::from_error(err.into()),
+    };
+    let filter = node_filter_for(&state, &octree_id, &req);
+    if matches!(point_colors, PointColors::Source)
+        && filter
+            .as_ref()
+            .map_or(false, |filter| !filter.allows_source_ids())
+    {
+        return HttpResponse::Forbidden()
+            .json("The access policy of this octree does not allow coloring by source id.");
+    }
+    let node_ids: Vec<Option<NodeId>> = requests
+        .iter()
+        .map(|request| NodeId::from_str(request.id()).ok())
+        .collect();
+
+    // Nodes are read in chunks by decreasing priority, and every chunk waits for the scheduler,
+    // so that urgent nodes of other requests are read in between.
+    let mut read_order: Vec<usize> = (0..requests.len())
+        .filter(|index| node_ids[*index].is_some())
+        .collect();
+    read_order.sort_by(|a, b| {
+        requests[*b]
+            .priority()
+            .partial_cmp(&requests[*a].priority())
+            .unwrap_or(Ordering::Equal)
+    });
+    let mut nodes_data: Vec<Option<point_viewer::errors::Result<octree::NodeData>>> =
+        requests.iter().map(|_| None).collect();
+    for chunk in read_order.chunks(NODES_PER_READ) {
+        let _permit = state
+            .read_scheduler()
+            .acquire(requests[chunk[0]].priority())
+            .await;
+        let octree = Arc::clone(&octree);
+        let filter = filter.clone();
+        let point_colors = point_colors.clone();
+        let nodes_to_load: Vec<NodeId> = chunk.iter().map(|i| node_ids[*i].unwrap()).collect();
+        let timer = metrics::NODE_READ_SECONDS.start_timer();
+        let chunk_data = web::block(move || {
+            let chunk_data = octree
+                .get_nodes_columns(&nodes_to_load, columns)
+                .into_iter()
+                .zip(&nodes_to_load)
+                .map(|(node_data, node_id)| {
+                    // Colors are replaced before the points are filtered, so that they match.
+                    let node_data = if !columns.color {
+                        node_data
+                    } else {
+                        match &point_colors {
+                            PointColors::Original => node_data.map(|mut node_data| {
+                                srgb_colors(&octree, &mut node_data);
+                                node_data
+                            }),
+                            PointColors::Source => color_by_source(&octree, node_id, node_data),
+                            PointColors::Deviation(reference, options) => {
+                                color_by_deviation(&octree, node_id, node_data, reference, options)
+                            }
+                        }
+                    };
+                    let node_data = match frame {
+                        Some(frame_index) => retain_frame(&octree, node_id, node_data, frame_index),
+                        None => node_data,
+                    };
+                    filter_node(filter.as_deref(), node_id, node_data)
+                })
+                .collect();
+            Ok::<_, ()>(chunk_data)
+        });
+        let chunk_data = match chunk_data.await {
+            Ok(chunk_data) => chunk_data,
+            Err(err) => {
+                return HttpResponse::from_error(
+                    PointsViewerError::InternalServerError(err.to_string()).into(),
+                );
+            }
+        };
+        timer.observe_duration();
+        for (index, node_data) in chunk.iter().zip(chunk_data) {
+            nodes_data[*index] = Some(node_data);
+        }
+    }
+
+    let mut reply_blob = Vec::<u8>::new();
+    reply_blob
+        .write_u32::<LittleEndian>(requests.len() as u32)
+        .unwrap();
+    pad(&mut reply_blob);
+    let mut num_nodes_fetched = 0;
+    let mut num_points = 0;
+    for (request, node_data) in requests.iter().zip(nodes_data) {
+        let name = request.id();
+        let mut node_blob = Vec::<u8>::new();
+        let status = match node_data {
+            None => NodeStatus::InvalidId,
+            Some(Err(_)) => NodeStatus::NotFound,
+            Some(Ok(mut node_data)) => {
+                write_node_data(&mut node_blob, &mut node_data, columns);
+                num_nodes_fetched += 1;
+                num_points += node_data.meta.num_points;
+                NodeStatus::Ok
+            }
+        };
+        // The body limit keeps ids far below 64 KiB.
+        reply_blob
+            .write_u16::<LittleEndian>(name.len() as u16)
+            .unwrap();
+        reply_blob.write_u8(status as u8).unwrap();
+        reply_blob.write_u8(0).unwrap();
+        reply_blob
+            .write_u32::<LittleEndian>(node_blob.len() as u32)
+            .unwrap();
+        reply_blob.extend_from_slice(name.as_bytes());
+        pad(&mut reply_blob);
+        reply_blob.append(&mut node_blob);
+    }
+
+    let duration_ms = start.elapsed().as_seconds_f64() * 1_000.;
+    debug!(
+        "Got {} of {} nodes with {} points ({}ms).",
+        num_nodes_fetched,
+        requests.len(),
+        num_points,
+        duration_ms
+    );
+    metrics::record_request("nodes_batch", reply_blob.len());
+
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .encoding(ContentEncoding::Identity)
+        .body(reply_blob)
+}
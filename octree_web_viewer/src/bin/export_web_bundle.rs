@@ -0,0 +1,65 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::Clap;
+use octree_web_viewer::bundle::{export_bundle, BundleOptions};
+use point_viewer::data_provider::DataProviderFactory;
+use point_viewer::octree::Octree;
+use std::path::PathBuf;
+
+/// Exports an octree with the web viewer into a directory that any static web server can serve.
+#[derive(Clap, Debug)]
+#[clap(name = "export_web_bundle")]
+struct CommandLineArguments {
+    /// The octree directory to export.
+    #[clap(parse(from_os_str))]
+    octree_path: PathBuf,
+    /// The directory to write the bundle to.
+    #[clap(parse(from_os_str))]
+    output_directory: PathBuf,
+    /// Only export the nodes up to this level, to keep the bundle small.
+    #[clap(long)]
+    max_level: Option<u8>,
+    /// Embed the point data into a script, so that the bundle can also be opened from the file
+    /// system without a web server.
+    #[clap(long)]
+    embed: bool,
+}
+
+fn main() {
+    let args = CommandLineArguments::parse();
+    let octree_argument = args.octree_path.to_string_lossy();
+    let octree = DataProviderFactory::new()
+        .generate_data_provider(&octree_argument)
+        .and_then(Octree::from_data_provider)
+        .unwrap_or_else(|err| panic!("Could not open octree '{}': {}", octree_argument, err));
+    let octree_id = args
+        .octree_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| octree_argument.to_string());
+    let options = BundleOptions {
+        max_level: args.max_level,
+        embed_data: args.embed,
+    };
+    let stats = export_bundle(&octree, &octree_id, &args.output_directory, &options)
+        .unwrap_or_else(|err| panic!("Could not export the bundle: {}", err));
+    println!(
+        "Exported {} nodes with {} points ({} bytes of point data) to {}.",
+        stats.num_nodes,
+        stats.num_points,
+        stats.num_bytes,
+        args.output_directory.display()
+    );
+}
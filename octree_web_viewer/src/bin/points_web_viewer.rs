@@ -13,61 +13,227 @@
 // limitations under the License.
 
 use clap::Clap;
+use log::info;
 use octree_web_viewer::backend_error::PointsViewerError;
-use octree_web_viewer::state::AppState;
+use octree_web_viewer::policy::AccessPolicy;
+use octree_web_viewer::quota::{ClientLimits, ClientQuota, Quotas};
+use octree_web_viewer::state::{
+    AppState, Settings, DEFAULT_CACHE_MAX_AGE, DEFAULT_CATALOG_MAX_AGE,
+    DEFAULT_MAX_CONCURRENT_READS, DEFAULT_THUMBNAIL_POINT_BUDGET,
+};
+use octree_web_viewer::tls::TlsConfig;
 use octree_web_viewer::utils::start_octree_server;
+use point_viewer::config::load_config;
 use point_viewer::data_provider::DataProviderFactory;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use point_viewer::data_provider::IoUringDataProvider;
+use point_viewer::overlay::read_overlay;
+use serde_derive::Deserialize;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 /// HTTP web viewer for 3d points stored in OnDiskOctrees
-#[derive(Clap, Debug)]
+#[derive(Clap, Clone, Debug)]
 #[clap(name = "points_web_viewer", about = "Visualizing points")]
 pub struct CommandLineArguments {
-    /// The octree directory to serve, including a trailing slash.
+    /// The octree directory to serve, including a trailing slash. Required unless the config
+    /// file sets 'dataset'.
     #[clap(name = "DIR", parse(from_os_str))]
-    octree_path: PathBuf,
-    /// Port to listen on.
-    #[clap(default_value = "5433")]
-    port: u16,
-    /// IP string.
-    #[clap(default_value = "127.0.0.1")]
+    octree_path: Option<PathBuf>,
+    /// Port to listen on. [default: 5433]
+    port: Option<u16>,
+    /// IP string. [default: 127.0.0.1]
+    ip: Option<String>,
+    /// Number of octrees to keep in memory. [default: 100]
+    cache_items: Option<usize>,
+    /// Seconds browsers and CDNs may cache node data before revalidating it. [default: 86400]
+    #[clap(long)]
+    cache_max_age: Option<u32>,
+    /// GeoJSON or DXF files with lines that the client draws over the point cloud, e.g.
+    /// alignments or parcel boundaries.
+    #[clap(long = "overlay", parse(from_os_str))]
+    overlays: Vec<PathBuf>,
+    /// TOML file with the settings of the server. Its values can be overridden by environment
+    /// variables like POINTS_WEB_VIEWER_PORT and are overridden by the command line arguments.
+    #[clap(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+}
+
+/// Settings of the server that can be given in the config file.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// The octree directory to serve.
+    dataset: Option<PathBuf>,
     ip: String,
-    #[clap(default_value = "100")]
+    port: u16,
     cache_items: usize,
+    cache_max_age: u32,
+    thumbnail_point_budget: usize,
+    /// Number of node reads that run at the same time. Further reads wait and run in the order of
+    /// the priorities the clients request them with.
+    max_concurrent_reads: usize,
+    /// Seconds after which '/datasets' scans the directory of the dataset again.
+    catalog_max_age: u64,
+    /// Read nodes with io_uring, which keeps all reads of a request in flight at the same time.
+    /// Requires Linux and the 'io_uring' feature.
+    io_uring: bool,
+    /// Limits of the request rate and bandwidth of every client without a token, per IP address.
+    client_limits: ClientLimits,
+    /// Clients with their own limits, identified by their token.
+    clients: Vec<ClientQuota>,
+    /// Serve HTTPS with this certificate, and optionally require client certificates.
+    tls: Option<TlsConfig>,
+    /// Bearer token of the admin API under '/admin/'. The admin API is disabled without one.
+    admin_token: Option<String>,
+    /// Coarsen the points of licensed octrees before they are sent.
+    access_policies: Vec<AccessPolicy>,
+    /// GeoJSON or DXF files with lines that the client draws over the point cloud.
+    overlays: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            dataset: None,
+            ip: "127.0.0.1".to_string(),
+            port: 5433,
+            cache_items: 100,
+            cache_max_age: DEFAULT_CACHE_MAX_AGE,
+            thumbnail_point_budget: DEFAULT_THUMBNAIL_POINT_BUDGET,
+            max_concurrent_reads: DEFAULT_MAX_CONCURRENT_READS,
+            catalog_max_age: DEFAULT_CATALOG_MAX_AGE,
+            io_uring: false,
+            client_limits: ClientLimits::default(),
+            clients: Vec::new(),
+            tls: None,
+            admin_token: None,
+            access_policies: Vec::new(),
+            overlays: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Command line arguments take precedence over the config file.
+    fn merge(mut self, args: CommandLineArguments) -> Self {
+        self.dataset = args.octree_path.or(self.dataset);
+        self.ip = args.ip.unwrap_or(self.ip);
+        self.port = args.port.unwrap_or(self.port);
+        self.cache_items = args.cache_items.unwrap_or(self.cache_items);
+        self.cache_max_age = args.cache_max_age.unwrap_or(self.cache_max_age);
+        if !args.overlays.is_empty() {
+            self.overlays = args.overlays;
+        }
+        self
+    }
 }
 
-/// init app state with command arguments
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+fn data_provider_factory(io_uring: bool) -> Result<DataProviderFactory, PointsViewerError> {
+    let factory = DataProviderFactory::new();
+    if io_uring {
+        // The empty prefix matches every octree path.
+        return Ok(factory.register("", IoUringDataProvider::from_directory));
+    }
+    Ok(factory)
+}
+
+#[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+fn data_provider_factory(io_uring: bool) -> Result<DataProviderFactory, PointsViewerError> {
+    if io_uring {
+        return Err(PointsViewerError::BadRequest(
+            "'io_uring' requires Linux and building with the 'io_uring' feature.".to_string(),
+        ));
+    }
+    Ok(DataProviderFactory::new())
+}
+
+/// The settings that can be reloaded while the server runs.
+fn settings_from(config: &Config) -> Settings {
+    Settings {
+        cache_max_age: config.cache_max_age,
+        thumbnail_point_budget: config.thumbnail_point_budget,
+        catalog_max_age: config.catalog_max_age,
+        quotas: Arc::new(Quotas::new(
+            config.client_limits.clone(),
+            config.clients.clone(),
+        )),
+        admin_token: config.admin_token.clone(),
+        access_policies: config
+            .access_policies
+            .iter()
+            .cloned()
+            .map(Arc::new)
+            .collect(),
+    }
+}
+
+/// init app state with the configuration
 /// backward compatibilty is ensured
-pub fn state_from(args: CommandLineArguments) -> Result<AppState, PointsViewerError> {
+fn state_from(config: &Config) -> Result<AppState, PointsViewerError> {
+    let octree_path = config.dataset.as_ref().ok_or_else(|| {
+        PointsViewerError::BadRequest(
+            "No octree to serve. Pass DIR or set 'dataset' in the config file.".to_string(),
+        )
+    })?;
     // initial implementation: suffix from args not yet supported
     let suffix = PathBuf::new();
-    let prefix = args.octree_path.parent().unwrap_or_else(|| Path::new(""));
-    let data_provider_factory = DataProviderFactory::new();
-    let octree_id = args.octree_path.strip_prefix(&prefix)?;
+    let prefix = octree_path.parent().unwrap_or_else(|| Path::new(""));
+    let data_provider_factory = data_provider_factory(config.io_uring)?;
+    let octree_id = octree_path.strip_prefix(&prefix)?;
+    let mut overlays = Vec::new();
+    for path in &config.overlays {
+        overlays.extend(read_overlay(path).map_err(|err| {
+            PointsViewerError::BadRequest(format!(
+                "Could not read overlay {}: {}",
+                path.display(),
+                err
+            ))
+        })?);
+    }
     Ok(AppState::new(
-        args.cache_items,
+        config.cache_items,
         prefix,
         suffix,
         octree_id.to_str().unwrap(),
         data_provider_factory,
-    ))
+    )
+    .with_max_concurrent_reads(config.max_concurrent_reads)
+    .with_overlays(overlays)
+    .with_settings(settings_from(config)))
 }
 
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args = CommandLineArguments::parse();
+    let config: Config = load_config(args.config.as_deref(), "POINTS_WEB_VIEWER")
+        .unwrap_or_else(|err| panic!("Could not load configuration: {}", err));
+    let config = config.merge(args.clone());
 
-    let ip_port = format!("{}:{}", args.ip, args.port);
+    let ip_port = format!("{}:{}", config.ip, config.port);
 
     // initialize app state
-    let app_state: Arc<AppState> = Arc::new(state_from(args).unwrap());
+    // '/admin/reload' reads the config file again. Settings that are not part of 'Settings' only
+    // change with a restart.
+    let app_state = state_from(&config).unwrap().with_settings_loader(move || {
+        let config: Config = load_config(args.config.as_deref(), "POINTS_WEB_VIEWER")?;
+        Ok(settings_from(&config.merge(args.clone())))
+    });
+    let app_state: Arc<AppState> = Arc::new(app_state);
     // The actix-web framework handles requests asynchronously using actors. If we need multi-threaded
     // write access to the Octree, instead of using an RwLock we should use the actor system.
     // put octree arc in cache
 
+    let tls = config.tls.as_ref().map(|tls| {
+        tls.server_config()
+            .unwrap_or_else(|err| panic!("Could not set up TLS: {}", err))
+    });
+    let scheme = if tls.is_some() { "https" } else { "http" };
+
     let sys = actix::System::new("octree-server");
-    let _ = start_octree_server(app_state, &ip_port);
+    let _ = start_octree_server(app_state, &ip_port, tls);
 
-    eprintln!("Starting http server: {}", &ip_port);
+    info!("Starting {} server: {}", scheme, &ip_port);
     let _ = sys.run();
 }
@@ -0,0 +1,196 @@
+//! Exports an octree together with the client into a directory that any static web server can
+//! serve, or that browsers open from 'file://', so datasets can be delivered without running
+//! 'points_web_viewer'. The client selects the nodes in view itself, like '/visible_nodes' does.
+//!
+//! The bundle consists of:
+//!
+//! - 'index.html' and 'app_bundle.js', the client.
+//! - 'octree_bundle.js', the manifest: the id and view settings of the octree, and for every node
+//!   its bounding cube, number of points and the range of its data in the container.
+//! - 'octree.bin', the container with the data of all nodes in the layout of 'write_node_data',
+//!   each padded to 8 bytes. Browsers do not load files from 'file://', so with 'embed_data' the
+//!   container is written base64 encoded into 'octree_data.js' instead.
+
+use crate::backend::{srgb_colors, write_node_data, ViewSettingsReply};
+use crate::backend_error::PointsViewerError;
+use crate::utils::APP_BUNDLE;
+use point_viewer::octree::{NodeColumns, Octree};
+use std::fs;
+use std::path::Path;
+
+pub const MANIFEST_FILENAME: &str = "octree_bundle.js";
+pub const CONTAINER_FILENAME: &str = "octree.bin";
+pub const EMBEDDED_CONTAINER_FILENAME: &str = "octree_data.js";
+
+/// A node in the manifest. Empty nodes are listed as well, since the client needs them to reach
+/// their children.
+#[derive(Serialize)]
+struct BundleNode {
+    id: String,
+    min: [f64; 3],
+    edge_length: f64,
+    num_points: i64,
+    /// Range of the node data in the container, empty for empty nodes.
+    offset: usize,
+    length: usize,
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    octree_id: String,
+    view_settings: ViewSettingsReply,
+    /// The container file, or null if it is embedded.
+    data: Option<&'static str>,
+    /// Parents come before their children.
+    nodes: Vec<BundleNode>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BundleOptions {
+    /// Only export the nodes up to this level, to keep the bundle small.
+    pub max_level: Option<u8>,
+    /// Embed the container into a script, so that the bundle also works from 'file://'.
+    pub embed_data: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BundleStats {
+    pub num_nodes: usize,
+    pub num_points: i64,
+    /// Size of the container.
+    pub num_bytes: usize,
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), PointsViewerError> {
+    fs::write(path, contents).map_err(|err| {
+        PointsViewerError::InternalServerError(format!(
+            "Could not write {}: {}",
+            path.display(),
+            err
+        ))
+    })
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// The page of the bundle. Unlike the page the server sends, it needs no scripts from the
+/// internet.
+fn index_html(octree_id: &str, embed_data: bool) -> String {
+    let data_script = if embed_data {
+        format!(
+            "\n  <script src=\"{}\"></script>",
+            EMBEDDED_CONTAINER_FILENAME
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        r#"<!doctype html>
+
+<head>
+  <meta charset="utf-8">
+  <title>{}</title>
+</head>
+
+<body>
+  <div id="renderArea" style="position: absolute; width: 100%; height: 100%; overflow: hidden;"></div>
+  <script src="{}"></script>{}
+  <script src="app_bundle.js"></script>
+</body>
+"#,
+        escape_html(octree_id),
+        MANIFEST_FILENAME,
+        data_script
+    )
+}
+
+/// Writes the bundle of 'octree' into 'directory'. Colors are converted to sRGB, like the server
+/// does.
+pub fn export_bundle(
+    octree: &Octree,
+    octree_id: &str,
+    directory: impl AsRef<Path>,
+    options: &BundleOptions,
+) -> Result<BundleStats, PointsViewerError> {
+    let directory = directory.as_ref();
+    fs::create_dir_all(directory).map_err(|err| {
+        PointsViewerError::InternalServerError(format!(
+            "Could not create {}: {}",
+            directory.display(),
+            err
+        ))
+    })?;
+
+    let mut container = Vec::new();
+    let mut nodes = Vec::new();
+    let mut stats = BundleStats::default();
+    let node_ids: Vec<_> = match options.max_level {
+        Some(max_level) => octree.nodes(..=max_level).collect(),
+        None => octree.nodes(..).collect(),
+    };
+    for node_id in node_ids {
+        let node_meta = match octree.get_node_meta(&node_id) {
+            Some(node_meta) => node_meta,
+            None => continue,
+        };
+        let offset = container.len();
+        if node_meta.num_points > 0 {
+            let mut node_data = octree.get_node_data(&node_id)?;
+            srgb_colors(octree, &mut node_data);
+            write_node_data(&mut container, &mut node_data, NodeColumns::ALL);
+            stats.num_points += node_meta.num_points;
+        }
+        let min = node_meta.bounding_cube.min();
+        nodes.push(BundleNode {
+            id: node_id.to_string(),
+            min: [min.x, min.y, min.z],
+            edge_length: node_meta.bounding_cube.edge_length(),
+            num_points: node_meta.num_points,
+            offset,
+            length: container.len() - offset,
+        });
+    }
+    stats.num_nodes = nodes.len();
+    stats.num_bytes = container.len();
+
+    let mut view_settings = ViewSettingsReply::new(octree);
+    // The bundle only has the colors of the points.
+    view_settings.color_mode = "color";
+    let manifest = Manifest {
+        octree_id: octree_id.to_string(),
+        view_settings,
+        data: if options.embed_data {
+            None
+        } else {
+            Some(CONTAINER_FILENAME)
+        },
+        nodes,
+    };
+    let manifest = serde_json::to_string(&manifest)
+        .map_err(|err| PointsViewerError::InternalServerError(err.to_string()))?;
+    write(
+        &directory.join(MANIFEST_FILENAME),
+        format!("window.OCTREE_BUNDLE = {};\n", manifest),
+    )?;
+    if options.embed_data {
+        write(
+            &directory.join(EMBEDDED_CONTAINER_FILENAME),
+            format!(
+                "window.OCTREE_BUNDLE_DATA = \"{}\";\n",
+                base64::encode(&container)
+            ),
+        )?;
+    } else {
+        write(&directory.join(CONTAINER_FILENAME), &container)?;
+    }
+    write(
+        &directory.join("index.html"),
+        index_html(octree_id, options.embed_data),
+    )?;
+    write(&directory.join("app_bundle.js"), APP_BUNDLE)?;
+    Ok(stats)
+}
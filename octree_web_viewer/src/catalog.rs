@@ -0,0 +1,244 @@
+//! A catalog of the octrees below the served directory. It keeps the bounding box and coordinate
+//! reference system of every octree and indexes them in a uniform grid over x and y, so that
+//! clients can look up which datasets intersect a region without loading every octree.
+
+use point_viewer::geometry::Aabb;
+use std::collections::BTreeSet;
+use std::time::Instant;
+
+/// A served octree as listed in the catalog.
+#[derive(Clone, Debug, Serialize)]
+pub struct Dataset {
+    /// The octree id that is used in the URLs of the other endpoints.
+    pub id: String,
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+    pub crs: Option<String>,
+    pub num_points: i64,
+}
+
+impl Dataset {
+    pub fn new(
+        id: impl Into<String>,
+        bounding_box: &Aabb,
+        crs: Option<&str>,
+        num_points: i64,
+    ) -> Self {
+        let min = bounding_box.min();
+        let max = bounding_box.max();
+        Dataset {
+            id: id.into(),
+            min: [min.x, min.y, min.z],
+            max: [max.x, max.y, max.z],
+            crs: crs.map(str::to_string),
+            num_points,
+        }
+    }
+
+    fn intersects(&self, region: &Region) -> bool {
+        (0..3).all(|axis| self.min[axis] <= region.max[axis] && region.min[axis] <= self.max[axis])
+    }
+}
+
+/// A query box. Regions given in two dimensions are unbounded in z.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+impl Region {
+    /// Parses 'min_x,min_y,max_x,max_y' or 'min_x,min_y,min_z,max_x,max_y,max_z'.
+    pub fn parse(bbox: &str) -> Result<Self, String> {
+        let v = bbox
+            .split(',')
+            .map(|s| s.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|err| format!("Parsing Error: {}", err))?;
+        let region = match v.len() {
+            4 => Region {
+                min: [v[0], v[1], std::f64::NEG_INFINITY],
+                max: [v[2], v[3], std::f64::INFINITY],
+            },
+            6 => Region {
+                min: [v[0], v[1], v[2]],
+                max: [v[3], v[4], v[5]],
+            },
+            _ => return Err("Parsing Error: Expected bbox with 4 or 6 elements".to_string()),
+        };
+        if (0..3).any(|axis| region.min[axis] > region.max[axis]) {
+            return Err("The minimum of bbox must not be larger than its maximum.".to_string());
+        }
+        Ok(region)
+    }
+}
+
+/// Uniform grid over the x-y extent of all datasets. Every cell holds the indices of the datasets
+/// that overlap it.
+struct GridIndex {
+    min: [f64; 2],
+    cell_size: [f64; 2],
+    num_cells: usize,
+    cells: Vec<Vec<usize>>,
+}
+
+impl GridIndex {
+    fn new(datasets: &[Dataset]) -> Self {
+        // About one dataset per cell for datasets that are spread out evenly.
+        let num_cells = ((datasets.len() as f64).sqrt().ceil() as usize).max(1);
+        let mut min = [std::f64::INFINITY; 2];
+        let mut max = [std::f64::NEG_INFINITY; 2];
+        for dataset in datasets {
+            for axis in 0..2 {
+                min[axis] = min[axis].min(dataset.min[axis]);
+                max[axis] = max[axis].max(dataset.max[axis]);
+            }
+        }
+        let mut cell_size = [1.; 2];
+        for axis in 0..2 {
+            let extent = max[axis] - min[axis];
+            if extent > 0. {
+                cell_size[axis] = extent / num_cells as f64;
+            }
+        }
+        let mut index = GridIndex {
+            min,
+            cell_size,
+            num_cells,
+            cells: vec![Vec::new(); num_cells * num_cells],
+        };
+        for (i, dataset) in datasets.iter().enumerate() {
+            let (x_range, y_range) = index.cell_ranges(&dataset.min, &dataset.max);
+            for y in y_range {
+                for x in x_range.clone() {
+                    index.cells[y * num_cells + x].push(i);
+                }
+            }
+        }
+        index
+    }
+
+    /// The range of cells along x and y that the box from 'min' to 'max' overlaps, clamped to
+    /// the grid.
+    fn cell_ranges(
+        &self,
+        min: &[f64; 3],
+        max: &[f64; 3],
+    ) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+        let cell = |value: f64, axis: usize| -> usize {
+            let cell = ((value - self.min[axis]) / self.cell_size[axis]).floor();
+            if cell.is_nan() || cell < 0. {
+                0
+            } else {
+                (cell as usize).min(self.num_cells - 1)
+            }
+        };
+        (
+            cell(min[0], 0)..cell(max[0], 0) + 1,
+            cell(min[1], 1)..cell(max[1], 1) + 1,
+        )
+    }
+}
+
+pub struct Catalog {
+    datasets: Vec<Dataset>,
+    index: GridIndex,
+    created: Instant,
+}
+
+impl Catalog {
+    pub fn new(mut datasets: Vec<Dataset>) -> Self {
+        datasets.sort_by(|a, b| a.id.cmp(&b.id));
+        let index = GridIndex::new(&datasets);
+        Catalog {
+            datasets,
+            index,
+            created: Instant::now(),
+        }
+    }
+
+    /// All datasets, ordered by id.
+    pub fn datasets(&self) -> &[Dataset] {
+        &self.datasets
+    }
+
+    /// The datasets whose bounding box intersects 'region', ordered by id.
+    pub fn intersecting(&self, region: &Region) -> Vec<&Dataset> {
+        if self.datasets.is_empty() {
+            return Vec::new();
+        }
+        let (x_range, y_range) = self.index.cell_ranges(&region.min, &region.max);
+        let mut candidates = BTreeSet::new();
+        for y in y_range {
+            for x in x_range.clone() {
+                candidates.extend(&self.index.cells[y * self.index.num_cells + x]);
+            }
+        }
+        candidates
+            .into_iter()
+            .map(|i| &self.datasets[i])
+            .filter(|dataset| dataset.intersects(region))
+            .collect()
+    }
+
+    /// The time in seconds since the catalog was scanned.
+    pub fn age_secs(&self) -> u64 {
+        self.created.elapsed().as_secs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Point3;
+
+    fn dataset(id: &str, min: (f64, f64), max: (f64, f64)) -> Dataset {
+        let bounding_box = Aabb::new(
+            Point3::new(min.0, min.1, 0.),
+            Point3::new(max.0, max.1, 10.),
+        );
+        Dataset::new(id, &bounding_box, Some("EPSG:25832"), 1)
+    }
+
+    fn ids(datasets: Vec<&Dataset>) -> Vec<&str> {
+        datasets.iter().map(|dataset| dataset.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_intersecting() {
+        let catalog = Catalog::new(vec![
+            dataset("c", (20., 20.), (30., 30.)),
+            dataset("a", (0., 0.), (10., 10.)),
+            dataset("b", (5., 5.), (25., 8.)),
+            dataset("d", (-100., -100.), (-90., -90.)),
+        ]);
+        assert_eq!(
+            vec!["a", "b", "c", "d"],
+            ids(catalog.datasets().iter().collect())
+        );
+        let region = Region::parse("1,1,2,2").unwrap();
+        assert_eq!(vec!["a"], ids(catalog.intersecting(&region)));
+        let region = Region::parse("9,6,21,21").unwrap();
+        assert_eq!(vec!["a", "b", "c"], ids(catalog.intersecting(&region)));
+        // Regions outside of the grid are clamped to its border cells.
+        let region = Region::parse("-1000,-1000,1000,1000").unwrap();
+        assert_eq!(4, catalog.intersecting(&region).len());
+        let region = Region::parse("0,0,20,11,10,30").unwrap();
+        assert!(catalog.intersecting(&region).is_empty());
+        let region = Region::parse("50,50,60,60").unwrap();
+        assert!(catalog.intersecting(&region).is_empty());
+    }
+
+    #[test]
+    fn test_parse_region() {
+        assert!(Region::parse("1,2,3").is_err());
+        assert!(Region::parse("3,0,1,1").is_err());
+        assert_eq!(
+            Region {
+                min: [1., 2., 3.],
+                max: [4., 5., 6.]
+            },
+            Region::parse("1,2,3,4,5,6").unwrap()
+        );
+    }
+}
@@ -2,7 +2,16 @@
 extern crate serde_derive;
 extern crate serde;
 
+pub mod admin;
 pub mod backend;
 pub mod backend_error;
+pub mod bundle;
+pub mod catalog;
+pub mod metrics;
+pub mod policy;
+pub mod quota;
+pub mod scheduler;
 pub mod state;
+pub mod thumbnail;
+pub mod tls;
 pub mod utils;
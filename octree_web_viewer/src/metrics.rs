@@ -0,0 +1,93 @@
+//! Prometheus metrics of the server, exported on '/metrics'.
+
+use crate::quota::ThrottleReason;
+use actix_web::HttpResponse;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter_vec, Encoder, Histogram, IntCounterVec, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "points_web_viewer_requests_total",
+        "Number of handled requests.",
+        &["handler"]
+    )
+    .unwrap();
+    pub static ref RESPONSE_BYTES: IntCounterVec = register_int_counter_vec!(
+        "points_web_viewer_response_bytes_total",
+        "Number of bytes sent in response bodies.",
+        &["handler"]
+    )
+    .unwrap();
+    /// 'octree' counts lookups in the octree cache of the app state, 'node' counts node requests
+    /// that could be answered with '304 Not Modified'.
+    pub static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "points_web_viewer_cache_lookups_total",
+        "Number of cache lookups by cache and result.",
+        &["cache", "result"]
+    )
+    .unwrap();
+    /// Clients are labeled with their name in the quota configuration, or 'anonymous'.
+    pub static ref THROTTLED_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "points_web_viewer_throttled_requests_total",
+        "Number of requests rejected by the limits of their client, by client and reason.",
+        &["client", "reason"]
+    )
+    .unwrap();
+    pub static ref CLIENT_RESPONSE_BYTES: IntCounterVec = register_int_counter_vec!(
+        "points_web_viewer_client_response_bytes_total",
+        "Number of bytes sent in response bodies by client.",
+        &["client"]
+    )
+    .unwrap();
+    pub static ref NODE_READ_SECONDS: Histogram = register_histogram!(
+        "points_web_viewer_node_read_seconds",
+        "Time to read the data of the nodes of a request."
+    )
+    .unwrap();
+}
+
+/// Counts a request to 'handler' which sent 'num_bytes' in the response body.
+pub fn record_request(handler: &str, num_bytes: usize) {
+    REQUESTS.with_label_values(&[handler]).inc();
+    RESPONSE_BYTES
+        .with_label_values(&[handler])
+        .inc_by(num_bytes as u64);
+}
+
+pub fn record_cache_lookup(cache: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[cache, result]).inc();
+}
+
+/// The number of hits or misses of 'cache' so far.
+pub fn cache_lookups(cache: &str, hit: bool) -> u64 {
+    let result = if hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[cache, result]).get()
+}
+
+/// Counts a request of 'client' that was rejected by its limits.
+pub fn record_throttled(client: &str, reason: ThrottleReason) {
+    THROTTLED_REQUESTS
+        .with_label_values(&[client, reason.as_str()])
+        .inc();
+}
+
+pub fn record_client_bytes(client: &str, num_bytes: usize) {
+    CLIENT_RESPONSE_BYTES
+        .with_label_values(&[client])
+        .inc_by(num_bytes as u64);
+}
+
+/// Handler that exports all metrics in the Prometheus text format.
+pub fn get_metrics() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,241 @@
+//! Access policies that coarsen the points the server sends, for datasets whose license forbids
+//! redistributing them at full precision. A policy jitters and rounds the positions and strips
+//! attributes of the octrees it covers, unless the client is exempt. Applications that embed the
+//! server can plug in their own 'NodeFilter' with 'AppState::with_node_filter'.
+
+use byteorder::{ByteOrder, LittleEndian};
+use fnv::FnvHasher;
+use point_viewer::errors::{Error, Result};
+use point_viewer::octree::{NodeData, NodeId};
+use point_viewer::read_write::{decode, fixpoint_decode, fixpoint_encode, PositionEncoding};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// The color of the points of octrees whose colors are stripped.
+const STRIPPED_COLOR: u8 = 160;
+
+/// Changes the data of a node before the server sends it.
+pub trait NodeFilter: Send + Sync {
+    /// Identifies the filter in the ETags of nodes, so that caches tell its responses apart.
+    fn name(&self) -> &str;
+
+    /// Whether clients may color the points by their source id, which reveals the scans.
+    fn allows_source_ids(&self) -> bool {
+        true
+    }
+
+    /// The size in meters of the smallest details that the filtered points reveal, which limits
+    /// the resolution of '/overview'.
+    fn min_detail(&self) -> f64 {
+        0.
+    }
+
+    fn filter(&self, node_id: &NodeId, node_data: &mut NodeData) -> Result<()>;
+}
+
+/// Returns the filter for a request of the octree 'octree_id' by the client with the name
+/// 'client', see 'Quotas::name', or None to fall back to the access policies.
+pub type NodeFilterHook = dyn Fn(&str, &str) -> Option<Arc<dyn NodeFilter>> + Send + Sync;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StrippedAttribute {
+    /// The points are sent gray.
+    Color,
+    /// Coloring the points by their source id is refused.
+    SourceId,
+}
+
+/// An entry of '[[access_policies]]' in the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessPolicy {
+    /// Name of the policy in the ETags of nodes.
+    pub name: String,
+    /// The octrees the policy applies to, all of them if empty.
+    #[serde(default)]
+    pub octree_ids: Vec<String>,
+    /// Names of clients from '[[clients]]' that receive the points at full precision.
+    #[serde(default)]
+    pub exempt_clients: Vec<String>,
+    /// Moves every coordinate by a random offset of up to this many meters. Every node has its
+    /// own offsets, which are the same in every response, so they cannot be averaged out.
+    #[serde(default)]
+    pub jitter: f64,
+    /// Seed of the jitter offsets. Keep it secret, since it allows to subtract them.
+    #[serde(default)]
+    pub seed: u64,
+    /// Rounds coordinates to multiples of this many meters, after the jitter.
+    #[serde(default)]
+    pub round_to: f64,
+    #[serde(default)]
+    pub strip: Vec<StrippedAttribute>,
+}
+
+impl AccessPolicy {
+    pub fn applies_to(&self, octree_id: &str, client: &str) -> bool {
+        (self.octree_ids.is_empty() || self.octree_ids.iter().any(|id| id == octree_id))
+            && !self.exempt_clients.iter().any(|exempt| exempt == client)
+    }
+
+    fn strips(&self, attribute: StrippedAttribute) -> bool {
+        self.strip.contains(&attribute)
+    }
+
+    /// Jitters and rounds the positions in world coordinates, and encodes them again. Points
+    /// that end up outside of the bounding cube of the node are clamped to it.
+    fn coarsen_positions(&self, node_id: &NodeId, node_data: &mut NodeData) -> Result<()> {
+        let encoding = node_data.meta.position_encoding.clone();
+        match encoding {
+            PositionEncoding::Uint8
+            | PositionEncoding::Uint16
+            | PositionEncoding::Float32
+            | PositionEncoding::Float64 => (),
+            _ => {
+                return Err(Error::InvalidInput(format!(
+                    "Cannot coarsen positions encoded as {:?}.",
+                    encoding
+                )))
+            }
+        }
+        let min = node_data.meta.bounding_cube.min();
+        let edge_length = node_data.meta.bounding_cube.edge_length();
+        let mut hasher = FnvHasher::with_key(self.seed);
+        node_id.hash(&mut hasher);
+        let mut rng = ChaCha8Rng::seed_from_u64(hasher.finish());
+        let bytes_per_coordinate = encoding.bytes_per_coordinate();
+        for (i, coordinate) in node_data
+            .position
+            .chunks_exact_mut(bytes_per_coordinate)
+            .enumerate()
+        {
+            let min = min[i % 3];
+            let mut value = match encoding {
+                PositionEncoding::Uint8 => fixpoint_decode(coordinate[0], min, edge_length),
+                PositionEncoding::Uint16 => {
+                    fixpoint_decode(LittleEndian::read_u16(coordinate), min, edge_length)
+                }
+                PositionEncoding::Float32 => {
+                    decode(LittleEndian::read_f32(coordinate), min, edge_length)
+                }
+                _ => decode(LittleEndian::read_f64(coordinate), min, edge_length),
+            };
+            if self.jitter > 0. {
+                value += rng.gen_range(-self.jitter, self.jitter);
+            }
+            if self.round_to > 0. {
+                value = (value / self.round_to).round() * self.round_to;
+            }
+            let unit = ((value - min) / edge_length).max(0.).min(1.);
+            match encoding {
+                PositionEncoding::Uint8 => coordinate[0] = fixpoint_encode(value, min, edge_length),
+                PositionEncoding::Uint16 => {
+                    LittleEndian::write_u16(coordinate, fixpoint_encode(value, min, edge_length))
+                }
+                PositionEncoding::Float32 => LittleEndian::write_f32(coordinate, unit as f32),
+                _ => LittleEndian::write_f64(coordinate, unit),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NodeFilter for AccessPolicy {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn allows_source_ids(&self) -> bool {
+        !self.strips(StrippedAttribute::SourceId)
+    }
+
+    fn min_detail(&self) -> f64 {
+        self.jitter.max(self.round_to)
+    }
+
+    fn filter(&self, node_id: &NodeId, node_data: &mut NodeData) -> Result<()> {
+        if self.strips(StrippedAttribute::Color) {
+            for channel in &mut node_data.color {
+                *channel = STRIPPED_COLOR;
+            }
+        }
+        if self.jitter > 0. || self.round_to > 0. {
+            self.coarsen_positions(node_id, node_data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use point_viewer::geometry::Cube;
+    use point_viewer::octree::NodeMeta;
+    use std::collections::HashMap;
+
+    const POSITION: [f32; 6] = [0.123, 0.456, 0.789, 0.5, 0.5, 0.5];
+
+    fn node_data(position: &[f32]) -> NodeData {
+        let mut bytes = vec![0; position.len() * 4];
+        LittleEndian::write_f32_into(position, &mut bytes);
+        NodeData {
+            meta: NodeMeta {
+                num_points: (position.len() / 3) as i64,
+                position_encoding: PositionEncoding::Float32,
+                bounding_cube: Cube::new(nalgebra::Point3::new(0., 0., 0.), 10.),
+                compressed_attributes: false,
+                checksums: HashMap::new(),
+                normal_cone: None,
+                occupancy: None,
+            },
+            position: bytes,
+            color: vec![255; position.len()],
+            shading: Vec::new(),
+        }
+    }
+
+    fn positions(node_data: &NodeData) -> Vec<f32> {
+        let mut position = vec![0.; node_data.position.len() / 4];
+        LittleEndian::read_f32_into(&node_data.position, &mut position);
+        position
+    }
+
+    #[test]
+    fn test_access_policy() {
+        let policy = AccessPolicy {
+            name: "public".to_string(),
+            octree_ids: vec!["survey".to_string()],
+            exempt_clients: vec!["partner".to_string()],
+            jitter: 0.2,
+            seed: 7,
+            round_to: 1.,
+            strip: vec![StrippedAttribute::Color],
+        };
+        assert!(policy.applies_to("survey", "anonymous"));
+        assert!(!policy.applies_to("survey", "partner"));
+        assert!(!policy.applies_to("other", "anonymous"));
+        assert!(policy.allows_source_ids());
+        assert_eq!(policy.min_detail(), 1.);
+
+        let node_id = NodeId::root();
+        let mut filtered = node_data(&POSITION);
+        policy.filter(&node_id, &mut filtered).unwrap();
+        // Every coordinate is moved by at most the jitter and then rounded to a full meter.
+        for (coordinate, original) in positions(&filtered).iter().zip(&POSITION) {
+            let meters = f64::from(*coordinate) * 10.;
+            assert!((meters - meters.round()).abs() < 1e-4);
+            assert!((meters - f64::from(*original) * 10.).abs() <= 0.7 + 1e-4);
+        }
+        assert!(filtered
+            .color
+            .iter()
+            .all(|channel| *channel == STRIPPED_COLOR));
+
+        // Every response of a node has the same offsets.
+        let mut again = node_data(&POSITION);
+        policy.filter(&node_id, &mut again).unwrap();
+        assert_eq!(again.position, filtered.position);
+    }
+}
@@ -0,0 +1,351 @@
+//! Limits the request rate and the bandwidth of every client, so that one aggressive client cannot
+//! saturate the disk and starve the interactive viewers of everybody else. Clients are identified
+//! by their token, sent as 'Authorization: Bearer <token>' header or as 'token' query parameter,
+//! and otherwise by their IP address. Both limits are token buckets: a client may send a burst
+//! after a pause, and is then held to the sustained rate.
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name of the clients without a known token in the metrics.
+pub const ANONYMOUS_CLIENT: &str = "anonymous";
+
+/// How often the buckets of clients that are back at their full limits are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The limits of a client. Zero disables a limit.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientLimits {
+    /// Sustained number of requests per second.
+    pub requests_per_second: f64,
+    /// Number of requests that may be sent at once after a pause. Defaults to one second's worth.
+    pub request_burst: f64,
+    /// Sustained number of response bytes per second.
+    pub bytes_per_second: f64,
+    /// Number of bytes that may be sent at once after a pause. Defaults to one second's worth.
+    pub byte_burst: f64,
+}
+
+impl ClientLimits {
+    fn is_unlimited(&self) -> bool {
+        self.requests_per_second <= 0. && self.bytes_per_second <= 0.
+    }
+
+    fn request_capacity(&self) -> f64 {
+        if self.request_burst > 0. {
+            self.request_burst.max(1.)
+        } else {
+            self.requests_per_second.max(1.)
+        }
+    }
+
+    fn byte_capacity(&self) -> f64 {
+        if self.byte_burst > 0. {
+            self.byte_burst
+        } else {
+            self.bytes_per_second
+        }
+    }
+}
+
+/// A client with its own limits, e.g. a tenant or a batch job.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientQuota {
+    /// Name of the client in the metrics.
+    pub name: String,
+    /// The secret that the client authenticates with.
+    pub token: String,
+    #[serde(default)]
+    pub limits: ClientLimits,
+}
+
+/// Who sent a request.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Client {
+    /// The index of a client with a known token.
+    Token(usize),
+    /// A client without a known token.
+    Address(Option<IpAddr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThrottleReason {
+    RequestRate,
+    Bandwidth,
+}
+
+impl ThrottleReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThrottleReason::RequestRate => "request_rate",
+            ThrottleReason::Bandwidth => "bandwidth",
+        }
+    }
+}
+
+/// A rejected request: why, and when the client may try again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Throttled {
+    pub reason: ThrottleReason,
+    pub retry_after: Duration,
+}
+
+impl Throttled {
+    /// The '429 Too Many Requests' response with a 'Retry-After' header.
+    pub fn response(&self) -> HttpResponse {
+        let retry_after = self.retry_after.as_secs_f64().ceil().max(1.);
+        HttpResponse::TooManyRequests()
+            .header(header::RETRY_AFTER, retry_after.to_string())
+            .json(format!(
+                "Too many requests, the {} limit of this client is exhausted.",
+                self.reason.as_str().replace('_', " ")
+            ))
+    }
+}
+
+/// Holds up to a capacity and refills at a rate per second.
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    level: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, capacity: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.level = (self.level + elapsed * rate).min(capacity);
+        self.updated = now;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Buckets {
+    requests: Bucket,
+    /// Negative while the client has received more bytes than its limits allow.
+    bytes: Bucket,
+}
+
+impl Buckets {
+    fn full(limits: &ClientLimits, now: Instant) -> Self {
+        Buckets {
+            requests: Bucket {
+                level: limits.request_capacity(),
+                updated: now,
+            },
+            bytes: Bucket {
+                level: limits.byte_capacity(),
+                updated: now,
+            },
+        }
+    }
+
+    fn refill(&mut self, limits: &ClientLimits, now: Instant) {
+        self.requests
+            .refill(limits.requests_per_second, limits.request_capacity(), now);
+        self.bytes
+            .refill(limits.bytes_per_second, limits.byte_capacity(), now);
+    }
+
+    fn is_full(&self, limits: &ClientLimits) -> bool {
+        self.requests.level >= limits.request_capacity()
+            && self.bytes.level >= limits.byte_capacity()
+    }
+}
+
+struct QuotaState {
+    buckets: HashMap<Client, Buckets>,
+    last_prune: Instant,
+}
+
+pub struct Quotas {
+    /// The limits of the clients without a known token, each IP address on its own.
+    default_limits: ClientLimits,
+    clients: Vec<ClientQuota>,
+    tokens: HashMap<String, usize>,
+    state: Mutex<QuotaState>,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Quotas::new(ClientLimits::default(), Vec::new())
+    }
+}
+
+impl Quotas {
+    pub fn new(default_limits: ClientLimits, clients: Vec<ClientQuota>) -> Self {
+        let tokens = clients
+            .iter()
+            .enumerate()
+            .map(|(index, client)| (client.token.clone(), index))
+            .collect();
+        Quotas {
+            default_limits,
+            clients,
+            tokens,
+            state: Mutex::new(QuotaState {
+                buckets: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    /// Identifies the client by its token or, without a known token, by its IP address. The peer
+    /// address is used, so clients behind the same proxy share their limits unless they send
+    /// tokens.
+    pub fn identify(&self, req: &HttpRequest) -> Client {
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let token = bearer.map(str::to_string).or_else(|| {
+            req.query_string()
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+                .map(str::to_string)
+        });
+        match token.and_then(|token| self.tokens.get(token.trim())) {
+            Some(index) => Client::Token(*index),
+            None => Client::Address(req.peer_addr().map(|address| address.ip())),
+        }
+    }
+
+    /// The name of the client in the metrics.
+    pub fn name(&self, client: &Client) -> &str {
+        match client {
+            Client::Token(index) => &self.clients[*index].name,
+            Client::Address(_) => ANONYMOUS_CLIENT,
+        }
+    }
+
+    fn limits(&self, client: &Client) -> &ClientLimits {
+        match client {
+            Client::Token(index) => &self.clients[*index].limits,
+            Client::Address(_) => &self.default_limits,
+        }
+    }
+
+    /// Takes one request from the limits of 'client', or returns why it has to wait.
+    pub fn admit(&self, client: &Client) -> Result<(), Throttled> {
+        self.admit_at(client, Instant::now())
+    }
+
+    /// Counts 'num_bytes' of a response against the bandwidth of 'client'. A large response is
+    /// sent in full and makes the client wait until its limit recovers.
+    pub fn charge(&self, client: &Client, num_bytes: usize) {
+        self.charge_at(client, num_bytes, Instant::now())
+    }
+
+    fn admit_at(&self, client: &Client, now: Instant) -> Result<(), Throttled> {
+        let limits = self.limits(client);
+        if limits.is_unlimited() {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        if now.saturating_duration_since(state.last_prune) >= PRUNE_INTERVAL {
+            // Clients with full buckets are no different from new ones.
+            state.buckets.retain(|client, buckets| {
+                let limits = self.limits(client);
+                buckets.refill(limits, now);
+                !buckets.is_full(limits)
+            });
+            state.last_prune = now;
+        }
+        let buckets = state
+            .buckets
+            .entry(client.clone())
+            .or_insert_with(|| Buckets::full(limits, now));
+        buckets.refill(limits, now);
+        if limits.bytes_per_second > 0. && buckets.bytes.level < 0. {
+            return Err(Throttled {
+                reason: ThrottleReason::Bandwidth,
+                retry_after: Duration::from_secs_f64(
+                    -buckets.bytes.level / limits.bytes_per_second,
+                ),
+            });
+        }
+        if limits.requests_per_second > 0. {
+            if buckets.requests.level < 1. {
+                return Err(Throttled {
+                    reason: ThrottleReason::RequestRate,
+                    retry_after: Duration::from_secs_f64(
+                        (1. - buckets.requests.level) / limits.requests_per_second,
+                    ),
+                });
+            }
+            buckets.requests.level -= 1.;
+        }
+        Ok(())
+    }
+
+    fn charge_at(&self, client: &Client, num_bytes: usize, now: Instant) {
+        let limits = self.limits(client);
+        if limits.bytes_per_second <= 0. {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let buckets = state
+            .buckets
+            .entry(client.clone())
+            .or_insert_with(|| Buckets::full(limits, now));
+        buckets.refill(limits, now);
+        buckets.bytes.level -= num_bytes as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let quotas = Quotas::new(
+            ClientLimits {
+                requests_per_second: 2.,
+                request_burst: 3.,
+                ..Default::default()
+            },
+            vec![ClientQuota {
+                name: "batch".to_string(),
+                token: "secret".to_string(),
+                limits: ClientLimits {
+                    bytes_per_second: 100.,
+                    ..Default::default()
+                },
+            }],
+        );
+        let start = Instant::now();
+        let viewer = Client::Address(None);
+        for _ in 0..3 {
+            assert!(quotas.admit_at(&viewer, start).is_ok());
+        }
+        let throttled = quotas.admit_at(&viewer, start).unwrap_err();
+        assert_eq!(throttled.reason, ThrottleReason::RequestRate);
+        assert_eq!(throttled.retry_after, Duration::from_millis(500));
+        assert!(quotas
+            .admit_at(&viewer, start + Duration::from_millis(500))
+            .is_ok());
+
+        // The batch job has no request limit, but may only receive 100 bytes per second.
+        let batch = Client::Token(0);
+        assert_eq!(quotas.name(&batch), "batch");
+        assert!(quotas.admit_at(&batch, start).is_ok());
+        quotas.charge_at(&batch, 300, start);
+        let throttled = quotas.admit_at(&batch, start).unwrap_err();
+        assert_eq!(throttled.reason, ThrottleReason::Bandwidth);
+        assert_eq!(throttled.retry_after, Duration::from_secs(2));
+        assert!(quotas
+            .admit_at(&batch, start + Duration::from_secs(2))
+            .is_ok());
+        // The limits of one client do not affect others.
+        assert!(quotas
+            .admit_at(&Client::Address("127.0.0.1".parse().ok()), start)
+            .is_ok());
+    }
+}
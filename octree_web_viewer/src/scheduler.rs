@@ -0,0 +1,162 @@
+//! Orders the node reads of concurrent requests by priority. Clients pass the screen-space error of
+//! the nodes they request, so when more nodes are requested than can be read at once, the nodes the
+//! user is looking at are read before large prefetches. Requests that the client cancels stop
+//! waiting, because actix drops their futures.
+
+use futures::channel::oneshot;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+
+struct Waiter {
+    priority: f64,
+    /// Orders waiters with the same priority first come, first served.
+    sequence: u64,
+    sender: oneshot::Sender<()>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Waiter) -> Ordering {
+        self.priority
+            .partial_cmp(&other.priority)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Waiter) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Waiter) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+struct Slots {
+    num_running: usize,
+    next_sequence: u64,
+    waiting: BinaryHeap<Waiter>,
+}
+
+pub struct NodeReadScheduler {
+    max_concurrent_reads: usize,
+    slots: Mutex<Slots>,
+}
+
+/// Allows one read until it is dropped.
+pub struct ReadPermit {
+    scheduler: Arc<NodeReadScheduler>,
+}
+
+impl Drop for ReadPermit {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+/// A waiting 'acquire'. If it is dropped after a finished read handed over its slot, the slot is
+/// passed on.
+struct PendingPermit {
+    receiver: oneshot::Receiver<()>,
+    scheduler: Arc<NodeReadScheduler>,
+    granted: bool,
+}
+
+impl Drop for PendingPermit {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        self.receiver.close();
+        if let Ok(Some(())) = self.receiver.try_recv() {
+            self.scheduler.release();
+        }
+    }
+}
+
+impl NodeReadScheduler {
+    pub fn new(max_concurrent_reads: usize) -> Self {
+        NodeReadScheduler {
+            max_concurrent_reads: max_concurrent_reads.max(1),
+            slots: Mutex::new(Slots {
+                num_running: 0,
+                next_sequence: 0,
+                waiting: BinaryHeap::new(),
+            }),
+        }
+    }
+
+    /// Waits until fewer than the maximum number of reads run and no read with a higher priority
+    /// waits.
+    pub async fn acquire(self: &Arc<Self>, priority: f64) -> ReadPermit {
+        let mut pending = {
+            let mut slots = self.slots.lock().unwrap();
+            if slots.num_running < self.max_concurrent_reads {
+                slots.num_running += 1;
+                return ReadPermit {
+                    scheduler: Arc::clone(self),
+                };
+            }
+            let (sender, receiver) = oneshot::channel();
+            let sequence = slots.next_sequence;
+            slots.next_sequence += 1;
+            slots.waiting.push(Waiter {
+                priority,
+                sequence,
+                sender,
+            });
+            PendingPermit {
+                receiver,
+                scheduler: Arc::clone(self),
+                granted: false,
+            }
+        };
+        // The sender is only dropped after it handed over a slot or when its receiver is closed.
+        let _ = (&mut pending.receiver).await;
+        pending.granted = true;
+        ReadPermit {
+            scheduler: Arc::clone(self),
+        }
+    }
+
+    /// Hands the slot of a finished read to the waiter with the highest priority that still waits.
+    fn release(&self) {
+        let mut slots = self.slots.lock().unwrap();
+        while let Some(waiter) = slots.waiting.pop() {
+            if waiter.sender.send(()).is_ok() {
+                return;
+            }
+        }
+        slots.num_running -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_reads_by_priority() {
+        let scheduler = Arc::new(NodeReadScheduler::new(1));
+        let running = scheduler.acquire(0.).now_or_never().unwrap();
+        let mut low = Box::pin(scheduler.acquire(1.));
+        let mut high = Box::pin(scheduler.acquire(2.));
+        assert!((&mut low).now_or_never().is_none());
+        assert!((&mut high).now_or_never().is_none());
+
+        drop(running);
+        assert!((&mut low).now_or_never().is_none());
+        let running = (&mut high).now_or_never().unwrap();
+        // A cancelled request gives up its place.
+        drop(low);
+        drop(running);
+        assert!(scheduler.acquire(0.).now_or_never().is_some());
+    }
+}
@@ -1,9 +1,29 @@
 use crate::backend_error::PointsViewerError;
-use point_viewer::data_provider;
+use crate::catalog::{Catalog, Dataset};
+use crate::metrics;
+use crate::policy::{AccessPolicy, NodeFilter, NodeFilterHook};
+use crate::quota::Quotas;
+use crate::scheduler::NodeReadScheduler;
+use log::{info, warn};
+use point_viewer::data_provider::{self, DataProvider, VersionedDataProvider};
+use point_viewer::iterator::PointCloud;
 use point_viewer::octree;
+use point_viewer::overlay::OverlayLayer;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Default max-age of cached node data: one day.
+pub const DEFAULT_CACHE_MAX_AGE: u32 = 86_400;
+/// Default maximum number of points drawn into a thumbnail.
+pub const DEFAULT_THUMBNAIL_POINT_BUDGET: usize = 2_000_000;
+/// Default maximum number of node reads of '/nodes_batch' requests that run at the same time.
+pub const DEFAULT_MAX_CONCURRENT_READS: usize = 4;
+/// Default number of seconds after which the dataset catalog is scanned again.
+pub const DEFAULT_CATALOG_MAX_AGE: u64 = 60;
+/// Interval in which versioned octrees are checked for a newer version.
+const VERSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// path information for the octrees
 #[derive(Clone)]
@@ -24,15 +44,97 @@ impl OctreeKeyParams {
     }
 }
 
+/// A loaded octree. Versioned octrees are served as of their newest version, which stays
+/// consistent while a later version is being written, until that version is committed.
+#[derive(Clone)]
+struct CachedOctree {
+    octree: Arc<octree::Octree>,
+    /// Directory and served version of a versioned octree.
+    version: Option<(PathBuf, u64)>,
+    /// The newest version of a versioned octree and when it was looked up.
+    latest_version: Arc<Mutex<(u64, Instant)>>,
+}
+
+impl CachedOctree {
+    /// Whether a newer version was committed. Requests do not list the versions, the newest
+    /// version is looked up at most once per 'VERSION_CHECK_INTERVAL'.
+    fn is_outdated(&self) -> bool {
+        let (directory, version) = match &self.version {
+            Some(version) => version,
+            None => return false,
+        };
+        let mut latest_version = self.latest_version.lock().unwrap();
+        if latest_version.1.elapsed() >= VERSION_CHECK_INTERVAL {
+            if let Some(latest) = data_provider::latest_version(directory) {
+                latest_version.0 = latest;
+            }
+            latest_version.1 = Instant::now();
+        }
+        latest_version.0 > *version
+    }
+}
+
+/// The settings that '/admin/reload' can change while the server runs.
+#[derive(Clone)]
+pub struct Settings {
+    /// max-age in seconds of the Cache-Control header sent with node data
+    pub cache_max_age: u32,
+    /// maximum number of points drawn into a thumbnail
+    pub thumbnail_point_budget: usize,
+    /// seconds after which the catalog is scanned again
+    pub catalog_max_age: u64,
+    /// limits of the request rate and bandwidth of every client. Reloading them starts every
+    /// client with full limits.
+    pub quotas: Arc<Quotas>,
+    /// bearer token of the admin API, which is disabled without one
+    pub admin_token: Option<String>,
+    /// coarsen the points of licensed octrees, the first policy that applies is used
+    pub access_policies: Vec<Arc<AccessPolicy>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            cache_max_age: DEFAULT_CACHE_MAX_AGE,
+            thumbnail_point_budget: DEFAULT_THUMBNAIL_POINT_BUDGET,
+            catalog_max_age: DEFAULT_CATALOG_MAX_AGE,
+            quotas: Arc::new(Quotas::default()),
+            admin_token: None,
+            access_policies: Vec::new(),
+        }
+    }
+}
+
+/// Loads the settings again, e.g. from the config file.
+pub type SettingsLoader = dyn Fn() -> Result<Settings, PointsViewerError> + Send + Sync;
+
+/// An octree in the cache, as listed by '/admin/cache'.
+#[derive(Clone, Debug, Serialize)]
+pub struct CachedOctreeInfo {
+    pub id: String,
+    pub num_points: i64,
+    /// The served version of a versioned octree.
+    pub version: Option<u64>,
+}
+
 #[derive(Clone)]
 pub struct AppState {
     /// Hash Map for Octrees
-    octree_map: Arc<RwLock<HashMap<String, Arc<octree::Octree>>>>,
+    octree_map: Arc<RwLock<HashMap<String, CachedOctree>>>,
     /// information for retieving octree path
     key_params: OctreeKeyParams,
     /// backward compatibility to input arguments
     init_octree_id: String,
     data_provider_factory: data_provider::DataProviderFactory,
+    /// orders the node reads of all requests by priority
+    read_scheduler: Arc<NodeReadScheduler>,
+    /// the octrees below the prefix, scanned at most every 'catalog_max_age' seconds
+    catalog: Arc<RwLock<Option<Arc<Catalog>>>>,
+    settings: Arc<RwLock<Settings>>,
+    settings_loader: Option<Arc<SettingsLoader>>,
+    node_filter: Option<Arc<NodeFilterHook>>,
+    /// the vector overlays that '/overlays' returns
+    overlays: Arc<Vec<OverlayLayer>>,
 }
 
 impl AppState {
@@ -51,6 +153,214 @@ impl AppState {
             },
             init_octree_id: octree_id.into(),
             data_provider_factory,
+            read_scheduler: Arc::new(NodeReadScheduler::new(DEFAULT_MAX_CONCURRENT_READS)),
+            catalog: Arc::new(RwLock::new(None)),
+            settings: Arc::new(RwLock::new(Settings::default())),
+            settings_loader: None,
+            node_filter: None,
+            overlays: Arc::new(Vec::new()),
+        }
+    }
+
+    pub fn with_settings(self, settings: Settings) -> Self {
+        *self.settings.write().unwrap() = settings;
+        self
+    }
+
+    /// Enables '/admin/reload', which replaces the settings with the ones 'loader' returns.
+    pub fn with_settings_loader(
+        mut self,
+        loader: impl Fn() -> Result<Settings, PointsViewerError> + Send + Sync + 'static,
+    ) -> Self {
+        self.settings_loader = Some(Arc::new(loader));
+        self
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Loads the settings again. The previous settings stay in place if this fails.
+    pub fn reload_settings(&self) -> Result<(), PointsViewerError> {
+        let loader = self.settings_loader.as_ref().ok_or_else(|| {
+            PointsViewerError::BadRequest("This server has no settings to reload.".to_string())
+        })?;
+        let settings = loader()?;
+        *self.settings.write().unwrap() = settings;
+        info!("Reloaded the settings.");
+        Ok(())
+    }
+
+    pub fn with_cache_max_age(self, cache_max_age: u32) -> Self {
+        self.settings.write().unwrap().cache_max_age = cache_max_age;
+        self
+    }
+
+    pub fn cache_max_age(&self) -> u32 {
+        self.settings.read().unwrap().cache_max_age
+    }
+
+    pub fn with_thumbnail_point_budget(self, thumbnail_point_budget: usize) -> Self {
+        self.settings.write().unwrap().thumbnail_point_budget = thumbnail_point_budget;
+        self
+    }
+
+    pub fn thumbnail_point_budget(&self) -> usize {
+        self.settings.read().unwrap().thumbnail_point_budget
+    }
+
+    pub fn with_max_concurrent_reads(mut self, max_concurrent_reads: usize) -> Self {
+        self.read_scheduler = Arc::new(NodeReadScheduler::new(max_concurrent_reads));
+        self
+    }
+
+    pub fn read_scheduler(&self) -> &Arc<NodeReadScheduler> {
+        &self.read_scheduler
+    }
+
+    pub fn with_quotas(self, quotas: Quotas) -> Self {
+        self.settings.write().unwrap().quotas = Arc::new(quotas);
+        self
+    }
+
+    pub fn quotas(&self) -> Arc<Quotas> {
+        Arc::clone(&self.settings.read().unwrap().quotas)
+    }
+
+    /// Plugs in a filter of the node data, which takes precedence over the access policies.
+    pub fn with_node_filter(
+        mut self,
+        hook: impl Fn(&str, &str) -> Option<Arc<dyn NodeFilter>> + Send + Sync + 'static,
+    ) -> Self {
+        self.node_filter = Some(Arc::new(hook));
+        self
+    }
+
+    /// The filter of the node data that 'client' requests from 'octree_id', see 'Quotas::name'.
+    pub fn node_filter(&self, octree_id: &str, client: &str) -> Option<Arc<dyn NodeFilter>> {
+        if let Some(filter) = self
+            .node_filter
+            .as_ref()
+            .and_then(|hook| hook(octree_id, client))
+        {
+            return Some(filter);
+        }
+        self.settings
+            .read()
+            .unwrap()
+            .access_policies
+            .iter()
+            .find(|policy| policy.applies_to(octree_id, client))
+            .map(|policy| Arc::clone(policy) as Arc<dyn NodeFilter>)
+    }
+
+    /// Whether the node data depends on the client, so that shared caches must not store it.
+    pub fn filters_nodes(&self) -> bool {
+        self.node_filter.is_some() || !self.settings.read().unwrap().access_policies.is_empty()
+    }
+
+    /// Serves 'overlays' to the clients, which draw them over every octree.
+    pub fn with_overlays(mut self, overlays: Vec<OverlayLayer>) -> Self {
+        self.overlays = Arc::new(overlays);
+        self
+    }
+
+    pub fn overlays(&self) -> &Arc<Vec<OverlayLayer>> {
+        &self.overlays
+    }
+
+    pub fn with_catalog_max_age(self, catalog_max_age: u64) -> Self {
+        self.settings.write().unwrap().catalog_max_age = catalog_max_age;
+        self
+    }
+
+    /// Returns the catalog of all octrees in the subdirectories of the prefix. It is scanned
+    /// again when it is older than 'catalog_max_age', so that new datasets show up.
+    pub fn catalog(&self) -> Result<Arc<Catalog>, PointsViewerError> {
+        let catalog_max_age = self.settings.read().unwrap().catalog_max_age;
+        if let Some(catalog) = self
+            .catalog
+            .read()
+            .unwrap()
+            .as_ref()
+            .filter(|catalog| catalog.age_secs() < catalog_max_age)
+        {
+            metrics::record_cache_lookup("catalog", true);
+            return Ok(Arc::clone(catalog));
+        }
+        metrics::record_cache_lookup("catalog", false);
+        let catalog = Arc::new(self.scan_catalog()?);
+        *self.catalog.write().unwrap() = Some(Arc::clone(&catalog));
+        Ok(catalog)
+    }
+
+    fn scan_catalog(&self) -> Result<Catalog, PointsViewerError> {
+        let entries = std::fs::read_dir(&self.key_params.prefix).map_err(|err| {
+            PointsViewerError::InternalServerError(format!(
+                "Could not list {}: {}",
+                self.key_params.prefix.display(),
+                err
+            ))
+        })?;
+        let mut datasets = Vec::new();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let octree_key = match entry.file_name().into_string() {
+                Ok(octree_key) => octree_key,
+                Err(_) => continue,
+            };
+            let addr = self.key_params.get_octree_address(&octree_key);
+            if !addr.join("meta.pb").is_file() && !octree::is_versioned(&addr) {
+                continue;
+            }
+            // Scanning does not fill the cache, since most datasets are never viewed.
+            match self.open_octree(&octree_key) {
+                Ok(cached) => datasets.push(Dataset::new(
+                    octree_key,
+                    cached.octree.bounding_box(),
+                    cached.octree.crs(),
+                    cached.octree.num_points(),
+                )),
+                Err(err) => warn!("Could not add {} to the catalog: {}", octree_key, err),
+            }
+        }
+        Ok(Catalog::new(datasets))
+    }
+
+    /// The catalog as it was last scanned, without scanning it.
+    pub fn cached_catalog(&self) -> Option<Arc<Catalog>> {
+        self.catalog.read().unwrap().clone()
+    }
+
+    /// The octrees in the cache, ordered by id.
+    pub fn cached_octrees(&self) -> Vec<CachedOctreeInfo> {
+        let mut octrees: Vec<_> = self
+            .octree_map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, cached)| CachedOctreeInfo {
+                id: id.clone(),
+                num_points: cached.octree.num_points(),
+                version: cached.version.as_ref().map(|(_, version)| *version),
+            })
+            .collect();
+        octrees.sort_by(|a, b| a.id.cmp(&b.id));
+        octrees
+    }
+
+    /// Removes 'octree_id', or all octrees and the catalog, from the cache, so that they are
+    /// loaded again from disk. Returns the number of evicted octrees. Requests that still hold
+    /// an evicted octree finish reading it.
+    pub fn evict(&self, octree_id: Option<&str>) -> usize {
+        let mut map = self.octree_map.write().unwrap();
+        match octree_id {
+            Some(octree_id) => map.remove(octree_id).map_or(0, |_| 1),
+            None => {
+                *self.catalog.write().unwrap() = None;
+                let num_evicted = map.len();
+                map.clear();
+                num_evicted
+            }
         }
     }
 
@@ -65,12 +375,14 @@ impl AppState {
             // read access to state
             let map = self.octree_map.read().unwrap();
             let octree = map.get(octree_key);
-            //some found
-            if let Some(tree) = octree {
-                return Ok(Arc::clone(&tree));
+            //some found, and no newer version was committed
+            if let Some(cached) = octree.filter(|cached| !cached.is_outdated()) {
+                metrics::record_cache_lookup("octree", true);
+                return Ok(Arc::clone(&cached.octree));
             }
         }
         // none found
+        metrics::record_cache_lookup("octree", false);
         self.insert_octree(octree_key.to_string())
     }
 
@@ -79,19 +391,38 @@ impl AppState {
         octree_id: impl Into<String>,
     ) -> Result<Arc<octree::Octree>, PointsViewerError> {
         let octree_key = octree_id.into();
-        let addr = &self.key_params.get_octree_address(&octree_key);
-        let octree: Arc<octree::Octree> = Arc::from(octree::Octree::from_data_provider(
-            self.data_provider_factory
-                .generate_data_provider(addr.to_string_lossy())?,
-        )?);
+        let cached = self.open_octree(&octree_key)?;
+        let octree = Arc::clone(&cached.octree);
         {
-            // write access to state
+            // write access to state. Requests that still hold the previous version keep reading
+            // it consistently, since its files are archived instead of overwritten.
             let mut wmap = self.octree_map.write().unwrap();
-            wmap.insert(octree_key, Arc::clone(&octree));
+            wmap.insert(octree_key, cached);
         }
         Ok(octree)
     }
 
+    fn open_octree(&self, octree_key: &str) -> Result<CachedOctree, PointsViewerError> {
+        let addr = &self.key_params.get_octree_address(octree_key);
+        let (data_provider, version): (Box<dyn DataProvider>, _) = if octree::is_versioned(addr) {
+            let data_provider = VersionedDataProvider::latest(addr)?;
+            let version = data_provider.version();
+            info!("Serving octree {} as of version {}.", octree_key, version);
+            (Box::new(data_provider), Some((addr.clone(), version)))
+        } else {
+            let data_provider = self
+                .data_provider_factory
+                .generate_data_provider(addr.to_string_lossy())?;
+            (data_provider, None)
+        };
+        let latest_version = version.as_ref().map_or(0, |(_, version)| *version);
+        Ok(CachedOctree {
+            octree: Arc::from(octree::Octree::from_data_provider(data_provider)?),
+            version,
+            latest_version: Arc::new(Mutex::new((latest_version, Instant::now()))),
+        })
+    }
+
     pub fn get_init_id(&self) -> String {
         self.init_octree_id.clone()
     }
@@ -0,0 +1,62 @@
+//! Renders octrees on the CPU into images, so that dataset catalogs can show previews without
+//! running a viewer. The drawing itself is 'render_snapshot_with_node_reader' of the library; this
+//! only adds the overview camera, the budget of the thumbnails and the access policy of the client.
+
+use crate::backend::srgb_colors;
+use crate::policy::NodeFilter;
+use image::{Rgba, RgbaImage};
+use nalgebra::{Isometry3, Matrix4, Perspective3, Vector3};
+use point_viewer::errors::Result;
+use point_viewer::iterator::PointCloud;
+use point_viewer::octree::{render_snapshot_with_node_reader, Octree, SnapshotOptions};
+
+const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 255]);
+const FIELD_OF_VIEW_Y: f64 = std::f64::consts::FRAC_PI_4;
+
+/// Returns a projection matrix of a camera that looks at the whole bounding box of the octree at an
+/// angle from above.
+pub fn default_overview_matrix(octree: &Octree, width: u32, height: u32) -> Matrix4<f64> {
+    let bounding_box = octree.bounding_box();
+    let target = bounding_box.center();
+    let radius = bounding_box.diag().norm() / 2.;
+    // Far enough away that the bounding sphere fits into the vertical field of view.
+    let distance = radius / (FIELD_OF_VIEW_Y / 2.).sin();
+    let eye = target + Vector3::new(0., -0.5, 1.).normalize() * distance;
+    let view = Isometry3::look_at_rh(&eye, &target, &Vector3::z());
+    let projection = Perspective3::new(
+        f64::from(width) / f64::from(height),
+        FIELD_OF_VIEW_Y,
+        0.01 * distance,
+        2. * distance + radius,
+    );
+    projection.as_matrix() * view.to_homogeneous()
+}
+
+/// Renders the nodes visible through 'matrix', starting with the largest ones on screen, until
+/// 'point_budget' points have been drawn. Every point covers a single pixel. The points are
+/// changed by 'filter' like the node data sent to the client.
+pub fn render(
+    octree: &Octree,
+    matrix: &Matrix4<f64>,
+    width: u32,
+    height: u32,
+    point_budget: usize,
+    filter: Option<&dyn NodeFilter>,
+) -> Result<RgbaImage> {
+    let options = SnapshotOptions {
+        width,
+        height,
+        point_size: 1,
+        background: BACKGROUND,
+        point_budget: Some(point_budget),
+        ..Default::default()
+    };
+    render_snapshot_with_node_reader(octree, matrix, &options, |node_id| {
+        let mut node_data = octree.get_node_data(node_id)?;
+        srgb_colors(octree, &mut node_data);
+        if let Some(filter) = filter {
+            filter.filter(node_id, &mut node_data)?;
+        }
+        Ok(node_data)
+    })
+}
@@ -0,0 +1,84 @@
+//! TLS termination in the server, so that simple deployments do not need a proxy in front of it.
+//! Optionally, clients have to present a certificate signed by a trusted CA (mutual TLS).
+
+use crate::backend_error::PointsViewerError;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// The '[tls]' table of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain of the server, starting with its own certificate.
+    pub certificate: PathBuf,
+    /// PEM file with the private key of the server, in PKCS#8 or RSA format.
+    pub private_key: PathBuf,
+    /// PEM file with the CA certificates that client certificates have to be signed by. Without
+    /// it, clients are not asked for a certificate.
+    pub client_ca: Option<PathBuf>,
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, PointsViewerError> {
+    File::open(path).map(BufReader::new).map_err(|err| {
+        PointsViewerError::BadRequest(format!("Could not open {}: {}", path.display(), err))
+    })
+}
+
+fn invalid_pem(path: &Path, what: &str) -> PointsViewerError {
+    PointsViewerError::BadRequest(format!("{} contains no valid {}.", path.display(), what))
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>, PointsViewerError> {
+    match certs(&mut open(path)?) {
+        Ok(certificates) if !certificates.is_empty() => Ok(certificates),
+        _ => Err(invalid_pem(path, "certificates")),
+    }
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey, PointsViewerError> {
+    let mut keys = pkcs8_private_keys(&mut open(path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?).unwrap_or_default();
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid_pem(path, "private key"))
+}
+
+impl TlsConfig {
+    /// Loads the certificates and the key into the configuration of the server.
+    pub fn server_config(&self) -> Result<ServerConfig, PointsViewerError> {
+        let client_verifier = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in load_certificates(client_ca)? {
+                    roots
+                        .add(&certificate)
+                        .map_err(|_| invalid_pem(client_ca, "CA certificates"))?;
+                }
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(client_verifier);
+        config
+            .set_single_cert(
+                load_certificates(&self.certificate)?,
+                load_private_key(&self.private_key)?,
+            )
+            .map_err(|err| {
+                PointsViewerError::BadRequest(format!(
+                    "Could not use the certificate {} with the private key {}: {}",
+                    self.certificate.display(),
+                    self.private_key.display(),
+                    err
+                ))
+            })?;
+        Ok(config)
+    }
+}
@@ -1,11 +1,23 @@
-use crate::backend::{get_nodes_data, get_visible_nodes};
+use crate::admin::{
+    get_admin_cache, get_admin_datasets, get_health, get_ready, post_admin_evict,
+    post_admin_reload, PROBE_PATHS,
+};
+use crate::backend::{
+    get_datasets, get_frames, get_node_data, get_nodes_batch, get_nodes_data, get_overlays,
+    get_overview, get_thumbnail, get_view_settings, get_visible_nodes,
+    MAX_NODES_BATCH_REQUEST_BYTES,
+};
 use crate::backend_error::PointsViewerError;
+use crate::metrics::{self, get_metrics};
 use crate::state::AppState;
-use actix_web::{web, HttpResponse, HttpServer};
+use actix_web::dev::{BodySize, MessageBody, Service};
+use actix_web::{middleware::Logger, web, HttpResponse, HttpServer};
+use futures::future::{self, Either};
+use rustls::ServerConfig;
 use std::sync::Arc;
 
 const INDEX_HTML: &str = include_str!("../client/index.html");
-const APP_BUNDLE: &str = include_str!("../../target/app_bundle.js");
+pub(crate) const APP_BUNDLE: &str = include_str!("../../target/app_bundle.js");
 const APP_BUNDLE_MAP: &str = include_str!("../../target/app_bundle.js.map");
 
 pub fn index() -> HttpResponse {
@@ -32,24 +44,81 @@ pub fn get_init_tree(state: web::Data<Arc<AppState>>) -> HttpResponse {
         .body(state.get_init_id())
 }
 
-/// octree server function
+/// octree server function, serving HTTPS if 'tls' is given
 pub fn start_octree_server(
     app_state: Arc<AppState>,
     ip_port: &str,
+    tls: Option<ServerConfig>,
 ) -> Result<(), PointsViewerError> {
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
+        let quota_state = Arc::clone(&app_state);
         actix_web::App::new()
             .data(Arc::clone(&app_state))
+            // Every request counts against the limits of its client, see 'Quotas'. Probes are
+            // exempt, so that a busy server is not restarted by its orchestrator.
+            .wrap_fn(move |req, srv| {
+                let quotas = quota_state.quotas();
+                let client = quotas.identify(req.request());
+                let admitted = if PROBE_PATHS.contains(&req.path()) {
+                    Ok(())
+                } else {
+                    quotas.admit(&client)
+                };
+                let response = match admitted {
+                    Ok(()) => Either::Left(srv.call(req)),
+                    Err(throttled) => {
+                        metrics::record_throttled(quotas.name(&client), throttled.reason);
+                        Either::Right(future::ok(req.into_response(throttled.response())))
+                    }
+                };
+                async move {
+                    let response = response.await?;
+                    if let BodySize::Sized(num_bytes) = response.response().body().size() {
+                        quotas.charge(&client, num_bytes as usize);
+                        metrics::record_client_bytes(quotas.name(&client), num_bytes as usize);
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap(Logger::default())
             .service(web::resource("/").route(web::get().to(index)))
             .service(web::resource("/app_bundle.js").route(web::get().to(app_bundle)))
             .service(
                 web::resource("/app_bundle.js.map").route(web::get().to(app_bundle_source_map)),
             )
             .service(web::resource("/init_tree").to(get_init_tree))
+            .service(web::resource("/metrics").route(web::get().to(get_metrics)))
+            .service(web::resource("/healthz").route(web::get().to(get_health)))
+            .service(web::resource("/readyz").route(web::get().to(get_ready)))
+            .service(web::resource("/admin/datasets").route(web::get().to(get_admin_datasets)))
+            .service(web::resource("/admin/cache").route(web::get().to(get_admin_cache)))
+            .service(web::resource("/admin/reload").route(web::post().to(post_admin_reload)))
+            .service(web::resource("/admin/evict").route(web::post().to(post_admin_evict)))
             .service(web::resource("/visible_nodes/{octree_id}/").to(get_visible_nodes))
             .service(web::resource("/nodes_data/{octree_id}/").to(get_nodes_data))
-    })
-    .bind(&ip_port)
+            .service(
+                web::resource("/nodes_batch/{octree_id}/")
+                    .app_data(web::JsonConfig::default().limit(MAX_NODES_BATCH_REQUEST_BYTES))
+                    .route(web::post().to(get_nodes_batch)),
+            )
+            .service(
+                web::resource("/node_data/{octree_id}/{node_id}")
+                    .route(web::get().to(get_node_data)),
+            )
+            .service(web::resource("/thumbnail/{octree_id}/").route(web::get().to(get_thumbnail)))
+            .service(web::resource("/overview/{octree_id}/").route(web::get().to(get_overview)))
+            .service(web::resource("/datasets").route(web::get().to(get_datasets)))
+            .service(web::resource("/frames/{octree_id}/").route(web::get().to(get_frames)))
+            .service(
+                web::resource("/view_settings/{octree_id}/")
+                    .route(web::get().to(get_view_settings)),
+            )
+            .service(web::resource("/overlays/").route(web::get().to(get_overlays)))
+    });
+    match tls {
+        Some(tls) => server.bind_rustls(&ip_port, tls),
+        None => server.bind(&ip_port),
+    }
     .unwrap_or_else(|_| panic!("Can not bind to {}", &ip_port))
     .run();
     Ok(())
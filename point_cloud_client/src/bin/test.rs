@@ -1,7 +1,7 @@
 use clap::Clap;
 use nalgebra::Point3;
 use point_cloud_client::PointCloudClientBuilder;
-use point_viewer::errors::{ErrorKind, Result};
+use point_viewer::errors::{Error, Result};
 use point_viewer::geometry::Aabb;
 use point_viewer::iterator::{PointLocation, PointQuery};
 use point_viewer::PointsBatch;
@@ -91,7 +91,7 @@ fn main() {
     match point_cloud_client.for_each_point_data(&point_location, callback_func) {
         Ok(_) => (),
         Err(e) => match e.kind() {
-            ErrorKind::Io(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Error::Io(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
             _ => {
                 eprintln!("Encountered error:\n{}", e);
                 std::process::exit(1);
@@ -91,7 +91,9 @@ impl<'a> PointCloudClientBuilder<'a> {
 
     pub fn build(self) -> Result<PointCloudClient> {
         if self.locations.is_empty() {
-            return Err("No locations specified for point cloud client.".into());
+            return Err(Error::InvalidInput(
+                "No locations specified for point cloud client.".to_string(),
+            ));
         }
         let data_providers = self
             .locations
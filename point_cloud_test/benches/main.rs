@@ -2,9 +2,13 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use point_cloud_client::PointCloudClient;
 use point_cloud_test_lib::queries::*;
 use point_cloud_test_lib::{
-    make_octree, make_s2_cells, setup_octree_client, setup_s2_client, Arguments, SyntheticData,
+    make_octree, make_s2_cells, setup_octree_client, setup_pointcloud, setup_s2_client, Arguments,
+    SyntheticData,
 };
-use point_viewer::iterator::{PointLocation, PointQuery};
+use point_viewer::batching::Batched;
+use point_viewer::iterator::{PointCloud, PointLocation, PointQuery};
+use point_viewer::read_write::{Encoding, NodeWriter, OpenMode, PlyIterator, PlyNodeWriter};
+use point_viewer::NUM_POINTS_PER_BATCH;
 use tempdir::TempDir;
 
 fn bench_octree_building_multithreaded(c: &mut Criterion) {
@@ -29,6 +33,46 @@ fn bench_s2_building_singlethreaded(c: &mut Criterion) {
     });
 }
 
+fn bench_ply_parsing(c: &mut Criterion) {
+    let args = Arguments::default();
+    let temp_dir = TempDir::new("ply").unwrap();
+    let ply_path = temp_dir.path().join("points.ply");
+    {
+        let mut writer = PlyNodeWriter::new(&ply_path, Encoding::Plain, OpenMode::Truncate);
+        let points = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
+        for batch in Batched::new(points, args.batch_size) {
+            NodeWriter::write(&mut writer, &batch).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+    c.bench_function("bench_ply_parsing", |b| {
+        b.iter(|| {
+            let num_points: usize = PlyIterator::from_file(&ply_path, NUM_POINTS_PER_BATCH)
+                .unwrap()
+                .map(|batch| batch.position.len())
+                .sum();
+            black_box(num_points);
+        })
+    });
+}
+
+fn bench_node_decoding(c: &mut Criterion) {
+    let (_, octree, _) = setup_pointcloud(&Arguments::default());
+    let node_ids = octree.nodes_in_location(&PointLocation::AllPoints);
+    c.bench_function("bench_node_decoding", |b| {
+        b.iter(|| {
+            for node_id in &node_ids {
+                for batch in octree
+                    .points_in_node(&["color"], *node_id, NUM_POINTS_PER_BATCH)
+                    .unwrap()
+                {
+                    black_box(batch);
+                }
+            }
+        })
+    });
+}
+
 fn all_query_octree(b: &mut Criterion) {
     run_bench(
         "all_query_octree",
@@ -98,6 +142,8 @@ criterion_group!(
     benches,
     bench_octree_building_multithreaded,
     bench_s2_building_singlethreaded,
+    bench_ply_parsing,
+    bench_node_decoding,
     all_query_octree,
     all_query_s2,
     box_query_octree,
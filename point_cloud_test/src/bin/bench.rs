@@ -0,0 +1,189 @@
+//! Measures the throughput of parsing, building, decoding and querying on user data, and prints a
+//! report that can be compared between versions. The criterion benches in 'benches/' do the same
+//! on synthetic data. Only PLY parsing is measured, since that is the only input format we read.
+
+use clap::Clap;
+use nalgebra::Vector3;
+use point_viewer::data_provider::OnDiskDataProvider;
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::{PointCloud, PointLocation, PointQuery};
+use point_viewer::octree::{build_octree_from_file, Octree};
+use point_viewer::read_write::PlyIterator;
+use point_viewer::NUM_POINTS_PER_BATCH;
+use rand::{Rng, SeedableRng};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tempdir::TempDir;
+
+#[derive(Clap, Debug)]
+#[clap(name = "bench")]
+struct CommandlineArguments {
+    /// PLY file to measure parsing and octree building with.
+    #[clap(long, parse(from_os_str))]
+    ply: Option<PathBuf>,
+
+    /// Octree to measure node decoding and queries with. If not given, the octree built from
+    /// '--ply' is used.
+    #[clap(long, parse(from_os_str))]
+    octree: Option<PathBuf>,
+
+    /// Resolution of the octree built from '--ply'.
+    #[clap(long, default_value = "0.001")]
+    resolution: f64,
+
+    /// Number of random box queries for measuring the query latency.
+    #[clap(long, default_value = "20")]
+    num_queries: usize,
+
+    /// Edge length of the query boxes, relative to the bounding box of the octree.
+    #[clap(long, default_value = "0.1")]
+    query_size: f64,
+
+    /// Seed for placing the query boxes, so that reports are comparable.
+    #[clap(long, default_value = "42")]
+    seed: u64,
+}
+
+struct Throughput {
+    name: &'static str,
+    num_points: usize,
+    duration: Duration,
+}
+
+impl Throughput {
+    fn print(&self) {
+        let seconds = self.duration.as_secs_f64();
+        println!(
+            "{:<16} {:>14} points {:>10.3} s {:>14.0} points/s",
+            self.name,
+            self.num_points,
+            seconds,
+            self.num_points as f64 / seconds
+        );
+    }
+}
+
+fn measure_parsing(ply: &Path) -> Throughput {
+    let start = Instant::now();
+    let num_points = PlyIterator::from_file(ply, NUM_POINTS_PER_BATCH)
+        .expect("Could not open PLY file.")
+        .map(|batch| batch.position.len())
+        .sum();
+    Throughput {
+        name: "ply parsing",
+        num_points,
+        duration: start.elapsed(),
+    }
+}
+
+fn measure_building(
+    ply: &Path,
+    resolution: f64,
+    output_directory: &Path,
+    num_points: usize,
+) -> Throughput {
+    let start = Instant::now();
+    build_octree_from_file(output_directory, resolution, ply, &["color", "intensity"]);
+    Throughput {
+        name: "octree building",
+        num_points,
+        duration: start.elapsed(),
+    }
+}
+
+fn measure_decoding(octree: &Octree) -> Throughput {
+    let start = Instant::now();
+    let mut num_points = 0;
+    for node_id in octree.nodes_in_location(&PointLocation::AllPoints) {
+        for batch in octree
+            .points_in_node(&["color"], node_id, NUM_POINTS_PER_BATCH)
+            .expect("Could not read node.")
+        {
+            num_points += batch.position.len();
+        }
+    }
+    Throughput {
+        name: "node decoding",
+        num_points,
+        duration: start.elapsed(),
+    }
+}
+
+fn measure_queries(octree: &Octree, args: &CommandlineArguments) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+    let bounding_box = octree.bounding_box();
+    let extent = bounding_box.diag() * args.query_size;
+    let mut latencies = Vec::with_capacity(args.num_queries);
+    let mut total_points = 0;
+    for _ in 0..args.num_queries {
+        let min = bounding_box.min()
+            + Vector3::new(
+                rng.gen_range(0., 1.),
+                rng.gen_range(0., 1.),
+                rng.gen_range(0., 1.),
+            )
+            .component_mul(&(bounding_box.diag() - extent));
+        let query = PointQuery {
+            attributes: vec!["color"],
+            location: PointLocation::Aabb(Aabb::new(min, min + extent)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        for node_id in octree.nodes_in_location(&query.location) {
+            octree
+                .stream_points_for_query_in_node(&query, node_id, NUM_POINTS_PER_BATCH, |batch| {
+                    total_points += batch.position.len();
+                    Ok(())
+                })
+                .expect("Query failed.");
+        }
+        latencies.push(start.elapsed());
+    }
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: f64| {
+        let index = ((latencies.len() - 1) as f64 * p).round() as usize;
+        latencies[index].as_secs_f64() * 1000.
+    };
+    println!(
+        "{:<16} {:>14} queries, {:.0} points on average, latency median {:.1} ms, p90 {:.1} ms, \
+         max {:.1} ms",
+        "box queries",
+        latencies.len(),
+        total_points as f64 / latencies.len() as f64,
+        percentile(0.5),
+        percentile(0.9),
+        percentile(1.)
+    );
+}
+
+fn main() {
+    let args = CommandlineArguments::parse();
+    if args.ply.is_none() && args.octree.is_none() {
+        panic!("Nothing to measure, pass '--ply' and/or '--octree'.");
+    }
+
+    // Keeps the octree built from the PLY file alive until we are done.
+    let mut built_octree_dir = None;
+    if let Some(ply) = &args.ply {
+        let parsing = measure_parsing(ply);
+        parsing.print();
+        let temp_dir = TempDir::new("bench_octree").expect("Could not create temp dir.");
+        measure_building(ply, args.resolution, temp_dir.path(), parsing.num_points).print();
+        built_octree_dir = Some(temp_dir);
+    }
+
+    let octree_directory = args
+        .octree
+        .clone()
+        .or_else(|| built_octree_dir.as_ref().map(|d| d.path().to_path_buf()))
+        .unwrap();
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: octree_directory,
+    }))
+    .expect("Could not open octree.");
+    measure_decoding(&octree).print();
+    measure_queries(&octree, &args);
+}
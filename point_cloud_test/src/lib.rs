@@ -1,8 +1,9 @@
 use point_cloud_client::{PointCloudClient, PointCloudClientBuilder};
 /// This module has functions to generate synthetic point clouds in a temp dir
 /// and provides queries on these synthetic point clouds.
+use point_viewer::batching::Batched;
 use point_viewer::data_provider::OnDiskDataProvider;
-use point_viewer::octree::{build_octree, Octree};
+use point_viewer::octree::{build_octree, BuildOptions, Octree};
 use point_viewer::read_write::{Encoding, NodeWriter, OpenMode, RawNodeWriter, S2Splitter};
 use point_viewer::s2_cells::S2Cells;
 use point_viewer::META_FILENAME;
@@ -14,7 +15,7 @@ use std::sync::Once;
 use tempdir::TempDir;
 
 pub mod synthetic_data;
-pub use synthetic_data::{Batched, SyntheticData};
+pub use synthetic_data::SyntheticData;
 
 pub mod queries;
 
@@ -57,7 +58,14 @@ pub fn make_octree(args: &Arguments, dir: &Path) {
     let bbox = points_oct.bbox();
     let batches_oct = Batched::new(points_oct, args.batch_size);
 
-    build_octree(dir, args.resolution, bbox, batches_oct, &["color"]);
+    build_octree(
+        dir,
+        args.resolution,
+        bbox,
+        batches_oct,
+        &["color"],
+        &BuildOptions::default(),
+    );
 }
 
 pub fn make_s2_cells(args: &Arguments, dir: &Path) {
@@ -69,7 +77,11 @@ pub fn make_s2_cells(args: &Arguments, dir: &Path) {
         .expect("Writing failed");
     // An S2 writer that has not written any points cannot produce a meta proto,
     // but in this case we know it did write points.
-    let meta = s2_writer.get_meta().unwrap().to_proto();
+    let meta = s2_writer
+        .get_meta()
+        .expect("Writing failed")
+        .unwrap()
+        .to_proto();
     let mut meta_writer = BufWriter::new(File::create(dir.join(META_FILENAME)).unwrap());
     meta.write_to_writer(&mut meta_writer).unwrap();
 }
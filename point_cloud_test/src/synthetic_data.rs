@@ -1,12 +1,10 @@
-use nalgebra::{Isometry3, Point3, Vector3};
-use point_viewer::attributes::AttributeData;
+use nalgebra::{Isometry3, Point3};
 use point_viewer::color::Color;
 use point_viewer::geometry::Aabb;
 use point_viewer::math::local_frame_from_lat_lng;
-use point_viewer::{NumberOfPoints, Point, PointsBatch};
+use point_viewer::Point;
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
-use std::collections::BTreeMap;
 
 #[derive(Clone)]
 pub struct SyntheticData {
@@ -81,73 +79,3 @@ impl Iterator for SyntheticData {
         (self.size, Some(self.size))
     }
 }
-
-pub struct Batched<T>
-where
-    T: Iterator<Item = Point>,
-{
-    inner: T,
-    batch: PointsBatch,
-    batch_size: usize,
-}
-
-impl<T> Batched<T>
-where
-    T: Iterator<Item = Point>,
-{
-    fn new_batch(batch_size: usize) -> PointsBatch {
-        let mut attrs = BTreeMap::new();
-        attrs.insert(
-            "color".to_owned(),
-            AttributeData::U8Vec3(Vec::with_capacity(batch_size)),
-        );
-        PointsBatch {
-            position: Vec::with_capacity(batch_size),
-            attributes: attrs,
-        }
-    }
-
-    pub fn new(inner: T, batch_size: usize) -> Self {
-        let batch = Self::new_batch(batch_size);
-        Batched {
-            inner,
-            batch,
-            batch_size,
-        }
-    }
-}
-
-impl<T> NumberOfPoints for Batched<T>
-where
-    T: Iterator<Item = Point>,
-{
-    fn num_points(&self) -> usize {
-        self.inner.size_hint().1.unwrap()
-    }
-}
-
-impl<T> Iterator for Batched<T>
-where
-    T: Iterator<Item = Point>,
-{
-    type Item = PointsBatch;
-
-    fn next(&mut self) -> Option<PointsBatch> {
-        for _ in 0..self.batch_size {
-            if let Some(pt) = self.inner.next() {
-                self.batch.position.push(pt.position);
-                let color = Vector3::new(pt.color.red, pt.color.green, pt.color.blue);
-                self.batch
-                    .get_attribute_vec_mut("color")
-                    .unwrap()
-                    .push(color);
-            }
-        }
-        if !self.batch.position.is_empty() {
-            let output_batch = std::mem::replace(&mut self.batch, Self::new_batch(self.batch_size));
-            Some(output_batch)
-        } else {
-            None
-        }
-    }
-}
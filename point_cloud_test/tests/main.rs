@@ -2,6 +2,7 @@ use nalgebra::{Point3, Vector3};
 use num_integer::div_ceil;
 use point_cloud_test_lib::queries::*;
 use point_cloud_test_lib::{setup_pointcloud, Arguments, SyntheticData};
+use point_viewer::errors::Error;
 use point_viewer::iterator::PointCloud;
 use point_viewer::iterator::{PointLocation, PointQuery};
 use point_viewer::math::{sat, ConvexPolyhedron, PointCulling};
@@ -134,7 +135,9 @@ where
     for node_id in point_cloud.nodes_in_location(&query.location).into_iter() {
         point_cloud
             .stream_points_for_query_in_node(query, node_id, batch_size, |batch| {
-                let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color")?;
+                let color: &Vec<Vector3<u8>> = batch
+                    .get_attribute_vec("color")
+                    .map_err(Error::SchemaMismatch)?;
                 let indexed_point_iter = color.iter().zip(batch.position.iter()).map(|(c, p)| {
                     // Decode the index we encoded in the color
                     let idx = ((c.x as usize) << 16) + ((c.y as usize) << 8) + c.z as usize;
@@ -0,0 +1,84 @@
+#ifndef POINT_VIEWER_H
+#define POINT_VIEWER_H
+
+/* Generated by cbindgen from point_viewer_c. Do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * An octree opened by 'pv_octree_open'.
+ */
+typedef struct PvOctree PvOctree;
+
+/**
+ * A batch of points passed to a 'PvPointsCallback'. The arrays are only valid during the call.
+ */
+typedef struct {
+  uintptr_t num_points;
+  /**
+   * x, y and z of every point.
+   */
+  const double *positions;
+  /**
+   * Red, green and blue of every point.
+   */
+  const uint8_t *colors;
+} PvPoints;
+
+/**
+ * Receives the points of a query batch by batch on the thread that started the query, together
+ * with the 'user_data' passed to the query. Returns false to stop the query.
+ */
+typedef bool (*PvPointsCallback)(const PvPoints *points, void *user_data);
+
+/**
+ * Returns the message of the last error on the calling thread, or null. The message is valid
+ * until the next call that fails on this thread.
+ */
+const char *pv_last_error(void);
+
+/**
+ * Writes the minimum and maximum corners of the bounding box of 'octree' into 'min' and 'max'.
+ *
+ * # Safety
+ *
+ * 'min' and 'max' must point to 3 doubles each.
+ */
+bool pv_octree_bounding_box(const PvOctree *octree, double *min, double *max);
+
+/**
+ * Frees an octree returned by 'pv_octree_open'. Does nothing for null.
+ *
+ * # Safety
+ *
+ * 'octree' must not be used afterwards.
+ */
+void pv_octree_free(PvOctree *octree);
+
+/**
+ * Opens the octree in 'directory', a UTF-8 path. Returns null on failure.
+ *
+ * # Safety
+ *
+ * 'directory' must be a null-terminated string.
+ */
+PvOctree *pv_octree_open(const char *directory);
+
+/**
+ * Passes all points of 'octree' within the box from 'min' to 'max' to 'callback'. The nodes are
+ * read in parallel. Returns true if all points were passed or 'callback' stopped the query.
+ *
+ * # Safety
+ *
+ * 'min' and 'max' must point to 3 doubles each.
+ */
+bool pv_octree_query_box(const PvOctree *octree,
+                         const double *min,
+                         const double *max,
+                         PvPointsCallback callback,
+                         void *user_data);
+
+#endif /* POINT_VIEWER_H */
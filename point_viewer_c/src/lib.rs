@@ -0,0 +1,224 @@
+//! C API to read octrees from other applications, e.g. game engine plugins. The header is
+//! 'include/point_viewer.h', which is generated by cbindgen when the crate is built.
+//!
+//! Functions that can fail return false or null, and 'pv_last_error' then describes the failure.
+//! Octrees are opened with 'pv_octree_open' and must be freed with 'pv_octree_free'. No Rust panic
+//! crosses the API.
+
+use nalgebra::Point3;
+use point_viewer::attributes::AttributeData;
+use point_viewer::data_provider::OnDiskDataProvider;
+use point_viewer::errors::*;
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::{ParallelIterator, PointCloud, PointLocation, PointQuery};
+use point_viewer::octree::Octree;
+use point_viewer::NUM_POINTS_PER_BATCH;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// An octree opened by 'pv_octree_open'.
+pub struct PvOctree {
+    octree: Octree,
+}
+
+/// A batch of points passed to a 'PvPointsCallback'. The arrays are only valid during the call.
+#[repr(C)]
+pub struct PvPoints {
+    pub num_points: usize,
+    /// x, y and z of every point.
+    pub positions: *const f64,
+    /// Red, green and blue of every point.
+    pub colors: *const u8,
+}
+
+/// Receives the points of a query batch by batch on the thread that started the query, together
+/// with the 'user_data' passed to the query. Returns false to stop the query.
+pub type PvPointsCallback =
+    Option<unsafe extern "C" fn(points: *const PvPoints, user_data: *mut c_void) -> bool>;
+
+/// Runs 'func', and returns 'on_error' after recording the error if it fails or panics.
+fn ffi_call<T>(on_error: T, func: impl FnOnce() -> Result<T>) -> T {
+    let message = match panic::catch_unwind(AssertUnwindSafe(func)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(err)) => err.to_string(),
+        Err(panic) => match panic.downcast_ref::<&str>() {
+            Some(message) => format!("Panic: {}", message),
+            None => match panic.downcast_ref::<String>() {
+                Some(message) => format!("Panic: {}", message),
+                None => "Panic.".to_string(),
+            },
+        },
+    };
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+    on_error
+}
+
+fn check_not_null<T>(pointer: *const T, name: &str) -> Result<()> {
+    if pointer.is_null() {
+        return Err(Error::InvalidInput(format!("'{}' is null.", name)));
+    }
+    Ok(())
+}
+
+/// Returns the message of the last error on the calling thread, or null. The message is valid
+/// until the next call that fails on this thread.
+#[no_mangle]
+pub extern "C" fn pv_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Opens the octree in 'directory', a UTF-8 path. Returns null on failure.
+///
+/// # Safety
+///
+/// 'directory' must be a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pv_octree_open(directory: *const c_char) -> *mut PvOctree {
+    ffi_call(ptr::null_mut(), || {
+        check_not_null(directory, "directory")?;
+        let directory = CStr::from_ptr(directory)
+            .to_str()
+            .map_err(|_| Error::InvalidInput("'directory' is not valid UTF-8.".to_string()))?;
+        let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: directory.into(),
+        }))?;
+        Ok(Box::into_raw(Box::new(PvOctree { octree })))
+    })
+}
+
+/// Frees an octree returned by 'pv_octree_open'. Does nothing for null.
+///
+/// # Safety
+///
+/// 'octree' must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pv_octree_free(octree: *mut PvOctree) {
+    if !octree.is_null() {
+        drop(Box::from_raw(octree));
+    }
+}
+
+/// Writes the minimum and maximum corners of the bounding box of 'octree' into 'min' and 'max'.
+///
+/// # Safety
+///
+/// 'min' and 'max' must point to 3 doubles each.
+#[no_mangle]
+pub unsafe extern "C" fn pv_octree_bounding_box(
+    octree: *const PvOctree,
+    min: *mut f64,
+    max: *mut f64,
+) -> bool {
+    ffi_call(false, || {
+        check_not_null(octree, "octree")?;
+        check_not_null(min, "min")?;
+        check_not_null(max, "max")?;
+        let bounding_box = (*octree).octree.bounding_box();
+        slice::from_raw_parts_mut(min, 3).copy_from_slice(bounding_box.min().coords.as_slice());
+        slice::from_raw_parts_mut(max, 3).copy_from_slice(bounding_box.max().coords.as_slice());
+        Ok(true)
+    })
+}
+
+/// Passes all points of 'octree' within the box from 'min' to 'max' to 'callback'. The nodes are
+/// read in parallel. Returns true if all points were passed or 'callback' stopped the query.
+///
+/// # Safety
+///
+/// 'min' and 'max' must point to 3 doubles each.
+#[no_mangle]
+pub unsafe extern "C" fn pv_octree_query_box(
+    octree: *const PvOctree,
+    min: *const f64,
+    max: *const f64,
+    callback: PvPointsCallback,
+    user_data: *mut c_void,
+) -> bool {
+    ffi_call(false, || {
+        check_not_null(octree, "octree")?;
+        check_not_null(min, "min")?;
+        check_not_null(max, "max")?;
+        let callback =
+            callback.ok_or_else(|| Error::InvalidInput("'callback' is null.".to_string()))?;
+        let min = slice::from_raw_parts(min, 3);
+        let max = slice::from_raw_parts(max, 3);
+        let query = PointQuery {
+            attributes: vec!["color"],
+            location: PointLocation::Aabb(Aabb::new(
+                Point3::new(min[0], min[1], min[2]),
+                Point3::new(max[0], max[1], max[2]),
+            )),
+            ..Default::default()
+        };
+        let num_threads = std::cmp::max(1, num_cpus::get() - 1);
+        let mut positions = Vec::new();
+        let mut colors = Vec::new();
+        let mut stopped = false;
+        let result = ParallelIterator::new(
+            slice::from_ref(&(*octree).octree),
+            &query,
+            NUM_POINTS_PER_BATCH,
+            num_threads,
+            4,
+        )
+        .try_for_each_batch(|batch| {
+            positions.clear();
+            positions.extend(batch.position.iter().flat_map(|p| p.coords.iter().copied()));
+            colors.clear();
+            match batch.attributes.get("color") {
+                Some(AttributeData::U8Vec3(data)) => {
+                    colors.extend(data.iter().flat_map(|color| color.iter().copied()))
+                }
+                _ => return Err(Error::InvalidInput("Points have no colors.".into())),
+            }
+            let points = PvPoints {
+                num_points: batch.position.len(),
+                positions: positions.as_ptr(),
+                colors: colors.as_ptr(),
+            };
+            if callback(&points, user_data) {
+                Ok(())
+            } else {
+                stopped = true;
+                Err(Error::Channel("Stopped by the callback.".to_string()))
+            }
+        });
+        match result {
+            Err(_) if stopped => Ok(true),
+            result => result.map(|_| true),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_are_reported() {
+        let directory = CString::new("/does/not/exist").unwrap();
+        let octree = unsafe { pv_octree_open(directory.as_ptr()) };
+        assert!(octree.is_null());
+        assert!(!pv_last_error().is_null());
+
+        let mut min = [0.; 3];
+        let mut max = [0.; 3];
+        assert!(!unsafe { pv_octree_bounding_box(octree, min.as_mut_ptr(), max.as_mut_ptr()) });
+        let message = unsafe { CStr::from_ptr(pv_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "'octree' is null.");
+        unsafe { pv_octree_free(octree) };
+    }
+}
@@ -39,3 +39,36 @@ impl From<&proto::Vector3d> for nalgebra::Point3<f64> {
         nalgebra::Point3::new(proto_vec.get_x(), proto_vec.get_y(), proto_vec.get_z())
     }
 }
+
+impl From<&nalgebra::Isometry3<f64>> for proto::Isometry3d {
+    fn from(isometry: &nalgebra::Isometry3<f64>) -> Self {
+        let mut proto_isometry = proto::Isometry3d::new();
+        let translation = nalgebra::Point3::from(isometry.translation.vector);
+        proto_isometry.set_translation(proto::Vector3d::from(&translation));
+        let rotation = isometry.rotation.quaternion();
+        let mut proto_rotation = proto::Quaterniond::new();
+        proto_rotation.set_x(rotation.i);
+        proto_rotation.set_y(rotation.j);
+        proto_rotation.set_z(rotation.k);
+        proto_rotation.set_w(rotation.w);
+        proto_isometry.set_rotation(proto_rotation);
+        proto_isometry
+    }
+}
+
+impl From<&proto::Isometry3d> for nalgebra::Isometry3<f64> {
+    fn from(proto_isometry: &proto::Isometry3d) -> Self {
+        let rotation = proto_isometry.get_rotation();
+        nalgebra::Isometry3::from_parts(
+            nalgebra::Translation3::from(
+                nalgebra::Point3::from(proto_isometry.get_translation()).coords,
+            ),
+            nalgebra::UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(
+                rotation.get_w(),
+                rotation.get_x(),
+                rotation.get_y(),
+                rotation.get_z(),
+            )),
+        )
+    }
+}
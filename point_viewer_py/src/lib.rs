@@ -0,0 +1,181 @@
+//! Python bindings to read point clouds into numpy arrays. Points are returned as a dict with the
+//! positions as an (N, 3) float64 array under 'position' and an array per attribute, of shape (N, 3)
+//! for vector attributes like 'color'.
+
+use nalgebra::{Point3, Scalar, Vector3};
+use numpy::{Element, PyArray1};
+use point_cloud_client::{PointCloudClient, PointCloudClientBuilder};
+use point_viewer::attributes::AttributeData;
+use point_viewer::errors::Error;
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::{PointLocation, PointQuery};
+use point_viewer::read_write;
+use point_viewer::{PointsBatch, NUM_POINTS_PER_BATCH};
+use pyo3::exceptions::PyIOError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::PyIterProtocol;
+
+fn to_py_err(err: Error) -> PyErr {
+    PyIOError::new_err(err.to_string())
+}
+
+/// Moves 'data' with 'dim' values per point into a numpy array of shape (N,) or (N, dim).
+fn to_numpy<T: Element>(py: Python, data: Vec<T>, dim: usize) -> PyResult<PyObject> {
+    let array = PyArray1::from_vec(py, data);
+    if dim == 1 {
+        return Ok(array.to_object(py));
+    }
+    let num_points = array.len() / dim;
+    Ok(array.reshape([num_points, dim])?.to_object(py))
+}
+
+fn flatten<T: Element + Scalar + Copy>(data: &[Vector3<T>]) -> Vec<T> {
+    data.iter().flat_map(|v| v.iter().copied()).collect()
+}
+
+fn batch_to_dict(py: Python, batch: PointsBatch) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    let positions = batch
+        .position
+        .iter()
+        .flat_map(|p| p.coords.iter().copied())
+        .collect();
+    dict.set_item("position", to_numpy(py, positions, 3)?)?;
+    for (name, data) in batch.attributes {
+        let array = match data {
+            AttributeData::U8(data) => to_numpy(py, data, 1)?,
+            AttributeData::U16(data) => to_numpy(py, data, 1)?,
+            AttributeData::U32(data) => to_numpy(py, data, 1)?,
+            AttributeData::U64(data) => to_numpy(py, data, 1)?,
+            AttributeData::I8(data) => to_numpy(py, data, 1)?,
+            AttributeData::I16(data) => to_numpy(py, data, 1)?,
+            AttributeData::I32(data) => to_numpy(py, data, 1)?,
+            AttributeData::I64(data) => to_numpy(py, data, 1)?,
+            AttributeData::F32(data) => to_numpy(py, data, 1)?,
+            AttributeData::F64(data) => to_numpy(py, data, 1)?,
+            AttributeData::U8Vec3(data) => to_numpy(py, flatten(&data), 3)?,
+            AttributeData::F64Vec3(data) => to_numpy(py, flatten(&data), 3)?,
+        };
+        dict.set_item(name, array)?;
+    }
+    Ok(dict.to_object(py))
+}
+
+/// Octrees or S2 cells, opened from their directories or any other location that the
+/// 'point_cloud_client' can open.
+#[pyclass]
+struct PointCloud {
+    client: PointCloudClient,
+}
+
+#[pymethods]
+impl PointCloud {
+    #[new]
+    fn new(locations: Vec<String>) -> PyResult<Self> {
+        let client = PointCloudClientBuilder::new(&locations)
+            .build()
+            .map_err(to_py_err)?;
+        Ok(Self { client })
+    }
+
+    /// Returns ((min_x, min_y, min_z), (max_x, max_y, max_z)) of all point clouds.
+    fn bounding_box(&self) -> ((f64, f64, f64), (f64, f64, f64)) {
+        let aabb = self.client.bounding_box();
+        let (min, max) = (aabb.min(), aabb.max());
+        ((min.x, min.y, min.z), (max.x, max.y, max.z))
+    }
+
+    /// Returns the points from 'min' to 'max' with 'attributes'.
+    #[args(attributes = "Vec::new()")]
+    fn points_in_box(
+        &self,
+        py: Python,
+        min: (f64, f64, f64),
+        max: (f64, f64, f64),
+        attributes: Vec<String>,
+    ) -> PyResult<PyObject> {
+        let aabb = Aabb::new(
+            Point3::new(min.0, min.1, min.2),
+            Point3::new(max.0, max.1, max.2),
+        );
+        self.query(py, PointLocation::Aabb(aabb), &attributes)
+    }
+
+    /// Returns all points with 'attributes'.
+    #[args(attributes = "Vec::new()")]
+    fn all_points(&self, py: Python, attributes: Vec<String>) -> PyResult<PyObject> {
+        self.query(py, PointLocation::AllPoints, &attributes)
+    }
+}
+
+impl PointCloud {
+    fn query(
+        &self,
+        py: Python,
+        location: PointLocation,
+        attributes: &[String],
+    ) -> PyResult<PyObject> {
+        let point_query = PointQuery {
+            attributes: attributes.iter().map(String::as_str).collect(),
+            location,
+            ..Default::default()
+        };
+        let client = &self.client;
+        // Other Python threads can run while the points are read.
+        let points = py
+            .allow_threads(|| -> point_viewer::errors::Result<PointsBatch> {
+                let mut points = PointsBatch::default();
+                client.for_each_point_data(&point_query, |mut batch| {
+                    points.append(&mut batch).map_err(Error::SchemaMismatch)?;
+                    Ok(())
+                })?;
+                Ok(points)
+            })
+            .map_err(to_py_err)?;
+        batch_to_dict(py, points)
+    }
+}
+
+/// Reads a binary PLY file in batches of up to 'batch_size' points.
+#[pyclass(name = PlyIterator)]
+struct PyPlyIterator {
+    iterator: read_write::PlyIterator,
+}
+
+#[pymethods]
+impl PyPlyIterator {
+    #[new]
+    #[args(batch_size = "NUM_POINTS_PER_BATCH")]
+    fn new(path: String, batch_size: usize) -> PyResult<Self> {
+        let iterator = read_write::PlyIterator::from_file(path, batch_size).map_err(to_py_err)?;
+        Ok(Self { iterator })
+    }
+
+    #[getter]
+    fn num_total_points(&self) -> i64 {
+        self.iterator.num_total_points
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for PyPlyIterator {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<PyObject>> {
+        let py = slf.py();
+        slf.iterator
+            .next()
+            .map(|batch| batch_to_dict(py, batch))
+            .transpose()
+    }
+}
+
+#[pymodule]
+fn point_viewer_py(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_class::<PointCloud>()?;
+    module.add_class::<PyPlyIterator>()?;
+    Ok(())
+}
@@ -0,0 +1,20 @@
+// The wgpu counterpart of box_drawer_outline.vs and box_drawer_outline.fs.
+
+[[block]]
+struct Outline {
+  transform: mat4x4<f32>;
+  color: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> outline: Outline;
+
+[[stage(vertex)]]
+fn vs_main([[location(0)]] position: vec3<f32>) -> [[builtin(position)]] vec4<f32> {
+  return outline.transform * vec4<f32>(position, 1.0);
+}
+
+[[stage(fragment)]]
+fn fs_main() -> [[location(0)]] vec4<f32> {
+  return outline.color;
+}
@@ -0,0 +1,66 @@
+#version 430 core
+
+// Selects the nodes of one level of the octree, see 'GpuNodeSelector'. A node is drawn if it and
+// all its ancestors are in the view and have a screen-space error of at least 'pixel_threshold'.
+
+layout(local_size_x = 64) in;
+
+struct VisibleNode {
+  uint index;
+  float error;
+};
+
+// The bounding cube of every node as (min, edge length).
+layout(std430, binding = 0) readonly buffer Cubes { dvec4 cubes[]; };
+// The index of the parent of every node, -1 for the root, and whether the node has points.
+layout(std430, binding = 1) readonly buffer Links { ivec2 links[]; };
+// Whether the traversal continues below a node.
+layout(std430, binding = 2) buffer Reached { uint reached[]; };
+layout(std430, binding = 3) buffer NumVisible { uint num_visible; };
+layout(std430, binding = 4) writeonly buffer Visible { VisibleNode visible_nodes[]; };
+
+uniform dmat4 world_to_gl;
+uniform dvec2 viewport_size;
+uniform double pixel_threshold;
+uniform uint first_node;
+uniform uint num_nodes;
+
+void main() {
+  if (gl_GlobalInvocationID.x >= num_nodes) {
+    return;
+  }
+  uint index = first_node + gl_GlobalInvocationID.x;
+  ivec2 link = links[index];
+  if (link.x >= 0 && reached[link.x] == 0u) {
+    reached[index] = 0u;
+    return;
+  }
+
+  dvec4 cube = cubes[index];
+  // Corners outside of each plane of the view, and the rectangle that the corners cover on screen,
+  // clipped to the view.
+  ivec3 below = ivec3(0);
+  ivec3 above = ivec3(0);
+  dvec2 screen_min = dvec2(1.0);
+  dvec2 screen_max = dvec2(-1.0);
+  for (int i = 0; i < 8; ++i) {
+    dvec3 corner = cube.xyz + cube.w * dvec3(i & 1, (i >> 1) & 1, (i >> 2) & 1);
+    dvec4 clip = world_to_gl * dvec4(corner, 1.0);
+    below += ivec3(lessThan(clip.xyz, -clip.www));
+    above += ivec3(greaterThan(clip.xyz, clip.www));
+    dvec2 p = clamp(clip.xy / clip.w, -1.0, 1.0);
+    screen_min = min(screen_min, p);
+    screen_max = max(screen_max, p);
+  }
+  bool in_view = all(lessThan(below, ivec3(8))) && all(lessThan(above, ivec3(8)));
+  // Like 'ScreenSpaceError::error': clip space spans 2 units in x and y.
+  dvec2 size = screen_max - screen_min;
+  double error = sqrt(size.x * size.y * viewport_size.x * viewport_size.y / 4.0);
+
+  bool visible = in_view && error >= pixel_threshold;
+  reached[index] = uint(visible);
+  if (visible && link.y != 0) {
+    uint slot = atomicAdd(num_visible, 1u);
+    visible_nodes[slot] = VisibleNode(index, float(error));
+  }
+}
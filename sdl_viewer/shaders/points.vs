@@ -3,20 +3,65 @@
 // inputs
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec3 color;
+// Unit normal and curvature, see 'Octree::shading_layout'. Nodes without them read zeros.
+layout(location = 2) in vec4 normal;
+layout(location = 3) in float curvature;
 
 uniform dmat4 world_to_gl;
 uniform double edge_length;
 uniform float size;
 uniform float gamma;
 uniform dvec3 min;
+// Whether 'color' holds linear colors instead of sRGB, see 'ColorSpace'.
+uniform bool linear_colors;
+// 0 draws the colors of the points, 1 colors them by their height in 'height_range', see
+// 'ColorMap'.
+uniform int color_map;
+uniform vec2 height_range;
+// Whether the points are lit by a light at the camera, which is at 'eye' in homogeneous
+// coordinates: a direction for orthographic views.
+uniform bool shading;
+uniform dvec4 eye;
 
 // varying outputs
 out vec4 v_color;
 
+vec3 linear_to_srgb(vec3 c) {
+  return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055,
+             step(vec3(0.0031308), c));
+}
+
+// Blue at the bottom, green in the middle and red at the top.
+vec3 height_color(float height) {
+  float t = clamp((height - height_range.x) /
+                      max(height_range.y - height_range.x, 1e-6),
+                  0., 1.);
+  return clamp(vec3(2. * t - 1., 1. - abs(2. * t - 1.), 1. - 2. * t), 0., 1.);
+}
+
 void main() {
-  vec3 corrected_color = pow(color / 255., vec3(1.0 / gamma));
+  dvec3 world_position = dvec3(position) * edge_length + min;
+  vec3 srgb_color = color / 255.;
+  if (linear_colors) {
+    srgb_color = linear_to_srgb(srgb_color);
+  }
+  if (color_map == 1) {
+    srgb_color = height_color(float(world_position.z));
+  }
+  vec3 corrected_color = pow(srgb_color, vec3(1.0 / gamma));
+  if (shading && dot(normal.xyz, normal.xyz) > 0.25) {
+    vec3 n = normalize(normal.xyz);
+    vec3 to_eye = normalize(vec3(eye.xyz - world_position * eye.w));
+    // Normals of scans often point to either side of the surface, so both sides are lit.
+    float diffuse = abs(dot(n, to_eye));
+    // The light is at the eye, so the halfway vector is the direction to the eye.
+    float specular = pow(diffuse, 32.);
+    // Darkens edges and corners, whose curvature is up to 1/3, like ambient occlusion.
+    float occlusion = 1. - 1.5 * clamp(curvature, 0., 1. / 3.);
+    corrected_color =
+        corrected_color * (0.3 + 0.7 * diffuse) * occlusion + vec3(0.2 * specular);
+  }
   v_color = vec4(corrected_color, 1.);
   gl_PointSize = size;
-  gl_Position =
-      vec4(world_to_gl * dvec4(dvec3(position) * edge_length + min, 1.0lf));
+  gl_Position = vec4(world_to_gl * dvec4(world_position, 1.0lf));
 }
@@ -0,0 +1,61 @@
+// The wgpu counterpart of points.vs and points.fs. WebGPU has no point size, so points are always
+// one pixel large.
+
+[[block]]
+struct Node {
+  // Maps the positions of the node, which are relative to its bounding cube, to clip space.
+  transform: mat4x4<f32>;
+  gamma: f32;
+  // Whether 'color' holds linear colors instead of sRGB, see 'ColorSpace'.
+  linear_colors: u32;
+  // 0 draws the colors of the points, 1 colors them by their height in 'min_height' to
+  // 'max_height', see 'ColorMap'.
+  color_map: u32;
+  // The height and edge length of the bounding cube of the node.
+  cube_min_z: f32;
+  edge_length: f32;
+  min_height: f32;
+  max_height: f32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> node: Node;
+
+struct VertexOutput {
+  [[builtin(position)]] position: vec4<f32>;
+  [[location(0)]] color: vec4<f32>;
+};
+
+fn linear_to_srgb(c: vec3<f32>) -> vec3<f32> {
+  return mix(c * 12.92, 1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - vec3<f32>(0.055),
+             step(vec3<f32>(0.0031308), c));
+}
+
+// Blue at the bottom, green in the middle and red at the top.
+fn height_color(height: f32) -> vec3<f32> {
+  let t = clamp((height - node.min_height) / max(node.max_height - node.min_height, 1e-6),
+                0.0, 1.0);
+  return clamp(vec3<f32>(2.0 * t - 1.0, 1.0 - abs(2.0 * t - 1.0), 1.0 - 2.0 * t),
+               vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+[[stage(vertex)]]
+fn vs_main([[location(0)]] position: vec3<f32>,
+           [[location(1)]] color: vec4<f32>) -> VertexOutput {
+  var srgb_color: vec3<f32> = color.rgb;
+  if (node.linear_colors != 0u) {
+    srgb_color = linear_to_srgb(srgb_color);
+  }
+  if (node.color_map == 1u) {
+    srgb_color = height_color(position.z * node.edge_length + node.cube_min_z);
+  }
+  var output: VertexOutput;
+  output.color = vec4<f32>(pow(srgb_color, vec3<f32>(1.0 / node.gamma)), 1.0);
+  output.position = node.transform * vec4<f32>(position, 1.0);
+  return output;
+}
+
+[[stage(fragment)]]
+fn fs_main(vertex: VertexOutput) -> [[location(0)]] vec4<f32> {
+  return vertex.color;
+}
@@ -0,0 +1,167 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The graphics APIs that draw the point cloud. OpenGL is always available, wgpu with the feature
+//! 'wgpu-backend'.
+
+use crate::box_drawer::BoxDrawer;
+use crate::node_drawer::{ColorMap, NodeDrawer, NodeView};
+use crate::opengl;
+use crate::viewport::Viewport;
+use nalgebra::Matrix4;
+use point_viewer::color::{Color, ColorSpace};
+use point_viewer::geometry::Aabb;
+use point_viewer::octree;
+use point_viewer::read_write::VertexLayout;
+use serde_derive::Deserialize;
+use std::rc::Rc;
+use std::str::FromStr;
+
+/// The graphics API of the viewer, chosen at startup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    OpenGl,
+    /// Vulkan, Metal or DirectX 12 through wgpu. It draws the point cloud, but not the terrain or
+    /// extensions, which are written against OpenGL.
+    Wgpu,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opengl" => Ok(Backend::OpenGl),
+            "wgpu" => Ok(Backend::Wgpu),
+            _ => Err(format!("Unknown backend '{}', use 'opengl' or 'wgpu'.", s)),
+        }
+    }
+}
+
+/// The points of a node on the GPU.
+pub trait GpuNode {
+    fn meta(&self) -> &octree::NodeMeta;
+    fn used_memory_bytes(&self) -> usize;
+}
+
+/// Draws the nodes of the point cloud in frames. Draw calls are only made between 'begin_frame'
+/// and 'end_frame', into the viewport that was set last.
+pub trait PointCloudBackend {
+    type Node: GpuNode;
+
+    /// Uploads the points of a node.
+    fn upload_node(&self, node_data: octree::NodeData) -> Self::Node;
+    /// Sets the size of the window.
+    fn set_viewport_size(&mut self, width: i32, height: i32);
+    /// Draws the following nodes and outlines into 'viewport' of the window.
+    fn set_viewport(&mut self, viewport: &Viewport);
+    /// With 'ColorMap::Height', heights from 'min_height' to 'max_height' span the whole ramp.
+    fn set_color_map(&mut self, color_map: ColorMap, min_height: f64, max_height: f64);
+    /// Clears the screen.
+    fn begin_frame(&mut self);
+    /// Draws the points of 'node' and returns their number.
+    fn draw_node(&mut self, node: &Self::Node, point_size: f32, gamma: f32) -> i64;
+    /// Shades the following nodes by their normals, see 'Octree::shading_layout'. Backends that
+    /// cannot shade draw the plain colors.
+    fn set_shading(&mut self, _shading: bool) {}
+    fn draw_outline(&mut self, aabb: &Aabb, color: &Color<f32>);
+    /// Submits the draw calls of the frame. OpenGL frames are shown by swapping the window, after
+    /// the terrain and extensions drew into them, wgpu frames are shown here.
+    fn end_frame(&mut self);
+}
+
+pub struct OpenGlBackend {
+    gl: Rc<opengl::Gl>,
+    node_drawer: NodeDrawer,
+    box_drawer: BoxDrawer,
+    world_to_gl: Matrix4<f64>,
+    window_height: i32,
+}
+
+impl OpenGlBackend {
+    /// Creates the backend for an octree whose colors are in 'color_space' and whose nodes are
+    /// shaded with the attributes of 'shading_layout', see 'NodeDrawer::new'.
+    pub fn new(
+        gl: Rc<opengl::Gl>,
+        color_space: ColorSpace,
+        shading_layout: Option<VertexLayout>,
+    ) -> Self {
+        OpenGlBackend {
+            node_drawer: NodeDrawer::new(&gl, color_space, shading_layout),
+            box_drawer: BoxDrawer::new(&gl),
+            world_to_gl: Matrix4::identity(),
+            window_height: 0,
+            gl,
+        }
+    }
+}
+
+impl PointCloudBackend for OpenGlBackend {
+    type Node = NodeView;
+
+    fn upload_node(&self, node_data: octree::NodeData) -> NodeView {
+        NodeView::new(&self.node_drawer, node_data)
+    }
+
+    fn set_viewport_size(&mut self, width: i32, height: i32) {
+        self.window_height = height;
+        unsafe {
+            self.gl.Viewport(0, 0, width, height);
+        }
+    }
+
+    fn set_viewport(&mut self, viewport: &Viewport) {
+        // OpenGL counts rows from the bottom of the window.
+        unsafe {
+            self.gl.Viewport(
+                viewport.x,
+                self.window_height - viewport.y - viewport.height,
+                viewport.width,
+                viewport.height,
+            );
+        }
+        self.node_drawer.update_world_to_gl(&viewport.world_to_gl);
+        self.world_to_gl = viewport.world_to_gl;
+    }
+
+    fn set_color_map(&mut self, color_map: ColorMap, min_height: f64, max_height: f64) {
+        self.node_drawer
+            .set_color_map(color_map, min_height, max_height);
+    }
+
+    fn begin_frame(&mut self) {
+        unsafe {
+            self.gl.ClearColor(0., 0., 0., 1.);
+            self.gl
+                .Clear(opengl::COLOR_BUFFER_BIT | opengl::DEPTH_BUFFER_BIT);
+        }
+    }
+
+    fn draw_node(&mut self, node: &NodeView, point_size: f32, gamma: f32) -> i64 {
+        self.node_drawer
+            .draw(node, 1 /* level of detail */, point_size, gamma)
+    }
+
+    fn set_shading(&mut self, shading: bool) {
+        self.node_drawer.set_shading(shading);
+    }
+
+    fn draw_outline(&mut self, aabb: &Aabb, color: &Color<f32>) {
+        self.box_drawer
+            .draw_outlines(aabb, &self.world_to_gl, color);
+    }
+
+    fn end_frame(&mut self) {}
+}
@@ -26,6 +26,42 @@ use std::rc::Rc;
 const FRAGMENT_SHADER_OUTLINED_BOX: &str = include_str!("../shaders/box_drawer_outline.fs");
 const VERTEX_SHADER_OUTLINED_BOX: &str = include_str!("../shaders/box_drawer_outline.vs");
 
+/// The vertices of the box in local coordinates.
+pub const BOX_VERTICES: [[f64; 3]; 8] = [
+    [-1.0, -1.0, 1.0],  // vertices of front quad
+    [1.0, -1.0, 1.0],   //
+    [1.0, 1.0, 1.0],    //
+    [-1.0, 1.0, 1.0],   //
+    [-1.0, -1.0, -1.0], // vertices of back quad
+    [1.0, -1.0, -1.0],  //
+    [1.0, 1.0, -1.0],   //
+    [-1.0, 1.0, -1.0],  //
+];
+
+/// The 12 edges of the box as pairs of indices into 'BOX_VERTICES'.
+pub const BOX_LINE_INDICES: [[i32; 2]; 12] = [
+    [0, 1],
+    [1, 2],
+    [2, 3],
+    [3, 0], // front quad
+    [4, 5],
+    [5, 6],
+    [6, 7],
+    [7, 4], // back back
+    [1, 5],
+    [6, 2], // right quad
+    [4, 0],
+    [3, 7], // left quad
+];
+
+/// Transforms the box in local coordinates into 'cuboid'.
+pub fn box_transform(cuboid: &Aabb) -> Matrix4<f64> {
+    let dim = cuboid.diag() / 2.0;
+    let scale_matrix = Matrix4::new_nonuniform_scaling(&dim);
+    let translation_matrix = Matrix4::new_translation(&cuboid.center().coords);
+    translation_matrix * scale_matrix
+}
+
 pub struct BoxDrawer {
     outline_program: GlProgram,
 
@@ -60,16 +96,7 @@ impl BoxDrawer {
         // vertex buffer: define 8 vertices of the box
         let _buffer_position = GlBuffer::new_array_buffer(Rc::clone(gl));
         _buffer_position.bind();
-        let vertices: [[f64; 3]; 8] = [
-            [-1.0, -1.0, 1.0],  // vertices of front quad
-            [1.0, -1.0, 1.0],   //
-            [1.0, 1.0, 1.0],    //
-            [-1.0, 1.0, 1.0],   //
-            [-1.0, -1.0, -1.0], // vertices of back quad
-            [1.0, -1.0, -1.0],  //
-            [1.0, 1.0, -1.0],   //
-            [-1.0, 1.0, -1.0],  //
-        ];
+        let vertices = BOX_VERTICES;
         unsafe {
             gl.BufferData(
                 opengl::ARRAY_BUFFER,
@@ -82,20 +109,7 @@ impl BoxDrawer {
         // define index buffer for 24 edges of the box
         let _buffer_indices = GlBuffer::new_element_array_buffer(Rc::clone(gl));
         _buffer_indices.bind();
-        let line_indices: [[i32; 2]; 12] = [
-            [0, 1],
-            [1, 2],
-            [2, 3],
-            [3, 0], // front quad
-            [4, 5],
-            [5, 6],
-            [6, 7],
-            [7, 4], // back back
-            [1, 5],
-            [6, 2], // right quad
-            [4, 0],
-            [3, 7], // left quad
-        ];
+        let line_indices = BOX_LINE_INDICES;
         unsafe {
             gl.BufferData(
                 opengl::ELEMENT_ARRAY_BUFFER,
@@ -168,11 +182,7 @@ impl BoxDrawer {
         world_to_gl: &Matrix4<f64>,
         color: &color::Color<f32>,
     ) {
-        let dim = cuboid.diag() / 2.0;
-        let scale_matrix = Matrix4::new_nonuniform_scaling(&dim);
-        let translation_matrix = Matrix4::new_translation(&cuboid.center().coords);
-        let transformation_matrix = world_to_gl * translation_matrix * scale_matrix;
-
+        let transformation_matrix = world_to_gl * box_transform(cuboid);
         self.draw_outlines_from_transformation(&transformation_matrix, color);
     }
 }
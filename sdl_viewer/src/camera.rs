@@ -12,8 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::opengl;
-use nalgebra::{Isometry3, Matrix4, Perspective3, UnitQuaternion, Vector3};
+use nalgebra::{
+    Isometry3, Matrix4, Orthographic3, Perspective3, Point3, Translation3, UnitQuaternion, Vector3,
+};
+use point_viewer::octree::UpAxis;
 
 use serde_derive::{Deserialize, Serialize};
 use std::f64;
@@ -35,6 +37,17 @@ impl RotationAngle {
     }
 }
 
+/// The direction of an orthographic view, see 'Camera::get_orthographic_world_to_gl'.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViewDirection {
+    /// Down along the up axis.
+    Top,
+    /// Horizontally, along the axis that the camera faces before it is turned.
+    Front,
+    /// Horizontally, at a right angle to 'Front'.
+    Side,
+}
+
 #[derive(Debug)]
 struct CtMode {
     pub enabled: bool,
@@ -56,6 +69,9 @@ pub struct Camera {
     pub width: i32,
     pub height: i32,
     ct_mode: CtMode,
+    // Moves only horizontally, and the viewer keeps the camera above the ground, see
+    // 'Octree::walk'.
+    walking: bool,
 
     movement_speed: f64,
     theta: f64,
@@ -73,6 +89,8 @@ pub struct Camera {
 
     moved: bool,
     transform: Isometry3<f64>,
+    // Maps the z axis, which 'theta' turns around, to the up axis of the point cloud.
+    up_rotation: UnitQuaternion<f64>,
 
     projection_matrix: Matrix4<f32>,
     local_from_global: Isometry3<f64>,
@@ -87,14 +105,12 @@ pub struct State {
 
 const FAR_PLANE: f32 = 10000.;
 const NEAR_PLANE: f32 = 0.1;
+/// Half the height of orthographic views in meters per unit of movement speed, so that the mouse
+/// wheel zooms them.
+const ORTHOGRAPHIC_HALF_HEIGHT_PER_SPEED: f64 = 10.;
 
 impl Camera {
-    pub fn new(
-        gl: &opengl::Gl,
-        width: i32,
-        height: i32,
-        local_from_global: Option<Isometry3<f64>>,
-    ) -> Self {
+    pub fn new(width: i32, height: i32, local_from_global: Option<Isometry3<f64>>) -> Self {
         let local_from_global = local_from_global.unwrap_or_else(Isometry3::identity);
         let mut camera = Camera {
             movement_speed: 10.,
@@ -115,6 +131,7 @@ impl Camera {
             rotation_speed: RotationAngle::zero(),
             delta_rotation: RotationAngle::zero(),
             transform: Isometry3::translation(0., 0., 150.),
+            up_rotation: UnitQuaternion::identity(),
             local_from_global,
 
             // These will be set by set_size().
@@ -126,23 +143,24 @@ impl Camera {
                 near_plane: 2.,
                 far_plane: 5.,
             },
+            walking: false,
         };
-        camera.set_size(gl, width, height);
+        camera.set_size(width, height);
         camera
     }
 
-    pub fn move_ct(&mut self, delta: f32, gl: &opengl::Gl) {
+    pub fn move_ct(&mut self, delta: f32) {
         if self.ct_mode.near_plane + delta > 0. {
             self.ct_mode.near_plane += delta;
             self.ct_mode.far_plane += delta;
-            self.update_viewport(gl);
+            self.update_projection();
         }
     }
 
-    pub fn move_far_plane_ct(&mut self, delta: f32, gl: &opengl::Gl) {
+    pub fn move_far_plane_ct(&mut self, delta: f32) {
         self.ct_mode.far_plane =
             (self.ct_mode.near_plane + 0.5).max(self.ct_mode.far_plane + delta);
-        self.update_viewport(gl);
+        self.update_projection();
     }
 
     pub fn state(&self) -> State {
@@ -160,35 +178,98 @@ impl Camera {
         self.moved = true;
     }
 
-    pub fn set_size(&mut self, gl: &opengl::Gl, width: i32, height: i32) {
+    /// Turns the camera around 'up_axis' from now on, and moves it above the origin looking down.
+    pub fn set_up_axis(&mut self, up_axis: UpAxis) {
+        self.up_rotation = match up_axis {
+            UpAxis::Z => UnitQuaternion::identity(),
+            UpAxis::Y => {
+                UnitQuaternion::from_axis_angle(&Vector3::x_axis(), -f64::consts::FRAC_PI_2)
+            }
+        };
+        self.theta = 0.;
+        self.phi = 0.;
+        self.transform = Isometry3::from_parts(
+            (self.up_rotation * Vector3::new(0., 0., 150.)).into(),
+            self.up_rotation,
+        );
+        self.moved = true;
+    }
+
+    /// Moves the camera to 'global_from_camera', e.g. the camera of the view settings of the
+    /// octree. The roll around the viewing direction is dropped, since the camera keeps the up
+    /// axis up.
+    pub fn set_pose(&mut self, global_from_camera: &Isometry3<f64>) {
+        let local_from_camera = self.local_from_global * global_from_camera;
+        let (roll, _, yaw) =
+            (self.up_rotation.inverse() * local_from_camera.rotation).euler_angles();
+        self.theta = yaw;
+        self.phi = roll;
+        self.transform = Isometry3::from_parts(
+            local_from_camera.translation,
+            self.up_rotation
+                * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), self.theta)
+                * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), self.phi),
+        );
+        self.moved = true;
+    }
+
+    /// Sets the size of the viewport, which the renderer applies, see
+    /// 'PointCloudBackend::set_viewport_size'.
+    pub fn set_size(&mut self, width: i32, height: i32) {
         self.width = width;
         self.height = height;
-        self.update_viewport(gl);
+        self.update_projection();
+    }
+
+    pub fn update_projection(&mut self) {
+        self.projection_matrix = self.perspective(self.width, self.height);
+        self.moved = true;
     }
 
-    pub fn update_viewport(&mut self, gl: &opengl::Gl) {
+    /// The perspective projection of a viewport of 'width' x 'height' pixels.
+    fn perspective(&self, width: i32, height: i32) -> Matrix4<f32> {
         let (near, far) = if self.ct_mode.enabled {
             (self.ct_mode.near_plane, self.ct_mode.far_plane)
         } else {
             (NEAR_PLANE, FAR_PLANE)
         };
 
-        self.projection_matrix = Perspective3::new(
-            self.width as f32 / self.height as f32,
+        Perspective3::new(
+            width as f32 / height as f32,
             std::f32::consts::FRAC_PI_4,
             near,
             far,
         )
-        .to_homogeneous();
-        unsafe {
-            gl.Viewport(0, 0, self.width, self.height);
-        }
-        self.moved = true;
+        .to_homogeneous()
     }
 
-    pub fn toggle_ct_mode(&mut self, gl: &opengl::Gl) {
+    pub fn toggle_ct_mode(&mut self) {
         self.ct_mode.enabled = !self.ct_mode.enabled;
-        self.update_viewport(gl);
+        self.update_projection();
+    }
+
+    /// Switches between flying and walking, and returns whether the camera walks now.
+    pub fn toggle_walk_mode(&mut self) -> bool {
+        self.walking = !self.walking;
+        // The viewer puts the camera onto the ground right away.
+        self.moved = true;
+        self.walking
+    }
+
+    pub fn is_walking(&self) -> bool {
+        self.walking
+    }
+
+    /// The position of the camera in the coordinates of the octree.
+    pub fn position(&self) -> Point3<f64> {
+        Point3::from(self.get_camera_to_world().translation.vector)
+    }
+
+    /// Moves the camera to 'position' in the coordinates of the octree, keeping its rotation. This
+    /// does not count as a move in the next 'update', so it should be called after 'update'
+    /// reported one.
+    pub fn set_position(&mut self, position: &Point3<f64>) {
+        self.transform.translation = (self.local_from_global * position).coords.into();
     }
 
     pub fn get_camera_to_world(&self) -> Isometry3<f64> {
@@ -201,6 +282,52 @@ impl Camera {
             * camera_from_global.to_homogeneous()
     }
 
+    /// Like 'get_world_to_gl' for a viewport of 'width' x 'height' pixels, seen from an eye that
+    /// is 'eye_offset' to the right of the camera.
+    pub fn get_eye_world_to_gl(&self, width: i32, height: i32, eye_offset: f64) -> Matrix4<f64> {
+        let camera_from_global = self.transform.inverse() * self.local_from_global;
+        let eye_from_global = Translation3::new(-eye_offset, 0., 0.) * camera_from_global;
+        nalgebra::convert::<Matrix4<f32>, Matrix4<f64>>(self.perspective(width, height))
+            * eye_from_global.to_homogeneous()
+    }
+
+    /// An orthographic view in 'direction' through the position of the camera, for a viewport of
+    /// 'width' x 'height' pixels. It shows everything up to the far plane in front of and behind
+    /// the camera.
+    pub fn get_orthographic_world_to_gl(
+        &self,
+        direction: ViewDirection,
+        width: i32,
+        height: i32,
+    ) -> Matrix4<f64> {
+        // Views look along their negative z axis, with their y axis up.
+        let look_horizontally =
+            UnitQuaternion::from_axis_angle(&Vector3::x_axis(), f64::consts::FRAC_PI_2);
+        let rotation = match direction {
+            ViewDirection::Top => UnitQuaternion::identity(),
+            ViewDirection::Front => look_horizontally,
+            ViewDirection::Side => {
+                UnitQuaternion::from_axis_angle(&Vector3::z_axis(), f64::consts::FRAC_PI_2)
+                    * look_horizontally
+            }
+        };
+        let local_from_view =
+            Isometry3::from_parts(self.transform.translation, self.up_rotation * rotation);
+        let half_height = ORTHOGRAPHIC_HALF_HEIGHT_PER_SPEED * self.movement_speed;
+        let half_width = half_height * f64::from(width) / f64::from(height);
+        let far = f64::from(FAR_PLANE);
+        Orthographic3::new(
+            -half_width,
+            half_width,
+            -half_height,
+            half_height,
+            -far,
+            far,
+        )
+        .to_homogeneous()
+            * (local_from_view.inverse() * self.local_from_global).to_homogeneous()
+    }
+
     /// Update the camera position for the current frame. Returns true if the camera moved in this
     /// step.
     pub fn update(&mut self, elapsed: time::Duration) -> bool {
@@ -221,10 +348,11 @@ impl Camera {
         if self.moving_forward {
             pan.z -= 1.;
         }
-        if self.moving_up {
+        // Walking cameras stay on the ground.
+        if self.moving_up && !self.walking {
             pan.y += 1.;
         }
-        if self.moving_down {
+        if self.moving_down && !self.walking {
             pan.y -= 1.;
         }
         if pan.norm_squared() > 0. {
@@ -250,10 +378,17 @@ impl Camera {
         // Apply changes
         if self.pan.norm_squared() > 0. {
             moved = true;
-            let translation = self
-                .transform
-                .rotation
-                .transform_vector(&(self.pan * self.movement_speed * elapsed_seconds));
+            // Walking cameras move level in the direction they face, as if they looked
+            // horizontally.
+            let rotation = if self.walking {
+                self.up_rotation
+                    * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), self.theta)
+                    * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), f64::consts::FRAC_PI_2)
+            } else {
+                self.transform.rotation
+            };
+            let translation =
+                rotation.transform_vector(&(self.pan * self.movement_speed * elapsed_seconds));
             self.transform.append_translation_mut(&translation.into());
         }
 
@@ -272,7 +407,7 @@ impl Camera {
             }
             let rotation_z = UnitQuaternion::from_axis_angle(&Vector3::z_axis(), self.theta);
             let rotation_x = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), self.phi);
-            self.transform.rotation = rotation_z * rotation_x;
+            self.transform.rotation = self.up_rotation * rotation_z * rotation_x;
         }
 
         self.pan = nalgebra::zero();
@@ -299,6 +434,8 @@ impl Camera {
         let sign = f64::from(delta.signum());
         self.movement_speed += sign * 0.1 * self.movement_speed;
         self.movement_speed = self.movement_speed.max(0.01);
+        // Orthographic views zoom with the movement speed.
+        self.moved = true;
     }
 
     pub fn pan(&mut self, x: f64, y: f64, z: f64) {
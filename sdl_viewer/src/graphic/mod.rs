@@ -59,11 +59,31 @@ impl GlBuffer {
         }
     }
 
+    pub fn new_shader_storage_buffer(gl: Rc<opengl::Gl>) -> Self {
+        let mut id = 0;
+        unsafe {
+            gl.GenBuffers(1, &mut id);
+        }
+        GlBuffer {
+            gl,
+            id,
+            buffer_type: opengl::SHADER_STORAGE_BUFFER,
+        }
+    }
+
     pub fn bind(&self) {
         unsafe {
             self.gl.BindBuffer(self.buffer_type, self.id);
         }
     }
+
+    /// Binds the buffer to the binding point 'index' of its type, e.g. to
+    /// 'layout(std430, binding = index)' in shaders.
+    pub fn bind_base(&self, index: GLuint) {
+        unsafe {
+            self.gl.BindBufferBase(self.buffer_type, index, self.id);
+        }
+    }
 }
 
 impl Drop for GlBuffer {
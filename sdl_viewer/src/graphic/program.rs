@@ -57,6 +57,18 @@ pub struct GlProgram {
     pub id: GLuint,
 }
 
+impl GlProgram {
+    /// A program that consists of a compute shader. Compute shaders need OpenGL 4.3.
+    pub fn new_compute(gl: Rc<opengl::Gl>, compute_shader: &str) -> Self {
+        let shader_id = compile_shader(&gl, compute_shader, opengl::COMPUTE_SHADER);
+        let id = link_program(&gl, &[shader_id]);
+        unsafe {
+            gl.DeleteShader(shader_id);
+        }
+        GlProgram { gl, id }
+    }
+}
+
 impl Drop for GlProgram {
     fn drop(&mut self) {
         unsafe {
@@ -18,6 +18,12 @@ impl Uniform for f32 {
     }
 }
 
+impl Uniform for u32 {
+    unsafe fn submit(&self, gl: &opengl::Gl, location: GLint) {
+        gl.Uniform1ui(location, *self);
+    }
+}
+
 impl Uniform for f64 {
     unsafe fn submit(&self, gl: &opengl::Gl, location: GLint) {
         gl.Uniform1d(location, *self);
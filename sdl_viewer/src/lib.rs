@@ -22,6 +22,7 @@ macro_rules! c_str {
     };
 }
 
+pub mod backend;
 mod camera;
 #[allow(
     non_upper_case_globals,
@@ -38,45 +39,66 @@ pub mod opengl {
 pub mod box_drawer;
 pub mod graphic;
 pub mod node_drawer;
+pub mod node_selector;
+pub mod overlay_drawer;
+pub mod script;
 pub mod terrain_drawer;
+pub mod viewport;
+#[cfg(feature = "wgpu-backend")]
+pub mod wgpu_backend;
 
-use crate::box_drawer::BoxDrawer;
+use crate::backend::{Backend, GpuNode, OpenGlBackend, PointCloudBackend};
 use crate::camera::Camera;
-use crate::node_drawer::{NodeDrawer, NodeViewContainer};
+use crate::node_drawer::{ColorMap, NodeViewContainer};
+use crate::node_selector::NodeSelector;
+use crate::overlay_drawer::OverlayDrawer;
+use crate::script::{Script, ScriptCommand};
 use crate::terrain_drawer::TerrainRenderer;
+use crate::viewport::{Layout, Viewport};
 use nalgebra::{Isometry3, Matrix4};
 use point_viewer::color::YELLOW;
-use point_viewer::data_provider::DataProviderFactory;
-use point_viewer::octree::{self, Octree};
+use point_viewer::config::load_config;
+use point_viewer::data_provider::{
+    DataProvider, DataProviderFactory, DiskCacheDataProvider, MmapDataProvider,
+};
+use point_viewer::frame_capture::FrameRecorder;
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::PointCloud;
+use point_viewer::octree::{self, Octree, WalkOptions, DEFAULT_PIXEL_THRESHOLD};
+use point_viewer::overlay::{read_overlay, OverlayLayer};
+use point_viewer::session_log::SessionLog;
 use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::{Mod, Scancode};
 use sdl2::video::{GLProfile, SwapInterval};
 use std::cmp;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::{mpsc, Arc};
-use std::thread;
+use std::sync::Arc;
 
-struct PointCloudRenderer {
-    gl: Rc<opengl::Gl>,
-    node_drawer: NodeDrawer,
+struct PointCloudRenderer<B: PointCloudBackend> {
+    backend: B,
     last_moving: time::Instant,
     // TODO(sirver): Logging does not fit into this classes responsibilities.
     last_log: time::Instant,
     visible_nodes: Vec<octree::NodeId>,
-    get_visible_nodes_params_tx: mpsc::Sender<Matrix4<f64>>,
-    get_visible_nodes_result_rx: mpsc::Receiver<Vec<octree::NodeId>>,
+    node_selector: NodeSelector,
     num_frames: u32,
     point_size: f32,
     gamma: f32,
+    point_budget: Option<usize>,
     needs_drawing: bool,
     max_nodes_in_memory: usize,
-    world_to_gl: Matrix4<f64>,
+    pixel_threshold: f64,
+    // The viewports that the nodes are drawn into, see 'Layout'.
+    viewports: Vec<Viewport>,
     max_nodes_moving: usize,
     show_octree_nodes: bool,
-    node_views: NodeViewContainer,
-    box_drawer: BoxDrawer,
+    // Whether the points are shaded by their normals, or None if the octree has none.
+    shading: Option<bool>,
+    node_views: NodeViewContainer<B::Node>,
+    // Records the streamed and drawn nodes of every frame with '--record-frames'.
+    frame_recorder: Option<Arc<FrameRecorder>>,
 }
 
 #[derive(Debug)]
@@ -84,71 +106,109 @@ enum DrawResult {
     HasDrawn,
     NoChange,
 }
-impl PointCloudRenderer {
+impl<B: PointCloudBackend> PointCloudRenderer<B> {
     pub fn new(
         max_nodes_in_memory: usize,
-        gl: Rc<opengl::Gl>,
+        backend: B,
         octree: Arc<octree::Octree>,
+        node_selector: NodeSelector,
+        frame_recorder: Option<Arc<FrameRecorder>>,
     ) -> Self {
         let now = time::Instant::now();
-
-        // This thread waits for requests to calculate the currently visible nodes, runs a
-        // calculation and sends the visible nodes back to the drawing thread. If multiple requests
-        // queue up while it is processing one, it will drop all but the latest one before
-        // restarting the next calculation.
-        let (get_visible_nodes_params_tx, rx) = mpsc::channel::<Matrix4<f64>>();
-        let (tx, get_visible_nodes_result_rx) = mpsc::channel();
-        let octree_clone = octree.clone();
-        thread::spawn(move || {
-            while let Ok(mut matrix) = rx.recv() {
-                // Drain the channel, we only ever want to update the latest.
-                while let Ok(newer_matrix) = rx.try_recv() {
-                    matrix = newer_matrix;
-                }
-                let visible_nodes = octree_clone.get_visible_nodes(&matrix);
-                tx.send(visible_nodes).unwrap();
-            }
-        });
-
         Self {
             last_moving: now,
             last_log: now,
             visible_nodes: Vec::new(),
-            node_drawer: NodeDrawer::new(&Rc::clone(&gl)),
+            backend,
             num_frames: 0,
             point_size: 1.,
             gamma: 1.,
-            get_visible_nodes_params_tx,
-            get_visible_nodes_result_rx,
+            point_budget: None,
+            node_selector,
             max_nodes_moving: max_nodes_in_memory,
             needs_drawing: true,
             show_octree_nodes: false,
+            shading: octree.shading_layout().map(|_| false),
             max_nodes_in_memory,
-            node_views: NodeViewContainer::new(octree, max_nodes_in_memory),
-            box_drawer: BoxDrawer::new(&Rc::clone(&gl)),
-            world_to_gl: Matrix4::identity(),
-            gl,
+            node_views: NodeViewContainer::new(octree, max_nodes_in_memory, frame_recorder.clone()),
+            pixel_threshold: DEFAULT_PIXEL_THRESHOLD,
+            viewports: Vec::new(),
+            frame_recorder,
         }
     }
 
-    pub fn camera_changed(&mut self, world_to_gl: &Matrix4<f64>) {
+    fn apply_config(&mut self, config: &Config, bounding_box: &Aabb) {
+        self.point_size = config.point_size.max(1.);
+        self.gamma = config.gamma;
+        self.pixel_threshold = config.pixel_threshold;
+        self.point_budget = config.point_budget;
+        self.backend
+            .set_color_map(config.color_map, bounding_box.min().z, bounding_box.max().z);
+    }
+
+    /// Selects the nodes for 'viewports' and draws them into all of them.
+    pub fn camera_changed(&mut self, viewports: Vec<Viewport>) {
         self.last_moving = time::Instant::now();
         self.needs_drawing = true;
-        self.node_drawer.update_world_to_gl(world_to_gl);
-        self.get_visible_nodes_params_tx.send(*world_to_gl).unwrap();
+        self.node_selector.request(
+            viewports
+                .iter()
+                .map(|viewport| {
+                    (
+                        viewport.world_to_gl,
+                        viewport.screen_space_error(self.pixel_threshold),
+                    )
+                })
+                .collect(),
+        );
         self.last_moving = time::Instant::now();
-        self.world_to_gl = *world_to_gl;
+        // Frames are replayed for a single camera, so only the last viewport is recorded, which
+        // shows the perspective view of the camera, or of its right eye.
+        if let (Some(frame_recorder), Some(viewport)) = (&self.frame_recorder, viewports.last()) {
+            frame_recorder.camera_changed(
+                &viewport.world_to_gl,
+                f64::from(viewport.width),
+                f64::from(viewport.height),
+            );
+        }
+        self.viewports = viewports;
+    }
+
+    pub fn viewports(&self) -> &[Viewport] {
+        &self.viewports
+    }
+
+    /// Sets the size of the window, whose viewports are passed to the next 'camera_changed'.
+    pub fn set_viewport_size(&mut self, width: i32, height: i32) {
+        self.backend.set_viewport_size(width, height);
     }
 
     pub fn toggle_show_octree_nodes(&mut self) {
         self.show_octree_nodes = !self.show_octree_nodes;
     }
 
+    /// Toggles shading the points by their normals, if the octree has them.
+    pub fn toggle_shading(&mut self) {
+        match &mut self.shading {
+            Some(shading) => {
+                *shading = !*shading;
+                self.backend.set_shading(*shading);
+                self.needs_drawing = true;
+            }
+            None => eprintln!("The octree has no normals to shade the points with."),
+        }
+    }
+
     pub fn adjust_gamma(&mut self, delta: f32) {
         self.gamma += delta;
         self.needs_drawing = true;
     }
 
+    /// Draws the next frame even if the point cloud did not change.
+    pub fn request_redraw(&mut self) {
+        self.needs_drawing = true;
+    }
+
     pub fn adjust_point_size(&mut self, delta: f32) {
         // Point size == 1. is the smallest that is rendered.
         self.point_size = (self.point_size + delta).max(1.);
@@ -162,21 +222,15 @@ impl PointCloudRenderer {
 
         let now = time::Instant::now();
         let moving = now - self.last_moving < time::Duration::milliseconds(150);
-        self.needs_drawing |= self.node_views.consume_arrived_nodes(&self.node_drawer);
-        while let Ok(visible_nodes) = self.get_visible_nodes_result_rx.try_recv() {
-            self.visible_nodes.clear();
-            self.visible_nodes.extend(visible_nodes);
+        let backend = &self.backend;
+        self.needs_drawing |= self
+            .node_views
+            .consume_arrived_nodes(|node_data| backend.upload_node(node_data));
+        if let Some(visible_nodes) = self.node_selector.poll() {
+            self.visible_nodes = visible_nodes;
             self.needs_drawing = true;
         }
 
-        if self.needs_drawing {
-            unsafe {
-                self.gl.ClearColor(0., 0., 0., 1.);
-                self.gl
-                    .Clear(opengl::COLOR_BUFFER_BIT | opengl::DEPTH_BUFFER_BIT);
-            }
-        }
-
         // We use a heuristic to keep the frame rate as stable as possible by increasing/decreasing the number of nodes to draw.
         let max_nodes_to_display = if moving {
             self.max_nodes_moving
@@ -185,32 +239,51 @@ impl PointCloudRenderer {
         };
         let filtered_visible_nodes = self.visible_nodes.iter().take(max_nodes_to_display);
 
+        let mut loaded_nodes = Vec::new();
+        let mut num_points_loaded = 0;
         for node_id in filtered_visible_nodes {
-            let view = self.node_views.get_or_request(&node_id);
-            if !self.needs_drawing || view.is_none() {
-                continue;
+            // The visible nodes are sorted by their size on screen, so the budget drops the
+            // smallest ones. It limits the points drawn into each viewport.
+            if self
+                .point_budget
+                .map_or(false, |budget| num_points_loaded >= budget as i64)
+            {
+                break;
             }
-            let view = view.unwrap();
-            num_points_drawn += self.node_drawer.draw(
-                view,
-                1, /* level of detail */
-                self.point_size,
-                self.gamma,
-            );
-            num_nodes_drawn += 1;
-
-            if self.show_octree_nodes {
-                self.box_drawer.draw_outlines(
-                    &view.meta.bounding_cube.to_aabb(),
-                    &self.world_to_gl,
-                    &YELLOW,
-                );
+            if let Some(view) = self.node_views.get_or_request(node_id) {
+                num_points_loaded += view.meta().num_points;
+                loaded_nodes.push(*node_id);
             }
         }
+
         if self.needs_drawing {
+            self.backend.begin_frame();
+            for viewport in &self.viewports {
+                self.backend.set_viewport(viewport);
+                for node_id in &loaded_nodes {
+                    let view = self.node_views.get(node_id).unwrap();
+                    num_points_drawn += self.backend.draw_node(view, self.point_size, self.gamma);
+                    if self.show_octree_nodes {
+                        self.backend
+                            .draw_outline(&view.meta().bounding_cube.to_aabb(), &YELLOW);
+                    }
+                }
+            }
+            num_nodes_drawn = loaded_nodes.len();
+            if let Some(frame_recorder) = &self.frame_recorder {
+                for node_id in &loaded_nodes {
+                    frame_recorder.rendered(node_id, 1);
+                }
+            }
+            self.backend.end_frame();
             draw_result = DrawResult::HasDrawn;
         }
         self.needs_drawing = moving;
+        if let Some(frame_recorder) = &self.frame_recorder {
+            if let Err(err) = frame_recorder.end_frame() {
+                eprintln!("Could not record the frame: {}", err);
+            }
+        }
 
         self.num_frames += 1;
         let now = time::Instant::now();
@@ -293,6 +366,78 @@ fn load_camera(index: usize, pose_path: &Option<PathBuf>, camera: &mut Camera) {
     camera.set_state(states.states[index]);
 }
 
+/// Settings of the viewer that can be given in the config file. Command line arguments take
+/// precedence.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// Path of the octree.
+    octree: Option<String>,
+    /// Terrain directories.
+    terrain: Vec<PathBuf>,
+    /// GeoJSON or DXF files with lines to draw over the point cloud, see 'read_overlay'.
+    overlays: Vec<PathBuf>,
+    /// Maximum cache size in MB for octree nodes in GPU memory.
+    cache_size_mb: usize,
+    /// Initial point size, at least 1.
+    point_size: f32,
+    /// Initial gamma applied to the point colors.
+    gamma: f32,
+    /// Height of the camera above the ground in meters when walking, see 'Octree::walk'.
+    eye_height: f64,
+    /// Nodes with a smaller screen-space error in pixels are not drawn, see 'ScreenSpaceError'.
+    pixel_threshold: f64,
+    /// Stop drawing nodes, the largest on screen first, once this many points have been drawn.
+    point_budget: Option<usize>,
+    /// How to color the points, 'rgb' or 'height'.
+    color_map: ColorMap,
+    /// Read the octree through memory mappings, which is fastest for local octrees that fit into
+    /// the page cache.
+    mmap: bool,
+    /// Write the nodes that were requested, received and drawn in every frame to this file, see
+    /// 'point_cloud_tool replay-frames'.
+    record_frames: Option<PathBuf>,
+    /// A Rhai script that automates the viewer, see 'script'.
+    script: Option<PathBuf>,
+    /// Log which features are used and how far the camera moves to this file, see
+    /// 'point_viewer::session_log'.
+    session_log: Option<PathBuf>,
+    /// Keep the nodes of remote octrees in this directory, see 'DiskCacheDataProvider'.
+    disk_cache: Option<PathBuf>,
+    /// Select the nodes to draw on a thread even if the GPU runs compute shaders, see
+    /// 'NodeSelector'.
+    cpu_node_selection: bool,
+    /// The graphics API, 'opengl' or 'wgpu', see 'Backend'.
+    backend: Backend,
+    /// The initial viewports, 'single', 'quad' or 'stereo', see 'Layout'.
+    layout: Layout,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            octree: None,
+            terrain: Vec::new(),
+            overlays: Vec::new(),
+            cache_size_mb: 2000,
+            point_size: 1.,
+            gamma: 1.,
+            eye_height: WalkOptions::default().eye_height,
+            pixel_threshold: DEFAULT_PIXEL_THRESHOLD,
+            point_budget: None,
+            color_map: ColorMap::Rgb,
+            mmap: false,
+            record_frames: None,
+            script: None,
+            session_log: None,
+            disk_cache: None,
+            cpu_node_selection: false,
+            backend: Backend::OpenGl,
+            layout: Layout::Single,
+        }
+    }
+}
+
 pub trait Extension {
     fn pre_init(app: clap::App) -> clap::App;
     fn new(matches: &clap::ArgMatches, opengl: Rc<opengl::Gl>) -> Self;
@@ -350,44 +495,169 @@ impl Joystick for SpaceMouseJoystick {
 pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
     let mut app = clap::App::new("sdl_viewer").args(&[
         clap::Arg::new("octree")
-            .about("Input path of the octree.")
-            .index(1)
-            .required(true),
+            .about("Input path of the octree. Required unless the config file sets 'octree'.")
+            .index(1),
         clap::Arg::new("terrain")
             .long("terrain")
             .takes_value(true)
             .multiple(true)
             .about("Terrain directories (multiple possible)."),
+        clap::Arg::new("overlay")
+            .long("overlay")
+            .takes_value(true)
+            .multiple(true)
+            .about(
+                "GeoJSON or DXF files with lines to draw over the point cloud, e.g. alignments or \
+                 parcel boundaries, in the coordinates of the octree. 'L' steps through their \
+                 layers.",
+            ),
         clap::Arg::new("cache_size_mb")
             .about(
                 "Maximum cache size in MB for octree nodes in GPU memory. \
                  The default value is 2000 MB and the valid range is 1000 MB to 16000 MB.",
             )
             .required(false),
+        clap::Arg::new("mmap")
+            .long("mmap")
+            .about("Read the octree through memory mappings instead of reading its files."),
+        clap::Arg::new("record_frames")
+            .long("record-frames")
+            .takes_value(true)
+            .about(
+                "Debug mode: write the nodes that were requested, received and drawn in every \
+                 frame to this file, for replaying it with 'point_cloud_tool replay-frames'.",
+            ),
+        clap::Arg::new("script")
+            .long("script")
+            .takes_value(true)
+            .about(
+                "A Rhai script with the hooks 'on_start', 'on_frame' and 'on_key' that moves the \
+                 camera, queries points and takes screenshots, e.g. for review sequences.",
+            ),
+        clap::Arg::new("session_log")
+            .long("session-log")
+            .takes_value(true)
+            .about(
+                "Log the datasets that are opened, the features that are used and how far the \
+                 camera moves, without positions, to this file. 'point_cloud_tool \
+                 session-summary' sums such logs up.",
+            ),
+        clap::Arg::new("disk_cache")
+            .long("disk-cache")
+            .takes_value(true)
+            .about(
+                "Keep the nodes of remote octrees in this directory, so that they open quickly \
+                 next time and can be viewed offline.",
+            ),
+        clap::Arg::new("cpu_node_selection")
+            .long("cpu-node-selection")
+            .about(
+                "Select the nodes to draw on the CPU instead of with a compute shader. This is \
+                 the default where OpenGL 4.3 is not available, e.g. on macOS.",
+            ),
+        clap::Arg::new("backend")
+            .long("backend")
+            .takes_value(true)
+            .possible_values(&["opengl", "wgpu"])
+            .about(
+                "Graphics API to draw with. 'wgpu' uses Vulkan, Metal or DirectX 12 and needs the \
+                 feature 'wgpu-backend'. It does not draw terrain or extensions.",
+            ),
+        clap::Arg::new("layout")
+            .long("layout")
+            .takes_value(true)
+            .possible_values(&["single", "quad", "stereo"])
+            .about(
+                "Initial viewports: 'quad' adds orthographic views from the top, front and side, \
+                 'stereo' shows the views of both eyes side by side. 'V' switches between them.",
+            ),
+        clap::Arg::new("config")
+            .long("config")
+            .takes_value(true)
+            .about(
+                "TOML file with the settings of the viewer. Its values can be overridden by \
+                 environment variables like SDL_VIEWER_CACHE_SIZE_MB and are overridden by the \
+                 command line arguments.",
+            ),
     ]);
     app = T::pre_init(app);
 
     let matches = app.get_matches();
 
-    let octree_argument = matches.value_of("octree").unwrap();
+    let mut config: Config = load_config(matches.value_of("config").map(Path::new), "SDL_VIEWER")
+        .unwrap_or_else(|err| panic!("Could not load configuration: {}", err));
+    if let Some(octree) = matches.value_of("octree") {
+        config.octree = Some(octree.to_string());
+    }
+    if let Some(terrain_paths) = matches.values_of("terrain") {
+        config.terrain = terrain_paths.map(PathBuf::from).collect();
+    }
+    if let Some(overlay_paths) = matches.values_of("overlay") {
+        config.overlays = overlay_paths.map(PathBuf::from).collect();
+    }
+    if matches.is_present("mmap") {
+        config.mmap = true;
+    }
+    if matches.is_present("cpu_node_selection") {
+        config.cpu_node_selection = true;
+    }
+    if let Some(backend) = matches.value_of("backend") {
+        config.backend = backend
+            .parse()
+            .unwrap_or_else(|err: String| panic!("{}", err));
+    }
+    if let Some(layout) = matches.value_of("layout") {
+        config.layout = layout
+            .parse()
+            .unwrap_or_else(|err: String| panic!("{}", err));
+    }
+    if let Some(record_frames) = matches.value_of("record_frames") {
+        config.record_frames = Some(PathBuf::from(record_frames));
+    }
+    if let Some(script) = matches.value_of("script") {
+        config.script = Some(PathBuf::from(script));
+    }
+    if let Some(session_log) = matches.value_of("session_log") {
+        config.session_log = Some(PathBuf::from(session_log));
+    }
+    if let Some(disk_cache) = matches.value_of("disk_cache") {
+        config.disk_cache = Some(PathBuf::from(disk_cache));
+    }
+    if let Some(cache_size_mb) = matches.value_of("cache_size_mb") {
+        config.cache_size_mb = cache_size_mb
+            .parse()
+            .expect("Could not parse 'cache_size_mb' option.");
+    }
 
-    // Maximum number of MB for the octree node cache. The default is 2 GB
-    let cache_size_mb: usize = matches
-        .value_of("cache_size_mb")
-        .unwrap_or("2000")
-        .parse()
-        .expect("Could not parse 'cache_size_mb' option.");
+    let octree_argument = config
+        .octree
+        .as_deref()
+        .expect("No octree given. Pass its path or set 'octree' in the config file.");
 
     // Maximum number of MB for the octree node cache in range 1..16 GB. The default is 2 GB
-    let limit_cache_size_mb = cmp::max(1000, cmp::min(16_000, cache_size_mb));
+    let limit_cache_size_mb = cmp::max(1000, cmp::min(16_000, config.cache_size_mb));
 
     // Assuming about 200 KB per octree node on average
     let max_nodes_in_memory = limit_cache_size_mb * 5;
 
     // If no octree was generated create a FromDisk loader
+    let data_provider = if config.mmap {
+        Ok(Box::new(MmapDataProvider::new(octree_argument)) as Box<dyn DataProvider>)
+    } else {
+        data_provider_factory.generate_data_provider(octree_argument)
+    };
+    // Local octrees are read directly, only remote ones are worth caching.
+    let data_provider = match &config.disk_cache {
+        Some(disk_cache) if !Path::new(octree_argument).exists() => {
+            data_provider.and_then(|provider| {
+                let provider = DiskCacheDataProvider::new(provider, disk_cache, octree_argument)?;
+                Ok(Box::new(provider) as Box<dyn DataProvider>)
+            })
+        }
+        _ => data_provider,
+    };
     let octree: Arc<Octree> = Arc::from(
-        data_provider_factory
-            .generate_data_provider(octree_argument)
+        data_provider
             .and_then(|provider| Octree::from_data_provider(provider))
             .unwrap_or_else(|_| panic!("Couldn't create octree from path '{}'.", octree_argument)),
     );
@@ -435,48 +705,309 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
         }
     }
 
-    let gl_attr = video_subsystem.gl_attr();
-
-    // TODO(hrapp): This should use OpenGL ES 2.0 to be compatible with WebGL, so this can be made
-    // to work with emscripten.
-    gl_attr.set_context_profile(GLProfile::Core);
-    gl_attr.set_context_version(4, 1);
-
     const WINDOW_WIDTH: i32 = 800;
     const WINDOW_HEIGHT: i32 = 600;
-    let window = match video_subsystem
-        .window("sdl2_viewer", WINDOW_WIDTH as u32, WINDOW_HEIGHT as u32)
-        .position_centered()
-        .resizable()
-        .opengl()
-        .build()
-    {
-        Ok(window) => window,
-        Err(err) => panic!("failed to create window: {}", err),
+    let bounding_box = octree.bounding_box().clone();
+    let view_settings = octree.view_settings().clone();
+    // The renderer owns the octree, the walking camera looks up the ground in it as well.
+    let ground = Arc::clone(&octree);
+    let walk_options = WalkOptions {
+        eye_height: config.eye_height,
+        ..Default::default()
     };
+    let frame_recorder =
+        config.record_frames.as_ref().map(|path| {
+            Arc::new(FrameRecorder::create(path).unwrap_or_else(|err| {
+                panic!("Could not record frames to {}: {}", path.display(), err)
+            }))
+        });
+    let script = config.script.as_ref().map(|path| {
+        Script::load(path, Arc::clone(&octree), data_provider_factory.clone())
+            .unwrap_or_else(|err| panic!("{}", err))
+    });
+    let session_log = config.session_log.as_ref().map(|path| {
+        let mut session_log = SessionLog::create(path, "sdl_viewer").unwrap_or_else(|err| {
+            panic!("Could not log the session to {}: {}", path.display(), err)
+        });
+        session_log.dataset_loaded(octree_argument);
+        session_log
+    });
+    let create_window = |opengl: bool| {
+        let mut builder =
+            video_subsystem.window("sdl2_viewer", WINDOW_WIDTH as u32, WINDOW_HEIGHT as u32);
+        builder.position_centered().resizable();
+        if opengl {
+            builder.opengl();
+        }
+        match builder.build() {
+            Ok(window) => window,
+            Err(err) => panic!("failed to create window: {}", err),
+        }
+    };
+    let events = ctx.event_pump().unwrap();
+
+    match config.backend {
+        Backend::OpenGl => {
+            let gl_attr = video_subsystem.gl_attr();
+
+            // TODO(hrapp): This should use OpenGL ES 2.0 to be compatible with WebGL, so this can
+            // be made to work with emscripten.
+            gl_attr.set_context_profile(GLProfile::Core);
+            gl_attr.set_context_version(4, 1);
+
+            let window = create_window(true);
+
+            // We need to create a context now, only after can we actually legally load the gl
+            // functions and query 'gl_attr'.
+            let _context = window.gl_create_context().unwrap();
+            let _swap_interval = video_subsystem.gl_set_swap_interval(SwapInterval::VSync);
+
+            assert_eq!(gl_attr.context_profile(), GLProfile::Core);
+
+            let gl = Rc::new(opengl::Gl::load_with(|s| {
+                let ptr = video_subsystem.gl_get_proc_address(s);
+                ptr as *const std::ffi::c_void
+            }));
+
+            let extension = T::new(&matches, Rc::clone(&gl));
+            let ext_local_from_global = T::local_from_global(&matches, &octree);
+            let node_selector = if config.cpu_node_selection {
+                NodeSelector::cpu(Arc::clone(&octree))
+            } else {
+                NodeSelector::new(Rc::clone(&gl), Arc::clone(&octree))
+            };
+            let backend = OpenGlBackend::new(
+                Rc::clone(&gl),
+                octree.color_space(),
+                octree.shading_layout(),
+            );
+            let mut renderer = PointCloudRenderer::new(
+                max_nodes_in_memory,
+                backend,
+                octree,
+                node_selector,
+                frame_recorder,
+            );
+            renderer.apply_config(&config, &bounding_box);
+            let terrain_renderer = TerrainRenderer::new(Rc::clone(&gl), config.terrain.iter());
+            let overlays: Vec<OverlayLayer> = config
+                .overlays
+                .iter()
+                .flat_map(|path| {
+                    read_overlay(path).unwrap_or_else(|err| {
+                        panic!("Could not read overlay {}: {}", path.display(), err)
+                    })
+                })
+                .collect();
+            let overlay_drawer = OverlayDrawer::new(&gl, &overlays);
+            let local_from_global =
+                ext_local_from_global.or_else(|| terrain_renderer.local_from_global());
+            let mut camera = Camera::new(WINDOW_WIDTH, WINDOW_HEIGHT, local_from_global);
+            let extras = OpenGlExtras {
+                gl,
+                terrain_renderer,
+                overlay_drawer,
+                extension,
+                window,
+            };
+            main_loop(
+                events,
+                &joysticks,
+                &mut camera,
+                &view_settings,
+                &ground,
+                &walk_options,
+                session_log,
+                script,
+                &pose_path,
+                config.layout,
+                &mut renderer,
+                Some(extras),
+            );
+        }
+        Backend::Wgpu => {
+            #[cfg(feature = "wgpu-backend")]
+            {
+                if !config.terrain.is_empty() {
+                    eprintln!("The wgpu backend does not draw terrain, ignoring it.");
+                }
+                if !config.overlays.is_empty() {
+                    eprintln!("The wgpu backend does not draw overlays, ignoring them.");
+                }
+                let window = create_window(false);
+                let backend = wgpu_backend::WgpuBackend::new(
+                    &window,
+                    WINDOW_WIDTH as u32,
+                    WINDOW_HEIGHT as u32,
+                    octree.color_space(),
+                );
+                let local_from_global = T::local_from_global(&matches, &octree);
+                let node_selector = NodeSelector::cpu(Arc::clone(&octree));
+                let mut renderer = PointCloudRenderer::new(
+                    max_nodes_in_memory,
+                    backend,
+                    octree,
+                    node_selector,
+                    frame_recorder,
+                );
+                renderer.apply_config(&config, &bounding_box);
+                let mut camera = Camera::new(WINDOW_WIDTH, WINDOW_HEIGHT, local_from_global);
+                main_loop::<_, T>(
+                    events,
+                    &joysticks,
+                    &mut camera,
+                    &view_settings,
+                    &ground,
+                    &walk_options,
+                    session_log,
+                    script,
+                    &pose_path,
+                    config.layout,
+                    &mut renderer,
+                    None,
+                );
+            }
+            #[cfg(not(feature = "wgpu-backend"))]
+            panic!("The viewer was built without the feature 'wgpu-backend'.");
+        }
+    }
+}
 
-    // We need to create a context now, only after can we actually legally load the gl functions
-    // and query 'gl_attr'.
-    let _context = window.gl_create_context().unwrap();
-    let _swap_interval = video_subsystem.gl_set_swap_interval(SwapInterval::VSync);
+/// What only the OpenGL backend draws next to the point cloud, and the window it presents frames
+/// in by swapping.
+struct OpenGlExtras<T> {
+    gl: Rc<opengl::Gl>,
+    terrain_renderer: TerrainRenderer,
+    overlay_drawer: OverlayDrawer,
+    extension: T,
+    window: sdl2::video::Window,
+}
 
-    assert_eq!(gl_attr.context_profile(), GLProfile::Core);
+impl<T: Extension> OpenGlExtras<T> {
+    /// Draws the terrain, the overlays and the extension into every viewport and shows the frame,
+    /// after writing it to the PNG file 'screenshot' if given.
+    fn draw(&mut self, viewports: &[Viewport], camera: &Camera, screenshot: Option<&Path>) {
+        for viewport in viewports {
+            unsafe {
+                self.gl.Viewport(
+                    viewport.x,
+                    camera.height - viewport.y - viewport.height,
+                    viewport.width,
+                    viewport.height,
+                );
+            }
+            self.terrain_renderer
+                .camera_changed(&viewport.world_to_gl, &camera.get_camera_to_world());
+            self.terrain_renderer.draw();
+            self.overlay_drawer.draw(&viewport.world_to_gl);
+            self.extension.camera_changed(&viewport.world_to_gl);
+            self.extension.draw();
+        }
+        if let Some(path) = screenshot {
+            if let Err(err) = self.save_screenshot(camera, path) {
+                eprintln!("Could not write {}: {}", path.display(), err);
+            }
+        }
+        self.window.gl_swap_window()
+    }
 
-    let gl = Rc::new(opengl::Gl::load_with(|s| {
-        let ptr = video_subsystem.gl_get_proc_address(s);
-        ptr as *const std::ffi::c_void
-    }));
+    /// Writes the frame that is drawn but not shown yet to the PNG file at 'path'.
+    fn save_screenshot(&self, camera: &Camera, path: &Path) -> image::ImageResult<()> {
+        let (width, height) = (camera.width as u32, camera.height as u32);
+        let mut pixels = vec![0u8; 4 * width as usize * height as usize];
+        unsafe {
+            self.gl.PixelStorei(opengl::PACK_ALIGNMENT, 1);
+            self.gl.ReadPixels(
+                0,
+                0,
+                camera.width,
+                camera.height,
+                opengl::RGBA,
+                opengl::UNSIGNED_BYTE,
+                pixels.as_mut_ptr() as *mut _,
+            );
+        }
+        let image = image::RgbaImage::from_raw(width, height, pixels)
+            .expect("The buffer has the size of the image.");
+        // OpenGL's rows start at the bottom.
+        image::imageops::flip_vertical(&image).save(path)
+    }
+}
 
-    let mut extension = T::new(&matches, Rc::clone(&gl));
-    let ext_local_from_global = T::local_from_global(&matches, &octree);
-    let mut renderer = PointCloudRenderer::new(max_nodes_in_memory, Rc::clone(&gl), octree);
-    let terrain_paths = matches.values_of("terrain").unwrap_or_default();
-    let mut terrain_renderer = TerrainRenderer::new(Rc::clone(&gl), terrain_paths);
-    let local_from_global = ext_local_from_global.or_else(|| terrain_renderer.local_from_global());
-    let mut camera = Camera::new(&gl, WINDOW_WIDTH, WINDOW_HEIGHT, local_from_global);
+/// The name under which pressing 'code' is logged in the session log, for the keys that use a
+/// feature rather than move the camera.
+fn feature_of_key(code: Scancode, keymod: Mod) -> Option<&'static str> {
+    let number = matches!(
+        code,
+        Scancode::Num0
+            | Scancode::Num1
+            | Scancode::Num2
+            | Scancode::Num3
+            | Scancode::Num4
+            | Scancode::Num5
+            | Scancode::Num6
+            | Scancode::Num7
+            | Scancode::Num8
+            | Scancode::Num9
+    );
+    if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+        if !number {
+            None
+        } else if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+            Some("save_camera")
+        } else {
+            Some("load_camera")
+        }
+    } else if keymod.is_empty() || keymod == Mod::NUMMOD {
+        match code {
+            Scancode::T => Some("ct_mode"),
+            Scancode::U | Scancode::I | Scancode::J | Scancode::K => Some("ct_planes"),
+            Scancode::O => Some("octree_nodes"),
+            Scancode::N => Some("shading"),
+            Scancode::G => Some("walk_mode"),
+            Scancode::L => Some("overlay_layers"),
+            Scancode::V => Some("layout"),
+            Scancode::Num7 | Scancode::Num8 => Some("gamma"),
+            Scancode::Num9 | Scancode::Num0 => Some("point_size"),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
 
-    let mut events = ctx.event_pump().unwrap();
-    let mut last_frame_time = time::Instant::now();
+fn main_loop<B: PointCloudBackend, T: Extension>(
+    mut events: sdl2::EventPump,
+    joysticks: &[Box<dyn Joystick>],
+    camera: &mut Camera,
+    view_settings: &octree::ViewSettings,
+    ground: &Octree,
+    walk_options: &WalkOptions,
+    mut session_log: Option<SessionLog>,
+    mut script: Option<Script>,
+    pose_path: &Option<PathBuf>,
+    mut layout: Layout,
+    renderer: &mut PointCloudRenderer<B>,
+    mut extras: Option<OpenGlExtras<T>>,
+) {
+    renderer.set_viewport_size(camera.width, camera.height);
+    camera.set_up_axis(view_settings.up_axis);
+    if let Some(octree_from_camera) = &view_settings.octree_from_camera {
+        camera.set_pose(octree_from_camera);
+    }
+
+    let start_time = time::Instant::now();
+    let mut last_frame_time = start_time;
+    let mut layout_changed = true;
+    // The file that the next drawn frame is written to, see 'ScriptCommand::Screenshot'.
+    let mut screenshot: Option<PathBuf> = None;
+    if let (Some(session_log), Some(_)) = (&mut session_log, &script) {
+        session_log.feature("script");
+    }
+    let mut script_commands = match &mut script {
+        Some(script) => script.on_start(&camera.get_camera_to_world()),
+        None => Vec::new(),
+    };
     'outer_loop: loop {
         for event in events.poll_iter() {
             match event {
@@ -484,8 +1015,19 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
                 Event::KeyDown {
                     scancode: Some(code),
                     keymod,
+                    repeat,
                     ..
                 } => {
+                    // Holding a key down counts as one use.
+                    if let (Some(session_log), Some(feature), false) =
+                        (&mut session_log, feature_of_key(code, keymod), repeat)
+                    {
+                        session_log.feature(feature);
+                    }
+                    if let (Some(script), false) = (&mut script, repeat) {
+                        script_commands
+                            .extend(script.on_key(code.name(), &camera.get_camera_to_world()));
+                    }
                     if keymod.is_empty() || keymod == Mod::NUMMOD {
                         match code {
                             Scancode::Escape => break 'outer_loop,
@@ -495,16 +1037,34 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
                             Scancode::D => camera.moving_right = true,
                             Scancode::Z => camera.moving_down = true,
                             Scancode::Q => camera.moving_up = true,
-                            Scancode::T => camera.toggle_ct_mode(&gl),
-                            Scancode::U => camera.move_ct(-0.5, &gl),
-                            Scancode::I => camera.move_ct(0.5, &gl),
-                            Scancode::J => camera.move_far_plane_ct(-0.5, &gl),
-                            Scancode::K => camera.move_far_plane_ct(0.5, &gl),
+                            Scancode::T => camera.toggle_ct_mode(),
+                            Scancode::U => camera.move_ct(-0.5),
+                            Scancode::I => camera.move_ct(0.5),
+                            Scancode::J => camera.move_far_plane_ct(-0.5),
+                            Scancode::K => camera.move_far_plane_ct(0.5),
                             Scancode::Left => camera.turning_left = true,
                             Scancode::Right => camera.turning_right = true,
                             Scancode::Down => camera.turning_down = true,
                             Scancode::Up => camera.turning_up = true,
                             Scancode::O => renderer.toggle_show_octree_nodes(),
+                            Scancode::N => renderer.toggle_shading(),
+                            Scancode::G => {
+                                if camera.toggle_walk_mode() {
+                                    eprintln!("Walking on the ground.");
+                                } else {
+                                    eprintln!("Flying.");
+                                }
+                            }
+                            Scancode::L => {
+                                if let Some(extras) = &mut extras {
+                                    extras.overlay_drawer.show_next_layers();
+                                    renderer.request_redraw();
+                                }
+                            }
+                            Scancode::V => {
+                                layout = layout.next();
+                                layout_changed = true;
+                            }
                             Scancode::Num7 => renderer.adjust_gamma(-0.1),
                             Scancode::Num8 => renderer.adjust_gamma(0.1),
                             Scancode::Num9 => renderer.adjust_point_size(-0.1),
@@ -516,31 +1076,31 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
                     {
                         // CTRL + SHIFT is pressed.
                         match code {
-                            Scancode::Num1 => save_camera(0, &pose_path, &camera),
-                            Scancode::Num2 => save_camera(1, &pose_path, &camera),
-                            Scancode::Num3 => save_camera(2, &pose_path, &camera),
-                            Scancode::Num4 => save_camera(3, &pose_path, &camera),
-                            Scancode::Num5 => save_camera(4, &pose_path, &camera),
-                            Scancode::Num6 => save_camera(5, &pose_path, &camera),
-                            Scancode::Num7 => save_camera(6, &pose_path, &camera),
-                            Scancode::Num8 => save_camera(7, &pose_path, &camera),
-                            Scancode::Num9 => save_camera(8, &pose_path, &camera),
-                            Scancode::Num0 => save_camera(9, &pose_path, &camera),
+                            Scancode::Num1 => save_camera(0, pose_path, camera),
+                            Scancode::Num2 => save_camera(1, pose_path, camera),
+                            Scancode::Num3 => save_camera(2, pose_path, camera),
+                            Scancode::Num4 => save_camera(3, pose_path, camera),
+                            Scancode::Num5 => save_camera(4, pose_path, camera),
+                            Scancode::Num6 => save_camera(5, pose_path, camera),
+                            Scancode::Num7 => save_camera(6, pose_path, camera),
+                            Scancode::Num8 => save_camera(7, pose_path, camera),
+                            Scancode::Num9 => save_camera(8, pose_path, camera),
+                            Scancode::Num0 => save_camera(9, pose_path, camera),
                             _ => (),
                         }
                     } else if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
                         // CTRL is pressed.
                         match code {
-                            Scancode::Num1 => load_camera(0, &pose_path, &mut camera),
-                            Scancode::Num2 => load_camera(1, &pose_path, &mut camera),
-                            Scancode::Num3 => load_camera(2, &pose_path, &mut camera),
-                            Scancode::Num4 => load_camera(3, &pose_path, &mut camera),
-                            Scancode::Num5 => load_camera(4, &pose_path, &mut camera),
-                            Scancode::Num6 => load_camera(5, &pose_path, &mut camera),
-                            Scancode::Num7 => load_camera(6, &pose_path, &mut camera),
-                            Scancode::Num8 => load_camera(7, &pose_path, &mut camera),
-                            Scancode::Num9 => load_camera(8, &pose_path, &mut camera),
-                            Scancode::Num0 => load_camera(9, &pose_path, &mut camera),
+                            Scancode::Num1 => load_camera(0, pose_path, camera),
+                            Scancode::Num2 => load_camera(1, pose_path, camera),
+                            Scancode::Num3 => load_camera(2, pose_path, camera),
+                            Scancode::Num4 => load_camera(3, pose_path, camera),
+                            Scancode::Num5 => load_camera(4, pose_path, camera),
+                            Scancode::Num6 => load_camera(5, pose_path, camera),
+                            Scancode::Num7 => load_camera(6, pose_path, camera),
+                            Scancode::Num8 => load_camera(7, pose_path, camera),
+                            Scancode::Num9 => load_camera(8, pose_path, camera),
+                            Scancode::Num0 => load_camera(9, pose_path, camera),
                             _ => (),
                         }
                     }
@@ -580,30 +1140,67 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
                     win_event: WindowEvent::SizeChanged(w, h),
                     ..
                 } => {
-                    camera.set_size(&gl, w, h);
+                    camera.set_size(w, h);
+                    renderer.set_viewport_size(w, h);
                 }
                 _ => (),
             }
         }
 
-        for j in &joysticks {
-            j.act(&mut camera);
+        for j in joysticks {
+            j.act(camera);
         }
         let current_time = time::Instant::now();
         let elapsed = current_time - last_frame_time;
         last_frame_time = current_time;
-        if camera.update(elapsed) {
-            renderer.camera_changed(&camera.get_world_to_gl());
-            terrain_renderer
-                .camera_changed(&camera.get_world_to_gl(), &camera.get_camera_to_world());
-            extension.camera_changed(&camera.get_world_to_gl());
+        if let Some(script) = &mut script {
+            script_commands.extend(script.on_frame(
+                (current_time - start_time).as_seconds_f64(),
+                &camera.get_camera_to_world(),
+            ));
+        }
+        for command in script_commands.drain(..) {
+            match command {
+                ScriptCommand::SetPose(pose) => camera.set_pose(&pose),
+                ScriptCommand::MoveTo(position) => {
+                    let rotation = camera.get_camera_to_world().rotation;
+                    camera.set_pose(&Isometry3::from_parts(position.coords.into(), rotation));
+                }
+                ScriptCommand::Screenshot(path) => {
+                    if extras.is_some() {
+                        screenshot = Some(path);
+                        renderer.request_redraw();
+                    } else {
+                        eprintln!("Screenshots need the OpenGL backend.");
+                    }
+                }
+                ScriptCommand::Quit => break 'outer_loop,
+            }
+        }
+        let position_before_update = camera.position();
+        // 'update' has to run in every frame.
+        let camera_moved = camera.update(elapsed);
+        if camera_moved && camera.is_walking() {
+            match ground.walk(&position_before_update, &camera.position(), walk_options) {
+                Ok(position) => camera.set_position(&position),
+                Err(err) => eprintln!("Could not find the ground: {}", err),
+            }
+        }
+        if camera_moved {
+            if let Some(session_log) = &mut session_log {
+                session_log.moved((camera.position() - position_before_update).norm());
+            }
+        }
+        if camera_moved || layout_changed {
+            layout_changed = false;
+            renderer.camera_changed(layout.viewports(camera));
         }
 
         match renderer.draw() {
             DrawResult::HasDrawn => {
-                terrain_renderer.draw();
-                extension.draw();
-                window.gl_swap_window()
+                if let Some(extras) = &mut extras {
+                    extras.draw(renderer.viewports(), camera, screenshot.take().as_deref());
+                }
             }
             DrawResult::NoChange => (),
         }
@@ -12,15 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::backend::GpuNode;
 use crate::graphic::{GlBuffer, GlProgram, GlProgramBuilder, GlVertexArray};
 use crate::opengl;
-use crate::opengl::types::{GLboolean, GLint, GLsizeiptr, GLuint};
+use crate::opengl::types::{GLboolean, GLint, GLsizei, GLsizeiptr, GLuint};
 use fnv::FnvHashSet;
 use lru::LruCache;
-use nalgebra::Matrix4;
+use nalgebra::{Matrix4, Vector4};
+use point_viewer::color::ColorSpace;
+use point_viewer::frame_capture::FrameRecorder;
 use point_viewer::octree;
-use point_viewer::read_write::PositionEncoding;
+use point_viewer::read_write::{PositionEncoding, VertexFormat, VertexLayout};
 use rand::{prelude::SliceRandom, thread_rng};
+use serde_derive::Deserialize;
+use std::ffi::CString;
 use std::os::raw::c_void;
 use std::ptr;
 use std::rc::Rc;
@@ -42,6 +47,16 @@ fn reshuffle(new_order: &[usize], old_data: &[u8], bytes_per_vertex: usize) -> V
     new_data
 }
 
+/// How the points are colored.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMap {
+    /// The colors of the points.
+    Rgb,
+    /// A ramp from blue to red by the height of the points in the octree.
+    Height,
+}
+
 pub struct NodeProgram {
     program: GlProgram,
 
@@ -51,15 +66,27 @@ pub struct NodeProgram {
     u_size: GLint,
     u_gamma: GLint,
     u_min: GLint,
+    u_color_map: GLint,
+    u_height_range: GLint,
+    u_eye: GLint,
+    u_shading: GLint,
 }
 
 pub struct NodeDrawer {
     program_f32: NodeProgram,
     program_f64: NodeProgram,
+    // The layout of 'NodeData::shading', see 'Octree::shading_layout'.
+    shading_layout: Option<VertexLayout>,
 }
 
 impl NodeDrawer {
-    pub fn new(gl: &Rc<opengl::Gl>) -> Self {
+    /// Creates the programs for an octree whose colors are in 'color_space'. The shaders convert
+    /// them to sRGB. Nodes are shaded with the attributes of 'shading_layout', if any.
+    pub fn new(
+        gl: &Rc<opengl::Gl>,
+        color_space: ColorSpace,
+        shading_layout: Option<VertexLayout>,
+    ) -> Self {
         let create_program = |vertex_shader: &str| {
             let program = GlProgramBuilder::new_with_vertex_shader(Rc::clone(gl), vertex_shader)
                 .fragment_shader(FRAGMENT_SHADER)
@@ -69,6 +96,10 @@ impl NodeDrawer {
             let u_size;
             let u_gamma;
             let u_min;
+            let u_color_map;
+            let u_height_range;
+            let u_eye;
+            let u_shading;
             unsafe {
                 gl.UseProgram(program.id);
 
@@ -77,6 +108,15 @@ impl NodeDrawer {
                 u_size = gl.GetUniformLocation(program.id, c_str!("size"));
                 u_gamma = gl.GetUniformLocation(program.id, c_str!("gamma"));
                 u_min = gl.GetUniformLocation(program.id, c_str!("min"));
+                u_color_map = gl.GetUniformLocation(program.id, c_str!("color_map"));
+                u_height_range = gl.GetUniformLocation(program.id, c_str!("height_range"));
+                u_eye = gl.GetUniformLocation(program.id, c_str!("eye"));
+                u_shading = gl.GetUniformLocation(program.id, c_str!("shading"));
+                // The color space does not change, so it is set once.
+                gl.Uniform1i(
+                    gl.GetUniformLocation(program.id, c_str!("linear_colors")),
+                    (color_space == ColorSpace::Linear) as GLint,
+                );
             }
             NodeProgram {
                 program,
@@ -85,6 +125,10 @@ impl NodeDrawer {
                 u_size,
                 u_gamma,
                 u_min,
+                u_color_map,
+                u_height_range,
+                u_eye,
+                u_shading,
             }
         };
         let program_f32 = create_program(VERTEX_SHADER);
@@ -96,6 +140,7 @@ impl NodeDrawer {
         NodeDrawer {
             program_f32,
             program_f64,
+            shading_layout,
         }
     }
 
@@ -108,6 +153,12 @@ impl NodeDrawer {
     }
 
     pub fn update_world_to_gl(&mut self, matrix: &Matrix4<f64>) {
+        // The camera in homogeneous coordinates, which clip space sees infinitely far behind the
+        // near plane: a point for perspective views and a direction for orthographic ones.
+        let eye = matrix
+            .try_inverse()
+            .map(|gl_to_world| gl_to_world * Vector4::new(0., 0., -1., 0.))
+            .unwrap_or_else(Vector4::zeros);
         let update_matrix = |node_program: &mut NodeProgram| unsafe {
             node_program.program.gl.UseProgram(node_program.program.id);
             node_program.program.gl.UniformMatrix4dv(
@@ -116,11 +167,50 @@ impl NodeDrawer {
                 false as GLboolean,
                 matrix.as_ptr(),
             );
+            node_program
+                .program
+                .gl
+                .Uniform4dv(node_program.u_eye, 1, eye.as_ptr());
         };
         update_matrix(&mut self.program_f32);
         update_matrix(&mut self.program_f64);
     }
 
+    /// With 'ColorMap::Height', heights from 'min_height' to 'max_height' span the whole ramp.
+    pub fn set_color_map(&mut self, color_map: ColorMap, min_height: f64, max_height: f64) {
+        let color_map = match color_map {
+            ColorMap::Rgb => 0,
+            ColorMap::Height => 1,
+        };
+        let update_color_map = |node_program: &mut NodeProgram| unsafe {
+            node_program.program.gl.UseProgram(node_program.program.id);
+            node_program
+                .program
+                .gl
+                .Uniform1i(node_program.u_color_map, color_map);
+            node_program.program.gl.Uniform2f(
+                node_program.u_height_range,
+                min_height as f32,
+                max_height as f32,
+            );
+        };
+        update_color_map(&mut self.program_f32);
+        update_color_map(&mut self.program_f64);
+    }
+
+    /// Shades the points by their normals, for nodes that have them.
+    pub fn set_shading(&mut self, shading: bool) {
+        let update_shading = |node_program: &mut NodeProgram| unsafe {
+            node_program.program.gl.UseProgram(node_program.program.id);
+            node_program
+                .program
+                .gl
+                .Uniform1i(node_program.u_shading, shading as GLint);
+        };
+        update_shading(&mut self.program_f32);
+        update_shading(&mut self.program_f64);
+    }
+
     pub fn draw(
         &self,
         node_view: &NodeView,
@@ -168,11 +258,22 @@ pub struct NodeView {
     vertex_array: GlVertexArray,
     _buffer_position: GlBuffer,
     _buffer_color: GlBuffer,
+    _buffer_shading: Option<GlBuffer>,
     used_memory_bytes: usize,
 }
 
+impl GpuNode for NodeView {
+    fn meta(&self) -> &octree::NodeMeta {
+        &self.meta
+    }
+
+    fn used_memory_bytes(&self) -> usize {
+        self.used_memory_bytes
+    }
+}
+
 impl NodeView {
-    fn new(node_drawer: &NodeDrawer, node_data: octree::NodeData) -> Self {
+    pub fn new(node_drawer: &NodeDrawer, node_data: octree::NodeData) -> Self {
         let node_program = node_drawer.program(&node_data.meta.position_encoding);
         let program = &node_program.program;
         unsafe {
@@ -196,6 +297,9 @@ impl NodeView {
                 PositionEncoding::Uint16 => 6,
                 PositionEncoding::Float32 => 12,
                 PositionEncoding::Float64 => 24,
+                PositionEncoding::Uint24 | PositionEncoding::Delta => {
+                    unreachable!("Octree::get_node_data transcodes these to Float32.")
+                }
             },
         );
         let color = reshuffle(&indices, &node_data.color, 3);
@@ -210,6 +314,9 @@ impl NodeView {
                 PositionEncoding::Uint16 => (opengl::TRUE, opengl::UNSIGNED_SHORT),
                 PositionEncoding::Float32 => (opengl::FALSE, opengl::FLOAT),
                 PositionEncoding::Float64 => (opengl::FALSE, opengl::DOUBLE),
+                PositionEncoding::Uint24 | PositionEncoding::Delta => {
+                    unreachable!("Octree::get_node_data transcodes these to Float32.")
+                }
             };
             program.gl.BufferData(
                 opengl::ARRAY_BUFFER,
@@ -249,36 +356,90 @@ impl NodeView {
                 ptr::null(),
             );
         }
+
+        // Attributes without a buffer read as zero, which the shader does not shade.
+        let mut shading_bytes = 0;
+        let buffer_shading = match &node_drawer.shading_layout {
+            Some(layout) if !node_data.shading.is_empty() => {
+                let shading = reshuffle(&indices, &node_data.shading, layout.stride());
+                shading_bytes = shading.len();
+                let buffer_shading = GlBuffer::new_array_buffer(Rc::clone(&program.gl));
+                unsafe {
+                    buffer_shading.bind();
+                    program.gl.BufferData(
+                        opengl::ARRAY_BUFFER,
+                        shading.len() as GLsizeiptr,
+                        &shading[0] as *const u8 as *const c_void,
+                        opengl::STATIC_DRAW,
+                    );
+                    for attribute in layout.attributes() {
+                        let (size, data_type, normalize) = match attribute.format {
+                            VertexFormat::Snorm8x4 => (4, opengl::BYTE, opengl::TRUE),
+                            VertexFormat::Float32 => (1, opengl::FLOAT, opengl::FALSE),
+                            _ => unreachable!("Octree::shading_layout has no other formats."),
+                        };
+                        let name = CString::new(attribute.name.as_str()).unwrap();
+                        let location = program.gl.GetAttribLocation(program.id, name.as_ptr());
+                        // The shader compiler removes attributes that it does not use.
+                        if location < 0 {
+                            continue;
+                        }
+                        program.gl.EnableVertexAttribArray(location as GLuint);
+                        program.gl.VertexAttribPointer(
+                            location as GLuint,
+                            size,
+                            data_type,
+                            normalize as GLboolean,
+                            layout.stride() as GLsizei,
+                            attribute.offset as *const c_void,
+                        );
+                    }
+                }
+                Some(buffer_shading)
+            }
+            _ => None,
+        };
         NodeView {
             vertex_array,
             _buffer_position: buffer_position,
             _buffer_color: buffer_color,
+            _buffer_shading: buffer_shading,
             meta: node_data.meta,
-            used_memory_bytes: position.len() + color.len(),
+            used_memory_bytes: position.len() + color.len() + shading_bytes,
         }
     }
 }
 
 // Keeps track of the nodes that were requested in-order and loads then one by one on request.
-pub struct NodeViewContainer {
-    node_views: LruCache<octree::NodeId, NodeView>,
+pub struct NodeViewContainer<V> {
+    node_views: LruCache<octree::NodeId, V>,
     // The node_ids that the I/O thread is currently loading.
     requested: FnvHashSet<octree::NodeId>,
     // Communication with the I/O thread.
     node_id_sender: Sender<octree::NodeId>,
     node_data_receiver: Receiver<(octree::NodeId, octree::NodeData)>,
+    // Records the requests and arrivals of nodes in debug mode.
+    frame_recorder: Option<Arc<FrameRecorder>>,
 }
 
-impl NodeViewContainer {
-    pub fn new(octree: Arc<octree::Octree>, max_nodes_in_memory: usize) -> Self {
+impl<V: GpuNode> NodeViewContainer<V> {
+    pub fn new(
+        octree: Arc<octree::Octree>,
+        max_nodes_in_memory: usize,
+        frame_recorder: Option<Arc<FrameRecorder>>,
+    ) -> Self {
         // We perform I/O in a separate thread in order to not block the main thread while loading.
         // Data sharing is done through channels.
         let (node_id_sender, node_id_receiver) = mpsc::channel();
         let (node_data_sender, node_data_receiver) = mpsc::channel();
         std::thread::spawn(move || {
+            let shading_layout = octree.shading_layout();
             // Loads the next node data in the receiver queue.
             for node_id in node_id_receiver {
-                let node_data = octree.get_node_data(&node_id).unwrap();
+                let mut node_data = octree.get_node_data(&node_id).unwrap();
+                if let Some(layout) = &shading_layout {
+                    node_data.shading = octree.get_node_vertices(&node_id, layout).unwrap();
+                }
                 // TODO(hrapp): reshuffle
                 node_data_sender.send((node_id, node_data)).unwrap();
             }
@@ -288,16 +449,20 @@ impl NodeViewContainer {
             requested: FnvHashSet::default(),
             node_id_sender,
             node_data_receiver,
+            frame_recorder,
         }
     }
 
-    pub fn consume_arrived_nodes(&mut self, node_drawer: &NodeDrawer) -> bool {
+    /// Uploads the nodes that the I/O thread loaded with 'upload'.
+    pub fn consume_arrived_nodes(&mut self, mut upload: impl FnMut(octree::NodeData) -> V) -> bool {
         let mut consumed_any = false;
         while let Ok((node_id, node_data)) = self.node_data_receiver.try_recv() {
             // Put loaded node into hash map.
             self.requested.remove(&node_id);
-            self.node_views
-                .put(node_id, NodeView::new(node_drawer, node_data));
+            if let Some(frame_recorder) = &self.frame_recorder {
+                frame_recorder.received(&node_id);
+            }
+            self.node_views.put(node_id, upload(node_data));
             consumed_any = true;
         }
         consumed_any
@@ -305,33 +470,44 @@ impl NodeViewContainer {
 
     // Returns the 'NodeView' for 'node_id' if it is already loaded, otherwise returns None, but
     // requested the node for loading in the I/O thread
-    pub fn get_or_request(&mut self, node_id: &octree::NodeId) -> Option<&NodeView> {
+    pub fn get_or_request(&mut self, node_id: &octree::NodeId) -> Option<&V> {
         if self.node_views.contains(node_id) {
-            return self.node_views.get_mut(node_id).map(|f| f as &NodeView);
+            return self.node_views.get_mut(node_id).map(|f| f as &V);
         }
 
         // Limit the number of requested nodes because after a camera move
         // requested nodes might not be in the frustum anymore.
         if !self.requested.contains(node_id) && self.requested.len() < 10 {
-            self.requested.insert(*node_id);
-            self.node_id_sender.send(*node_id).unwrap();
+            self.request(*node_id);
         }
         None
     }
 
+    // Returns the 'NodeView' for 'node_id' if it is loaded, without marking it as recently used.
+    pub fn get(&self, node_id: &octree::NodeId) -> Option<&V> {
+        self.node_views.peek(node_id)
+    }
+
     pub fn request_all(&mut self, node_ids: &[octree::NodeId]) {
         for &node_id in node_ids {
             if !self.node_views.contains(&node_id) && !self.requested.contains(&node_id) {
-                self.requested.insert(node_id);
-                self.node_id_sender.send(node_id).unwrap();
+                self.request(node_id);
             }
         }
     }
 
+    fn request(&mut self, node_id: octree::NodeId) {
+        self.requested.insert(node_id);
+        self.node_id_sender.send(node_id).unwrap();
+        if let Some(frame_recorder) = &self.frame_recorder {
+            frame_recorder.requested(&node_id);
+        }
+    }
+
     pub fn get_used_memory_bytes(&self) -> usize {
         self.node_views
             .iter()
-            .map(|(_, node_view)| node_view.used_memory_bytes)
+            .map(|(_, node_view)| node_view.used_memory_bytes())
             .sum()
     }
 }
@@ -0,0 +1,348 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selects the nodes to draw for one or more views, either on a background thread or with a
+//! compute shader.
+
+use crate::graphic::{GlBuffer, GlProgram, GlUniform};
+use crate::opengl;
+use crate::opengl::types::{GLint, GLsizeiptr, GLsync, GLuint};
+use fnv::FnvHashMap;
+use nalgebra::{Matrix4, Vector2};
+use point_viewer::octree::{NodeId, Octree, ScreenSpaceError};
+use std::mem;
+use std::ops::Range;
+use std::os::raw::c_void;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+const COMPUTE_SHADER: &str = include_str!("../shaders/node_selection.cs");
+
+/// 'local_size_x' of the compute shader.
+const WORK_GROUP_SIZE: usize = 64;
+
+/// The most views that one selection is for, see 'Layout'.
+pub const MAX_VIEWS: usize = 4;
+
+/// The view transform and the metric of every view that nodes are selected for.
+pub type Views = Vec<(Matrix4<f64>, ScreenSpaceError)>;
+
+/// The nodes selected for any of the views with their screen-space error in one of them. Every
+/// node is returned once, the largest on screen in any of the views first, so that the renderer
+/// can draw only the first ones while the camera moves.
+fn largest_first(selected: impl IntoIterator<Item = (NodeId, f64)>) -> Vec<NodeId> {
+    let mut errors: FnvHashMap<NodeId, f64> = FnvHashMap::default();
+    for (node_id, error) in selected {
+        let max_error = errors.entry(node_id).or_insert(error);
+        *max_error = max_error.max(error);
+    }
+    let mut nodes: Vec<(NodeId, f64)> = errors.into_iter().collect();
+    nodes.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    nodes.into_iter().map(|(node_id, _)| node_id).collect()
+}
+
+/// Whether the context runs compute shaders, which need OpenGL 4.3. macOS only provides 4.1.
+pub fn supports_compute_shaders(gl: &opengl::Gl) -> bool {
+    let mut major: GLint = 0;
+    let mut minor: GLint = 0;
+    unsafe {
+        gl.GetIntegerv(opengl::MAJOR_VERSION, &mut major);
+        gl.GetIntegerv(opengl::MINOR_VERSION, &mut minor);
+    }
+    (major, minor) >= (4, 3) && gl.DispatchCompute.is_loaded()
+}
+
+pub enum NodeSelector {
+    /// Traverses the octree with 'Octree::get_visible_nodes_with_screen_space_error' on a thread,
+    /// once per view. If multiple requests queue up while it is processing one, it drops all but
+    /// the latest one before starting the next.
+    Cpu {
+        params_tx: mpsc::Sender<Views>,
+        result_rx: mpsc::Receiver<Vec<NodeId>>,
+    },
+    Gpu(GpuNodeSelector),
+}
+
+impl NodeSelector {
+    pub fn cpu(octree: Arc<Octree>) -> Self {
+        let (params_tx, rx) = mpsc::channel::<Views>();
+        let (tx, result_rx) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(mut params) = rx.recv() {
+                // Drain the channel, we only ever want to update the latest.
+                while let Ok(newer_params) = rx.try_recv() {
+                    params = newer_params;
+                }
+                let visible_nodes = largest_first(params.iter().flat_map(|(matrix, metric)| {
+                    octree.get_visible_nodes_with_screen_space_error(matrix, metric)
+                }));
+                tx.send(visible_nodes).unwrap();
+            }
+        });
+        NodeSelector::Cpu {
+            params_tx,
+            result_rx,
+        }
+    }
+
+    /// Selects the nodes with a compute shader if the context supports them, and on a thread
+    /// otherwise.
+    pub fn new(gl: Rc<opengl::Gl>, octree: Arc<Octree>) -> Self {
+        if supports_compute_shaders(&gl) {
+            NodeSelector::Gpu(GpuNodeSelector::new(gl, &octree))
+        } else {
+            Self::cpu(octree)
+        }
+    }
+
+    /// Starts selecting the nodes for 'views', at most 'MAX_VIEWS'. The result is returned by a
+    /// later 'poll'.
+    pub fn request(&mut self, views: Views) {
+        assert!(
+            views.len() <= MAX_VIEWS,
+            "Nodes can be selected for at most {} views.",
+            MAX_VIEWS
+        );
+        match self {
+            NodeSelector::Cpu { params_tx, .. } => params_tx.send(views).unwrap(),
+            NodeSelector::Gpu(selector) => selector.request(views),
+        }
+    }
+
+    /// The latest selected nodes, largest on screen first, if a selection finished since the
+    /// last call. Never blocks.
+    pub fn poll(&mut self) -> Option<Vec<NodeId>> {
+        match self {
+            NodeSelector::Cpu { result_rx, .. } => result_rx.try_iter().last(),
+            NodeSelector::Gpu(selector) => selector.poll(),
+        }
+    }
+}
+
+/// Selects the nodes to draw with a compute shader, which frees the CPU on very deep octrees. The
+/// bounding cubes and parents of all nodes are uploaded once, and every selection runs one
+/// dispatch per level and view, in which a node is reached if its parent was. It selects the same
+/// nodes as 'Octree::get_visible_nodes_with_screen_space_error', except that nodes are culled
+/// against the planes of the view instead of the exact frustum, which keeps a few nodes near its
+/// edges.
+pub struct GpuNodeSelector {
+    program: GlProgram,
+    u_world_to_gl: GlUniform<Matrix4<f64>>,
+    u_viewport_size: GlUniform<Vector2<f64>>,
+    u_pixel_threshold: GlUniform<f64>,
+    u_first_node: GlUniform<u32>,
+    u_num_nodes: GlUniform<u32>,
+    // In the order of their bindings in the shader: cubes, links, reached, num_visible and
+    // visible_nodes.
+    buffers: Vec<GlBuffer>,
+    // The nodes by level, which is their order in the buffers.
+    node_ids: Vec<NodeId>,
+    levels: Vec<Range<usize>>,
+    pending: Option<Views>,
+    // Signals that the GPU finished the running selection.
+    fence: Option<GLsync>,
+}
+
+/// A buffer holding 'size' bytes, initialized from 'data' if given.
+fn storage_buffer(gl: &Rc<opengl::Gl>, size: usize, data: *const c_void) -> GlBuffer {
+    let buffer = GlBuffer::new_shader_storage_buffer(Rc::clone(gl));
+    buffer.bind();
+    unsafe {
+        gl.BufferData(
+            opengl::SHADER_STORAGE_BUFFER,
+            size as GLsizeiptr,
+            data,
+            opengl::DYNAMIC_READ,
+        );
+    }
+    buffer
+}
+
+impl GpuNodeSelector {
+    pub fn new(gl: Rc<opengl::Gl>, octree: &Octree) -> Self {
+        let mut node_ids: Vec<NodeId> = octree.nodes(..).collect();
+        node_ids.sort_by_key(|id| (id.level(), *id));
+        let indices: FnvHashMap<NodeId, i32> = node_ids
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (*id, index as i32))
+            .collect();
+        let mut levels: Vec<Range<usize>> = Vec::new();
+        let mut cubes = Vec::with_capacity(node_ids.len());
+        let mut links = Vec::with_capacity(node_ids.len());
+        for (index, id) in node_ids.iter().enumerate() {
+            let level = usize::from(id.level());
+            if levels.len() <= level {
+                levels.resize(level + 1, index..index);
+            }
+            levels[level].end = index + 1;
+            let meta = octree.get_node_meta(id).unwrap();
+            let min = meta.bounding_cube.min();
+            cubes.push([min.x, min.y, min.z, meta.bounding_cube.edge_length()]);
+            let parent = id.parent_id().map_or(-1, |parent_id| indices[&parent_id]);
+            links.push([parent, (meta.num_points > 0) as i32]);
+        }
+
+        let num_nodes = node_ids.len();
+        let buffers = vec![
+            storage_buffer(
+                &gl,
+                num_nodes * mem::size_of::<[f64; 4]>(),
+                cubes.as_ptr() as *const c_void,
+            ),
+            storage_buffer(
+                &gl,
+                num_nodes * mem::size_of::<[i32; 2]>(),
+                links.as_ptr() as *const c_void,
+            ),
+            storage_buffer(&gl, num_nodes * mem::size_of::<u32>(), ptr::null()),
+            storage_buffer(&gl, mem::size_of::<u32>(), ptr::null()),
+            storage_buffer(
+                &gl,
+                MAX_VIEWS * num_nodes * mem::size_of::<[u32; 2]>(),
+                ptr::null(),
+            ),
+        ];
+
+        let program = GlProgram::new_compute(gl, COMPUTE_SHADER);
+        GpuNodeSelector {
+            u_world_to_gl: GlUniform::new(&program, "world_to_gl", Matrix4::identity()),
+            u_viewport_size: GlUniform::new(&program, "viewport_size", Vector2::zeros()),
+            u_pixel_threshold: GlUniform::new(&program, "pixel_threshold", 0.),
+            u_first_node: GlUniform::new(&program, "first_node", 0),
+            u_num_nodes: GlUniform::new(&program, "num_nodes", 0),
+            program,
+            buffers,
+            node_ids,
+            levels,
+            pending: None,
+            fence: None,
+        }
+    }
+
+    /// Selects the nodes for 'views' once the running selection finished. Only the latest request
+    /// is kept.
+    pub fn request(&mut self, views: Views) {
+        self.pending = Some(views);
+    }
+
+    /// The selected nodes, if the GPU finished a selection, and starts the pending one.
+    pub fn poll(&mut self) -> Option<Vec<NodeId>> {
+        let mut visible_nodes = None;
+        if let Some(fence) = self.fence {
+            let status = unsafe {
+                self.program
+                    .gl
+                    .ClientWaitSync(fence, opengl::SYNC_FLUSH_COMMANDS_BIT, 0)
+            };
+            if status == opengl::TIMEOUT_EXPIRED {
+                return None;
+            }
+            unsafe {
+                self.program.gl.DeleteSync(fence);
+            }
+            self.fence = None;
+            visible_nodes = Some(self.read_visible_nodes());
+        }
+        if let Some(views) = self.pending.take() {
+            self.dispatch(&views);
+        }
+        visible_nodes
+    }
+
+    fn dispatch(&mut self, views: &[(Matrix4<f64>, ScreenSpaceError)]) {
+        let gl = Rc::clone(&self.program.gl);
+        unsafe {
+            gl.UseProgram(self.program.id);
+            for (binding, buffer) in self.buffers.iter().enumerate() {
+                buffer.bind_base(binding as GLuint);
+            }
+            self.buffers[3].bind();
+            gl.BufferSubData(
+                opengl::SHADER_STORAGE_BUFFER,
+                0,
+                mem::size_of::<u32>() as GLsizeiptr,
+                &0u32 as *const u32 as *const c_void,
+            );
+        }
+        // All views append to the same list of visible nodes.
+        for (world_to_gl, metric) in views {
+            self.u_world_to_gl.value = *world_to_gl;
+            self.u_world_to_gl.submit();
+            self.u_viewport_size.value = Vector2::new(metric.width, metric.height);
+            self.u_viewport_size.submit();
+            self.u_pixel_threshold.value = metric.pixel_threshold;
+            self.u_pixel_threshold.submit();
+            for level in &self.levels {
+                self.u_first_node.value = level.start as u32;
+                self.u_first_node.submit();
+                self.u_num_nodes.value = level.len() as u32;
+                self.u_num_nodes.submit();
+                let num_groups = (level.len() + WORK_GROUP_SIZE - 1) / WORK_GROUP_SIZE;
+                unsafe {
+                    gl.DispatchCompute(num_groups as GLuint, 1, 1);
+                    // The next level reads which of its parents were reached.
+                    gl.MemoryBarrier(opengl::SHADER_STORAGE_BARRIER_BIT);
+                }
+            }
+        }
+        unsafe {
+            gl.MemoryBarrier(opengl::BUFFER_UPDATE_BARRIER_BIT);
+            self.fence = Some(gl.FenceSync(opengl::SYNC_GPU_COMMANDS_COMPLETE, 0));
+        }
+    }
+
+    fn read_visible_nodes(&self) -> Vec<NodeId> {
+        let gl = &self.program.gl;
+        let mut num_visible = 0u32;
+        let mut visible: Vec<[u32; 2]>;
+        unsafe {
+            self.buffers[3].bind();
+            gl.GetBufferSubData(
+                opengl::SHADER_STORAGE_BUFFER,
+                0,
+                mem::size_of::<u32>() as GLsizeiptr,
+                &mut num_visible as *mut u32 as *mut c_void,
+            );
+            visible = vec![[0; 2]; num_visible as usize];
+            self.buffers[4].bind();
+            gl.GetBufferSubData(
+                opengl::SHADER_STORAGE_BUFFER,
+                0,
+                (visible.len() * mem::size_of::<[u32; 2]>()) as GLsizeiptr,
+                visible.as_mut_ptr() as *mut c_void,
+            );
+        }
+        // The entries are the index of the node and its screen-space error as float bits, once
+        // for every view that selected the node.
+        largest_first(visible.iter().map(|entry| {
+            (
+                self.node_ids[entry[0] as usize],
+                f64::from(f32::from_bits(entry[1])),
+            )
+        }))
+    }
+}
+
+impl Drop for GpuNodeSelector {
+    fn drop(&mut self) {
+        if let Some(fence) = self.fence {
+            unsafe {
+                self.program.gl.DeleteSync(fence);
+            }
+        }
+    }
+}
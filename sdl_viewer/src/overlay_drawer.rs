@@ -0,0 +1,185 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Draws the lines of vector overlays over the point cloud, in a color per layer, see
+//! 'point_viewer::overlay'.
+
+use crate::graphic::{GlBuffer, GlProgram, GlProgramBuilder, GlVertexArray};
+use crate::opengl;
+use crate::opengl::types::{GLboolean, GLint, GLsizei, GLsizeiptr, GLuint};
+use nalgebra::Matrix4;
+use point_viewer::color::{category_color, Color};
+use point_viewer::overlay::OverlayLayer;
+use std::mem;
+use std::os::raw::c_void;
+use std::ptr;
+use std::rc::Rc;
+
+const FRAGMENT_SHADER: &str = include_str!("../shaders/box_drawer_outline.fs");
+const VERTEX_SHADER: &str = include_str!("../shaders/box_drawer_outline.vs");
+
+/// The line segments of a layer on the GPU.
+struct LayerLines {
+    name: String,
+    color: Color<f32>,
+    num_vertices: usize,
+    vertex_array: GlVertexArray,
+    _buffer_position: GlBuffer,
+}
+
+/// Which layers are shown. 'L' steps from all layers through every single layer to none.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Visibility {
+    All,
+    Only(usize),
+    None,
+}
+
+pub struct OverlayDrawer {
+    program: GlProgram,
+    u_transform: GLint,
+    u_color: GLint,
+    layers: Vec<LayerLines>,
+    visibility: Visibility,
+}
+
+impl OverlayDrawer {
+    pub fn new(gl: &Rc<opengl::Gl>, layers: &[OverlayLayer]) -> Self {
+        let program = GlProgramBuilder::new_with_vertex_shader(Rc::clone(gl), VERTEX_SHADER)
+            .fragment_shader(FRAGMENT_SHADER)
+            .build();
+        let (u_transform, u_color, position_attribute) = unsafe {
+            gl.UseProgram(program.id);
+            (
+                gl.GetUniformLocation(program.id, c_str!("transform")),
+                gl.GetUniformLocation(program.id, c_str!("color")),
+                gl.GetAttribLocation(program.id, c_str!("position")),
+            )
+        };
+        let layers = layers
+            .iter()
+            .enumerate()
+            .map(|(index, layer)| {
+                // Every polyline becomes line segments, closed ones get the segment back to their
+                // first point.
+                let mut vertices: Vec<[f64; 3]> = Vec::new();
+                for polyline in &layer.polylines {
+                    let points = &polyline.points;
+                    let num_segments = if polyline.closed {
+                        points.len()
+                    } else {
+                        points.len() - 1
+                    };
+                    for i in 0..num_segments {
+                        for p in &[points[i], points[(i + 1) % points.len()]] {
+                            vertices.push([p.x, p.y, p.z]);
+                        }
+                    }
+                }
+                let vertex_array = GlVertexArray::new(Rc::clone(gl));
+                vertex_array.bind();
+                let buffer_position = GlBuffer::new_array_buffer(Rc::clone(gl));
+                buffer_position.bind();
+                unsafe {
+                    gl.BufferData(
+                        opengl::ARRAY_BUFFER,
+                        (vertices.len() * 3 * mem::size_of::<f64>()) as GLsizeiptr,
+                        vertices.as_ptr() as *const c_void,
+                        opengl::STATIC_DRAW,
+                    );
+                    gl.EnableVertexAttribArray(position_attribute as GLuint);
+                    gl.VertexAttribLPointer(
+                        position_attribute as GLuint,
+                        3,
+                        opengl::DOUBLE,
+                        3 * mem::size_of::<f64>() as i32,
+                        ptr::null(),
+                    );
+                }
+                LayerLines {
+                    name: layer.name.clone(),
+                    color: category_color(index as u32).to_f32(),
+                    num_vertices: vertices.len(),
+                    vertex_array,
+                    _buffer_position: buffer_position,
+                }
+            })
+            .collect();
+        OverlayDrawer {
+            program,
+            u_transform,
+            u_color,
+            layers,
+            visibility: Visibility::All,
+        }
+    }
+
+    /// Shows the next layers, see 'Visibility', and prints which ones are shown.
+    pub fn show_next_layers(&mut self) {
+        if self.layers.is_empty() {
+            return;
+        }
+        self.visibility = match self.visibility {
+            // With a single layer, showing only it is the same as showing all.
+            Visibility::All if self.layers.len() > 1 => Visibility::Only(0),
+            Visibility::Only(index) if index + 1 < self.layers.len() => Visibility::Only(index + 1),
+            Visibility::All | Visibility::Only(_) => Visibility::None,
+            Visibility::None => Visibility::All,
+        };
+        match self.visibility {
+            Visibility::All => eprintln!("Showing all overlay layers."),
+            Visibility::Only(index) => {
+                eprintln!("Showing the overlay layer '{}'.", self.layers[index].name)
+            }
+            Visibility::None => eprintln!("Hiding the overlays."),
+        }
+    }
+
+    fn is_visible(&self, index: usize) -> bool {
+        match self.visibility {
+            Visibility::All => true,
+            Visibility::Only(shown) => shown == index,
+            Visibility::None => false,
+        }
+    }
+
+    pub fn draw(&self, world_to_gl: &Matrix4<f64>) {
+        let gl = &self.program.gl;
+        unsafe {
+            gl.UseProgram(self.program.id);
+            gl.UniformMatrix4dv(
+                self.u_transform,
+                1,
+                false as GLboolean,
+                world_to_gl.as_ptr(),
+            );
+        }
+        for (index, layer) in self.layers.iter().enumerate() {
+            if !self.is_visible(index) {
+                continue;
+            }
+            layer.vertex_array.bind();
+            unsafe {
+                gl.Uniform4f(
+                    self.u_color,
+                    layer.color.red,
+                    layer.color.green,
+                    layer.color.blue,
+                    layer.color.alpha,
+                );
+                gl.DrawArrays(opengl::LINES, 0, layer.num_vertices as GLsizei);
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scripts in [Rhai](https://rhai.rs) that automate the viewer without recompiling it, e.g.
+//! review sequences that move the camera along poses and take screenshots, or measurement macros
+//! bound to keys. A script defines any of the hooks
+//!
+//! - 'on_start()', once before the first frame,
+//! - 'on_frame(time_s)', in every frame with the seconds since the start,
+//! - 'on_key(name)', for every key that is pressed, with its SDL name, e.g. "F1",
+//!
+//! and calls the functions registered in 'Script::load' from them. Numbers passed to these
+//! functions are floats, e.g. '1.0', and positions are in the coordinates of the octree. Rhai
+//! functions do not see the variables of the script, so hooks keep what they need in the next
+//! call with 'set_state(name, value)' and 'get_state(name, default)'.
+
+use nalgebra::{Isometry3, Point3, Quaternion, Translation3, UnitQuaternion, Vector3};
+use point_viewer::data_provider::DataProviderFactory;
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::{PointCloud, PointLocation, PointQuery};
+use point_viewer::octree::Octree;
+use point_viewer::NUM_POINTS_PER_BATCH;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// 'points_in_box' fails for more points than this, since every point is a script value.
+const MAX_SCRIPT_POINTS: usize = 1_000_000;
+
+/// What a script asked the viewer to do. The viewer carries the commands out after every hook.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptCommand {
+    /// Moves the camera to this pose, see 'Camera::set_pose'.
+    SetPose(Isometry3<f64>),
+    /// Moves the camera to this position, keeping its direction.
+    MoveTo(Point3<f64>),
+    /// Writes the next frame to this PNG file.
+    Screenshot(PathBuf),
+    Quit,
+}
+
+/// What scripts can read about the viewer.
+#[derive(Clone, Debug)]
+struct ViewerState {
+    camera_pose: Isometry3<f64>,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    commands: Rc<RefCell<Vec<ScriptCommand>>>,
+    state: Rc<RefCell<ViewerState>>,
+}
+
+/// The points of 'octree' inside the box as '[x, y, z]' arrays.
+fn points_in_box(octree: &Octree, aabb: Aabb) -> ScriptResult<Array> {
+    let query = PointQuery {
+        location: PointLocation::Aabb(aabb),
+        ..Default::default()
+    };
+    let mut points = Array::new();
+    for node_id in octree.nodes_in_location(&query.location) {
+        octree
+            .stream_points_for_query_in_node(&query, node_id, NUM_POINTS_PER_BATCH, |batch| {
+                points.extend(batch.position.iter().map(|p| {
+                    let coordinates: Array = vec![p.x.into(), p.y.into(), p.z.into()];
+                    Dynamic::from(coordinates)
+                }));
+                Ok(())
+            })
+            .map_err(|err| err.to_string())?;
+        if points.len() > MAX_SCRIPT_POINTS {
+            return Err(format!(
+                "More than {} points are in the box, query a smaller one.",
+                MAX_SCRIPT_POINTS
+            )
+            .into());
+        }
+    }
+    Ok(points)
+}
+
+impl Script {
+    /// Compiles the script at 'path' and runs its top level statements. Its queries run on
+    /// 'octree' until it opens another one with 'load_octree', through 'data_provider_factory'.
+    pub fn load(
+        path: &Path,
+        octree: Arc<Octree>,
+        data_provider_factory: DataProviderFactory,
+    ) -> Result<Self, String> {
+        let commands = Rc::new(RefCell::new(Vec::new()));
+        let state = Rc::new(RefCell::new(ViewerState {
+            camera_pose: Isometry3::identity(),
+        }));
+        let octree = Rc::new(RefCell::new(octree));
+        let mut engine = Engine::new();
+
+        let values = Rc::new(RefCell::new(Map::new()));
+        let stored = Rc::clone(&values);
+        engine.register_fn("set_state", move |name: &str, value: Dynamic| {
+            stored.borrow_mut().insert(name.into(), value);
+        });
+        engine.register_fn(
+            "get_state",
+            move |name: &str, default: Dynamic| -> Dynamic {
+                values.borrow().get(name).cloned().unwrap_or(default)
+            },
+        );
+
+        let camera_state = Rc::clone(&state);
+        engine.register_fn("camera_position", move || -> Array {
+            let t = camera_state.borrow().camera_pose.translation.vector;
+            vec![t.x.into(), t.y.into(), t.z.into()]
+        });
+        let camera_state = Rc::clone(&state);
+        engine.register_fn("camera_rotation", move || -> Array {
+            let q = camera_state.borrow().camera_pose.rotation.coords;
+            vec![q.x.into(), q.y.into(), q.z.into(), q.w.into()]
+        });
+        let queue = Rc::clone(&commands);
+        engine.register_fn(
+            "set_camera",
+            move |x: f64, y: f64, z: f64, qx: f64, qy: f64, qz: f64, qw: f64| {
+                let rotation = UnitQuaternion::from_quaternion(Quaternion::new(qw, qx, qy, qz));
+                queue
+                    .borrow_mut()
+                    .push(ScriptCommand::SetPose(Isometry3::from_parts(
+                        Translation3::new(x, y, z),
+                        rotation,
+                    )));
+            },
+        );
+        let queue = Rc::clone(&commands);
+        engine.register_fn("move_camera", move |x: f64, y: f64, z: f64| {
+            queue
+                .borrow_mut()
+                .push(ScriptCommand::MoveTo(Point3::new(x, y, z)));
+        });
+        let queue = Rc::clone(&commands);
+        engine.register_fn("screenshot", move |path: &str| {
+            queue
+                .borrow_mut()
+                .push(ScriptCommand::Screenshot(PathBuf::from(path)));
+        });
+        let queue = Rc::clone(&commands);
+        engine.register_fn("quit", move || {
+            queue.borrow_mut().push(ScriptCommand::Quit);
+        });
+
+        let query_octree = Rc::clone(&octree);
+        engine.register_result_fn("load_octree", move |path: &str| -> ScriptResult<()> {
+            let loaded = data_provider_factory
+                .generate_data_provider(path)
+                .and_then(Octree::from_data_provider)
+                .map_err(|err| format!("Could not open octree '{}': {}", path, err))?;
+            *query_octree.borrow_mut() = Arc::new(loaded);
+            Ok(())
+        });
+        let query_octree = Rc::clone(&octree);
+        engine.register_result_fn(
+            "points_in_box",
+            move |min_x: f64, min_y: f64, min_z: f64, max_x: f64, max_y: f64, max_z: f64| {
+                let aabb = Aabb::new(
+                    Point3::new(min_x, min_y, min_z),
+                    Point3::new(max_x, max_y, max_z),
+                );
+                points_in_box(&query_octree.borrow(), aabb)
+            },
+        );
+        let query_octree = Rc::clone(&octree);
+        engine.register_fn("num_points", move || -> i64 {
+            query_octree.borrow().num_points()
+        });
+        engine.register_fn(
+            "distance",
+            |x1: f64, y1: f64, z1: f64, x2: f64, y2: f64, z2: f64| -> f64 {
+                (Vector3::new(x2, y2, z2) - Vector3::new(x1, y1, z1)).norm()
+            },
+        );
+
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|err| format!("Could not compile {}: {}", path.display(), err))?;
+        let mut scope = Scope::new();
+        engine
+            .consume_ast_with_scope(&mut scope, &ast)
+            .map_err(|err| format!("Could not run {}: {}", path.display(), err))?;
+        Ok(Script {
+            engine,
+            ast,
+            scope,
+            commands,
+            state,
+        })
+    }
+
+    /// Calls the hook 'name' if the script defines it, and returns the commands it gave. Errors of
+    /// the script are printed, so that the viewer keeps running.
+    fn call_hook(
+        &mut self,
+        name: &str,
+        args: impl rhai::FuncArgs,
+        camera_pose: &Isometry3<f64>,
+    ) -> Vec<ScriptCommand> {
+        self.state.borrow_mut().camera_pose = *camera_pose;
+        let result: ScriptResult<Dynamic> =
+            self.engine.call_fn(&mut self.scope, &self.ast, name, args);
+        match result {
+            Ok(_) => (),
+            // Hooks are optional.
+            Err(err) => match *err {
+                EvalAltResult::ErrorFunctionNotFound(ref signature, _)
+                    if signature.starts_with(name) => {}
+                _ => eprintln!("Error in '{}' of the script: {}", name, err),
+            },
+        }
+        self.commands.borrow_mut().drain(..).collect()
+    }
+
+    pub fn on_start(&mut self, camera_pose: &Isometry3<f64>) -> Vec<ScriptCommand> {
+        self.call_hook("on_start", (), camera_pose)
+    }
+
+    pub fn on_frame(&mut self, time_s: f64, camera_pose: &Isometry3<f64>) -> Vec<ScriptCommand> {
+        self.call_hook("on_frame", (time_s,), camera_pose)
+    }
+
+    pub fn on_key(&mut self, key: &str, camera_pose: &Isometry3<f64>) -> Vec<ScriptCommand> {
+        self.call_hook("on_key", (key.to_string(),), camera_pose)
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Layouts that draw the point cloud into several viewports of the window in every frame. All
+//! viewports share the nodes in memory, and the nodes selected for any of them are drawn in all.
+
+use crate::camera::{Camera, ViewDirection};
+use nalgebra::Matrix4;
+use point_viewer::octree::ScreenSpaceError;
+use serde_derive::Deserialize;
+use std::str::FromStr;
+
+/// Distance between the eyes of the stereo layout in meters.
+const EYE_SEPARATION: f64 = 0.065;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// The view of the camera in the whole window.
+    Single,
+    /// Orthographic views from the top, front and side through the position of the camera in the
+    /// top left, top right and bottom left quarter, and the view of the camera in the bottom
+    /// right quarter.
+    Quad,
+    /// The views of the left and the right eye side by side, for cross-eyed viewing or
+    /// side-by-side stereo displays.
+    Stereo,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(Layout::Single),
+            "quad" => Ok(Layout::Quad),
+            "stereo" => Ok(Layout::Stereo),
+            _ => Err(format!(
+                "Unknown layout '{}', use 'single', 'quad' or 'stereo'.",
+                s
+            )),
+        }
+    }
+}
+
+/// A rectangle of the window in pixels from its top left corner, and the transform of its view.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub world_to_gl: Matrix4<f64>,
+}
+
+impl Viewport {
+    /// The metric that selects the nodes for this viewport.
+    pub fn screen_space_error(&self, pixel_threshold: f64) -> ScreenSpaceError {
+        ScreenSpaceError::new(f64::from(self.width), f64::from(self.height))
+            .with_pixel_threshold(pixel_threshold)
+    }
+}
+
+impl Layout {
+    /// The layout that follows this one when cycling through them.
+    pub fn next(self) -> Self {
+        match self {
+            Layout::Single => Layout::Quad,
+            Layout::Quad => Layout::Stereo,
+            Layout::Stereo => Layout::Single,
+        }
+    }
+
+    /// The viewports of a window of the size of 'camera'.
+    pub fn viewports(self, camera: &Camera) -> Vec<Viewport> {
+        let left_width = camera.width / 2;
+        let right_width = camera.width - left_width;
+        let top_height = camera.height / 2;
+        let bottom_height = camera.height - top_height;
+        let orthographic = |x, y, width, height, direction| Viewport {
+            x,
+            y,
+            width,
+            height,
+            world_to_gl: camera.get_orthographic_world_to_gl(direction, width, height),
+        };
+        let eye = |x, width, eye_offset| Viewport {
+            x,
+            y: 0,
+            width,
+            height: camera.height,
+            world_to_gl: camera.get_eye_world_to_gl(width, camera.height, eye_offset),
+        };
+        match self {
+            Layout::Single => vec![Viewport {
+                x: 0,
+                y: 0,
+                width: camera.width,
+                height: camera.height,
+                world_to_gl: camera.get_world_to_gl(),
+            }],
+            Layout::Quad => vec![
+                orthographic(0, 0, left_width, top_height, ViewDirection::Top),
+                orthographic(left_width, 0, right_width, top_height, ViewDirection::Front),
+                orthographic(
+                    0,
+                    top_height,
+                    left_width,
+                    bottom_height,
+                    ViewDirection::Side,
+                ),
+                Viewport {
+                    x: left_width,
+                    y: top_height,
+                    width: right_width,
+                    height: bottom_height,
+                    world_to_gl: camera.get_eye_world_to_gl(right_width, bottom_height, 0.),
+                },
+            ],
+            Layout::Stereo => vec![
+                eye(0, left_width, -EYE_SEPARATION / 2.),
+                eye(left_width, right_width, EYE_SEPARATION / 2.),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_viewports_tile_the_window() {
+        let camera = Camera::new(801, 601, None);
+        for layout in &[Layout::Single, Layout::Quad, Layout::Stereo] {
+            let viewports = layout.viewports(&camera);
+            let area: i32 = viewports.iter().map(|v| v.width * v.height).sum();
+            assert_eq!(801 * 601, area);
+            assert!(viewports
+                .iter()
+                .all(|v| v.x + v.width <= 801 && v.y + v.height <= 601));
+        }
+        // An eye without offset sees what the camera sees.
+        assert_eq!(
+            camera.get_world_to_gl(),
+            camera.get_eye_world_to_gl(801, 601, 0.)
+        );
+    }
+}
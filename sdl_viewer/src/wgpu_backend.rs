@@ -0,0 +1,543 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Draws the point cloud with wgpu, which runs on Vulkan, Metal and DirectX 12. The environment
+//! variable WGPU_BACKEND, e.g. 'vulkan' or 'metal', picks one of them.
+
+use crate::backend::{GpuNode, PointCloudBackend};
+use crate::box_drawer::{box_transform, BOX_LINE_INDICES, BOX_VERTICES};
+use crate::node_drawer::ColorMap;
+use crate::viewport::Viewport;
+use nalgebra::Matrix4;
+use point_viewer::color::{Color, ColorSpace};
+use point_viewer::geometry::{Aabb, Cube};
+use point_viewer::octree;
+use point_viewer::read_write::PositionEncoding;
+use std::rc::Rc;
+use wgpu::util::DeviceExt;
+
+const POINTS_SHADER: &str = include_str!("../shaders/points.wgsl");
+const OUTLINE_SHADER: &str = include_str!("../shaders/box_drawer_outline.wgsl");
+
+// The colors are written as they are, like into the default framebuffer of OpenGL.
+const COLOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Bgra8Unorm;
+const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// Maps the depth of OpenGL clip space, -1 to 1, to that of wgpu, 0 to 1.
+#[rustfmt::skip]
+fn wgpu_from_gl() -> Matrix4<f64> {
+    Matrix4::new(
+        1., 0., 0., 0.,
+        0., 1., 0., 0.,
+        0., 0., 0.5, 0.5,
+        0., 0., 0., 1.,
+    )
+}
+
+fn to_bytes(values: &[f32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// The uniforms of a node or an outline, see the shaders: the transform as a column-major matrix
+/// followed by further values of 4 bytes each.
+fn uniform_bytes(transform: &Matrix4<f64>, values: &[[u8; 4]]) -> Vec<u8> {
+    let transform: Matrix4<f32> = nalgebra::convert(*transform);
+    let mut data = to_bytes(transform.as_slice());
+    data.extend(values.iter().flatten());
+    data
+}
+
+/// How the points of all nodes are colored, see 'ColorMap'.
+#[derive(Clone, Copy)]
+struct PointColors {
+    gamma: f32,
+    linear_colors: bool,
+    color_map: ColorMap,
+    height_range: (f32, f32),
+}
+
+fn node_uniform_bytes(transform: &Matrix4<f64>, cube: &Cube, colors: &PointColors) -> Vec<u8> {
+    let color_map: u32 = match colors.color_map {
+        ColorMap::Rgb => 0,
+        ColorMap::Height => 1,
+    };
+    uniform_bytes(
+        transform,
+        &[
+            colors.gamma.to_le_bytes(),
+            u32::from(colors.linear_colors).to_le_bytes(),
+            color_map.to_le_bytes(),
+            (cube.min().z as f32).to_le_bytes(),
+            (cube.edge_length() as f32).to_le_bytes(),
+            colors.height_range.0.to_le_bytes(),
+            colors.height_range.1.to_le_bytes(),
+            [0; 4],
+        ],
+    )
+}
+
+/// The positions of 'node_data' relative to its bounding cube, as three floats per point.
+fn relative_positions(node_data: &octree::NodeData) -> Vec<f32> {
+    let position = &node_data.position;
+    match node_data.meta.position_encoding {
+        PositionEncoding::Uint8 => position.iter().map(|p| f32::from(*p) / 255.).collect(),
+        PositionEncoding::Uint16 => position
+            .chunks_exact(2)
+            .map(|p| f32::from(u16::from_le_bytes([p[0], p[1]])) / 65535.)
+            .collect(),
+        PositionEncoding::Float32 => position
+            .chunks_exact(4)
+            .map(|p| f32::from_le_bytes([p[0], p[1], p[2], p[3]]))
+            .collect(),
+        PositionEncoding::Float64 => position
+            .chunks_exact(8)
+            .map(|p| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(p);
+                f64::from_le_bytes(bytes) as f32
+            })
+            .collect(),
+        PositionEncoding::Uint24 | PositionEncoding::Delta => {
+            unreachable!("Octree::get_node_data transcodes these to Float32.")
+        }
+    }
+}
+
+struct NodeBuffers {
+    position: wgpu::Buffer,
+    color: wgpu::Buffer,
+    num_points: u32,
+}
+
+/// A step of a frame. The steps are recorded until 'end_frame' runs them in one render pass.
+enum Draw {
+    /// The rectangle of the window that the following steps draw into, as x, y, width and height
+    /// in pixels from the top left corner.
+    Viewport([f32; 4]),
+    /// The points of a node with the uniforms of this draw, since a node is drawn once per
+    /// viewport.
+    Node(Rc<NodeBuffers>, wgpu::BindGroup),
+    Outline(wgpu::BindGroup),
+}
+
+pub struct WgpuNode {
+    meta: octree::NodeMeta,
+    // Shared with the frame that draws the node.
+    buffers: Rc<NodeBuffers>,
+    used_memory_bytes: usize,
+}
+
+impl GpuNode for WgpuNode {
+    fn meta(&self) -> &octree::NodeMeta {
+        &self.meta
+    }
+
+    fn used_memory_bytes(&self) -> usize {
+        self.used_memory_bytes
+    }
+}
+
+pub struct WgpuBackend {
+    surface: wgpu::Surface,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    swap_chain_descriptor: wgpu::SwapChainDescriptor,
+    swap_chain: wgpu::SwapChain,
+    depth_view: wgpu::TextureView,
+    uniform_layout: wgpu::BindGroupLayout,
+    points_pipeline: wgpu::RenderPipeline,
+    outline_pipeline: wgpu::RenderPipeline,
+    outline_vertices: wgpu::Buffer,
+    outline_indices: wgpu::Buffer,
+    colors: PointColors,
+    world_to_gl: Matrix4<f64>,
+    // What the current frame draws, if a frame was begun.
+    frame: Option<wgpu::SwapChainFrame>,
+    draws: Vec<Draw>,
+}
+
+fn create_depth_view(device: &wgpu::Device, width: u32, height: u32) -> wgpu::TextureView {
+    device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: Some("depth"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    uniform_layout: &wgpu::BindGroupLayout,
+    shader: &str,
+    vertex_buffers: &[wgpu::VertexBufferLayout],
+    topology: wgpu::PrimitiveTopology,
+) -> wgpu::RenderPipeline {
+    let module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+        label: None,
+        source: wgpu::ShaderSource::Wgsl(shader.into()),
+        flags: wgpu::ShaderFlags::all(),
+    });
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: None,
+        bind_group_layouts: &[uniform_layout],
+        push_constant_ranges: &[],
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: None,
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: &module,
+            entry_point: "vs_main",
+            buffers: vertex_buffers,
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &module,
+            entry_point: "fs_main",
+            targets: &[wgpu::ColorTargetState {
+                format: COLOR_FORMAT,
+                blend: None,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology,
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+    })
+}
+
+impl WgpuBackend {
+    /// Draws into 'window' of 'width' x 'height' pixels. Panics if no graphics adapter supports
+    /// wgpu.
+    pub fn new(
+        window: &sdl2::video::Window,
+        width: u32,
+        height: u32,
+        color_space: ColorSpace,
+    ) -> Self {
+        let instance = wgpu::Instance::new(
+            wgpu::util::backend_bits_from_env().unwrap_or(wgpu::BackendBit::PRIMARY),
+        );
+        let surface = unsafe { instance.create_surface(window) };
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: Some(&surface),
+        }))
+        .expect("No graphics adapter supports wgpu.");
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                features: wgpu::Features::empty(),
+                limits: wgpu::Limits::default(),
+            },
+            None,
+        ))
+        .expect("Could not open the graphics device.");
+        eprintln!("Drawing with wgpu on {:?}.", adapter.get_info());
+
+        let swap_chain_descriptor = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+            format: COLOR_FORMAT,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+        let swap_chain = device.create_swap_chain(&surface, &swap_chain_descriptor);
+        let depth_view = create_depth_view(&device, width, height);
+
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let points_pipeline = create_pipeline(
+            &device,
+            &uniform_layout,
+            POINTS_SHADER,
+            &[
+                wgpu::VertexBufferLayout {
+                    array_stride: 12,
+                    step_mode: wgpu::InputStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float32x3],
+                },
+                wgpu::VertexBufferLayout {
+                    array_stride: 4,
+                    step_mode: wgpu::InputStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![1 => Unorm8x4],
+                },
+            ],
+            wgpu::PrimitiveTopology::PointList,
+        );
+        let outline_pipeline = create_pipeline(
+            &device,
+            &uniform_layout,
+            OUTLINE_SHADER,
+            &[wgpu::VertexBufferLayout {
+                array_stride: 12,
+                step_mode: wgpu::InputStepMode::Vertex,
+                attributes: &wgpu::vertex_attr_array![0 => Float32x3],
+            }],
+            wgpu::PrimitiveTopology::LineList,
+        );
+        let vertices: Vec<f32> = BOX_VERTICES
+            .iter()
+            .flat_map(|vertex| vertex.iter().map(|v| *v as f32))
+            .collect();
+        let outline_vertices = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("outline vertices"),
+            contents: &to_bytes(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let indices: Vec<u8> = BOX_LINE_INDICES
+            .iter()
+            .flatten()
+            .flat_map(|index| (*index as u32).to_le_bytes().to_vec())
+            .collect();
+        let outline_indices = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("outline indices"),
+            contents: &indices,
+            usage: wgpu::BufferUsage::INDEX,
+        });
+
+        WgpuBackend {
+            surface,
+            device,
+            queue,
+            swap_chain_descriptor,
+            swap_chain,
+            depth_view,
+            uniform_layout,
+            points_pipeline,
+            outline_pipeline,
+            outline_vertices,
+            outline_indices,
+            colors: PointColors {
+                gamma: 1.,
+                linear_colors: color_space == ColorSpace::Linear,
+                color_map: ColorMap::Rgb,
+                height_range: (0., 1.),
+            },
+            world_to_gl: Matrix4::identity(),
+            frame: None,
+            draws: Vec::new(),
+        }
+    }
+
+    /// A bind group of a new uniform buffer holding 'contents'.
+    fn uniforms(&self, label: &str, contents: &[u8]) -> wgpu::BindGroup {
+        let buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage: wgpu::BufferUsage::UNIFORM,
+            });
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.uniform_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        })
+    }
+}
+
+impl PointCloudBackend for WgpuBackend {
+    type Node = WgpuNode;
+
+    fn upload_node(&self, node_data: octree::NodeData) -> WgpuNode {
+        let position = to_bytes(&relative_positions(&node_data));
+        // Vertex attributes are at least 4 bytes large.
+        let color: Vec<u8> = node_data
+            .color
+            .chunks_exact(3)
+            .flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 255])
+            .collect();
+        let create_buffer = |label, contents: &[u8], usage| {
+            self.device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(label),
+                    contents,
+                    usage,
+                })
+        };
+        WgpuNode {
+            used_memory_bytes: position.len() + color.len(),
+            buffers: Rc::new(NodeBuffers {
+                position: create_buffer("node positions", &position, wgpu::BufferUsage::VERTEX),
+                color: create_buffer("node colors", &color, wgpu::BufferUsage::VERTEX),
+                num_points: node_data.meta.num_points as u32,
+            }),
+            meta: node_data.meta,
+        }
+    }
+
+    fn set_viewport_size(&mut self, width: i32, height: i32) {
+        // Minimized windows have no size, and swap chains need one.
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        self.swap_chain_descriptor.width = width as u32;
+        self.swap_chain_descriptor.height = height as u32;
+        self.swap_chain = self
+            .device
+            .create_swap_chain(&self.surface, &self.swap_chain_descriptor);
+        self.depth_view = create_depth_view(&self.device, width as u32, height as u32);
+    }
+
+    fn set_viewport(&mut self, viewport: &Viewport) {
+        self.world_to_gl = viewport.world_to_gl;
+        self.draws.push(Draw::Viewport([
+            viewport.x as f32,
+            viewport.y as f32,
+            viewport.width as f32,
+            viewport.height as f32,
+        ]));
+    }
+
+    fn set_color_map(&mut self, color_map: ColorMap, min_height: f64, max_height: f64) {
+        self.colors.color_map = color_map;
+        self.colors.height_range = (min_height as f32, max_height as f32);
+    }
+
+    fn begin_frame(&mut self) {
+        self.draws.clear();
+        self.frame = match self.swap_chain.get_current_frame() {
+            Ok(frame) => Some(frame),
+            Err(err) => {
+                eprintln!("Could not get the next frame: {:?}", err);
+                None
+            }
+        };
+    }
+
+    fn draw_node(&mut self, node: &WgpuNode, _point_size: f32, gamma: f32) -> i64 {
+        let cube = &node.meta.bounding_cube;
+        let transform = wgpu_from_gl()
+            * self.world_to_gl
+            * Matrix4::new_translation(&cube.min().coords)
+            * Matrix4::new_scaling(cube.edge_length());
+        self.colors.gamma = gamma;
+        let bind_group = self.uniforms(
+            "node uniforms",
+            &node_uniform_bytes(&transform, cube, &self.colors),
+        );
+        self.draws
+            .push(Draw::Node(Rc::clone(&node.buffers), bind_group));
+        node.meta.num_points
+    }
+
+    fn draw_outline(&mut self, aabb: &Aabb, color: &Color<f32>) {
+        let transform = wgpu_from_gl() * self.world_to_gl * box_transform(aabb);
+        let bind_group = self.uniforms(
+            "outline uniforms",
+            &uniform_bytes(
+                &transform,
+                &[
+                    color.red.to_le_bytes(),
+                    color.green.to_le_bytes(),
+                    color.blue.to_le_bytes(),
+                    color.alpha.to_le_bytes(),
+                ],
+            ),
+        );
+        self.draws.push(Draw::Outline(bind_group));
+    }
+
+    fn end_frame(&mut self) {
+        let frame = match self.frame.take() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &frame.output.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            for draw in &self.draws {
+                match draw {
+                    Draw::Viewport([x, y, width, height]) => {
+                        pass.set_viewport(*x, *y, *width, *height, 0., 1.)
+                    }
+                    Draw::Node(node, bind_group) => {
+                        pass.set_pipeline(&self.points_pipeline);
+                        pass.set_bind_group(0, bind_group, &[]);
+                        pass.set_vertex_buffer(0, node.position.slice(..));
+                        pass.set_vertex_buffer(1, node.color.slice(..));
+                        pass.draw(0..node.num_points, 0..1);
+                    }
+                    Draw::Outline(bind_group) => {
+                        pass.set_pipeline(&self.outline_pipeline);
+                        pass.set_bind_group(0, bind_group, &[]);
+                        pass.set_vertex_buffer(0, self.outline_vertices.slice(..));
+                        pass.set_index_buffer(
+                            self.outline_indices.slice(..),
+                            wgpu::IndexFormat::Uint32,
+                        );
+                        pass.draw_indexed(0..(BOX_LINE_INDICES.len() * 2) as u32, 0, 0..1);
+                    }
+                }
+            }
+        }
+        self.queue.submit(std::iter::once(encoder.finish()));
+        // Dropping the frame presents it.
+    }
+}
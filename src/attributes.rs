@@ -1,4 +1,4 @@
-use crate::errors::{ErrorKind, Result};
+use crate::errors::{Error, Result};
 use nalgebra::Vector3;
 use std::convert::TryFrom;
 
@@ -53,9 +53,9 @@ impl AttributeDataType {
             proto::AttributeDataType::U8Vec3 => AttributeDataType::U8Vec3,
             proto::AttributeDataType::F64Vec3 => AttributeDataType::F64Vec3,
             proto::AttributeDataType::INVALID_DATA_TYPE => {
-                return Err(
-                    ErrorKind::InvalidInput("Attribute data type invalid".to_string()).into(),
-                )
+                return Err(Error::InvalidInput(
+                    "Attribute data type invalid".to_string(),
+                ))
             }
         };
         Ok(attr)
@@ -71,6 +71,14 @@ impl AttributeDataType {
             AttributeDataType::F64Vec3 => 3 * 8,
         }
     }
+
+    /// Number of components of a value, e.g. 3 for a color.
+    pub fn dim(self) -> usize {
+        match self {
+            AttributeDataType::U8Vec3 | AttributeDataType::F64Vec3 => 3,
+            _ => 1,
+        }
+    }
 }
 
 /// General field to describe point feature attributes such as color, intensity, ...
@@ -132,6 +140,23 @@ macro_rules! match_1d_attr_data {
 }
 
 impl AttributeData {
+    pub fn with_capacity(data_type: AttributeDataType, capacity: usize) -> Self {
+        match data_type {
+            AttributeDataType::U8 => AttributeData::U8(Vec::with_capacity(capacity)),
+            AttributeDataType::U16 => AttributeData::U16(Vec::with_capacity(capacity)),
+            AttributeDataType::U32 => AttributeData::U32(Vec::with_capacity(capacity)),
+            AttributeDataType::U64 => AttributeData::U64(Vec::with_capacity(capacity)),
+            AttributeDataType::I8 => AttributeData::I8(Vec::with_capacity(capacity)),
+            AttributeDataType::I16 => AttributeData::I16(Vec::with_capacity(capacity)),
+            AttributeDataType::I32 => AttributeData::I32(Vec::with_capacity(capacity)),
+            AttributeDataType::I64 => AttributeData::I64(Vec::with_capacity(capacity)),
+            AttributeDataType::F32 => AttributeData::F32(Vec::with_capacity(capacity)),
+            AttributeDataType::F64 => AttributeData::F64(Vec::with_capacity(capacity)),
+            AttributeDataType::U8Vec3 => AttributeData::U8Vec3(Vec::with_capacity(capacity)),
+            AttributeDataType::F64Vec3 => AttributeData::F64Vec3(Vec::with_capacity(capacity)),
+        }
+    }
+
     pub fn len(&self) -> usize {
         macro_rules! rhs {
             ($dtype:ident, $data:ident) => {
@@ -195,6 +220,16 @@ impl AttributeData {
         Ok(())
     }
 
+    /// Removes all values, keeping the allocated memory.
+    pub fn clear(&mut self) {
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident) => {
+                $data.clear()
+            };
+        }
+        match_attr_data!(self, rhs)
+    }
+
     pub fn split_off(&mut self, at: usize) -> Self {
         macro_rules! rhs {
             ($dtype:ident, $data:ident, $at:expr) => {
@@ -212,6 +247,149 @@ impl AttributeData {
         }
         match_attr_data!(self, rhs, idx)
     }
+
+    /// Converts the values of a scalar attribute to 'data_type' with 'as'. This is exact if
+    /// 'ValueRange::narrower_data_type' chose 'data_type' for the values.
+    pub fn cast(&self, data_type: AttributeDataType) -> std::result::Result<Self, String> {
+        macro_rules! cast_to {
+            ($data:ident) => {
+                match data_type {
+                    AttributeDataType::U8 => {
+                        AttributeData::U8($data.iter().map(|v| *v as u8).collect())
+                    }
+                    AttributeDataType::U16 => {
+                        AttributeData::U16($data.iter().map(|v| *v as u16).collect())
+                    }
+                    AttributeDataType::U32 => {
+                        AttributeData::U32($data.iter().map(|v| *v as u32).collect())
+                    }
+                    AttributeDataType::U64 => {
+                        AttributeData::U64($data.iter().map(|v| *v as u64).collect())
+                    }
+                    AttributeDataType::I8 => {
+                        AttributeData::I8($data.iter().map(|v| *v as i8).collect())
+                    }
+                    AttributeDataType::I16 => {
+                        AttributeData::I16($data.iter().map(|v| *v as i16).collect())
+                    }
+                    AttributeDataType::I32 => {
+                        AttributeData::I32($data.iter().map(|v| *v as i32).collect())
+                    }
+                    AttributeDataType::I64 => {
+                        AttributeData::I64($data.iter().map(|v| *v as i64).collect())
+                    }
+                    AttributeDataType::F32 => {
+                        AttributeData::F32($data.iter().map(|v| *v as f32).collect())
+                    }
+                    AttributeDataType::F64 => {
+                        AttributeData::F64($data.iter().map(|v| *v as f64).collect())
+                    }
+                    AttributeDataType::U8Vec3 | AttributeDataType::F64Vec3 => {
+                        return Err(format!("Cannot cast to data type '{:?}'.", data_type))
+                    }
+                }
+            };
+        }
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident) => {
+                cast_to!($data)
+            };
+        }
+        match self {
+            AttributeData::U8Vec3(_) | AttributeData::F64Vec3(_) => {
+                Err(format!("Cannot cast data type '{:?}'.", self.data_type()))
+            }
+            _ => Ok(match_1d_attr_data!(self, rhs)),
+        }
+    }
+}
+
+/// The range of the values of a scalar attribute. Vendor exports often store small integers such
+/// as classes or return numbers as 'F64' or 'I64', and the range tells which smaller data type
+/// holds them exactly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ValueRange {
+    pub min: f64,
+    pub max: f64,
+    /// Whether all values are integers.
+    pub integral: bool,
+}
+
+impl ValueRange {
+    /// The range of the values of 'data', or None if it has none or is a vector attribute.
+    pub fn of(data: &AttributeData) -> Option<Self> {
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident) => {
+                $data.iter().fold(None, |range: Option<ValueRange>, v| {
+                    let v = *v as f64;
+                    let single = ValueRange {
+                        min: v,
+                        max: v,
+                        // Also false for NaN and infinite values.
+                        integral: v.fract() == 0.,
+                    };
+                    Some(range.map_or(single, |range| range.merge(single)))
+                })
+            };
+        }
+        match data {
+            AttributeData::U8Vec3(_) | AttributeData::F64Vec3(_) => None,
+            _ => match_1d_attr_data!(data, rhs),
+        }
+    }
+
+    pub fn merge(self, other: Self) -> Self {
+        ValueRange {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            integral: self.integral && other.integral,
+        }
+    }
+
+    /// The smallest integer data type that holds all values exactly, if it takes fewer bytes
+    /// than 'data_type'.
+    pub fn narrower_data_type(&self, data_type: AttributeDataType) -> Option<AttributeDataType> {
+        if !self.integral {
+            return None;
+        }
+        let candidates = [
+            (
+                AttributeDataType::U8,
+                f64::from(u8::min_value()),
+                f64::from(u8::max_value()),
+            ),
+            (
+                AttributeDataType::I8,
+                f64::from(i8::min_value()),
+                f64::from(i8::max_value()),
+            ),
+            (
+                AttributeDataType::U16,
+                f64::from(u16::min_value()),
+                f64::from(u16::max_value()),
+            ),
+            (
+                AttributeDataType::I16,
+                f64::from(i16::min_value()),
+                f64::from(i16::max_value()),
+            ),
+            (
+                AttributeDataType::U32,
+                f64::from(u32::min_value()),
+                f64::from(u32::max_value()),
+            ),
+            (
+                AttributeDataType::I32,
+                f64::from(i32::min_value()),
+                f64::from(i32::max_value()),
+            ),
+        ];
+        candidates
+            .iter()
+            .find(|(_, min, max)| *min <= self.min && self.max <= *max)
+            .map(|(narrower, _, _)| *narrower)
+            .filter(|narrower| narrower.size_of() < data_type.size_of())
+    }
 }
 
 macro_rules! try_from_impl {
@@ -270,3 +448,31 @@ try_from_attribute_data!(F32, f32);
 try_from_attribute_data!(F64, f64);
 try_from_attribute_data!(U8Vec3, Vector3<u8>);
 try_from_attribute_data!(F64Vec3, Vector3<f64>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_narrow_attribute() {
+        let classes = AttributeData::F64(vec![2., 0., 7., 255.]);
+        let range = ValueRange::of(&classes).unwrap();
+        assert_eq!(
+            Some(AttributeDataType::U8),
+            range.narrower_data_type(AttributeDataType::F64)
+        );
+        let narrowed = classes.cast(AttributeDataType::U8).unwrap();
+        assert_eq!(vec![2u8, 0, 7, 255], Vec::<u8>::try_from(narrowed).unwrap());
+
+        let offsets = ValueRange::of(&AttributeData::I64(vec![-300, 12])).unwrap();
+        assert_eq!(
+            Some(AttributeDataType::I16),
+            offsets.narrower_data_type(AttributeDataType::I64)
+        );
+        // Narrowing must save space.
+        assert_eq!(None, offsets.narrower_data_type(AttributeDataType::I16));
+        let fractions = ValueRange::of(&AttributeData::F32(vec![0.5, 1.])).unwrap();
+        assert_eq!(None, fractions.narrower_data_type(AttributeDataType::F32));
+        assert_eq!(None, ValueRange::of(&AttributeData::F64(Vec::new())));
+    }
+}
@@ -0,0 +1,226 @@
+//! Adapters between iterators of single 'Point's and of 'PointsBatch'es. Both reuse their
+//! buffers: 'Batched' refills the batch passed to 'next_into' and 'Unbatched' keeps a single
+//! batch that it refills from its source.
+//!
+//! A 'Point' has a color and optionally an intensity, and these are the only attributes that
+//! survive a round trip. The alpha channel of the colors is not kept, and other attributes of
+//! the batches are dropped when unbatching.
+
+use crate::attributes::{AttributeData, AttributeDataType};
+use crate::color::{self, Color};
+use crate::{NextInto, NumberOfPoints, Point, PointsBatch};
+use nalgebra::Vector3;
+use std::iter::Peekable;
+
+/// Collects the points of 'inner' into batches of up to 'batch_size' points with a "color"
+/// attribute and, if the points have an intensity, an "intensity" attribute. A batch ends early
+/// where the points start or stop having an intensity, so that no intensity is lost.
+pub struct Batched<I>
+where
+    I: Iterator<Item = Point>,
+{
+    inner: Peekable<I>,
+    batch_size: usize,
+}
+
+impl<I> Batched<I>
+where
+    I: Iterator<Item = Point>,
+{
+    pub fn new(inner: I, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "Batches must hold at least one point.");
+        Batched {
+            inner: inner.peekable(),
+            batch_size,
+        }
+    }
+}
+
+impl<I> NumberOfPoints for Batched<I>
+where
+    I: Iterator<Item = Point>,
+{
+    /// Requires 'inner' to know its length.
+    fn num_points(&self) -> usize {
+        self.inner.size_hint().1.unwrap()
+    }
+}
+
+impl<I> Iterator for Batched<I>
+where
+    I: Iterator<Item = Point>,
+{
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = PointsBatch::default();
+        if self.next_into(&mut batch) {
+            Some(batch)
+        } else {
+            None
+        }
+    }
+}
+
+impl<I> NextInto for Batched<I>
+where
+    I: Iterator<Item = Point>,
+{
+    fn next_into(&mut self, batch: &mut PointsBatch) -> bool {
+        let with_intensity = match self.inner.peek() {
+            Some(point) => point.intensity.is_some(),
+            None => return false,
+        };
+        batch.reset(std::iter::once(("color", AttributeDataType::U8Vec3)).chain(
+            if with_intensity {
+                Some(("intensity", AttributeDataType::F32))
+            } else {
+                None
+            },
+        ));
+        let mut color = None;
+        let mut intensity = None;
+        for (name, data) in batch.attributes.iter_mut() {
+            match (name.as_str(), data) {
+                ("color", AttributeData::U8Vec3(data)) => color = Some(data),
+                ("intensity", AttributeData::F32(data)) => intensity = Some(data),
+                _ => (),
+            }
+        }
+        let color = color.unwrap();
+        while batch.position.len() < self.batch_size {
+            let point = match self.inner.peek() {
+                Some(point) if point.intensity.is_some() == with_intensity => {
+                    self.inner.next().unwrap()
+                }
+                _ => break,
+            };
+            batch.position.push(point.position);
+            color.push(Vector3::new(
+                point.color.red,
+                point.color.green,
+                point.color.blue,
+            ));
+            if let (Some(intensity), Some(value)) = (intensity.as_mut(), point.intensity) {
+                intensity.push(value);
+            }
+        }
+        true
+    }
+}
+
+/// Yields the points of the batches of 'inner' one by one. Points of batches without a "color"
+/// attribute are white, and all points are opaque.
+pub struct Unbatched<I>
+where
+    I: NextInto,
+{
+    inner: I,
+    batch: PointsBatch,
+    index: usize,
+}
+
+impl<I> Unbatched<I>
+where
+    I: NextInto,
+{
+    pub fn new(inner: I) -> Self {
+        Unbatched {
+            inner,
+            batch: PointsBatch::default(),
+            index: 0,
+        }
+    }
+
+    fn point(&self, index: usize) -> Point {
+        let color = match self.batch.get_attribute_vec::<Vector3<u8>>("color") {
+            Ok(color) => Color {
+                red: color[index].x,
+                green: color[index].y,
+                blue: color[index].z,
+                alpha: 255,
+            },
+            Err(_) => color::WHITE.to_u8(),
+        };
+        let intensity = self
+            .batch
+            .get_attribute_vec::<f32>("intensity")
+            .ok()
+            .map(|intensity| intensity[index]);
+        Point {
+            position: self.batch.position[index],
+            color,
+            intensity,
+        }
+    }
+}
+
+impl<I> Iterator for Unbatched<I>
+where
+    I: NextInto,
+{
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        while self.index == self.batch.position.len() {
+            if !self.inner.next_into(&mut self.batch) {
+                return None;
+            }
+            self.index = 0;
+        }
+        let point = self.point(self.index);
+        self.index += 1;
+        Some(point)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.batch.position.len() - self.index, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Point3;
+
+    fn point(i: usize, intensity: Option<f32>) -> Point {
+        Point {
+            position: Point3::new(i as f64, -(i as f64), 0.5),
+            color: Color {
+                red: i as u8,
+                green: 1,
+                blue: 2,
+                alpha: 255,
+            },
+            intensity,
+        }
+    }
+
+    #[test]
+    fn test_batched_round_trip() {
+        let points: Vec<Point> = (0..10)
+            .map(|i| point(i, if i < 7 { Some(i as f32) } else { None }))
+            .collect();
+        let mut batched = Batched::new(points.clone().into_iter(), 3);
+        let mut batch = PointsBatch::default();
+        let mut sizes = Vec::new();
+        while batched.next_into(&mut batch) {
+            sizes.push(batch.position.len());
+            assert_eq!(
+                batch.attributes.contains_key("intensity"),
+                batch.position[0].x < 7.
+            );
+        }
+        // The batch ends early where the intensities stop.
+        assert_eq!(sizes, vec![3, 3, 1, 3]);
+
+        let unbatched: Vec<Point> =
+            Unbatched::new(Batched::new(points.clone().into_iter(), 3)).collect();
+        assert_eq!(unbatched.len(), points.len());
+        for (actual, expected) in unbatched.iter().zip(&points) {
+            assert_eq!(actual.position, expected.position);
+            assert_eq!(actual.color, expected.color);
+            assert_eq!(actual.intensity, expected.intensity);
+        }
+    }
+}
@@ -13,41 +13,500 @@
 // limitations under the License.
 
 use clap::Clap;
-use point_viewer::octree::build_octree_from_file;
+use log::warn;
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+use point_viewer::classify::{Classifier, SubprocessClassifier};
+use point_viewer::color::ColorSpace;
+use point_viewer::colorize::{ColorSource, Colorizer, Projection};
+use point_viewer::intensity::IntensityNormalization;
+use point_viewer::octree::{
+    build_octree_from_files, estimate_build, find_principal_frame, BakedColors, BuildOptions,
+    InputFile, MultiFileIterator, NodeLimits, PointMerging, Subdivision, UpAxis,
+};
+use point_viewer::read_write::{set_sync_on_close, PlyIterator};
+use point_viewer::utils::init_logging;
+use point_viewer::NUM_POINTS_PER_BATCH;
 use rayon::ThreadPoolBuilder;
-use std::path::PathBuf;
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
 #[derive(Clap, Debug)]
 #[clap(name = "build_octree")]
 struct CommandlineArguments {
-    /// PLY/PTS file to parse for the points.
-    #[clap(parse(from_os_str))]
-    input: PathBuf,
+    /// PLY files to parse for the points. Glob patterns like 'scans/*.ply' are expanded.
+    /// All files are combined into a single octree.
+    #[clap(required = true, min_values = 1)]
+    inputs: Vec<String>,
 
-    /// Output directory to write the octree into.
+    /// Output directory to write the octree into. Required unless '--dry-run' is given.
     #[clap(long, parse(from_os_str))]
-    output_directory: PathBuf,
+    output_directory: Option<PathBuf>,
 
     /// Minimal precision that this point cloud should have.
     /// This decides on the number of bits used to encode each node.
     #[clap(long, default_value = "0.001")]
     resolution: f64,
 
-    /// The number of threads used to shard octree building. Set this as high as possible for SSDs.
-    #[clap(long, default_value = "10")]
-    num_threads: usize,
+    /// The number of threads used to shard octree building. Defaults to the number of CPUs.
+    /// Independent subtrees are built in parallel, so this should be as high as the disks allow.
+    #[clap(long)]
+    num_threads: Option<usize>,
+
+    /// Upper bound for the number of points held in memory by all threads together. A point takes
+    /// roughly 60 bytes while being processed.
+    #[clap(long, default_value = "50000000")]
+    max_points_in_memory: usize,
+
+    /// Number of batches per node being split that wait to be written to disk while the next
+    /// batch is partitioned. Increase this if the disks are slow to respond.
+    #[clap(long, default_value = "8")]
+    write_queue_len: usize,
+
+    /// Seed for the random subsampling of the points into the parent nodes. Building the same
+    /// inputs with the same seed gives identical octrees.
+    #[clap(long, default_value = "0")]
+    seed: u64,
+
+    /// Do not store checksums of the node files, which saves reading the octree once more at the
+    /// end of the build. Reads then cannot detect corrupted nodes.
+    #[clap(long)]
+    no_checksums: bool,
+
+    /// Coordinate reference system of the input positions, e.g. 'EPSG:25832'. It is recorded in
+    /// the meta data, e.g. for dataset catalogs.
+    #[clap(long)]
+    crs: Option<String>,
+
+    /// Store the index of the input file of every point as the attribute 'source_id', and the
+    /// paths of the input files in the meta data.
+    #[clap(long)]
+    source_ids: bool,
+
+    /// Build the octree in a frame aligned to the data instead of the frame of the inputs, which
+    /// fits elongated datasets like corridors much better. Either 'auto' to align the x axis with
+    /// the direction in which the points extend the most, or the pose of the frame as
+    /// 'x,y,z,qx,qy,qz,qw'. The pose is recorded in the meta data.
+    #[clap(long)]
+    frame: Option<FrameArgument>,
+
+    /// Split nodes only in x and y, so that every node spans the whole height of the point cloud.
+    /// This gives fewer and shallower nodes for airborne data, which is far wider than it is high.
+    #[clap(long)]
+    quadtree: bool,
+
+    /// Store a kd-tree of the points of every node next to its files, which speeds up nearest
+    /// neighbor and radius queries inside nodes. It takes 5 bytes per point.
+    #[clap(long)]
+    kd_index: bool,
+
+    /// Store the inputs as frames of a capture over time, e.g. of a construction site, which the
+    /// web viewer can play back. Either 'files' to make every input file a frame, in the order in
+    /// which the files are given and globs expand, or the capture time of every input file as
+    /// 't1,t2,...', where files with the same time form one frame. Points store the index of
+    /// their frame as 'frame_index'.
+    #[clap(long)]
+    timestamps: Option<TimestampsArgument>,
+
+    /// Replace the colors of the coarse levels by a pseudo-color, so that viewers that only draw
+    /// RGB show meaningful overviews. Given as '<intensity|height>:<max level>', e.g. 'height:4'
+    /// colors the nodes of the levels 0 to 4 by their height.
+    #[clap(long)]
+    bake_colors: Option<BakedColors>,
+
+    /// Color space of the colors in the input files, 'srgb' or 'linear'. Linear colors, e.g. from
+    /// photogrammetry or HDR pipelines, look washed out when they are shown as sRGB.
+    #[clap(long, default_value = "srgb")]
+    input_color_space: ColorSpace,
+
+    /// Color space to store the colors in, 'srgb' or 'linear'. The inputs are converted to it,
+    /// and it is recorded in the meta data, so that viewers convert the colors for display.
+    #[clap(long, default_value = "srgb")]
+    color_space: ColorSpace,
+
+    /// The axis that points up in the input files, 'z' or 'y'. Data from modeling tools is often
+    /// Y-up and looks sideways unless it is rotated.
+    #[clap(long, default_value = "z")]
+    input_up_axis: UpAxis,
+
+    /// The axis that points up in the octree, 'z' or 'y'. The inputs are rotated to it, and it is
+    /// recorded in the view settings, so that viewers turn the camera around it.
+    #[clap(long, default_value = "z")]
+    up_axis: UpAxis,
+
+    /// Tolerate common violations of the PLY format in the inputs, like CRLF line endings,
+    /// unknown header lines or a wrong vertex count, instead of failing. What was fixed is logged
+    /// for every file.
+    #[clap(long)]
+    lenient_ply: bool,
+
+    /// Merge points that are closer than a tolerance, e.g. where overlapping scan passes
+    /// multiply the density. Given as '<tolerance>[:<first|average>]', e.g. '0.005:average'
+    /// averages the positions, colors and floating point attributes of the merged points, while
+    /// 'first' keeps the first point.
+    #[clap(long)]
+    merge_points: Option<PointMerging>,
+
+    /// Store the normals of the inputs, their PLY properties 'nx', 'ny' and 'nz', and bound them
+    /// per node, so that viewers can skip nodes that face away from the camera. All inputs need
+    /// normals.
+    #[clap(long)]
+    normals: bool,
+
+    /// Store the PLY property 'curvature' of the inputs, which the SDL viewer uses to shade edges
+    /// and corners together with the normals. All inputs need curvatures.
+    #[clap(long)]
+    curvature: bool,
+
+    /// Read the inputs in batches of about this many megabytes instead of a fixed number of
+    /// points, which keeps the memory per batch predictable for points with many attributes.
+    #[clap(long)]
+    max_batch_mb: Option<usize>,
+
+    /// The number of points from which nodes are split, per level starting at the root, e.g.
+    /// '100001,100001,400000'. Deeper levels use the last value. Higher values for the deep
+    /// levels keep sparse areas from growing as deep as dense ones.
+    #[clap(long, use_delimiter = true)]
+    min_points_to_split: Vec<i64>,
+
+    /// Record which parts of every node contain points, so that queries and viewers skip the
+    /// empty space inside nodes, e.g. above the ground in airborne data.
+    #[clap(long)]
+    occupancy: bool,
+
+    /// Also write 'preview.ply' into the octree directory, a binary PLY file with at most this
+    /// many points of the whole dataset, e.g. 2000000, for catalogs and quick checks without the
+    /// streaming viewers.
+    #[clap(long)]
+    preview_points: Option<usize>,
+
+    /// Sync every written file to disk before it is closed, so the octree survives a power loss
+    /// once the build finished. Slows down the build.
+    #[clap(long)]
+    fsync: bool,
+
+    /// Also store the intensity normalized to [0, 1] per input file as 'normalized_intensity'.
+    /// Either 'clip:<low>,<high>' to clip to these percentiles, e.g. 'clip:1,99', or 'equalize'
+    /// for histogram equalization.
+    #[clap(long)]
+    intensity_normalization: Option<IntensityNormalization>,
+
+    /// Optional JSON file mapping input files to the transform into the octree frame, e.g.
+    /// {"scans/a.ply": {"translation": [10.0, 0.0, 0.0], "rotation": [0.0, 0.0, 0.0, 1.0]}}.
+    /// The rotation is a quaternion given as [x, y, z, w]. Files can be referred to by their path
+    /// as given on the command line or by their file name. Every file in it must be an input.
+    #[clap(long, parse(from_os_str))]
+    transforms: Option<PathBuf>,
+
+    /// Optional JSON file listing georeferenced images to color the points from, e.g.
+    /// [{"image": "cam/0001.jpg", "projection": {"type": "equirectangular"},
+    /// "translation": [1.0, 2.0, 0.5], "rotation": [0.0, 0.0, 0.0, 1.0]}]. The transform is the
+    /// pose of the camera in the octree frame. Pinhole cameras are given as
+    /// {"type": "pinhole", "fx": ..., "fy": ..., "cx": ..., "cy": ...} in pixels.
+    #[clap(long, parse(from_os_str))]
+    colorize: Option<PathBuf>,
+
+    /// Command of an external classifier, e.g. 'python3 classify_ground.py model.onnx'. Its
+    /// classes are stored as 'classification'. See the README for the protocol.
+    #[clap(long)]
+    classifier: Option<String>,
+
+    /// Only scan the inputs and print the bounding box, number of points, suggested resolution
+    /// and estimates of the output size and build time, without building anything.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Transform {
+    #[serde(default)]
+    translation: [f64; 3],
+    #[serde(default = "identity_rotation")]
+    rotation: [f64; 4],
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ProjectionConfig {
+    Pinhole { fx: f64, fy: f64, cx: f64, cy: f64 },
+    Equirectangular,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageConfig {
+    image: PathBuf,
+    projection: ProjectionConfig,
+    #[serde(flatten)]
+    world_from_camera: Transform,
+}
+
+#[derive(Debug)]
+enum FrameArgument {
+    Auto,
+    Pose(Isometry3<f64>),
+}
+
+impl FromStr for FrameArgument {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(FrameArgument::Auto);
+        }
+        let v = s
+            .split(',')
+            .map(|n| n.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| format!("Could not parse frame '{}': {}", s, e))?;
+        if v.len() != 7 {
+            return Err("The frame must be 'auto' or 'x,y,z,qx,qy,qz,qw'.".to_string());
+        }
+        let transform = Transform {
+            translation: [v[0], v[1], v[2]],
+            rotation: [v[3], v[4], v[5], v[6]],
+        };
+        Ok(FrameArgument::Pose(transform.to_isometry()))
+    }
+}
+
+#[derive(Debug)]
+enum TimestampsArgument {
+    Files,
+    Values(Vec<f64>),
+}
+
+impl FromStr for TimestampsArgument {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "files" {
+            return Ok(TimestampsArgument::Files);
+        }
+        s.split(',')
+            .map(|n| n.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map(TimestampsArgument::Values)
+            .map_err(|e| format!("Could not parse timestamps '{}': {}", s, e))
+    }
+}
+
+fn identity_rotation() -> [f64; 4] {
+    [0., 0., 0., 1.]
+}
+
+impl Transform {
+    fn to_isometry(&self) -> Isometry3<f64> {
+        let [x, y, z, w] = self.rotation;
+        Isometry3::from_parts(
+            Translation3::new(
+                self.translation[0],
+                self.translation[1],
+                self.translation[2],
+            ),
+            UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)),
+        )
+    }
+}
+
+fn expand_globs(inputs: &[String]) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for input in inputs {
+        if Path::new(input).exists() {
+            paths.push(PathBuf::from(input));
+            continue;
+        }
+        let matches: Vec<PathBuf> = glob::glob(input)
+            .unwrap_or_else(|e| panic!("Invalid glob pattern '{}': {}", input, e))
+            .filter_map(|entry| entry.ok())
+            .collect();
+        if matches.is_empty() {
+            panic!("No input files found for '{}'.", input);
+        }
+        paths.extend(matches);
+    }
+    paths
+}
+
+fn read_transforms(path: &Path) -> HashMap<String, Transform> {
+    let file =
+        File::open(path).unwrap_or_else(|e| panic!("Could not open {}: {}", path.display(), e));
+    serde_json::from_reader(BufReader::new(file))
+        .unwrap_or_else(|e| panic!("Could not parse {}: {}", path.display(), e))
+}
+
+fn read_colorizer(path: &Path) -> Colorizer {
+    let file =
+        File::open(path).unwrap_or_else(|e| panic!("Could not open {}: {}", path.display(), e));
+    let images: Vec<ImageConfig> = serde_json::from_reader(BufReader::new(file))
+        .unwrap_or_else(|e| panic!("Could not parse {}: {}", path.display(), e));
+    let sources = images
+        .iter()
+        .map(|image| {
+            let projection = match image.projection {
+                ProjectionConfig::Pinhole { fx, fy, cx, cy } => {
+                    Projection::Pinhole { fx, fy, cx, cy }
+                }
+                ProjectionConfig::Equirectangular => Projection::Equirectangular,
+            };
+            ColorSource::from_file(
+                &image.image,
+                projection,
+                image.world_from_camera.to_isometry(),
+            )
+            .unwrap_or_else(|e| panic!("{}", e))
+        })
+        .collect();
+    Colorizer::new(sources)
 }
 
 fn main() {
+    init_logging();
     let args = CommandlineArguments::parse();
+    let num_threads = args.num_threads.unwrap_or_else(num_cpus::get);
     ThreadPoolBuilder::new()
-        .num_threads(args.num_threads)
+        .num_threads(num_threads)
         .build_global()
         .expect("Could not create thread pool.");
-    build_octree_from_file(
-        args.output_directory,
+
+    let transforms = args
+        .transforms
+        .as_ref()
+        .map(|path| read_transforms(path))
+        .unwrap_or_default();
+    let mut matched_keys = HashSet::new();
+    let inputs: Vec<InputFile> = expand_globs(&args.inputs)
+        .into_iter()
+        .map(|path| {
+            let file_name = path.file_name().map(|f| f.to_string_lossy().into_owned());
+            let transform = transforms
+                .get_key_value(&*path.to_string_lossy())
+                .or_else(|| file_name.and_then(|f| transforms.get_key_value(&f)));
+            let mut input = InputFile::new(path);
+            if let Some((key, transform)) = transform {
+                matched_keys.insert(key.clone());
+                input.octree_from_file = transform.to_isometry();
+            }
+            input.lenient = args.lenient_ply;
+            input.color_space = args.input_color_space;
+            input.up_axis = args.input_up_axis;
+            input
+        })
+        .collect();
+    // A key that matches no input is most likely a typo, which would silently leave the input
+    // untransformed.
+    let mut unmatched_keys: Vec<&str> = transforms
+        .keys()
+        .filter(|key| !matched_keys.contains(*key))
+        .map(String::as_str)
+        .collect();
+    if !unmatched_keys.is_empty() {
+        unmatched_keys.sort();
+        panic!(
+            "The transforms of {} match no input: {}",
+            args.transforms.as_ref().unwrap().display(),
+            unmatched_keys.join(", ")
+        );
+    }
+    if args.lenient_ply {
+        for input in &inputs {
+            let stream = PlyIterator::from_file_lenient(&input.path, NUM_POINTS_PER_BATCH)
+                .unwrap_or_else(|e| panic!("Could not open {}: {}", input.path.display(), e));
+            for repair in stream.repairs() {
+                warn!("{}: {}", input.path.display(), repair);
+            }
+        }
+    }
+
+    set_sync_on_close(args.fsync);
+
+    if args.dry_run {
+        let estimate =
+            estimate_build(&inputs, args.resolution, num_threads).expect("Could not scan inputs.");
+        println!("{}", estimate);
+        return;
+    }
+
+    let colorizer = args
+        .colorize
+        .as_ref()
+        .map(|path| Arc::new(read_colorizer(path)));
+    let classifier = args.classifier.as_ref().map(|command| {
+        let mut words = command.split_whitespace();
+        let program = words.next().expect("'--classifier' is empty.");
+        let args: Vec<&str> = words.collect();
+        let classifier =
+            SubprocessClassifier::new(program, &args).unwrap_or_else(|e| panic!("{}", e));
+        Arc::new(Mutex::new(classifier)) as Arc<Mutex<dyn Classifier>>
+    });
+    let output_directory = args
+        .output_directory
+        .expect("'--output-directory' is required unless '--dry-run' is given.");
+    let world_from_octree = args.frame.map(|frame| match frame {
+        FrameArgument::Auto => find_principal_frame(
+            MultiFileIterator::new(&inputs, NUM_POINTS_PER_BATCH)
+                .expect("Could not open inputs.")
+                .with_up_axis(args.up_axis),
+        ),
+        FrameArgument::Pose(world_from_octree) => world_from_octree,
+    });
+    let frame_timestamps = args.timestamps.map(|timestamps| match timestamps {
+        TimestampsArgument::Files => (0..inputs.len()).map(|i| i as f64).collect(),
+        TimestampsArgument::Values(values) => {
+            assert_eq!(
+                values.len(),
+                inputs.len(),
+                "'--timestamps' needs one time per input file."
+            );
+            values
+        }
+    });
+    build_octree_from_files(
+        output_directory,
         args.resolution,
-        args.input,
+        &inputs,
         &["color", "intensity"],
+        &BuildOptions {
+            max_points_in_memory: args.max_points_in_memory,
+            write_queue_len: args.write_queue_len,
+            seed: args.seed,
+            intensity_normalization: args.intensity_normalization,
+            colorizer,
+            classifier,
+            checksums: !args.no_checksums,
+            crs: args.crs,
+            source_ids: args.source_ids,
+            world_from_octree,
+            subdivision: if args.quadtree {
+                Subdivision::Quadtree
+            } else {
+                Subdivision::Octree
+            },
+            kd_index: args.kd_index,
+            frame_timestamps,
+            baked_colors: args.bake_colors,
+            max_batch_bytes: args.max_batch_mb.map(|mb| mb * 1024 * 1024),
+            color_space: args.color_space,
+            up_axis: args.up_axis,
+            merge_points: args.merge_points,
+            normals: args.normals,
+            curvature: args.curvature,
+            node_limits: if args.min_points_to_split.is_empty() {
+                vec![NodeLimits::default()]
+            } else {
+                args.min_points_to_split
+                    .iter()
+                    .map(|min_points_to_split| NodeLimits {
+                        min_points_to_split: *min_points_to_split,
+                        capacity: None,
+                    })
+                    .collect()
+            },
+            occupancy: args.occupancy,
+            preview_points: args.preview_points,
+        },
     );
 }
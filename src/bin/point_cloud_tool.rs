@@ -0,0 +1,1623 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::Clap;
+use nalgebra::{Isometry3, Point2, Point3};
+use point_viewer::data_provider::{DataProvider, OnDiskDataProvider, VersionedDataProvider};
+use point_viewer::errors::*;
+use point_viewer::fitting::{fit_in_query, Plane, Primitive, RansacOptions, VerticalCylinder};
+use point_viewer::frame_capture::{read_frame_capture, replay_frame_capture};
+use point_viewer::geometry::{Aabb, Corridor, Polygon, Sphere};
+use point_viewer::iterator::{PointCloud, PointLocation, PointQuery};
+use point_viewer::math::ClosedInterval;
+use point_viewer::octree::{
+    compact_octree, enable_versioning, export_tiles, extract_contours, is_versioned,
+    rasterize_octree, read_version_log, reencode_octree, render_overview, set_view_settings,
+    trim_octree, write_contours, ColorMode, ContourFormat, NodeSchema, Octree, OverviewKind,
+    RasterOptions, RasterStatistic, ReencodeOptions, SnapshotOptions, TileExportOptions,
+    TileFormat, UpAxis, ViewSettings,
+};
+use point_viewer::profile::extract_profile;
+use point_viewer::read_write::{
+    morton_sort, AttributeCompression, ColumnCodec, CsvNodeWriter, Encoding, LasNodeWriter,
+    MortonSortOptions, NodeWriter, OpenMode, PlyIterator, PlyNodeWriter, PositionEncoding,
+    TextFormat, TextIterator,
+};
+use point_viewer::registration::{align_query, IcpMethod, IcpOptions, Reference};
+use point_viewer::report::{read_report_views, write_report, ReportOptions};
+use point_viewer::session_log::{read_session_log, summarize_sessions};
+use point_viewer::sink::{MultiSink, QuerySink, WriterSink};
+use point_viewer::stats::PointStats;
+use point_viewer::utils::{create_progress_bar, init_logging, parse_key_val, parse_pose};
+use point_viewer::{PointsBatch, NUM_POINTS_PER_BATCH};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clap, Debug)]
+#[clap(
+    name = "point_cloud_tool",
+    about = "Utilities for working with point clouds."
+)]
+struct CommandlineArguments {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Clap, Debug)]
+enum Command {
+    /// Writes the points of an octree inside a region into a PLY, CSV or LAS file.
+    Extract(ExtractArguments),
+    /// Writes all levels of detail of an octree as 3D Tiles or Potree tiles, so that other
+    /// viewers stream them like the octree.
+    ExportTiles(ExportTilesArguments),
+    /// Prints the bounding box and per-attribute min, max, mean, standard deviation and histogram
+    /// of PLY files or octrees.
+    Stats(StatsArguments),
+    /// Rewrites the nodes of an octree with a different position encoding or fewer attributes.
+    Reencode(ReencodeArguments),
+    /// Writes the nodes of an octree inside a region into a new, smaller octree, clipping the
+    /// nodes on its boundary, e.g. to ship a subset of a large octree without rebuilding it.
+    Trim(TrimArguments),
+    /// Removes orphaned node files and empty subtrees of an octree and rewrites nodes whose files
+    /// are larger than needed, in place.
+    Compact(CompactArguments),
+    /// Checks all node files of an octree against the checksums in its meta data.
+    Verify(VerifyArguments),
+    /// Prints the schema stored with the files of a node and optionally writes its points to a
+    /// PLY, CSV or LAS file, without needing the meta data of the octree.
+    Node(NodeArguments),
+    /// Prints the source id and path of every input file of an octree built with
+    /// '--source-ids'.
+    Sources(SourcesArguments),
+    /// Writes a GeoTIFF with the minimum, maximum or mean height of the points per grid cell, e.g.
+    /// a digital surface or elevation model.
+    Rasterize(RasterizeArguments),
+    /// Writes a top-down density or elevation PNG with a world file, rendered from the meta data
+    /// of the nodes without reading points, e.g. as an overview for catalogs.
+    Overview(OverviewArguments),
+    /// Renders labeled views of an octree from camera poses or of annotated places, and writes
+    /// them with a summary of its extent, size and attributes as an HTML report, e.g. to document
+    /// a delivery.
+    Report(ReportArguments),
+    /// Writes contour lines of the minimum, maximum or mean height per grid cell as GeoJSON or DXF.
+    Contour(ContourArguments),
+    /// Writes the points of an octree in a corridor around a polyline to a PLY or CSV file, with
+    /// their 'station' along the polyline and their 'offset' from it, e.g. for cross sections of
+    /// roads.
+    Profile(ProfileArguments),
+    /// Fits a plane, sphere or vertical cylinder to the points of an octree inside a box with
+    /// RANSAC, prints it and optionally writes its inliers to a PLY or CSV file.
+    Fit(FitArguments),
+    /// Aligns the points of an octree to a reference octree with ICP and prints the transform
+    /// that maps them onto the reference.
+    Register(RegisterArguments),
+    /// Reorders the points of a PLY file along a Morton curve, in bounded memory, so that nearby
+    /// points are stored together. Building an octree from the sorted file is much faster.
+    Sort(SortArguments),
+    /// Converts a PTS, CSV or XYZ text file to PLY, CSV or LAS, e.g. to build an octree from it.
+    /// Lines that cannot be parsed are skipped and reported.
+    ImportText(ImportTextArguments),
+    /// Prints the versions of an octree, or starts versioning it.
+    Versions(VersionsArguments),
+    /// Replays a capture of the nodes that a viewer streamed, see 'sdl_viewer --record-frames',
+    /// and prints the state of the streaming and its problems frame by frame.
+    ReplayFrames(ReplayFramesArguments),
+    /// Sums up session logs of the viewers, see 'sdl_viewer --session-log': how long the sessions
+    /// took and how much of it was navigation, and how often every dataset was opened and every
+    /// feature was used.
+    SessionSummary(SessionSummaryArguments),
+    /// Prints or changes the settings that viewers start with when they open an octree: the
+    /// camera, the up axis, the color mode and the point budget.
+    ViewSettings(ViewSettingsArguments),
+}
+
+/// The region of the points to work on. Without any of its options, all points are used.
+#[derive(Clap, Debug)]
+struct RegionArguments {
+    /// The points inside this box, given as 'min_x,min_y,min_z,max_x,max_y,max_z'.
+    #[clap(long, parse(try_from_str), conflicts_with_all = &["sphere", "polygon"])]
+    bbox: Option<Numbers>,
+
+    /// The points inside this sphere, given as 'x,y,z,radius'.
+    #[clap(long, parse(try_from_str), conflicts_with = "polygon")]
+    sphere: Option<Numbers>,
+
+    /// The points above and below this polygon in the x-y plane, given as
+    /// 'x1,y1,x2,y2,x3,y3,...'. Use '--min-z' and '--max-z' to limit the height.
+    #[clap(long, parse(try_from_str))]
+    polygon: Option<Numbers>,
+
+    /// Lower z bound for '--polygon'.
+    #[clap(long, default_value = "-inf")]
+    min_z: f64,
+
+    /// Upper z bound for '--polygon'.
+    #[clap(long, default_value = "inf")]
+    max_z: f64,
+}
+
+#[derive(Clap, Debug)]
+struct ExtractArguments {
+    /// Directory of the octree to extract points from.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Output file. The format is derived from the extension unless '--format' is given.
+    #[clap(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Output format, one of 'ply', 'csv' or 'las'.
+    #[clap(long)]
+    format: Option<OutputFormat>,
+
+    #[clap(flatten)]
+    region: RegionArguments,
+
+    /// Only keep points whose attribute lies in an interval, e.g. '--filter intensity=2.0,51.0'.
+    /// Can be given multiple times.
+    #[clap(long, parse(try_from_str = parse_key_val), number_of_values = 1)]
+    filter: Vec<(String, ClosedInterval<f64>)>,
+
+    /// Only extract nodes up to this level of detail. The root node has level 0.
+    #[clap(long)]
+    max_lod: Option<u8>,
+
+    /// Attributes to extract besides the position.
+    #[clap(long, default_value = "color,intensity", use_delimiter = true)]
+    attributes: Vec<String>,
+
+    /// Extract from this version of a versioned octree instead of the current one.
+    #[clap(long)]
+    as_of_version: Option<u64>,
+
+    /// Also print the bounding box and per-attribute min, max, mean and standard deviation of the
+    /// extracted points, computed in the same pass.
+    #[clap(long)]
+    stats: bool,
+}
+
+#[derive(Clap, Debug)]
+struct StatsArguments {
+    /// PLY files or octree directories. Statistics are computed over all of them together.
+    #[clap(parse(from_os_str), required = true, min_values = 1)]
+    inputs: Vec<PathBuf>,
+
+    /// Number of histogram bins per attribute.
+    #[clap(long, default_value = "10")]
+    num_bins: usize,
+
+    /// Attributes to read from octrees. PLY files always provide all their attributes.
+    #[clap(long, default_value = "color,intensity", use_delimiter = true)]
+    attributes: Vec<String>,
+}
+
+#[derive(Clap, Debug)]
+struct TrimArguments {
+    /// Directory of the octree to trim.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Directory to write the trimmed octree into. Must not be the input directory.
+    #[clap(parse(from_os_str))]
+    output_directory: PathBuf,
+
+    #[clap(flatten)]
+    region: RegionArguments,
+}
+
+#[derive(Clap, Debug)]
+struct ReencodeArguments {
+    /// Directory of the octree to re-encode.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Directory to write the re-encoded octree into. Must not be the input directory.
+    #[clap(parse(from_os_str))]
+    output_directory: PathBuf,
+
+    /// Position encoding for all nodes, one of 'uint8', 'uint16', 'uint24', 'float32', 'float64'
+    /// or 'delta'. 'uint24' has the precision of 'float32' in three bytes, 'delta' stores the
+    /// differences between consecutive points and is smallest for dense data.
+    #[clap(long, parse(try_from_str = parse_position_encoding), conflicts_with = "resolution")]
+    position_encoding: Option<PositionEncoding>,
+
+    /// Choose the position encoding of each node to achieve this resolution.
+    #[clap(long)]
+    resolution: Option<f64>,
+
+    /// Only keep these attributes. 'color' is always required.
+    #[clap(long, use_delimiter = true)]
+    attributes: Option<Vec<String>>,
+
+    /// Losslessly compress the attribute columns of all nodes, choosing the smallest codec for
+    /// every block.
+    #[clap(long, conflicts_with = "uncompress-attributes")]
+    compress_attributes: bool,
+
+    /// Store the attribute columns of all nodes uncompressed.
+    #[clap(long)]
+    uncompress_attributes: bool,
+
+    /// Always use this codec for an attribute, e.g. '--attribute-codec intensity=delta'. Codecs
+    /// are 'raw', 'rle', 'bitpacked' and 'delta'. Implies '--compress-attributes' and can be
+    /// given multiple times.
+    #[clap(
+        long,
+        parse(try_from_str = parse_attribute_codec),
+        number_of_values = 1,
+        conflicts_with = "uncompress-attributes"
+    )]
+    attribute_codec: Vec<(String, ColumnCodec)>,
+
+    /// Store attributes whose values are all small integers, e.g. classes exported as doubles,
+    /// in the smallest integer type that holds them. The former types are kept in the meta data.
+    #[clap(long)]
+    narrow_attributes: bool,
+}
+
+#[derive(Clap, Debug)]
+struct CompactArguments {
+    /// Directory of the octree to compact.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+}
+
+#[derive(Clap, Debug)]
+struct VerifyArguments {
+    /// Directory of the octree to verify.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+}
+
+#[derive(Clap, Debug)]
+struct SourcesArguments {
+    /// Directory of the octree.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+}
+
+#[derive(Clap, Debug)]
+struct RasterizeArguments {
+    /// Directory of the octree to rasterize.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Output GeoTIFF file.
+    #[clap(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Edge length of the grid cells in the units of the octree.
+    #[clap(long)]
+    cell_size: f64,
+
+    /// Height per cell, one of 'min' for the lowest point, 'max' for the highest point, i.e. a
+    /// surface model, or 'mean'.
+    #[clap(long, default_value = "max")]
+    statistic: RasterStatistic,
+
+    /// Only rasterize the points inside this box, given as
+    /// 'min_x,min_y,min_z,max_x,max_y,max_z'. Defaults to the whole octree.
+    #[clap(long, parse(try_from_str))]
+    bbox: Option<Numbers>,
+
+    /// Only read nodes up to this level of detail. Defaults to the level needed for the cell
+    /// size.
+    #[clap(long)]
+    max_lod: Option<u8>,
+
+    /// EPSG code of the projected coordinate system of the octree, e.g. 25832 for UTM zone 32N.
+    #[clap(long)]
+    epsg: Option<u16>,
+}
+
+#[derive(Clap, Debug)]
+struct ExportTilesArguments {
+    /// Directory of the octree to export.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Output directory.
+    #[clap(parse(from_os_str))]
+    output_directory: PathBuf,
+
+    /// Tile format, one of '3dtiles' for a 'tileset.json' with '.pnts' tiles or 'potree' for
+    /// Potree 1.7 with LAS tiles.
+    #[clap(long, default_value = "3dtiles")]
+    format: TileFormat,
+
+    /// Only export nodes up to this level of detail. The root node has level 0.
+    #[clap(long)]
+    max_lod: Option<u8>,
+}
+
+#[derive(Clap, Debug)]
+struct OverviewArguments {
+    /// Directory of the octree to render.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Output PNG file. The world file is written next to it with the extension 'pgw'.
+    #[clap(parse(from_os_str))]
+    output: PathBuf,
+
+    /// What to show, one of 'density' for the number of points per area or 'elevation'.
+    #[clap(long, default_value = "density")]
+    kind: OverviewKind,
+
+    /// Number of pixels along the longer edge of the image.
+    #[clap(long, default_value = "1024")]
+    size: u32,
+}
+
+#[derive(Clap, Debug)]
+struct ReportArguments {
+    /// Directory of the octree to report on.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// JSON file with a list of views. Every view has a 'label' and either a 'camera' pose as
+    /// 'x,y,z,qx,qy,qz,qw' or the 'position' [x, y, z] of an annotated place, which is seen from
+    /// an optional 'distance'.
+    #[clap(parse(from_os_str))]
+    views: PathBuf,
+
+    /// Directory to write 'index.html' and the images of the views into.
+    #[clap(parse(from_os_str))]
+    output_directory: PathBuf,
+
+    /// Title of the report.
+    #[clap(long, default_value = "Point cloud report")]
+    title: String,
+
+    /// Width of the images in pixels.
+    #[clap(long, default_value = "1280")]
+    width: u32,
+
+    /// Height of the images in pixels.
+    #[clap(long, default_value = "720")]
+    height: u32,
+
+    /// Edge length of the square that a point covers, in pixels.
+    #[clap(long, default_value = "2")]
+    point_size: u32,
+
+    /// Attributes to add statistics of to the summary, e.g. 'color,intensity'. This reads all
+    /// points of the octree.
+    #[clap(long, use_delimiter = true)]
+    stats_attributes: Vec<String>,
+}
+
+#[derive(Clap, Debug)]
+struct ContourArguments {
+    /// Directory of the octree to trace contour lines in.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Output file. The format is derived from the extension unless '--format' is given.
+    #[clap(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Output format, one of 'geojson' or 'dxf'.
+    #[clap(long)]
+    format: Option<ContourFormat>,
+
+    /// Height difference between contour lines. Lines are at multiples of it.
+    #[clap(long)]
+    interval: f64,
+
+    /// Edge length of the grid cells the heights are sampled in, in the units of the octree.
+    #[clap(long)]
+    cell_size: f64,
+
+    /// Height per cell, one of 'min' for the lowest point, which approximates the terrain,
+    /// 'max' for the highest point or 'mean'.
+    #[clap(long, default_value = "min")]
+    statistic: RasterStatistic,
+
+    /// Only trace contour lines inside this box, given as
+    /// 'min_x,min_y,min_z,max_x,max_y,max_z'. Defaults to the whole octree.
+    #[clap(long, parse(try_from_str))]
+    bbox: Option<Numbers>,
+
+    /// Only read nodes up to this level of detail. Defaults to the level needed for the cell
+    /// size.
+    #[clap(long)]
+    max_lod: Option<u8>,
+}
+
+#[derive(Clap, Debug)]
+struct ProfileArguments {
+    /// Directory of the octree to extract points from.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Output file. The format is derived from the extension unless '--format' is given.
+    #[clap(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Output format, one of 'ply' or 'csv'.
+    #[clap(long)]
+    format: Option<OutputFormat>,
+
+    /// Polyline in the x-y plane that the corridor follows and the stations are measured along,
+    /// given as 'x1,y1,x2,y2,...'. Two points across a road give a cross section, its center line
+    /// a longitudinal profile.
+    #[clap(long, parse(try_from_str))]
+    polyline: Numbers,
+
+    /// Maximum horizontal distance of the points from the polyline.
+    #[clap(long)]
+    half_width: f64,
+
+    /// Lower z bound of the corridor.
+    #[clap(long, default_value = "-inf")]
+    min_z: f64,
+
+    /// Upper z bound of the corridor.
+    #[clap(long, default_value = "inf")]
+    max_z: f64,
+
+    /// Attributes to extract besides the position and the profile coordinates.
+    #[clap(long, default_value = "color,intensity", use_delimiter = true)]
+    attributes: Vec<String>,
+}
+
+#[derive(Clap, Debug)]
+struct FitArguments {
+    /// Directory of the octree to fit a primitive in.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// The primitive to fit, one of 'plane', 'sphere' or 'cylinder'. Cylinders are vertical.
+    #[clap(long, default_value = "plane")]
+    primitive: PrimitiveKind,
+
+    /// Only use the points inside this box, given as 'min_x,min_y,min_z,max_x,max_y,max_z'.
+    /// Defaults to the whole octree, which needs to fit into memory.
+    #[clap(long, parse(try_from_str))]
+    bbox: Option<Numbers>,
+
+    /// Points at most this far from the primitive are inliers.
+    #[clap(long, default_value = "0.05")]
+    threshold: f64,
+
+    /// Number of random samples to try.
+    #[clap(long, default_value = "1000")]
+    iterations: usize,
+
+    /// Seed of the random sampling.
+    #[clap(long, default_value = "0")]
+    seed: u64,
+
+    /// Write the inliers to this file. The format is derived from the extension.
+    #[clap(long, parse(from_os_str))]
+    inliers: Option<PathBuf>,
+
+    /// Attributes to write with the inliers besides the position.
+    #[clap(long, default_value = "color,intensity", use_delimiter = true)]
+    attributes: Vec<String>,
+}
+
+#[derive(Clap, Debug)]
+struct RegisterArguments {
+    /// Directory of the octree to align.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Directory of the octree to align to.
+    #[clap(parse(from_os_str))]
+    reference_directory: PathBuf,
+
+    /// Only use the points of both octrees inside this box, given as
+    /// 'min_x,min_y,min_z,max_x,max_y,max_z'. Defaults to the whole octrees, which need to fit
+    /// into memory.
+    #[clap(long, parse(try_from_str))]
+    bbox: Option<Numbers>,
+
+    /// One of 'point-to-plane' or 'point-to-point'.
+    #[clap(long, default_value = "point-to-plane")]
+    method: IcpMethod,
+
+    /// Points farther than this from the reference have no correspondence. Must be larger than
+    /// the misalignment.
+    #[clap(long, default_value = "1")]
+    max_distance: f64,
+
+    /// Radius of the neighborhoods that the normals of the reference are estimated from.
+    #[clap(long, default_value = "0.5")]
+    normal_radius: f64,
+
+    /// Maximum number of ICP iterations.
+    #[clap(long, default_value = "30")]
+    iterations: usize,
+}
+
+#[derive(Clap, Debug)]
+struct SortArguments {
+    /// PLY file to sort.
+    #[clap(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Output file. The format is derived from the extension unless '--format' is given.
+    #[clap(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Output format, one of 'ply', 'csv' or 'las'.
+    #[clap(long)]
+    format: Option<OutputFormat>,
+
+    /// The number of points sorted in memory at once. Larger inputs are sorted in runs that are
+    /// written to temporary files and merged. A point takes roughly 60 bytes.
+    #[clap(long, default_value = "50000000")]
+    max_points_in_memory: usize,
+
+    /// Directory for the temporary files. Defaults to the directory of the output.
+    #[clap(long, parse(from_os_str))]
+    temp_directory: Option<PathBuf>,
+}
+
+#[derive(Clap, Debug)]
+struct ImportTextArguments {
+    /// Text file with one point per line. Its format is derived from the extension, one of 'pts',
+    /// 'csv', 'xyz' or 'txt', and changed by the options below.
+    #[clap(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Output file. The format is derived from the extension unless '--format' is given.
+    #[clap(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Output format, one of 'ply', 'csv' or 'las'.
+    #[clap(long)]
+    format: Option<OutputFormat>,
+
+    /// The character between columns, or 'space' or 'tab' to separate them by whitespace.
+    #[clap(long)]
+    delimiter: Option<String>,
+
+    /// The decimal separator, e.g. ',' for files from a German locale.
+    #[clap(long)]
+    decimal_separator: Option<char>,
+
+    /// Number of lines at the start of the file to skip.
+    #[clap(long)]
+    skip_lines: Option<usize>,
+
+    /// Names of the columns, e.g. 'x,y,z,intensity,red,green,blue', instead of the header line of
+    /// CSV files. Columns named '-' are skipped.
+    #[clap(long, use_delimiter = true)]
+    columns: Vec<String>,
+}
+
+#[derive(Clap, Debug)]
+struct NodeArguments {
+    /// Path of the node without extension, e.g. 'octree/r04'.
+    #[clap(parse(from_os_str))]
+    node: PathBuf,
+
+    /// Write the points of the node with all its attributes to this file.
+    #[clap(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Output format, one of 'ply', 'csv' or 'las'.
+    #[clap(long)]
+    format: Option<OutputFormat>,
+}
+
+#[derive(Clap, Debug)]
+struct VersionsArguments {
+    /// Directory of the octree.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Start versioning the octree with its current state as version 1. Later compactions keep
+    /// the files they replace, so that every version can still be read.
+    #[clap(long)]
+    init: bool,
+}
+
+#[derive(Clap, Debug)]
+struct ReplayFramesArguments {
+    /// The capture, a JSON lines file.
+    #[clap(parse(from_os_str))]
+    capture: PathBuf,
+
+    /// A local octree to check the node ids against and to select the nodes for every recorded
+    /// camera, e.g. a copy of the one that was viewed.
+    #[clap(long, parse(from_os_str))]
+    octree: Option<PathBuf>,
+
+    /// The pixel threshold that the viewer selected nodes with.
+    #[clap(long, default_value = "1")]
+    pixel_threshold: f64,
+
+    /// Only print the frames with problems.
+    #[clap(long)]
+    issues_only: bool,
+}
+
+#[derive(Clap, Debug)]
+struct SessionSummaryArguments {
+    /// Session logs, JSON lines files. They are summed up together.
+    #[clap(parse(from_os_str), required = true, min_values = 1)]
+    logs: Vec<PathBuf>,
+}
+
+#[derive(Clap, Debug)]
+struct ViewSettingsArguments {
+    /// Directory of the octree.
+    #[clap(parse(from_os_str))]
+    octree_directory: PathBuf,
+
+    /// Pose of the camera in the octree frame as 'x,y,z,qx,qy,qz,qw'. The camera looks along its
+    /// -z axis with its y axis up on screen, like in OpenGL.
+    #[clap(long, parse(try_from_str = parse_pose))]
+    camera: Option<Isometry3<f64>>,
+
+    /// The axis that points up, 'z' or 'y'.
+    #[clap(long)]
+    up_axis: Option<UpAxis>,
+
+    /// What viewers color the points by, 'color' or 'source_id'.
+    #[clap(long)]
+    color_mode: Option<ColorMode>,
+
+    /// The maximum number of points that viewers draw at once.
+    #[clap(long)]
+    point_budget: Option<u64>,
+
+    /// Remove all view settings before applying the other options.
+    #[clap(long)]
+    reset: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PrimitiveKind {
+    Plane,
+    Sphere,
+    Cylinder,
+}
+
+impl FromStr for PrimitiveKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "plane" => Ok(PrimitiveKind::Plane),
+            "sphere" => Ok(PrimitiveKind::Sphere),
+            "cylinder" => Ok(PrimitiveKind::Cylinder),
+            _ => Err(format!("Unknown primitive '{}'.", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Ply,
+    Csv,
+    Las,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "ply" => Ok(OutputFormat::Ply),
+            "csv" => Ok(OutputFormat::Csv),
+            "las" => Ok(OutputFormat::Las),
+            _ => Err(format!("Unknown output format '{}'.", s)),
+        }
+    }
+}
+
+fn parse_position_encoding(s: &str) -> std::result::Result<PositionEncoding, String> {
+    match &s.to_lowercase()[..] {
+        "uint8" => Ok(PositionEncoding::Uint8),
+        "uint16" => Ok(PositionEncoding::Uint16),
+        "uint24" => Ok(PositionEncoding::Uint24),
+        "float32" => Ok(PositionEncoding::Float32),
+        "float64" => Ok(PositionEncoding::Float64),
+        "delta" => Ok(PositionEncoding::Delta),
+        _ => Err(format!("Unknown position encoding '{}'.", s)),
+    }
+}
+
+/// A single character, or 'space' or 'tab' for whitespace, which is None.
+fn parse_delimiter(s: &str) -> std::result::Result<Option<char>, String> {
+    match s {
+        "space" | "tab" => Ok(None),
+        _ => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if !c.is_whitespace() => Ok(Some(c)),
+                _ => Err(format!("The delimiter '{}' is not a single character.", s)),
+            }
+        }
+    }
+}
+
+fn parse_attribute_codec(s: &str) -> std::result::Result<(String, ColumnCodec), String> {
+    let pos = s
+        .find('=')
+        .ok_or_else(|| format!("invalid KEY=value: no `=` found in `{}`", s))?;
+    Ok((s[..pos].to_string(), s[pos + 1..].parse()?))
+}
+
+/// A comma separated list of numbers.
+#[derive(Debug)]
+struct Numbers(Vec<f64>);
+
+impl FromStr for Numbers {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split(',')
+            .map(|v| {
+                v.trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("Could not parse '{}' as a number.", v))
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map(Numbers)
+    }
+}
+
+fn location_from_args(args: &RegionArguments) -> std::result::Result<PointLocation, String> {
+    if let Some(Numbers(bbox)) = &args.bbox {
+        if bbox.len() != 6 {
+            return Err("'--bbox' needs exactly 6 numbers.".to_string());
+        }
+        let min = Point3::new(bbox[0], bbox[1], bbox[2]);
+        let max = Point3::new(bbox[3], bbox[4], bbox[5]);
+        return Ok(PointLocation::Aabb(Aabb::new(min, max)));
+    }
+    if let Some(Numbers(sphere)) = &args.sphere {
+        if sphere.len() != 4 {
+            return Err("'--sphere' needs exactly 4 numbers.".to_string());
+        }
+        let center = Point3::new(sphere[0], sphere[1], sphere[2]);
+        return Ok(PointLocation::Sphere(Sphere::new(center, sphere[3])));
+    }
+    if let Some(Numbers(polygon)) = &args.polygon {
+        if polygon.len() % 2 != 0 {
+            return Err("'--polygon' needs an even number of coordinates.".to_string());
+        }
+        let vertices = polygon
+            .chunks(2)
+            .map(|xy| Point2::new(xy[0], xy[1]))
+            .collect();
+        return Polygon::new(vertices, args.min_z, args.max_z)
+            .map(PointLocation::Polygon)
+            .ok_or_else(|| "'--polygon' needs at least 3 vertices.".to_string());
+    }
+    Ok(PointLocation::AllPoints)
+}
+
+fn output_format(
+    output: &Path,
+    format: Option<OutputFormat>,
+) -> std::result::Result<OutputFormat, String> {
+    if let Some(format) = format {
+        return Ok(format);
+    }
+    output
+        .extension()
+        .and_then(|e| e.to_str())
+        .ok_or_else(|| "Output file has no extension, please pass '--format'.".to_string())
+        .and_then(OutputFormat::from_str)
+}
+
+/// Streams all points matching 'query' in nodes up to 'max_lod' into 'sink'. Returns the number of
+/// points written.
+fn extract(
+    octree: &Octree,
+    query: &PointQuery,
+    max_lod: Option<u8>,
+    sink: &mut dyn QuerySink,
+) -> Result<usize> {
+    let mut num_points = 0;
+    for node_id in octree.nodes_in_location(&query.location) {
+        if max_lod.map_or(false, |max_lod| node_id.level() > max_lod) {
+            continue;
+        }
+        octree.stream_points_for_query_in_node(query, node_id, NUM_POINTS_PER_BATCH, |batch| {
+            num_points += batch.position.len();
+            sink.write(&batch)
+        })?;
+    }
+    sink.finish()?;
+    Ok(num_points)
+}
+
+fn run_extract(args: ExtractArguments) {
+    let location = location_from_args(&args.region).unwrap_or_else(|e| panic!("{}", e));
+    let format = output_format(&args.output, args.format).unwrap_or_else(|e| panic!("{}", e));
+    let query = PointQuery {
+        attributes: args.attributes.iter().map(String::as_str).collect(),
+        location,
+        filter_intervals: args
+            .filter
+            .iter()
+            .map(|(name, interval)| (name.as_str(), *interval))
+            .collect(),
+    };
+    let _lock = OctreeLock::read(&args.octree_directory).expect("Could not lock octree.");
+    let data_provider: Box<dyn DataProvider> = match args.as_of_version {
+        Some(version) => Box::new(
+            VersionedDataProvider::new(args.octree_directory.clone(), version)
+                .unwrap_or_else(|e| panic!("{}", e)),
+        ),
+        None => Box::new(OnDiskDataProvider {
+            directory: args.octree_directory.clone(),
+        }),
+    };
+    let octree = Octree::from_data_provider(data_provider).expect("Could not open octree.");
+
+    // The statistics are computed in the same pass over the points.
+    let mut stats = PointStats::default();
+    let mut sink = match format {
+        OutputFormat::Ply => {
+            MultiSink::new().with_sink(WriterSink::<PlyNodeWriter>::new(&args.output))
+        }
+        OutputFormat::Csv => {
+            MultiSink::new().with_sink(WriterSink::<CsvNodeWriter>::new(&args.output))
+        }
+        OutputFormat::Las => {
+            MultiSink::new().with_sink(WriterSink::<LasNodeWriter>::new(&args.output))
+        }
+    };
+    if args.stats {
+        sink.add_sink(&mut stats);
+    }
+    let num_points =
+        extract(&octree, &query, args.max_lod, &mut sink).expect("Could not extract points.");
+    drop(sink);
+    eprintln!("Wrote {} points to {}.", num_points, args.output.display());
+    if args.stats {
+        print!("{}", stats);
+    }
+}
+
+/// Calls 'func' with all points of 'input', which is either an octree directory or a PLY file.
+fn for_each_batch(
+    input: &Path,
+    attributes: &[&str],
+    mut func: impl FnMut(&PointsBatch),
+) -> Result<()> {
+    if input.is_dir() {
+        let _lock = OctreeLock::read(input)?;
+        let data_provider = OnDiskDataProvider {
+            directory: input.to_path_buf(),
+        };
+        let octree = Octree::from_data_provider(Box::new(data_provider))?;
+        for node_id in octree.nodes_in_location(&PointLocation::AllPoints) {
+            for batch in octree.points_in_node(attributes, node_id, NUM_POINTS_PER_BATCH)? {
+                func(&batch);
+            }
+        }
+    } else {
+        for batch in PlyIterator::from_file(input, NUM_POINTS_PER_BATCH)? {
+            func(&batch);
+        }
+    }
+    Ok(())
+}
+
+fn run_stats(args: StatsArguments) {
+    let attributes: Vec<&str> = args.attributes.iter().map(String::as_str).collect();
+    let mut stats = PointStats::default();
+    for input in &args.inputs {
+        for_each_batch(input, &attributes, |batch| stats.add_batch(batch))
+            .unwrap_or_else(|e| panic!("Could not read {}: {}", input.display(), e));
+    }
+    // The histogram bins depend on min and max, so we need a second pass.
+    stats.init_histograms(args.num_bins);
+    for input in &args.inputs {
+        for_each_batch(input, &attributes, |batch| {
+            stats.add_batch_to_histograms(batch)
+        })
+        .unwrap_or_else(|e| panic!("Could not read {}: {}", input.display(), e));
+    }
+    print!("{}", stats);
+}
+
+fn assert_different_directories(octree_directory: &Path, output_directory: &Path) {
+    let same_directory = match (
+        octree_directory.canonicalize(),
+        output_directory.canonicalize(),
+    ) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => false,
+    };
+    if same_directory {
+        panic!("The output directory must be different from the octree directory.");
+    }
+}
+
+fn run_reencode(args: ReencodeArguments) {
+    assert_different_directories(&args.octree_directory, &args.output_directory);
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let attribute_compression = if args.uncompress_attributes {
+        Some(AttributeCompression::Uncompressed)
+    } else if args.compress_attributes || !args.attribute_codec.is_empty() {
+        Some(AttributeCompression::Compressed(
+            args.attribute_codec.into_iter().collect(),
+        ))
+    } else {
+        None
+    };
+    let options = ReencodeOptions {
+        position_encoding: args.position_encoding,
+        resolution: args.resolution,
+        attributes: args.attributes,
+        attribute_compression,
+        narrow_attributes: args.narrow_attributes,
+    };
+    reencode_octree(&octree, &args.output_directory, &options)
+        .expect("Could not re-encode octree.");
+}
+
+fn run_trim(args: TrimArguments) {
+    assert_different_directories(&args.octree_directory, &args.output_directory);
+    let location = location_from_args(&args.region).unwrap_or_else(|e| panic!("{}", e));
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let stats =
+        trim_octree(&octree, &args.output_directory, &location).expect("Could not trim octree.");
+    eprintln!(
+        "Wrote {} points in {} nodes, {} of them clipped, to {}.",
+        stats.num_points,
+        stats.num_nodes,
+        stats.num_clipped_nodes,
+        args.output_directory.display()
+    );
+}
+
+fn run_compact(args: CompactArguments) {
+    let stats = compact_octree(&args.octree_directory).expect("Could not compact octree.");
+    eprintln!(
+        "Reclaimed {} bytes: rewrote {} nodes, pruned {} empty nodes and removed {} orphaned files.",
+        stats.reclaimed_bytes,
+        stats.num_rewritten_nodes,
+        stats.num_pruned_nodes,
+        stats.num_removed_files
+    );
+}
+
+fn run_verify(args: VerifyArguments) {
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let node_ids = octree.nodes_in_location(&PointLocation::AllPoints);
+    let mut progress_bar = create_progress_bar(node_ids.len(), "Verifying nodes");
+    let (mut num_verified, mut num_corrupt) = (0, 0);
+    for node_id in &node_ids {
+        match octree.verify_node(node_id) {
+            Ok(true) => num_verified += 1,
+            // Nodes without points and nodes of octrees written without checksums.
+            Ok(false) => (),
+            Err(e) => {
+                eprintln!("{}", e);
+                num_corrupt += 1;
+            }
+        }
+        progress_bar.inc();
+    }
+    progress_bar.finish();
+    eprintln!(
+        "Verified {} of {} nodes, {} are corrupt.",
+        num_verified,
+        node_ids.len(),
+        num_corrupt
+    );
+    if num_corrupt > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Opens the octree in 'directory' together with a lock that keeps other processes from changing
+/// it while it is read.
+fn open_octree(directory: PathBuf) -> (Octree, OctreeLock) {
+    let lock = OctreeLock::read(&directory).expect("Could not lock octree.");
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider { directory }))
+        .expect("Could not open octree.");
+    (octree, lock)
+}
+
+fn bounding_box_from_args(bbox: Option<Numbers>) -> Option<Aabb> {
+    bbox.map(|Numbers(bbox)| {
+        if bbox.len() != 6 {
+            panic!("'--bbox' needs exactly 6 numbers.");
+        }
+        Aabb::new(
+            Point3::new(bbox[0], bbox[1], bbox[2]),
+            Point3::new(bbox[3], bbox[4], bbox[5]),
+        )
+    })
+}
+
+fn run_sources(args: SourcesArguments) {
+    let (octree, _lock) = open_octree(args.octree_directory);
+    if octree.sources().is_empty() {
+        eprintln!("The octree was built without source ids.");
+        return;
+    }
+    for (source_id, path) in octree.sources().iter().enumerate() {
+        println!("{}\t{}", source_id, path);
+    }
+}
+
+fn run_rasterize(args: RasterizeArguments) {
+    let bounding_box = bounding_box_from_args(args.bbox);
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let options = RasterOptions {
+        cell_size: args.cell_size,
+        statistic: args.statistic,
+        bounding_box,
+        max_lod: args.max_lod,
+    };
+    let raster = rasterize_octree(&octree, &options).expect("Could not rasterize octree.");
+    raster
+        .write_geotiff(&args.output, args.epsg)
+        .expect("Could not write raster.");
+    eprintln!(
+        "Wrote a {}x{} raster to {}.",
+        raster.width(),
+        raster.height(),
+        args.output.display()
+    );
+}
+
+fn run_export_tiles(args: ExportTilesArguments) {
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let options = TileExportOptions {
+        format: args.format,
+        max_lod: args.max_lod,
+    };
+    let stats =
+        export_tiles(&octree, &args.output_directory, &options).expect("Could not export tiles.");
+    eprintln!(
+        "Wrote {} points in {} tiles to {}.",
+        stats.num_points,
+        stats.num_tiles,
+        args.output_directory.display()
+    );
+}
+
+fn run_overview(args: OverviewArguments) {
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let overview =
+        render_overview(&octree, args.size, args.kind).expect("Could not render overview.");
+    let world_file = overview
+        .write_png(&args.output)
+        .expect("Could not write overview.");
+    eprintln!(
+        "Wrote a {}x{} overview to {} and {}.",
+        overview.image.width(),
+        overview.image.height(),
+        args.output.display(),
+        world_file.display()
+    );
+}
+
+fn run_report(args: ReportArguments) {
+    let views = read_report_views(&args.views).expect("Could not read views.");
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let options = ReportOptions {
+        title: args.title,
+        snapshot: SnapshotOptions {
+            width: args.width,
+            height: args.height,
+            point_size: args.point_size,
+            ..Default::default()
+        },
+        stats_attributes: args.stats_attributes,
+    };
+    let page = write_report(&octree, &views, &args.output_directory, &options)
+        .expect("Could not write report.");
+    eprintln!(
+        "Wrote a report with {} views to {}.",
+        views.len(),
+        page.display()
+    );
+}
+
+fn run_contour(args: ContourArguments) {
+    let format = args
+        .format
+        .or_else(|| {
+            args.output
+                .extension()
+                .and_then(|e| e.to_str())
+                .and_then(|e| e.parse().ok())
+        })
+        .expect("Unknown output file extension, please pass '--format'.");
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let options = RasterOptions {
+        cell_size: args.cell_size,
+        statistic: args.statistic,
+        bounding_box: bounding_box_from_args(args.bbox),
+        max_lod: args.max_lod,
+    };
+    let raster = rasterize_octree(&octree, &options).expect("Could not rasterize octree.");
+    let contours = extract_contours(&raster, args.interval).expect("Could not trace contours.");
+    write_contours(&contours, &args.output, format).expect("Could not write contours.");
+    eprintln!(
+        "Wrote {} contour lines to {}.",
+        contours.len(),
+        args.output.display()
+    );
+}
+
+/// Writes the points of 'octree' in 'corridor' with their profile coordinates with a writer of
+/// type 'W'. Returns the number of points written.
+fn write_profile<W: NodeWriter<PointsBatch>>(
+    octree: &Octree,
+    corridor: &Corridor,
+    attributes: &[&str],
+    output: &Path,
+) -> Result<usize> {
+    let mut writer = W::new(output, Encoding::Plain, OpenMode::Truncate);
+    let mut num_points = 0;
+    extract_profile(octree, corridor, attributes, |batch| {
+        num_points += batch.position.len();
+        writer.write(&batch).map_err(Error::from)
+    })?;
+    writer.finish()?;
+    Ok(num_points)
+}
+
+fn run_profile(args: ProfileArguments) {
+    let Numbers(polyline) = &args.polyline;
+    if polyline.len() % 2 != 0 {
+        panic!("'--polyline' needs an even number of coordinates.");
+    }
+    let vertices = polyline
+        .chunks(2)
+        .map(|xy| Point2::new(xy[0], xy[1]))
+        .collect();
+    let corridor = Corridor::new(vertices, args.half_width, args.min_z, args.max_z)
+        .expect("'--polyline' needs at least 2 vertices.");
+    let format = args
+        .format
+        .or_else(|| {
+            args.output
+                .extension()
+                .and_then(|e| e.to_str())
+                .and_then(|e| e.parse().ok())
+        })
+        .expect("Unknown output file extension, please pass '--format'.");
+    let (octree, _lock) = open_octree(args.octree_directory.clone());
+    let attributes: Vec<&str> = args.attributes.iter().map(String::as_str).collect();
+    let num_points = match format {
+        OutputFormat::Ply => {
+            write_profile::<PlyNodeWriter>(&octree, &corridor, &attributes, &args.output)
+        }
+        OutputFormat::Csv => {
+            write_profile::<CsvNodeWriter>(&octree, &corridor, &attributes, &args.output)
+        }
+        OutputFormat::Las => panic!("LAS files cannot store the profile coordinates."),
+    }
+    .expect("Could not extract profile.");
+    eprintln!("Wrote {} points to {}.", num_points, args.output.display());
+}
+
+fn write_batch<W: NodeWriter<PointsBatch>>(batch: &PointsBatch, output: &Path) -> Result<()> {
+    let mut writer = W::new(output, Encoding::Plain, OpenMode::Truncate);
+    writer.write(batch)?;
+    writer.finish().map_err(Error::from)
+}
+
+/// Fits a 'T' to the points of 'octree' matching 'query', panicking if there is none.
+fn fit_primitive<T: Primitive>(
+    octree: &Octree,
+    query: &PointQuery,
+    options: &RansacOptions,
+) -> (T, PointsBatch) {
+    fit_in_query::<T, _>(octree, query, options)
+        .expect("Could not read points.")
+        .expect("Found no primitive, there are too few points.")
+}
+
+fn run_fit(args: FitArguments) {
+    let location =
+        bounding_box_from_args(args.bbox).map_or(PointLocation::AllPoints, PointLocation::Aabb);
+    let attributes = if args.inliers.is_some() {
+        args.attributes.iter().map(String::as_str).collect()
+    } else {
+        Vec::new()
+    };
+    let query = PointQuery {
+        attributes,
+        location,
+        filter_intervals: Default::default(),
+    };
+    let options = RansacOptions {
+        distance_threshold: args.threshold,
+        num_iterations: args.iterations,
+        seed: args.seed,
+    };
+    let (octree, _lock) = open_octree(args.octree_directory);
+    let inliers = match args.primitive {
+        PrimitiveKind::Plane => {
+            let (plane, inliers) = fit_primitive::<Plane>(&octree, &query, &options);
+            let n = plane.normal;
+            println!(
+                "Plane with normal ({}, {}, {}) and offset {}.",
+                n.x, n.y, n.z, plane.offset
+            );
+            inliers
+        }
+        PrimitiveKind::Sphere => {
+            let (sphere, inliers) = fit_primitive::<Sphere>(&octree, &query, &options);
+            let c = sphere.center();
+            println!(
+                "Sphere with center ({}, {}, {}) and radius {}.",
+                c.x,
+                c.y,
+                c.z,
+                sphere.radius()
+            );
+            inliers
+        }
+        PrimitiveKind::Cylinder => {
+            let (cylinder, inliers) = fit_primitive::<VerticalCylinder>(&octree, &query, &options);
+            println!(
+                "Vertical cylinder with axis at ({}, {}) and radius {}.",
+                cylinder.center.x, cylinder.center.y, cylinder.radius
+            );
+            inliers
+        }
+    };
+    println!("{} inliers.", inliers.position.len());
+    if let Some(output) = args.inliers {
+        let format = output
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| e.parse().ok())
+            .expect("Unknown extension of the inliers file.");
+        match format {
+            OutputFormat::Ply => write_batch::<PlyNodeWriter>(&inliers, &output),
+            OutputFormat::Csv => write_batch::<CsvNodeWriter>(&inliers, &output),
+            OutputFormat::Las => write_batch::<LasNodeWriter>(&inliers, &output),
+        }
+        .expect("Could not write inliers.");
+    }
+}
+
+fn run_register(args: RegisterArguments) {
+    let location =
+        bounding_box_from_args(args.bbox).map_or(PointLocation::AllPoints, PointLocation::Aabb);
+    let query = PointQuery {
+        attributes: Vec::new(),
+        location,
+        filter_intervals: Default::default(),
+    };
+    let options = IcpOptions {
+        method: args.method,
+        max_correspondence_distance: args.max_distance,
+        normal_radius: args.normal_radius,
+        max_iterations: args.iterations,
+        ..Default::default()
+    };
+    let reference =
+        Reference::from_query(&open_octree(args.reference_directory).0, &query, &options)
+            .expect("Could not read reference points.");
+    let result = align_query(
+        &open_octree(args.octree_directory).0,
+        &query,
+        &reference,
+        &Isometry3::identity(),
+        &options,
+    )
+    .expect("Could not read points.");
+    let t = result.transform.translation.vector;
+    let q = result.transform.rotation.quaternion();
+    println!("Translation: {} {} {}", t.x, t.y, t.z);
+    println!("Rotation (w x y z): {} {} {} {}", q.w, q.i, q.j, q.k);
+    println!(
+        "RMSE {} over {} correspondences after {} iterations{}.",
+        result.rmse,
+        result.residuals.len(),
+        result.num_iterations,
+        if result.converged {
+            ""
+        } else {
+            ", not converged"
+        }
+    );
+}
+
+fn run_sort(args: SortArguments) {
+    let format = output_format(&args.output, args.format).unwrap_or_else(|e| panic!("{}", e));
+    let options = MortonSortOptions {
+        max_points_in_memory: args.max_points_in_memory,
+        temp_directory: args.temp_directory,
+    };
+    let num_points = match format {
+        OutputFormat::Ply => morton_sort::<PlyNodeWriter>(&args.input, &args.output, &options),
+        OutputFormat::Csv => morton_sort::<CsvNodeWriter>(&args.input, &args.output, &options),
+        OutputFormat::Las => morton_sort::<LasNodeWriter>(&args.input, &args.output, &options),
+    }
+    .unwrap_or_else(|e| panic!("Could not sort {}: {}", args.input.display(), e));
+    eprintln!("Wrote {} points to {}.", num_points, args.output.display());
+}
+
+/// Writes all points of 'points' to 'output' and returns their number.
+fn import_text<W: NodeWriter<PointsBatch>>(
+    points: &mut TextIterator,
+    output: &Path,
+) -> Result<usize> {
+    let mut writer = W::new(output, Encoding::Plain, OpenMode::Truncate);
+    let mut num_points = 0;
+    for batch in points {
+        writer.write(&batch)?;
+        num_points += batch.position.len();
+    }
+    writer.finish()?;
+    Ok(num_points)
+}
+
+fn run_import_text(args: ImportTextArguments) {
+    let format = output_format(&args.output, args.format).unwrap_or_else(|e| panic!("{}", e));
+    let mut text_format = TextFormat::from_extension(&args.input).unwrap_or_else(|| {
+        panic!(
+            "Unknown text format of {}, use the extension 'pts', 'csv', 'xyz' or 'txt'.",
+            args.input.display()
+        )
+    });
+    if let Some(delimiter) = &args.delimiter {
+        text_format.delimiter = parse_delimiter(delimiter).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if let Some(decimal_separator) = args.decimal_separator {
+        text_format.decimal_separator = decimal_separator;
+    }
+    if let Some(skip_lines) = args.skip_lines {
+        text_format.skip_lines = skip_lines;
+    }
+    if !args.columns.is_empty() {
+        text_format.columns = Some(args.columns);
+    }
+    let mut points = TextIterator::from_file(&args.input, text_format, NUM_POINTS_PER_BATCH)
+        .unwrap_or_else(|e| panic!("Could not open {}: {}", args.input.display(), e));
+    let num_points = match format {
+        OutputFormat::Ply => import_text::<PlyNodeWriter>(&mut points, &args.output),
+        OutputFormat::Csv => import_text::<CsvNodeWriter>(&mut points, &args.output),
+        OutputFormat::Las => import_text::<LasNodeWriter>(&mut points, &args.output),
+    }
+    .unwrap_or_else(|e| panic!("Could not write {}: {}", args.output.display(), e));
+    eprintln!("Wrote {} points to {}.", num_points, args.output.display());
+    if points.num_invalid_lines() > 0 {
+        eprintln!(
+            "Skipped {} lines that are not points:",
+            points.num_invalid_lines()
+        );
+        for error in points.errors() {
+            eprintln!("  {}", error);
+        }
+        if points.num_invalid_lines() > points.errors().len() {
+            eprintln!("  ...");
+        }
+    }
+}
+
+fn write_node<W: NodeWriter<PointsBatch>>(
+    schema: &NodeSchema,
+    data_provider: &OnDiskDataProvider,
+    node_id: &str,
+    output: &Path,
+) -> Result<()> {
+    let attributes: Vec<&str> = schema
+        .attribute_data_types
+        .keys()
+        .map(String::as_str)
+        .collect();
+    let mut writer = W::new(output, Encoding::Plain, OpenMode::Truncate);
+    for batch in schema.points(data_provider, node_id, &attributes, NUM_POINTS_PER_BATCH)? {
+        writer.write(&batch)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn run_node(args: NodeArguments) {
+    let node_id = args
+        .node
+        .file_name()
+        .and_then(|name| name.to_str())
+        .expect("Invalid node path.");
+    let data_provider = OnDiskDataProvider {
+        directory: args
+            .node
+            .parent()
+            .map_or_else(PathBuf::new, Path::to_path_buf),
+    };
+    let schema = NodeSchema::read(&data_provider, node_id)
+        .unwrap_or_else(|e| panic!("Could not read the schema of {}: {}", node_id, e))
+        .unwrap_or_else(|| panic!("Node {} was written without a schema.", node_id));
+    println!("{}", schema);
+    if let Some(output) = args.output {
+        let format = output_format(&output, args.format).unwrap_or_else(|e| panic!("{}", e));
+        match format {
+            OutputFormat::Ply => {
+                write_node::<PlyNodeWriter>(&schema, &data_provider, node_id, &output)
+            }
+            OutputFormat::Csv => {
+                write_node::<CsvNodeWriter>(&schema, &data_provider, node_id, &output)
+            }
+            OutputFormat::Las => {
+                write_node::<LasNodeWriter>(&schema, &data_provider, node_id, &output)
+            }
+        }
+        .unwrap_or_else(|e| panic!("Could not write {}: {}", output.display(), e));
+        eprintln!(
+            "Wrote {} points to {}.",
+            schema.num_points,
+            output.display()
+        );
+    }
+}
+
+fn run_versions(args: VersionsArguments) {
+    if args.init {
+        enable_versioning(&args.octree_directory).expect("Could not enable versioning.");
+    }
+    if !is_versioned(&args.octree_directory) {
+        eprintln!("The octree is not versioned, use '--init' to start versioning it.");
+        return;
+    }
+    for entry in read_version_log(&args.octree_directory).expect("Could not read version log.") {
+        println!(
+            "{}\t{}\t{}\t{}",
+            entry.version, entry.timestamp, entry.operation, entry.description
+        );
+    }
+}
+
+fn run_replay_frames(args: ReplayFramesArguments) {
+    let records = read_frame_capture(&args.capture)
+        .unwrap_or_else(|e| panic!("Could not read {}: {}", args.capture.display(), e));
+    let octree = args.octree.map(open_octree);
+    let replay = replay_frame_capture(
+        &records,
+        octree.as_ref().map(|(octree, _)| octree),
+        args.pixel_threshold,
+    );
+    println!("frame\ttime_ms\trequested\treceived\trendered\tpending\tselected\tloaded");
+    let mut num_issues = 0;
+    for frame in &replay {
+        num_issues += frame.issues.len();
+        if args.issues_only && frame.issues.is_empty() {
+            continue;
+        }
+        let (selected, loaded) = match frame.selection {
+            Some((selected, loaded)) => (selected.to_string(), loaded.to_string()),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        println!(
+            "{}\t{:.1}\t{}\t{}\t{}\t{}\t{}\t{}",
+            frame.frame,
+            frame.time_ms,
+            frame.num_requested,
+            frame.num_received,
+            frame.num_rendered,
+            frame.num_pending,
+            selected,
+            loaded
+        );
+        for issue in &frame.issues {
+            println!("\t{}", issue);
+        }
+    }
+    eprintln!(
+        "Replayed {} frames with {} problems.",
+        replay.len(),
+        num_issues
+    );
+}
+
+fn run_session_summary(args: SessionSummaryArguments) {
+    let mut records = Vec::new();
+    for log in &args.logs {
+        records.extend(
+            read_session_log(log)
+                .unwrap_or_else(|e| panic!("Could not read {}: {}", log.display(), e)),
+        );
+    }
+    let summary = summarize_sessions(&records);
+    println!("sessions\t{}", summary.num_sessions);
+    println!("duration_s\t{:.1}", summary.duration_s);
+    println!("navigation_s\t{:.1}", summary.navigation_s);
+    println!("distance\t{:.1}", summary.distance);
+    for (name, count) in &summary.datasets {
+        println!("dataset\t{}\t{}", name, count);
+    }
+    for (name, count) in &summary.features {
+        println!("feature\t{}\t{}", name, count);
+    }
+}
+
+fn run_view_settings(args: ViewSettingsArguments) {
+    let mut view_settings = if args.reset {
+        ViewSettings::default()
+    } else {
+        let (octree, _lock) = open_octree(args.octree_directory.clone());
+        octree.view_settings().clone()
+    };
+    let changed = args.reset
+        || args.camera.is_some()
+        || args.up_axis.is_some()
+        || args.color_mode.is_some()
+        || args.point_budget.is_some();
+    if let Some(camera) = args.camera {
+        view_settings.octree_from_camera = Some(camera);
+    }
+    if let Some(up_axis) = args.up_axis {
+        view_settings.up_axis = up_axis;
+    }
+    if let Some(color_mode) = args.color_mode {
+        view_settings.color_mode = color_mode;
+    }
+    if let Some(point_budget) = args.point_budget {
+        view_settings.point_budget = Some(point_budget);
+    }
+    if changed {
+        set_view_settings(&args.octree_directory, &view_settings)
+            .expect("Could not write view settings.");
+    }
+
+    match view_settings.octree_from_camera {
+        Some(camera) => {
+            let (t, q) = (camera.translation.vector, camera.rotation.coords);
+            println!(
+                "camera\t{},{},{},{},{},{},{}",
+                t.x, t.y, t.z, q.x, q.y, q.z, q.w
+            );
+        }
+        None => println!("camera\tdefault"),
+    }
+    println!("up_axis\t{}", view_settings.up_axis.name());
+    println!("color_mode\t{}", view_settings.color_mode.name());
+    match view_settings.point_budget {
+        Some(point_budget) => println!("point_budget\t{}", point_budget),
+        None => println!("point_budget\tdefault"),
+    }
+}
+
+fn main() {
+    init_logging();
+    let args = CommandlineArguments::parse();
+    match args.command {
+        Command::Extract(extract_args) => run_extract(extract_args),
+        Command::ExportTiles(export_tiles_args) => run_export_tiles(export_tiles_args),
+        Command::Stats(stats_args) => run_stats(stats_args),
+        Command::Reencode(reencode_args) => run_reencode(reencode_args),
+        Command::Trim(trim_args) => run_trim(trim_args),
+        Command::Compact(compact_args) => run_compact(compact_args),
+        Command::Verify(verify_args) => run_verify(verify_args),
+        Command::Node(node_args) => run_node(node_args),
+        Command::Sources(sources_args) => run_sources(sources_args),
+        Command::Rasterize(rasterize_args) => run_rasterize(rasterize_args),
+        Command::Overview(overview_args) => run_overview(overview_args),
+        Command::Report(report_args) => run_report(report_args),
+        Command::Contour(contour_args) => run_contour(contour_args),
+        Command::Profile(profile_args) => run_profile(profile_args),
+        Command::Fit(fit_args) => run_fit(fit_args),
+        Command::Register(register_args) => run_register(register_args),
+        Command::Sort(sort_args) => run_sort(sort_args),
+        Command::ImportText(import_text_args) => run_import_text(import_text_args),
+        Command::Versions(versions_args) => run_versions(versions_args),
+        Command::ReplayFrames(replay_frames_args) => run_replay_frames(replay_frames_args),
+        Command::SessionSummary(session_summary_args) => run_session_summary(session_summary_args),
+        Command::ViewSettings(view_settings_args) => run_view_settings(view_settings_args),
+    }
+}
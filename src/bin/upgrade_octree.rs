@@ -13,9 +13,11 @@
 // limitations under the License.
 
 use clap::Clap;
+use log::{error, info};
 use point_viewer::data_provider::{DataProvider, OnDiskDataProvider};
 use point_viewer::octree::NodeId;
 use point_viewer::proto;
+use point_viewer::utils::init_logging;
 use point_viewer::META_FILENAME;
 use protobuf::Message;
 use std::fs::File;
@@ -37,7 +39,7 @@ fn write_meta(directory: &Path, mut meta: proto::Meta, version: i32) {
 }
 
 fn upgrade_version9(directory: &Path, mut meta: proto::Meta) {
-    eprintln!("Upgrading version 9 => 10.");
+    info!("Upgrading version 9 => 10.");
     for node_proto in &mut meta.deprecated_nodes.iter_mut() {
         let mut id = node_proto.id.as_mut().unwrap();
         let node_id = NodeId::from_proto(id);
@@ -49,7 +51,7 @@ fn upgrade_version9(directory: &Path, mut meta: proto::Meta) {
 }
 
 fn upgrade_version10(directory: &Path, mut meta: proto::Meta) {
-    eprintln!("Upgrading version 10 => 11.");
+    info!("Upgrading version 10 => 11.");
     let bbox = meta.bounding_box.as_mut().unwrap();
     let deprecated_min = bbox.take_deprecated_min();
     bbox.set_min(point_viewer::proto::Vector3d::from(deprecated_min));
@@ -59,7 +61,7 @@ fn upgrade_version10(directory: &Path, mut meta: proto::Meta) {
 }
 
 fn upgrade_version11(directory: &Path, mut meta: proto::Meta) {
-    eprintln!("Upgrading version 11 => 12.");
+    info!("Upgrading version 11 => 12.");
     let mut octree = proto::OctreeMeta::new();
 
     octree.set_resolution(meta.deprecated_resolution);
@@ -72,7 +74,7 @@ fn upgrade_version11(directory: &Path, mut meta: proto::Meta) {
 }
 
 fn upgrade_version12(directory: &Path, mut meta: proto::Meta) {
-    eprintln!("Upgrading version 12 => 13.");
+    info!("Upgrading version 12 => 13.");
     if meta.has_octree() {
         let bounding_box = meta.mut_octree().take_deprecated_bounding_box();
         meta.set_bounding_box(bounding_box);
@@ -81,6 +83,7 @@ fn upgrade_version12(directory: &Path, mut meta: proto::Meta) {
 }
 
 fn main() {
+    init_logging();
     let args = CommandlineArguments::parse();
     let data_provider = OnDiskDataProvider {
         directory: args.directory.clone(),
@@ -96,14 +99,14 @@ fn main() {
             11 => upgrade_version11(&args.directory, meta),
             12 => upgrade_version12(&args.directory, meta),
             other if other == point_viewer::CURRENT_VERSION => {
-                eprintln!(
+                info!(
                     "Point cloud at current version {}",
                     point_viewer::CURRENT_VERSION
                 );
                 break;
             }
             other => {
-                eprintln!("Do not know how to upgrade version {}", other);
+                error!("Do not know how to upgrade version {}", other);
                 std::process::exit(1);
             }
         }
@@ -0,0 +1,139 @@
+//! Hooks for classifying points while they are ingested, e.g. into ground and vegetation, so that
+//! the classes are stored in the octree as the attribute 'classification'.
+
+use crate::errors::*;
+use crate::{AttributeData, PointsBatch};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fmt;
+use std::io::{self, BufWriter, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread;
+
+/// Name of the attribute that holds the class of every point.
+pub const CLASSIFICATION: &str = "classification";
+
+/// Classifies the points of a batch. Implementations set the attribute 'CLASSIFICATION' with one
+/// class per point, using the ASPRS LAS codes where they apply, e.g. 2 for ground.
+pub trait Classifier: fmt::Debug + Send {
+    fn classify(&mut self, batch: &mut PointsBatch) -> Result<()>;
+}
+
+/// Runs an external program, e.g. a script that evaluates an ONNX model, and exchanges the points
+/// with it over stdin and stdout. The program stays alive for the whole ingestion. For every
+/// batch, it reads the number of points as little endian u32 followed by x, y and z of every
+/// point as little endian f64, and has to answer with one byte per point.
+pub struct SubprocessClassifier {
+    child: Child,
+    // None after a batch could not be sent.
+    stdin: Option<BufWriter<ChildStdin>>,
+    stdout: ChildStdout,
+}
+
+impl fmt::Debug for SubprocessClassifier {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "SubprocessClassifier (pid {})", self.child.id())
+    }
+}
+
+impl SubprocessClassifier {
+    pub fn new(program: &str, args: &[&str]) -> Result<Self> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Could not start classifier '{}'.", program))?;
+        let stdin = Some(BufWriter::new(child.stdin.take().unwrap()));
+        let stdout = child.stdout.take().unwrap();
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+impl Classifier for SubprocessClassifier {
+    fn classify(&mut self, batch: &mut PointsBatch) -> Result<()> {
+        let num_points = batch.position.len();
+        let mut request: Vec<u8> = Vec::with_capacity(4 + 24 * num_points);
+        request.write_u32::<LittleEndian>(num_points as u32)?;
+        for position in &batch.position {
+            for coordinate in position.iter() {
+                request.write_f64::<LittleEndian>(*coordinate)?;
+            }
+        }
+        // The program may answer before it has read the whole batch, so the points are sent from
+        // another thread. Otherwise, both sides could wait for the other to empty a full pipe.
+        let mut stdin = self.stdin.take().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The classifier failed on an earlier batch.",
+            )
+        })?;
+        let sender = thread::spawn(move || -> io::Result<_> {
+            stdin.write_all(&request)?;
+            stdin.flush()?;
+            Ok(stdin)
+        });
+        let mut classes = vec![0; num_points];
+        let received = self.stdout.read_exact(&mut classes);
+        if received.is_err() {
+            // Unblocks the sender if the program stopped reading.
+            let _ = self.child.kill();
+        }
+        let sent = sender.join().expect("Classifier sender panicked.");
+        self.stdin = Some(sent.with_context(|| "Could not send points to the classifier.")?);
+        received.with_context(|| "Classifier did not return a class for every point.")?;
+        batch
+            .attributes
+            .insert(CLASSIFICATION.to_string(), AttributeData::U8(classes));
+        Ok(())
+    }
+}
+
+impl Drop for SubprocessClassifier {
+    fn drop(&mut self) {
+        // The program might wait for more points forever.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use nalgebra::Point3;
+
+    #[test]
+    fn test_subprocess_classifier() {
+        // Reads the 4 + 2 * 24 bytes of a batch of two points and classifies both as ground.
+        let mut classifier =
+            SubprocessClassifier::new("sh", &["-c", "head -c 52 >/dev/null; printf '\\002\\002'"])
+                .unwrap();
+        let mut batch = PointsBatch {
+            position: vec![Point3::new(1., 2., 3.), Point3::new(4., 5., 6.)],
+            attributes: Default::default(),
+        };
+        classifier.classify(&mut batch).unwrap();
+        let classes: &Vec<u8> = batch.get_attribute_vec(CLASSIFICATION).unwrap();
+        assert_eq!(classes, &vec![2, 2]);
+        // The program has exited, so further batches fail instead of blocking.
+        assert!(classifier.classify(&mut batch).is_err());
+    }
+
+    #[test]
+    fn test_subprocess_classifier_answers_while_reading() {
+        // Answers before it reads the points, with more data than a pipe holds either way.
+        let num_points = 1_000_000;
+        let script = format!("yes | tr -d '\\n' | head -c {}; cat >/dev/null", num_points);
+        let mut classifier = SubprocessClassifier::new("sh", &["-c", &script]).unwrap();
+        let mut batch = PointsBatch {
+            position: vec![Point3::origin(); num_points],
+            attributes: Default::default(),
+        };
+        classifier.classify(&mut batch).unwrap();
+        let classes: &Vec<u8> = batch.get_attribute_vec(CLASSIFICATION).unwrap();
+        assert!(classes.iter().all(|class| *class == b'y'));
+    }
+}
@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::proto;
 use nalgebra::RealField;
 use std::iter::Sum;
 use std::ops::{Add, AddAssign, Div};
+use std::str::FromStr;
 
 // Entries follow GL semantics: they are in [0.; 1.] with 1. being fully saturated.
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
@@ -36,6 +38,28 @@ impl Color<f32> {
     }
 }
 
+impl Color<f32> {
+    /// Decodes the sRGB channels to linear intensities. Alpha is left as is.
+    pub fn to_linear(self) -> Color<f32> {
+        Color {
+            red: srgb_to_linear(self.red),
+            green: srgb_to_linear(self.green),
+            blue: srgb_to_linear(self.blue),
+            alpha: self.alpha,
+        }
+    }
+
+    /// Encodes the linear channels as sRGB. Alpha is left as is.
+    pub fn to_srgb(self) -> Color<f32> {
+        Color {
+            red: linear_to_srgb(self.red),
+            green: linear_to_srgb(self.green),
+            blue: linear_to_srgb(self.blue),
+            alpha: self.alpha,
+        }
+    }
+}
+
 impl Color<u8> {
     pub fn to_f32(self) -> Color<f32> {
         Color {
@@ -47,6 +71,102 @@ impl Color<u8> {
     }
 }
 
+/// How the values of the 'color' attribute encode the color. Most scanners and cameras, and
+/// everything that displays colors, use sRGB. Some photogrammetry and HDR pipelines export linear
+/// colors, which look washed out when they are shown as sRGB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+impl Default for ColorSpace {
+    fn default() -> Self {
+        ColorSpace::Srgb
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "srgb" => Ok(ColorSpace::Srgb),
+            "linear" => Ok(ColorSpace::Linear),
+            _ => Err(format!(
+                "Unknown color space '{}', expected 'srgb' or 'linear'.",
+                s
+            )),
+        }
+    }
+}
+
+impl ColorSpace {
+    pub fn from_proto(proto: proto::ColorSpace) -> Self {
+        match proto {
+            proto::ColorSpace::SRGB => ColorSpace::Srgb,
+            proto::ColorSpace::LINEAR => ColorSpace::Linear,
+        }
+    }
+
+    pub fn to_proto(self) -> proto::ColorSpace {
+        match self {
+            ColorSpace::Srgb => proto::ColorSpace::SRGB,
+            ColorSpace::Linear => proto::ColorSpace::LINEAR,
+        }
+    }
+
+    /// Converts the 8 bit channels in 'values' from this color space to 'to' in place, e.g. the
+    /// bytes of 'NodeData::color' or the entries of the 'color' attribute.
+    pub fn convert_u8<'a>(self, to: ColorSpace, values: impl IntoIterator<Item = &'a mut u8>) {
+        if self == to {
+            return;
+        }
+        let convert = match to {
+            ColorSpace::Srgb => linear_to_srgb,
+            ColorSpace::Linear => srgb_to_linear,
+        };
+        let table: Vec<u8> = (0..=255u8)
+            .map(|value| (convert(f32::from(value) / 255.) * 255.).round() as u8)
+            .collect();
+        for value in values {
+            *value = table[usize::from(*value)];
+        }
+    }
+}
+
+/// Decodes an sRGB channel in [0, 1] to its linear intensity.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.040_45 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encodes a linear intensity in [0, 1] as an sRGB channel.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Reduces a 16 bit channel to 8 bits. Rounding alone turns smooth gradients into bands, so the
+/// value is dithered with a threshold that depends on 'index', e.g. the index of the channel in
+/// the file. The thresholds come from a low discrepancy sequence, so the result is deterministic
+/// and neighboring points average to the 16 bit value.
+pub fn u16_to_u8(value: u16, index: usize) -> u8 {
+    let threshold = (index as f64 * 0.618_033_988_749_895).fract();
+    (f64::from(value) / 257. + threshold).floor().min(255.) as u8
+}
+
+/// Expands an 8 bit channel to 16 bits, so that 255 becomes 65535.
+pub fn u8_to_u16(value: u8) -> u16 {
+    u16::from(value) * 257
+}
+
 impl<T> From<Color<T>> for image::Rgba<T>
 where
     T: image::Primitive,
@@ -157,3 +277,79 @@ pub const TRANSPARENT: Color<f32> = Color {
     blue: 1.,
     alpha: 0.,
 };
+
+/// A color for the category 'index', e.g. the source id of a point. Consecutive indices get hues
+/// that are far apart, so neighboring scans are easy to tell apart.
+pub fn category_color(index: u32) -> Color<u8> {
+    // The golden angle spreads any number of hues evenly around the color wheel.
+    let hue = (f64::from(index) * 0.381_966_011_250_105).fract() * 6.;
+    let x = (1. - ((hue % 2.) - 1.).abs()) as f32;
+    let (red, green, blue) = match hue as u32 {
+        0 => (1., x, 0.),
+        1 => (x, 1., 0.),
+        2 => (0., 1., x),
+        3 => (0., x, 1.),
+        4 => (x, 0., 1.),
+        _ => (1., 0., x),
+    };
+    Color {
+        red,
+        green,
+        blue,
+        alpha: 1.,
+    }
+    .to_u8()
+}
+
+/// Maps 'value' in [0, 1] to black, red, yellow and white, like the glow of hot metal.
+pub fn heat_color(value: f32) -> Color<u8> {
+    let value = value.max(0.).min(1.) * 3.;
+    Color {
+        red: value.min(1.),
+        green: (value - 1.).max(0.).min(1.),
+        blue: (value - 2.).max(0.).min(1.),
+        alpha: 1.,
+    }
+    .to_u8()
+}
+
+/// Maps 'value' in [-1, 1] to blue, white and red, so that the sign of deviations is visible.
+pub fn diverging_color(value: f32) -> Color<u8> {
+    let value = value.max(-1.).min(1.);
+    Color {
+        red: 1. + value.min(0.),
+        green: 1. - value.abs(),
+        blue: 1. - value.max(0.),
+        alpha: 1.,
+    }
+    .to_u8()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srgb_round_trip() {
+        for value in 0..=255u8 {
+            let value = f32::from(value) / 255.;
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 1e-5);
+        }
+        let mut values = vec![0, 128, 255];
+        ColorSpace::Linear.convert_u8(ColorSpace::Srgb, &mut values);
+        assert_eq!(values, vec![0, 188, 255]);
+        ColorSpace::Srgb.convert_u8(ColorSpace::Linear, &mut values);
+        assert_eq!(values, vec![0, 128, 255]);
+    }
+
+    #[test]
+    fn test_u16_to_u8() {
+        assert_eq!(u16_to_u8(0, 7), 0);
+        assert_eq!(u16_to_u8(u16::max_value(), 7), 255);
+        assert_eq!(u16_to_u8(u8_to_u16(100), 0), 100);
+        // A value between two 8 bit values is dithered to both, on average hitting it.
+        let value = u8_to_u16(100) + 257 / 4;
+        let sum: usize = (0..1000).map(|i| usize::from(u16_to_u8(value, i))).sum();
+        assert!((sum as f64 / 1000. - 100.25).abs() < 0.01);
+    }
+}
@@ -0,0 +1,195 @@
+//! Colors points by projecting them into georeferenced images, e.g. to colorize LiDAR scans with
+//! the photos or panoramas taken during the same survey.
+
+use crate::errors::*;
+use crate::{AttributeData, PointsBatch};
+use image::RgbImage;
+use nalgebra::{Isometry3, Point3, Vector3};
+use std::convert::TryInto;
+use std::f64::consts::PI;
+use std::fmt;
+use std::path::Path;
+
+/// How an image maps directions in the camera frame to pixels. The camera looks along +z, +x is
+/// to the right and +y points down in the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// A pinhole camera with the focal lengths and principal point in pixels, as found by camera
+    /// calibration. Lens distortion must be removed from the image beforehand.
+    Pinhole { fx: f64, fy: f64, cx: f64, cy: f64 },
+    /// A 360° panorama. The image center looks along +z, the columns span the longitude and the
+    /// rows the latitude from straight up to straight down.
+    Equirectangular,
+}
+
+/// An image together with its projection and pose.
+pub struct ColorSource {
+    pub image: RgbImage,
+    pub projection: Projection,
+    pub world_from_camera: Isometry3<f64>,
+}
+
+impl ColorSource {
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        projection: Projection,
+        world_from_camera: Isometry3<f64>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .map_err(|e| Error::InvalidInput(format!("Could not read {}: {}", path.display(), e)))?
+            .to_rgb8();
+        Ok(Self {
+            image,
+            projection,
+            world_from_camera,
+        })
+    }
+
+    /// Returns the pixel that 'point' in the world frame projects to, if it is in the image.
+    fn pixel(&self, point: &Point3<f64>) -> Option<(u32, u32)> {
+        let p = self.world_from_camera.inverse_transform_point(point);
+        let (width, height) = (
+            f64::from(self.image.width()),
+            f64::from(self.image.height()),
+        );
+        let (u, v) = match self.projection {
+            Projection::Pinhole { fx, fy, cx, cy } => {
+                if p.z <= 0. {
+                    return None;
+                }
+                (fx * p.x / p.z + cx, fy * p.y / p.z + cy)
+            }
+            Projection::Equirectangular => {
+                let longitude = p.x.atan2(p.z);
+                let latitude = (-p.y).atan2(p.x.hypot(p.z));
+                (
+                    (longitude / (2. * PI) + 0.5) * width,
+                    (0.5 - latitude / PI) * height,
+                )
+            }
+        };
+        if u < 0. || v < 0. || u >= width || v >= height {
+            return None;
+        }
+        Some((u as u32, v as u32))
+    }
+
+    fn camera_position(&self) -> Point3<f64> {
+        Point3::from(self.world_from_camera.translation.vector)
+    }
+}
+
+/// Colors points from the closest image they project into. Occlusions are not detected, so points
+/// hidden from a camera by other geometry still get their color from it; dense image coverage
+/// makes this rare.
+#[derive(Default)]
+pub struct Colorizer {
+    sources: Vec<ColorSource>,
+}
+
+impl fmt::Debug for Colorizer {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Colorizer with {} images", self.sources.len())
+    }
+}
+
+impl Colorizer {
+    pub fn new(sources: Vec<ColorSource>) -> Self {
+        Self { sources }
+    }
+
+    /// Sets the color of the points of 'batch' that are seen by an image, and adds a color
+    /// attribute if the batch has none. Other points keep their color, or are black.
+    pub fn colorize(&self, batch: &mut PointsBatch) {
+        let num_points = batch.position.len();
+        let colors: &mut Vec<Vector3<u8>> = match batch
+            .attributes
+            .entry("color".to_string())
+            .or_insert_with(|| AttributeData::U8Vec3(vec![Vector3::zeros(); num_points]))
+            .try_into()
+        {
+            Ok(colors) => colors,
+            Err(_) => return,
+        };
+        for (position, color) in batch.position.iter().zip(colors.iter_mut()) {
+            let closest = self
+                .sources
+                .iter()
+                .filter_map(|source| source.pixel(position).map(|pixel| (source, pixel)))
+                .min_by(|(a, _), (b, _)| {
+                    let distance_a = (a.camera_position() - position).norm_squared();
+                    let distance_b = (b.camera_position() - position).norm_squared();
+                    distance_a.partial_cmp(&distance_b).unwrap()
+                });
+            if let Some((source, (u, v))) = closest {
+                let pixel = source.image.get_pixel(u, v);
+                *color = Vector3::new(pixel[0], pixel[1], pixel[2]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+    use nalgebra::Translation3;
+
+    fn source(projection: Projection, x: f64, color: [u8; 3]) -> ColorSource {
+        ColorSource {
+            image: RgbImage::from_pixel(100, 50, Rgb(color)),
+            projection,
+            world_from_camera: Isometry3::from_parts(
+                Translation3::new(x, 0., 0.),
+                nalgebra::UnitQuaternion::identity(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_pinhole_projection() {
+        let source = source(
+            Projection::Pinhole {
+                fx: 10.,
+                fy: 10.,
+                cx: 50.,
+                cy: 25.,
+            },
+            0.,
+            [0; 3],
+        );
+        assert_eq!(source.pixel(&Point3::new(0., 0., 1.)), Some((50, 25)));
+        assert_eq!(source.pixel(&Point3::new(1., -1., 2.)), Some((55, 20)));
+        assert_eq!(source.pixel(&Point3::new(0., 0., -1.)), None);
+        assert_eq!(source.pixel(&Point3::new(10., 0., 1.)), None);
+    }
+
+    #[test]
+    fn test_equirectangular_projection() {
+        let source = source(Projection::Equirectangular, 0., [0; 3]);
+        assert_eq!(source.pixel(&Point3::new(0., 0., 1.)), Some((50, 25)));
+        // To the right of the camera.
+        assert_eq!(source.pixel(&Point3::new(1., 0., 0.)), Some((75, 25)));
+        // Straight up.
+        assert_eq!(source.pixel(&Point3::new(0., -1., 0.)), Some((50, 0)));
+    }
+
+    #[test]
+    fn test_closest_image_wins() {
+        let colorizer = Colorizer::new(vec![
+            source(Projection::Equirectangular, 0., [255, 0, 0]),
+            source(Projection::Equirectangular, 10., [0, 255, 0]),
+        ]);
+        let mut batch = PointsBatch {
+            position: vec![Point3::new(1., 0., 1.), Point3::new(9., 0., 1.)],
+            attributes: Default::default(),
+        };
+        colorizer.colorize(&mut batch);
+        let colors: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+        assert_eq!(
+            colors,
+            &vec![Vector3::new(255, 0, 0), Vector3::new(0, 255, 0)]
+        );
+    }
+}
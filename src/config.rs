@@ -0,0 +1,219 @@
+//! Loads the TOML configuration files of the server and viewer binaries. Every value can be
+//! overridden by an environment variable named after the binary's prefix and the upper case key,
+//! e.g. 'POINTS_WEB_VIEWER_PORT=8080'. Keys in nested tables are separated by two underscores,
+//! e.g. 'SDL_VIEWER_RENDERING__GAMMA=1.2' sets 'gamma' in the '[rendering]' table. Variables
+//! with the prefix that do not name a setting are ignored with a warning, since the settings
+//! reject unknown keys.
+
+use crate::errors::*;
+use log::warn;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, Visitor,
+};
+use std::fs;
+use std::path::Path;
+use toml::value::{Table, Value};
+
+const NESTING_SEPARATOR: &str = "__";
+
+/// Reads the configuration from the file at 'path', if given, and applies the overrides from
+/// environment variables starting with 'env_prefix'. Values missing in both are left to the
+/// serde defaults of 'T'.
+pub fn load_config<T: DeserializeOwned>(path: Option<&Path>, env_prefix: &str) -> Result<T> {
+    let mut table = match path {
+        Some(path) => {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("Could not read config file {}.", path.display()))?;
+            toml::from_str::<Table>(&contents).map_err(|err| {
+                Error::InvalidInput(format!("Invalid config file {}: {}", path.display(), err))
+            })?
+        }
+        None => Table::new(),
+    };
+    apply_env_overrides::<T>(&mut table, env_prefix, std::env::vars());
+    Value::Table(table)
+        .try_into()
+        .map_err(|err| Error::InvalidInput(format!("Invalid configuration: {}", err)))
+}
+
+fn apply_env_overrides<T: DeserializeOwned>(
+    table: &mut Table,
+    env_prefix: &str,
+    vars: impl IntoIterator<Item = (String, String)>,
+) {
+    let prefix = format!("{}_", env_prefix);
+    for (name, value) in vars {
+        let key = match name.strip_prefix(&prefix) {
+            Some(key) if !key.is_empty() => key.to_lowercase(),
+            _ => continue,
+        };
+        let mut segments: Vec<&str> = key.split(NESTING_SEPARATOR).collect();
+        let is_setting = (0..segments.len()).all(|depth| {
+            struct_fields::<T>(&segments[..depth])
+                .map_or(false, |fields| fields.contains(&segments[depth]))
+        });
+        if !is_setting {
+            warn!("Ignoring {}, it does not name a setting.", name);
+            continue;
+        }
+        let last = segments.pop().unwrap();
+        let mut current = &mut *table;
+        for segment in segments {
+            let entry = current
+                .entry(segment.to_string())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            current = entry.as_table_mut().unwrap();
+        }
+        current.insert(last.to_string(), parse_env_value(&value));
+    }
+}
+
+/// The keys of the table at 'path' in 'T', or None if there is no table at 'path'. They are found
+/// by letting 'T' deserialize from a 'FieldProbe', which only answers with the one key on 'path'
+/// at every level.
+fn struct_fields<T: DeserializeOwned>(path: &[&str]) -> Option<&'static [&'static str]> {
+    let mut fields = None;
+    // The probe always fails, it only has to get far enough to see the fields.
+    let _ = T::deserialize(FieldProbe {
+        path,
+        fields: &mut fields,
+    });
+    fields
+}
+
+struct FieldProbe<'a> {
+    path: &'a [&'a str],
+    fields: &'a mut Option<&'static [&'static str]>,
+}
+
+impl<'de, 'a> Deserializer<'de> for FieldProbe<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> std::result::Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a table"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        match self.path.split_first() {
+            Some((key, path)) => visitor.visit_map(FieldProbeMap {
+                key: Some(key),
+                value: Some(FieldProbe {
+                    path,
+                    fields: self.fields,
+                }),
+            }),
+            None => {
+                *self.fields = Some(fields);
+                Err(de::Error::custom("found the fields"))
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// A map with the single entry 'key' = 'value'.
+struct FieldProbeMap<'a> {
+    key: Option<&'a str>,
+    value: Option<FieldProbe<'a>>,
+}
+
+impl<'de, 'a> MapAccess<'de> for FieldProbeMap<'a> {
+    type Error = de::value::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> std::result::Result<Option<K::Value>, Self::Error> {
+        match self.key.take() {
+            Some(key) => seed.deserialize(key.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        seed.deserialize(self.value.take().unwrap())
+    }
+}
+
+/// Environment variables are parsed like TOML values, so that numbers, booleans and arrays keep
+/// their type. Everything else, e.g. paths, is taken as a string.
+fn parse_env_value(value: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::Deserialize;
+
+    #[derive(Debug, Default, Deserialize, PartialEq)]
+    #[serde(default, deny_unknown_fields)]
+    struct Rendering {
+        gamma: f32,
+    }
+
+    #[derive(Debug, Default, Deserialize, PartialEq)]
+    #[serde(default, deny_unknown_fields)]
+    struct Config {
+        dataset: String,
+        port: u16,
+        rendering: Rendering,
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut table = toml::from_str::<Table>("port = 5433\n[rendering]\ngamma = 1.0\n").unwrap();
+        let vars = vec![
+            ("TEST_PORT".to_string(), "8080".to_string()),
+            ("TEST_DATASET".to_string(), "/data/octree".to_string()),
+            ("TEST_RENDERING__GAMMA".to_string(), "1.5".to_string()),
+            ("OTHER_PORT".to_string(), "1".to_string()),
+            ("TEST_VERBOSE".to_string(), "true".to_string()),
+            ("TEST_RENDERING__EXPOSURE".to_string(), "2".to_string()),
+            ("TEST_PORT__NUMBER".to_string(), "1".to_string()),
+        ];
+        apply_env_overrides::<Config>(&mut table, "TEST", vars);
+        let config: Config = Value::Table(table).try_into().unwrap();
+        assert_eq!(
+            config,
+            Config {
+                dataset: "/data/octree".to_string(),
+                port: 8080,
+                rendering: Rendering { gamma: 1.5 },
+            }
+        );
+    }
+}
@@ -10,4 +10,17 @@ pub trait DataProvider: Send + Sync {
         node_id: &str,
         node_attributes: &[&str],
     ) -> Result<HashMap<String, Box<dyn Read + Send>>>;
+
+    /// Returns the data of several nodes, in the order of 'node_ids'. Providers that can read
+    /// many nodes concurrently should override this; by default, the nodes are read one by one.
+    fn data_for_nodes(
+        &self,
+        node_ids: &[&str],
+        node_attributes: &[&str],
+    ) -> Vec<Result<HashMap<String, Box<dyn Read + Send>>>> {
+        node_ids
+            .iter()
+            .map(|node_id| self.data(node_id, node_attributes))
+            .collect()
+    }
 }
@@ -0,0 +1,231 @@
+//! Keeps the node files that a remote data provider returned in a local directory, so that
+//! reopening a remote octree is nearly instant and viewing it keeps working while offline.
+
+use crate::attribute_extension;
+use crate::data_provider::DataProvider;
+use crate::errors::*;
+use crate::proto;
+use crate::read_write::write_file_atomically;
+use crate::META_FILENAME;
+use fnv::FnvHasher;
+use log::warn;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+/// File in the cache directory of a dataset with the version that was served last.
+const LATEST_FILENAME: &str = "latest";
+
+fn hash_hex(bytes: &[u8]) -> String {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    format!("{:016x}", hasher.finish())
+}
+
+type NodeReaders = HashMap<String, Box<dyn Read + Send>>;
+
+/// Caches the node files of 'inner' on disk. Every dataset gets a directory below the cache
+/// directory, and the node files are kept per version of the dataset, which is the hash of its
+/// meta data. Once a new version shows up, the files of the previous ones are removed. If the
+/// meta data can not be fetched, e.g. without connection, the last version is served from the
+/// cache, as far as its nodes were cached.
+pub struct DiskCacheDataProvider {
+    inner: Box<dyn DataProvider>,
+    meta: proto::Meta,
+    /// Directory with the node files of the served version.
+    directory: PathBuf,
+}
+
+impl DiskCacheDataProvider {
+    /// 'dataset' identifies the dataset in the cache, e.g. the URL that 'inner' reads from.
+    pub fn new(
+        inner: Box<dyn DataProvider>,
+        cache_directory: impl AsRef<Path>,
+        dataset: &str,
+    ) -> Result<Self> {
+        let dataset_directory = cache_directory.as_ref().join(hash_hex(dataset.as_bytes()));
+        fs::create_dir_all(&dataset_directory).with_context(|| {
+            format!(
+                "Could not create cache directory {}.",
+                dataset_directory.display()
+            )
+        })?;
+        let latest_path = dataset_directory.join(LATEST_FILENAME);
+        let (meta, version) = match inner.meta_proto() {
+            Ok(meta) => {
+                let bytes = meta
+                    .write_to_bytes()
+                    .with_context(|| "Could not serialize the meta data.")?;
+                let version = hash_hex(&bytes);
+                let directory = dataset_directory.join(&version);
+                fs::create_dir_all(&directory)?;
+                write_file_atomically(directory.join(META_FILENAME), &bytes)?;
+                write_file_atomically(&latest_path, version.as_bytes())?;
+                remove_other_versions(&dataset_directory, &version);
+                (meta, version)
+            }
+            Err(err) => {
+                let version = match fs::read_to_string(&latest_path) {
+                    Ok(version) => version,
+                    Err(_) => return Err(err),
+                };
+                warn!(
+                    "Could not fetch the meta data of {}, serving it from the cache: {}",
+                    dataset, err
+                );
+                let bytes = fs::read(dataset_directory.join(&version).join(META_FILENAME))?;
+                let meta = protobuf::parse_from_bytes::<proto::Meta>(&bytes)
+                    .with_context(|| format!("Could not parse the cached {}", META_FILENAME))?;
+                (meta, version)
+            }
+        };
+        Ok(Self {
+            inner,
+            meta,
+            directory: dataset_directory.join(version),
+        })
+    }
+
+    fn path(&self, node_id: &str, node_attribute: &str) -> PathBuf {
+        self.directory.join(format!(
+            "{}.{}",
+            node_id,
+            attribute_extension(node_attribute)
+        ))
+    }
+
+    /// Returns the node files from the cache, or None unless all of them are cached.
+    fn cached(&self, node_id: &str, node_attributes: &[&str]) -> Result<Option<NodeReaders>> {
+        let mut readers = NodeReaders::new();
+        for node_attribute in node_attributes {
+            let file = match File::open(self.path(node_id, node_attribute)) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                e => e,
+            }?;
+            readers.insert((*node_attribute).to_string(), Box::new(file));
+        }
+        Ok(Some(readers))
+    }
+
+    /// Reads the node files that 'inner' returned into memory and writes them into the cache.
+    fn store(&self, node_id: &str, readers: NodeReaders) -> Result<NodeReaders> {
+        let mut cached = NodeReaders::new();
+        for (node_attribute, mut reader) in readers {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            // The cache is only an optimization, a full disk must not stop the viewer.
+            let path = self.path(node_id, &node_attribute);
+            if let Err(err) = write_file_atomically(&path, &bytes) {
+                warn!("Could not cache {}: {}", path.display(), err);
+            }
+            cached.insert(node_attribute, Box::new(Cursor::new(bytes)));
+        }
+        Ok(cached)
+    }
+}
+
+/// Removes the cached files of all versions of a dataset other than 'version'.
+fn remove_other_versions(dataset_directory: &Path, version: &str) {
+    let entries = match fs::read_dir(dataset_directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if entry.file_name() != version && entry.path().is_dir() {
+            if let Err(err) = fs::remove_dir_all(entry.path()) {
+                warn!("Could not remove {}: {}", entry.path().display(), err);
+            }
+        }
+    }
+}
+
+impl DataProvider for DiskCacheDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        Ok(self.meta.clone())
+    }
+
+    fn data(&self, node_id: &str, node_attributes: &[&str]) -> Result<NodeReaders> {
+        if let Some(readers) = self.cached(node_id, node_attributes)? {
+            return Ok(readers);
+        }
+        let readers = self.inner.data(node_id, node_attributes)?;
+        self.store(node_id, readers)
+    }
+
+    /// Only the nodes that are not cached are requested from 'inner', in one call.
+    fn data_for_nodes(
+        &self,
+        node_ids: &[&str],
+        node_attributes: &[&str],
+    ) -> Vec<Result<NodeReaders>> {
+        let mut results: Vec<Option<Result<NodeReaders>>> = node_ids
+            .iter()
+            .map(|node_id| self.cached(node_id, node_attributes).transpose())
+            .collect();
+        let missing: Vec<usize> = (0..node_ids.len())
+            .filter(|i| results[*i].is_none())
+            .collect();
+        let missing_ids: Vec<&str> = missing.iter().map(|i| node_ids[*i]).collect();
+        let fetched = self.inner.data_for_nodes(&missing_ids, node_attributes);
+        for (i, readers) in missing.into_iter().zip(fetched) {
+            results[i] = Some(readers.and_then(|readers| self.store(node_ids[i], readers)));
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_provider::OnDiskDataProvider;
+    use tempdir::TempDir;
+
+    fn read_position(data_provider: &dyn DataProvider) -> Vec<u8> {
+        let mut readers = data_provider.data("r0", &["position"]).unwrap();
+        let mut position = Vec::new();
+        readers
+            .get_mut("position")
+            .unwrap()
+            .read_to_end(&mut position)
+            .unwrap();
+        position
+    }
+
+    #[test]
+    fn test_serves_cached_nodes_offline() {
+        let remote_dir = TempDir::new("remote").unwrap();
+        let cache_dir = TempDir::new("cache").unwrap();
+        let meta = proto::Meta::new();
+        fs::write(
+            remote_dir.path().join(META_FILENAME),
+            meta.write_to_bytes().unwrap(),
+        )
+        .unwrap();
+        fs::write(remote_dir.path().join("r0.xyz"), b"\x01\x02\x03").unwrap();
+        let remote = || {
+            Box::new(OnDiskDataProvider {
+                directory: remote_dir.path().to_path_buf(),
+            })
+        };
+
+        let data_provider =
+            DiskCacheDataProvider::new(remote(), cache_dir.path(), "remote").unwrap();
+        assert_eq!(read_position(&data_provider), vec![1, 2, 3]);
+
+        // Without the remote files, the cached ones are served.
+        fs::remove_file(remote_dir.path().join(META_FILENAME)).unwrap();
+        fs::remove_file(remote_dir.path().join("r0.xyz")).unwrap();
+        let data_provider =
+            DiskCacheDataProvider::new(remote(), cache_dir.path(), "remote").unwrap();
+        assert_eq!(read_position(&data_provider), vec![1, 2, 3]);
+        assert!(matches!(
+            data_provider.data("r1", &["position"]),
+            Err(Error::NodeNotFound)
+        ));
+        // Other datasets are not served from this cache.
+        assert!(DiskCacheDataProvider::new(remote(), cache_dir.path(), "other").is_err());
+    }
+}
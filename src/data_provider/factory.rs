@@ -45,11 +45,10 @@ impl DataProviderFactory {
                 directory: data_provider_argument.into(),
             }))
         } else {
-            Err(format!(
+            Err(Error::InvalidInput(format!(
                 "Directory '{}' for creating an OnDiskDataProvider doesn't exist.",
                 data_provider_argument
-            )
-            .into())
+            )))
         }
     }
 }
@@ -1,7 +1,21 @@
 mod common;
+#[cfg(feature = "native")]
+mod disk_cache;
 mod factory;
+#[cfg(feature = "native")]
+mod mmap;
 mod on_disk;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod uring;
+mod versioned;
 
 pub use common::DataProvider;
+#[cfg(feature = "native")]
+pub use disk_cache::DiskCacheDataProvider;
 pub use factory::{DataProviderFactory, DataProviderFactoryResult};
+#[cfg(feature = "native")]
+pub use mmap::{MmapDataProvider, MMAP_PREFIX};
 pub use on_disk::OnDiskDataProvider;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+pub use uring::IoUringDataProvider;
+pub use versioned::{latest_version, version_exists, VersionedDataProvider, VERSIONS_DIRECTORY};
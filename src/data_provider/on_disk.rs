@@ -24,7 +24,7 @@ impl OnDiskDataProvider {
         let stem = self.stem(node_id);
         let file_meta_data_opt = fs::metadata(stem.with_extension(attribute_extension("color")));
         if file_meta_data_opt.is_err() {
-            return Err(ErrorKind::NodeNotFound.into());
+            return Err(Error::NodeNotFound);
         }
 
         let file_size_bytes = file_meta_data_opt.unwrap().len();
@@ -37,14 +37,14 @@ impl DataProvider for OnDiskDataProvider {
     fn meta_proto(&self) -> Result<proto::Meta> {
         // We used to use JSON earlier.
         if self.directory.join("meta.json").exists() {
-            return Err(ErrorKind::InvalidVersion(3).into());
+            return Err(Error::InvalidVersion(3));
         }
 
         let mut data = Vec::new();
         File::open(&self.directory.join(META_FILENAME))?.read_to_end(&mut data)?;
         Ok(
             protobuf::parse_from_reader::<proto::Meta>(&mut Cursor::new(data))
-                .chain_err(|| format!("Could not parse {}", META_FILENAME))?,
+                .with_context(|| format!("Could not parse {}", META_FILENAME))?,
         )
     }
 
@@ -58,7 +58,7 @@ impl DataProvider for OnDiskDataProvider {
         for node_attribute in node_attributes {
             let file = match File::open(&stem.with_extension(attribute_extension(node_attribute))) {
                 Err(ref err) if err.kind() == ::std::io::ErrorKind::NotFound => {
-                    return Err(ErrorKind::NodeNotFound.into());
+                    return Err(Error::NodeNotFound);
                 }
                 e => e,
             }?;
@@ -0,0 +1,220 @@
+//! Reads nodes from disk with io_uring, which keeps all reads of a request in flight at the same
+//! time. On network filesystems, this hides most of the latency of reading many small files.
+
+use crate::attribute_extension;
+use crate::data_provider::{DataProvider, DataProviderFactoryResult, OnDiskDataProvider};
+use crate::errors::*;
+use crate::proto;
+use io_uring::{opcode, types, IoUring};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+/// Maximum number of reads that are submitted at the same time.
+const DEFAULT_QUEUE_DEPTH: u32 = 256;
+
+pub struct IoUringDataProvider {
+    on_disk: OnDiskDataProvider,
+    queue_depth: u32,
+}
+
+/// One file to read completely.
+struct PendingRead {
+    node_index: usize,
+    attribute: String,
+    file: File,
+    buffer: Vec<u8>,
+    num_read: usize,
+}
+
+impl IoUringDataProvider {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            on_disk: OnDiskDataProvider {
+                directory: directory.into(),
+            },
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+        }
+    }
+
+    pub fn with_queue_depth(mut self, queue_depth: u32) -> Self {
+        self.queue_depth = queue_depth.max(1);
+        self
+    }
+
+    /// Can be registered with a 'DataProviderFactory'.
+    pub fn from_directory(directory: &str) -> DataProviderFactoryResult {
+        Ok(Box::new(Self::new(directory)))
+    }
+
+    /// Reads all 'reads' into their buffers, keeping up to 'queue_depth' reads in flight. Errors
+    /// of the ring are only returned once no read is in flight anymore, since the kernel writes
+    /// into the buffers until then.
+    fn read_all(&self, reads: &mut [PendingRead]) -> io::Result<Vec<Option<io::Error>>> {
+        let mut errors: Vec<Option<io::Error>> = (0..reads.len()).map(|_| None).collect();
+        if reads.is_empty() {
+            return Ok(errors);
+        }
+        let num_entries = (reads.len() as u32)
+            .min(self.queue_depth)
+            .next_power_of_two();
+        let mut ring = IoUring::new(num_entries)?;
+        let mut queued: Vec<usize> = (0..reads.len()).rev().collect();
+        let mut in_flight = vec![false; reads.len()];
+        let mut num_in_flight = 0;
+        // Once set, no more reads are submitted and the ones in flight are drained.
+        let mut failure = None;
+        while (failure.is_none() && !queued.is_empty()) || num_in_flight > 0 {
+            while failure.is_none() && num_in_flight < num_entries as usize {
+                let index = match queued.pop() {
+                    Some(index) => index,
+                    None => break,
+                };
+                let read = &mut reads[index];
+                let remaining = &mut read.buffer[read.num_read..];
+                let entry = opcode::Read::new(
+                    types::Fd(read.file.as_raw_fd()),
+                    remaining.as_mut_ptr(),
+                    remaining.len() as u32,
+                )
+                .offset(read.num_read as i64)
+                .build()
+                .user_data(index as u64);
+                // The buffers are allocated before the first submission and never resized, so
+                // they stay valid until the reads complete.
+                match unsafe { ring.submission().push(&entry) } {
+                    Ok(()) => {
+                        in_flight[index] = true;
+                        num_in_flight += 1;
+                    }
+                    Err(_) => {
+                        failure = Some(io::Error::new(io::ErrorKind::Other, "Queue is full."))
+                    }
+                }
+            }
+            if num_in_flight == 0 {
+                break;
+            }
+            match ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    // Without the ring, there is no telling when the reads in flight are done,
+                    // so their buffers and the ring are leaked instead of freed under the kernel.
+                    for (read, in_flight) in reads.iter_mut().zip(in_flight) {
+                        if in_flight {
+                            std::mem::forget(std::mem::take(&mut read.buffer));
+                        }
+                    }
+                    std::mem::forget(ring);
+                    return Err(failure.unwrap_or(err));
+                }
+            }
+            let completed: Vec<(usize, i32)> = ring
+                .completion()
+                .map(|entry| (entry.user_data() as usize, entry.result()))
+                .collect();
+            for (index, result) in completed {
+                in_flight[index] = false;
+                num_in_flight -= 1;
+                let read = &mut reads[index];
+                if result < 0 {
+                    errors[index] = Some(io::Error::from_raw_os_error(-result));
+                } else if result == 0 {
+                    errors[index] = Some(io::ErrorKind::UnexpectedEof.into());
+                } else {
+                    read.num_read += result as usize;
+                    // Short reads are continued where they stopped.
+                    if read.num_read < read.buffer.len() {
+                        queued.push(index);
+                    }
+                }
+            }
+        }
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(errors),
+        }
+    }
+}
+
+impl DataProvider for IoUringDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        self.on_disk.meta_proto()
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        self.data_for_nodes(&[node_id], node_attributes)
+            .pop()
+            .unwrap()
+    }
+
+    fn data_for_nodes(
+        &self,
+        node_ids: &[&str],
+        node_attributes: &[&str],
+    ) -> Vec<Result<HashMap<String, Box<dyn Read + Send>>>> {
+        let mut results: Vec<Result<HashMap<String, Box<dyn Read + Send>>>> =
+            node_ids.iter().map(|_| Ok(HashMap::new())).collect();
+        let mut reads = Vec::new();
+        for (node_index, node_id) in node_ids.iter().enumerate() {
+            let stem = self.on_disk.stem(node_id);
+            for node_attribute in node_attributes {
+                let path = stem.with_extension(attribute_extension(node_attribute));
+                let opened = File::open(&path).and_then(|file| {
+                    let len = file.metadata()?.len() as usize;
+                    Ok((file, len))
+                });
+                match opened {
+                    Ok((file, len)) => reads.push(PendingRead {
+                        node_index,
+                        attribute: (*node_attribute).to_string(),
+                        file,
+                        buffer: vec![0; len],
+                        num_read: 0,
+                    }),
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                        results[node_index] = Err(Error::NodeNotFound);
+                    }
+                    Err(err) => results[node_index] = Err(err.into()),
+                }
+            }
+        }
+        // Nodes that could not be opened completely are not read at all.
+        reads.retain(|read| results[read.node_index].is_ok());
+        // Empty files need no read.
+        let (empty, mut reads): (Vec<_>, Vec<_>) =
+            reads.into_iter().partition(|read| read.buffer.is_empty());
+
+        let errors = match self.read_all(&mut reads) {
+            Ok(errors) => errors,
+            Err(err) => {
+                let message = err.to_string();
+                return node_ids
+                    .iter()
+                    .map(|_| Err(io::Error::new(err.kind(), message.clone()).into()))
+                    .collect();
+            }
+        };
+        let empty = empty.into_iter().map(|read| (read, None));
+        for (read, error) in empty.chain(reads.into_iter().zip(errors)) {
+            let result = &mut results[read.node_index];
+            match error {
+                Some(err) => *result = Err(err.into()),
+                None => {
+                    if let Ok(readers) = result {
+                        let reader: Box<dyn Read + Send> = Box::new(Cursor::new(read.buffer));
+                        readers.insert(read.attribute, reader);
+                    }
+                }
+            }
+        }
+        results
+    }
+}
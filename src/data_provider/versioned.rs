@@ -0,0 +1,135 @@
+//! Reads an octree as of one of its versions, see 'octree::Transaction'.
+
+use crate::attribute_extension;
+use crate::data_provider::{DataProvider, OnDiskDataProvider};
+use crate::errors::*;
+use crate::proto;
+use crate::META_FILENAME;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Directory inside a versioned octree with the meta data of every version and the node files
+/// that later versions replaced or removed, in a subdirectory per version.
+pub const VERSIONS_DIRECTORY: &str = "versions";
+
+/// Reads an octree as it was at one version, even while a later change is made to it: the node
+/// files that the change replaces are archived before their new versions take their place.
+pub struct VersionedDataProvider {
+    on_disk: OnDiskDataProvider,
+    version: u64,
+}
+
+fn version_directory(directory: &Path, version: u64) -> PathBuf {
+    directory.join(VERSIONS_DIRECTORY).join(version.to_string())
+}
+
+/// Whether 'version' of the octree in 'directory' was committed. The meta data of a version
+/// appears atomically, and its existence marks the commit.
+pub fn version_exists(directory: impl AsRef<Path>, version: u64) -> bool {
+    version_directory(directory.as_ref(), version)
+        .join(META_FILENAME)
+        .exists()
+}
+
+/// Returns the newest version of the octree in 'directory', or None if it is not versioned.
+pub fn latest_version(directory: impl AsRef<Path>) -> Option<u64> {
+    let directory = directory.as_ref();
+    fs::read_dir(directory.join(VERSIONS_DIRECTORY))
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|version| version_exists(directory, *version))
+        .max()
+}
+
+impl VersionedDataProvider {
+    pub fn new(directory: impl Into<PathBuf>, version: u64) -> Result<Self> {
+        let directory = directory.into();
+        if !version_exists(&directory, version) {
+            return Err(Error::InvalidInput(format!(
+                "Version {} of the octree in {} does not exist.",
+                version,
+                directory.display()
+            )));
+        }
+        Ok(Self {
+            on_disk: OnDiskDataProvider { directory },
+            version,
+        })
+    }
+
+    /// Opens the newest version of the octree in 'directory'.
+    pub fn latest(directory: impl Into<PathBuf>) -> Result<Self> {
+        let directory = directory.into();
+        let version = latest_version(&directory).ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "The octree in {} is not versioned.",
+                directory.display()
+            ))
+        })?;
+        Self::new(directory, version)
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// A node file is archived in the directory of the last version it belonged to when a later
+    /// version replaces or removes it, so the first archive from 'version' on that has the file
+    /// holds the one of 'version'. Files that were never replaced are in the octree directory.
+    fn open(&self, file_name: &str) -> io::Result<File> {
+        if let Some(file) = self.open_archived(file_name)? {
+            return Ok(file);
+        }
+        let file = File::open(self.on_disk.directory.join(file_name))?;
+        // A running change might have archived the file meanwhile and put its new version in its
+        // place. Archiving comes first, so if the file is still not archived, 'file' is the one of
+        // 'version'.
+        Ok(self.open_archived(file_name)?.unwrap_or(file))
+    }
+
+    fn open_archived(&self, file_name: &str) -> io::Result<Option<File>> {
+        // Versions are numbered without gaps, so the first missing directory ends the search.
+        for version in self.version.. {
+            let directory = version_directory(&self.on_disk.directory, version);
+            if !directory.exists() {
+                break;
+            }
+            match File::open(directory.join(file_name)) {
+                Ok(file) => return Ok(Some(file)),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl DataProvider for VersionedDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        OnDiskDataProvider {
+            directory: version_directory(&self.on_disk.directory, self.version),
+        }
+        .meta_proto()
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        let mut readers = HashMap::<String, Box<dyn Read + Send>>::new();
+        for node_attribute in node_attributes {
+            let file_name = format!("{}.{}", node_id, attribute_extension(node_attribute));
+            let file = match self.open(&file_name) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Err(Error::NodeNotFound);
+                }
+                e => e,
+            }?;
+            readers.insert((*node_attribute).to_string(), Box::new(file));
+        }
+        Ok(readers)
+    }
+}
@@ -12,37 +12,119 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use error_chain::error_chain;
 use std::io;
+use thiserror::Error;
 
-error_chain! {
-    foreign_links {
-        Io(io::Error);
-    }
+pub type Result<T> = std::result::Result<T, Error>;
 
-    errors {
-        InvalidInput(msg: String) {
-            description("The input is not supported or invalid")
-            display("{}", msg)
-        }
+/// The errors of this crate. Match on 'Error::kind' to handle a failure mode, since it looks
+/// through the context that 'ResultExt::with_context' adds.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
 
-        InvalidVersion(version: i32) {
-            description("invalid octree version on disk")
-            display(
-            "Octree in this directory has a version of {}, the only supported version is {}. \
-            Try running upgrade_octree on this data to get it to the current version. \
-            The viewer might eventually be backwards compatible, but for now only \
-            the currently created version is supported.", version, crate::CURRENT_VERSION)
-        }
+    /// The input is invalid, e.g. an argument out of range or a malformed file.
+    #[error("{0}")]
+    InvalidInput(String),
 
-        NodeNotFound {
-            description("The node does not exist.")
-        }
+    /// The input is valid, but uses a format or encoding that is not supported.
+    #[error("{0}")]
+    UnsupportedFormat(String),
+
+    /// Data does not have the attributes or data types that are expected, e.g. when merging
+    /// batches or appending to a file.
+    #[error("{0}")]
+    SchemaMismatch(String),
+
+    #[error(
+        "Octree in this directory has a version of {0}, the only supported version is {}. \
+         Try running upgrade_octree on this data to get it to the current version. \
+         The viewer might eventually be backwards compatible, but for now only \
+         the currently created version is supported.",
+        crate::CURRENT_VERSION
+    )]
+    InvalidVersion(i32),
 
-        Channel(msg: String) {
-            description("The current channel failed an operation")
-            display("{}", msg)
+    #[error("The node does not exist.")]
+    NodeNotFound,
+
+    /// The data of a node does not match its checksum, with the node id and the attribute.
+    #[error("The {1} data of node {0} does not match its checksum.")]
+    CorruptNode(String, String),
+
+    /// Another process writes or reads the octree in this directory.
+    #[error("The octree in {0} is locked by another process.")]
+    OctreeLocked(String),
+
+    /// The current channel failed an operation.
+    #[error("{0}")]
+    Channel(String),
+
+    /// What failed, with the error that caused it.
+    #[error("{message}")]
+    Context {
+        message: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+impl Error {
+    /// The error without the context that was added to it.
+    pub fn kind(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => match source.downcast_ref::<Error>() {
+                Some(error) => error.kind(),
+                None => self,
+            },
+            error => error,
         }
+    }
+}
+
+pub trait ResultExt<T> {
+    /// Wraps the error in 'Error::Context' with the message returned by 'message'.
+    fn with_context<S, F>(self, message: F) -> Result<T>
+    where
+        S: Into<String>,
+        F: FnOnce() -> S;
+}
+
+impl<T, E> ResultExt<T> for std::result::Result<T, E>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    fn with_context<S, F>(self, message: F) -> Result<T>
+    where
+        S: Into<String>,
+        F: FnOnce() -> S,
+    {
+        self.map_err(|err| Error::Context {
+            message: message().into(),
+            source: Box::new(err),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_looks_through_context() {
+        let result: Result<()> = Err(Error::NodeNotFound);
+        let err = result
+            .with_context(|| "Could not read r0.")
+            .with_context(|| "Could not build the octree.")
+            .unwrap_err();
+        assert_eq!("Could not build the octree.", err.to_string());
+        assert!(matches!(err.kind(), Error::NodeNotFound));
 
+        let result: std::result::Result<(), io::Error> = Err(io::ErrorKind::NotFound.into());
+        let err = result
+            .with_context(|| "Could not open meta.pb.")
+            .unwrap_err();
+        assert!(matches!(err.kind(), Error::Context { .. }));
     }
 }
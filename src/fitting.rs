@@ -0,0 +1,313 @@
+//! Fits geometric primitives to points with RANSAC, e.g. to extract floors and walls as planes or
+//! to estimate the radius of poles and trunks as vertical cylinders.
+
+use crate::errors::*;
+use crate::geometry::Sphere;
+use crate::iterator::{PointCloud, PointQuery};
+use crate::{PointsBatch, NUM_POINTS_PER_BATCH};
+use nalgebra::{Matrix2, Matrix3, Point2, Point3, Unit, Vector2, Vector3};
+use rand::seq::index;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+/// A shape that RANSAC can hypothesize from a minimal random sample of points.
+pub trait Primitive: Sized {
+    /// The number of points that determine the primitive.
+    const NUM_SAMPLES: usize;
+
+    /// Returns `None` if the samples are degenerate, e.g. collinear points for a plane.
+    fn from_samples(samples: &[Point3<f64>]) -> Option<Self>;
+
+    /// The distance of `p` from the surface of the primitive.
+    fn distance(&self, p: &Point3<f64>) -> f64;
+
+    /// Improves the primitive found from the samples using all its inliers. The default keeps it.
+    fn refine(self, _inliers: &[Point3<f64>]) -> Self {
+        self
+    }
+}
+
+/// The plane of all points `p` with `normal.dot(p) == offset`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plane {
+    pub normal: Unit<Vector3<f64>>,
+    pub offset: f64,
+}
+
+impl Plane {
+    /// The least squares plane through `points`, or `None` for less than three points.
+    pub fn fit(points: &[Point3<f64>]) -> Option<Self> {
+        if points.len() < 3 {
+            return None;
+        }
+        let centroid = points
+            .iter()
+            .fold(Vector3::zeros(), |sum, p| sum + p.coords)
+            / points.len() as f64;
+        let covariance = points.iter().fold(Matrix3::zeros(), |sum, p| {
+            let d = p.coords - centroid;
+            sum + d * d.transpose()
+        });
+        // The normal is the direction in which the points vary least.
+        let eigen = covariance.symmetric_eigen();
+        let normal = Unit::new_normalize(
+            eigen
+                .eigenvectors
+                .column(eigen.eigenvalues.imin())
+                .into_owned(),
+        );
+        Some(Plane {
+            normal,
+            offset: normal.dot(&centroid),
+        })
+    }
+}
+
+impl Primitive for Plane {
+    const NUM_SAMPLES: usize = 3;
+
+    fn from_samples(samples: &[Point3<f64>]) -> Option<Self> {
+        let normal = (samples[1] - samples[0]).cross(&(samples[2] - samples[0]));
+        let normal = Unit::try_new(normal, std::f64::EPSILON)?;
+        Some(Plane {
+            normal,
+            offset: normal.dot(&samples[0].coords),
+        })
+    }
+
+    fn distance(&self, p: &Point3<f64>) -> f64 {
+        (self.normal.dot(&p.coords) - self.offset).abs()
+    }
+
+    fn refine(self, inliers: &[Point3<f64>]) -> Self {
+        Plane::fit(inliers).unwrap_or(self)
+    }
+}
+
+impl Primitive for Sphere {
+    const NUM_SAMPLES: usize = 4;
+
+    fn from_samples(samples: &[Point3<f64>]) -> Option<Self> {
+        // Subtracting |p_0 - c|² = r² from |p_i - c|² = r² leaves a linear system in c.
+        let p0 = samples[0].coords;
+        let rows: Vec<_> = samples[1..]
+            .iter()
+            .map(|p| 2. * (p.coords - p0).transpose())
+            .collect();
+        let a = Matrix3::from_rows(&rows);
+        let b = Vector3::from_iterator(
+            samples[1..]
+                .iter()
+                .map(|p| p.coords.norm_squared() - p0.norm_squared()),
+        );
+        let center = Point3::from(a.try_inverse()? * b);
+        Some(Sphere::new(
+            center,
+            nalgebra::distance(&center, &samples[0]),
+        ))
+    }
+
+    fn distance(&self, p: &Point3<f64>) -> f64 {
+        (nalgebra::distance(self.center(), p) - self.radius()).abs()
+    }
+}
+
+/// An infinite cylinder along the z axis, e.g. a pole, a tree trunk or a pillar.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerticalCylinder {
+    pub center: Point2<f64>,
+    pub radius: f64,
+}
+
+impl Primitive for VerticalCylinder {
+    const NUM_SAMPLES: usize = 3;
+
+    fn from_samples(samples: &[Point3<f64>]) -> Option<Self> {
+        // The circle through the samples projected into the x-y plane, found like for 'Sphere'.
+        let p0 = samples[0].xy().coords;
+        let d1 = samples[1].xy().coords - p0;
+        let d2 = samples[2].xy().coords - p0;
+        let a = Matrix2::new(2. * d1.x, 2. * d1.y, 2. * d2.x, 2. * d2.y);
+        let b = Vector2::new(
+            samples[1].xy().coords.norm_squared() - p0.norm_squared(),
+            samples[2].xy().coords.norm_squared() - p0.norm_squared(),
+        );
+        let center = Point2::from(a.try_inverse()? * b);
+        Some(VerticalCylinder {
+            center,
+            radius: nalgebra::distance(&center, &samples[0].xy()),
+        })
+    }
+
+    fn distance(&self, p: &Point3<f64>) -> f64 {
+        (nalgebra::distance(&self.center, &p.xy()) - self.radius).abs()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RansacOptions {
+    /// Points at most this far from the primitive are inliers.
+    pub distance_threshold: f64,
+    /// Number of random samples to try.
+    pub num_iterations: usize,
+    /// Seed of the random sampling, so that fits are reproducible.
+    pub seed: u64,
+}
+
+impl Default for RansacOptions {
+    fn default() -> Self {
+        Self {
+            distance_threshold: 0.05,
+            num_iterations: 1000,
+            seed: 0,
+        }
+    }
+}
+
+/// A primitive together with the indices of its inliers.
+#[derive(Clone, Debug)]
+pub struct Fit<T> {
+    pub primitive: T,
+    pub inliers: Vec<usize>,
+}
+
+fn inliers<T: Primitive>(primitive: &T, points: &[Point3<f64>], threshold: f64) -> Vec<usize> {
+    points
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| primitive.distance(p) <= threshold)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Finds the primitive with the most inliers among the ones through random samples of 'points',
+/// and refines it with its inliers. Returns `None` if there are too few points or all samples
+/// were degenerate.
+pub fn ransac<T: Primitive>(points: &[Point3<f64>], options: &RansacOptions) -> Option<Fit<T>> {
+    if points.len() < T::NUM_SAMPLES {
+        return None;
+    }
+    let mut rng = ChaCha8Rng::seed_from_u64(options.seed);
+    let mut best: Option<(T, usize)> = None;
+    let mut samples = Vec::with_capacity(T::NUM_SAMPLES);
+    for _ in 0..options.num_iterations {
+        samples.clear();
+        samples.extend(
+            index::sample(&mut rng, points.len(), T::NUM_SAMPLES)
+                .iter()
+                .map(|i| points[i]),
+        );
+        let candidate = match T::from_samples(&samples) {
+            Some(candidate) => candidate,
+            None => continue,
+        };
+        let num_inliers = points
+            .iter()
+            .filter(|p| candidate.distance(p) <= options.distance_threshold)
+            .count();
+        if best.as_ref().map_or(true, |(_, best)| num_inliers > *best) {
+            best = Some((candidate, num_inliers));
+        }
+    }
+    let (primitive, _) = best?;
+    let inlier_points: Vec<_> = inliers(&primitive, points, options.distance_threshold)
+        .into_iter()
+        .map(|i| points[i])
+        .collect();
+    let primitive = primitive.refine(&inlier_points);
+    Some(Fit {
+        inliers: inliers(&primitive, points, options.distance_threshold),
+        primitive,
+    })
+}
+
+/// Fits a primitive to the points of 'point_cloud' that match 'query' and returns it together with
+/// its inlier points and their attributes. All matching points are held in memory, so the query
+/// should be limited to the region of interest.
+pub fn fit_in_query<T, C>(
+    point_cloud: &C,
+    query: &PointQuery,
+    options: &RansacOptions,
+) -> Result<Option<(T, PointsBatch)>>
+where
+    T: Primitive,
+    C: PointCloud,
+{
+    let mut points = PointsBatch::default();
+    for node_id in point_cloud.nodes_in_location(&query.location) {
+        point_cloud.stream_points_for_query_in_node(
+            query,
+            node_id,
+            NUM_POINTS_PER_BATCH,
+            |mut batch| points.append(&mut batch).map_err(Error::SchemaMismatch),
+        )?;
+    }
+    Ok(ransac::<T>(&points.position, options).map(|fit| {
+        let mut keep = vec![false; points.position.len()];
+        for i in fit.inliers {
+            keep[i] = true;
+        }
+        points.retain(&keep);
+        (fit.primitive, points)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn test_plane_with_outliers() {
+        // A slightly tilted 10x10 grid on z = 0.1 * x + 2, and some points above it.
+        let mut points: Vec<_> = (0..100)
+            .map(|i| {
+                let (x, y) = (f64::from(i % 10), f64::from(i / 10));
+                Point3::new(x, y, 0.1 * x + 2.)
+            })
+            .collect();
+        points.extend((0..20).map(|i| Point3::new(f64::from(i % 5), f64::from(i / 5), 10.)));
+        let fit = ransac::<Plane>(&points, &RansacOptions::default()).unwrap();
+        assert_eq!(fit.inliers, (0..100).collect::<Vec<_>>());
+        let expected = Unit::new_normalize(Vector3::new(-0.1, 0., 1.));
+        let sign = fit.primitive.normal.dot(&expected).signum();
+        assert_abs_diff_eq!(
+            sign * fit.primitive.normal.into_inner(),
+            expected.into_inner(),
+            epsilon = 1e-9
+        );
+        assert_abs_diff_eq!(
+            fit.primitive.distance(&Point3::new(3., 7., 2.3)),
+            0.,
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_sphere_and_cylinder_from_samples() {
+        let sphere = Sphere::from_samples(&[
+            Point3::new(3., 2., 3.),
+            Point3::new(1., 4., 3.),
+            Point3::new(1., 2., 5.),
+            Point3::new(-1., 2., 3.),
+        ])
+        .unwrap();
+        assert_abs_diff_eq!(*sphere.center(), Point3::new(1., 2., 3.), epsilon = 1e-9);
+        assert_abs_diff_eq!(sphere.radius(), 2., epsilon = 1e-9);
+
+        let cylinder = VerticalCylinder::from_samples(&[
+            Point3::new(5.5, 0., 0.),
+            Point3::new(5., 0.5, 7.),
+            Point3::new(4.5, 0., -3.),
+        ])
+        .unwrap();
+        assert_abs_diff_eq!(cylinder.center, Point2::new(5., 0.), epsilon = 1e-9);
+        assert_abs_diff_eq!(cylinder.radius, 0.5, epsilon = 1e-9);
+        assert!(VerticalCylinder::from_samples(&[
+            Point3::new(0., 0., 0.),
+            Point3::new(1., 1., 0.),
+            Point3::new(2., 2., 5.),
+        ])
+        .is_none());
+    }
+}
@@ -0,0 +1,329 @@
+//! Captures of which nodes a viewer requested, received and rendered in every frame, so that
+//! streaming bugs of remote users can be analysed and reproduced locally without access to their
+//! data. A capture is a JSON lines file with one 'FrameRecord' per frame, which the SDL viewer
+//! writes with '--record-frames' and 'point_cloud_tool replay-frames' replays.
+
+use crate::errors::*;
+use crate::octree::{
+    NodeId, Octree, ScreenSpaceError, DEFAULT_VIEWPORT_HEIGHT, DEFAULT_VIEWPORT_WIDTH,
+};
+use nalgebra::Matrix4;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The events of one frame. Node ids are kept as strings, so that captures with ids that a
+/// client got wrong can still be read.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrameRecord {
+    /// Index of the frame since the recording started. Frames without events are left out.
+    pub frame: u64,
+    /// Milliseconds since the recording started.
+    pub time_ms: f64,
+    /// The matrix from the octree frame to clip space in column major order, if the camera
+    /// changed in this frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<Matrix4<f64>>,
+    /// Width and height of the viewport in pixels, if the camera changed in this frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub viewport: Option<(f64, f64)>,
+    /// Nodes whose data was requested.
+    #[serde(default)]
+    pub requested: Vec<String>,
+    /// Nodes whose data arrived.
+    #[serde(default)]
+    pub received: Vec<String>,
+    /// Nodes that were drawn, with the level of detail they were drawn at, see
+    /// 'NodeMeta::num_points_for_level_of_detail'.
+    #[serde(default)]
+    pub rendered: Vec<(String, i32)>,
+}
+
+impl FrameRecord {
+    pub fn is_empty(&self) -> bool {
+        self.camera.is_none()
+            && self.requested.is_empty()
+            && self.received.is_empty()
+            && self.rendered.is_empty()
+    }
+}
+
+struct RecorderState {
+    writer: BufWriter<File>,
+    start: Instant,
+    frame: u64,
+    current: FrameRecord,
+}
+
+/// Writes a capture while a viewer runs. It can be shared by the threads of a viewer, e.g. the
+/// renderer and the loader of nodes.
+pub struct FrameRecorder {
+    state: Mutex<RecorderState>,
+}
+
+impl FrameRecorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Could not create {}.", path.display()))?;
+        Ok(FrameRecorder {
+            state: Mutex::new(RecorderState {
+                writer: BufWriter::new(file),
+                start: Instant::now(),
+                frame: 0,
+                current: FrameRecord::default(),
+            }),
+        })
+    }
+
+    pub fn camera_changed(&self, projection_matrix: &Matrix4<f64>, width: f64, height: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.current.camera = Some(*projection_matrix);
+        state.current.viewport = Some((width, height));
+    }
+
+    pub fn requested(&self, node_id: &NodeId) {
+        let mut state = self.state.lock().unwrap();
+        state.current.requested.push(node_id.to_string());
+    }
+
+    pub fn received(&self, node_id: &NodeId) {
+        let mut state = self.state.lock().unwrap();
+        state.current.received.push(node_id.to_string());
+    }
+
+    pub fn rendered(&self, node_id: &NodeId, level_of_detail: i32) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .current
+            .rendered
+            .push((node_id.to_string(), level_of_detail));
+    }
+
+    /// Writes the events since the last call as one frame, unless there were none, and starts
+    /// the next frame. The file is flushed, so the capture survives a crash of the viewer.
+    pub fn end_frame(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut record = std::mem::take(&mut state.current);
+        record.frame = state.frame;
+        state.frame += 1;
+        if record.is_empty() {
+            return Ok(());
+        }
+        record.time_ms = state.start.elapsed().as_secs_f64() * 1000.;
+        serde_json::to_writer(&mut state.writer, &record)
+            .map_err(|e| Error::InvalidInput(e.to_string()))?;
+        state.writer.write_all(b"\n")?;
+        state.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the frames of a capture written by a 'FrameRecorder'.
+pub fn read_frame_capture(path: impl AsRef<Path>) -> Result<Vec<FrameRecord>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Could not open {}.", path.display()))?;
+    let mut records = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|e| {
+            Error::InvalidInput(format!("Line {} of {}: {}", index + 1, path.display(), e))
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// A problem of the streaming that a replay found.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamingIssue {
+    /// The node was drawn before any of its data arrived.
+    RenderedBeforeReceived(String),
+    /// Data arrived for a node that had not been requested.
+    ReceivedUnrequested(String),
+    /// The node was requested again while an earlier request was pending.
+    DuplicateRequest(String),
+    /// The node does not exist in the octree that the capture is replayed against, e.g. because
+    /// it is another version than the one of the user.
+    UnknownNode(String),
+}
+
+impl std::fmt::Display for StreamingIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StreamingIssue::RenderedBeforeReceived(id) => {
+                write!(f, "{} was rendered before it was received", id)
+            }
+            StreamingIssue::ReceivedUnrequested(id) => {
+                write!(f, "{} was received without a request", id)
+            }
+            StreamingIssue::DuplicateRequest(id) => {
+                write!(
+                    f,
+                    "{} was requested again while its request was pending",
+                    id
+                )
+            }
+            StreamingIssue::UnknownNode(id) => write!(f, "{} is not in the octree", id),
+        }
+    }
+}
+
+/// The state of the streaming after a recorded frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameReplay {
+    pub frame: u64,
+    pub time_ms: f64,
+    pub num_requested: usize,
+    pub num_received: usize,
+    pub num_rendered: usize,
+    /// Requests that are still waiting for their data.
+    pub num_pending: usize,
+    /// With an octree and a camera change in this frame: the number of nodes that the octree
+    /// selects for the camera and how many of them had been received by the end of the frame.
+    pub selection: Option<(usize, usize)>,
+    pub issues: Vec<StreamingIssue>,
+}
+
+/// Steps through the frames of a capture and tracks the state of every node. Within a frame, the
+/// requests are processed before the arrivals and the arrivals before the drawing. Nodes that a
+/// viewer evicts from its cache are not recorded, so they count as received until they are
+/// requested again.
+///
+/// With an 'octree', e.g. a local copy of the one the user viewed, the node ids are checked
+/// against it, and the nodes it selects for every recorded camera are compared to the received
+/// ones, using the recorded viewport and 'pixel_threshold' for the 'ScreenSpaceError'.
+pub fn replay_frame_capture(
+    records: &[FrameRecord],
+    octree: Option<&Octree>,
+    pixel_threshold: f64,
+) -> Vec<FrameReplay> {
+    let mut pending = HashSet::new();
+    let mut received = HashSet::new();
+    records
+        .iter()
+        .map(|record| {
+            let mut issues = Vec::new();
+            if let Some(octree) = octree {
+                let ids = record
+                    .requested
+                    .iter()
+                    .chain(&record.received)
+                    .chain(record.rendered.iter().map(|(id, _)| id));
+                for id in ids {
+                    let known = id
+                        .parse::<NodeId>()
+                        .map_or(false, |node_id| octree.get_node_meta(&node_id).is_some());
+                    if !known {
+                        issues.push(StreamingIssue::UnknownNode(id.clone()));
+                    }
+                }
+            }
+            for id in &record.requested {
+                if !pending.insert(id.clone()) {
+                    issues.push(StreamingIssue::DuplicateRequest(id.clone()));
+                }
+                received.remove(id);
+            }
+            for id in &record.received {
+                if !pending.remove(id) {
+                    issues.push(StreamingIssue::ReceivedUnrequested(id.clone()));
+                }
+                received.insert(id.clone());
+            }
+            for (id, _) in &record.rendered {
+                if !received.contains(id) {
+                    issues.push(StreamingIssue::RenderedBeforeReceived(id.clone()));
+                }
+            }
+            issues.dedup();
+            let selection = match (octree, &record.camera) {
+                (Some(octree), Some(camera)) => {
+                    let (width, height) = record
+                        .viewport
+                        .unwrap_or((DEFAULT_VIEWPORT_WIDTH, DEFAULT_VIEWPORT_HEIGHT));
+                    let metric =
+                        ScreenSpaceError::new(width, height).with_pixel_threshold(pixel_threshold);
+                    let selected =
+                        octree.get_visible_nodes_with_screen_space_error(camera, &metric);
+                    let num_loaded = selected
+                        .iter()
+                        .filter(|(node_id, _)| received.contains(&node_id.to_string()))
+                        .count();
+                    Some((selected.len(), num_loaded))
+                }
+                _ => None,
+            };
+            FrameReplay {
+                frame: record.frame,
+                time_ms: record.time_ms,
+                num_requested: record.requested.len(),
+                num_received: record.received.len(),
+                num_rendered: record.rendered.len(),
+                num_pending: pending.len(),
+                selection,
+                issues,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_record_and_replay() {
+        let tmp_dir = TempDir::new("frame_capture").unwrap();
+        let path = tmp_dir.path().join("capture.jsonl");
+        let root = NodeId::root();
+        let child: NodeId = "r0".parse().unwrap();
+        {
+            let recorder = FrameRecorder::create(&path).unwrap();
+            recorder.camera_changed(&Matrix4::identity(), 800., 600.);
+            recorder.requested(&root);
+            recorder.end_frame().unwrap();
+            // Nothing happens in this frame.
+            recorder.end_frame().unwrap();
+            recorder.received(&root);
+            recorder.rendered(&root, 1);
+            recorder.rendered(&child, 1);
+            recorder.requested(&child);
+            recorder.end_frame().unwrap();
+            recorder.requested(&child);
+            recorder.received(&child);
+            recorder.end_frame().unwrap();
+        }
+
+        let records = read_frame_capture(&path).unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.frame).collect::<Vec<_>>(),
+            vec![0, 2, 3]
+        );
+        assert_eq!(records[0].camera, Some(Matrix4::identity()));
+        assert_eq!(records[0].viewport, Some((800., 600.)));
+
+        let replay = replay_frame_capture(&records, None, 1.);
+        assert!(replay[0].issues.is_empty());
+        assert_eq!(replay[0].num_pending, 1);
+        assert_eq!(
+            replay[1].issues,
+            vec![StreamingIssue::RenderedBeforeReceived("r0".to_string())]
+        );
+        assert_eq!(replay[1].num_pending, 1);
+        assert_eq!(
+            replay[2].issues,
+            vec![StreamingIssue::DuplicateRequest("r0".to_string())]
+        );
+        assert_eq!(replay[2].num_pending, 0);
+    }
+}
@@ -0,0 +1,239 @@
+//! A corridor around a polyline in the x-y plane, extruded along z.
+
+use crate::geometry::polygon::segments_intersect;
+use crate::geometry::Aabb;
+use crate::math::base::{HasAabbIntersector, IntersectAabb, PointCulling};
+use nalgebra::{Point2, Point3};
+use serde::{Deserialize, Serialize};
+
+/// All points whose horizontal distance to a polyline, e.g. the center line of a road or track,
+/// is at most `half_width` and whose z coordinate lies in `[min_z, max_z]`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Corridor {
+    polyline: Vec<Point2<f64>>,
+    half_width: f64,
+    min_z: f64,
+    max_z: f64,
+}
+
+/// The position of a point relative to the polyline of a corridor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StationOffset {
+    /// Distance along the polyline to the point on it that is closest to the point.
+    pub station: f64,
+    /// Signed horizontal distance to the polyline, positive on the left in the direction of the
+    /// polyline.
+    pub offset: f64,
+}
+
+impl Corridor {
+    /// Returns `None` if there are less than two vertices.
+    pub fn new(
+        polyline: Vec<Point2<f64>>,
+        half_width: f64,
+        min_z: f64,
+        max_z: f64,
+    ) -> Option<Self> {
+        if polyline.len() < 2 {
+            return None;
+        }
+        Some(Corridor {
+            polyline,
+            half_width: half_width.abs(),
+            min_z: min_z.min(max_z),
+            max_z: min_z.max(max_z),
+        })
+    }
+
+    pub fn polyline(&self) -> &[Point2<f64>] {
+        &self.polyline
+    }
+
+    pub fn half_width(&self) -> f64 {
+        self.half_width
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        let first = &self.polyline[0];
+        let mut aabb = Aabb::new(
+            Point3::new(first.x, first.y, self.min_z),
+            Point3::new(first.x, first.y, self.max_z),
+        );
+        for v in &self.polyline[1..] {
+            aabb.grow(Point3::new(v.x, v.y, self.min_z));
+        }
+        let (min, max) = (aabb.min(), aabb.max());
+        Aabb::new(
+            Point3::new(min.x - self.half_width, min.y - self.half_width, min.z),
+            Point3::new(max.x + self.half_width, max.y + self.half_width, max.z),
+        )
+    }
+
+    fn segments(&self) -> impl Iterator<Item = (&Point2<f64>, &Point2<f64>)> {
+        self.polyline.iter().zip(self.polyline.iter().skip(1))
+    }
+
+    /// Projects `p` onto the closest segment of the polyline. Where segments meet at an angle, the
+    /// earlier segment wins ties.
+    pub fn station_offset(&self, p: &Point2<f64>) -> StationOffset {
+        let mut closest = (
+            std::f64::INFINITY,
+            StationOffset {
+                station: 0.,
+                offset: 0.,
+            },
+        );
+        let mut segment_start = 0.;
+        for (a, b) in self.segments() {
+            let length = (b - a).norm();
+            let (t, on_segment) = closest_point_on_segment(p, a, b);
+            let distance = (p - on_segment).norm();
+            if distance < closest.0 {
+                let side = if (b - a).perp(&(p - a)) < 0. { -1. } else { 1. };
+                closest = (
+                    distance,
+                    StationOffset {
+                        station: segment_start + t * length,
+                        offset: side * distance,
+                    },
+                );
+            }
+            segment_start += length;
+        }
+        closest.1
+    }
+
+    fn distance_2d(&self, p: &Point2<f64>) -> f64 {
+        self.segments()
+            .map(|(a, b)| (p - closest_point_on_segment(p, a, b).1).norm())
+            .fold(std::f64::INFINITY, f64::min)
+    }
+}
+
+/// Returns the parameter in `[0, 1]` along `a`-`b` and the point of the segment closest to `p`.
+fn closest_point_on_segment(
+    p: &Point2<f64>,
+    a: &Point2<f64>,
+    b: &Point2<f64>,
+) -> (f64, Point2<f64>) {
+    let ab = b - a;
+    let length_squared = ab.norm_squared();
+    if length_squared == 0. {
+        return (0., *a);
+    }
+    let t = ((p - a).dot(&ab) / length_squared).max(0.).min(1.);
+    (t, a + ab * t)
+}
+
+/// The distance between the segment `a`-`b` and the rectangle from `min` to `max`.
+fn segment_rect_distance(
+    a: &Point2<f64>,
+    b: &Point2<f64>,
+    min: &Point2<f64>,
+    max: &Point2<f64>,
+) -> f64 {
+    let in_rect = |p: &Point2<f64>| min.x <= p.x && p.x <= max.x && min.y <= p.y && p.y <= max.y;
+    let corners = [
+        *min,
+        Point2::new(max.x, min.y),
+        *max,
+        Point2::new(min.x, max.y),
+    ];
+    if in_rect(a)
+        || in_rect(b)
+        || (0..4).any(|i| segments_intersect(a, b, &corners[i], &corners[(i + 1) % 4]))
+    {
+        return 0.;
+    }
+    // Without an intersection, the closest pair involves an endpoint or a corner.
+    let endpoint_distance = |p: &Point2<f64>| (p - p.sup(min).inf(max)).norm();
+    let corner_distance = |c: &Point2<f64>| (c - closest_point_on_segment(c, a, b).1).norm();
+    corners
+        .iter()
+        .map(corner_distance)
+        .chain([a, b].iter().map(|p| endpoint_distance(*p)))
+        .fold(std::f64::INFINITY, f64::min)
+}
+
+impl PointCulling for Corridor {
+    fn contains(&self, p: &Point3<f64>) -> bool {
+        self.min_z <= p.z && p.z <= self.max_z && self.distance_2d(&p.xy()) <= self.half_width
+    }
+}
+
+impl IntersectAabb for Corridor {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        if aabb.max().z < self.min_z || self.max_z < aabb.min().z {
+            return false;
+        }
+        let (min, max) = (aabb.min().xy(), aabb.max().xy());
+        self.segments()
+            .any(|(a, b)| segment_rect_distance(a, b, &min, &max) <= self.half_width)
+    }
+}
+
+impl<'a> HasAabbIntersector<'a> for Corridor {
+    type Intersector = &'a Corridor;
+    fn aabb_intersector(&'a self) -> Self::Intersector {
+        self
+    }
+}
+
+impl IntersectAabb for &Corridor {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        (*self).intersect_aabb(aabb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corridor() {
+        // Along +x, then turning left along +y.
+        let corridor = Corridor::new(
+            vec![
+                Point2::new(0., 0.),
+                Point2::new(10., 0.),
+                Point2::new(10., 10.),
+            ],
+            1.,
+            -5.,
+            5.,
+        )
+        .unwrap();
+        assert!(corridor.contains(&Point3::new(5., 0.9, 0.)));
+        assert!(corridor.contains(&Point3::new(10.9, 5., 0.)));
+        assert!(!corridor.contains(&Point3::new(5., 1.1, 0.)));
+        assert!(!corridor.contains(&Point3::new(5., 0., 6.)));
+
+        let left = corridor.station_offset(&Point2::new(4., 0.5));
+        assert_eq!(
+            left,
+            StationOffset {
+                station: 4.,
+                offset: 0.5
+            }
+        );
+        let right = corridor.station_offset(&Point2::new(10.5, 3.));
+        assert_eq!(
+            right,
+            StationOffset {
+                station: 13.,
+                offset: -0.5
+            }
+        );
+
+        let near = Aabb::new(Point3::new(1., 1.5, 0.), Point3::new(2., 3., 1.));
+        let inside_bend = Aabb::new(Point3::new(2., 2., 0.), Point3::new(8., 8., 1.));
+        let crossing = Aabb::new(Point3::new(4., -2., 0.), Point3::new(5., 2., 1.));
+        assert!(!corridor.intersect_aabb(&near));
+        assert!(!corridor.intersect_aabb(&inside_bend));
+        assert!(corridor.intersect_aabb(&crossing));
+        assert!(corridor.intersect_aabb(&Aabb::new(
+            Point3::new(10.5, 10.5, 0.),
+            Point3::new(12., 12., 1.),
+        )));
+    }
+}
@@ -1,12 +1,18 @@
 //! Contains geometric primitives, e.g. for defining queries against the point cloud.
 mod aabb;
+mod corridor;
 mod frustum;
 mod obb;
+mod polygon;
 mod s2_cell_union;
+mod sphere;
 mod web_mercator_rect;
 
 pub use aabb::*;
+pub use corridor::*;
 pub use frustum::*;
 pub use obb::*;
+pub use polygon::*;
 pub use s2_cell_union::*;
+pub use sphere::*;
 pub use web_mercator_rect::*;
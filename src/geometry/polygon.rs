@@ -0,0 +1,153 @@
+//! A polygon in the x-y plane, extruded along z.
+
+use crate::geometry::Aabb;
+use crate::math::base::{HasAabbIntersector, IntersectAabb, PointCulling};
+use nalgebra::{Point2, Point3};
+use serde::{Deserialize, Serialize};
+
+/// A simple (i.e. not self-intersecting) polygon in the x-y plane, which contains all points
+/// above it whose z coordinate lies in `[min_z, max_z]`. The polygon does not need to be convex,
+/// and the last vertex is implicitly connected to the first one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Polygon {
+    vertices: Vec<Point2<f64>>,
+    min_z: f64,
+    max_z: f64,
+}
+
+impl Polygon {
+    /// Returns `None` if there are less than three vertices.
+    pub fn new(vertices: Vec<Point2<f64>>, min_z: f64, max_z: f64) -> Option<Self> {
+        if vertices.len() < 3 {
+            return None;
+        }
+        Some(Polygon {
+            vertices,
+            min_z: min_z.min(max_z),
+            max_z: min_z.max(max_z),
+        })
+    }
+
+    pub fn vertices(&self) -> &[Point2<f64>] {
+        &self.vertices
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        let first = &self.vertices[0];
+        let mut aabb = Aabb::new(
+            Point3::new(first.x, first.y, self.min_z),
+            Point3::new(first.x, first.y, self.max_z),
+        );
+        for v in &self.vertices[1..] {
+            aabb.grow(Point3::new(v.x, v.y, self.min_z));
+        }
+        aabb
+    }
+
+    fn edges(&self) -> impl Iterator<Item = (&Point2<f64>, &Point2<f64>)> {
+        self.vertices
+            .iter()
+            .zip(self.vertices.iter().cycle().skip(1))
+    }
+
+    /// Even-odd rule: count how many edges a ray in +x direction crosses.
+    fn contains_2d(&self, p: &Point2<f64>) -> bool {
+        self.edges().fold(false, |inside, (a, b)| {
+            if (a.y > p.y) != (b.y > p.y) && p.x < (b.x - a.x) * (p.y - a.y) / (b.y - a.y) + a.x {
+                !inside
+            } else {
+                inside
+            }
+        })
+    }
+}
+
+/// Returns true if the segments `p0`-`p1` and `q0`-`q1` intersect.
+pub(crate) fn segments_intersect(
+    p0: &Point2<f64>,
+    p1: &Point2<f64>,
+    q0: &Point2<f64>,
+    q1: &Point2<f64>,
+) -> bool {
+    let orientation =
+        |a: &Point2<f64>, b: &Point2<f64>, c: &Point2<f64>| ((b - a).perp(&(c - a))).signum();
+    orientation(p0, p1, q0) != orientation(p0, p1, q1)
+        && orientation(q0, q1, p0) != orientation(q0, q1, p1)
+}
+
+impl PointCulling for Polygon {
+    fn contains(&self, p: &Point3<f64>) -> bool {
+        self.min_z <= p.z && p.z <= self.max_z && self.contains_2d(&p.xy())
+    }
+}
+
+impl IntersectAabb for Polygon {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        if aabb.max().z < self.min_z || self.max_z < aabb.min().z {
+            return false;
+        }
+        let (min, max) = (aabb.min().xy(), aabb.max().xy());
+        let in_rect =
+            |p: &Point2<f64>| min.x <= p.x && p.x <= max.x && min.y <= p.y && p.y <= max.y;
+        if self.vertices.iter().any(in_rect) {
+            return true;
+        }
+        let corners = [
+            min,
+            Point2::new(max.x, min.y),
+            max,
+            Point2::new(min.x, max.y),
+        ];
+        if corners.iter().any(|c| self.contains_2d(c)) {
+            return true;
+        }
+        // Neither contains a vertex of the other, so they can only overlap if edges cross.
+        self.edges().any(|(a, b)| {
+            (0..4).any(|i| segments_intersect(a, b, &corners[i], &corners[(i + 1) % 4]))
+        })
+    }
+}
+
+impl<'a> HasAabbIntersector<'a> for Polygon {
+    type Intersector = &'a Polygon;
+    fn aabb_intersector(&'a self) -> Self::Intersector {
+        self
+    }
+}
+
+impl IntersectAabb for &Polygon {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        (*self).intersect_aabb(aabb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concave_polygon() {
+        // An L shape, with the upper right quadrant of the 2x2 square missing.
+        let polygon = Polygon::new(
+            vec![
+                Point2::new(0., 0.),
+                Point2::new(2., 0.),
+                Point2::new(2., 1.),
+                Point2::new(1., 1.),
+                Point2::new(1., 2.),
+                Point2::new(0., 2.),
+            ],
+            0.,
+            1.,
+        )
+        .unwrap();
+        assert!(polygon.contains(&Point3::new(0.5, 1.5, 0.5)));
+        assert!(!polygon.contains(&Point3::new(1.5, 1.5, 0.5)));
+        assert!(!polygon.contains(&Point3::new(0.5, 0.5, 1.5)));
+
+        let in_notch = Aabb::new(Point3::new(1.2, 1.2, 0.), Point3::new(1.8, 1.8, 1.));
+        let crossing = Aabb::new(Point3::new(0.5, -1., 0.), Point3::new(0.6, 3., 1.));
+        assert!(!polygon.intersect_aabb(&in_notch));
+        assert!(polygon.intersect_aabb(&crossing));
+    }
+}
@@ -0,0 +1,78 @@
+//! A sphere.
+
+use crate::geometry::Aabb;
+use crate::math::base::{HasAabbIntersector, IntersectAabb, PointCulling};
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+/// A ball around `center`, including its surface.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sphere {
+    center: Point3<f64>,
+    radius: f64,
+}
+
+impl Sphere {
+    pub fn new(center: Point3<f64>, radius: f64) -> Self {
+        Sphere {
+            center,
+            radius: radius.abs(),
+        }
+    }
+
+    pub fn center(&self) -> &Point3<f64> {
+        &self.center
+    }
+
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        let extent = Vector3::repeat(self.radius);
+        Aabb::new(self.center - extent, self.center + extent)
+    }
+}
+
+impl PointCulling for Sphere {
+    fn contains(&self, p: &Point3<f64>) -> bool {
+        nalgebra::distance_squared(&self.center, p) <= self.radius * self.radius
+    }
+}
+
+impl IntersectAabb for Sphere {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        // The point of the box closest to the center decides.
+        let closest = self.center.sup(aabb.min()).inf(aabb.max());
+        self.contains(&closest)
+    }
+}
+
+impl<'a> HasAabbIntersector<'a> for Sphere {
+    type Intersector = &'a Sphere;
+    fn aabb_intersector(&'a self) -> Self::Intersector {
+        self
+    }
+}
+
+impl IntersectAabb for &Sphere {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        (*self).intersect_aabb(aabb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sphere_intersects_aabb() {
+        let sphere = Sphere::new(Point3::new(0., 0., 0.), 1.);
+        let touching = Aabb::new(Point3::new(0.5, 0.5, -1.), Point3::new(2., 2., 1.));
+        let corner_outside = Aabb::new(Point3::new(0.8, 0.8, 0.8), Point3::new(2., 2., 2.));
+        assert!(sphere.intersect_aabb(&touching));
+        assert!(!sphere.intersect_aabb(&corner_outside));
+        assert!(sphere.contains(&Point3::new(0., 0., 1.)));
+        assert!(!sphere.contains(&Point3::new(0.6, 0.6, 0.6)));
+    }
+}
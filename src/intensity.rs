@@ -0,0 +1,234 @@
+//! Normalization of the raw sensor intensity. Its range differs between sensors and even between
+//! scans of the same sensor, so coloring by raw intensity rarely shows anything. The normalized
+//! values are in [0, 1] and are stored next to the raw ones.
+
+use crate::{match_1d_attr_data, AttributeData, NumberOfPoints, PointsBatch};
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// Name of the attribute that holds the normalized intensity.
+pub const NORMALIZED_INTENSITY: &str = "normalized_intensity";
+
+/// Maximum number of intensities per scan that normalizers are computed from.
+const MAX_SAMPLES: usize = 1_000_000;
+
+/// How to map the raw intensities of a scan to [0, 1].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntensityNormalization {
+    /// Maps the 'low' to the 'high' percentile linearly and clips the values outside, e.g. 1 and
+    /// 99 to ignore outliers.
+    PercentileClip { low: f64, high: f64 },
+    /// Histogram equalization: a value is mapped to the fraction of the points of the scan with a
+    /// lower intensity, which spreads the values evenly over the whole range.
+    Equalize,
+}
+
+impl FromStr for IntensityNormalization {
+    type Err = String;
+
+    /// Parses 'equalize' or 'clip:<low>,<high>' with percentiles, e.g. 'clip:1,99'.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "equalize" {
+            return Ok(IntensityNormalization::Equalize);
+        }
+        let percentiles = s
+            .strip_prefix("clip:")
+            .ok_or_else(|| format!("Unknown intensity normalization '{}'.", s))?;
+        let bounds = percentiles
+            .split(',')
+            .map(|p| p.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid percentiles '{}': {}", percentiles, e))?;
+        match bounds[..] {
+            [low, high] if 0. <= low && low < high && high <= 100. => {
+                Ok(IntensityNormalization::PercentileClip { low, high })
+            }
+            _ => Err(format!(
+                "Expected two increasing percentiles between 0 and 100, got '{}'.",
+                percentiles
+            )),
+        }
+    }
+}
+
+/// Normalizes the intensities of one scan. It is computed from a sample of the intensities of
+/// the scan.
+#[derive(Clone, Debug)]
+pub struct IntensityNormalizer {
+    normalization: IntensityNormalization,
+    // Sorted, without NaN.
+    samples: Vec<f32>,
+    low: f32,
+    high: f32,
+}
+
+impl IntensityNormalizer {
+    pub fn new(normalization: IntensityNormalization, mut samples: Vec<f32>) -> Self {
+        samples.retain(|s| !s.is_nan());
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let percentile = |p: f64| -> f32 {
+            if samples.is_empty() {
+                return 0.;
+            }
+            let index = (p / 100. * (samples.len() - 1) as f64).round() as usize;
+            samples[index]
+        };
+        let (low, high) = match normalization {
+            IntensityNormalization::PercentileClip { low, high } => {
+                (percentile(low), percentile(high))
+            }
+            IntensityNormalization::Equalize => (percentile(0.), percentile(100.)),
+        };
+        Self {
+            normalization,
+            samples,
+            low,
+            high,
+        }
+    }
+
+    /// Reads the intensities of all points of 'stream', but keeps at most 'MAX_SAMPLES' evenly
+    /// spaced ones.
+    pub fn from_stream(
+        normalization: IntensityNormalization,
+        stream: impl Iterator<Item = PointsBatch> + NumberOfPoints,
+    ) -> Self {
+        let step = (stream.num_points() + MAX_SAMPLES - 1) / MAX_SAMPLES;
+        let step = step.max(1);
+        let mut samples = Vec::new();
+        let mut index = 0;
+        for batch in stream {
+            if let Some(intensity) = batch.attributes.get("intensity") {
+                let values = intensities(intensity);
+                let first = (step - index % step) % step;
+                samples.extend(values.iter().skip(first).step_by(step));
+                index += values.len();
+            }
+        }
+        Self::new(normalization, samples)
+    }
+
+    pub fn normalize(&self, intensity: f32) -> f32 {
+        if intensity.is_nan() || self.samples.is_empty() {
+            return intensity;
+        }
+        match self.normalization {
+            IntensityNormalization::PercentileClip { .. } => {
+                if self.high <= self.low {
+                    return 0.;
+                }
+                ((intensity - self.low) / (self.high - self.low))
+                    .max(0.)
+                    .min(1.)
+            }
+            IntensityNormalization::Equalize => {
+                // Never finds an equal element, so the error is the number of lower samples.
+                let num_lower = self
+                    .samples
+                    .binary_search_by(|s| {
+                        if *s < intensity {
+                            Ordering::Less
+                        } else {
+                            Ordering::Greater
+                        }
+                    })
+                    .unwrap_err();
+                num_lower as f32 / self.samples.len() as f32
+            }
+        }
+    }
+
+    /// Adds the attribute 'NORMALIZED_INTENSITY' to 'batch' if it has an intensity.
+    pub fn add_normalized(&self, batch: &mut PointsBatch) {
+        let normalized = match batch.attributes.get("intensity") {
+            Some(intensity) => intensities(intensity)
+                .into_iter()
+                .map(|i| self.normalize(i))
+                .collect(),
+            None => return,
+        };
+        batch.attributes.insert(
+            NORMALIZED_INTENSITY.to_string(),
+            AttributeData::F32(normalized),
+        );
+    }
+}
+
+/// The values of a scalar attribute as 'f32', or nothing for vector attributes.
+pub(crate) fn intensities(data: &AttributeData) -> Vec<f32> {
+    macro_rules! rhs {
+        ($dtype:ident, $data:ident) => {
+            $data.iter().map(|i| *i as f32).collect()
+        };
+    }
+    match data {
+        AttributeData::U8Vec3(_) | AttributeData::F64Vec3(_) => Vec::new(),
+        data => match_1d_attr_data!(data, rhs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "clip:1,99".parse::<IntensityNormalization>(),
+            Ok(IntensityNormalization::PercentileClip { low: 1., high: 99. })
+        );
+        assert_eq!(
+            "equalize".parse::<IntensityNormalization>(),
+            Ok(IntensityNormalization::Equalize)
+        );
+        assert!("clip:99,1".parse::<IntensityNormalization>().is_err());
+        assert!("clip:1".parse::<IntensityNormalization>().is_err());
+    }
+
+    #[test]
+    fn test_percentile_clip() {
+        let samples: Vec<f32> = (0..=100).map(|i| i as f32 * 10.).collect();
+        let normalizer = IntensityNormalizer::new(
+            IntensityNormalization::PercentileClip {
+                low: 10.,
+                high: 90.,
+            },
+            samples,
+        );
+        assert_eq!(normalizer.normalize(50.), 0.);
+        assert_eq!(normalizer.normalize(100.), 0.);
+        assert_eq!(normalizer.normalize(500.), 0.5);
+        assert_eq!(normalizer.normalize(900.), 1.);
+        assert_eq!(normalizer.normalize(2000.), 1.);
+    }
+
+    #[test]
+    fn test_equalize() {
+        // Most points are dark, equalization spreads them out.
+        let samples = vec![1., 1., 2., 2., 3., 3., 4., 1000.];
+        let normalizer = IntensityNormalizer::new(IntensityNormalization::Equalize, samples);
+        assert_eq!(normalizer.normalize(1.), 0.);
+        assert_eq!(normalizer.normalize(3.), 0.5);
+        assert_eq!(normalizer.normalize(1000.), 0.875);
+    }
+
+    #[test]
+    fn test_add_normalized() {
+        let mut batch = PointsBatch::default();
+        batch
+            .attributes
+            .insert("intensity".to_string(), AttributeData::U16(vec![0, 5, 10]));
+        let normalizer = IntensityNormalizer::new(
+            IntensityNormalization::PercentileClip {
+                low: 0.,
+                high: 100.,
+            },
+            vec![0., 10.],
+        );
+        normalizer.add_normalized(&mut batch);
+        let normalized: &Vec<f32> = batch.get_attribute_vec(NORMALIZED_INTENSITY).unwrap();
+        assert_eq!(normalized, &vec![0., 0.5, 1.]);
+        let raw: &Vec<u16> = batch.get_attribute_vec("intensity").unwrap();
+        assert_eq!(raw, &vec![0, 5, 10]);
+    }
+}
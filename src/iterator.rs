@@ -1,5 +1,5 @@
 use crate::errors::*;
-use crate::geometry::{Aabb, CellUnion, Frustum, Obb, WebMercatorRect};
+use crate::geometry::{Aabb, CellUnion, Corridor, Frustum, Obb, Polygon, Sphere, WebMercatorRect};
 use crate::math::{AllPoints, ClosedInterval, PointCulling};
 use crate::read_write::{Encoding, NodeIterator};
 use crate::{match_1d_attr_data, AttributeData, PointsBatch};
@@ -17,6 +17,9 @@ pub enum PointLocation {
     Obb(Obb),
     S2Cells(CellUnion),
     WebMercatorRect(WebMercatorRect),
+    Sphere(Sphere),
+    Polygon(Polygon),
+    Corridor(Corridor),
 }
 
 impl Default for PointLocation {
@@ -34,6 +37,9 @@ impl PointLocation {
             PointLocation::Obb(obb) => Box::new(obb.clone()),
             PointLocation::S2Cells(cell_union) => Box::new(cell_union.clone()),
             PointLocation::WebMercatorRect(wmr) => Box::new(wmr.clone()),
+            PointLocation::Sphere(sphere) => Box::new(sphere.clone()),
+            PointLocation::Polygon(polygon) => Box::new(polygon.clone()),
+            PointLocation::Corridor(corridor) => Box::new(corridor.clone()),
         }
     }
 }
@@ -58,6 +64,9 @@ macro_rules! dispatch_point_location {
             PointLocation::Obb(obb) => $func($($arg,)* obb),
             PointLocation::S2Cells(cu) => $func($($arg,)* cu),
             PointLocation::WebMercatorRect(wmr) => $func($($arg,)* wmr),
+            PointLocation::Sphere(s) => $func($($arg,)* s),
+            PointLocation::Polygon(p) => $func($($arg,)* p),
+            PointLocation::Corridor(c) => $func($($arg,)* c),
         }
     }
 }
@@ -157,7 +166,7 @@ where
     }
 
     fn push_points_and_callback(&mut self, mut batch: PointsBatch) -> Result<()> {
-        self.buf.append(&mut batch)?;
+        self.buf.append(&mut batch).map_err(Error::SchemaMismatch)?;
         while self.buf.position.len() >= self.batch_size {
             self.callback()?;
         }
@@ -283,11 +292,10 @@ where
                 s.spawn(move |_| {
                     let send_func = |batch: PointsBatch| match tx.send(batch) {
                         Ok(_) => Ok(()),
-                        Err(e) => Err(ErrorKind::Channel(format!(
+                        Err(e) => Err(Error::Channel(format!(
                             "Thread {}: sending operation failed, nothing more to do {:?}",
                             curr_thread, e,
-                        ))
-                        .into()),
+                        ))),
                     };
 
                     // One `PointStream` per thread vs one per node allows to send more full point batches
@@ -308,7 +316,7 @@ where
                             Ok(_) => continue,
                             Err(ref e) => {
                                 match e.kind() {
-                                    ErrorKind::Channel(ref _s) => break, // done with the function computation
+                                    Error::Channel(ref _s) => break, // done with the function computation
                                     _ => panic!("ParallelIterator: Thread error {}", e), //some other error
                                 }
                             }
@@ -317,7 +325,7 @@ where
                     // last batch of points: calling callback
                     if let Err(ref e) = point_stream.callback() {
                         match e.kind() {
-                            ErrorKind::Channel(ref _s) => (), // done with the function computation
+                            Error::Channel(ref _s) => (), // done with the function computation
                             _ => panic!("ParallelIterator: Thread error {}", e), //some other error
                         }
                     }
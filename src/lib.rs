@@ -19,20 +19,36 @@ pub mod math;
 
 #[macro_use]
 pub mod attributes;
+pub mod batching;
+pub mod classify;
 pub mod color;
+pub mod colorize;
+pub mod config;
 pub mod data_provider;
-// Workaround for https://github.com/rust-lang-nursery/error-chain/issues/254
-#[allow(deprecated)]
 pub mod errors;
+pub mod fitting;
+#[cfg(feature = "native")]
+pub mod frame_capture;
 pub mod geometry;
+pub mod intensity;
 #[macro_use]
 pub mod iterator;
 pub mod octree;
+#[cfg(feature = "native")]
+pub mod overlay;
+pub mod profile;
 pub mod read_write;
+pub mod registration;
+#[cfg(feature = "native")]
+pub mod report;
 pub mod s2_cells;
+#[cfg(feature = "native")]
+pub mod session_log;
+pub mod sink;
+pub mod stats;
 pub mod utils;
 
-use errors::Result;
+use errors::{Error, Result};
 use nalgebra::Point3;
 use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
@@ -56,6 +72,23 @@ pub trait NumberOfPoints {
     fn num_points(&self) -> usize;
 }
 
+/// Sources of 'PointsBatch'es that can refill an existing batch instead of allocating new vectors
+/// for every batch. Long running consumers should keep a single batch and call 'next_into' in a
+/// loop.
+pub trait NextInto: Iterator<Item = PointsBatch> {
+    /// Overwrites 'batch' with the next batch. Returns false, leaving 'batch' untouched, if there
+    /// are no more points.
+    fn next_into(&mut self, batch: &mut PointsBatch) -> bool {
+        match self.next() {
+            Some(next) => {
+                *batch = next;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 use attributes::{AttributeData, AttributeDataType};
 
 // TODO(nnmm): Remove
@@ -91,14 +124,31 @@ trait PointCloudMeta {
                 self.attribute_data_types()
                     .get(*a)
                     .map(|d| ((*a).to_string(), *d))
-                    .ok_or_else(|| format!("Data type for attribute '{}' not found.", a).into())
+                    .ok_or_else(|| {
+                        Error::SchemaMismatch(format!("Data type for attribute '{}' not found.", a))
+                    })
             })
             .collect()
     }
 }
 
+/// The number of points that a batch with the attributes 'attribute_data_types' holds in
+/// 'max_bytes' of memory, but at least one. Readers size their batches by memory with it, since a
+/// fixed number of points with many attributes takes many times the memory of bare positions.
+pub fn batch_size_for_bytes(
+    max_bytes: usize,
+    attribute_data_types: impl IntoIterator<Item = AttributeDataType>,
+) -> usize {
+    let bytes_per_point = std::mem::size_of::<Point3<f64>>()
+        + attribute_data_types
+            .into_iter()
+            .map(AttributeDataType::size_of)
+            .sum::<usize>();
+    (max_bytes / bytes_per_point).max(1)
+}
+
 /// General structure that contains points and attached feature attributes.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct PointsBatch {
     pub position: Vec<Point3<f64>>,
     // BTreeMap for deterministic iteration order.
@@ -123,6 +173,27 @@ impl PointsBatch {
         Ok(())
     }
 
+    /// Empties the batch, so that it can be refilled with the attributes in 'attribute_data_types'.
+    /// The allocations of the positions and of attributes that keep their data type are reused.
+    pub fn reset<'a>(
+        &mut self,
+        attribute_data_types: impl IntoIterator<Item = (&'a str, AttributeDataType)>,
+    ) {
+        let attribute_data_types: Vec<_> = attribute_data_types.into_iter().collect();
+        self.position.clear();
+        self.attributes.retain(|name, data| {
+            attribute_data_types
+                .iter()
+                .any(|(n, data_type)| *n == name.as_str() && *data_type == data.data_type())
+        });
+        for (name, data_type) in attribute_data_types {
+            self.attributes
+                .entry(name.to_string())
+                .or_insert_with(|| AttributeData::with_capacity(data_type, 0))
+                .clear();
+        }
+    }
+
     pub fn split_off(&mut self, at: usize) -> Self {
         let position = self.position.split_off(at);
         let attributes = self
@@ -0,0 +1,281 @@
+//! Bakes a pseudo-color into the 'color' attribute of the coarse levels of an octree. Thin clients
+//! that only draw RGB then show the intensity or the height of the points in overviews, while the
+//! finer levels keep their original colors.
+
+use crate::color::{heat_color, ColorSpace};
+use crate::data_provider::OnDiskDataProvider;
+use crate::errors::*;
+use crate::intensity::{
+    intensities, IntensityNormalization, IntensityNormalizer, NORMALIZED_INTENSITY,
+};
+use crate::octree::{NodeId, OctreeMeta};
+use crate::read_write::{write_file_atomically, NodeIterator};
+use crate::{attribute_extension, AttributeDataType, NUM_POINTS_PER_BATCH};
+use fnv::FnvHashMap;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The value that the baked colors show.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PseudoColor {
+    /// The normalized intensity if the octree has it, and the raw intensity clipped to its 1st
+    /// and 99th percentile otherwise.
+    Intensity,
+    /// The height within the bounding box, along 'BuildOptions::up_axis'.
+    Height,
+}
+
+/// Replaces the colors of the nodes up to 'max_level' by 'pseudo_color', see
+/// 'BuildOptions::baked_colors'.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BakedColors {
+    pub pseudo_color: PseudoColor,
+    pub max_level: u8,
+}
+
+impl FromStr for BakedColors {
+    type Err = String;
+
+    /// Parses '<intensity|height>:<max level>', e.g. 'height:4'.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let pseudo_color = match parts.next() {
+            Some("intensity") => PseudoColor::Intensity,
+            Some("height") => PseudoColor::Height,
+            _ => return Err(format!("Unknown pseudo-color in '{}'.", s)),
+        };
+        let max_level = parts
+            .next()
+            .ok_or_else(|| format!("Missing the maximum level in '{}'.", s))?
+            .trim()
+            .parse::<u8>()
+            .map_err(|e| format!("Invalid maximum level in '{}': {}", s, e))?;
+        Ok(BakedColors {
+            pseudo_color,
+            max_level,
+        })
+    }
+}
+
+/// Maps the points of a node to [0, 1].
+enum Scale {
+    Height { up: usize, min: f64, range: f64 },
+    Intensity(&'static str, Option<IntensityNormalizer>),
+}
+
+/// Overwrites the color files of the nodes in 'nodes' up to 'baked_colors.max_level'. The nodes
+/// must be written uncompressed, as during a build, with the attributes in
+/// 'attribute_data_types'.
+pub(super) fn bake_colors(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &OctreeMeta,
+    attribute_data_types: &HashMap<String, AttributeDataType>,
+    nodes: &FnvHashMap<NodeId, i64>,
+    baked_colors: &BakedColors,
+) -> Result<()> {
+    let nodes: Vec<(NodeId, i64)> = nodes
+        .iter()
+        .filter(|(id, num_points)| id.level() <= baked_colors.max_level && **num_points > 0)
+        .map(|(id, num_points)| (*id, *num_points))
+        .collect();
+    let scale = match baked_colors.pseudo_color {
+        PseudoColor::Height => {
+            let bounding_box = &octree_meta.bounding_box;
+            let up = octree_meta.view_settings.up_axis.index();
+            Scale::Height {
+                up,
+                min: bounding_box.min()[up],
+                range: bounding_box.diag()[up].max(std::f64::EPSILON),
+            }
+        }
+        PseudoColor::Intensity if attribute_data_types.contains_key(NORMALIZED_INTENSITY) => {
+            Scale::Intensity(NORMALIZED_INTENSITY, None)
+        }
+        PseudoColor::Intensity if attribute_data_types.contains_key("intensity") => {
+            // The root holds a random sample of all points, which is plenty for the percentiles.
+            let samples = match nodes.iter().find(|(id, _)| id.level() == 0) {
+                Some((id, num_points)) => read_values(
+                    octree_data_provider,
+                    octree_meta,
+                    attribute_data_types,
+                    id,
+                    *num_points,
+                    "intensity",
+                )?,
+                None => Vec::new(),
+            };
+            let normalizer = IntensityNormalizer::new(
+                IntensityNormalization::PercentileClip { low: 1., high: 99. },
+                samples,
+            );
+            Scale::Intensity("intensity", Some(normalizer))
+        }
+        PseudoColor::Intensity => {
+            return Err(Error::SchemaMismatch(
+                "Baking intensity colors needs the attribute 'intensity'.".to_string(),
+            ))
+        }
+    };
+
+    nodes
+        .par_iter()
+        .map(|(id, num_points)| {
+            let values: Vec<f32> = match &scale {
+                Scale::Height { up, min, range } => {
+                    read_heights(octree_data_provider, octree_meta, id, *num_points, *up)?
+                        .into_iter()
+                        .map(|h| ((h - min) / range) as f32)
+                        .collect()
+                }
+                Scale::Intensity(attribute, normalizer) => {
+                    let values = read_values(
+                        octree_data_provider,
+                        octree_meta,
+                        attribute_data_types,
+                        id,
+                        *num_points,
+                        attribute,
+                    )?;
+                    match normalizer {
+                        Some(normalizer) => {
+                            values.iter().map(|i| normalizer.normalize(*i)).collect()
+                        }
+                        None => values,
+                    }
+                }
+            };
+            let mut bytes = Vec::with_capacity(3 * values.len());
+            for value in values {
+                let color = heat_color(value);
+                bytes.extend_from_slice(&[color.red, color.green, color.blue]);
+            }
+            ColorSpace::Srgb.convert_u8(octree_meta.color_space, &mut bytes);
+            let path = octree_data_provider
+                .stem(&id.to_string())
+                .with_extension(attribute_extension("color"));
+            write_file_atomically(&path, &bytes)
+                .with_context(|| format!("Could not write {}.", path.display()))
+        })
+        .collect()
+}
+
+/// Reads the coordinate 'up' of the positions of the points of a node.
+fn read_heights(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &OctreeMeta,
+    id: &NodeId,
+    num_points: i64,
+    up: usize,
+) -> Result<Vec<f64>> {
+    let node_iterator = NodeIterator::from_data_provider(
+        octree_data_provider,
+        &HashMap::new(),
+        octree_meta.encoding_for_node(*id),
+        false,
+        id,
+        num_points as usize,
+        NUM_POINTS_PER_BATCH,
+    )?;
+    Ok(node_iterator
+        .flat_map(move |batch| batch.position.into_iter().map(move |p| p[up]))
+        .collect())
+}
+
+fn read_values(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &OctreeMeta,
+    attribute_data_types: &HashMap<String, AttributeDataType>,
+    id: &NodeId,
+    num_points: i64,
+    attribute: &str,
+) -> Result<Vec<f32>> {
+    let attribute_data_types: HashMap<String, AttributeDataType> =
+        std::iter::once((attribute.to_string(), attribute_data_types[attribute])).collect();
+    let node_iterator = NodeIterator::from_data_provider(
+        octree_data_provider,
+        &attribute_data_types,
+        octree_meta.encoding_for_node(*id),
+        false,
+        id,
+        num_points as usize,
+        NUM_POINTS_PER_BATCH,
+    )?;
+    Ok(node_iterator
+        .flat_map(|batch| intensities(&batch.attributes[attribute]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iterator::PointCloud;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "height:4".parse::<BakedColors>(),
+            Ok(BakedColors {
+                pseudo_color: PseudoColor::Height,
+                max_level: 4
+            })
+        );
+        assert_eq!(
+            "intensity:0".parse::<BakedColors>(),
+            Ok(BakedColors {
+                pseudo_color: PseudoColor::Intensity,
+                max_level: 0
+            })
+        );
+        assert!("height".parse::<BakedColors>().is_err());
+        assert!("color:2".parse::<BakedColors>().is_err());
+    }
+
+    #[test]
+    fn test_coarse_levels_are_baked() {
+        // Red points at the bottom of the bounding box and one at its top, enough of them for the
+        // root to be split.
+        let mut position = vec![Point3::origin(); 100_000];
+        position.push(Point3::new(-200., -40., 30.));
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let options = BuildOptions {
+            baked_colors: Some(BakedColors {
+                pseudo_color: PseudoColor::Height,
+                max_level: 0,
+            }),
+            ..Default::default()
+        };
+        let octree = build_octree_of_batch(tmp_dir.path(), 1., batch, &options);
+        let colors = |node_id: NodeId| -> Vec<Vector3<u8>> {
+            let mut colors = Vec::new();
+            // Reading verifies the checksums, which must cover the baked colors.
+            for batch in octree.points_in_node(&["color"], node_id, 1000).unwrap() {
+                let batch_colors: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+                colors.extend(batch_colors);
+            }
+            colors
+        };
+        // The points at the bottom are black, the highest one is white.
+        let root_colors = colors(NodeId::root());
+        assert!(root_colors
+            .iter()
+            .all(|c| *c == Vector3::new(0, 0, 0) || *c == Vector3::new(255, 255, 255)));
+        let finer_colors: Vec<_> = octree.nodes(1..=1).flat_map(colors).collect();
+        assert!(!finer_colors.is_empty());
+        assert!(finer_colors.iter().all(|c| *c == Vector3::new(255, 0, 0)));
+    }
+}
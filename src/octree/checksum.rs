@@ -0,0 +1,90 @@
+//! Detects node files that changed after they were written, e.g. by bit rot in long-term archives.
+//! The writers hash every file of a node and store the hashes in the meta data, see
+//! 'NodeMeta::checksums'.
+
+use crate::data_provider::DataProvider;
+use crate::errors::*;
+use crate::proto;
+use fnv::FnvHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::io::{self, Cursor, Read, Write};
+
+/// Hashes everything written to it.
+struct HashWriter(FnvHasher);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The FNV-1a hash of all data in 'reader'. It is not cryptographic, but every step of it is
+/// invertible, so changing any single byte always changes the checksum.
+pub fn checksum(mut reader: impl Read) -> io::Result<u64> {
+    let mut writer = HashWriter(FnvHasher::default());
+    io::copy(&mut reader, &mut writer)?;
+    Ok(writer.0.finish())
+}
+
+/// Returns the checksums of the 'attributes' of a node that was written to 'data_provider'.
+pub fn compute_checksums(
+    data_provider: &dyn DataProvider,
+    node_id: &str,
+    attributes: &[&str],
+) -> Result<HashMap<String, u64>> {
+    data_provider
+        .data(node_id, attributes)?
+        .into_iter()
+        .map(|(attribute, reader)| Ok((attribute, checksum(reader)?)))
+        .collect()
+}
+
+/// Reads all data of the node with 'node_id' that has a checksum and checks it. The readers are
+/// replaced by readers of the data in memory, so that it is read only once.
+pub(crate) fn verify_reads(
+    node_id: &str,
+    checksums: &HashMap<String, u64>,
+    reads: &mut HashMap<String, Box<dyn Read + Send>>,
+) -> Result<()> {
+    for (attribute, reader) in reads.iter_mut() {
+        let expected = match checksums.get(attribute) {
+            Some(expected) => *expected,
+            None => continue,
+        };
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if checksum(&data[..])? != expected {
+            return Err(Error::CorruptNode(node_id.to_string(), attribute.clone()));
+        }
+        *reader = Box::new(Cursor::new(data));
+    }
+    Ok(())
+}
+
+/// Checks the data that 'data_provider' returns for a node against the node's 'checksums'.
+pub(crate) struct ChecksumVerifier<'a> {
+    pub data_provider: &'a dyn DataProvider,
+    pub checksums: &'a HashMap<String, u64>,
+}
+
+impl DataProvider for ChecksumVerifier<'_> {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        self.data_provider.meta_proto()
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        let mut reads = self.data_provider.data(node_id, node_attributes)?;
+        verify_reads(node_id, self.checksums, &mut reads)?;
+        Ok(reads)
+    }
+}
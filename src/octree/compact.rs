@@ -0,0 +1,276 @@
+//! Reclaims the space that accumulates in an octree directory, e.g. after interrupted builds or
+//! tools that rewrote nodes in place: node files that are not in the meta data, empty subtrees
+//! and bytes that are not needed to store the points of a node.
+
+use crate::data_provider::OnDiskDataProvider;
+use crate::errors::*;
+use crate::iterator::PointCloud;
+use crate::octree::{
+    compute_checksums, to_meta_proto, to_node_proto, write_meta, NodeId, NodeSchema, Octree,
+    OctreeLock, Operation, Transaction, KD_INDEX, NODE_SCHEMA,
+};
+use crate::read_write::{AttributeCompression, NodeWriter, OpenMode, RawNodeWriter};
+use crate::utils::create_progress_bar;
+use crate::{attribute_extension, PointCloudMeta, NUM_POINTS_PER_BATCH};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Directory inside the octree in which nodes are rewritten before they replace the originals.
+const REWRITE_DIRECTORY: &str = "compact.tmp";
+
+/// What 'compact_octree' changed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompactionStats {
+    /// Nodes whose files were replaced by smaller ones.
+    pub num_rewritten_nodes: usize,
+    /// Nodes without points in their whole subtree, which were removed from the meta data.
+    pub num_pruned_nodes: usize,
+    /// Node files that did not belong to a node in the meta data.
+    pub num_removed_files: usize,
+    pub reclaimed_bytes: u64,
+}
+
+/// Compacts the octree in 'directory' in place. Files that do not look like node files are left
+/// alone. For versioned octrees, the replaced and removed files are archived with the previous
+/// version instead of being deleted, so only the current version gets smaller.
+pub fn compact_octree(directory: impl AsRef<Path>) -> Result<CompactionStats> {
+    let directory = directory.as_ref();
+    let _lock = OctreeLock::write(directory)?;
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: directory.to_path_buf(),
+    }))?;
+    let transaction = Transaction::begin(directory, Operation::Compact)?;
+    let mut stats = CompactionStats::default();
+
+    // A node is kept if it or any of its descendants has points, so that the tree stays connected.
+    let mut kept = FnvHashSet::default();
+    for (node_id, node_meta) in &octree.nodes {
+        if node_meta.num_points == 0 {
+            continue;
+        }
+        let mut id = *node_id;
+        while kept.insert(id) {
+            match id.parent_id() {
+                Some(parent_id) if octree.nodes.contains_key(&parent_id) => id = parent_id,
+                _ => break,
+            }
+        }
+    }
+    stats.num_pruned_nodes = octree.nodes.len() - kept.len();
+
+    let rewrite_directory = directory.join(REWRITE_DIRECTORY);
+    fs::create_dir_all(&rewrite_directory)
+        .with_context(|| "Could not create directory for rewriting nodes.")?;
+    let rewrite_data_provider = OnDiskDataProvider {
+        directory: rewrite_directory.clone(),
+    };
+    let data_provider = OnDiskDataProvider {
+        directory: directory.to_path_buf(),
+    };
+    // Reading the nodes verified their old checksums, so the checksums of the compacted files
+    // also give octrees that were written without checksums a trustworthy start.
+    let mut checksums = FnvHashMap::default();
+    let mut progress_bar = create_progress_bar(kept.len(), "Compacting nodes");
+    for node_id in &kept {
+        let stem = directory.join(node_id.to_string());
+        let mut attributes: Vec<&str> = octree
+            .meta
+            .attribute_data_types
+            .keys()
+            .map(String::as_str)
+            .filter(|attribute| stem.with_extension(attribute_extension(attribute)).exists())
+            .collect();
+        attributes.sort();
+        let rewritten_stem = rewrite_data_provider.stem(&node_id.to_string());
+        let attribute_compression = if octree.nodes[node_id].compressed_attributes {
+            AttributeCompression::Compressed(HashMap::new())
+        } else {
+            AttributeCompression::Uncompressed
+        };
+        let mut writer = RawNodeWriter::new(
+            &rewritten_stem,
+            octree.encoding_for_node(*node_id),
+            OpenMode::Truncate,
+        )
+        .with_attribute_compression(attribute_compression);
+        for batch in octree.points_in_node(&attributes, *node_id, NUM_POINTS_PER_BATCH)? {
+            writer.write(&batch)?;
+        }
+        writer.finish()?;
+
+        let extensions: Vec<&str> = std::iter::once("position")
+            .chain(attributes.iter().copied())
+            .map(attribute_extension)
+            .collect();
+        let old_size = files_size(&stem, &extensions);
+        let new_size = files_size(&rewritten_stem, &extensions);
+        if new_size < old_size {
+            for extension in &extensions {
+                let rewritten = rewritten_stem.with_extension(extension);
+                let original = stem.with_extension(extension);
+                if let Some(transaction) = &transaction {
+                    if original.exists() {
+                        transaction.archive(&original)?;
+                    }
+                }
+                // Writers do not leave files for nodes without points.
+                if rewritten.exists() {
+                    fs::rename(rewritten, original)?;
+                } else {
+                    let _ = fs::remove_file(original);
+                }
+            }
+            stats.num_rewritten_nodes += 1;
+            stats.reclaimed_bytes += old_size - new_size;
+        }
+        if octree.nodes[node_id].num_points > 0 && !stem.with_extension(NODE_SCHEMA).exists() {
+            // Compacting does not change what the files hold, so only nodes that were written
+            // without a schema need one.
+            let node_meta = &octree.nodes[node_id];
+            let schema = NodeSchema {
+                num_points: node_meta.num_points,
+                bounding_cube: node_meta.bounding_cube.clone(),
+                position_encoding: node_meta.position_encoding.clone(),
+                compressed_attributes: node_meta.compressed_attributes,
+                attribute_data_types: octree.meta.attribute_data_types_for(&attributes)?,
+            };
+            schema.write(&stem)?;
+        }
+        if octree.nodes[node_id].num_points > 0 {
+            // The order of the points is unchanged, so the kd-tree stays valid.
+            let files: Vec<&str> = std::iter::once("position")
+                .chain(attributes.iter().copied())
+                .chain(Some(KD_INDEX).filter(|_| stem.with_extension(KD_INDEX).exists()))
+                .collect();
+            checksums.insert(
+                *node_id,
+                compute_checksums(&data_provider, &node_id.to_string(), &files)?,
+            );
+        }
+        progress_bar.inc();
+    }
+    progress_bar.finish();
+    fs::remove_dir_all(&rewrite_directory)?;
+
+    // Removes the files of pruned nodes and of nodes that are not in the meta data at all.
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let node_id = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) if is_node_stem(stem) => stem.parse::<NodeId>().ok(),
+            _ => None,
+        };
+        if let Some(node_id) = node_id {
+            if !kept.contains(&node_id) {
+                stats.reclaimed_bytes += fs::metadata(&path)?.len();
+                stats.num_removed_files += 1;
+                match &transaction {
+                    Some(transaction) => transaction.archive(&path)?,
+                    None => fs::remove_file(&path)?,
+                }
+            }
+        }
+    }
+
+    let nodes = kept
+        .iter()
+        .map(|node_id| {
+            let node_meta = &octree.nodes[node_id];
+            to_node_proto(
+                node_id,
+                node_meta.num_points,
+                &node_meta.position_encoding,
+                node_meta.compressed_attributes,
+                checksums.remove(node_id).unwrap_or_default(),
+                node_meta.normal_cone,
+                node_meta.occupancy,
+            )
+        })
+        .collect();
+    write_meta(directory, &to_meta_proto(&octree.meta, nodes))?;
+    if let Some(transaction) = transaction {
+        transaction.commit(format!(
+            "Rewrote {} nodes, pruned {} empty nodes and removed {} orphaned files.",
+            stats.num_rewritten_nodes, stats.num_pruned_nodes, stats.num_removed_files
+        ))?;
+    }
+    Ok(stats)
+}
+
+/// Node files are named like the node, e.g. 'r0153.rgb'.
+fn is_node_stem(stem: &str) -> bool {
+    stem.starts_with('r') && stem[1..].chars().all(|c| ('0'..='7').contains(&c))
+}
+
+fn files_size(stem: &Path, extensions: &[&str]) -> u64 {
+    extensions
+        .iter()
+        .filter_map(|extension| fs::metadata(stem.with_extension(extension)).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_compact_octree() {
+        let mut position = vec![Point3::origin(); 16];
+        position.push(Point3::new(-200., -40., 30.));
+        let num_points = position.len();
+        let batch = PointsBatch {
+            position,
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        // Without checksums, like an octree from a tool that changes node files in place.
+        let options = BuildOptions {
+            checksums: false,
+            ..Default::default()
+        };
+        build_octree_of_batch(tmp_dir.path(), 1., batch, &options);
+        let orphan = tmp_dir.path().join("r7777.rgb");
+        fs::write(&orphan, [0u8; 30]).unwrap();
+        let root_positions = tmp_dir.path().join("r.xyz");
+        let mut positions = fs::read(&root_positions).unwrap();
+        positions.extend_from_slice(&[0; 12]);
+        fs::write(&root_positions, positions).unwrap();
+
+        let reader = OctreeLock::read(tmp_dir.path()).unwrap();
+        match compact_octree(tmp_dir.path()) {
+            Err(Error::OctreeLocked(_)) => (),
+            other => panic!("Expected a locked octree, got {:?}.", other),
+        }
+        drop(reader);
+
+        let stats = compact_octree(tmp_dir.path()).unwrap();
+        assert_eq!(stats.num_rewritten_nodes, 1);
+        assert_eq!(stats.num_removed_files, 1);
+        assert_eq!(stats.reclaimed_bytes, 42);
+        assert!(!orphan.exists());
+
+        let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: tmp_dir.path().to_path_buf(),
+        }))
+        .unwrap();
+        let compacted_num_points: i64 = octree.nodes.values().map(|node| node.num_points).sum();
+        assert_eq!(compacted_num_points, num_points as i64);
+        assert!(octree.verify_node(&"r".parse().unwrap()).unwrap());
+        assert_eq!(compact_octree(tmp_dir.path()).unwrap().reclaimed_bytes, 0);
+    }
+}
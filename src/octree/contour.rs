@@ -0,0 +1,328 @@
+//! Traces contour lines of constant height through a raster of heights with marching squares and
+//! writes them as GeoJSON or DXF.
+
+use crate::errors::*;
+use crate::octree::Raster;
+use fnv::FnvHashMap;
+use nalgebra::Point2;
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// A polyline along which the height of the raster is 'level'.
+#[derive(Clone, Debug)]
+pub struct ContourLine {
+    pub level: f64,
+    /// For closed lines, the first point is not repeated at the end.
+    pub points: Vec<Point2<f64>>,
+    pub closed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContourFormat {
+    GeoJson,
+    Dxf,
+}
+
+impl FromStr for ContourFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "geojson" | "json" => Ok(ContourFormat::GeoJson),
+            "dxf" => Ok(ContourFormat::Dxf),
+            _ => Err(format!("Unknown contour format '{}'.", s)),
+        }
+    }
+}
+
+/// The edge between the centers of two neighboring cells. 'Right' connects '(column, row)' with
+/// '(column + 1, row)' and 'Down' with '(column, row + 1)'.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Edge {
+    Right(usize, usize),
+    Down(usize, usize),
+}
+
+/// Traces the contour lines at all multiples of 'interval' from the lowest height of 'raster' up to
+/// below its highest height. The lines run through the cell centers, so they stop at empty cells and at the border
+/// of the raster instead of being closed there.
+pub fn extract_contours(raster: &Raster, interval: f64) -> Result<Vec<ContourLine>> {
+    if interval <= 0. || interval.is_nan() {
+        return Err(Error::InvalidInput(
+            "The interval must be positive.".to_string(),
+        ));
+    }
+    let (min, max) = (0..raster.height())
+        .flat_map(|row| (0..raster.width()).map(move |column| (column, row)))
+        .filter_map(|(column, row)| raster.value(column, row))
+        .fold(
+            (std::f64::INFINITY, std::f64::NEG_INFINITY),
+            |(min, max), v| (min.min(v), max.max(v)),
+        );
+    if min > max {
+        return Ok(Vec::new());
+    }
+    // A level at the maximum height would only touch the highest cells in single points.
+    let first = (min / interval).ceil() as i64;
+    let last = (max / interval).ceil() as i64;
+    Ok((first..last)
+        .flat_map(|i| trace_level(raster, i as f64 * interval))
+        .collect())
+}
+
+fn trace_level(raster: &Raster, level: f64) -> Vec<ContourLine> {
+    let segments = level_segments(raster, level);
+    let mut segments_at_edge: FnvHashMap<Edge, Vec<usize>> = FnvHashMap::default();
+    for (index, (from, to)) in segments.iter().enumerate() {
+        segments_at_edge.entry(*from).or_default().push(index);
+        segments_at_edge.entry(*to).or_default().push(index);
+    }
+    let mut visited = vec![false; segments.len()];
+    let trace = |start: Edge, visited: &mut [bool]| {
+        let mut edges = vec![start];
+        let mut current = start;
+        while let Some(&index) = segments_at_edge[&current]
+            .iter()
+            .find(|index| !visited[**index])
+        {
+            visited[index] = true;
+            let (from, to) = segments[index];
+            current = if from == current { to } else { from };
+            edges.push(current);
+        }
+        let closed = edges.len() > 2 && edges.first() == edges.last();
+        if closed {
+            edges.pop();
+        }
+        ContourLine {
+            level,
+            points: edges
+                .iter()
+                .map(|edge| edge_point(raster, *edge, level))
+                .collect(),
+            closed,
+        }
+    };
+    // Open lines start at an edge with only one segment, everything left afterwards is a loop.
+    let mut lines = Vec::new();
+    for (index, (from, to)) in segments.iter().enumerate() {
+        for end in &[*from, *to] {
+            if !visited[index] && segments_at_edge[end].len() == 1 {
+                lines.push(trace(*end, &mut visited));
+            }
+        }
+    }
+    for (index, (from, _)) in segments.iter().enumerate() {
+        if !visited[index] {
+            lines.push(trace(*from, &mut visited));
+        }
+    }
+    lines
+}
+
+/// The segments of the contour line at 'level' within each square of four cell centers.
+fn level_segments(raster: &Raster, level: f64) -> Vec<(Edge, Edge)> {
+    let mut segments = Vec::new();
+    for row in 0..raster.height().saturating_sub(1) {
+        for column in 0..raster.width().saturating_sub(1) {
+            let corners = [
+                raster.value(column, row),
+                raster.value(column + 1, row),
+                raster.value(column + 1, row + 1),
+                raster.value(column, row + 1),
+            ];
+            let (top_left, top_right, bottom_right, bottom_left) = match corners {
+                [Some(a), Some(b), Some(c), Some(d)] => (a, b, c, d),
+                _ => continue,
+            };
+            let case = (top_left >= level) as u8 * 8
+                + (top_right >= level) as u8 * 4
+                + (bottom_right >= level) as u8 * 2
+                + (bottom_left >= level) as u8;
+            let top = Edge::Right(column, row);
+            let right = Edge::Down(column + 1, row);
+            let bottom = Edge::Right(column, row + 1);
+            let left = Edge::Down(column, row);
+            // Saddles are resolved by the mean height of the square.
+            let center_above = (top_left + top_right + bottom_right + bottom_left) / 4. >= level;
+            match case {
+                1 | 14 => segments.push((left, bottom)),
+                2 | 13 => segments.push((bottom, right)),
+                3 | 12 => segments.push((left, right)),
+                4 | 11 => segments.push((top, right)),
+                6 | 9 => segments.push((top, bottom)),
+                7 | 8 => segments.push((left, top)),
+                5 if center_above => {
+                    segments.push((left, top));
+                    segments.push((bottom, right));
+                }
+                10 if !center_above => {
+                    segments.push((left, top));
+                    segments.push((bottom, right));
+                }
+                5 | 10 => {
+                    segments.push((top, right));
+                    segments.push((left, bottom));
+                }
+                _ => (),
+            }
+        }
+    }
+    segments
+}
+
+/// The point on 'edge' where the linearly interpolated height equals 'level'.
+fn edge_point(raster: &Raster, edge: Edge, level: f64) -> Point2<f64> {
+    let ((column_a, row_a), (column_b, row_b)) = match edge {
+        Edge::Right(column, row) => ((column, row), (column + 1, row)),
+        Edge::Down(column, row) => ((column, row), (column, row + 1)),
+    };
+    let a = raster.value(column_a, row_a).unwrap();
+    let b = raster.value(column_b, row_b).unwrap();
+    let t = (level - a) / (b - a);
+    let from = raster.cell_center(column_a, row_a);
+    let to = raster.cell_center(column_b, row_b);
+    from + (to - from) * t
+}
+
+/// Writes the contour lines into 'path' in the given format.
+pub fn write_contours(
+    contours: &[ContourLine],
+    path: impl AsRef<Path>,
+    format: ContourFormat,
+) -> Result<()> {
+    let path = path.as_ref();
+    let file =
+        File::create(path).with_context(|| format!("Could not create {}.", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        ContourFormat::GeoJson => write_geojson_to(contours, &mut writer)?,
+        ContourFormat::Dxf => write_dxf_to(contours, &mut writer)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A feature collection with a LineString per contour line and its height as 'elevation'.
+fn write_geojson_to(contours: &[ContourLine], writer: &mut impl Write) -> Result<()> {
+    let features: Vec<_> = contours
+        .iter()
+        .map(|contour| {
+            let mut coordinates: Vec<_> = contour.points.iter().map(|p| [p.x, p.y]).collect();
+            if contour.closed {
+                coordinates.push(coordinates[0]);
+            }
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "LineString", "coordinates": coordinates },
+                "properties": { "elevation": contour.level },
+            })
+        })
+        .collect();
+    let collection = json!({ "type": "FeatureCollection", "features": features });
+    serde_json::to_writer(writer, &collection).map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// An ASCII DXF (R12) with a 3D polyline per contour line on the layer 'contours'.
+fn write_dxf_to(contours: &[ContourLine], writer: &mut impl Write) -> Result<()> {
+    writeln!(writer, "0\nSECTION\n2\nENTITIES")?;
+    for contour in contours {
+        // 8 marks a 3D polyline, 1 a closed one.
+        let flags = if contour.closed { 9 } else { 8 };
+        writeln!(writer, "0\nPOLYLINE\n8\ncontours\n66\n1\n70\n{}", flags)?;
+        writeln!(writer, "10\n0.0\n20\n0.0\n30\n{}", contour.level)?;
+        for point in &contour.points {
+            writeln!(writer, "0\nVERTEX\n8\ncontours\n70\n32")?;
+            writeln!(
+                writer,
+                "10\n{}\n20\n{}\n30\n{}",
+                point.x, point.y, contour.level
+            )?;
+        }
+        writeln!(writer, "0\nSEQEND\n8\ncontours")?;
+    }
+    writeln!(writer, "0\nENDSEC\n0\nEOF")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Aabb;
+    use crate::octree::RasterStatistic;
+    use nalgebra::Point3;
+
+    /// A 3x3 raster with cell size 1 and the given heights, row 0 at the top.
+    fn raster_from(heights: [[f64; 3]; 3]) -> Raster {
+        let bounding_box = Aabb::new(Point3::new(0., 0., -10.), Point3::new(3., 3., 10.));
+        let mut raster = Raster::new(&bounding_box, 1., RasterStatistic::Max);
+        for (row, values) in heights.iter().enumerate() {
+            for (column, height) in values.iter().enumerate() {
+                let center = raster.cell_center(column, row);
+                raster.add_point(&Point3::new(center.x, center.y, *height));
+            }
+        }
+        raster
+    }
+
+    #[test]
+    fn test_closed_contour_around_peak() {
+        let raster = raster_from([[0., 0., 0.], [0., 2., 0.], [0., 0., 0.]]);
+        let contours = extract_contours(&raster, 1.).unwrap();
+        // Levels 0 and 2 are the lowest and highest heights and are not traced.
+        assert_eq!(contours.len(), 1);
+        let contour = &contours[0];
+        assert_eq!(contour.level, 1.);
+        assert!(contour.closed);
+        assert_eq!(contour.points.len(), 4);
+        for point in &contour.points {
+            let distance = (point - Point2::new(1.5, 1.5)).norm();
+            assert!((distance - 0.5).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_open_contour_on_slope() {
+        let raster = raster_from([[0., 1., 2.], [0., 1., 2.], [0., 1., 2.]]);
+        let contours = extract_contours(&raster, 0.5).unwrap();
+        let levels: Vec<f64> = contours.iter().map(|c| c.level).collect();
+        assert_eq!(levels, [0.5, 1., 1.5]);
+        let contour = &contours[0];
+        assert!(!contour.closed);
+        assert_eq!(contour.points.len(), 3);
+        assert!(contour.points.iter().all(|p| (p.x - 1.).abs() < 1e-9));
+        assert!(extract_contours(&raster, 0.).is_err());
+    }
+
+    #[test]
+    fn test_writers() {
+        let contours = [ContourLine {
+            level: 5.,
+            points: vec![
+                Point2::new(0., 0.),
+                Point2::new(1., 0.),
+                Point2::new(1., 1.),
+            ],
+            closed: true,
+        }];
+        let mut bytes = Vec::new();
+        write_geojson_to(&contours, &mut bytes).unwrap();
+        let geojson: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let feature = &geojson["features"][0];
+        assert_eq!(feature["properties"]["elevation"], 5.);
+        let coordinates = feature["geometry"]["coordinates"].as_array().unwrap();
+        assert_eq!(coordinates.len(), 4);
+        assert_eq!(coordinates[0], coordinates[3]);
+
+        let mut bytes = Vec::new();
+        write_dxf_to(&contours, &mut bytes).unwrap();
+        let dxf = String::from_utf8(bytes).unwrap();
+        assert_eq!(dxf.matches("\nVERTEX\n").count(), 3);
+        assert!(dxf.contains("POLYLINE\n8\ncontours\n66\n1\n70\n9\n"));
+        assert!(dxf.ends_with("EOF\n"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Signed distances of the points of an octree from the surfaces of another one, e.g. to compare
+//! an as-built scan with the design, or two scans of a changing site.
+
+use crate::color::{diverging_color, Color};
+use crate::errors::*;
+use crate::geometry::Aabb;
+use crate::iterator::{PointCloud, PointLocation};
+use crate::octree::{NodeId, Octree};
+use crate::registration::{IcpMethod, IcpOptions, Reference};
+use crate::{NextInto, PointsBatch, NUM_POINTS_PER_BATCH};
+use nalgebra::Vector3;
+
+#[derive(Clone, Debug)]
+pub struct DeviationOptions {
+    /// Points farther than this from the other octree have no deviation. Deviations of this size
+    /// get the most saturated colors.
+    pub max_distance: f64,
+    /// The normals of the other octree are estimated from its points within this radius.
+    pub normal_radius: f64,
+}
+
+impl Default for DeviationOptions {
+    fn default() -> Self {
+        Self {
+            max_distance: 0.1,
+            normal_radius: 0.2,
+        }
+    }
+}
+
+/// Red for points above the other octree, blue for points below it and white for points on it,
+/// gray if 'deviation' is unknown.
+pub fn deviation_color(deviation: Option<f64>, max_distance: f64) -> Color<u8> {
+    match deviation {
+        Some(deviation) => diverging_color((deviation / max_distance) as f32),
+        None => Color {
+            red: 128,
+            green: 128,
+            blue: 128,
+            alpha: 255,
+        },
+    }
+}
+
+impl Octree {
+    /// The distance of every point of the node 'node_id', in the order of 'get_node_data', from
+    /// the closest surface of 'reference' along its normal, positive in the up direction of
+    /// 'reference'. Only the nodes of 'reference' with at least half the edge length of the node
+    /// are compared, so that both have about the same resolution.
+    pub fn get_node_deviations(
+        &self,
+        node_id: &NodeId,
+        reference: &Octree,
+        options: &DeviationOptions,
+    ) -> Result<Vec<Option<f64>>> {
+        let node_meta = self.nodes.get(node_id).ok_or(Error::NodeNotFound)?;
+        let cube = &node_meta.bounding_cube;
+        let margin = Vector3::repeat(options.max_distance + options.normal_radius);
+        let aabb = Aabb::new(cube.min() - margin, cube.max() + margin);
+
+        let mut reference_points = Vec::new();
+        let mut batch = PointsBatch::default();
+        for reference_id in reference.nodes_in_location(&PointLocation::Aabb(aabb.clone())) {
+            if reference.nodes[&reference_id].bounding_cube.edge_length() < cube.edge_length() / 2.
+            {
+                continue;
+            }
+            let mut points = reference.points_in_node(&[], reference_id, NUM_POINTS_PER_BATCH)?;
+            while points.next_into(&mut batch) {
+                reference_points.extend(batch.position.iter().filter(|p| aabb.contains(p)));
+            }
+        }
+        let icp_options = IcpOptions {
+            method: IcpMethod::PointToPlane,
+            max_correspondence_distance: options.max_distance,
+            normal_radius: options.normal_radius,
+            ..Default::default()
+        };
+        let reference_points = Reference::new(reference_points, &icp_options);
+        let up = Vector3::ith(reference.view_settings().up_axis.index(), 1.);
+
+        let mut deviations = Vec::with_capacity(node_meta.num_points as usize);
+        let mut points = self.points_in_node(&[], *node_id, NUM_POINTS_PER_BATCH)?;
+        while points.next_into(&mut batch) {
+            deviations.extend(
+                batch
+                    .position
+                    .iter()
+                    .map(|p| reference_points.signed_distance(p, options.max_distance, &up)),
+            );
+        }
+        Ok(deviations)
+    }
+
+    /// The colors of 'get_node_deviations' as RGB, see 'deviation_color'.
+    pub fn get_node_deviation_colors(
+        &self,
+        node_id: &NodeId,
+        reference: &Octree,
+        options: &DeviationOptions,
+    ) -> Result<Vec<u8>> {
+        let deviations = self.get_node_deviations(node_id, reference, options)?;
+        let mut colors = Vec::with_capacity(deviations.len() * 3);
+        for deviation in deviations {
+            let color = deviation_color(deviation, options.max_distance);
+            colors.extend_from_slice(&[color.red, color.green, color.blue]);
+        }
+        Ok(colors)
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::AttributeData;
+    use nalgebra::Point3;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn build_plane_octree_into(directory: &Path, height: f64) -> Octree {
+        let position: Vec<_> = (0..=100)
+            .flat_map(|i| {
+                (0..=100)
+                    .map(move |j| Point3::new(f64::from(i) * 0.05, f64::from(j) * 0.05, height))
+            })
+            .collect();
+        let num_points = position.len();
+        let batch = PointsBatch {
+            position,
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        build_octree_of_batch(directory, 0.001, batch, &BuildOptions::default())
+    }
+
+    #[test]
+    fn test_deviations() {
+        let design_dir = TempDir::new("design").unwrap();
+        let design = build_plane_octree_into(design_dir.path(), 0.);
+        let scan_dir = TempDir::new("scan").unwrap();
+        let scan = build_plane_octree_into(scan_dir.path(), 0.03);
+        let options = DeviationOptions::default();
+        let mut num_deviations = 0;
+        for node_id in scan.nodes(..) {
+            let deviations = scan
+                .get_node_deviations(&node_id, &design, &options)
+                .unwrap();
+            assert_eq!(deviations.len() as i64, scan.nodes[&node_id].num_points);
+            // Points without a normal nearby have their distance to the closest point.
+            for deviation in deviations.into_iter().flatten() {
+                assert!(deviation >= 0.03 - 1e-9);
+                num_deviations += 1;
+            }
+            let colors = scan
+                .get_node_deviation_colors(&node_id, &design, &options)
+                .unwrap();
+            assert_eq!(colors.len() as i64, scan.nodes[&node_id].num_points * 3);
+        }
+        assert!(num_deviations > 0);
+        for node_id in design.nodes(..) {
+            let deviations = design
+                .get_node_deviations(&node_id, &scan, &options)
+                .unwrap();
+            assert!(deviations.into_iter().flatten().all(|d| d <= -0.03 + 1e-9));
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rough estimates of what building an octree will result in, so that parameters can be tuned
+//! before starting a build that might take hours.
+
+use crate::errors::*;
+use crate::geometry::{Aabb, Cube};
+use crate::octree::extent::scan_inputs;
+use crate::octree::generation::MAX_POINTS_PER_NODE;
+use crate::octree::{InputFile, UpAxis};
+use crate::read_write::PositionEncoding;
+use std::fmt;
+use std::time::Duration;
+
+// Bytes per point of the standard attributes: color (3 x u8) and intensity (f32).
+const ATTRIBUTE_BYTES_PER_POINT: u64 = 3 + 4;
+
+// Every point is read and written once per level while splitting and once more while
+// subsampling. This is the throughput of one such pass on a single thread with an SSD, measured
+// on typical terrestrial scans.
+const POINTS_PER_SECOND_PER_PASS_AND_THREAD: f64 = 1_500_000.;
+
+#[derive(Debug)]
+pub struct BuildEstimate {
+    pub bounding_box: Aabb,
+    pub num_points: usize,
+    pub resolution: f64,
+    pub suggested_resolution: f64,
+    pub max_points_per_node: i64,
+    pub estimated_depth: u8,
+    pub estimated_num_nodes: u64,
+    pub estimated_output_bytes: u64,
+    pub estimated_build_time: Duration,
+}
+
+/// Scans all 'inputs' and estimates the results of building an octree with 'resolution' using
+/// 'num_threads' threads.
+///
+/// Most point clouds are samples of surfaces, so we assume that the points of a node are spread
+/// over 4 of its 8 children.
+pub fn estimate_build(
+    inputs: &[InputFile],
+    resolution: f64,
+    num_threads: usize,
+) -> Result<BuildEstimate> {
+    let extent = scan_inputs(inputs, UpAxis::Z)?;
+    let num_points = extent.num_points;
+    let bounding_box = extent.bounding_box;
+    let root_edge_length = Cube::bounding(&bounding_box).edge_length();
+
+    // Nodes stop splitting if they have few enough points or are smaller than the resolution.
+    let depth_for_points = (num_points as f64 / MAX_POINTS_PER_NODE as f64)
+        .log(4.)
+        .ceil()
+        .max(0.);
+    let depth_for_resolution = (root_edge_length / resolution).log2().ceil().max(0.);
+    let estimated_depth = depth_for_points.min(depth_for_resolution) as u8;
+    let estimated_num_nodes = (0..=u32::from(estimated_depth))
+        .map(|level| 4u64.saturating_pow(level))
+        .sum::<u64>()
+        .min(num_points as u64);
+
+    let leaf_edge_length = root_edge_length / 2f64.powi(i32::from(estimated_depth));
+    let leaf_cube = Cube::new(*bounding_box.min(), leaf_edge_length);
+    let bytes_per_coordinate =
+        PositionEncoding::new(&leaf_cube, resolution).bytes_per_coordinate() as u64;
+    let estimated_output_bytes =
+        num_points as u64 * (3 * bytes_per_coordinate + ATTRIBUTE_BYTES_PER_POINT);
+
+    // Spacing of points if they were evenly spread over the ground area of the bounding box.
+    let diag = bounding_box.diag();
+    let area = (diag.x * diag.y).max(diag.x * diag.z).max(diag.y * diag.z);
+    let point_spacing = (area / num_points.max(1) as f64).sqrt();
+    // A tenth of the point spacing keeps the quantization error invisible.
+    let suggested_resolution = if point_spacing > 0. {
+        10f64.powf((point_spacing / 10.).log10().floor())
+    } else {
+        resolution
+    };
+
+    let num_passes = f64::from(estimated_depth) + 1.;
+    let seconds = num_points as f64 * num_passes
+        / (POINTS_PER_SECOND_PER_PASS_AND_THREAD * num_threads.max(1) as f64);
+
+    Ok(BuildEstimate {
+        bounding_box,
+        num_points,
+        resolution,
+        suggested_resolution,
+        max_points_per_node: MAX_POINTS_PER_NODE,
+        estimated_depth,
+        estimated_num_nodes,
+        estimated_output_bytes,
+        estimated_build_time: Duration::from_secs_f64(seconds),
+    })
+}
+
+impl fmt::Display for BuildEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let min = self.bounding_box.min();
+        let max = self.bounding_box.max();
+        writeln!(
+            f,
+            "Bounding box:           [{:.3}, {:.3}, {:.3}] - [{:.3}, {:.3}, {:.3}]",
+            min.x, min.y, min.z, max.x, max.y, max.z
+        )?;
+        writeln!(f, "Number of points:       {}", self.num_points)?;
+        writeln!(f, "Resolution:             {}", self.resolution)?;
+        writeln!(f, "Suggested resolution:   {}", self.suggested_resolution)?;
+        writeln!(f, "Max points per node:    {}", self.max_points_per_node)?;
+        writeln!(f, "Estimated depth:        {}", self.estimated_depth)?;
+        writeln!(f, "Estimated nodes:        {}", self.estimated_num_nodes)?;
+        writeln!(
+            f,
+            "Estimated output size:  {:.2} GiB",
+            self.estimated_output_bytes as f64 / f64::from(1 << 30)
+        )?;
+        let minutes = self.estimated_build_time.as_secs() / 60;
+        write!(
+            f,
+            "Estimated build time:   {}h {}min",
+            minutes / 60,
+            minutes % 60
+        )
+    }
+}
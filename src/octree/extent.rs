@@ -0,0 +1,117 @@
+//! A fast first pass over the input that computes its exact bounding box and number of points,
+//! so that the builder does not need the bounding box up front.
+
+use crate::color::ColorSpace;
+use crate::errors::*;
+use crate::geometry::Aabb;
+use crate::octree::{InputFile, MultiFileIterator, UpAxis};
+use crate::utils::{create_progress_bar, create_syncable_progress_bar};
+use crate::{NextInto, NumberOfPoints, PointsBatch, NUM_POINTS_PER_BATCH};
+use nalgebra::Point3;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+/// The bounding box and number of points of an input.
+#[derive(Clone, Debug)]
+pub struct InputExtent {
+    /// 'Aabb::zero' if there are no points.
+    pub bounding_box: Aabb,
+    pub num_points: usize,
+}
+
+impl InputExtent {
+    fn merge(self, other: InputExtent) -> InputExtent {
+        match (self.num_points, other.num_points) {
+            (0, _) => other,
+            (_, 0) => self,
+            _ => {
+                let mut bounding_box = self.bounding_box;
+                bounding_box.grow(*other.bounding_box.min());
+                bounding_box.grow(*other.bounding_box.max());
+                InputExtent {
+                    bounding_box,
+                    num_points: self.num_points + other.num_points,
+                }
+            }
+        }
+    }
+}
+
+/// Grows 'min' and 'max' by 'positions'. The coordinates are reduced independently and without
+/// branches, which the compiler vectorizes.
+fn grow_bounds(positions: &[Point3<f64>], min: &mut [f64; 3], max: &mut [f64; 3]) {
+    for p in positions {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+}
+
+/// Reads all batches of 'stream' and returns their extent. 'progress' is called with the number
+/// of points of every batch.
+fn extent_of(mut stream: impl NextInto, mut progress: impl FnMut(usize)) -> InputExtent {
+    let mut min = [std::f64::INFINITY; 3];
+    let mut max = [std::f64::NEG_INFINITY; 3];
+    let mut num_points = 0;
+    let mut batch = PointsBatch::default();
+    while stream.next_into(&mut batch) {
+        grow_bounds(&batch.position, &mut min, &mut max);
+        num_points += batch.position.len();
+        progress(batch.position.len());
+    }
+    let bounding_box = if num_points > 0 {
+        Aabb::new(Point3::from(min), Point3::from(max))
+    } else {
+        Aabb::zero()
+    };
+    InputExtent {
+        bounding_box,
+        num_points,
+    }
+}
+
+/// Returns the extent of the points of 'stream'.
+pub fn scan_extent(stream: impl NextInto + NumberOfPoints) -> InputExtent {
+    let mut progress_bar = create_progress_bar(stream.num_points(), "Determining bounding box");
+    let extent = extent_of(stream, |num_points| {
+        progress_bar.add(num_points as u64);
+    });
+    progress_bar.finish();
+    extent
+}
+
+/// Returns the extent of the points of 'inputs' in the octree frame with 'up_axis' up. The files
+/// are read in parallel, and only their positions are transformed.
+pub fn scan_inputs(inputs: &[InputFile], up_axis: UpAxis) -> Result<InputExtent> {
+    let inputs: Vec<InputFile> = inputs
+        .iter()
+        .map(|input| InputFile {
+            intensity_normalizer: None,
+            source_id: None,
+            frame_index: None,
+            color_space: ColorSpace::Srgb,
+            ..input.clone()
+        })
+        .collect();
+    // Opening all files first reports unreadable ones before the scan starts.
+    let total = MultiFileIterator::new(&inputs, NUM_POINTS_PER_BATCH)?.num_points();
+    let progress_bar = create_syncable_progress_bar(total, "Determining bounding box");
+    let extents = inputs
+        .par_iter()
+        .map(|input| {
+            let stream = MultiFileIterator::new(std::slice::from_ref(input), NUM_POINTS_PER_BATCH)?
+                .with_up_axis(up_axis);
+            Ok(extent_of(stream, |num_points| {
+                progress_bar.lock().unwrap().add(num_points as u64);
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    progress_bar.lock().unwrap().finish();
+    Ok(extents.into_iter().fold(
+        InputExtent {
+            bounding_box: Aabb::zero(),
+            num_points: 0,
+        },
+        InputExtent::merge,
+    ))
+}
@@ -12,29 +12,248 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::classify::{Classifier, CLASSIFICATION};
+use crate::color::ColorSpace;
+use crate::colorize::Colorizer;
 use crate::data_provider::OnDiskDataProvider;
 use crate::errors::*;
 use crate::geometry::{Aabb, Cube};
-use crate::octree::{self, to_meta_proto, to_node_proto, ChildIndex, NodeId, OctreeMeta};
+use crate::intensity::{IntensityNormalization, IntensityNormalizer, NORMALIZED_INTENSITY};
+use crate::octree::baked_colors::bake_colors;
+use crate::octree::extent::{scan_extent, scan_inputs};
+use crate::octree::merge::{merge_points, PointMerging};
+use crate::octree::{
+    self, compute_checksums, gather_curvature, gather_normals, to_meta_proto, to_node_proto,
+    write_meta, write_preview, BakedColors, ChildIndex, KdIndex, NodeId, NodeSchema, NormalCone,
+    Occupancy, Octree, OctreeLock, OctreeMeta, Subdivision, UpAxis, CURVATURE, FRAME_INDEX,
+    KD_INDEX, NORMAL, PREVIEW_FILENAME, SOURCE_ID,
+};
 use crate::proto;
 use crate::read_write::{
-    attempt_increasing_rlimit_to_max, Encoding, NodeIterator, NodeWriter, OpenMode, PlyIterator,
-    PositionEncoding, RawNodeWriter,
+    attempt_increasing_rlimit_to_max, write_file_atomically, Encoding, NodeIterator, NodeWriter,
+    OpenMode, PlyIterator, PositionEncoding, RawNodeWriter,
+};
+use crate::utils::{create_progress_bar, create_syncable_progress_bar};
+use crate::{
+    AttributeData, AttributeDataType, NextInto, NumberOfPoints, PointCloudMeta, PointsBatch,
+    NUM_POINTS_PER_BATCH,
 };
-use crate::utils::create_progress_bar;
-use crate::META_FILENAME;
-use crate::{AttributeDataType, NumberOfPoints, PointCloudMeta, PointsBatch, NUM_POINTS_PER_BATCH};
-use fnv::{FnvHashMap, FnvHashSet};
-use protobuf::Message;
+use fnv::{FnvHashMap, FnvHasher};
+use log::{debug, info, warn};
+use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+use pbr::ProgressBar;
+use rand::seq::index;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use rayon::Scope;
 use std::cmp;
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::BufWriter;
-use std::path::Path;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+pub(super) const MAX_POINTS_PER_NODE: i64 = 100_000;
+
+/// How many points the nodes of one level of the octree hold, see 'BuildOptions::node_limits'.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeLimits {
+    /// Nodes with at least this many points are split into children, unless they are already as
+    /// small as the resolution.
+    pub min_points_to_split: i64,
+    /// The most points that inner nodes keep when they are subsampled from their children. By
+    /// default, every child gives an eighth of its points to its parent.
+    pub capacity: Option<i64>,
+}
+
+impl Default for NodeLimits {
+    fn default() -> Self {
+        Self {
+            min_points_to_split: MAX_POINTS_PER_NODE + 1,
+            capacity: None,
+        }
+    }
+}
+
+impl NodeLimits {
+    /// The limits of the nodes of 'level' in 'node_limits', which is indexed by level. Deeper
+    /// levels use the last entry.
+    fn for_level(node_limits: &[NodeLimits], level: u8) -> NodeLimits {
+        node_limits
+            .get(usize::from(level))
+            .or_else(|| node_limits.last())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Settings of the octree build. 'max_points_in_memory' and 'write_queue_len' only tune memory
+/// use and speed and do not change the result, all other settings change the written octree.
+#[derive(Clone, Debug)]
+pub struct BuildOptions {
+    /// Upper bound for the number of points that all threads hold in memory together. Threads
+    /// wait before reading more points until others are done. A point takes roughly 60 bytes
+    /// while it is being split.
+    pub max_points_in_memory: usize,
+    /// Number of batches per node being split that wait to be written to disk while the next
+    /// batch is partitioned. Higher values smooth out slow disks at the cost of memory.
+    pub write_queue_len: usize,
+    /// Seed for choosing the points that are moved up into the parent nodes. It is recorded in
+    /// the meta data, and building the same input with the same seed gives identical files.
+    pub seed: u64,
+    /// Stores the intensity normalized per input file as 'normalized_intensity' next to the raw
+    /// intensity. Only used when building from files.
+    pub intensity_normalization: Option<IntensityNormalization>,
+    /// Colors the points from images, replacing the colors of the input files where an image sees
+    /// a point. Only used when building from files.
+    pub colorizer: Option<Arc<Colorizer>>,
+    /// Stores the classes assigned by this classifier as the attribute 'classification'. Only
+    /// used when building from files.
+    pub classifier: Option<Arc<Mutex<dyn Classifier>>>,
+    /// Stores the checksums of the node files in the meta data, so that reads detect corruption.
+    /// Computing them reads the octree once more at the end of the build.
+    pub checksums: bool,
+    /// Coordinate reference system of the input positions, e.g. "EPSG:25832", which is recorded
+    /// in the meta data.
+    pub crs: Option<String>,
+    /// Stores the index of the input file of every point as the attribute 'source_id' and the
+    /// paths of the files in the meta data, so that the points of a bad scan can be found later.
+    /// Only used when building from files.
+    pub source_ids: bool,
+    /// Builds the octree in a frame with this pose in the frame of the input points, e.g. one
+    /// aligned to a long corridor with 'find_principal_frame', so that the bounding box and the
+    /// root cube fit the data tightly. It is recorded in the meta data. Points passed to
+    /// 'build_octree' must already be in this frame.
+    pub world_from_octree: Option<Isometry3<f64>>,
+    /// Splits nodes only in x and y with 'Subdivision::Quadtree', which suits airborne data. It is
+    /// recorded in the meta data.
+    pub subdivision: Subdivision,
+    /// Stores a kd-tree of the points of every node as '<node>.kd_index', so that
+    /// 'Octree::nearest_in_node' and 'Octree::points_within_radius_in_node' do not compare every
+    /// point. It takes 5 bytes per point and reads the octree once more at the end of the build.
+    pub kd_index: bool,
+    /// The capture time of every input file, for captures of a scene over time such as the
+    /// progress on a construction site. Files with the same timestamp form one frame. The points
+    /// store the index of their frame as the attribute 'frame_index', and the timestamps of the
+    /// frames are recorded in the meta data. Only used when building from files.
+    pub frame_timestamps: Option<Vec<f64>>,
+    /// Replaces the colors of the coarse levels by a pseudo-color from the intensity or the
+    /// height, so that clients that only draw RGB show meaningful overviews. The finer levels keep
+    /// their colors.
+    pub baked_colors: Option<BakedColors>,
+    /// Reads the input files in batches of about this many bytes instead of
+    /// 'NUM_POINTS_PER_BATCH' points, so that points with many attributes do not make batches
+    /// huge and lean points do not make them small. Only used when building from files.
+    pub max_batch_bytes: Option<usize>,
+    /// Color space of the attribute 'color' of the octree, which is recorded in the meta data.
+    /// The colors of input files with another 'InputFile::color_space' are converted to it.
+    /// 'colorizer' needs it to be sRGB, like its images.
+    pub color_space: ColorSpace,
+    /// The axis that points up in the octree frame, which is recorded in the view settings. The
+    /// points of input files with another 'InputFile::up_axis' are rotated to it. Quadtrees and
+    /// baked height colors split and color along it, so quadtrees need it to be z.
+    pub up_axis: UpAxis,
+    /// Merges points that are closer than a tolerance, e.g. where overlapping scan passes
+    /// multiply the density along their seams. Only points in the same leaf node are merged.
+    pub merge_points: Option<PointMerging>,
+    /// Stores the normals of the input files, their properties 'nx', 'ny' and 'nz', as the
+    /// attribute 'normal' and bounds them per node with a 'NormalCone', so that viewers can skip
+    /// nodes that face away from the camera. Only used when building from files; 'build_octree'
+    /// computes the cones if its input has the attribute 'normal'.
+    pub normals: bool,
+    /// Stores the property 'curvature' of the input files as the attribute 'curvature', which the
+    /// SDL viewer shades with together with 'normals'. Only used when building from files.
+    pub curvature: bool,
+    /// The limits of the nodes of each level, starting at the root. Deeper levels use the last
+    /// entry. Raising 'NodeLimits::min_points_to_split' for the deep levels keeps sparse areas at
+    /// the edge of a scan from growing as deep as its dense center.
+    pub node_limits: Vec<NodeLimits>,
+    /// Records which of the 4 x 4 x 4 cells of every node contain points of its subtree, so that
+    /// queries and viewers skip the empty parts of nodes, see 'Occupancy'. It reads the octree
+    /// once more at the end of the build.
+    pub occupancy: bool,
+    /// Writes a preview of the whole octree with at most this many points to 'PREVIEW_FILENAME'
+    /// in the octree directory, see 'write_preview'. It reads the coarse levels once more at the
+    /// end of the build.
+    pub preview_points: Option<usize>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            max_points_in_memory: 50_000_000,
+            write_queue_len: 8,
+            seed: 0,
+            intensity_normalization: None,
+            colorizer: None,
+            classifier: None,
+            checksums: true,
+            crs: None,
+            source_ids: false,
+            world_from_octree: None,
+            subdivision: Subdivision::Octree,
+            kd_index: false,
+            frame_timestamps: None,
+            baked_colors: None,
+            max_batch_bytes: None,
+            color_space: ColorSpace::Srgb,
+            up_axis: UpAxis::Z,
+            merge_points: None,
+            normals: false,
+            curvature: false,
+            node_limits: vec![NodeLimits::default()],
+            occupancy: false,
+            preview_points: None,
+        }
+    }
+}
 
-const MAX_POINTS_PER_NODE: i64 = 100_000;
+/// Counts the points that are held in memory by the build tasks and blocks tasks that would
+/// exceed the maximum. Tasks never wait for a budget while holding one, so this cannot deadlock.
+struct PointBudget {
+    max_points: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl PointBudget {
+    fn new(max_points: usize) -> Self {
+        Self {
+            max_points: cmp::max(max_points, 1),
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Blocks until 'num_points' fit into the budget. Requests larger than the whole budget wait
+    /// until nothing else is in flight.
+    fn acquire(&self, num_points: usize) -> PointBudgetGuard<'_> {
+        let num_points = cmp::min(num_points, self.max_points);
+        let mut used = self.used.lock().unwrap();
+        while *used + num_points > self.max_points {
+            used = self.freed.wait(used).unwrap();
+        }
+        *used += num_points;
+        PointBudgetGuard {
+            budget: self,
+            num_points,
+        }
+    }
+}
+
+struct PointBudgetGuard<'a> {
+    budget: &'a PointBudget,
+    num_points: usize,
+}
+
+impl Drop for PointBudgetGuard<'_> {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap() -= self.num_points;
+        self.budget.freed.notify_all();
+    }
+}
 
 impl RawNodeWriter {
     fn from_data_provider(
@@ -43,7 +262,7 @@ impl RawNodeWriter {
         node_id: &NodeId,
     ) -> Self {
         let path = octree_data_provider.stem(&node_id.to_string());
-        let bounding_cube = node_id.find_bounding_cube(&Cube::bounding(&octree_meta.bounding_box));
+        let bounding_cube = octree_meta.bounding_cube(node_id);
         let position_encoding = PositionEncoding::new(&bounding_cube, octree_meta.resolution);
         let min = bounding_cube.min();
         RawNodeWriter::new(
@@ -58,65 +277,97 @@ impl RawNodeWriter {
 fn split<P>(
     octree_data_provider: &OnDiskDataProvider,
     octree_meta: &octree::OctreeMeta,
+    budget: &PointBudget,
+    write_queue_len: usize,
+    node_limits: &[NodeLimits],
     node_id: &octree::NodeId,
-    stream: P,
+    mut stream: P,
 ) -> (Vec<octree::NodeId>, Vec<octree::NodeId>)
 where
-    P: Iterator<Item = PointsBatch> + NumberOfPoints,
+    P: NextInto + NumberOfPoints,
 {
-    let mut children: Vec<Option<RawNodeWriter>> =
-        vec![None, None, None, None, None, None, None, None];
     let size = stream.num_points();
-    eprintln!(
-        "Splitting {} which has {} points ({:.2}x MAX_POINTS_PER_NODE).",
-        node_id,
-        size,
-        size as f64 / MAX_POINTS_PER_NODE as f64
-    );
+    debug!("Splitting {} which has {} points.", node_id, size);
 
     let bounding_cube = node_id.find_bounding_cube(&Cube::bounding(&octree_meta.bounding_box));
-    stream.for_each(|batch| {
-        let child_indices: Vec<_> = batch
-            .position
-            .iter()
-            .map(|p| octree::ChildIndex::from_bounding_cube(&bounding_cube, p))
-            .collect();
-        for (array_index, child_writer) in children.iter_mut().enumerate() {
-            let mut child_batch = batch.clone();
-            let keep: Vec<_> = child_indices
+    // A batch, the copy for one child, the queued children and the one being written are in
+    // memory at the same time.
+    let _budget_guard =
+        budget.acquire((3 + write_queue_len) * cmp::min(size, NUM_POINTS_PER_BATCH));
+    // The children are encoded and written on a separate thread, so that disk writes overlap with
+    // partitioning the next batch.
+    let children_num_written = crossbeam::scope(|s| {
+        let (child_batch_sender, child_batch_receiver) =
+            crossbeam::channel::bounded::<(usize, PointsBatch)>(write_queue_len);
+        let writer_thread = s.spawn(move |_| {
+            let mut children: Vec<Option<RawNodeWriter>> =
+                vec![None, None, None, None, None, None, None, None];
+            for (array_index, child_batch) in child_batch_receiver {
+                children[array_index]
+                    .get_or_insert_with(|| {
+                        RawNodeWriter::from_data_provider(
+                            octree_data_provider,
+                            octree_meta,
+                            &node_id.get_child_id(ChildIndex::from_u8(array_index as u8)),
+                        )
+                    })
+                    .write(&child_batch)
+                    .unwrap();
+            }
+            // Finishing the writers completes their files before the children are read again.
+            children
+                .into_iter()
+                .map(|c| {
+                    c.map(|c| {
+                        let num_written = c.num_written();
+                        c.finish().unwrap();
+                        num_written
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut batch = PointsBatch::default();
+        while stream.next_into(&mut batch) {
+            let child_indices: Vec<_> = batch
+                .position
                 .iter()
-                .map(|i| i.as_u8() == array_index as u8)
+                .map(|p| octree_meta.subdivision.child_index(&bounding_cube, p))
                 .collect();
-            child_batch.retain(&keep);
-            if !child_batch.position.is_empty() {
-                if child_writer.is_none() {
-                    *child_writer = Some(RawNodeWriter::from_data_provider(
-                        octree_data_provider,
-                        octree_meta,
-                        &node_id.get_child_id(ChildIndex::from_u8(array_index as u8)),
-                    ));
+            for array_index in 0..8 {
+                let mut child_batch = batch.clone();
+                let keep: Vec<_> = child_indices
+                    .iter()
+                    .map(|i| i.as_u8() == array_index as u8)
+                    .collect();
+                child_batch.retain(&keep);
+                if !child_batch.position.is_empty() {
+                    child_batch_sender.send((array_index, child_batch)).unwrap();
                 }
-                child_writer.as_mut().unwrap().write(&child_batch).unwrap();
             }
         }
-    });
+        drop(child_batch_sender);
+        writer_thread.join().unwrap()
+    })
+    .unwrap();
 
-    // Remove the node file on disk by reopening the node and immediately dropping it again without
+    // Remove the node file on disk by reopening the node and immediately finishing it again without
     // writing a point. This only saves some disk space during processing - all nodes will be
     // rewritten by subsampling the children in the second step anyways. We also ignore file
     // removing error. For example, we never write out the root, so it cannot be removed.
-    RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, node_id);
+    let _ = RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, node_id).finish();
 
     let mut leaf_nodes = Vec::new();
     let mut split_nodes = Vec::new();
-    for (child_index, c) in children.into_iter().enumerate() {
-        if c.is_none() {
-            continue;
-        }
-        let c = c.unwrap();
+    for (child_index, num_written) in children_num_written.into_iter().enumerate() {
+        let num_written = match num_written {
+            Some(num_written) => num_written,
+            None => continue,
+        };
         let child_id = node_id.get_child_id(octree::ChildIndex::from_u8(child_index as u8));
 
-        if should_split_node(&child_id, c.num_written(), octree_meta) {
+        let limits = NodeLimits::for_level(node_limits, child_id.level());
+        if should_split_node(&child_id, num_written, octree_meta, &limits) {
             split_nodes.push(child_id);
         } else {
             leaf_nodes.push(child_id);
@@ -129,38 +380,51 @@ fn should_split_node(
     id: &octree::NodeId,
     num_points: i64,
     octree_meta: &octree::OctreeMeta,
+    limits: &NodeLimits,
 ) -> bool {
-    if num_points <= MAX_POINTS_PER_NODE {
+    if num_points < limits.min_points_to_split {
         return false;
     }
     let bounding_cube = id.find_bounding_cube(&Cube::bounding(&octree_meta.bounding_box));
     if bounding_cube.edge_length() <= octree_meta.resolution {
         // TODO(hrapp): If the data has billion of points in this small spot, performance will
         // greatly suffer if we display it. Drop points?
-        eprintln!(
-            "Node {} which has {} points ({:.2}x MAX_POINTS_PER_NODE) \
+        warn!(
+            "Node {} which has {} points ({:.2}x the minimum to split) \
              is too small to be split, keeping all points.",
             id,
             num_points,
-            num_points as f64 / MAX_POINTS_PER_NODE as f64
+            num_points as f64 / limits.min_points_to_split as f64
         );
         return false;
     }
     true
 }
 
+#[allow(clippy::too_many_arguments)]
 fn split_node<'a, P>(
     scope: &Scope<'a>,
     octree_data_provider: &'a OnDiskDataProvider,
     octree_meta: &'a octree::OctreeMeta,
     attribute_data_types: &'a HashMap<String, AttributeDataType>,
+    budget: &'a PointBudget,
+    write_queue_len: usize,
+    node_limits: &'a [NodeLimits],
     node_id: &octree::NodeId,
     stream: P,
     leaf_nodes_sender: &crossbeam::channel::Sender<octree::NodeId>,
 ) where
-    P: Iterator<Item = PointsBatch> + NumberOfPoints,
+    P: NextInto + NumberOfPoints,
 {
-    let (leaf_nodes, split_nodes) = split(octree_data_provider, octree_meta, node_id, stream);
+    let (leaf_nodes, split_nodes) = split(
+        octree_data_provider,
+        octree_meta,
+        budget,
+        write_queue_len,
+        node_limits,
+        node_id,
+        stream,
+    );
     for child_id in split_nodes {
         let leaf_nodes_sender_clone = leaf_nodes_sender.clone();
         scope.spawn(move |scope| {
@@ -168,6 +432,7 @@ fn split_node<'a, P>(
                 octree_data_provider,
                 attribute_data_types,
                 octree_meta.encoding_for_node(child_id),
+                false,
                 &child_id,
                 octree_data_provider
                     .number_of_points(&child_id.to_string())
@@ -180,6 +445,9 @@ fn split_node<'a, P>(
                 octree_data_provider,
                 octree_meta,
                 attribute_data_types,
+                budget,
+                write_queue_len,
+                node_limits,
                 &child_id,
                 stream,
                 &leaf_nodes_sender_clone,
@@ -192,41 +460,70 @@ fn split_node<'a, P>(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn subsample_children_into(
     octree_data_provider: &OnDiskDataProvider,
     octree_meta: &octree::OctreeMeta,
     attribute_data_types: &HashMap<String, AttributeDataType>,
+    budget: &PointBudget,
+    node_limits: &[NodeLimits],
     node_id: &octree::NodeId,
+    children: &FnvHashMap<NodeId, Vec<NodeId>>,
+    merge_points_in_leaves: Option<&PointMerging>,
     nodes_sender: &crossbeam::channel::Sender<(octree::NodeId, i64)>,
 ) -> Result<()> {
     let mut parent_writer =
         RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, node_id);
+    let mut children_num_points = Vec::with_capacity(8);
     for i in 0..8 {
         let child_id = node_id.get_child_id(octree::ChildIndex::from_u8(i));
-        let num_points = match octree_data_provider.number_of_points(&child_id.to_string()) {
-            Ok(num_points) => num_points,
-            Err(Error(ErrorKind::NodeNotFound, _)) => continue,
+        match octree_data_provider.number_of_points(&child_id.to_string()) {
+            Ok(num_points) => children_num_points.push((child_id, num_points)),
+            Err(Error::NodeNotFound) => continue,
             Err(err) => return Err(err),
-        };
+        }
+    }
+    // With a capacity, every child gives the parent a share of it in proportion to its points.
+    let capacity = NodeLimits::for_level(node_limits, node_id.level()).capacity;
+    let total_num_points: i64 = children_num_points.iter().map(|(_, n)| n).sum();
+    for (child_id, num_points) in children_num_points {
         let mut node_iterator = NodeIterator::from_data_provider(
             octree_data_provider,
             attribute_data_types,
             octree_meta.encoding_for_node(child_id),
+            false,
             &child_id,
             num_points as usize,
             NUM_POINTS_PER_BATCH,
         )?;
 
         // We read all points into memory, because the new node writer will rewrite this child's
-        // file(s).
+        // file(s). The parent and child copies double that.
+        let _budget_guard = budget.acquire(2 * num_points as usize);
         let mut batch = node_iterator.next().unwrap();
         node_iterator.for_each(|mut b| batch.append(&mut b).unwrap());
-        let (keep_parent, keep_child): (Vec<bool>, Vec<bool>) = (0..batch.position.len())
-            .map(|i| {
-                let in_parent = i % 8 == 0;
-                (in_parent, !in_parent)
-            })
-            .unzip();
+        let is_leaf = !children.contains_key(&child_id);
+        if let Some(merging) = merge_points_in_leaves.filter(|_| is_leaf) {
+            let num_merged = merge_points(&mut batch, merging);
+            debug!("Merged {} points of {}.", num_merged, child_id);
+        }
+        // Every child gets its own random stream, so the result does not depend on the order in
+        // which the nodes are processed.
+        let mut hasher = FnvHasher::with_key(octree_meta.subsampling_seed);
+        child_id.hash(&mut hasher);
+        let mut rng = ChaCha8Rng::seed_from_u64(hasher.finish());
+        let num_points_in_file = num_points;
+        let num_points = batch.position.len();
+        let mut num_parent_points = (num_points + 7) / 8;
+        if let Some(capacity) = capacity {
+            let share = capacity as f64 * num_points_in_file as f64 / total_num_points as f64;
+            num_parent_points = cmp::min(num_parent_points, share as usize);
+        }
+        let mut keep_parent = vec![false; num_points];
+        for i in index::sample(&mut rng, num_points, num_parent_points) {
+            keep_parent[i] = true;
+        }
+        let keep_child: Vec<bool> = keep_parent.iter().map(|in_parent| !in_parent).collect();
         let mut parent_batch = batch.clone();
         parent_batch.retain(&keep_parent);
         let mut child_batch = batch;
@@ -236,37 +533,497 @@ fn subsample_children_into(
             RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, &child_id);
         parent_writer.write(&parent_batch)?;
         child_writer.write(&child_batch)?;
+        let num_written = child_writer.num_written();
+        child_writer.finish()?;
 
         // Update child.
-        nodes_sender
-            .send((child_id, child_writer.num_written()))
-            .unwrap();
+        nodes_sender.send((child_id, num_written)).unwrap();
     }
 
+    let num_written = parent_writer.num_written();
+    parent_writer.finish()?;
     // Make sure the root node is also tracked as an existing node.
     if node_id.level() == 0 {
-        nodes_sender
-            .send((*node_id, parent_writer.num_written()))
-            .unwrap();
+        nodes_sender.send((*node_id, num_written)).unwrap();
     }
     Ok(())
 }
 
-/// Returns the bounding box containing all points
-fn find_bounding_box(filename: impl AsRef<Path>) -> Aabb {
-    let mut bounding_box = None;
-    let stream = PlyIterator::from_file(filename, NUM_POINTS_PER_BATCH).unwrap();
-    let mut progress_bar = create_progress_bar(stream.num_points(), "Determining bounding box");
+/// Subsamples the children of all inner nodes below and including 'node_id', from the bottom up.
+/// A node is built as soon as all its children are finished, and the subtrees of the children are
+/// processed in parallel, so idle threads steal work from other subtrees instead of waiting for a
+/// whole level of the tree to finish.
+#[allow(clippy::too_many_arguments)]
+fn subsample_subtree(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &octree::OctreeMeta,
+    attribute_data_types: &HashMap<String, AttributeDataType>,
+    budget: &PointBudget,
+    node_limits: &[NodeLimits],
+    node_id: &octree::NodeId,
+    children: &FnvHashMap<NodeId, Vec<NodeId>>,
+    merge_points_in_leaves: Option<&PointMerging>,
+    nodes_sender: &crossbeam::channel::Sender<(octree::NodeId, i64)>,
+    progress_bar: &Mutex<ProgressBar<io::Stderr>>,
+) -> Result<()> {
+    let child_ids = match children.get(node_id) {
+        Some(child_ids) => child_ids,
+        None => return Ok(()),
+    };
+    child_ids.par_iter().try_for_each(|child_id| {
+        subsample_subtree(
+            octree_data_provider,
+            octree_meta,
+            attribute_data_types,
+            budget,
+            node_limits,
+            child_id,
+            children,
+            merge_points_in_leaves,
+            nodes_sender,
+            progress_bar,
+        )
+    })?;
+    subsample_children_into(
+        octree_data_provider,
+        octree_meta,
+        attribute_data_types,
+        budget,
+        node_limits,
+        node_id,
+        children,
+        merge_points_in_leaves,
+        nodes_sender,
+    )?;
+    progress_bar.lock().unwrap().inc();
+    Ok(())
+}
 
-    stream.for_each(|batch| {
-        for pos in batch.position {
-            let b = bounding_box.get_or_insert(Aabb::new(pos, pos));
-            b.grow(pos);
-            progress_bar.inc();
+/// A file to build the octree from, together with the transform from the file's frame into the
+/// frame of the octree.
+#[derive(Clone, Debug)]
+pub struct InputFile {
+    pub path: PathBuf,
+    pub octree_from_file: Isometry3<f64>,
+    /// Adds the normalized intensity of the points of this file.
+    pub intensity_normalizer: Option<IntensityNormalizer>,
+    /// Stores this id as the attribute 'source_id' of the points of this file.
+    pub source_id: Option<u16>,
+    /// Stores this index as the attribute 'frame_index' of the points of this file.
+    pub frame_index: Option<u32>,
+    /// Tolerates common violations of the PLY format, see 'PlyIterator::from_file_lenient'.
+    pub lenient: bool,
+    /// Color space of the colors in this file, see 'BuildOptions::color_space'.
+    pub color_space: ColorSpace,
+    /// The axis that points up in this file, see 'BuildOptions::up_axis'. The points are rotated
+    /// to the up axis of the octree before 'octree_from_file' is applied.
+    pub up_axis: UpAxis,
+}
+
+impl InputFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            octree_from_file: Isometry3::identity(),
+            intensity_normalizer: None,
+            source_id: None,
+            frame_index: None,
+            lenient: false,
+            color_space: ColorSpace::Srgb,
+            up_axis: UpAxis::Z,
         }
-    });
+    }
+
+    fn open(&self, batch_size: usize) -> Result<PlyIterator> {
+        if self.lenient {
+            PlyIterator::from_file_lenient(&self.path, batch_size)
+        } else {
+            PlyIterator::from_file(&self.path, batch_size)
+        }
+    }
+}
+
+/// Streams the points of several files one after the other, transformed into the octree frame.
+/// All files must have the same attributes.
+pub struct MultiFileIterator {
+    streams: VecDeque<(PlyIterator, InputFile)>,
+    num_points: usize,
+    color_space: ColorSpace,
+    up_axis: UpAxis,
+    normals: bool,
+    curvature: bool,
+    colorizer: Option<Arc<Colorizer>>,
+    classifier: Option<Arc<Mutex<dyn Classifier>>>,
+}
+
+impl MultiFileIterator {
+    pub fn new(inputs: &[InputFile], batch_size: usize) -> Result<Self> {
+        let streams = inputs
+            .iter()
+            .map(|input| {
+                input
+                    .open(batch_size)
+                    .with_context(|| format!("Could not open {}.", input.path.display()))
+                    .map(|stream| (stream, input.clone()))
+            })
+            .collect::<Result<VecDeque<_>>>()?;
+        if let Some((first, _)) = streams.front() {
+            let attribute_data_types = first.attribute_data_types();
+            for ((stream, _), input) in streams.iter().zip(inputs).skip(1) {
+                let other = stream.attribute_data_types();
+                if other != attribute_data_types {
+                    return Err(Error::SchemaMismatch(format!(
+                        "{} has the attributes {:?}, but {} has {:?}.",
+                        inputs[0].path.display(),
+                        attribute_data_types.keys().collect::<Vec<_>>(),
+                        input.path.display(),
+                        other.keys().collect::<Vec<_>>()
+                    )));
+                }
+            }
+        }
+        let num_points = streams.iter().map(|(s, _)| s.num_points()).sum();
+        Ok(Self {
+            streams,
+            num_points,
+            color_space: ColorSpace::Srgb,
+            up_axis: UpAxis::Z,
+            normals: false,
+            curvature: false,
+            colorizer: None,
+            classifier: None,
+        })
+    }
+
+    /// Sizes the batches of all files so that they take about 'max_bytes' of memory, see
+    /// 'PlyIterator::with_max_batch_bytes'.
+    pub fn with_max_batch_bytes(mut self, max_bytes: usize) -> Self {
+        self.streams = self
+            .streams
+            .into_iter()
+            .map(|(stream, input)| (stream.with_max_batch_bytes(max_bytes), input))
+            .collect();
+        self
+    }
+
+    /// Converts the colors of all files to 'color_space' instead of sRGB, see
+    /// 'InputFile::color_space'.
+    pub fn with_color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+
+    /// Rotates the points of all files to 'up_axis' instead of z, see 'InputFile::up_axis'.
+    pub fn with_up_axis(mut self, up_axis: UpAxis) -> Self {
+        self.up_axis = up_axis;
+        self
+    }
+
+    /// Replaces the properties 'nx', 'ny' and 'nz' of all files by the attribute 'normal' in the
+    /// octree frame, see 'BuildOptions::normals'. Panics on files without normals.
+    pub fn with_normals(mut self) -> Self {
+        self.normals = true;
+        self
+    }
+
+    /// Replaces the float property 'curvature' of all files by the attribute 'curvature', see
+    /// 'BuildOptions::curvature'. Panics on files without curvatures.
+    pub fn with_curvature(mut self) -> Self {
+        self.curvature = true;
+        self
+    }
+
+    /// Colors the points with 'colorizer' after they have been transformed into the octree frame.
+    pub fn with_colorizer(mut self, colorizer: Arc<Colorizer>) -> Self {
+        self.colorizer = Some(colorizer);
+        self
+    }
+
+    /// Classifies the points with 'classifier' after they have been transformed into the octree
+    /// frame.
+    pub fn with_classifier(mut self, classifier: Arc<Mutex<dyn Classifier>>) -> Self {
+        self.classifier = Some(classifier);
+        self
+    }
+}
+
+impl NumberOfPoints for MultiFileIterator {
+    fn num_points(&self) -> usize {
+        self.num_points
+    }
+}
+
+impl Iterator for MultiFileIterator {
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = PointsBatch::default();
+        if self.next_into(&mut batch) {
+            Some(batch)
+        } else {
+            None
+        }
+    }
+}
+
+impl NextInto for MultiFileIterator {
+    fn next_into(&mut self, batch: &mut PointsBatch) -> bool {
+        while let Some((stream, input)) = self.streams.front_mut() {
+            if stream.next_into(batch) {
+                let octree_from_file = if input.up_axis != self.up_axis {
+                    input.octree_from_file * input.up_axis.rotation_to(self.up_axis)
+                } else {
+                    input.octree_from_file
+                };
+                if octree_from_file != Isometry3::identity() {
+                    for p in &mut batch.position {
+                        *p = octree_from_file * *p;
+                    }
+                }
+                if self.normals && !gather_normals(batch, &octree_from_file.rotation) {
+                    panic!("{} has no normals.", input.path.display());
+                }
+                if self.curvature && !gather_curvature(batch) {
+                    panic!("{} has no curvatures.", input.path.display());
+                }
+                if input.color_space != self.color_space {
+                    if let Ok(color) = batch.get_attribute_vec_mut::<Vector3<u8>>("color") {
+                        input.color_space.convert_u8(
+                            self.color_space,
+                            color.iter_mut().flat_map(|c| c.iter_mut()),
+                        );
+                    }
+                }
+                if let Some(source_id) = input.source_id {
+                    batch.attributes.insert(
+                        SOURCE_ID.to_string(),
+                        AttributeData::U16(vec![source_id; batch.position.len()]),
+                    );
+                }
+                if let Some(frame_index) = input.frame_index {
+                    batch.attributes.insert(
+                        FRAME_INDEX.to_string(),
+                        AttributeData::U32(vec![frame_index; batch.position.len()]),
+                    );
+                }
+                if let Some(intensity_normalizer) = &input.intensity_normalizer {
+                    intensity_normalizer.add_normalized(batch);
+                }
+                if let Some(colorizer) = &self.colorizer {
+                    colorizer.colorize(batch);
+                }
+                if let Some(classifier) = &self.classifier {
+                    classifier
+                        .lock()
+                        .unwrap()
+                        .classify(batch)
+                        .unwrap_or_else(|e| panic!("Could not classify points: {}", e));
+                }
+                return true;
+            }
+            self.streams.pop_front();
+        }
+        false
+    }
+}
+
+/// Passes the batches of 'stream' through and advances a progress bar by their points. Its total
+/// comes from 'NumberOfPoints', so it shows how long reading all of the input will take.
+struct ProgressStream<P> {
+    stream: P,
+    progress_bar: Option<ProgressBar<io::Stderr>>,
+}
+
+impl<P: NumberOfPoints> ProgressStream<P> {
+    fn new(stream: P, message: &str) -> Self {
+        let progress_bar = Some(create_progress_bar(stream.num_points(), message));
+        Self {
+            stream,
+            progress_bar,
+        }
+    }
+}
+
+impl<P> NumberOfPoints for ProgressStream<P>
+where
+    P: NumberOfPoints,
+{
+    fn num_points(&self) -> usize {
+        self.stream.num_points()
+    }
+}
+
+impl<P> Iterator for ProgressStream<P>
+where
+    P: NextInto,
+{
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = PointsBatch::default();
+        if self.next_into(&mut batch) {
+            Some(batch)
+        } else {
+            None
+        }
+    }
+}
+
+impl<P> NextInto for ProgressStream<P>
+where
+    P: NextInto,
+{
+    fn next_into(&mut self, batch: &mut PointsBatch) -> bool {
+        if self.stream.next_into(batch) {
+            if let Some(progress_bar) = &mut self.progress_bar {
+                progress_bar.add(batch.position.len() as u64);
+            }
+            return true;
+        }
+        if let Some(mut progress_bar) = self.progress_bar.take() {
+            progress_bar.finish();
+        }
+        false
+    }
+}
+
+/// Returns a frame at the centroid of the points whose x axis points along the direction in which
+/// the points extend the most in the x-y plane, e.g. along a road or rail corridor. Octrees built
+/// in this frame have a much smaller root cube for elongated data that does not run along an
+/// axis. The z axis is kept, so that heights stay heights.
+pub fn find_principal_frame(mut stream: impl NextInto + NumberOfPoints) -> Isometry3<f64> {
+    let mut progress_bar = create_progress_bar(stream.num_points(), "Determining frame");
+    // Sums relative to the first point, which keeps the variances precise for georeferenced
+    // coordinates.
+    let mut origin = None;
+    let mut num_points = 0.;
+    let mut sum = Vector3::zeros();
+    let (mut sum_xx, mut sum_xy, mut sum_yy) = (0., 0., 0.);
+    let mut batch = PointsBatch::default();
+    while stream.next_into(&mut batch) {
+        for pos in &batch.position {
+            let d = pos - *origin.get_or_insert(*pos);
+            num_points += 1.;
+            sum += d;
+            sum_xx += d.x * d.x;
+            sum_xy += d.x * d.y;
+            sum_yy += d.y * d.y;
+        }
+        progress_bar.add(batch.position.len() as u64);
+    }
     progress_bar.finish();
-    bounding_box.unwrap_or_else(Aabb::zero)
+    let origin = match origin {
+        Some(origin) => origin,
+        None => return Isometry3::identity(),
+    };
+    let mean = sum / num_points;
+    let cov_xx = sum_xx / num_points - mean.x * mean.x;
+    let cov_xy = sum_xy / num_points - mean.x * mean.y;
+    let cov_yy = sum_yy / num_points - mean.y * mean.y;
+    let angle = 0.5 * (2. * cov_xy).atan2(cov_xx - cov_yy);
+    Isometry3::from_parts(
+        Translation3::from((origin + mean).coords),
+        UnitQuaternion::from_axis_angle(&Vector3::z_axis(), angle),
+    )
+}
+
+/// Writes the kd-tree of the points of the finished node 'id', see 'BuildOptions::kd_index'.
+fn write_kd_index(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &OctreeMeta,
+    id: &NodeId,
+    num_points: i64,
+) -> Result<()> {
+    let mut positions = Vec::with_capacity(num_points as usize);
+    let node_iterator = NodeIterator::from_data_provider(
+        octree_data_provider,
+        &HashMap::new(),
+        octree_meta.encoding_for_node(*id),
+        false,
+        id,
+        num_points as usize,
+        NUM_POINTS_PER_BATCH,
+    )?;
+    for mut batch in node_iterator {
+        positions.append(&mut batch.position);
+    }
+    let path = octree_data_provider
+        .stem(&id.to_string())
+        .with_extension(KD_INDEX);
+    write_file_atomically(&path, &KdIndex::new(&positions).to_bytes())
+        .with_context(|| format!("Could not write {}.", path.display()))
+}
+
+/// Bounds the normals of the finished node 'id', see 'BuildOptions::normals'.
+fn compute_normal_cone(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &OctreeMeta,
+    id: &NodeId,
+    num_points: i64,
+) -> Result<Option<NormalCone>> {
+    let mut normals = Vec::with_capacity(num_points as usize);
+    let node_iterator = NodeIterator::from_data_provider(
+        octree_data_provider,
+        &std::iter::once((NORMAL.to_string(), AttributeDataType::F64Vec3)).collect(),
+        octree_meta.encoding_for_node(*id),
+        false,
+        id,
+        num_points as usize,
+        NUM_POINTS_PER_BATCH,
+    )?;
+    for mut batch in node_iterator {
+        normals.append(
+            &mut batch
+                .remove_attribute_vec::<Vector3<f64>>(NORMAL)
+                .map_err(Error::SchemaMismatch)?,
+        );
+    }
+    Ok(NormalCone::from_normals(&normals))
+}
+
+/// Computes the occupancy of the subtrees of 'finished_nodes', see 'BuildOptions::occupancy'.
+/// Points are only read for the nodes themselves, and the occupancy of every node is then merged
+/// into its ancestors, deepest first.
+fn compute_occupancies(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &OctreeMeta,
+    finished_nodes: &FnvHashMap<NodeId, i64>,
+) -> Result<FnvHashMap<NodeId, Occupancy>> {
+    let mut occupancies = finished_nodes
+        .par_iter()
+        .map(|(id, num_points)| {
+            let mut occupancy = Occupancy::default();
+            if *num_points > 0 {
+                let cube = octree_meta.bounding_cube(id);
+                let node_iterator = NodeIterator::from_data_provider(
+                    octree_data_provider,
+                    &HashMap::new(),
+                    octree_meta.encoding_for_node(*id),
+                    false,
+                    id,
+                    *num_points as usize,
+                    NUM_POINTS_PER_BATCH,
+                )?;
+                for batch in node_iterator {
+                    occupancy.0 |= Occupancy::from_points(&cube, &batch.position).0;
+                }
+            }
+            Ok((*id, occupancy))
+        })
+        .collect::<Result<FnvHashMap<_, _>>>()?;
+    let mut ids: Vec<NodeId> = occupancies.keys().copied().collect();
+    ids.sort_by_key(|id| cmp::Reverse(id.level()));
+    for id in ids {
+        let occupancy = occupancies[&id];
+        if let (Some(parent_id), false) = (id.parent_id(), occupancy.is_empty()) {
+            occupancies.entry(parent_id).or_default().merge_child(
+                &octree_meta.bounding_cube(&parent_id),
+                occupancy,
+                &octree_meta.bounding_cube(&id),
+            );
+        }
+    }
+    Ok(occupancies)
 }
 
 pub fn build_octree_from_file(
@@ -275,14 +1032,125 @@ pub fn build_octree_from_file(
     filename: impl AsRef<Path>,
     attributes: &[&str],
 ) {
-    let bounding_box = find_bounding_box(filename.as_ref());
-    let stream = PlyIterator::from_file(filename, NUM_POINTS_PER_BATCH).unwrap();
-    build_octree(
+    build_octree_from_files(
+        output_directory,
+        resolution,
+        &[InputFile::new(filename.as_ref())],
+        attributes,
+        &BuildOptions::default(),
+    )
+}
+
+/// Builds a single octree out of all 'inputs'. The inputs are read twice: the root cube of the
+/// octree is the bounding cube of all points, so it is only known after the first pass, and no
+/// point can be placed into a node before.
+pub fn build_octree_from_files(
+    output_directory: impl AsRef<Path>,
+    resolution: f64,
+    inputs: &[InputFile],
+    attributes: &[&str],
+    options: &BuildOptions,
+) {
+    let mut inputs = inputs.to_vec();
+    let mut attributes = attributes.to_vec();
+    if let Some(normalization) = options.intensity_normalization {
+        let mut progress_bar = create_progress_bar(inputs.len(), "Normalizing intensities");
+        for input in &mut inputs {
+            let stream = input.open(NUM_POINTS_PER_BATCH).unwrap();
+            input.intensity_normalizer =
+                Some(IntensityNormalizer::from_stream(normalization, stream));
+            progress_bar.inc();
+        }
+        progress_bar.finish();
+        attributes.push(NORMALIZED_INTENSITY);
+    }
+    let mut sources = Vec::new();
+    if options.source_ids {
+        assert!(
+            inputs.len() <= usize::from(u16::max_value()) + 1,
+            "Source ids support at most {} input files.",
+            usize::from(u16::max_value()) + 1
+        );
+        for (source_id, input) in inputs.iter_mut().enumerate() {
+            input.source_id = Some(source_id as u16);
+            sources.push(input.path.to_string_lossy().into_owned());
+        }
+        attributes.push(SOURCE_ID);
+    }
+    let mut frame_timestamps = Vec::new();
+    if let Some(timestamps) = &options.frame_timestamps {
+        assert_eq!(
+            timestamps.len(),
+            inputs.len(),
+            "Every input file needs a timestamp."
+        );
+        frame_timestamps = timestamps.clone();
+        frame_timestamps.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        frame_timestamps.dedup();
+        for (input, timestamp) in inputs.iter_mut().zip(timestamps) {
+            let frame_index = frame_timestamps
+                .iter()
+                .position(|t| t == timestamp)
+                .unwrap();
+            input.frame_index = Some(frame_index as u32);
+        }
+        attributes.push(FRAME_INDEX);
+    }
+    if let Some(world_from_octree) = &options.world_from_octree {
+        let octree_from_world = world_from_octree.inverse();
+        for input in &mut inputs {
+            input.octree_from_file = octree_from_world * input.octree_from_file;
+        }
+    }
+    assert!(
+        options.up_axis == UpAxis::Z || options.subdivision == Subdivision::Octree,
+        "Quadtrees need an octree with z up."
+    );
+    let open_inputs = || {
+        let stream = MultiFileIterator::new(&inputs, NUM_POINTS_PER_BATCH)
+            .unwrap()
+            .with_up_axis(options.up_axis);
+        match options.max_batch_bytes {
+            Some(max_bytes) => stream.with_max_batch_bytes(max_bytes),
+            None => stream,
+        }
+    };
+    let bounding_box = scan_inputs(&inputs, options.up_axis)
+        .expect("Could not scan inputs.")
+        .bounding_box;
+    let mut stream = open_inputs().with_color_space(options.color_space);
+    if let Some(colorizer) = &options.colorizer {
+        assert_eq!(
+            options.color_space,
+            ColorSpace::Srgb,
+            "Colorizing needs an octree with sRGB colors."
+        );
+        stream = stream.with_colorizer(Arc::clone(colorizer));
+        if !attributes.contains(&"color") {
+            attributes.push("color");
+        }
+    }
+    if let Some(classifier) = &options.classifier {
+        stream = stream.with_classifier(Arc::clone(classifier));
+        attributes.push(CLASSIFICATION);
+    }
+    if options.normals {
+        stream = stream.with_normals();
+        attributes.push(NORMAL);
+    }
+    if options.curvature {
+        stream = stream.with_curvature();
+        attributes.push(CURVATURE);
+    }
+    build_octree_with_inputs(
         output_directory,
         resolution,
         bounding_box,
         stream,
-        attributes,
+        &attributes,
+        options,
+        sources,
+        frame_timestamps,
     )
 }
 
@@ -290,114 +1158,239 @@ pub fn build_octree(
     output_directory: impl AsRef<Path>,
     resolution: f64,
     bounding_box: Aabb,
-    input: impl Iterator<Item = PointsBatch> + NumberOfPoints + Send,
+    input: impl NextInto + NumberOfPoints + Send,
+    attributes: &[&str],
+    options: &BuildOptions,
+) {
+    build_octree_with_inputs(
+        output_directory,
+        resolution,
+        bounding_box,
+        input,
+        attributes,
+        options,
+        Vec::new(),
+        Vec::new(),
+    )
+}
+
+/// Like 'build_octree', but computes the bounding box in a first pass over the input instead of
+/// requiring it up front. 'open_input' is called once for each pass and must return the same
+/// points both times.
+pub fn build_octree_two_pass<P>(
+    output_directory: impl AsRef<Path>,
+    resolution: f64,
+    open_input: impl Fn() -> P,
+    attributes: &[&str],
+    options: &BuildOptions,
+) where
+    P: NextInto + NumberOfPoints + Send,
+{
+    let extent = scan_extent(open_input());
+    build_octree(
+        output_directory,
+        resolution,
+        extent.bounding_box,
+        open_input(),
+        attributes,
+        options,
+    )
+}
+
+/// Like 'build_octree', recording 'sources' as the input files of the points and
+/// 'frame_timestamps' as the timestamps of their frames in the meta data.
+#[allow(clippy::too_many_arguments)]
+fn build_octree_with_inputs(
+    output_directory: impl AsRef<Path>,
+    resolution: f64,
+    bounding_box: Aabb,
+    input: impl NextInto + NumberOfPoints + Send,
     attributes: &[&str],
+    options: &BuildOptions,
+    sources: Vec<String>,
+    frame_timestamps: Vec<f64>,
 ) {
     attempt_increasing_rlimit_to_max();
 
-    let octree_meta =
-        &octree::OctreeMeta::new_with_standard_attributes(resolution, bounding_box.clone());
+    let mut octree_meta =
+        octree::OctreeMeta::new_with_standard_attributes(resolution, bounding_box.clone());
+    octree_meta.subsampling_seed = options.seed;
+    octree_meta.crs = options.crs.clone();
+    octree_meta.sources = sources;
+    octree_meta.frame_timestamps = frame_timestamps;
+    octree_meta.world_from_octree = options.world_from_octree;
+    octree_meta.subdivision = options.subdivision;
+    octree_meta.color_space = options.color_space;
+    octree_meta.view_settings.up_axis = options.up_axis;
+    if attributes.contains(&NORMALIZED_INTENSITY) {
+        octree_meta.add_attribute(NORMALIZED_INTENSITY, AttributeDataType::F32);
+    }
+    if attributes.contains(&CLASSIFICATION) {
+        octree_meta.add_attribute(CLASSIFICATION, AttributeDataType::U8);
+    }
+    if attributes.contains(&SOURCE_ID) {
+        octree_meta.add_attribute(SOURCE_ID, AttributeDataType::U16);
+    }
+    if attributes.contains(&FRAME_INDEX) {
+        octree_meta.add_attribute(FRAME_INDEX, AttributeDataType::U32);
+    }
+    if attributes.contains(&NORMAL) {
+        octree_meta.add_attribute(NORMAL, AttributeDataType::F64Vec3);
+    }
+    if attributes.contains(&CURVATURE) {
+        octree_meta.add_attribute(CURVATURE, AttributeDataType::F32);
+    }
+    let octree_meta = &octree_meta;
     let attribute_data_types = &octree_meta.attribute_data_types_for(attributes).unwrap();
     let octree_data_provider = OnDiskDataProvider {
         directory: output_directory.as_ref().to_path_buf(),
     };
     let octree_data_provider = &octree_data_provider;
+    let budget = &PointBudget::new(options.max_points_in_memory);
+    let write_queue_len = options.write_queue_len;
+    let node_limits = &options.node_limits[..];
 
     // Ignore errors, maybe directory is already there.
     let _ = fs::create_dir(output_directory.as_ref());
+    let _lock = OctreeLock::write(output_directory.as_ref()).unwrap_or_else(|e| panic!("{}", e));
 
-    eprintln!("Creating octree structure.");
+    info!("Creating octree structure.");
 
+    let root_id = octree::Node::root_with_bounding_cube(Cube::bounding(&bounding_box)).id;
+    let input = ProgressStream::new(input, "Splitting points");
     let (leaf_nodes_sender, leaf_nodes_receiver) = crossbeam::channel::unbounded();
     rayon::scope(move |scope| {
-        let root_node = octree::Node::root_with_bounding_cube(Cube::bounding(&bounding_box));
         split_node(
             scope,
             octree_data_provider,
             octree_meta,
             attribute_data_types,
-            &root_node.id,
+            budget,
+            write_queue_len,
+            node_limits,
+            &root_id,
             input,
             &leaf_nodes_sender,
         );
     });
 
-    let mut nodes_to_subsample = Vec::new();
-    let mut deepest_level = 0u8;
-    for id in leaf_nodes_receiver {
-        deepest_level = cmp::max(deepest_level, id.level());
-        nodes_to_subsample.push(id);
+    // Every inner node is built by subsampling its children. Register each leaf with its parent
+    // and walk up until reaching an ancestor that is already known.
+    let mut children: FnvHashMap<NodeId, Vec<NodeId>> = FnvHashMap::default();
+    for leaf_id in leaf_nodes_receiver {
+        let mut id = leaf_id;
+        while let Some(parent_id) = id.parent_id() {
+            let siblings = children.entry(parent_id).or_default();
+            let is_known = !siblings.is_empty();
+            siblings.push(id);
+            if is_known {
+                break;
+            }
+            id = parent_id;
+        }
     }
-    let mut finished_nodes = FnvHashMap::default();
-
-    // sub sampling returns the list of finished nodes including all meta data
-    // We start on the deepest level and work our way up the tree.
-    for current_level in (1..=deepest_level).rev() {
-        // All nodes on the same level can be subsampled in parallel.
-        let res = nodes_to_subsample
-            .into_iter()
-            .partition(|n| n.level() == current_level);
-        nodes_to_subsample = res.1;
-
-        // Unwrap is safe, since we stop at current_level = 1, so the root can never appear.
-        let parent_ids: FnvHashSet<_> = res
-            .0
-            .into_iter()
-            .map(|id| id.parent_id().unwrap())
-            .collect();
-        let mut progress_bar = create_progress_bar(
-            parent_ids.len(),
-            &format!("Building level {}", current_level - 1),
-        );
 
-        let (finished_nodes_sender, finished_nodes_receiver) = crossbeam::channel::unbounded();
-        let (progress_tx, progress_rx) = crossbeam::channel::unbounded();
-        rayon::scope(|scope| {
-            scope.spawn(|_| {
-                for (id, num_points) in finished_nodes_receiver {
-                    finished_nodes.insert(id, num_points);
-                }
-            });
-
-            scope.spawn(|_| {
-                for _ in progress_rx {
-                    progress_bar.inc();
-                }
-            });
-
-            parent_ids.par_iter().for_each(|id| {
-                subsample_children_into(
-                    octree_data_provider,
-                    octree_meta,
-                    attribute_data_types,
-                    id,
-                    &finished_nodes_sender,
-                )
-                .unwrap();
-                progress_tx.send(()).unwrap();
-            });
-            drop(finished_nodes_sender);
-            drop(progress_tx);
-        });
-        progress_bar.finish();
+    let (finished_nodes_sender, finished_nodes_receiver) = crossbeam::channel::unbounded();
+    let progress_bar = create_syncable_progress_bar(children.len(), "Subsampling nodes");
+    subsample_subtree(
+        octree_data_provider,
+        octree_meta,
+        attribute_data_types,
+        budget,
+        node_limits,
+        &root_id,
+        &children,
+        options.merge_points.as_ref(),
+        &finished_nodes_sender,
+        &progress_bar,
+    )
+    .unwrap();
+    progress_bar.lock().unwrap().finish();
+    drop(finished_nodes_sender);
+    let finished_nodes: FnvHashMap<_, _> = finished_nodes_receiver.into_iter().collect();
 
-        // The nodes that were just now created through sub-sampling will be required to create
-        // their parents.
-        nodes_to_subsample.extend(parent_ids.into_iter());
+    if let Some(baked_colors) = &options.baked_colors {
+        info!("Baking colors into the coarse levels.");
+        bake_colors(
+            octree_data_provider,
+            octree_meta,
+            attribute_data_types,
+            &finished_nodes,
+            baked_colors,
+        )
+        .unwrap();
     }
 
-    // Add all non-zero node meta data to meta file
+    let occupancies = if options.occupancy {
+        info!("Computing the occupancy of the nodes.");
+        compute_occupancies(octree_data_provider, octree_meta, &finished_nodes).unwrap()
+    } else {
+        FnvHashMap::default()
+    };
+
+    // Add all non-zero node meta data to meta file. The node files are final now, so this is when
+    // their schemas, kd-trees and checksums are written.
+    let attributes: Vec<&str> = std::iter::once("position")
+        .chain(attribute_data_types.keys().map(String::as_str))
+        .chain(Some(KD_INDEX).filter(|_| options.kd_index))
+        .collect();
     let nodes: Vec<proto::OctreeNode> = finished_nodes
-        .iter()
+        .par_iter()
         .map(|(id, num_points)| {
-            let bounding_cube = id.find_bounding_cube(&Cube::bounding(&octree_meta.bounding_box));
+            let bounding_cube = octree_meta.bounding_cube(id);
             let position_encoding = PositionEncoding::new(&bounding_cube, octree_meta.resolution);
-            to_node_proto(&id, *num_points, &position_encoding)
+            if *num_points > 0 {
+                let schema = NodeSchema {
+                    num_points: *num_points,
+                    bounding_cube,
+                    position_encoding: position_encoding.clone(),
+                    compressed_attributes: false,
+                    attribute_data_types: attribute_data_types.clone(),
+                };
+                schema
+                    .write(octree_data_provider.stem(&id.to_string()))
+                    .unwrap();
+            }
+            if options.kd_index && *num_points > 0 {
+                write_kd_index(octree_data_provider, octree_meta, id, *num_points).unwrap();
+            }
+            // Writers do not leave files for nodes without points.
+            let checksums = if options.checksums && *num_points > 0 {
+                compute_checksums(octree_data_provider, &id.to_string(), &attributes).unwrap()
+            } else {
+                HashMap::new()
+            };
+            let normal_cone = if attribute_data_types.contains_key(NORMAL) && *num_points > 0 {
+                compute_normal_cone(octree_data_provider, octree_meta, id, *num_points).unwrap()
+            } else {
+                None
+            };
+            to_node_proto(
+                &id,
+                *num_points,
+                &position_encoding,
+                false,
+                checksums,
+                normal_cone,
+                occupancies.get(id).copied(),
+            )
         })
         .collect();
     let meta = to_meta_proto(&octree_meta, nodes);
 
-    let mut buf_writer =
-        BufWriter::new(File::create(&output_directory.as_ref().join(META_FILENAME)).unwrap());
-    meta.write_to_writer(&mut buf_writer).unwrap();
+    write_meta(output_directory.as_ref(), &meta).unwrap();
+
+    if let Some(preview_points) = options.preview_points {
+        info!("Writing the preview.");
+        let data_provider = OnDiskDataProvider {
+            directory: output_directory.as_ref().to_path_buf(),
+        };
+        let octree = Octree::from_data_provider(Box::new(data_provider)).unwrap();
+        write_preview(
+            &octree,
+            output_directory.as_ref().join(PREVIEW_FILENAME),
+            preview_points,
+        )
+        .unwrap();
+    }
 }
@@ -0,0 +1,337 @@
+//! An optional kd-tree over the points of a node, stored next to the node's files as
+//! '<node>.kd_index'. It answers nearest neighbor and radius queries inside a node without
+//! comparing every point. The tree is implicit: the points are ordered so that the middle of every
+//! range is the median of the range along the axis stored for it, and the two halves are the
+//! subtrees. Only this order and the axes are stored, 5 bytes per point.
+
+use crate::errors::*;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nalgebra::Point3;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::Cursor;
+use std::ops::Range;
+
+/// Name of the file of a node that holds its kd-tree, see 'BuildOptions::kd_index'.
+pub const KD_INDEX: &str = "kd_index";
+
+pub struct KdIndex {
+    /// Indices of the points of the node in the order of the tree.
+    order: Vec<u32>,
+    /// The split axis of the subtree whose median is at the same place in 'order'.
+    axes: Vec<u8>,
+}
+
+impl KdIndex {
+    /// Builds the tree over 'positions', which are the points of a node in the order in which
+    /// they are stored.
+    pub fn new(positions: &[Point3<f64>]) -> Self {
+        let mut kd_index = KdIndex {
+            order: (0..positions.len() as u32).collect(),
+            axes: vec![0; positions.len()],
+        };
+        build(positions, &mut kd_index.order, &mut kd_index.axes);
+        kd_index
+    }
+
+    /// The number of points in the tree.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + 5 * self.order.len());
+        bytes
+            .write_u32::<LittleEndian>(self.order.len() as u32)
+            .unwrap();
+        for index in &self.order {
+            bytes.write_u32::<LittleEndian>(*index).unwrap();
+        }
+        bytes.extend_from_slice(&self.axes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(bytes);
+        let num_points = cursor.read_u32::<LittleEndian>()? as usize;
+        if bytes.len() != 4 + 5 * num_points {
+            return Err(Error::InvalidInput(format!(
+                "A kd-tree index of {} points must have {} bytes, not {}.",
+                num_points,
+                4 + 5 * num_points,
+                bytes.len()
+            )));
+        }
+        let order = (0..num_points)
+            .map(|_| cursor.read_u32::<LittleEndian>())
+            .collect::<std::io::Result<Vec<u32>>>()?;
+        if order.iter().any(|index| *index as usize >= num_points) {
+            return Err(Error::InvalidInput(
+                "The kd-tree index is out of range.".to_string(),
+            ));
+        }
+        let axes = bytes[4 + 4 * num_points..].to_vec();
+        if axes.iter().any(|axis| *axis > 2) {
+            return Err(Error::InvalidInput(
+                "The kd-tree index has an invalid axis.".to_string(),
+            ));
+        }
+        Ok(KdIndex { order, axes })
+    }
+
+    fn nearest(&self, positions: &[Point3<f64>], point: &Point3<f64>, k: usize) -> Vec<Neighbor> {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        if k > 0 {
+            self.nearest_in(positions, 0..self.order.len(), point, k, &mut heap);
+        }
+        heap.into_sorted_vec()
+    }
+
+    fn nearest_in(
+        &self,
+        positions: &[Point3<f64>],
+        range: Range<usize>,
+        point: &Point3<f64>,
+        k: usize,
+        heap: &mut BinaryHeap<Neighbor>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let mid = range.start + range.len() / 2;
+        let index = self.order[mid] as usize;
+        let position = &positions[index];
+        heap.push(Neighbor {
+            index,
+            distance_squared: nalgebra::distance_squared(position, point),
+        });
+        if heap.len() > k {
+            heap.pop();
+        }
+        let axis = usize::from(self.axes[mid]);
+        let diff = point[axis] - position[axis];
+        let (near, far) = if diff <= 0. {
+            (range.start..mid, mid + 1..range.end)
+        } else {
+            (mid + 1..range.end, range.start..mid)
+        };
+        self.nearest_in(positions, near, point, k, heap);
+        let worst = heap.peek().map(|n| n.distance_squared);
+        if heap.len() < k || worst.map_or(true, |worst| diff * diff <= worst) {
+            self.nearest_in(positions, far, point, k, heap);
+        }
+    }
+
+    fn within_radius_in(
+        &self,
+        positions: &[Point3<f64>],
+        range: Range<usize>,
+        center: &Point3<f64>,
+        radius_squared: f64,
+        result: &mut Vec<usize>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let mid = range.start + range.len() / 2;
+        let index = self.order[mid] as usize;
+        let position = &positions[index];
+        if nalgebra::distance_squared(position, center) <= radius_squared {
+            result.push(index);
+        }
+        let axis = usize::from(self.axes[mid]);
+        let diff = center[axis] - position[axis];
+        if diff <= 0. || diff * diff <= radius_squared {
+            self.within_radius_in(positions, range.start..mid, center, radius_squared, result);
+        }
+        if diff >= 0. || diff * diff <= radius_squared {
+            self.within_radius_in(
+                positions,
+                mid + 1..range.end,
+                center,
+                radius_squared,
+                result,
+            );
+        }
+    }
+}
+
+/// Orders 'order' into an implicit kd-tree, splitting every range along its longest extent.
+fn build(positions: &[Point3<f64>], order: &mut [u32], axes: &mut [u8]) {
+    if order.len() <= 1 {
+        return;
+    }
+    let mut min = Point3::new(std::f64::INFINITY, std::f64::INFINITY, std::f64::INFINITY);
+    let mut max = Point3::new(
+        std::f64::NEG_INFINITY,
+        std::f64::NEG_INFINITY,
+        std::f64::NEG_INFINITY,
+    );
+    for index in order.iter() {
+        let position = &positions[*index as usize];
+        for axis in 0..3 {
+            min[axis] = min[axis].min(position[axis]);
+            max[axis] = max[axis].max(position[axis]);
+        }
+    }
+    let diag = max - min;
+    let axis = (0..3)
+        .max_by(|a, b| diag[*a].partial_cmp(&diag[*b]).unwrap_or(Ordering::Equal))
+        .unwrap();
+    let mid = order.len() / 2;
+    order.select_nth_unstable_by(mid, |a, b| {
+        positions[*a as usize][axis]
+            .partial_cmp(&positions[*b as usize][axis])
+            .unwrap_or(Ordering::Equal)
+    });
+    axes[mid] = axis as u8;
+    let (left_order, right_order) = order.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(positions, left_order, left_axes);
+    build(positions, &mut right_order[1..], &mut right_axes[1..]);
+}
+
+/// A point of a node and its squared distance to the query, ordered by the distance.
+struct Neighbor {
+    index: usize,
+    distance_squared: f64,
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Neighbor) -> Ordering {
+        self.distance_squared
+            .partial_cmp(&other.distance_squared)
+            .unwrap_or(Ordering::Equal)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Neighbor) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Neighbor {
+    fn eq(&self, other: &Neighbor) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Neighbor {}
+
+/// The 'k' points of 'positions' that are closest to 'point', as their indices and distances,
+/// closest first. Uses 'kd_index' if given and compares all points otherwise.
+pub fn nearest(
+    positions: &[Point3<f64>],
+    kd_index: Option<&KdIndex>,
+    point: &Point3<f64>,
+    k: usize,
+) -> Vec<(usize, f64)> {
+    let neighbors = match kd_index {
+        Some(kd_index) => kd_index.nearest(positions, point, k),
+        None => {
+            let mut neighbors: Vec<Neighbor> = positions
+                .iter()
+                .enumerate()
+                .map(|(index, position)| Neighbor {
+                    index,
+                    distance_squared: nalgebra::distance_squared(position, point),
+                })
+                .collect();
+            neighbors.sort();
+            neighbors.truncate(k);
+            neighbors
+        }
+    };
+    neighbors
+        .into_iter()
+        .map(|n| (n.index, n.distance_squared.sqrt()))
+        .collect()
+}
+
+/// The indices of the points of 'positions' within 'radius' of 'center', in ascending order. Uses
+/// 'kd_index' if given and compares all points otherwise.
+pub fn within_radius(
+    positions: &[Point3<f64>],
+    kd_index: Option<&KdIndex>,
+    center: &Point3<f64>,
+    radius: f64,
+) -> Vec<usize> {
+    let radius_squared = radius * radius;
+    match kd_index {
+        Some(kd_index) => {
+            let mut result = Vec::new();
+            kd_index.within_radius_in(
+                positions,
+                0..kd_index.len(),
+                center,
+                radius_squared,
+                &mut result,
+            );
+            result.sort();
+            result
+        }
+        None => positions
+            .iter()
+            .enumerate()
+            .filter(|(_, position)| nalgebra::distance_squared(*position, center) <= radius_squared)
+            .map(|(index, _)| index)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn test_queries_match_scan() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        // Flat like most scans, with duplicates.
+        let mut positions: Vec<_> = (0..2000)
+            .map(|_| {
+                Point3::new(
+                    rng.gen_range(0., 100.),
+                    rng.gen_range(0., 50.),
+                    rng.gen_range(0., 2.),
+                )
+            })
+            .collect();
+        positions.extend(positions[..100].to_vec());
+        let kd_index = KdIndex::from_bytes(&KdIndex::new(&positions).to_bytes()).unwrap();
+        assert_eq!(positions.len(), kd_index.len());
+
+        for _ in 0..50 {
+            let point = Point3::new(
+                rng.gen_range(-10., 110.),
+                rng.gen_range(-10., 60.),
+                rng.gen_range(-1., 3.),
+            );
+            assert_eq!(
+                within_radius(&positions, None, &point, 5.),
+                within_radius(&positions, Some(&kd_index), &point, 5.)
+            );
+            assert_eq!(
+                nearest(&positions, None, &point, 10),
+                nearest(&positions, Some(&kd_index), &point, 10)
+            );
+        }
+        assert!(nearest(&positions, Some(&kd_index), &positions[0], 0).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_bytes() {
+        let bytes = KdIndex::new(&[Point3::origin(), Point3::new(1., 0., 0.)]).to_bytes();
+        assert!(KdIndex::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut bytes = bytes;
+        bytes[4] = 7;
+        assert!(KdIndex::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Keeps processes from corrupting an octree by changing it at the same time. Writers hold an
+//! exclusive lock on the octree directory, and readers that need the node files to stay unchanged
+//! hold a shared one. The locks are 'flock' locks on a file in the directory, so the operating
+//! system releases them when the process exits, even after a crash, and stale locks never need to
+//! be cleaned up. They are advisory: only processes that take them are kept out.
+
+use crate::errors::*;
+use crate::proto;
+use crate::read_write::{DataWriter, OpenMode};
+use crate::META_FILENAME;
+use protobuf::Message;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// The file in the octree directory that is locked.
+pub const LOCK_FILENAME: &str = "octree.lock";
+
+/// Held until it is dropped.
+#[derive(Debug)]
+pub struct OctreeLock {
+    _file: File,
+}
+
+impl OctreeLock {
+    /// Takes the exclusive lock for changing the octree in 'directory', which must exist. Fails
+    /// with 'Error::OctreeLocked' instead of waiting if another process writes or reads it.
+    pub fn write(directory: impl AsRef<Path>) -> Result<Self> {
+        let directory = directory.as_ref();
+        let file = open_lock_file(directory)?;
+        if let Err(err) = flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            return Err(match err.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Error::OctreeLocked(directory.display().to_string()),
+                _ => err.into(),
+            });
+        }
+        Ok(Self { _file: file })
+    }
+
+    /// Waits until no process writes the octree in 'directory', and keeps others from writing it
+    /// until the lock is dropped. Any number of readers can hold the lock at the same time.
+    pub fn read(directory: impl AsRef<Path>) -> Result<Self> {
+        let file = open_lock_file(directory.as_ref())?;
+        flock(&file, libc::LOCK_SH)?;
+        Ok(Self { _file: file })
+    }
+}
+
+fn open_lock_file(directory: &Path) -> Result<File> {
+    let path = directory.join(LOCK_FILENAME);
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Could not open {}.", path.display()))
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Replaces the meta data of the octree in 'directory' atomically, so that readers that open the
+/// octree meanwhile see either the old or the new meta data, but never a partially written file.
+pub fn write_meta(directory: impl AsRef<Path>, meta: &proto::Meta) -> Result<()> {
+    let path = directory.as_ref().join(META_FILENAME);
+    let mut writer = DataWriter::new(&path, OpenMode::Truncate)
+        .with_context(|| format!("Could not create {}.", path.display()))?
+        .with_sync();
+    meta.write_to_writer(&mut writer)
+        .with_context(|| "Could not write meta file.")?;
+    writer
+        .finish()
+        .with_context(|| format!("Could not replace {}.", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_readers_and_writers_exclude_each_other() {
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let readers = [
+            OctreeLock::read(tmp_dir.path()).unwrap(),
+            OctreeLock::read(tmp_dir.path()).unwrap(),
+        ];
+        match OctreeLock::write(tmp_dir.path()) {
+            Err(Error::OctreeLocked(_)) => (),
+            other => panic!("Expected a locked octree, got {:?}.", other),
+        }
+        drop(readers);
+
+        let writer = OctreeLock::write(tmp_dir.path()).unwrap();
+        assert!(OctreeLock::write(tmp_dir.path()).is_err());
+        drop(writer);
+        assert!(OctreeLock::write(tmp_dir.path()).is_ok());
+    }
+}
@@ -0,0 +1,278 @@
+//! Merges near-duplicate points, e.g. where overlapping scan passes multiply the density along
+//! their seams. Points closer than a tolerance become one point.
+
+use crate::attributes::AttributeData;
+use crate::PointsBatch;
+use fnv::FnvHashMap;
+use nalgebra::{Point3, Vector3};
+use std::str::FromStr;
+
+/// What a merged point keeps of the points that it replaces.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergedAttributes {
+    /// The position and the attributes of the first point.
+    First,
+    /// The mean position, colors and floating point attributes. Integer attributes, like classes
+    /// and ids, are taken from the first point.
+    Average,
+}
+
+/// Merges points closer than 'tolerance' during a build, see 'BuildOptions::merge_points'.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PointMerging {
+    pub tolerance: f64,
+    pub attributes: MergedAttributes,
+}
+
+impl FromStr for PointMerging {
+    type Err = String;
+
+    /// Parses '<tolerance>[:<first|average>]', e.g. '0.005:average'. Keeps the first point by
+    /// default.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let tolerance = parts
+            .next()
+            .unwrap()
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid tolerance in '{}': {}", s, e))?;
+        if !tolerance.is_finite() || tolerance <= 0. {
+            return Err(format!("The tolerance in '{}' must be positive.", s));
+        }
+        let attributes = match parts.next().map(str::trim) {
+            None | Some("first") => MergedAttributes::First,
+            Some("average") => MergedAttributes::Average,
+            Some(_) => return Err(format!("Unknown merged attributes in '{}'.", s)),
+        };
+        Ok(PointMerging {
+            tolerance,
+            attributes,
+        })
+    }
+}
+
+/// Merges the points of 'batch' that are closer than 'merging.tolerance' to an earlier point, in
+/// the order of the batch. Returns the number of points that were merged away.
+pub fn merge_points(batch: &mut PointsBatch, merging: &PointMerging) -> usize {
+    let num_points = batch.position.len();
+    let cell = |p: &Point3<f64>| {
+        let c = p.coords / merging.tolerance;
+        (c.x.floor() as i64, c.y.floor() as i64, c.z.floor() as i64)
+    };
+    // The points that are kept, by grid cell. Points that are merged have a kept point within the
+    // tolerance, which is in the same or a neighboring cell.
+    let mut cells: FnvHashMap<(i64, i64, i64), Vec<usize>> = FnvHashMap::default();
+    let mut kept_index = Vec::with_capacity(num_points);
+    for (i, p) in batch.position.iter().enumerate() {
+        let (x, y, z) = cell(p);
+        let mut neighbors = (-1..=1).flat_map(|dx| {
+            (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| (x + dx, y + dy, z + dz)))
+        });
+        let kept = neighbors.find_map(|c| {
+            cells.get(&c)?.iter().copied().find(|&k| {
+                (batch.position[k] - p).norm_squared() < merging.tolerance * merging.tolerance
+            })
+        });
+        match kept {
+            Some(k) => kept_index.push(k),
+            None => {
+                cells.entry((x, y, z)).or_default().push(i);
+                kept_index.push(i);
+            }
+        }
+    }
+    let keep: Vec<bool> = kept_index
+        .iter()
+        .enumerate()
+        .map(|(i, k)| i == *k)
+        .collect();
+    let num_merged = keep.iter().filter(|k| !**k).count();
+    if num_merged == 0 {
+        return 0;
+    }
+
+    if merging.attributes == MergedAttributes::Average {
+        let mut counts = vec![0usize; num_points];
+        for k in &kept_index {
+            counts[*k] += 1;
+        }
+        average(
+            &mut batch.position,
+            &kept_index,
+            &counts,
+            |p| p.coords,
+            Point3::from,
+        );
+        for data in batch.attributes.values_mut() {
+            match data {
+                AttributeData::F32(values) => average(
+                    values,
+                    &kept_index,
+                    &counts,
+                    |v| Vector3::new(f64::from(*v), 0., 0.),
+                    |v| v.x as f32,
+                ),
+                AttributeData::F64(values) => average(
+                    values,
+                    &kept_index,
+                    &counts,
+                    |v| Vector3::new(*v, 0., 0.),
+                    |v| v.x,
+                ),
+                AttributeData::U8Vec3(values) => average(
+                    values,
+                    &kept_index,
+                    &counts,
+                    |v| v.map(f64::from),
+                    |v| v.map(|c| c.round() as u8),
+                ),
+                AttributeData::F64Vec3(values) => {
+                    average(values, &kept_index, &counts, |v| *v, |v| v)
+                }
+                _ => (),
+            }
+        }
+    }
+    batch.retain(&keep);
+    num_merged
+}
+
+/// Replaces the values of the kept points by the mean of the values of the points merged into
+/// them, computed on up to three components.
+fn average<T>(
+    values: &mut [T],
+    kept_index: &[usize],
+    counts: &[usize],
+    to_vector: impl Fn(&T) -> Vector3<f64>,
+    from_vector: impl Fn(Vector3<f64>) -> T,
+) {
+    let mut sums = vec![Vector3::zeros(); values.len()];
+    for (value, k) in values.iter().zip(kept_index) {
+        sums[*k] += to_vector(value);
+    }
+    for (i, (value, sum)) in values.iter_mut().zip(sums).enumerate() {
+        if counts[i] > 1 {
+            *value = from_vector(sum / counts[i] as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use tempdir::TempDir;
+
+    fn batch() -> PointsBatch {
+        PointsBatch {
+            position: vec![
+                Point3::new(0., 0., 0.),
+                Point3::new(1., 0., 0.),
+                Point3::new(0.004, 0., 0.),
+                Point3::new(1., 0.002, 0.),
+            ],
+            attributes: vec![
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![
+                        Vector3::new(0, 0, 0),
+                        Vector3::new(10, 10, 10),
+                        Vector3::new(100, 100, 100),
+                        Vector3::new(20, 20, 20),
+                    ]),
+                ),
+                (
+                    "source_id".to_string(),
+                    AttributeData::U16(vec![0, 0, 1, 1]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "0.005".parse::<PointMerging>(),
+            Ok(PointMerging {
+                tolerance: 0.005,
+                attributes: MergedAttributes::First
+            })
+        );
+        assert_eq!(
+            "0.01:average".parse::<PointMerging>().unwrap().attributes,
+            MergedAttributes::Average
+        );
+        assert!("0".parse::<PointMerging>().is_err());
+        assert!("0.01:median".parse::<PointMerging>().is_err());
+    }
+
+    #[test]
+    fn test_merge_first() {
+        let mut batch = batch();
+        let merging = "0.005:first".parse().unwrap();
+        assert_eq!(merge_points(&mut batch, &merging), 2);
+        assert_eq!(
+            batch.position,
+            [Point3::new(0., 0., 0.), Point3::new(1., 0., 0.)]
+        );
+        let colors: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+        assert_eq!(colors, &[Vector3::new(0, 0, 0), Vector3::new(10, 10, 10)]);
+    }
+
+    #[test]
+    fn test_merge_average() {
+        let mut batch = batch();
+        let merging = "0.005:average".parse().unwrap();
+        assert_eq!(merge_points(&mut batch, &merging), 2);
+        assert_eq!(
+            batch.position,
+            [Point3::new(0.002, 0., 0.), Point3::new(1., 0.001, 0.)]
+        );
+        let colors: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+        assert_eq!(
+            colors,
+            &[Vector3::new(50, 50, 50), Vector3::new(15, 15, 15)]
+        );
+        let source_ids: &Vec<u16> = batch.get_attribute_vec("source_id").unwrap();
+        assert_eq!(source_ids, &[0, 0]);
+    }
+
+    #[test]
+    fn test_merge_across_cells() {
+        let mut batch = PointsBatch {
+            position: vec![Point3::new(0.0049, 0., 0.), Point3::new(0.0051, 0., 0.)],
+            attributes: Default::default(),
+        };
+        let merging = "0.005".parse().unwrap();
+        assert_eq!(merge_points(&mut batch, &merging), 1);
+        assert_eq!(merge_points(&mut batch, &merging), 0);
+    }
+
+    #[test]
+    fn test_build_merges_points() {
+        let mut position = vec![Point3::origin(); 16];
+        position.push(Point3::new(-200., -40., 30.));
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let options = BuildOptions {
+            merge_points: Some("0.01".parse().unwrap()),
+            ..Default::default()
+        };
+        let octree = build_octree_of_batch(tmp_dir.path(), 1., batch, &options);
+        // The points at the origin become one.
+        let num_points: i64 = octree.nodes.values().map(|node| node.num_points).sum();
+        assert_eq!(num_points, 2);
+    }
+}
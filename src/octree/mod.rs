@@ -11,6 +11,7 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use crate::color::{category_color, ColorSpace};
 use crate::data_provider::DataProvider;
 use crate::errors::*;
 use crate::geometry::{Aabb, Cube, Frustum};
@@ -19,31 +20,195 @@ use crate::math::base::{HasAabbIntersector, IntersectAabb};
 use crate::math::sat::{ConvexPolyhedron, Relation};
 use crate::math::AllPoints;
 use crate::proto;
-use crate::read_write::{Encoding, NodeIterator, PositionEncoding};
-use crate::{AttributeDataType, PointCloudMeta, CURRENT_VERSION};
+use crate::read_write::{
+    vec3_encode, ColumnDecoder, Encoding, NodeIterator, PositionEncoding, RawNodeReader,
+    VertexFormat, VertexLayout,
+};
+use crate::{
+    AttributeDataType, NextInto, PointCloudMeta, PointsBatch, CURRENT_VERSION, NUM_POINTS_PER_BATCH,
+};
+use byteorder::{LittleEndian, WriteBytesExt};
 use fnv::FnvHashMap;
-use nalgebra::{Matrix4, Point3};
-use num::clamp;
-use std::cmp::Ordering;
+use log::warn;
+use nalgebra::{Isometry3, Matrix4, Point3};
+use std::cmp::{self, Ordering};
 use std::collections::{BinaryHeap, HashMap};
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Cursor, Read};
+use std::ops::{Bound, RangeBounds};
 
+#[cfg(feature = "native")]
+mod baked_colors;
+#[cfg(feature = "native")]
+pub use self::baked_colors::{BakedColors, PseudoColor};
+
+mod checksum;
+pub use self::checksum::{checksum, compute_checksums};
+use self::checksum::{verify_reads, ChecksumVerifier};
+
+#[cfg(feature = "native")]
+mod compact;
+#[cfg(feature = "native")]
+pub use self::compact::{compact_octree, CompactionStats};
+
+#[cfg(feature = "native")]
+mod contour;
+#[cfg(feature = "native")]
+pub use self::contour::{extract_contours, write_contours, ContourFormat, ContourLine};
+
+mod deviation;
+pub use self::deviation::{deviation_color, DeviationOptions};
+
+#[cfg(feature = "native")]
+mod estimate;
+#[cfg(feature = "native")]
+pub use self::estimate::{estimate_build, BuildEstimate};
+
+#[cfg(feature = "native")]
+mod extent;
+#[cfg(feature = "native")]
+pub use self::extent::{scan_extent, scan_inputs, InputExtent};
+
+#[cfg(feature = "native")]
 mod generation;
-pub use self::generation::{build_octree, build_octree_from_file};
+#[cfg(feature = "native")]
+pub use self::generation::{
+    build_octree, build_octree_from_file, build_octree_from_files, build_octree_two_pass,
+    find_principal_frame, BuildOptions, InputFile, MultiFileIterator, NodeLimits,
+};
+
+mod kd_index;
+pub use self::kd_index::{KdIndex, KD_INDEX};
+
+#[cfg(feature = "native")]
+mod lock;
+#[cfg(feature = "native")]
+pub use self::lock::{write_meta, OctreeLock, LOCK_FILENAME};
+
+#[cfg(feature = "native")]
+mod merge;
+#[cfg(feature = "native")]
+pub use self::merge::{merge_points, MergedAttributes, PointMerging};
 
 mod node;
-pub use self::node::{to_node_proto, ChildIndex, Node, NodeId, NodeMeta};
+pub use self::node::{to_node_proto, ChildIndex, Node, NodeId, NodeMeta, Subdivision};
+
+mod node_schema;
+pub use self::node_schema::{NodeSchema, NODE_SCHEMA, NODE_SCHEMA_VERSION};
+
+mod normal_cone;
+pub use self::normal_cone::{
+    camera_position, gather_curvature, gather_normals, NormalCone, CURVATURE, NORMAL,
+};
+
+mod occupancy;
+pub use self::occupancy::{Occupancy, OCCUPANCY_CELLS_PER_AXIS};
 
 mod octree_iterator;
 pub use self::octree_iterator::NodeIdsIterator;
 
-#[cfg(test)]
+#[cfg(feature = "native")]
+mod overview;
+#[cfg(feature = "native")]
+pub use self::overview::{
+    render_overview, render_overview_with_min_detail, Overview, OverviewKind,
+};
+
+#[cfg(feature = "native")]
+mod preview;
+#[cfg(feature = "native")]
+pub use self::preview::{write_preview, PREVIEW_FILENAME};
+
+#[cfg(feature = "native")]
+mod raster;
+#[cfg(feature = "native")]
+pub use self::raster::{rasterize_octree, Raster, RasterOptions, RasterStatistic};
+
+#[cfg(feature = "native")]
+mod reencode;
+#[cfg(feature = "native")]
+pub use self::reencode::{reencode_octree, ReencodeOptions};
+
+#[cfg(feature = "native")]
+mod rewrite;
+#[cfg(feature = "native")]
+pub use self::rewrite::rewrite_attributes;
+
+mod screen_space_error;
+pub use self::screen_space_error::{
+    ScreenSpaceError, DEFAULT_PIXEL_THRESHOLD, DEFAULT_VIEWPORT_HEIGHT, DEFAULT_VIEWPORT_WIDTH,
+};
+
+#[cfg(feature = "native")]
+mod snapshot;
+#[cfg(feature = "native")]
+pub use self::snapshot::{
+    render_snapshot, render_snapshot_with_matrix, render_snapshot_with_node_reader, SnapshotOptions,
+};
+
+#[cfg(feature = "native")]
+mod tiles;
+#[cfg(feature = "native")]
+pub use self::tiles::{export_tiles, TileExportOptions, TileExportStats, TileFormat};
+
+#[cfg(feature = "native")]
+mod trim;
+#[cfg(feature = "native")]
+pub use self::trim::{trim_octree, TrimStats};
+
+#[cfg(feature = "native")]
+mod versions;
+#[cfg(feature = "native")]
+pub use self::versions::{
+    enable_versioning, is_versioned, read_version_log, Operation, Transaction, VersionEntry,
+};
+
+mod view_settings;
+#[cfg(feature = "native")]
+pub use self::view_settings::set_view_settings;
+pub use self::view_settings::{ColorMode, UpAxis, ViewSettings};
+
+mod visitor;
+pub use self::visitor::{NodeVisitor, Visit};
+
+mod walk;
+pub use self::walk::WalkOptions;
+
+#[cfg(all(test, feature = "native"))]
 mod tests;
 
+/// Name of the attribute that holds the index of the input file of a point, see
+/// 'BuildOptions::source_ids'.
+pub const SOURCE_ID: &str = "source_id";
+
+/// Name of the attribute that holds the index of the frame of a point in captures over time, see
+/// 'BuildOptions::frame_timestamps'.
+pub const FRAME_INDEX: &str = "frame_index";
+
 #[derive(Clone, Debug)]
 pub struct OctreeMeta {
     pub resolution: f64,
     pub bounding_box: Aabb,
+    /// Seed of the random subsampling when building the octree, see 'BuildOptions'.
+    pub subsampling_seed: u64,
+    /// Coordinate reference system of the positions, e.g. "EPSG:25832", if it is known.
+    pub crs: Option<String>,
+    /// Input files of the build, indexed by the attribute 'source_id' of the points.
+    pub sources: Vec<String>,
+    /// Timestamps of the frames, in ascending order and indexed by the attribute 'frame_index'
+    /// of the points.
+    pub frame_timestamps: Vec<f64>,
+    /// Pose of the octree frame in the frame of the input points, if the octree was built in a
+    /// frame aligned to the data. Positions are in the octree frame.
+    pub world_from_octree: Option<Isometry3<f64>>,
+    /// How nodes are split into children, see 'BuildOptions::subdivision'.
+    pub subdivision: Subdivision,
+    /// How the values of the attribute 'color' encode the color, see 'BuildOptions::color_space'.
+    pub color_space: ColorSpace,
+    /// Settings that viewers start with, see 'set_view_settings'.
+    pub view_settings: ViewSettings,
+    /// The data types of the attributes before 'ReencodeOptions::narrow_attributes' narrowed
+    /// them.
+    pub narrowed_attributes: HashMap<String, AttributeDataType>,
     attribute_data_types: HashMap<String, AttributeDataType>,
 }
 
@@ -69,12 +234,31 @@ impl OctreeMeta {
         Self {
             resolution,
             bounding_box,
+            subsampling_seed: 0,
+            crs: None,
+            sources: Vec::new(),
+            frame_timestamps: Vec::new(),
+            world_from_octree: None,
+            subdivision: Subdivision::Octree,
+            color_space: ColorSpace::Srgb,
+            view_settings: ViewSettings::default(),
+            narrowed_attributes: HashMap::new(),
             attribute_data_types,
         }
     }
 
+    /// Adds an attribute besides the standard ones, e.g. one derived during the build.
+    pub fn add_attribute(&mut self, name: impl Into<String>, data_type: AttributeDataType) {
+        self.attribute_data_types.insert(name.into(), data_type);
+    }
+
+    /// The cube that the positions of the node are encoded relative to.
+    pub fn bounding_cube(&self, id: &NodeId) -> Cube {
+        self.subdivision.bounding_cube(id, &self.bounding_box)
+    }
+
     pub fn encoding_for_node(&self, id: NodeId) -> Encoding {
-        let bounding_cube = id.find_bounding_cube(&Cube::bounding(&self.bounding_box));
+        let bounding_cube = self.bounding_cube(&id);
         let position_encoding = PositionEncoding::new(&bounding_cube, self.resolution);
         Encoding::ScaledToCube(
             bounding_cube.min(),
@@ -84,10 +268,47 @@ impl OctreeMeta {
     }
 }
 
-pub fn to_meta_proto(octree_meta: &OctreeMeta, nodes: Vec<proto::OctreeNode>) -> proto::Meta {
+fn attribute_protos(data_types: &HashMap<String, AttributeDataType>) -> Vec<proto::Attribute> {
+    let mut attributes: Vec<proto::Attribute> = data_types
+        .iter()
+        .map(|(name, data_type)| {
+            let mut attribute = proto::Attribute::new();
+            attribute.set_name(name.to_string());
+            attribute.set_data_type(data_type.to_proto());
+            attribute
+        })
+        .collect();
+    attributes.sort_by(|a, b| a.name.cmp(&b.name));
+    attributes
+}
+
+pub fn to_meta_proto(octree_meta: &OctreeMeta, mut nodes: Vec<proto::OctreeNode>) -> proto::Meta {
     let mut octree_proto = proto::OctreeMeta::new();
     octree_proto.set_resolution(octree_meta.resolution);
+    octree_proto.set_subsampling_seed(octree_meta.subsampling_seed);
+    if let Some(crs) = &octree_meta.crs {
+        octree_proto.set_crs(crs.clone());
+    }
+    if let Some(world_from_octree) = &octree_meta.world_from_octree {
+        octree_proto.set_world_from_octree(proto::Isometry3d::from(world_from_octree));
+    }
+    octree_proto.set_subdivision(octree_meta.subdivision.to_proto());
+    octree_proto.set_color_space(octree_meta.color_space.to_proto());
+    octree_proto.set_view_settings(octree_meta.view_settings.to_proto());
+    octree_proto.set_sources(::protobuf::RepeatedField::from_vec(
+        octree_meta.sources.clone(),
+    ));
+    octree_proto.set_frame_timestamps(octree_meta.frame_timestamps.clone());
+    octree_proto.set_attributes(::protobuf::RepeatedField::from_vec(attribute_protos(
+        &octree_meta.attribute_data_types,
+    )));
+    octree_proto.set_narrowed_attributes(::protobuf::RepeatedField::from_vec(attribute_protos(
+        &octree_meta.narrowed_attributes,
+    )));
 
+    // Nodes are collected from hash maps and parallel tasks. Sorting them makes the meta file
+    // identical for identical octrees.
+    nodes.sort_by_key(|node| NodeId::from_proto(node.get_id()));
     let octree_nodes = ::protobuf::RepeatedField::<proto::OctreeNode>::from_vec(nodes);
     octree_proto.set_nodes(octree_nodes);
 
@@ -98,57 +319,49 @@ pub fn to_meta_proto(octree_meta: &OctreeMeta, nodes: Vec<proto::OctreeNode>) ->
     meta
 }
 
-// TODO(hrapp): something is funky here. "r" is smaller on screen than "r4" in many cases, though
-// that is impossible.
-fn project(m: &Matrix4<f64>, p: &Point3<f64>) -> Point3<f64> {
-    let q = m * p.to_homogeneous();
-    Point3::from_homogeneous(q).unwrap()
-}
-
-fn clip_point_to_hemicube(p: &Point3<f64>) -> Point3<f64> {
-    Point3::new(clamp(p.x, -1., 1.), clamp(p.y, -1., 1.), clamp(p.z, 0., 1.))
-}
-
-// This method projects world points through the matrix and returns a value proportional to the
-// size of the screen. We do not know the width and height of the frustum in pixels here, so the
-// unit of the value is not really well defined.
-// Ideally we'd need to know the aspect ration of width/height to make the returned value truly
-// proportional to the size on the screen, but this parameter would need to be passed through to
-// all API calls. I decided on relying on a 'good enough' metric instead which did not require the
-// parameter.
-fn relative_size_on_screen(bounding_cube: &Cube, matrix: &Matrix4<f64>) -> f64 {
-    // z is unused here.
-    let min = bounding_cube.min();
-    let max = bounding_cube.max();
-    let mut rv = Aabb::new(
-        clip_point_to_hemicube(&project(matrix, &min)),
-        clip_point_to_hemicube(&project(matrix, &max)),
-    );
-    for p in &[
-        Point3::new(max.x, min.y, min.z),
-        Point3::new(min.x, max.y, min.z),
-        Point3::new(max.x, max.y, min.z),
-        Point3::new(min.x, min.y, max.z),
-        Point3::new(max.x, min.y, max.z),
-        Point3::new(min.x, max.y, max.z),
-    ] {
-        rv.grow(clip_point_to_hemicube(&project(matrix, p)));
-    }
-    let diag = rv.diag();
-    diag.x * diag.y
-}
-
 pub struct Octree {
     data_provider: Box<dyn DataProvider>,
     meta: OctreeMeta,
     nodes: FnvHashMap<NodeId, NodeMeta>,
+    verify_checksums: bool,
 }
 
+/// The raw position and color data of a node, as uploaded to the GPU by the viewers. Positions
+/// are never Uint24 or Delta encoded, see 'Octree::get_node_data'.
 #[derive(Debug)]
 pub struct NodeData {
     pub meta: NodeMeta,
     pub position: Vec<u8>,
     pub color: Vec<u8>,
+    /// The vertices of 'Octree::shading_layout', for viewers that shade the points. Empty unless
+    /// a viewer fills it with 'Octree::get_node_vertices'.
+    pub shading: Vec<u8>,
+}
+
+/// The columns of 'NodeData' to read, so that viewers can stream the positions of nodes first
+/// and their colors later. Columns that are not read are empty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeColumns {
+    pub position: bool,
+    pub color: bool,
+}
+
+impl NodeColumns {
+    pub const ALL: NodeColumns = NodeColumns {
+        position: true,
+        color: true,
+    };
+
+    fn attributes(self) -> Vec<&'static str> {
+        let mut attributes = Vec::new();
+        if self.position {
+            attributes.push("position");
+        }
+        if self.color {
+            attributes.push("color");
+        }
+        attributes
+    }
 }
 
 impl Octree {
@@ -156,17 +369,16 @@ impl Octree {
     pub fn from_data_provider(data_provider: Box<dyn DataProvider>) -> Result<Self> {
         let meta_proto = data_provider.meta_proto()?;
         if meta_proto.version < CURRENT_VERSION {
-            eprintln!(
+            warn!(
                 "Data is an older octree version: {}, current would be {}. \
                  If feasible, try upgrading this octree using `upgrade_octree`.",
                 meta_proto.version, CURRENT_VERSION
             );
         }
-        let (bounding_box, meta, nodes_proto) = match meta_proto.version {
+        let (meta, nodes_proto) = match meta_proto.version {
             9 | 10 | 11 => {
                 let bounding_box = Aabb::from(meta_proto.get_bounding_box());
                 (
-                    bounding_box.clone(),
                     OctreeMeta::new_with_standard_attributes(
                         meta_proto.deprecated_resolution,
                         bounding_box,
@@ -176,7 +388,7 @@ impl Octree {
             }
             12 | CURRENT_VERSION => {
                 if !meta_proto.has_octree() {
-                    return Err(ErrorKind::InvalidInput("No octree meta found".to_string()).into());
+                    return Err(Error::InvalidInput("No octree meta found".to_string()));
                 }
                 let octree_meta = meta_proto.get_octree();
                 let bounding_box = Aabb::from(if meta_proto.version == 12 {
@@ -184,13 +396,34 @@ impl Octree {
                 } else {
                     meta_proto.get_bounding_box()
                 });
-                (
-                    bounding_box.clone(),
-                    OctreeMeta::new_with_standard_attributes(octree_meta.resolution, bounding_box),
-                    octree_meta.get_nodes(),
-                )
+                let mut meta =
+                    OctreeMeta::new_with_standard_attributes(octree_meta.resolution, bounding_box);
+                meta.subsampling_seed = octree_meta.subsampling_seed;
+                meta.crs = Some(octree_meta.crs.clone()).filter(|crs| !crs.is_empty());
+                meta.sources = octree_meta.get_sources().to_vec();
+                meta.frame_timestamps = octree_meta.get_frame_timestamps().to_vec();
+                if octree_meta.has_world_from_octree() {
+                    meta.world_from_octree =
+                        Some(Isometry3::from(octree_meta.get_world_from_octree()));
+                }
+                meta.subdivision = Subdivision::from_proto(octree_meta.get_subdivision());
+                meta.color_space = ColorSpace::from_proto(octree_meta.get_color_space());
+                meta.view_settings = ViewSettings::from_proto(octree_meta.get_view_settings());
+                for attribute in octree_meta.get_attributes() {
+                    meta.add_attribute(
+                        attribute.get_name(),
+                        AttributeDataType::from_proto(attribute.get_data_type())?,
+                    );
+                }
+                for attribute in octree_meta.get_narrowed_attributes() {
+                    meta.narrowed_attributes.insert(
+                        attribute.get_name().to_string(),
+                        AttributeDataType::from_proto(attribute.get_data_type())?,
+                    );
+                }
+                (meta, octree_meta.get_nodes())
             }
-            _ => return Err(ErrorKind::InvalidVersion(meta_proto.version).into()),
+            _ => return Err(Error::InvalidVersion(meta_proto.version)),
         };
 
         let mut nodes = FnvHashMap::default();
@@ -202,7 +435,18 @@ impl Octree {
                 NodeMeta {
                     num_points: node_proto.num_points,
                     position_encoding: PositionEncoding::from_proto(node_proto.position_encoding)?,
-                    bounding_cube: node_id.find_bounding_cube(&Cube::bounding(&bounding_box)),
+                    compressed_attributes: node_proto.compressed_attributes,
+                    bounding_cube: meta.bounding_cube(&node_id),
+                    checksums: node_proto.get_checksums().clone(),
+                    normal_cone: if node_proto.has_normal_cone() {
+                        NormalCone::from_proto(node_proto.get_normal_cone())
+                    } else {
+                        None
+                    },
+                    occupancy: match node_proto.occupancy {
+                        0 => None,
+                        occupancy => Some(Occupancy(occupancy)),
+                    },
                 },
             );
         }
@@ -211,21 +455,67 @@ impl Octree {
             meta,
             nodes,
             data_provider,
+            verify_checksums: true,
         })
     }
 
+    /// Whether node data is checked against the checksums in the meta data when it is read, which
+    /// is the default. A mismatch is reported as 'Error::CorruptNode'.
+    pub fn set_verify_checksums(&mut self, verify_checksums: bool) {
+        self.verify_checksums = verify_checksums;
+    }
+
+    /// Reads all files of the node and checks them against its checksums, regardless of
+    /// 'set_verify_checksums'. Returns false if the node has no checksums to check.
+    pub fn verify_node(&self, node_id: &NodeId) -> Result<bool> {
+        let node_meta = self.nodes.get(node_id).ok_or(Error::NodeNotFound)?;
+        if node_meta.checksums.is_empty() {
+            return Ok(false);
+        }
+        let attributes: Vec<&str> = node_meta.checksums.keys().map(String::as_str).collect();
+        let mut reads = self.data_provider.data(&node_id.to_string(), &attributes)?;
+        verify_reads(&node_id.to_string(), &node_meta.checksums, &mut reads)?;
+        Ok(true)
+    }
+
     pub fn to_meta_proto(&self) -> proto::Meta {
         let nodes: Vec<proto::OctreeNode> = self
             .nodes
             .iter()
             .map(|(id, node_meta)| {
-                to_node_proto(&id, node_meta.num_points, &node_meta.position_encoding)
+                to_node_proto(
+                    &id,
+                    node_meta.num_points,
+                    &node_meta.position_encoding,
+                    node_meta.compressed_attributes,
+                    node_meta.checksums.clone(),
+                    node_meta.normal_cone,
+                    node_meta.occupancy,
+                )
             })
             .collect();
         to_meta_proto(&self.meta, nodes)
     }
 
+    /// The nodes in view whose screen-space error on a default viewport reaches the
+    /// 'DEFAULT_PIXEL_THRESHOLD', the largest first.
     pub fn get_visible_nodes(&self, projection_matrix: &Matrix4<f64>) -> Vec<NodeId> {
+        self.get_visible_nodes_with_screen_space_error(
+            projection_matrix,
+            &ScreenSpaceError::default(),
+        )
+        .into_iter()
+        .map(|(node_id, _)| node_id)
+        .collect()
+    }
+
+    /// Like 'get_visible_nodes', but for the viewport and pixel threshold of 'metric', and also
+    /// returns the screen-space error of every node in pixels.
+    pub fn get_visible_nodes_with_screen_space_error(
+        &self,
+        projection_matrix: &Matrix4<f64>,
+        metric: &ScreenSpaceError,
+    ) -> Vec<(NodeId, f64)> {
         let frustum =
             Frustum::from_matrix4(*projection_matrix).expect("Invalid projection matrix.");
         let frustum_isec = frustum.intersector().cache_separating_axes_for_aabb();
@@ -234,39 +524,61 @@ impl Octree {
             &mut open,
             &self.nodes,
             Relation::Cross,
-            Node::root_with_bounding_cube(Cube::bounding(&self.meta.bounding_box)),
+            NodeId::root(),
             projection_matrix,
+            metric,
         );
 
         let mut visible = Vec::new();
         while let Some(current) = open.pop() {
+            // Nodes come out largest first, so all remaining nodes are too small as well.
+            if !metric.is_visible(current.screen_space_error) {
+                break;
+            }
             match current.relation {
                 Relation::Cross => {
-                    for child_index in 0..8 {
-                        let child = current.node.get_child(ChildIndex::from_u8(child_index));
-                        let child_relation = frustum_isec
-                            .intersect(&child.bounding_cube.to_aabb().compute_corners());
+                    for child_id in current.node.id.children() {
+                        // The cubes come from the node meta data, which knows the subdivision.
+                        let child_meta = match self.nodes.get(&child_id) {
+                            Some(meta) => meta,
+                            None => continue,
+                        };
+                        let child_cube = &child_meta.bounding_cube;
+                        let child_relation =
+                            frustum_isec.intersect(&child_cube.to_aabb().compute_corners());
                         if child_relation == Relation::Out {
                             continue;
                         }
+                        // A child crossing the frustum only with its empty cells is invisible.
+                        if child_relation == Relation::Cross
+                            && child_meta.occupancy.map_or(false, |occupancy| {
+                                occupancy.occupied_cells(child_cube).all(|cell| {
+                                    frustum_isec.intersect(&cell.compute_corners()) == Relation::Out
+                                })
+                            })
+                        {
+                            continue;
+                        }
                         maybe_push_node(
                             &mut open,
                             &self.nodes,
                             child_relation,
-                            child,
+                            child_id,
                             projection_matrix,
+                            metric,
                         );
                     }
                 }
                 Relation::In => {
                     // When the parent is fully in the frustum, so are the children.
-                    for child_index in 0..8 {
+                    for child_id in current.node.id.children() {
                         maybe_push_node(
                             &mut open,
                             &self.nodes,
                             Relation::In,
-                            current.node.get_child(ChildIndex::from_u8(child_index)),
+                            child_id,
                             projection_matrix,
+                            metric,
                         );
                     }
                 }
@@ -276,33 +588,392 @@ impl Octree {
                 }
             };
             if !current.empty {
-                visible.push(current.node.id);
+                visible.push((current.node.id, current.screen_space_error));
             }
         }
         visible
     }
 
+    /// Returns the meta data of the node, or None if the node does not exist.
+    pub fn get_node_meta(&self, node_id: &NodeId) -> Option<&NodeMeta> {
+        self.nodes.get(node_id)
+    }
+
+    /// Whether all points of the node face away from a camera at 'eye', see 'camera_position'.
+    /// Only the points of the node itself are considered, not those of its children. Always false
+    /// for nodes without a normal cone.
+    pub fn is_back_facing(&self, node_id: &NodeId, eye: &Point3<f64>) -> bool {
+        self.nodes.get(node_id).map_or(false, |node_meta| {
+            node_meta.normal_cone.map_or(false, |cone| {
+                cone.faces_away_from(&node_meta.bounding_cube, eye)
+            })
+        })
+    }
+
+    /// Returns the schema stored with the files of the node, or None if the node was written
+    /// without one.
+    pub fn get_node_schema(&self, node_id: &NodeId) -> Result<Option<NodeSchema>> {
+        NodeSchema::read(&*self.data_provider, &node_id.to_string())
+    }
+
+    /// The coordinate reference system of the positions, if it was recorded when building.
+    pub fn crs(&self) -> Option<&str> {
+        self.meta.crs.as_deref()
+    }
+
+    /// The pose of the octree frame in the frame of the input points, if the octree was built in
+    /// a frame aligned to the data. Positions and queries are in the octree frame.
+    pub fn world_from_octree(&self) -> Option<&Isometry3<f64>> {
+        self.meta.world_from_octree.as_ref()
+    }
+
+    /// The input files of the build, indexed by the attribute 'source_id' of the points. Empty
+    /// unless the octree was built with source ids.
+    pub fn sources(&self) -> &[String] {
+        &self.meta.sources
+    }
+
+    /// The timestamps of the frames of a capture over time, indexed by the attribute
+    /// 'frame_index' of the points. Empty unless the octree was built with frames.
+    pub fn frame_timestamps(&self) -> &[f64] {
+        &self.meta.frame_timestamps
+    }
+
+    /// How the values of the attribute 'color' encode the color. Viewers convert linear colors to
+    /// sRGB before they show them.
+    pub fn color_space(&self) -> ColorSpace {
+        self.meta.color_space
+    }
+
+    /// The settings that viewers start with, see 'set_view_settings'.
+    pub fn view_settings(&self) -> &ViewSettings {
+        &self.meta.view_settings
+    }
+
+    /// The number of points in all nodes.
+    pub fn num_points(&self) -> i64 {
+        self.nodes
+            .values()
+            .map(|node_meta| node_meta.num_points)
+            .sum()
+    }
+
     pub fn get_node_data(&self, node_id: &NodeId) -> Result<NodeData> {
         // TODO(hrapp): If we'd randomize the points while writing, we could just read the
         // first N points instead of reading everything and skipping over a few.
-        let mut position_color_reads = self
+        let position_color_reads = self
             .data_provider
             .data(&node_id.to_string(), &["position", "color"])?;
+        self.node_data_from_reads(node_id, position_color_reads, NodeColumns::ALL)
+    }
+
+    /// Like 'get_node_data' for several nodes, in the order of 'node_ids'. Data providers can read
+    /// the nodes concurrently, which is a lot faster than calling 'get_node_data' repeatedly on
+    /// storage with a high latency.
+    pub fn get_nodes_data(&self, node_ids: &[NodeId]) -> Vec<Result<NodeData>> {
+        self.get_nodes_columns(node_ids, NodeColumns::ALL)
+    }
+
+    /// Like 'get_nodes_data', but only reads the files of 'columns'. The other columns are empty.
+    pub fn get_nodes_columns(
+        &self,
+        node_ids: &[NodeId],
+        columns: NodeColumns,
+    ) -> Vec<Result<NodeData>> {
+        let names: Vec<String> = node_ids.iter().map(NodeId::to_string).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.data_provider
+            .data_for_nodes(&names, &columns.attributes())
+            .into_iter()
+            .zip(node_ids)
+            .map(|(reads, node_id)| {
+                reads.and_then(|reads| self.node_data_from_reads(node_id, reads, columns))
+            })
+            .collect()
+    }
+
+    /// Decodes the node into an interleaved vertex buffer with the given 'layout', in the order
+    /// in which the points are stored. Positions are relative to the bounding cube of the node,
+    /// see 'VertexLayout'.
+    pub fn get_node_vertices(&self, node_id: &NodeId, layout: &VertexLayout) -> Result<Vec<u8>> {
+        let node_meta = self.nodes.get(node_id).ok_or(Error::NodeNotFound)?;
+        let num_points = node_meta.num_points as usize;
+        let min = node_meta.bounding_cube.min();
+        let edge_length = node_meta.bounding_cube.edge_length();
+        let mut buffer = Vec::with_capacity(num_points * layout.stride());
+        let mut batch = PointsBatch::default();
+        let mut points = self.points_in_node(
+            &layout.point_attributes(),
+            *node_id,
+            cmp::max(num_points, 1),
+        )?;
+        while points.next_into(&mut batch) {
+            layout.write_vertices(&batch, &min, edge_length, &mut buffer)?;
+        }
+        Ok(buffer)
+    }
+
+    /// The layout of the normals and curvatures that viewers shade the points with: 'NORMAL' as
+    /// 'Snorm8x4' and, if the octree has it, 'CURVATURE' as 'Float32'. None if the octree has no
+    /// normals.
+    pub fn shading_layout(&self) -> Option<VertexLayout> {
+        let attribute_data_types = &self.meta.attribute_data_types;
+        if !attribute_data_types.contains_key(NORMAL) {
+            return None;
+        }
+        let layout = VertexLayout::new().with_attribute(NORMAL, VertexFormat::Snorm8x4);
+        if attribute_data_types.contains_key(CURVATURE) {
+            Some(layout.with_attribute(CURVATURE, VertexFormat::Float32))
+        } else {
+            Some(layout)
+        }
+    }
+
+    /// Colors the points of the node by their 'SOURCE_ID' with 'category_color', in the order of
+    /// 'get_node_data', so that viewers can show which scan the points come from. The result
+    /// replaces 'NodeData::color'.
+    pub fn get_node_source_colors(&self, node_id: &NodeId) -> Result<Vec<u8>> {
+        let node_meta = self.nodes.get(node_id).ok_or(Error::NodeNotFound)?;
+        let num_points = node_meta.num_points as usize;
+        let mut colors = Vec::with_capacity(num_points * 3);
+        let mut batch = PointsBatch::default();
+        let mut points = self.points_in_node(&[SOURCE_ID], *node_id, cmp::max(num_points, 1))?;
+        while points.next_into(&mut batch) {
+            let source_ids: &Vec<u16> = batch
+                .get_attribute_vec(SOURCE_ID)
+                .map_err(Error::SchemaMismatch)?;
+            for source_id in source_ids {
+                let color = category_color(u32::from(*source_id));
+                colors.extend_from_slice(&[color.red, color.green, color.blue]);
+            }
+        }
+        Ok(colors)
+    }
+
+    /// Keeps only the points of 'node_data' of the node 'node_id' that are in the frame
+    /// 'frame_index', so that viewers can play back captures over time.
+    pub fn retain_frame(
+        &self,
+        node_id: &NodeId,
+        node_data: &mut NodeData,
+        frame_index: u32,
+    ) -> Result<()> {
+        let num_points = node_data.meta.num_points as usize;
+        let mut keep = Vec::with_capacity(num_points);
+        let mut batch = PointsBatch::default();
+        let mut points = self.points_in_node(&[FRAME_INDEX], *node_id, cmp::max(num_points, 1))?;
+        while points.next_into(&mut batch) {
+            let frame_indices: &Vec<u32> = batch
+                .get_attribute_vec(FRAME_INDEX)
+                .map_err(Error::SchemaMismatch)?;
+            keep.extend(frame_indices.iter().map(|index| *index == frame_index));
+        }
+        let position_stride = 3 * node_data.meta.position_encoding.bytes_per_coordinate();
+        let retain_chunks = |data: &[u8], stride: usize| -> Vec<u8> {
+            data.chunks_exact(stride)
+                .zip(&keep)
+                .filter(|(_, keep)| **keep)
+                .flat_map(|(chunk, _)| chunk.iter().copied())
+                .collect()
+        };
+        node_data.position = retain_chunks(&node_data.position, position_stride);
+        node_data.color = retain_chunks(&node_data.color, 3);
+        node_data.meta.num_points = keep.iter().filter(|keep| **keep).count() as i64;
+        Ok(())
+    }
+
+    /// The 'k' points of the node that are closest to 'point', as their indices in the order of
+    /// 'points_in_node' and their distances, closest first.
+    pub fn nearest_in_node(
+        &self,
+        node_id: &NodeId,
+        point: &Point3<f64>,
+        k: usize,
+    ) -> Result<Vec<(usize, f64)>> {
+        let (positions, kd_index) = self.node_positions_and_kd_index(node_id)?;
+        Ok(kd_index::nearest(&positions, kd_index.as_ref(), point, k))
+    }
+
+    /// The indices of the points of the node within 'radius' of 'center', in ascending order.
+    pub fn points_within_radius_in_node(
+        &self,
+        node_id: &NodeId,
+        center: &Point3<f64>,
+        radius: f64,
+    ) -> Result<Vec<usize>> {
+        let (positions, kd_index) = self.node_positions_and_kd_index(node_id)?;
+        Ok(kd_index::within_radius(
+            &positions,
+            kd_index.as_ref(),
+            center,
+            radius,
+        ))
+    }
 
+    /// Reads the positions of the node and its kd-tree. Nodes without a kd-tree, or with one that
+    /// does not match the node any more, are searched by comparing all points.
+    fn node_positions_and_kd_index(
+        &self,
+        node_id: &NodeId,
+    ) -> Result<(Vec<Point3<f64>>, Option<KdIndex>)> {
+        let node_meta = self.nodes.get(node_id).ok_or(Error::NodeNotFound)?;
+        let mut positions = Vec::with_capacity(node_meta.num_points as usize);
+        for mut batch in self.points_in_node(&[], *node_id, NUM_POINTS_PER_BATCH)? {
+            positions.append(&mut batch.position);
+        }
+        let mut reads = match self.data_provider.data(&node_id.to_string(), &[KD_INDEX]) {
+            Ok(reads) => reads,
+            Err(Error::NodeNotFound) => return Ok((positions, None)),
+            Err(err) => return Err(err),
+        };
+        if self.verify_checksums {
+            verify_reads(&node_id.to_string(), &node_meta.checksums, &mut reads)?;
+        }
+        let mut bytes = Vec::new();
+        reads.remove(KD_INDEX).unwrap().read_to_end(&mut bytes)?;
+        let kd_index = KdIndex::from_bytes(&bytes)?;
+        if kd_index.len() != positions.len() {
+            warn!("Ignoring the kd-tree of {}, which is out of date.", node_id);
+            return Ok((positions, None));
+        }
+        Ok((positions, Some(kd_index)))
+    }
+
+    fn node_data_from_reads(
+        &self,
+        node_id: &NodeId,
+        mut position_color_reads: HashMap<String, Box<dyn Read + Send>>,
+        columns: NodeColumns,
+    ) -> Result<NodeData> {
+        if self.verify_checksums {
+            verify_reads(
+                &node_id.to_string(),
+                &self.nodes[node_id].checksums,
+                &mut position_color_reads,
+            )?;
+        }
         let mut get_data = |node_attribute: &str, err: &str| -> Result<Vec<u8>> {
-            let mut reader =
-                BufReader::new(position_color_reads.remove(node_attribute).ok_or(err)?);
+            let mut reader = BufReader::new(
+                position_color_reads
+                    .remove(node_attribute)
+                    .ok_or_else(|| Error::InvalidInput(err.to_string()))?,
+            );
             let mut all_data = Vec::new();
-            reader.read_to_end(&mut all_data).chain_err(|| err)?;
+            reader.read_to_end(&mut all_data).with_context(|| err)?;
             Ok(all_data)
         };
-        let position = get_data("position", "Could not read position")?;
-        let color = get_data("color", "Could not read color")?;
+        let position = if columns.position {
+            get_data("position", "Could not read position")?
+        } else {
+            Vec::new()
+        };
+        let mut color = if columns.color {
+            get_data("color", "Could not read color")?
+        } else {
+            Vec::new()
+        };
+        if columns.color && self.nodes[node_id].compressed_attributes {
+            let mut decoded = Vec::new();
+            ColumnDecoder::new(&color[..], AttributeDataType::U8Vec3)
+                .read_to_end(&mut decoded)
+                .with_context(|| "Could not decode color")?;
+            color = decoded;
+        }
+
+        let mut meta = self.nodes[node_id].clone();
+        let position = match meta.position_encoding {
+            _ if !columns.position => position,
+            // GPUs have no 24 bit or variable length vertex attributes. 24 bits fit into the
+            // mantissa of a float, so transcoding them to Float32 keeps all of the precision.
+            PositionEncoding::Uint24 | PositionEncoding::Delta => {
+                let mut reader = RawNodeReader::new(
+                    Box::new(Cursor::new(position)),
+                    HashMap::new(),
+                    self.encoding_for_node(*node_id),
+                )?;
+                let batch = reader.read_batch(meta.num_points as usize)?;
+                let min = meta.bounding_cube.min();
+                let edge_length = meta.bounding_cube.edge_length();
+                let mut float32 = Vec::with_capacity(batch.position.len() * 3 * 4);
+                for position in &batch.position {
+                    let encoded = vec3_encode::<f32>(position, &min, edge_length);
+                    for coordinate in encoded.iter() {
+                        float32.write_f32::<LittleEndian>(*coordinate)?;
+                    }
+                }
+                meta.position_encoding = PositionEncoding::Float32;
+                float32
+            }
+            _ => position,
+        };
 
         Ok(NodeData {
             position,
             color,
-            meta: self.nodes[node_id].clone(),
+            shading: Vec::new(),
+            meta,
+        })
+    }
+
+    /// Returns the ids of the nodes whose level is in 'levels', level by level from the root.
+    /// Subtrees below the last level are not visited.
+    pub fn nodes(&self, levels: impl RangeBounds<u8>) -> impl Iterator<Item = NodeId> + '_ {
+        let min_level = match levels.start_bound() {
+            Bound::Included(level) => i32::from(*level),
+            Bound::Excluded(level) => i32::from(*level) + 1,
+            Bound::Unbounded => 0,
+        };
+        let max_level = match levels.end_bound() {
+            Bound::Included(level) => i32::from(*level),
+            Bound::Excluded(level) => i32::from(*level) - 1,
+            Bound::Unbounded => i32::max_value(),
+        };
+        self.nodes_between(min_level, max_level)
+    }
+
+    // Not generic over the range, so that the iterator does not depend on its type.
+    fn nodes_between(&self, min_level: i32, max_level: i32) -> impl Iterator<Item = NodeId> + '_ {
+        NodeIdsIterator::new(self, move |node_id, _| {
+            i32::from(node_id.level()) <= max_level
+        })
+        .filter(move |node_id| i32::from(node_id.level()) >= min_level)
+    }
+
+    /// Streams the points of all nodes up to the level 'max_lod', or of all nodes, in batches. Use
+    /// 'par_all_points' to read the nodes in parallel.
+    pub fn all_points<'a>(
+        &'a self,
+        attributes: &'a [&'a str],
+        max_lod: Option<u8>,
+    ) -> impl Iterator<Item = Result<PointsBatch>> + 'a {
+        self.nodes(..=max_lod.unwrap_or(NodeId::MAX_LEVEL))
+            .flat_map(move |node_id| {
+                let (points, error) =
+                    match self.points_in_node(attributes, node_id, NUM_POINTS_PER_BATCH) {
+                        Ok(points) => (Some(points), None),
+                        Err(e) => (None, Some(Err(e))),
+                    };
+                points.into_iter().flatten().map(Ok).chain(error)
+            })
+    }
+
+    /// Like 'all_points', but reads the nodes in parallel on the rayon thread pool, e.g. for
+    /// statistics or reclassification jobs. The batches arrive in no particular order.
+    #[cfg(feature = "native")]
+    pub fn par_all_points<'a>(
+        &'a self,
+        attributes: &'a [&'a str],
+        max_lod: Option<u8>,
+    ) -> impl rayon::iter::ParallelIterator<Item = Result<PointsBatch>> + 'a {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+        let node_ids: Vec<NodeId> = self
+            .nodes(..=max_lod.unwrap_or(NodeId::MAX_LEVEL))
+            .collect();
+        node_ids.into_par_iter().flat_map(move |node_id| {
+            match self.points_in_node(attributes, node_id, NUM_POINTS_PER_BATCH) {
+                Ok(points) => points.map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            }
         })
     }
 
@@ -315,11 +986,25 @@ impl Octree {
         // it's a generalized version of get_visible_nodes(), and get_visible_nodes() can use this
         // function instead.
         let isec = location.aabb_intersector();
-        NodeIdsIterator::new(&self, |node_id, octree| {
-            let aabb = octree.nodes[&node_id].bounding_cube.to_aabb();
-            isec.intersect_aabb(&aabb)
-        })
-        .collect()
+        let mut node_ids = Vec::new();
+        self.visit_nodes(&mut |node_id: &NodeId, node_meta: &NodeMeta| {
+            let cube = &node_meta.bounding_cube;
+            // Nodes whose occupied cells all miss the location have no points there, and neither
+            // do their descendants.
+            if isec.intersect_aabb(&cube.to_aabb())
+                && node_meta.occupancy.map_or(true, |occupancy| {
+                    occupancy
+                        .occupied_cells(cube)
+                        .any(|cell| isec.intersect_aabb(&cell))
+                })
+            {
+                node_ids.push(*node_id);
+                Visit::Continue
+            } else {
+                Visit::SkipChildren
+            }
+        });
+        node_ids
     }
 }
 
@@ -331,7 +1016,14 @@ impl PointCloud for Octree {
     }
 
     fn encoding_for_node(&self, id: Self::Id) -> Encoding {
-        self.meta.encoding_for_node(id)
+        // Use the encoding stored with the node instead of deriving it from the resolution, since
+        // nodes might have been re-encoded.
+        let node_meta = &self.nodes[&id];
+        Encoding::ScaledToCube(
+            node_meta.bounding_cube.min(),
+            node_meta.bounding_cube.edge_length(),
+            node_meta.position_encoding.clone(),
+        )
     }
 
     fn points_in_node(
@@ -340,15 +1032,39 @@ impl PointCloud for Octree {
         node_id: Self::Id,
         batch_size: usize,
     ) -> Result<NodeIterator> {
+        let verifier = ChecksumVerifier {
+            data_provider: &*self.data_provider,
+            checksums: &self.nodes[&node_id].checksums,
+        };
+        let data_provider: &dyn DataProvider = if self.verify_checksums {
+            &verifier
+        } else {
+            &*self.data_provider
+        };
         let node_iterator = NodeIterator::from_data_provider(
-            &*self.data_provider,
+            data_provider,
             &self.meta.attribute_data_types_for(&attributes)?,
-            self.meta.encoding_for_node(node_id),
+            self.encoding_for_node(node_id),
+            self.nodes[&node_id].compressed_attributes,
             &node_id,
             self.nodes[&node_id].num_points as usize,
             batch_size,
-        )?;
-        Ok(node_iterator)
+        );
+        match node_iterator {
+            // Nodes can lack attributes of the octree, e.g. standard attributes that the build did
+            // not write. Their schema tells this apart from missing files.
+            Err(Error::NodeNotFound) => match self.get_node_schema(&node_id) {
+                Ok(Some(schema)) if !schema.missing_attributes(attributes).is_empty() => {
+                    Err(Error::SchemaMismatch(format!(
+                        "Node {} has no attributes {}.",
+                        node_id,
+                        schema.missing_attributes(attributes).join(", ")
+                    )))
+                }
+                _ => Err(Error::NodeNotFound),
+            },
+            node_iterator => node_iterator,
+        }
     }
 
     /// return the bounding box saved in meta
@@ -360,7 +1076,7 @@ impl PointCloud for Octree {
 struct OpenNode {
     node: Node,
     relation: Relation,
-    size_on_screen: f64,
+    screen_space_error: f64,
     empty: bool,
 }
 
@@ -372,13 +1088,14 @@ impl Ord for OpenNode {
 
 impl PartialOrd for OpenNode {
     fn partial_cmp(&self, other: &OpenNode) -> Option<Ordering> {
-        self.size_on_screen.partial_cmp(&other.size_on_screen)
+        self.screen_space_error
+            .partial_cmp(&other.screen_space_error)
     }
 }
 
 impl PartialEq for OpenNode {
     fn eq(&self, other: &OpenNode) -> bool {
-        self.size_on_screen == other.size_on_screen
+        self.screen_space_error == other.screen_space_error
     }
 }
 
@@ -389,15 +1106,20 @@ fn maybe_push_node(
     v: &mut BinaryHeap<OpenNode>,
     nodes: &FnvHashMap<NodeId, NodeMeta>,
     relation: Relation,
-    node: Node,
+    node_id: NodeId,
     projection_matrix: &Matrix4<f64>,
+    metric: &ScreenSpaceError,
 ) {
-    if let Some(meta) = nodes.get(&node.id) {
-        let size_on_screen = relative_size_on_screen(&node.bounding_cube, projection_matrix);
+    if let Some(meta) = nodes.get(&node_id) {
+        let node = Node {
+            id: node_id,
+            bounding_cube: meta.bounding_cube.clone(),
+        };
+        let screen_space_error = metric.error(&node.bounding_cube, projection_matrix);
         v.push(OpenNode {
             node,
             relation,
-            size_on_screen,
+            screen_space_error,
             empty: meta.num_points == 0,
         });
     }
@@ -12,11 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::geometry::Cube;
+use crate::errors::*;
+use crate::geometry::{Aabb, Cube};
+use crate::octree::{NormalCone, Occupancy};
 use crate::proto;
 use crate::read_write::PositionEncoding;
 use nalgebra::Point3;
-use std::num::ParseIntError;
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::{fmt, result};
 
@@ -46,27 +48,93 @@ impl ChildIndex {
     }
 }
 
+/// How nodes are split into their children.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Subdivision {
+    /// Nodes are split at their center in x, y and z into eight children.
+    Octree,
+    /// Nodes are split at their center in x and y into the four children whose child index has
+    /// the z bit unset, and every node spans the z range of the bounding box. This fits airborne
+    /// data, which is far wider than it is high, with fewer and shallower nodes.
+    Quadtree,
+}
+
+impl Default for Subdivision {
+    fn default() -> Self {
+        Subdivision::Octree
+    }
+}
+
+impl Subdivision {
+    pub fn from_proto(proto: proto::Subdivision) -> Self {
+        match proto {
+            proto::Subdivision::OCTREE => Subdivision::Octree,
+            proto::Subdivision::QUADTREE => Subdivision::Quadtree,
+        }
+    }
+
+    pub fn to_proto(self) -> proto::Subdivision {
+        match self {
+            Subdivision::Octree => proto::Subdivision::OCTREE,
+            Subdivision::Quadtree => proto::Subdivision::QUADTREE,
+        }
+    }
+
+    /// Returns the child of the node with the bounding cube from 'find_bounding_cube' that
+    /// contains 'v'.
+    pub fn child_index(self, bounding_cube: &Cube, v: &Point3<f64>) -> ChildIndex {
+        let child_index = ChildIndex::from_bounding_cube(bounding_cube, v);
+        match self {
+            Subdivision::Octree => child_index,
+            Subdivision::Quadtree => ChildIndex(child_index.0 & 0b110),
+        }
+    }
+
+    /// The cube that contains all points of the node 'id' in a point cloud with the given
+    /// bounding box, which positions are encoded relative to. For the quadtree, it starts at the
+    /// bottom of the bounding box and is just large enough for the node's square and the z range.
+    pub fn bounding_cube(self, id: &NodeId, bounding_box: &Aabb) -> Cube {
+        let cube = id.find_bounding_cube(&Cube::bounding(bounding_box));
+        match self {
+            Subdivision::Octree => cube,
+            Subdivision::Quadtree => {
+                let height = bounding_box.max().z - bounding_box.min().z;
+                Cube::new(cube.min(), cube.edge_length().max(height))
+            }
+        }
+    }
+}
+
 /// A unique identifier to a node. Currently this is implemented as 'r' being the root and r[0-7]
 /// being the children, r[0-7][0-7] being the grand children and so on. The actual representation
 /// might change though.
 // Top 8 bits of the value are level, the rest is the index.
 // The root has level = 0, its children 1 and so on. Multiple nodes can have the same index,
 // but none can have the same index and level.
-#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct NodeId(u128);
 
 impl FromStr for NodeId {
-    type Err = ParseIntError;
-
-    /// Construct a NodeId. No checking is done if this is a valid Id.
-    fn from_str(name: &str) -> std::result::Result<Self, Self::Err> {
-        let level = (name.len() - 1) as u8;
-        let index = if level > 0 {
-            u128::from_str_radix(&name[1..], 8)?
-        } else {
+    type Err = Error;
+
+    /// Parses the name of a node as written by 'Display', e.g. 'r0153'.
+    fn from_str(name: &str) -> Result<Self> {
+        let digits = match name.strip_prefix('r') {
+            Some(digits)
+                if digits.len() <= usize::from(NodeId::MAX_LEVEL)
+                    && digits.chars().all(|c| ('0'..='7').contains(&c)) =>
+            {
+                digits
+            }
+            _ => return Err(Error::InvalidInput(format!("Invalid node id '{}'.", name))),
+        };
+        let index = if digits.is_empty() {
             0
+        } else {
+            // Cannot fail, the digits were checked above.
+            u128::from_str_radix(digits, 8).unwrap()
         };
-        Ok(NodeId::from_level_index(level, index))
+        Ok(NodeId::from_level_index(digits.len() as u8, index))
     }
 }
 
@@ -86,6 +154,9 @@ impl fmt::Display for NodeId {
 }
 
 impl NodeId {
+    /// The deepest level that fits into a NodeId: the index takes 3 bits per level.
+    pub const MAX_LEVEL: u8 = 40;
+
     pub fn from_proto(proto: &proto::NodeId) -> Self {
         let deprecated_level = proto.deprecated_level as u8;
         let deprecated_index = proto.deprecated_index;
@@ -111,7 +182,7 @@ impl NodeId {
     }
 
     /// Returns the root node of the octree.
-    fn root() -> Self {
+    pub fn root() -> Self {
         NodeId(0)
     }
 
@@ -124,8 +195,14 @@ impl NodeId {
         )
     }
 
-    /// The child index of this node in its parent.
-    fn child_index(&self) -> Option<ChildIndex> {
+    /// Returns the ids of all eight children, in the order of their 'ChildIndex'.
+    pub fn children(&self) -> impl Iterator<Item = NodeId> {
+        let id = *self;
+        (0..8).map(move |child_index| id.get_child_id(ChildIndex(child_index)))
+    }
+
+    /// The child index of this node in its parent, or None for the root.
+    pub fn child_index(&self) -> Option<ChildIndex> {
         if self.level() == 0 {
             return None;
         }
@@ -153,7 +230,60 @@ impl NodeId {
         self.0 & 0x00ff_ffff_ffff_ffff_ffff_ffff_ffff_ffff
     }
 
-    /// Computes the bounding cube from a NodeID.
+    /// Returns the nodes on the same level that share a face with this one. Nodes at the boundary
+    /// of the octree have fewer than six. The nodes need not exist in a particular octree.
+    pub fn neighbors(&self) -> Vec<NodeId> {
+        let level = self.level();
+        let size = 1u64 << level;
+        let cell = self.grid_cell();
+        let mut neighbors = Vec::with_capacity(6);
+        for axis in 0..3 {
+            if cell[axis] > 0 {
+                let mut neighbor = cell;
+                neighbor[axis] -= 1;
+                neighbors.push(NodeId::from_grid_cell(level, neighbor));
+            }
+            if cell[axis] + 1 < size {
+                let mut neighbor = cell;
+                neighbor[axis] += 1;
+                neighbors.push(NodeId::from_grid_cell(level, neighbor));
+            }
+        }
+        neighbors
+    }
+
+    /// The x, y and z position of the node in the grid of all nodes on its level. The bits of the
+    /// index interleave them, with the ones of the root's child in the most significant place.
+    fn grid_cell(&self) -> [u64; 3] {
+        let mut cell = [0; 3];
+        for bit in 0..self.level() {
+            let child_index = (self.index() >> (3 * bit)) & 7;
+            cell[0] |= (((child_index >> 2) & 1) as u64) << bit;
+            cell[1] |= (((child_index >> 1) & 1) as u64) << bit;
+            cell[2] |= ((child_index & 1) as u64) << bit;
+        }
+        cell
+    }
+
+    fn from_grid_cell(level: u8, cell: [u64; 3]) -> Self {
+        let mut index = 0;
+        for bit in 0..level {
+            let child_index =
+                ((cell[0] >> bit) & 1) << 2 | ((cell[1] >> bit) & 1) << 1 | ((cell[2] >> bit) & 1);
+            index |= u128::from(child_index) << (3 * bit);
+        }
+        NodeId::from_level_index(level, index)
+    }
+
+    /// The bounding box of the node in an octree with the given bounding box, like
+    /// 'find_bounding_cube'.
+    pub fn bounding_box(&self, octree_bounding_box: &Aabb) -> Aabb {
+        self.find_bounding_cube(&Cube::bounding(octree_bounding_box))
+            .to_aabb()
+    }
+
+    /// Computes the bounding cube from a NodeID. For the quadtree, this is the node's square at the
+    /// bottom of the root cube, see 'Subdivision::bounding_cube'.
     pub fn find_bounding_cube(&self, root_bounding_cube: &Cube) -> Cube {
         let mut edge_length = root_bounding_cube.edge_length();
         let mut min = root_bounding_cube.min();
@@ -249,6 +379,16 @@ pub struct NodeMeta {
     pub num_points: i64,
     pub position_encoding: PositionEncoding,
     pub bounding_cube: Cube,
+    /// Whether the attribute columns were written with a 'ColumnEncoder'.
+    pub compressed_attributes: bool,
+    /// Checksums of the node's files by attribute, see 'octree::checksum'. Empty for octrees
+    /// written before checksums were stored.
+    pub checksums: HashMap<String, u64>,
+    /// Bounds the normals of the points, if the octree was built with normals.
+    pub normal_cone: Option<NormalCone>,
+    /// The cells of 'bounding_cube' that contain points of the subtree, if the octree was built
+    /// with occupancy.
+    pub occupancy: Option<Occupancy>,
 }
 
 impl NodeMeta {
@@ -261,11 +401,23 @@ pub fn to_node_proto(
     node_id: &NodeId,
     num_points: i64,
     position_encoding: &PositionEncoding,
+    compressed_attributes: bool,
+    checksums: HashMap<String, u64>,
+    normal_cone: Option<NormalCone>,
+    occupancy: Option<Occupancy>,
 ) -> proto::OctreeNode {
     let mut proto = proto::OctreeNode::new();
     *proto.mut_id() = node_id.to_proto();
     proto.set_num_points(num_points);
     proto.set_position_encoding(position_encoding.to_proto());
+    proto.set_compressed_attributes(compressed_attributes);
+    proto.set_checksums(checksums);
+    if let Some(normal_cone) = normal_cone {
+        proto.set_normal_cone(normal_cone.to_proto());
+    }
+    if let Some(occupancy) = occupancy {
+        proto.set_occupancy(occupancy.0);
+    }
     proto
 }
 
@@ -295,6 +447,41 @@ mod tests {
         assert_eq!(None, NodeId::from_str("r").unwrap().child_index());
     }
 
+    #[test]
+    fn test_string_round_trip() {
+        let deepest = format!("r{}", "7".repeat(40));
+        for name in &["r", "r0", "r7", "r0153", deepest.as_str()] {
+            assert_eq!(NodeId::from_str(name).unwrap().to_string(), *name);
+        }
+        let too_deep = format!("r{}", "0".repeat(41));
+        for name in &["", "0", "r8", "r01x", "x01", too_deep.as_str()] {
+            assert!(NodeId::from_str(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_children_and_neighbors() {
+        let node_id = NodeId::from_str("r13").unwrap();
+        let children: Vec<String> = node_id.children().map(|id| id.to_string()).collect();
+        assert_eq!(
+            children,
+            ["r130", "r131", "r132", "r133", "r134", "r135", "r136", "r137"]
+        );
+        assert!(node_id
+            .children()
+            .all(|child| child.parent_id() == Some(node_id)));
+
+        // r13 is at x = 0, y = 1, z = 3 in the 4x4x4 grid of level 2.
+        let mut neighbors: Vec<String> = node_id
+            .neighbors()
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        neighbors.sort();
+        assert_eq!(neighbors, ["r11", "r12", "r17", "r31"]);
+        assert!(NodeId::root().neighbors().is_empty());
+    }
+
     #[test]
     fn test_bounding_box() {
         let root_bounding_cube = Cube::new(Point3::new(-5., -5., -5.), 10.);
@@ -315,4 +502,33 @@ mod tests {
         assert_eq!(2.5, bounding_cube.min().z);
         assert_eq!(2.5, bounding_cube.edge_length());
     }
+
+    #[test]
+    fn test_quadtree() {
+        let bounding_box = Aabb::new(Point3::new(0., 0., 10.), Point3::new(100., 80., 14.));
+        let point = Point3::new(52., 2., 13.5);
+        let id = NodeId::from_str("r4000").unwrap();
+        let square = id.find_bounding_cube(&Cube::bounding(&bounding_box));
+        assert_eq!(
+            ChildIndex(0),
+            Subdivision::Quadtree.child_index(&square, &point)
+        );
+        assert_eq!(
+            ChildIndex(1),
+            Subdivision::Octree.child_index(&square, &point)
+        );
+
+        // Coarse nodes are wider than high, the finest ones are as high as the bounding box.
+        let bounding_cube =
+            Subdivision::Quadtree.bounding_cube(&NodeId::from_str("r4").unwrap(), &bounding_box);
+        assert_eq!(Point3::new(50., 0., 10.), bounding_cube.min());
+        assert_eq!(50., bounding_cube.edge_length());
+        let bounding_cube = Subdivision::Quadtree.bounding_cube(&id, &bounding_box);
+        assert_eq!(6.25, bounding_cube.edge_length());
+        let bounding_cube =
+            Subdivision::Quadtree.bounding_cube(&id.get_child_id(ChildIndex(0)), &bounding_box);
+        assert_eq!(Point3::new(50., 0., 10.), bounding_cube.min());
+        assert_eq!(4., bounding_cube.edge_length());
+        assert!(bounding_cube.to_aabb().contains(&point));
+    }
 }
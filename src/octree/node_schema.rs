@@ -0,0 +1,271 @@
+//! A small header stored next to the files of a node as '<node>.schema'. It describes how the
+//! files are encoded and which attributes they hold, so that a node can be read without the meta
+//! data of its octree, e.g. from a partial copy, and so that nodes whose attributes differ from
+//! the rest of the octree fail with an error that says so.
+//!
+//! The schema is a file of its own rather than a header in front of the node files: those are
+//! plain arrays that the web client, the memory mapped and io_uring data providers and
+//! 'compact_octree' read and copy as they are, and octrees written before the schema existed have
+//! to stay readable. A node without a schema is read with the meta data of its octree.
+
+use crate::data_provider::DataProvider;
+use crate::errors::*;
+use crate::geometry::Cube;
+use crate::proto;
+#[cfg(feature = "native")]
+use crate::read_write::write_file_atomically;
+use crate::read_write::{Encoding, NodeIterator, PositionEncoding};
+use crate::AttributeDataType;
+use nalgebra::Point3;
+#[cfg(feature = "native")]
+use protobuf::Message;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+#[cfg(feature = "native")]
+use std::path::Path;
+
+/// Name of the file of a node that holds its schema.
+pub const NODE_SCHEMA: &str = "schema";
+
+/// The version of the schemas that are written. Schemas with a newer version are not read, since
+/// their files might be encoded in a way that this version does not know.
+pub const NODE_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug)]
+pub struct NodeSchema {
+    pub num_points: i64,
+    pub bounding_cube: Cube,
+    pub position_encoding: PositionEncoding,
+    pub compressed_attributes: bool,
+    /// The attributes that the node has files for, without 'position'.
+    pub attribute_data_types: HashMap<String, AttributeDataType>,
+}
+
+impl NodeSchema {
+    pub fn from_proto(proto: &proto::NodeSchema) -> Result<Self> {
+        if proto.version > NODE_SCHEMA_VERSION {
+            return Err(Error::UnsupportedFormat(format!(
+                "The node schema has version {}, the latest supported version is {}.",
+                proto.version, NODE_SCHEMA_VERSION
+            )));
+        }
+        let attribute_data_types = proto
+            .get_attributes()
+            .iter()
+            .map(|attribute| {
+                AttributeDataType::from_proto(attribute.get_data_type())
+                    .map(|data_type| (attribute.get_name().to_string(), data_type))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            num_points: proto.num_points,
+            bounding_cube: Cube::new(Point3::from(proto.get_cube_min()), proto.cube_edge_length),
+            position_encoding: PositionEncoding::from_proto(proto.position_encoding)?,
+            compressed_attributes: proto.compressed_attributes,
+            attribute_data_types,
+        })
+    }
+
+    pub fn to_proto(&self) -> proto::NodeSchema {
+        let mut proto = proto::NodeSchema::new();
+        proto.set_version(NODE_SCHEMA_VERSION);
+        proto.set_num_points(self.num_points);
+        proto.set_cube_min(proto::Vector3d::from(&self.bounding_cube.min()));
+        proto.set_cube_edge_length(self.bounding_cube.edge_length());
+        proto.set_position_encoding(self.position_encoding.to_proto());
+        proto.set_compressed_attributes(self.compressed_attributes);
+        let mut attributes: Vec<proto::Attribute> = self
+            .attribute_data_types
+            .iter()
+            .map(|(name, data_type)| {
+                let mut attribute = proto::Attribute::new();
+                attribute.set_name(name.to_string());
+                attribute.set_data_type(data_type.to_proto());
+                attribute
+            })
+            .collect();
+        attributes.sort_by(|a, b| a.name.cmp(&b.name));
+        proto.set_attributes(::protobuf::RepeatedField::from_vec(attributes));
+        proto
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        Encoding::ScaledToCube(
+            self.bounding_cube.min(),
+            self.bounding_cube.edge_length(),
+            self.position_encoding.clone(),
+        )
+    }
+
+    /// Reads the schema of 'node_id'. Returns 'None' for nodes that were written without one.
+    pub fn read(data_provider: &dyn DataProvider, node_id: &str) -> Result<Option<Self>> {
+        let mut reads = match data_provider.data(node_id, &[NODE_SCHEMA]) {
+            Ok(reads) => reads,
+            Err(Error::NodeNotFound) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut bytes = Vec::new();
+        reads.remove(NODE_SCHEMA).unwrap().read_to_end(&mut bytes)?;
+        let proto = protobuf::parse_from_bytes::<proto::NodeSchema>(&bytes)
+            .with_context(|| format!("Could not parse the schema of node {}.", node_id))?;
+        Self::from_proto(&proto).map(Some)
+    }
+
+    /// Writes the schema next to the files of a node, whose path without extension is 'stem'.
+    #[cfg(feature = "native")]
+    pub fn write(&self, stem: impl AsRef<Path>) -> Result<()> {
+        let path = stem.as_ref().with_extension(NODE_SCHEMA);
+        let bytes = self
+            .to_proto()
+            .write_to_bytes()
+            .with_context(|| "Could not serialize the node schema.")?;
+        write_file_atomically(&path, &bytes)
+            .with_context(|| format!("Could not write {}.", path.display()))
+    }
+
+    /// The attributes in 'attributes' that the node has no files for.
+    pub fn missing_attributes<'a>(&self, attributes: &[&'a str]) -> Vec<&'a str> {
+        attributes
+            .iter()
+            .copied()
+            .filter(|a| !self.attribute_data_types.contains_key(*a))
+            .collect()
+    }
+
+    /// Reads the points of 'node_id' with 'attributes' by this schema alone.
+    pub fn points(
+        &self,
+        data_provider: &dyn DataProvider,
+        node_id: &str,
+        attributes: &[&str],
+        batch_size: usize,
+    ) -> Result<NodeIterator> {
+        let missing = self.missing_attributes(attributes);
+        if !missing.is_empty() {
+            return Err(Error::SchemaMismatch(format!(
+                "Node {} has no attributes {}.",
+                node_id,
+                missing.join(", ")
+            )));
+        }
+        let attribute_data_types = attributes
+            .iter()
+            .map(|a| ((*a).to_string(), self.attribute_data_types[*a]))
+            .collect();
+        NodeIterator::from_data_provider(
+            data_provider,
+            &attribute_data_types,
+            self.encoding(),
+            self.compressed_attributes,
+            &node_id,
+            self.num_points as usize,
+            batch_size,
+        )
+    }
+}
+
+impl fmt::Display for NodeSchema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let min = self.bounding_cube.min();
+        writeln!(f, "Points: {}", self.num_points)?;
+        writeln!(
+            f,
+            "Cube: min ({}, {}, {}), edge length {}",
+            min.x,
+            min.y,
+            min.z,
+            self.bounding_cube.edge_length()
+        )?;
+        writeln!(f, "Position encoding: {:?}", self.position_encoding)?;
+        writeln!(f, "Compressed attributes: {}", self.compressed_attributes)?;
+        let mut attributes: Vec<_> = self.attribute_data_types.iter().collect();
+        attributes.sort_by_key(|(name, _)| name.as_str());
+        write!(f, "Attributes:")?;
+        for (name, data_type) in attributes {
+            write!(f, " {} ({:?})", name, data_type)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::data_provider::OnDiskDataProvider;
+    use crate::iterator::PointCloud;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::{BuildOptions, NodeId};
+    use crate::{AttributeData, PointsBatch, META_FILENAME, NUM_POINTS_PER_BATCH};
+    use nalgebra::Vector3;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_proto_round_trip() {
+        let schema = NodeSchema {
+            num_points: 42,
+            bounding_cube: Cube::new(Point3::new(-1., 2., 0.5), 4.),
+            position_encoding: PositionEncoding::Uint16,
+            compressed_attributes: true,
+            attribute_data_types: vec![("color".to_string(), AttributeDataType::U8Vec3)]
+                .into_iter()
+                .collect(),
+        };
+        let read = NodeSchema::from_proto(&schema.to_proto()).unwrap();
+        assert_eq!(read.num_points, 42);
+        assert_eq!(read.bounding_cube.min(), Point3::new(-1., 2., 0.5));
+        assert_eq!(read.bounding_cube.edge_length(), 4.);
+        assert_eq!(read.position_encoding, PositionEncoding::Uint16);
+        assert!(read.compressed_attributes);
+        assert_eq!(read.attribute_data_types, schema.attribute_data_types);
+        assert_eq!(
+            read.missing_attributes(&["color", "intensity"]),
+            ["intensity"]
+        );
+
+        let mut newer = schema.to_proto();
+        newer.set_version(NODE_SCHEMA_VERSION + 1);
+        assert!(NodeSchema::from_proto(&newer).is_err());
+    }
+
+    #[test]
+    fn test_read_node_by_schema() {
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let batch = PointsBatch {
+            position: vec![Point3::origin(), Point3::new(1., 1., 1.)],
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); 2]),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let octree = build_octree_of_batch(tmp_dir.path(), 0.001, batch, &BuildOptions::default());
+        let root_id = NodeId::root();
+        let schema = octree.get_node_schema(&root_id).unwrap().unwrap();
+        assert_eq!(schema.num_points, octree.nodes[&root_id].num_points);
+        assert_eq!(
+            schema.attribute_data_types.keys().collect::<Vec<_>>(),
+            ["color"]
+        );
+
+        // 'intensity' is a standard attribute of the octree, but the build did not write it.
+        match octree.points_in_node(&["intensity"], root_id, NUM_POINTS_PER_BATCH) {
+            Err(Error::SchemaMismatch(_)) => (),
+            other => panic!("Expected a schema mismatch, got {:?}.", other.err()),
+        }
+
+        // The node can be read by its schema alone.
+        fs::remove_file(tmp_dir.path().join(META_FILENAME)).unwrap();
+        let data_provider = OnDiskDataProvider {
+            directory: tmp_dir.path().to_path_buf(),
+        };
+        let num_points: usize = schema
+            .points(&data_provider, "r", &["color"], NUM_POINTS_PER_BATCH)
+            .unwrap()
+            .map(|batch| batch.position.len())
+            .sum();
+        assert_eq!(num_points as i64, schema.num_points);
+    }
+}
@@ -0,0 +1,235 @@
+//! Bounds on the normals of the points of a node, computed during the build, so that viewers and
+//! queries can skip nodes whose surfaces all face away from the camera, e.g. the far side of
+//! building facades.
+
+use crate::attributes::AttributeData;
+use crate::geometry::Cube;
+use crate::proto;
+use crate::PointsBatch;
+use nalgebra::{Matrix3, Matrix4, Point3, Unit, UnitQuaternion, Vector3};
+use std::f64::consts::FRAC_PI_2;
+
+/// Name of the attribute with the unit normals of the points, see 'BuildOptions::normals'.
+pub const NORMAL: &str = "normal";
+
+/// Name of the attribute with the curvatures of the points, see 'BuildOptions::curvature'. It is
+/// the surface variation of the neighborhood, e.g. as estimated by PCL, in [0, 1/3]: 0 on planes.
+pub const CURVATURE: &str = "curvature";
+
+/// The names of the components of the normals in input files, e.g. PLY properties.
+const NORMAL_COMPONENTS: [&str; 3] = ["nx", "ny", "nz"];
+
+/// Replaces the attributes 'nx', 'ny' and 'nz' of 'batch' by the attribute 'normal', rotated by
+/// 'rotation' like the positions. Returns false if the batch has no normals.
+pub fn gather_normals(batch: &mut PointsBatch, rotation: &UnitQuaternion<f64>) -> bool {
+    if !NORMAL_COMPONENTS
+        .iter()
+        .all(|name| batch.attributes.contains_key(*name))
+    {
+        return false;
+    }
+    let components: Vec<Vec<f64>> = NORMAL_COMPONENTS
+        .iter()
+        .map(|name| match batch.attributes.remove(*name).unwrap() {
+            AttributeData::F32(values) => values.into_iter().map(f64::from).collect(),
+            AttributeData::F64(values) => values,
+            other => panic!(
+                "Normal component '{}' must be a float, not {:?}.",
+                name,
+                other.data_type()
+            ),
+        })
+        .collect();
+    let normals = (0..batch.position.len())
+        .map(|i| {
+            let normal =
+                rotation * Vector3::new(components[0][i], components[1][i], components[2][i]);
+            normal.try_normalize(0.).unwrap_or_else(Vector3::zeros)
+        })
+        .collect();
+    batch
+        .attributes
+        .insert(NORMAL.to_string(), AttributeData::F64Vec3(normals));
+    true
+}
+
+/// Stores the property 'curvature' of 'batch' as 32 bit floats, the type of the attribute
+/// 'curvature'. Returns false if the batch has no curvatures.
+pub fn gather_curvature(batch: &mut PointsBatch) -> bool {
+    let curvature = match batch.attributes.remove(CURVATURE) {
+        Some(AttributeData::F32(values)) => values,
+        Some(AttributeData::F64(values)) => values.into_iter().map(|c| c as f32).collect(),
+        Some(other) => panic!("Curvature must be a float, not {:?}.", other.data_type()),
+        None => return false,
+    };
+    batch
+        .attributes
+        .insert(CURVATURE.to_string(), AttributeData::F32(curvature));
+    true
+}
+
+/// All normals of a node are within 'half_angle' radians of 'axis'.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NormalCone {
+    pub axis: Unit<Vector3<f64>>,
+    pub half_angle: f64,
+}
+
+impl NormalCone {
+    /// The cone around the mean direction of 'normals'. Returns None if the normals point in too
+    /// many directions for the cone to ever face away from a camera. Zero normals are ignored.
+    pub fn from_normals(normals: &[Vector3<f64>]) -> Option<Self> {
+        let sum: Vector3<f64> = normals.iter().filter_map(|n| n.try_normalize(0.)).sum();
+        let axis = Unit::try_new(sum, 1e-9)?;
+        let min_cos = normals
+            .iter()
+            .filter_map(|n| n.try_normalize(0.))
+            .map(|n| axis.dot(&n))
+            .fold(1., f64::min);
+        let half_angle = min_cos.max(-1.).acos();
+        if half_angle >= FRAC_PI_2 {
+            return None;
+        }
+        Some(NormalCone { axis, half_angle })
+    }
+
+    pub fn from_proto(proto: &proto::NormalCone) -> Option<Self> {
+        let axis = proto.get_axis();
+        Unit::try_new(Vector3::new(axis.x, axis.y, axis.z), 1e-9).map(|axis| NormalCone {
+            axis,
+            half_angle: proto.half_angle,
+        })
+    }
+
+    pub fn to_proto(&self) -> proto::NormalCone {
+        let mut proto = proto::NormalCone::new();
+        proto.set_axis(proto::Vector3d::from(&Point3::from(self.axis.into_inner())));
+        proto.set_half_angle(self.half_angle);
+        proto
+    }
+
+    /// Whether all points in 'cube' with a normal in this cone face away from a camera at 'eye',
+    /// so that a viewer that culls back faces would not draw any of them.
+    pub fn faces_away_from(&self, cube: &Cube, eye: &Point3<f64>) -> bool {
+        let to_eye = eye.coords - cube.center();
+        let distance = to_eye.norm();
+        let radius = cube.edge_length() * 3f64.sqrt() / 2.;
+        if distance <= radius {
+            return false;
+        }
+        // The directions from the points of the cube to the eye are within this angle of
+        // 'to_eye'.
+        let spread = self.half_angle + (radius / distance).asin();
+        if spread >= FRAC_PI_2 {
+            return false;
+        }
+        self.axis.dot(&to_eye) / distance < -spread.sin()
+    }
+}
+
+/// The position of the camera of a perspective 'projection_matrix', which maps points into clip
+/// space, or None for an orthographic projection, whose camera is infinitely far away.
+pub fn camera_position(projection_matrix: &Matrix4<f64>) -> Option<Point3<f64>> {
+    // The camera is the point whose clip coordinates x, y and w are all zero.
+    let m = projection_matrix;
+    let a = Matrix3::new(
+        m[(0, 0)],
+        m[(0, 1)],
+        m[(0, 2)],
+        m[(1, 0)],
+        m[(1, 1)],
+        m[(1, 2)],
+        m[(3, 0)],
+        m[(3, 1)],
+        m[(3, 2)],
+    );
+    let b = Vector3::new(-m[(0, 3)], -m[(1, 3)], -m[(3, 3)]);
+    a.try_inverse().map(|a_inv| Point3::from(a_inv * b))
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::{BuildOptions, NodeId};
+    use nalgebra::{Isometry3, Perspective3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_normal_cone() {
+        let normals = [
+            Vector3::new(1., 0., 0.),
+            Vector3::new(1., 0.1, 0.),
+            Vector3::new(1., -0.1, 0.),
+        ];
+        let cone = NormalCone::from_normals(&normals).unwrap();
+        assert!((cone.axis.into_inner() - Vector3::x()).norm() < 1e-12);
+        assert!((cone.half_angle - 0.1f64.atan()).abs() < 1e-12);
+        assert_eq!(NormalCone::from_proto(&cone.to_proto()), Some(cone));
+
+        let opposite = [Vector3::new(1., 0., 0.), Vector3::new(-1., 0.1, 0.)];
+        assert_eq!(NormalCone::from_normals(&opposite), None);
+
+        let cube = Cube::new(Point3::new(0., 0., 0.), 1.);
+        assert!(cone.faces_away_from(&cube, &Point3::new(-20., 0.5, 0.5)));
+        assert!(!cone.faces_away_from(&cube, &Point3::new(20., 0.5, 0.5)));
+        // Close to the cube, some of its points can be seen from the front.
+        assert!(!cone.faces_away_from(&cube, &Point3::new(-1., 0.5, 0.5)));
+    }
+
+    #[test]
+    fn test_camera_position() {
+        let eye = Point3::new(1., 2., 3.);
+        let view = Isometry3::look_at_rh(&eye, &Point3::origin(), &Vector3::z());
+        let projection = Perspective3::new(1.5, 1., 0.1, 100.).to_homogeneous();
+        let position = camera_position(&(projection * view.to_homogeneous())).unwrap();
+        assert!((position - eye).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_build_with_normals() {
+        // Upward facing points at the origin and one at the top of the bounding box.
+        let mut position = vec![Point3::origin(); 16];
+        position.push(Point3::new(-200., -40., 30.));
+        let num_points = position.len();
+        let batch = PointsBatch {
+            position,
+            attributes: vec![
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+                ),
+                (
+                    NORMAL.to_string(),
+                    AttributeData::F64Vec3(vec![Vector3::z(); num_points]),
+                ),
+                (
+                    CURVATURE.to_string(),
+                    AttributeData::F32(vec![0.25; num_points]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let octree = build_octree_of_batch(tmp_dir.path(), 1., batch, &BuildOptions::default());
+        let root_id = NodeId::root();
+        let cone = octree.get_node_meta(&root_id).unwrap().normal_cone.unwrap();
+        assert!((cone.axis.into_inner() - Vector3::z()).norm() < 1e-9);
+        assert!(octree.is_back_facing(&root_id, &Point3::new(-100., -20., -10_000.)));
+        assert!(!octree.is_back_facing(&root_id, &Point3::new(-100., -20., 10_000.)));
+
+        let layout = octree.shading_layout().unwrap();
+        assert_eq!(layout.stride(), 8);
+        let mut num_vertices = 0;
+        for node_id in octree.nodes.keys() {
+            let vertices = octree.get_node_vertices(node_id, &layout).unwrap();
+            for vertex in vertices.chunks_exact(layout.stride()) {
+                assert_eq!(&vertex[..4], &[0, 0, 127, 0]);
+                assert_eq!(&vertex[4..], &0.25f32.to_le_bytes());
+            }
+            num_vertices += vertices.len() / layout.stride();
+        }
+        assert_eq!(num_vertices, num_points);
+    }
+}
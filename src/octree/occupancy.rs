@@ -0,0 +1,164 @@
+//! Coarse occupancy of the bounding cubes of nodes, computed during the build, so that queries
+//! and viewers can skip the empty parts of a node, e.g. the air above the ground in airborne
+//! data, instead of only whole nodes.
+
+use crate::geometry::{Aabb, Cube};
+use nalgebra::{Point3, Vector3};
+
+/// Number of cells along every axis of the bounding cube of a node.
+pub const OCCUPANCY_CELLS_PER_AXIS: usize = 4;
+
+/// Which of the 4 x 4 x 4 cells of the bounding cube of a node contain points of the node or of
+/// any of its descendants. Bit 'x + 4 * y + 16 * z' is set for the cell at '(x, y, z)'.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Occupancy(pub u64);
+
+/// The cell along one axis of 'cube' that contains 'value', clamped to the cube.
+fn cell_coordinate(value: f64, min: f64, cell_edge_length: f64) -> usize {
+    let cell = ((value - min) / cell_edge_length).floor();
+    cell.max(0.).min((OCCUPANCY_CELLS_PER_AXIS - 1) as f64) as usize
+}
+
+fn cell_edge_length(cube: &Cube) -> f64 {
+    cube.edge_length() / OCCUPANCY_CELLS_PER_AXIS as f64
+}
+
+impl Occupancy {
+    /// The occupancy of 'points' in 'cube'.
+    pub fn from_points<'a>(cube: &Cube, points: impl IntoIterator<Item = &'a Point3<f64>>) -> Self {
+        let mut occupancy = Occupancy::default();
+        let min = cube.min();
+        let cell_edge_length = cell_edge_length(cube);
+        for p in points {
+            let index = (0..3).rev().fold(0, |index, axis| {
+                index * OCCUPANCY_CELLS_PER_AXIS
+                    + cell_coordinate(p[axis], min[axis], cell_edge_length)
+            });
+            occupancy.0 |= 1 << index;
+        }
+        occupancy
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Marks all cells of 'cube' that 'aabb' overlaps as occupied.
+    pub fn mark_aabb(&mut self, cube: &Cube, aabb: &Aabb) {
+        let min = cube.min();
+        let cell_edge_length = cell_edge_length(cube);
+        // Boxes that end on a cell boundary do not reach into the next cell.
+        let epsilon = cell_edge_length * 1e-9;
+        let range = |axis: usize| {
+            cell_coordinate(aabb.min()[axis] + epsilon, min[axis], cell_edge_length)
+                ..=cell_coordinate(aabb.max()[axis] - epsilon, min[axis], cell_edge_length)
+        };
+        for z in range(2) {
+            for y in range(1) {
+                for x in range(0) {
+                    self.0 |=
+                        1 << (x + OCCUPANCY_CELLS_PER_AXIS * (y + OCCUPANCY_CELLS_PER_AXIS * z));
+                }
+            }
+        }
+    }
+
+    /// Adds the occupied cells of a child with the bounding cube 'child_cube' to the occupancy of
+    /// its parent with the bounding cube 'cube'.
+    pub fn merge_child(&mut self, cube: &Cube, child: Occupancy, child_cube: &Cube) {
+        for cell in child.occupied_cells(child_cube) {
+            self.mark_aabb(cube, &cell);
+        }
+    }
+
+    /// The boxes of the occupied cells of 'cube'.
+    pub fn occupied_cells(self, cube: &Cube) -> impl Iterator<Item = Aabb> {
+        let min = cube.min();
+        let cell_edge_length = cell_edge_length(cube);
+        (0..OCCUPANCY_CELLS_PER_AXIS.pow(3))
+            .filter(move |index| self.0 & (1 << index) != 0)
+            .map(move |index| {
+                let cell_min = Point3::new(
+                    min.x + (index % OCCUPANCY_CELLS_PER_AXIS) as f64 * cell_edge_length,
+                    min.y
+                        + (index / OCCUPANCY_CELLS_PER_AXIS % OCCUPANCY_CELLS_PER_AXIS) as f64
+                            * cell_edge_length,
+                    min.z
+                        + (index / (OCCUPANCY_CELLS_PER_AXIS * OCCUPANCY_CELLS_PER_AXIS)) as f64
+                            * cell_edge_length,
+                );
+                Aabb::new(cell_min, cell_min + Vector3::repeat(cell_edge_length))
+            })
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::iterator::{PointCloud, PointLocation};
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::{BuildOptions, NodeId, Octree};
+    use crate::{AttributeData, PointsBatch};
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_occupancy() {
+        let cube = Cube::new(Point3::new(0., 0., 0.), 8.);
+        let points = [Point3::new(0.5, 0.5, 0.5), Point3::new(7.5, 3., 0.)];
+        let occupancy = Occupancy::from_points(&cube, points.iter());
+        assert_eq!(occupancy, Occupancy((1 << 0) | (1 << (3 + 4))));
+        let cells: Vec<Aabb> = occupancy.occupied_cells(&cube).collect();
+        assert_eq!(2, cells.len());
+        assert_eq!(&Point3::new(6., 2., 0.), cells[1].min());
+
+        // The first child covers the cells with coordinates 0 and 1 of the parent.
+        let child_cube = Cube::new(Point3::new(0., 0., 0.), 4.);
+        let child = Occupancy::from_points(&child_cube, [Point3::new(3.5, 0.5, 0.5)].iter());
+        let mut parent = Occupancy::default();
+        parent.merge_child(&cube, child, &child_cube);
+        assert_eq!(Occupancy(1 << 1), parent);
+    }
+
+    #[test]
+    fn test_queries_skip_empty_cells() {
+        // The points are in two opposite corners of the root cube.
+        let build = |directory: &Path, occupancy: bool| -> Octree {
+            let mut position = vec![Point3::origin(); 16];
+            position.push(Point3::new(-200., -40., 30.));
+            let batch = PointsBatch {
+                attributes: vec![(
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+                )]
+                .into_iter()
+                .collect(),
+                position,
+            };
+            let options = BuildOptions {
+                occupancy,
+                ..Default::default()
+            };
+            build_octree_of_batch(directory, 1., batch, &options)
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let octree = build(tmp_dir.path(), true);
+        let root_occupancy = octree.nodes[&NodeId::root()].occupancy.unwrap();
+        assert_eq!(root_occupancy.0.count_ones(), 2);
+
+        let empty = PointLocation::Aabb(Aabb::new(
+            Point3::new(-100., 100., 100.),
+            Point3::new(-99., 101., 101.),
+        ));
+        assert!(octree.nodes_in_location(&empty).is_empty());
+        let without_occupancy_dir = TempDir::new("without_occupancy").unwrap();
+        let without_occupancy = build(without_occupancy_dir.path(), false);
+        assert!(!without_occupancy.nodes_in_location(&empty).is_empty());
+
+        let origin = PointLocation::Aabb(Aabb::new(
+            Point3::new(-1., -1., -1.),
+            Point3::new(1., 1., 1.),
+        ));
+        assert!(octree.nodes_in_location(&origin).contains(&NodeId::root()));
+    }
+}
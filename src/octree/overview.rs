@@ -0,0 +1,265 @@
+//! Renders top-down overview images of an octree from the meta data of its nodes, without reading
+//! any points. The number of points and the bounding cube of every node are enough for a density
+//! map, and the cubes of the finest nodes approximate the elevation, so overviews of large
+//! catalogs take moments.
+
+use crate::color::heat_color;
+use crate::errors::*;
+use crate::geometry::Cube;
+use crate::octree::Octree;
+use image::RgbaImage;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What the color of a pixel shows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverviewKind {
+    /// The number of points per area, on a logarithmic scale.
+    Density,
+    /// The height of the highest of the finest nodes above the pixel.
+    Elevation,
+}
+
+impl FromStr for OverviewKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "density" => Ok(OverviewKind::Density),
+            "elevation" => Ok(OverviewKind::Elevation),
+            _ => Err(format!("Unknown overview kind '{}'.", s)),
+        }
+    }
+}
+
+/// A top-down image of the x-y extent of an octree. Row 0 is at the maximum y.
+pub struct Overview {
+    pub image: RgbaImage,
+    min_x: f64,
+    max_y: f64,
+    pixel_size: f64,
+}
+
+impl Overview {
+    /// The edge length of a pixel in the units of the octree.
+    pub fn pixel_size(&self) -> f64 {
+        self.pixel_size
+    }
+
+    /// The x coordinate of the left edge of the image.
+    pub fn min_x(&self) -> f64 {
+        self.min_x
+    }
+
+    /// The y coordinate of the top edge of the image.
+    pub fn max_y(&self) -> f64 {
+        self.max_y
+    }
+
+    /// The contents of an ESRI world file, which places the image in the coordinates of the
+    /// octree. It refers to the center of the upper left pixel.
+    pub fn world_file(&self) -> String {
+        format!(
+            "{}\n0\n0\n{}\n{}\n{}\n",
+            self.pixel_size,
+            -self.pixel_size,
+            self.min_x + 0.5 * self.pixel_size,
+            self.max_y - 0.5 * self.pixel_size
+        )
+    }
+
+    /// Writes the image as PNG to 'path' and the world file next to it, with the extension
+    /// 'pgw'. Returns the path of the world file.
+    pub fn write_png(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = path.as_ref();
+        self.image
+            .save_with_format(path, image::ImageFormat::Png)
+            .with_context(|| format!("Could not write {}.", path.display()))?;
+        let world_file_path = path.with_extension("pgw");
+        fs::write(&world_file_path, self.world_file())
+            .with_context(|| format!("Could not write {}.", world_file_path.display()))?;
+        Ok(world_file_path)
+    }
+}
+
+/// Renders an overview of 'octree' that is 'size' pixels along the longer edge of its x-y extent.
+pub fn render_overview(octree: &Octree, size: u32, kind: OverviewKind) -> Result<Overview> {
+    render_overview_with_min_detail(octree, size, kind, 0.)
+}
+
+/// Like 'render_overview', but shows no details smaller than 'min_detail' in the units of the
+/// octree, e.g. for octrees that must not be shared at full precision. Pixels are at least that
+/// large, and nodes with smaller cubes count as their first ancestor that is not.
+pub fn render_overview_with_min_detail(
+    octree: &Octree,
+    size: u32,
+    kind: OverviewKind,
+    min_detail: f64,
+) -> Result<Overview> {
+    if size == 0 {
+        return Err(Error::InvalidInput(
+            "The size must be positive.".to_string(),
+        ));
+    }
+    let bounding_box = &octree.meta.bounding_box;
+    let diag = bounding_box.diag();
+    let extent = diag.x.max(diag.y);
+    let pixel_size = if extent > 0. {
+        extent / f64::from(size)
+    } else {
+        1.
+    }
+    .max(min_detail);
+    let width = ((diag.x / pixel_size).ceil() as usize).max(1);
+    let height = ((diag.y / pixel_size).ceil() as usize).max(1);
+    let min_x = bounding_box.min().x;
+    let max_y = bounding_box.max().y;
+
+    // Points per area for density, or the level and height of the finest node for elevation.
+    let mut density = vec![0.; width * height];
+    let mut elevation: Vec<Option<(u8, f64)>> = vec![None; width * height];
+    let root_cube = Cube::bounding(bounding_box);
+    for (node_id, node_meta) in &octree.nodes {
+        if node_meta.num_points == 0 {
+            continue;
+        }
+        let mut node_id = *node_id;
+        let mut cube = node_id.find_bounding_cube(&root_cube);
+        while cube.edge_length() < min_detail {
+            match node_id.parent_id() {
+                Some(parent_id) => node_id = parent_id,
+                None => break,
+            }
+            cube = node_id.find_bounding_cube(&root_cube);
+        }
+        let (min, max) = (cube.min(), cube.max());
+        let points_per_area = node_meta.num_points as f64 / (cube.edge_length().powi(2));
+        // The points are inside the bounding box, which is smaller than the cubes of the nodes.
+        let z = cube
+            .center()
+            .z
+            .max(bounding_box.min().z)
+            .min(bounding_box.max().z);
+        let columns = ((min.x - min_x) / pixel_size).floor().max(0.) as usize
+            ..(((max.x - min_x) / pixel_size).ceil().max(0.) as usize).min(width);
+        let rows = ((max_y - max.y) / pixel_size).floor().max(0.) as usize
+            ..(((max_y - min.y) / pixel_size).ceil().max(0.) as usize).min(height);
+        for row in rows {
+            let pixel_max_y = max_y - row as f64 * pixel_size;
+            let overlap_y = pixel_max_y.min(max.y) - (pixel_max_y - pixel_size).max(min.y);
+            for column in columns.clone() {
+                let pixel_min_x = min_x + column as f64 * pixel_size;
+                let overlap_x = (pixel_min_x + pixel_size).min(max.x) - pixel_min_x.max(min.x);
+                let index = row * width + column;
+                // Nodes smaller than a pixel add all of their points to it.
+                density[index] +=
+                    points_per_area * (overlap_x * overlap_y).max(0.) / pixel_size.powi(2);
+                let level = node_id.level();
+                elevation[index] = match elevation[index] {
+                    Some((l, h)) if l > level || (l == level && h >= z) => Some((l, h)),
+                    _ => Some((level, z)),
+                };
+            }
+        }
+    }
+
+    let mut image = RgbaImage::new(width as u32, height as u32);
+    match kind {
+        OverviewKind::Density => {
+            let max_density = density.iter().cloned().fold(0., f64::max);
+            for (index, value) in density.iter().enumerate() {
+                if *value > 0. {
+                    let normalized = (1. + value).ln() / (1. + max_density).ln();
+                    image.put_pixel(
+                        (index % width) as u32,
+                        (index / width) as u32,
+                        heat_color(normalized as f32).into(),
+                    );
+                }
+            }
+        }
+        OverviewKind::Elevation => {
+            let min_z = bounding_box.min().z;
+            let range = diag.z.max(std::f64::EPSILON);
+            for (index, value) in elevation.iter().enumerate() {
+                if let Some((_, z)) = value {
+                    image.put_pixel(
+                        (index % width) as u32,
+                        (index / width) as u32,
+                        heat_color(((z - min_z) / range) as f32).into(),
+                    );
+                }
+            }
+        }
+    }
+    Ok(Overview {
+        image,
+        min_x,
+        max_y,
+        pixel_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_heat_color() {
+        assert_eq!(heat_color(0.).to_f32().red, 0.);
+        let white = heat_color(1.);
+        assert_eq!((white.red, white.green, white.blue), (255, 255, 255));
+        let red = heat_color(1. / 3.);
+        assert_eq!((red.red, red.green, red.blue), (255, 0, 0));
+    }
+
+    #[test]
+    fn test_world_file() {
+        let overview = Overview {
+            image: RgbaImage::new(1, 1),
+            min_x: 100.,
+            max_y: 50.,
+            pixel_size: 2.,
+        };
+        assert_eq!(overview.world_file(), "2\n0\n0\n-2\n101\n49\n");
+    }
+
+    #[test]
+    fn test_render_overview() {
+        // A cluster at the origin, which is the corner with the maximum x and y, and a single
+        // point at the opposite corner.
+        let mut position = vec![Point3::origin(); 100];
+        position.push(Point3::new(-200., -40., 30.));
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let octree = build_octree_of_batch(tmp_dir.path(), 1., batch, &BuildOptions::default());
+        for kind in &[OverviewKind::Density, OverviewKind::Elevation] {
+            let overview = render_overview(&octree, 100, *kind).unwrap();
+            assert_eq!(overview.image.dimensions(), (100, 20));
+            assert_eq!(overview.pixel_size(), 2.);
+            assert_eq!(overview.image.get_pixel(99, 0)[3], 255);
+        }
+        let density = render_overview(&octree, 100, OverviewKind::Density).unwrap();
+        // The densest pixel is white.
+        assert_eq!(density.image.get_pixel(99, 0).0, [255, 255, 255, 255]);
+
+        let coarse =
+            render_overview_with_min_detail(&octree, 100, OverviewKind::Density, 10.).unwrap();
+        assert_eq!(coarse.image.dimensions(), (20, 4));
+        assert_eq!(coarse.pixel_size(), 10.);
+    }
+}
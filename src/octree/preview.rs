@@ -0,0 +1,130 @@
+//! A small preview of a whole octree in a single PLY file, so that catalogs and quick checks can
+//! look at the data with any point cloud tool, without streaming the octree. The coarse levels of
+//! an octree already hold a random subsample of their subtrees, so the preview takes them from the
+//! root down and thins out the first level that does not fit anymore.
+
+use crate::color::ColorSpace;
+use crate::errors::*;
+use crate::iterator::PointCloud;
+use crate::octree::{NodeId, Octree};
+use crate::read_write::{Encoding, NodeWriter, OpenMode, PlyNodeWriter};
+use crate::NUM_POINTS_PER_BATCH;
+use nalgebra::Vector3;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The name of the preview in the octree directory, see 'BuildOptions::preview_points'.
+pub const PREVIEW_FILENAME: &str = "preview.ply";
+
+/// Writes at most 'max_points' points of 'octree' with their sRGB colors and, if it has them,
+/// intensities to the binary PLY file at 'path'. Returns the number of points written.
+pub fn write_preview(octree: &Octree, path: impl AsRef<Path>, max_points: usize) -> Result<usize> {
+    let mut levels: BTreeMap<u8, Vec<NodeId>> = BTreeMap::new();
+    let mut points_per_level: BTreeMap<u8, usize> = BTreeMap::new();
+    for (node_id, node_meta) in &octree.nodes {
+        if node_meta.num_points > 0 {
+            levels.entry(node_id.level()).or_default().push(*node_id);
+            *points_per_level.entry(node_id.level()).or_insert(0) += node_meta.num_points as usize;
+        }
+    }
+
+    // Octrees only have intensities if their inputs had them, although their meta data always
+    // lists the attribute.
+    let has_intensity = match levels.values().flatten().next() {
+        Some(node_id) => octree.get_node_schema(node_id)?.map_or(true, |schema| {
+            schema.missing_attributes(&["intensity"]).is_empty()
+        }),
+        None => false,
+    };
+    let attributes: &[&str] = if has_intensity {
+        &["color", "intensity"]
+    } else {
+        &["color"]
+    };
+
+    let mut writer = PlyNodeWriter::new(path.as_ref(), Encoding::Plain, OpenMode::Truncate);
+    // The same octree always gets the same preview.
+    let mut rng = ChaCha8Rng::seed_from_u64(octree.meta.subsampling_seed);
+    let mut num_written = 0;
+    for (level, mut node_ids) in levels {
+        let remaining = max_points - num_written;
+        if remaining == 0 {
+            break;
+        }
+        // The fraction of the points of this level that still fits.
+        let fraction = (remaining as f64 / points_per_level[&level] as f64).min(1.);
+        node_ids.sort();
+        for node_id in node_ids {
+            for mut batch in octree.points_in_node(attributes, node_id, NUM_POINTS_PER_BATCH)? {
+                if fraction < 1. {
+                    let keep: Vec<bool> = (0..batch.position.len())
+                        .map(|_| rng.gen::<f64>() < fraction)
+                        .collect();
+                    batch.retain(&keep);
+                }
+                let num_points = batch.position.len().min(max_points - num_written);
+                if num_points < batch.position.len() {
+                    batch.split_off(num_points);
+                }
+                if let Ok(color) = batch.get_attribute_vec_mut::<Vector3<u8>>("color") {
+                    octree.color_space().convert_u8(
+                        ColorSpace::Srgb,
+                        color.iter_mut().flat_map(|c| c.iter_mut()),
+                    );
+                }
+                writer.write(&batch)?;
+                num_written += num_points;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(num_written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::read_write::PlyIterator;
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::Point3;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_preview() {
+        // The points have no intensities. The root holds an eighth of the 100 points at the
+        // origin, 13, and the far point.
+        let mut position = vec![Point3::origin(); 100];
+        position.push(Point3::new(-200., -40., 30.));
+        let num_points = position.len();
+        let num_preview_points = |max_points| {
+            let batch = PointsBatch {
+                position: position.clone(),
+                attributes: vec![(
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+                )]
+                .into_iter()
+                .collect(),
+            };
+            let tmp_dir = TempDir::new("octree").unwrap();
+            let options = BuildOptions {
+                preview_points: Some(max_points),
+                ..Default::default()
+            };
+            build_octree_of_batch(tmp_dir.path(), 1., batch, &options);
+            PlyIterator::from_file(tmp_dir.path().join(PREVIEW_FILENAME), NUM_POINTS_PER_BATCH)
+                .unwrap()
+                .map(|batch| batch.position.len())
+                .sum::<usize>()
+        };
+        // The root fills the preview, so nothing of the next level is taken.
+        assert_eq!(num_preview_points(14), 14);
+        assert!(num_preview_points(50) <= 50);
+        // Small octrees fit into the preview as a whole.
+        assert_eq!(num_preview_points(1000), num_points);
+    }
+}
@@ -0,0 +1,420 @@
+//! Rasterizes the heights of the points of an octree region into a grid and writes it as a
+//! GeoTIFF, e.g. a digital surface model from the highest points per cell.
+
+use crate::errors::*;
+use crate::geometry::{Aabb, Cube};
+use crate::iterator::{PointCloud, PointLocation, PointQuery};
+use crate::octree::Octree;
+use crate::NUM_POINTS_PER_BATCH;
+use byteorder::{LittleEndian, WriteBytesExt};
+use nalgebra::{Point2, Point3};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Roughly how many points a node holds along its edge for surface-like scans, which determines
+/// the level of detail that is sufficient for a cell size.
+const POINTS_ALONG_NODE_EDGE: f64 = 256.;
+
+/// Which height of the points in a cell becomes the value of the cell.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RasterStatistic {
+    /// The lowest point, which approximates the terrain if the ground is visible.
+    Min,
+    /// The highest point, i.e. a digital surface model.
+    Max,
+    Mean,
+}
+
+impl FromStr for RasterStatistic {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "min" => Ok(RasterStatistic::Min),
+            "max" => Ok(RasterStatistic::Max),
+            "mean" => Ok(RasterStatistic::Mean),
+            _ => Err(format!("Unknown statistic '{}'.", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RasterOptions {
+    /// Edge length of the square cells.
+    pub cell_size: f64,
+    pub statistic: RasterStatistic,
+    /// Only rasterize the points inside this box. Defaults to the whole octree.
+    pub bounding_box: Option<Aabb>,
+    /// Only read nodes up to this level of detail. Defaults to the coarsest level whose points
+    /// are dense enough for the cell size.
+    pub max_lod: Option<u8>,
+}
+
+impl Default for RasterOptions {
+    fn default() -> Self {
+        Self {
+            cell_size: 1.,
+            statistic: RasterStatistic::Max,
+            bounding_box: None,
+            max_lod: None,
+        }
+    }
+}
+
+/// A grid of heights in the x-y plane. Row 0 is at the maximum y, as in images.
+#[derive(Clone, Debug)]
+pub struct Raster {
+    min_x: f64,
+    max_y: f64,
+    cell_size: f64,
+    width: usize,
+    height: usize,
+    statistic: RasterStatistic,
+    values: Vec<f64>,
+    counts: Vec<u32>,
+}
+
+impl Raster {
+    /// An empty raster covering the x-y extent of 'bounding_box'.
+    pub fn new(bounding_box: &Aabb, cell_size: f64, statistic: RasterStatistic) -> Self {
+        let diag = bounding_box.diag();
+        let width = ((diag.x / cell_size).ceil() as usize).max(1);
+        let height = ((diag.y / cell_size).ceil() as usize).max(1);
+        let initial = match statistic {
+            RasterStatistic::Min => std::f64::INFINITY,
+            RasterStatistic::Max => std::f64::NEG_INFINITY,
+            RasterStatistic::Mean => 0.,
+        };
+        Self {
+            min_x: bounding_box.min().x,
+            max_y: bounding_box.max().y,
+            cell_size,
+            width,
+            height,
+            statistic,
+            values: vec![initial; width * height],
+            counts: vec![0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The x-y position of the center of a cell.
+    pub fn cell_center(&self, column: usize, row: usize) -> Point2<f64> {
+        Point2::new(
+            self.min_x + (column as f64 + 0.5) * self.cell_size,
+            self.max_y - (row as f64 + 0.5) * self.cell_size,
+        )
+    }
+
+    /// Adds the height of 'point' to its cell. Points outside the raster are ignored.
+    pub fn add_point(&mut self, point: &Point3<f64>) {
+        let column = ((point.x - self.min_x) / self.cell_size).floor();
+        let row = ((self.max_y - point.y) / self.cell_size).floor();
+        // Points on the border of the bounding box belong to the last cell.
+        let column = column.min(self.width as f64 - 1.);
+        let row = row.min(self.height as f64 - 1.);
+        if column < 0. || row < 0. {
+            return;
+        }
+        let index = row as usize * self.width + column as usize;
+        let value = &mut self.values[index];
+        match self.statistic {
+            RasterStatistic::Min => *value = value.min(point.z),
+            RasterStatistic::Max => *value = value.max(point.z),
+            RasterStatistic::Mean => *value += point.z,
+        }
+        self.counts[index] += 1;
+    }
+
+    /// The value of a cell, or None if no point fell into it.
+    pub fn value(&self, column: usize, row: usize) -> Option<f64> {
+        let index = row * self.width + column;
+        match self.counts[index] {
+            0 => None,
+            count if self.statistic == RasterStatistic::Mean => {
+                Some(self.values[index] / f64::from(count))
+            }
+            _ => Some(self.values[index]),
+        }
+    }
+
+    /// Writes the raster as a single band float32 GeoTIFF. Empty cells are NaN, which is declared
+    /// as the no data value. The coordinates are only tagged with a coordinate system if 'epsg' is
+    /// given, since the octree does not know its own.
+    pub fn write_geotiff(&self, path: impl AsRef<Path>, epsg: Option<u16>) -> Result<()> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Could not create {}.", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write_geotiff_to(&mut writer, epsg)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write_geotiff_to(&self, writer: &mut impl Write, epsg: Option<u16>) -> Result<()> {
+        // GeoKeyDirectory: version 1.1.0, then (key, location, count, value) per key.
+        let mut geo_keys: Vec<u16> = vec![
+            1, 1, 0, 0, // Header, the number of keys is set below.
+            1024, 0, 1, 1, // GTModelTypeGeoKey: projected.
+            1025, 0, 1, 1, // GTRasterTypeGeoKey: pixel is area.
+        ];
+        if let Some(epsg) = epsg {
+            // ProjectedCSTypeGeoKey.
+            geo_keys.extend_from_slice(&[3072, 0, 1, epsg]);
+        }
+        geo_keys[3] = (geo_keys.len() / 4 - 1) as u16;
+
+        let num_pixels = self.width * self.height;
+        let mut ifd = Ifd::default();
+        ifd.long(256, self.width as u32);
+        ifd.long(257, self.height as u32);
+        ifd.short(258, 32);
+        // No compression.
+        ifd.short(259, 1);
+        // Black is zero.
+        ifd.short(262, 1);
+        // The image is a single strip, its offset is known once the size of the IFD is.
+        ifd.long(273, 0);
+        ifd.short(277, 1);
+        ifd.long(278, self.height as u32);
+        ifd.long(279, (num_pixels * 4) as u32);
+        // IEEE floating point samples.
+        ifd.short(339, 3);
+        // ModelPixelScaleTag.
+        ifd.doubles(33550, &[self.cell_size, self.cell_size, 0.]);
+        // ModelTiepointTag, which maps the upper left corner of the raster.
+        ifd.doubles(33922, &[0., 0., 0., self.min_x, self.max_y, 0.]);
+        ifd.shorts(34735, &geo_keys);
+        // GDAL_NODATA.
+        ifd.ascii(42113, "nan");
+
+        let image_offset = ifd.set_image_offset(273);
+        writer.write_all(b"II")?;
+        writer.write_u16::<LittleEndian>(42)?;
+        writer.write_u32::<LittleEndian>(TIFF_HEADER_LEN)?;
+        ifd.write(writer)?;
+        debug_assert_eq!(image_offset, TIFF_HEADER_LEN + ifd.len());
+        for row in 0..self.height {
+            for column in 0..self.width {
+                let value = self.value(column, row).unwrap_or(std::f64::NAN);
+                writer.write_f32::<LittleEndian>(value as f32)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+const TIFF_HEADER_LEN: u32 = 8;
+
+/// A TIFF image file directory with its values. Values that do not fit into an entry are stored
+/// right after the directory.
+#[derive(Default)]
+struct Ifd {
+    // Tag, field type, count and the little endian value.
+    entries: Vec<(u16, u16, u32, Vec<u8>)>,
+}
+
+impl Ifd {
+    fn push(&mut self, tag: u16, field_type: u16, count: usize, value: Vec<u8>) {
+        self.entries.push((tag, field_type, count as u32, value));
+    }
+
+    fn short(&mut self, tag: u16, value: u16) {
+        self.shorts(tag, &[value]);
+    }
+
+    fn shorts(&mut self, tag: u16, values: &[u16]) {
+        let bytes = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        self.push(tag, 3, values.len(), bytes);
+    }
+
+    fn long(&mut self, tag: u16, value: u32) {
+        self.push(tag, 4, 1, value.to_le_bytes().to_vec());
+    }
+
+    fn doubles(&mut self, tag: u16, values: &[f64]) {
+        let bytes = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        self.push(tag, 12, values.len(), bytes);
+    }
+
+    fn ascii(&mut self, tag: u16, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.push(tag, 2, bytes.len(), bytes);
+    }
+
+    fn directory_len(&self) -> u32 {
+        2 + 12 * self.entries.len() as u32 + 4
+    }
+
+    /// Number of bytes of the directory and the values stored after it.
+    fn len(&self) -> u32 {
+        let external: usize = self
+            .entries
+            .iter()
+            .filter(|(_, _, _, value)| value.len() > 4)
+            .map(|(_, _, _, value)| value.len() + value.len() % 2)
+            .sum();
+        self.directory_len() + external as u32
+    }
+
+    /// Sets the value of 'tag' to the offset right after the directory and returns the offset.
+    fn set_image_offset(&mut self, tag: u16) -> u32 {
+        let offset = TIFF_HEADER_LEN + self.len();
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.0 == tag)
+            .unwrap();
+        entry.3 = offset.to_le_bytes().to_vec();
+        offset
+    }
+
+    fn write(&mut self, writer: &mut impl Write) -> Result<()> {
+        // Readers require the entries to be sorted by tag.
+        self.entries.sort_by_key(|entry| entry.0);
+        writer.write_u16::<LittleEndian>(self.entries.len() as u16)?;
+        let mut external_offset = TIFF_HEADER_LEN + self.directory_len();
+        for (tag, field_type, count, value) in &self.entries {
+            writer.write_u16::<LittleEndian>(*tag)?;
+            writer.write_u16::<LittleEndian>(*field_type)?;
+            writer.write_u32::<LittleEndian>(*count)?;
+            if value.len() > 4 {
+                writer.write_u32::<LittleEndian>(external_offset)?;
+                external_offset += (value.len() + value.len() % 2) as u32;
+            } else {
+                let mut inline = [0; 4];
+                inline[..value.len()].copy_from_slice(value);
+                writer.write_all(&inline)?;
+            }
+        }
+        // There is no next directory.
+        writer.write_u32::<LittleEndian>(0)?;
+        for (_, _, _, value) in &self.entries {
+            if value.len() > 4 {
+                writer.write_all(value)?;
+                if value.len() % 2 == 1 {
+                    // Values start on word boundaries.
+                    writer.write_u8(0)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rasterizes the points of 'octree' into a grid of heights. Only nodes up to the level of detail
+/// needed for the cell size are read, since finer levels would not change a coarse raster much.
+pub fn rasterize_octree(octree: &Octree, options: &RasterOptions) -> Result<Raster> {
+    if options.cell_size <= 0. || options.cell_size.is_nan() {
+        return Err(Error::InvalidInput(
+            "The cell size must be positive.".to_string(),
+        ));
+    }
+    let bounding_box = options
+        .bounding_box
+        .clone()
+        .unwrap_or_else(|| octree.meta.bounding_box.clone());
+    let max_lod = options.max_lod.unwrap_or_else(|| {
+        let root_edge_length = Cube::bounding(&octree.meta.bounding_box).edge_length();
+        let num_levels = (root_edge_length / (POINTS_ALONG_NODE_EDGE * options.cell_size))
+            .log2()
+            .ceil();
+        num_levels.max(0.).min(f64::from(u8::max_value())) as u8
+    });
+    let mut raster = Raster::new(&bounding_box, options.cell_size, options.statistic);
+    let query = PointQuery {
+        attributes: Vec::new(),
+        location: PointLocation::Aabb(bounding_box),
+        filter_intervals: Default::default(),
+    };
+    for node_id in octree.nodes_in_location(&query.location) {
+        if node_id.level() > max_lod {
+            continue;
+        }
+        octree.stream_points_for_query_in_node(&query, node_id, NUM_POINTS_PER_BATCH, |batch| {
+            for position in &batch.position {
+                raster.add_point(position);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(raster)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ByteOrder;
+
+    fn test_raster(statistic: RasterStatistic) -> Raster {
+        let bounding_box = Aabb::new(Point3::new(0., 0., 0.), Point3::new(2., 1., 10.));
+        let mut raster = Raster::new(&bounding_box, 1., statistic);
+        raster.add_point(&Point3::new(0.5, 0.5, 1.));
+        raster.add_point(&Point3::new(0.2, 0.7, 3.));
+        // On the border, so in the last column.
+        raster.add_point(&Point3::new(2., 0.5, 5.));
+        // Outside.
+        raster.add_point(&Point3::new(-1., 0.5, 7.));
+        raster
+    }
+
+    #[test]
+    fn test_statistics() {
+        let raster = test_raster(RasterStatistic::Min);
+        assert_eq!((raster.width(), raster.height()), (2, 1));
+        assert_eq!(raster.value(0, 0), Some(1.));
+        assert_eq!(raster.value(1, 0), Some(5.));
+        assert_eq!(test_raster(RasterStatistic::Max).value(0, 0), Some(3.));
+        assert_eq!(test_raster(RasterStatistic::Mean).value(0, 0), Some(2.));
+
+        let mut raster = Raster::new(
+            &Aabb::new(Point3::new(0., 0., 0.), Point3::new(2., 2., 1.)),
+            1.,
+            RasterStatistic::Max,
+        );
+        raster.add_point(&Point3::new(0.5, 1.5, 1.));
+        // Row 0 is at the maximum y.
+        assert_eq!(raster.value(0, 0), Some(1.));
+        assert_eq!(raster.value(0, 1), None);
+    }
+
+    #[test]
+    fn test_geotiff() {
+        let raster = test_raster(RasterStatistic::Max);
+        let mut bytes = Vec::new();
+        raster.write_geotiff_to(&mut bytes, Some(25832)).unwrap();
+        assert_eq!(&bytes[..4], b"II\x2a\x00");
+        let ifd = LittleEndian::read_u32(&bytes[4..]) as usize;
+        let num_entries = LittleEndian::read_u16(&bytes[ifd..]) as usize;
+        let entry = |tag: u16| {
+            (0..num_entries)
+                .map(|i| ifd + 2 + 12 * i)
+                .find(|offset| LittleEndian::read_u16(&bytes[*offset..]) == tag)
+                .unwrap()
+        };
+        assert_eq!(LittleEndian::read_u32(&bytes[entry(256) + 8..]), 2);
+        let image = LittleEndian::read_u32(&bytes[entry(273) + 8..]) as usize;
+        assert_eq!(bytes.len(), image + 8);
+        let mut values = [0.; 2];
+        LittleEndian::read_f32_into(&bytes[image..], &mut values);
+        assert_eq!(values, [3., 5.]);
+        let tiepoint = LittleEndian::read_u32(&bytes[entry(33922) + 8..]) as usize;
+        assert_eq!(LittleEndian::read_f64(&bytes[tiepoint + 32..]), 1.);
+    }
+}
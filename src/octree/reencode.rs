@@ -0,0 +1,363 @@
+//! Rewrites the nodes of an existing octree, e.g. with a different position encoding, without
+//! having to build it again from the original data.
+
+use crate::attributes::ValueRange;
+use crate::classify::CLASSIFICATION;
+use crate::data_provider::OnDiskDataProvider;
+use crate::errors::*;
+use crate::intensity::NORMALIZED_INTENSITY;
+use crate::iterator::PointCloud;
+use crate::octree::{
+    compute_checksums, to_meta_proto, to_node_proto, write_meta, NodeSchema, Octree, OctreeLock,
+    FRAME_INDEX, SOURCE_ID,
+};
+use crate::read_write::{
+    AttributeCompression, Encoding, NodeWriter, OpenMode, PositionEncoding, RawNodeWriter,
+};
+use crate::utils::create_progress_bar;
+use crate::{AttributeDataType, NUM_POINTS_PER_BATCH};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// What to change when re-encoding. The default keeps everything as it is.
+#[derive(Clone, Debug, Default)]
+pub struct ReencodeOptions {
+    /// Use this encoding for the positions of all nodes.
+    pub position_encoding: Option<PositionEncoding>,
+    /// Choose the position encoding of every node so that it achieves this resolution, like
+    /// building with this resolution would. Ignored if `position_encoding` is set.
+    pub resolution: Option<f64>,
+    /// Only keep these attributes. 'color' is required by the on-disk format.
+    pub attributes: Option<Vec<String>>,
+    /// How to store the attribute columns of all nodes. By default, compressed nodes stay
+    /// compressed, choosing the codecs anew.
+    pub attribute_compression: Option<AttributeCompression>,
+    /// Stores every attribute whose values are all small integers in the smallest integer data
+    /// type that holds them, e.g. classes that a vendor exported as 'F64' in 'U8'. The former
+    /// data types are recorded in 'OctreeMeta::narrowed_attributes'. Attributes whose data type
+    /// this crate relies on, such as 'intensity', are kept.
+    pub narrow_attributes: bool,
+}
+
+/// Attributes that are read with a fixed data type and therefore never narrowed.
+const FIXED_DATA_TYPES: [&str; 5] = [
+    "intensity",
+    NORMALIZED_INTENSITY,
+    CLASSIFICATION,
+    SOURCE_ID,
+    FRAME_INDEX,
+];
+
+/// Reads all nodes and returns the attributes of 'attributes' that can be narrowed, with their
+/// narrower data types.
+fn narrowed_data_types(
+    octree: &Octree,
+    attributes: &[&str],
+) -> Result<HashMap<String, AttributeDataType>> {
+    let attributes: Vec<&str> = attributes
+        .iter()
+        .copied()
+        .filter(|attribute| !FIXED_DATA_TYPES.contains(attribute))
+        .filter(|attribute| {
+            octree
+                .meta
+                .attribute_data_types
+                .get(*attribute)
+                .map_or(false, |data_type| data_type.dim() == 1)
+        })
+        .collect();
+    if attributes.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut ranges: HashMap<String, ValueRange> = HashMap::new();
+    let mut progress_bar = create_progress_bar(octree.nodes.len(), "Analyzing attributes");
+    for (node_id, node_meta) in &octree.nodes {
+        if node_meta.num_points > 0 {
+            for batch in octree.points_in_node(&attributes, *node_id, NUM_POINTS_PER_BATCH)? {
+                for (name, data) in &batch.attributes {
+                    if let Some(range) = ValueRange::of(data) {
+                        ranges
+                            .entry(name.clone())
+                            .and_modify(|other| *other = other.merge(range))
+                            .or_insert(range);
+                    }
+                }
+            }
+        }
+        progress_bar.inc();
+    }
+    progress_bar.finish();
+    Ok(ranges
+        .into_iter()
+        .filter_map(|(name, range)| {
+            range
+                .narrower_data_type(octree.meta.attribute_data_types[&name])
+                .map(|data_type| (name, data_type))
+        })
+        .collect())
+}
+
+/// Writes all nodes of 'octree' into 'output_directory', which must be different from the
+/// directory of the octree, applying the changes in 'options'.
+pub fn reencode_octree(
+    octree: &Octree,
+    output_directory: impl AsRef<Path>,
+    options: &ReencodeOptions,
+) -> Result<()> {
+    let attributes: Vec<&str> = match &options.attributes {
+        Some(attributes) => attributes.iter().map(String::as_str).collect(),
+        None => {
+            let mut attributes: Vec<&str> = octree
+                .meta
+                .attribute_data_types
+                .keys()
+                .map(String::as_str)
+                .collect();
+            attributes.sort();
+            attributes
+        }
+    };
+    if !attributes.contains(&"color") {
+        return Err(Error::InvalidInput(
+            "Octree nodes must keep the 'color' attribute.".to_string(),
+        ));
+    }
+
+    let mut meta = octree.meta.clone();
+    meta.attribute_data_types
+        .retain(|name, _| attributes.contains(&name.as_str()));
+    meta.narrowed_attributes
+        .retain(|name, _| attributes.contains(&name.as_str()));
+    let narrowed = if options.narrow_attributes {
+        narrowed_data_types(octree, &attributes)?
+    } else {
+        HashMap::new()
+    };
+    for (name, data_type) in &narrowed {
+        let original = meta.attribute_data_types.insert(name.clone(), *data_type);
+        // Narrowing twice keeps the data type the octree was built with.
+        meta.narrowed_attributes
+            .entry(name.clone())
+            .or_insert_with(|| original.unwrap());
+    }
+    if options.position_encoding.is_none() {
+        if let Some(resolution) = options.resolution {
+            meta.resolution = resolution;
+        }
+    }
+
+    fs::create_dir_all(output_directory.as_ref())
+        .with_context(|| "Could not create output directory.")?;
+    let _lock = OctreeLock::write(output_directory.as_ref())?;
+    let output_data_provider = OnDiskDataProvider {
+        directory: output_directory.as_ref().to_path_buf(),
+    };
+
+    let mut progress_bar = create_progress_bar(octree.nodes.len(), "Re-encoding nodes");
+    let mut nodes = Vec::with_capacity(octree.nodes.len());
+    for (node_id, node_meta) in &octree.nodes {
+        let position_encoding = match (&options.position_encoding, options.resolution) {
+            (Some(position_encoding), _) => position_encoding.clone(),
+            (None, Some(resolution)) => PositionEncoding::new(&node_meta.bounding_cube, resolution),
+            (None, None) => node_meta.position_encoding.clone(),
+        };
+        let encoding = Encoding::ScaledToCube(
+            node_meta.bounding_cube.min(),
+            node_meta.bounding_cube.edge_length(),
+            position_encoding.clone(),
+        );
+        let attribute_compression = match &options.attribute_compression {
+            Some(attribute_compression) => attribute_compression.clone(),
+            None if node_meta.compressed_attributes => {
+                AttributeCompression::Compressed(HashMap::new())
+            }
+            None => AttributeCompression::Uncompressed,
+        };
+        let compressed_attributes = attribute_compression != AttributeCompression::Uncompressed;
+        let mut writer = RawNodeWriter::new(
+            output_data_provider.stem(&node_id.to_string()),
+            encoding,
+            OpenMode::Truncate,
+        )
+        .with_attribute_compression(attribute_compression);
+        for mut batch in octree.points_in_node(&attributes, *node_id, NUM_POINTS_PER_BATCH)? {
+            for (name, data_type) in &narrowed {
+                let data = batch.attributes.get_mut(name).unwrap();
+                *data = data.cast(*data_type).map_err(Error::SchemaMismatch)?;
+            }
+            writer.write(&batch)?;
+        }
+        writer.finish()?;
+        if node_meta.num_points > 0 {
+            let schema = NodeSchema {
+                num_points: node_meta.num_points,
+                bounding_cube: node_meta.bounding_cube.clone(),
+                position_encoding: position_encoding.clone(),
+                compressed_attributes,
+                attribute_data_types: meta.attribute_data_types.clone(),
+            };
+            schema.write(output_data_provider.stem(&node_id.to_string()))?;
+        }
+        let checksums = if node_meta.num_points > 0 {
+            let files: Vec<&str> = std::iter::once("position")
+                .chain(attributes.iter().copied())
+                .collect();
+            compute_checksums(&output_data_provider, &node_id.to_string(), &files)?
+        } else {
+            HashMap::new()
+        };
+        nodes.push(to_node_proto(
+            node_id,
+            node_meta.num_points,
+            &position_encoding,
+            compressed_attributes,
+            checksums,
+            node_meta.normal_cone,
+            node_meta.occupancy,
+        ));
+        progress_bar.inc();
+    }
+    progress_bar.finish();
+
+    write_meta(output_directory, &to_meta_proto(&meta, nodes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::{rewrite_attributes, BuildOptions, NodeId};
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_reencode_drops_attributes() {
+        let position: Vec<_> = (0..16)
+            .map(|i| Point3::new(f64::from(i), 0., f64::from(i)))
+            .collect();
+        let num_points = position.len();
+        let batch = PointsBatch {
+            position,
+            attributes: vec![
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+                ),
+                (
+                    "intensity".to_string(),
+                    AttributeData::F32(vec![1.; num_points]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let octree = build_octree_of_batch(tmp_dir.path(), 0.001, batch, &BuildOptions::default());
+
+        let reencoded_dir = TempDir::new("reencoded").unwrap();
+        let options = ReencodeOptions {
+            attributes: Some(vec!["color".to_string()]),
+            ..Default::default()
+        };
+        reencode_octree(&octree, reencoded_dir.path(), &options).unwrap();
+        let reencoded = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: reencoded_dir.path().to_path_buf(),
+        }))
+        .unwrap();
+        assert!(reencoded.meta.attribute_data_types.contains_key("color"));
+        assert!(!reencoded
+            .meta
+            .attribute_data_types
+            .contains_key("intensity"));
+        let mut num_read = 0;
+        for node_id in reencoded.nodes.keys() {
+            for batch in reencoded
+                .points_in_node(&["color"], *node_id, NUM_POINTS_PER_BATCH)
+                .unwrap()
+            {
+                num_read += batch.position.len();
+            }
+        }
+        assert_eq!(num_read, num_points);
+
+        let options = ReencodeOptions {
+            attributes: Some(vec!["intensity".to_string()]),
+            ..Default::default()
+        };
+        assert!(reencode_octree(&octree, reencoded_dir.path(), &options).is_err());
+    }
+
+    #[test]
+    fn test_narrow_attributes() {
+        let position: Vec<_> = (0..16)
+            .map(|i| Point3::new(f64::from(i), 0., f64::from(i)))
+            .collect();
+        let num_points = position.len();
+        let batch = PointsBatch {
+            position,
+            attributes: vec![
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+                ),
+                (
+                    "intensity".to_string(),
+                    AttributeData::F32(vec![1.5; num_points]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        build_octree_of_batch(tmp_dir.path(), 0.001, batch, &BuildOptions::default());
+        // Whole numbers that fit into a byte.
+        let heights = |_: NodeId, batch: &mut PointsBatch| -> Result<()> {
+            let heights = batch.position.iter().map(|p| p.z.round() + 40.).collect();
+            batch
+                .attributes
+                .insert("height".to_string(), AttributeData::F64(heights));
+            Ok(())
+        };
+        rewrite_attributes(tmp_dir.path(), heights).unwrap();
+        let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: tmp_dir.path().to_path_buf(),
+        }))
+        .unwrap();
+
+        let narrowed_dir = TempDir::new("narrowed").unwrap();
+        let options = ReencodeOptions {
+            narrow_attributes: true,
+            ..Default::default()
+        };
+        reencode_octree(&octree, narrowed_dir.path(), &options).unwrap();
+        let narrowed = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: narrowed_dir.path().to_path_buf(),
+        }))
+        .unwrap();
+        assert_eq!(
+            narrowed.meta.attribute_data_types.get("height"),
+            Some(&AttributeDataType::U8)
+        );
+        assert_eq!(
+            narrowed.meta.narrowed_attributes.get("height"),
+            Some(&AttributeDataType::F64)
+        );
+        // The intensity is not whole and keeps its data type.
+        assert_eq!(
+            narrowed.meta.attribute_data_types.get("intensity"),
+            Some(&AttributeDataType::F32)
+        );
+        let mut num_read = 0;
+        for batch in narrowed.all_points(&["height"], None) {
+            let batch = batch.unwrap();
+            let heights: &Vec<u8> = batch.get_attribute_vec("height").unwrap();
+            for (position, height) in batch.position.iter().zip(heights) {
+                assert_eq!(f64::from(*height), position.z.round() + 40.);
+            }
+            num_read += batch.position.len();
+        }
+        assert_eq!(num_read, num_points);
+    }
+}
@@ -0,0 +1,319 @@
+//! Changes the attributes of the points of an existing octree in place, e.g. to add the height
+//! above ground or to fix a wrongly scaled intensity, without building it again.
+
+use crate::data_provider::OnDiskDataProvider;
+use crate::errors::*;
+use crate::iterator::PointCloud;
+use crate::octree::{
+    compute_checksums, to_meta_proto, to_node_proto, write_meta, NodeId, NodeSchema, Octree,
+    OctreeLock, Operation, Transaction, KD_INDEX, NODE_SCHEMA,
+};
+use crate::read_write::{AttributeCompression, NodeWriter, OpenMode, RawNodeWriter};
+use crate::utils::create_syncable_progress_bar;
+use crate::{attribute_extension, AttributeDataType, PointsBatch};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Directory inside the octree in which nodes are rewritten before they replace the originals.
+const REWRITE_DIRECTORY: &str = "rewrite.tmp";
+
+/// The attributes and their data types after the rewrite.
+type Schema = BTreeMap<String, AttributeDataType>;
+
+/// Calls 'func' with all points of every node of the octree in 'directory' and writes their
+/// attributes back in place. 'func' may change, add and remove attributes, but not the positions,
+/// and must leave all nodes with the same attributes, which become the attributes of the octree.
+/// 'color' must be kept. The nodes are processed in parallel. Nothing is changed if 'func' fails
+/// for any node. For versioned octrees, the rewrite becomes a new version.
+pub fn rewrite_attributes<F>(directory: impl AsRef<Path>, func: F) -> Result<()>
+where
+    F: Fn(NodeId, &mut PointsBatch) -> Result<()> + Sync,
+{
+    let directory = directory.as_ref();
+    let _lock = OctreeLock::write(directory)?;
+    let mut octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: directory.to_path_buf(),
+    }))?;
+    let transaction = Transaction::begin(directory, Operation::RewriteAttributes)?;
+
+    let rewrite_directory = directory.join(REWRITE_DIRECTORY);
+    fs::create_dir_all(&rewrite_directory)
+        .with_context(|| "Could not create directory for rewriting nodes.")?;
+    let rewrite_data_provider = OnDiskDataProvider {
+        directory: rewrite_directory.clone(),
+    };
+    let data_provider = OnDiskDataProvider {
+        directory: directory.to_path_buf(),
+    };
+    let node_ids: Vec<NodeId> = octree
+        .nodes
+        .iter()
+        .filter(|(_, node_meta)| node_meta.num_points > 0)
+        .map(|(node_id, _)| *node_id)
+        .collect();
+    let progress_bar = create_syncable_progress_bar(node_ids.len(), "Rewriting attributes");
+    let schemas: Result<Vec<(Vec<String>, Schema)>> = node_ids
+        .par_iter()
+        .map(|node_id| {
+            let rewritten = rewrite_node(
+                &octree,
+                &data_provider,
+                &rewrite_data_provider,
+                *node_id,
+                &func,
+            );
+            progress_bar.lock().unwrap().inc();
+            rewritten
+        })
+        .collect();
+    progress_bar.lock().unwrap().finish();
+    let schemas = schemas.and_then(|schemas| {
+        let schema = schemas.first().map(|(_, schema)| schema.clone());
+        if schemas
+            .iter()
+            .any(|(_, other)| Some(other) != schema.as_ref())
+        {
+            return Err(Error::SchemaMismatch(
+                "All nodes must have the same attributes after the rewrite.".to_string(),
+            ));
+        }
+        if schema
+            .as_ref()
+            .map_or(false, |schema| !schema.contains_key("color"))
+        {
+            return Err(Error::InvalidInput(
+                "Octree nodes must keep the 'color' attribute.".to_string(),
+            ));
+        }
+        Ok((schemas, schema))
+    });
+    let (schemas, schema) = match schemas {
+        Ok(schemas) => schemas,
+        Err(err) => {
+            let _ = fs::remove_dir_all(&rewrite_directory);
+            return Err(err);
+        }
+    };
+
+    let new_attributes: Vec<&str> = schema
+        .iter()
+        .flat_map(|schema| schema.keys())
+        .map(String::as_str)
+        .collect();
+    for (node_id, (old_attributes, _)) in node_ids.iter().zip(&schemas) {
+        let stem = data_provider.stem(&node_id.to_string());
+        let rewritten_stem = rewrite_data_provider.stem(&node_id.to_string());
+        let mut attributes: Vec<&str> = old_attributes.iter().map(String::as_str).collect();
+        attributes.extend(&new_attributes);
+        attributes.sort();
+        attributes.dedup();
+        // The positions did not change, so their file is kept.
+        for attribute in attributes {
+            let original = stem.with_extension(attribute_extension(attribute));
+            let rewritten = rewritten_stem.with_extension(attribute_extension(attribute));
+            if original.exists() {
+                match &transaction {
+                    Some(transaction) => transaction.archive(&original)?,
+                    None if !rewritten.exists() => fs::remove_file(&original)?,
+                    None => (),
+                }
+            }
+            if rewritten.exists() {
+                fs::rename(rewritten, original)?;
+            }
+        }
+        let schema_path = stem.with_extension(NODE_SCHEMA);
+        if let Some(transaction) = &transaction {
+            if schema_path.exists() {
+                transaction.archive(&schema_path)?;
+            }
+        }
+        let node_meta = &octree.nodes[node_id];
+        let node_schema = NodeSchema {
+            num_points: node_meta.num_points,
+            bounding_cube: node_meta.bounding_cube.clone(),
+            position_encoding: node_meta.position_encoding.clone(),
+            compressed_attributes: node_meta.compressed_attributes,
+            attribute_data_types: schema
+                .iter()
+                .flatten()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+        };
+        node_schema.write(&stem)?;
+        // The order of the points is unchanged, so the kd-tree stays valid.
+        let files: Vec<&str> = std::iter::once("position")
+            .chain(new_attributes.iter().copied())
+            .chain(Some(KD_INDEX).filter(|_| stem.with_extension(KD_INDEX).exists()))
+            .collect();
+        octree.nodes.get_mut(node_id).unwrap().checksums =
+            compute_checksums(&data_provider, &node_id.to_string(), &files)?;
+    }
+    fs::remove_dir_all(&rewrite_directory)?;
+
+    if let Some(schema) = schema {
+        octree.meta.attribute_data_types = schema.into_iter().collect();
+    }
+    let nodes = octree
+        .nodes
+        .iter()
+        .map(|(node_id, node_meta)| {
+            to_node_proto(
+                node_id,
+                node_meta.num_points,
+                &node_meta.position_encoding,
+                node_meta.compressed_attributes,
+                node_meta.checksums.clone(),
+                node_meta.normal_cone,
+                node_meta.occupancy,
+            )
+        })
+        .collect();
+    write_meta(directory, &to_meta_proto(&octree.meta, nodes))?;
+    if let Some(transaction) = transaction {
+        transaction.commit(format!(
+            "Rewrote the attributes of {} nodes.",
+            node_ids.len()
+        ))?;
+    }
+    Ok(())
+}
+
+/// Writes the node with its attributes changed by 'func' into 'rewrite_data_provider'. Returns the
+/// attributes the node had before and its schema after the rewrite.
+fn rewrite_node<F>(
+    octree: &Octree,
+    data_provider: &OnDiskDataProvider,
+    rewrite_data_provider: &OnDiskDataProvider,
+    node_id: NodeId,
+    func: &F,
+) -> Result<(Vec<String>, Schema)>
+where
+    F: Fn(NodeId, &mut PointsBatch) -> Result<()>,
+{
+    let node_meta = &octree.nodes[&node_id];
+    let num_points = node_meta.num_points as usize;
+    // Standard attributes are in the meta data even if the octree was built without them.
+    let stem = data_provider.stem(&node_id.to_string());
+    let mut attributes: Vec<&str> = octree
+        .meta
+        .attribute_data_types
+        .keys()
+        .map(String::as_str)
+        .filter(|attribute| stem.with_extension(attribute_extension(attribute)).exists())
+        .collect();
+    attributes.sort();
+
+    let mut batch = PointsBatch::default();
+    for mut points in octree.points_in_node(&attributes, node_id, num_points)? {
+        batch.append(&mut points).map_err(Error::SchemaMismatch)?;
+    }
+    let positions = batch.position.clone();
+    func(node_id, &mut batch)?;
+    if batch.position != positions {
+        return Err(Error::InvalidInput(format!(
+            "The positions of node {} were changed.",
+            node_id
+        )));
+    }
+    if let Some((name, data)) = batch
+        .attributes
+        .iter()
+        .find(|(_, data)| data.len() != num_points)
+    {
+        return Err(Error::InvalidInput(format!(
+            "Node {} has {} points, but {} values of '{}'.",
+            node_id,
+            num_points,
+            data.len(),
+            name
+        )));
+    }
+
+    let attribute_compression = if node_meta.compressed_attributes {
+        AttributeCompression::Compressed(HashMap::new())
+    } else {
+        AttributeCompression::Uncompressed
+    };
+    let mut writer = RawNodeWriter::new(
+        rewrite_data_provider.stem(&node_id.to_string()),
+        octree.encoding_for_node(node_id),
+        OpenMode::Truncate,
+    )
+    .with_attribute_compression(attribute_compression);
+    writer.write(&batch)?;
+    writer.finish()?;
+    let schema = batch
+        .attributes
+        .iter()
+        .map(|(name, data)| (name.clone(), data.data_type()))
+        .collect();
+    Ok((attributes.into_iter().map(str::to_string).collect(), schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::AttributeData;
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_rewrite_attributes() {
+        let mut position = vec![Point3::origin(); 16];
+        position.push(Point3::new(-200., -40., 30.));
+        let num_points = position.len();
+        let batch = PointsBatch {
+            position,
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        build_octree_of_batch(tmp_dir.path(), 1., batch, &BuildOptions::default());
+        let height_above_ground = |_: NodeId, batch: &mut PointsBatch| -> Result<()> {
+            let heights = batch.position.iter().map(|p| p.z + 40.).collect();
+            batch
+                .attributes
+                .insert("height".to_string(), AttributeData::F64(heights));
+            Ok(())
+        };
+        rewrite_attributes(tmp_dir.path(), height_above_ground).unwrap();
+
+        let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: tmp_dir.path().to_path_buf(),
+        }))
+        .unwrap();
+        assert_eq!(
+            octree.meta.attribute_data_types.get("height"),
+            Some(&AttributeDataType::F64)
+        );
+        let mut num_read = 0;
+        for batch in octree.all_points(&["color", "height"], None) {
+            let batch = batch.unwrap();
+            let heights: &Vec<f64> = batch.get_attribute_vec("height").unwrap();
+            for (position, height) in batch.position.iter().zip(heights) {
+                assert_eq!(*height, position.z + 40.);
+            }
+            num_read += batch.position.len();
+        }
+        assert_eq!(num_read, num_points);
+        for (node_id, node_meta) in &octree.nodes {
+            assert!(node_meta.num_points == 0 || octree.verify_node(node_id).unwrap());
+        }
+
+        let move_points = |_: NodeId, batch: &mut PointsBatch| -> Result<()> {
+            batch.position[0].x += 1.;
+            Ok(())
+        };
+        assert!(rewrite_attributes(tmp_dir.path(), move_points).is_err());
+        assert!(!tmp_dir.path().join(REWRITE_DIRECTORY).exists());
+    }
+}
@@ -0,0 +1,125 @@
+//! The screen-space error that decides which nodes are drawn. The server, the SDL viewer and the
+//! web client all select nodes with it, so that a pixel threshold means the same in all of them.
+
+use crate::geometry::{Aabb, Cube};
+use nalgebra::{Matrix4, Point3};
+use num::clamp;
+
+/// Nodes whose screen-space error is smaller than this many pixels are not drawn by default.
+pub const DEFAULT_PIXEL_THRESHOLD: f64 = 1.;
+
+/// The viewport size that is assumed when the caller does not know it, a full HD screen.
+pub const DEFAULT_VIEWPORT_WIDTH: f64 = 1920.;
+pub const DEFAULT_VIEWPORT_HEIGHT: f64 = 1080.;
+
+/// Measures how large nodes appear on a viewport and decides which of them are worth drawing.
+///
+/// The screen-space error of a node is the edge length in pixels of a square with the area that
+/// its bounding cube covers on the viewport. All nodes hold about the same number of points, so
+/// this is proportional to the spacing of its points on screen, i.e. to the error of drawing its
+/// parent instead of it. Parts of the cube outside of the view do not count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScreenSpaceError {
+    /// Width of the viewport in pixels.
+    pub width: f64,
+    /// Height of the viewport in pixels.
+    pub height: f64,
+    /// Nodes with a smaller error are left out together with their children.
+    pub pixel_threshold: f64,
+}
+
+impl Default for ScreenSpaceError {
+    fn default() -> Self {
+        Self::new(DEFAULT_VIEWPORT_WIDTH, DEFAULT_VIEWPORT_HEIGHT)
+    }
+}
+
+impl ScreenSpaceError {
+    /// A metric for a viewport of 'width' x 'height' pixels with the 'DEFAULT_PIXEL_THRESHOLD'.
+    pub fn new(width: f64, height: f64) -> Self {
+        ScreenSpaceError {
+            width,
+            height,
+            pixel_threshold: DEFAULT_PIXEL_THRESHOLD,
+        }
+    }
+
+    pub fn with_pixel_threshold(mut self, pixel_threshold: f64) -> Self {
+        self.pixel_threshold = pixel_threshold;
+        self
+    }
+
+    /// The screen-space error in pixels of a node with 'bounding_cube' seen through
+    /// 'projection_matrix', which maps points into clip space.
+    pub fn error(&self, bounding_cube: &Cube, projection_matrix: &Matrix4<f64>) -> f64 {
+        // Clip space spans 2 units in x and y.
+        let pixels_per_unit_area = self.width / 2. * self.height / 2.;
+        (relative_size_on_screen(bounding_cube, projection_matrix) * pixels_per_unit_area).sqrt()
+    }
+
+    /// Whether a node with screen-space 'error' should be drawn.
+    pub fn is_visible(&self, error: f64) -> bool {
+        error >= self.pixel_threshold
+    }
+}
+
+// TODO(hrapp): something is funky here. "r" is smaller on screen than "r4" in many cases, though
+// that is impossible.
+fn project(m: &Matrix4<f64>, p: &Point3<f64>) -> Point3<f64> {
+    let q = m * p.to_homogeneous();
+    Point3::from_homogeneous(q).unwrap()
+}
+
+fn clip_point_to_hemicube(p: &Point3<f64>) -> Point3<f64> {
+    Point3::new(clamp(p.x, -1., 1.), clamp(p.y, -1., 1.), clamp(p.z, 0., 1.))
+}
+
+// Projects the corners of the cube through the matrix and returns the area of their bounding
+// rectangle in clip space, clipped to the view.
+fn relative_size_on_screen(bounding_cube: &Cube, matrix: &Matrix4<f64>) -> f64 {
+    // z is unused here.
+    let min = bounding_cube.min();
+    let max = bounding_cube.max();
+    let mut rv = Aabb::new(
+        clip_point_to_hemicube(&project(matrix, &min)),
+        clip_point_to_hemicube(&project(matrix, &max)),
+    );
+    for p in &[
+        Point3::new(max.x, min.y, min.z),
+        Point3::new(min.x, max.y, min.z),
+        Point3::new(max.x, max.y, min.z),
+        Point3::new(min.x, min.y, max.z),
+        Point3::new(max.x, min.y, max.z),
+        Point3::new(min.x, max.y, max.z),
+    ] {
+        rv.grow(clip_point_to_hemicube(&project(matrix, p)));
+    }
+    let diag = rv.diag();
+    diag.x * diag.y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_is_in_pixels() {
+        // Maps x and y from [0, 1] to the whole clip space.
+        let matrix = Matrix4::new(
+            2., 0., 0., -1., //
+            0., 2., 0., -1., //
+            0., 0., 1., 0., //
+            0., 0., 0., 1.,
+        );
+        let cube = Cube::new(Point3::new(0., 0., 0.), 1.);
+        let metric = ScreenSpaceError::new(400., 100.);
+        assert!((metric.error(&cube, &matrix) - 200.).abs() < 1e-9);
+
+        let quarter = Cube::new(Point3::new(0., 0., 0.), 0.5);
+        assert!((metric.error(&quarter, &matrix) - 100.).abs() < 1e-9);
+        assert!(metric.is_visible(metric.error(&quarter, &matrix)));
+        assert!(!metric
+            .with_pixel_threshold(150.)
+            .is_visible(metric.error(&quarter, &matrix)));
+    }
+}
@@ -0,0 +1,242 @@
+//! Renders images of an octree from a camera on the CPU, without a window or a GPU, e.g. for
+//! thumbnails and reports on a server. The nodes are selected with the same screen-space error as
+//! in the viewers, and every point is drawn as a square of 'point_size' pixels, the nearest point in front.
+
+use crate::color::ColorSpace;
+use crate::errors::*;
+use crate::octree::{NodeData, NodeId, Octree, ScreenSpaceError, DEFAULT_PIXEL_THRESHOLD};
+use crate::read_write::{decode, fixpoint_decode, PositionEncoding};
+use byteorder::{ByteOrder, LittleEndian};
+use image::{Rgba, RgbaImage};
+use nalgebra::{Isometry3, Matrix4, Perspective3, Point3, Vector3};
+
+#[derive(Clone, Debug)]
+pub struct SnapshotOptions {
+    pub width: u32,
+    pub height: u32,
+    /// Vertical field of view in radians.
+    pub fov_y: f64,
+    /// Distances of the near and far clipping planes from the camera.
+    pub near: f64,
+    pub far: f64,
+    /// Nodes with a smaller screen-space error are not drawn, see 'ScreenSpaceError'.
+    pub pixel_threshold: f64,
+    /// Edge length of the square that a point covers, in pixels.
+    pub point_size: u32,
+    pub background: Rgba<u8>,
+    /// Stops drawing nodes, the largest on screen first, once this many points have been drawn.
+    pub point_budget: Option<usize>,
+}
+
+impl Default for SnapshotOptions {
+    /// The projection of the viewers.
+    fn default() -> Self {
+        Self {
+            width: 1280,
+            height: 720,
+            fov_y: std::f64::consts::FRAC_PI_4,
+            near: 0.1,
+            far: 10000.,
+            pixel_threshold: DEFAULT_PIXEL_THRESHOLD,
+            point_size: 2,
+            background: Rgba([0, 0, 0, 255]),
+            point_budget: None,
+        }
+    }
+}
+
+impl SnapshotOptions {
+    /// The matrix that maps the octree into clip space for a camera at 'octree_from_camera', which
+    /// looks along its negative z axis with y up, like the cameras of the viewers.
+    pub fn clip_from_octree(&self, octree_from_camera: &Isometry3<f64>) -> Matrix4<f64> {
+        let aspect = f64::from(self.width) / f64::from(self.height.max(1));
+        Perspective3::new(aspect, self.fov_y, self.near, self.far).to_homogeneous()
+            * octree_from_camera.inverse().to_homogeneous()
+    }
+
+    /// The column, row and depth in clip space at which 'point' is drawn, if it is in view.
+    pub fn project(
+        &self,
+        clip_from_octree: &Matrix4<f64>,
+        point: &Point3<f64>,
+    ) -> Option<(f64, f64, f64)> {
+        let clip = clip_from_octree * point.to_homogeneous();
+        if clip.w <= 0. {
+            return None;
+        }
+        let ndc = Vector3::new(clip.x, clip.y, clip.z) / clip.w;
+        if ndc.iter().any(|c| c.abs() > 1.) {
+            return None;
+        }
+        Some((
+            (ndc.x + 1.) / 2. * f64::from(self.width),
+            (1. - ndc.y) / 2. * f64::from(self.height),
+            ndc.z,
+        ))
+    }
+}
+
+/// Renders the points of 'octree' in their sRGB colors as seen from 'octree_from_camera'.
+pub fn render_snapshot(
+    octree: &Octree,
+    octree_from_camera: &Isometry3<f64>,
+    options: &SnapshotOptions,
+) -> Result<RgbaImage> {
+    render_snapshot_with_matrix(
+        octree,
+        &options.clip_from_octree(octree_from_camera),
+        options,
+    )
+}
+
+/// Like 'render_snapshot', but with a given projection, so 'fov_y', 'near' and 'far' are ignored.
+pub fn render_snapshot_with_matrix(
+    octree: &Octree,
+    clip_from_octree: &Matrix4<f64>,
+    options: &SnapshotOptions,
+) -> Result<RgbaImage> {
+    render_snapshot_with_node_reader(octree, clip_from_octree, options, |node_id| {
+        let mut node_data = octree.get_node_data(node_id)?;
+        octree
+            .color_space()
+            .convert_u8(ColorSpace::Srgb, &mut node_data.color);
+        Ok(node_data)
+    })
+}
+
+/// Like 'render_snapshot_with_matrix', but draws the node data that 'read_node' returns, e.g. to
+/// change the points before they are drawn. The colors have to be sRGB.
+pub fn render_snapshot_with_node_reader<F>(
+    octree: &Octree,
+    clip_from_octree: &Matrix4<f64>,
+    options: &SnapshotOptions,
+    mut read_node: F,
+) -> Result<RgbaImage>
+where
+    F: FnMut(&NodeId) -> Result<NodeData>,
+{
+    if options.width == 0 || options.height == 0 || options.point_size == 0 {
+        return Err(Error::InvalidInput(
+            "The image size and the point size must be positive.".to_string(),
+        ));
+    }
+    let (width, height) = (options.width as usize, options.height as usize);
+    let metric = ScreenSpaceError::new(f64::from(options.width), f64::from(options.height))
+        .with_pixel_threshold(options.pixel_threshold);
+    let mut image = RgbaImage::from_pixel(options.width, options.height, options.background);
+    let mut depths = vec![std::f64::INFINITY; width * height];
+    // The square of a point starts this many pixels left of and above it.
+    let offset = (options.point_size / 2) as i64;
+    let mut num_points_drawn = 0;
+    for (node_id, _) in octree.get_visible_nodes_with_screen_space_error(clip_from_octree, &metric)
+    {
+        if options
+            .point_budget
+            .map_or(false, |budget| num_points_drawn >= budget)
+        {
+            break;
+        }
+        let node_data = read_node(&node_id)?;
+        let positions = decode_positions(&node_data)?;
+        for (point, color) in positions.iter().zip(node_data.color.chunks_exact(3)) {
+            let (column, row, depth) = match options.project(clip_from_octree, point) {
+                Some(pixel) => pixel,
+                None => continue,
+            };
+            let left = column as i64 - offset;
+            let top = row as i64 - offset;
+            for y in top.max(0)..(top + i64::from(options.point_size)).min(height as i64) {
+                for x in left.max(0)..(left + i64::from(options.point_size)).min(width as i64) {
+                    let index = y as usize * width + x as usize;
+                    if depth < depths[index] {
+                        depths[index] = depth;
+                        image.put_pixel(
+                            x as u32,
+                            y as u32,
+                            Rgba([color[0], color[1], color[2], 255]),
+                        );
+                    }
+                }
+            }
+        }
+        num_points_drawn += positions.len();
+    }
+    Ok(image)
+}
+
+/// The positions of 'node_data' in the coordinates of the octree.
+fn decode_positions(node_data: &NodeData) -> Result<Vec<Point3<f64>>> {
+    let encoding = &node_data.meta.position_encoding;
+    let bytes_per_coordinate = encoding.bytes_per_coordinate();
+    let min = node_data.meta.bounding_cube.min();
+    let edge_length = node_data.meta.bounding_cube.edge_length();
+    let coordinates = node_data
+        .position
+        .chunks_exact(bytes_per_coordinate)
+        .enumerate()
+        .map(|(i, coordinate)| {
+            let min = min[i % 3];
+            match encoding {
+                PositionEncoding::Uint8 => Ok(fixpoint_decode(coordinate[0], min, edge_length)),
+                PositionEncoding::Uint16 => Ok(fixpoint_decode(
+                    LittleEndian::read_u16(coordinate),
+                    min,
+                    edge_length,
+                )),
+                PositionEncoding::Float32 => {
+                    Ok(decode(LittleEndian::read_f32(coordinate), min, edge_length))
+                }
+                PositionEncoding::Float64 => {
+                    Ok(decode(LittleEndian::read_f64(coordinate), min, edge_length))
+                }
+                _ => Err(Error::InvalidInput(format!(
+                    "Node data cannot be {:?} encoded.",
+                    encoding
+                ))),
+            }
+        })
+        .collect::<Result<Vec<f64>>>()?;
+    Ok(coordinates
+        .chunks_exact(3)
+        .map(|xyz| Point3::new(xyz[0], xyz[1], xyz[2]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::{AttributeData, PointsBatch};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_render_snapshot() {
+        // Red points at the origin and one far away to the side.
+        let mut position = vec![Point3::origin(); 16];
+        position.push(Point3::new(-200., -40., 30.));
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let octree = build_octree_of_batch(tmp_dir.path(), 1., batch, &BuildOptions::default());
+        let options = SnapshotOptions {
+            width: 64,
+            height: 48,
+            point_size: 3,
+            ..Default::default()
+        };
+        // Above the origin, looking down.
+        let camera = Isometry3::translation(0., 0., 10.);
+        let image = render_snapshot(&octree, &camera, &options).unwrap();
+        assert_eq!(image.dimensions(), (64, 48));
+        assert_eq!(image.get_pixel(32, 24).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    }
+}
@@ -1,10 +1,18 @@
 use crate::data_provider::OnDiskDataProvider;
-use crate::errors::Result;
+use crate::errors::{Error, Result};
 use crate::geometry::Aabb;
 use crate::iterator::{ParallelIterator, PointQuery};
-use crate::octree::{build_octree, Octree};
-use crate::{AttributeData, NumberOfPoints, PointsBatch};
+use crate::octree::{
+    build_octree, build_octree_from_files, build_octree_two_pass, find_principal_frame,
+    scan_extent, BuildOptions, InputFile, MultiFileIterator, NodeColumns, NodeId, NodeLimits,
+    Octree, Subdivision, FRAME_INDEX, KD_INDEX, SOURCE_ID,
+};
+use crate::read_write::VertexLayout;
+use crate::{AttributeData, NextInto, NumberOfPoints, PointsBatch, NUM_POINTS_PER_BATCH};
 use nalgebra::{Point3, Vector3};
+use rayon::iter::ParallelIterator as _;
+use std::fs;
+use std::path::Path;
 use tempdir::TempDir;
 
 const NUM_POINTS: usize = 100_001;
@@ -15,7 +23,18 @@ impl NumberOfPoints for std::vec::IntoIter<PointsBatch> {
     }
 }
 
+impl NextInto for std::vec::IntoIter<PointsBatch> {}
+
 fn build_test_octree() -> Octree {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    build_test_octree_into(tmp_dir.path(), &BuildOptions::default());
+    Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.into_path(),
+    }))
+    .unwrap()
+}
+
+fn build_test_octree_into(directory: &Path, options: &BuildOptions) {
     let mut batch = PointsBatch {
         position: vec![Point3::new(0.0, 0.0, 0.0); NUM_POINTS],
         attributes: vec![(
@@ -30,17 +49,39 @@ fn build_test_octree() -> Octree {
 
     let bounding_box = Aabb::new(batch.position[0], batch.position[NUM_POINTS - 1]);
 
-    let tmp_dir = TempDir::new("octree").unwrap();
-
     build_octree(
-        &tmp_dir,
+        directory,
         1.0,
         bounding_box,
         vec![batch].into_iter(),
         &["color"],
+        options,
+    );
+}
+
+/// Builds an octree of 'batch' into 'directory' and opens it, for the tests of the octree modules.
+pub(crate) fn build_octree_of_batch(
+    directory: &Path,
+    resolution: f64,
+    batch: PointsBatch,
+    options: &BuildOptions,
+) -> Octree {
+    let mut bounding_box = Aabb::new(batch.position[0], batch.position[0]);
+    for p in &batch.position {
+        bounding_box.grow(*p);
+    }
+    let attributes: Vec<String> = batch.attributes.keys().cloned().collect();
+    let attributes: Vec<&str> = attributes.iter().map(String::as_str).collect();
+    build_octree(
+        directory,
+        resolution,
+        bounding_box,
+        vec![batch].into_iter(),
+        &attributes,
+        options,
     );
     Octree::from_data_provider(Box::new(OnDiskDataProvider {
-        directory: tmp_dir.into_path(),
+        directory: directory.to_path_buf(),
     }))
     .unwrap()
 }
@@ -134,3 +175,513 @@ fn test_batch_iterator_more_points() {
         .expect("Iterator errored even though callback should not have errored.");
     assert_eq!(c.num_received_points, NUM_POINTS);
 }
+
+#[test]
+fn test_inputs_with_different_attributes() {
+    let inputs = [
+        InputFile::new("src/test_data/xyz_f32_rgb_u8_le.ply"),
+        InputFile::new("src/test_data/xyz_f32_rgb_u8_intensity_f32.ply"),
+    ];
+    let result = MultiFileIterator::new(&inputs, NUM_POINTS_PER_BATCH);
+    assert!(matches!(result, Err(Error::SchemaMismatch(_))));
+    assert!(MultiFileIterator::new(&inputs[..1], NUM_POINTS_PER_BATCH).is_ok());
+}
+
+#[test]
+fn test_node_vertices() {
+    let octree = build_test_octree();
+    let layout = VertexLayout::position_color();
+    let mut num_vertices = 0;
+    for (node_id, node_meta) in &octree.nodes {
+        let vertices = octree.get_node_vertices(node_id, &layout).unwrap();
+        assert_eq!(
+            vertices.len(),
+            node_meta.num_points as usize * layout.stride()
+        );
+        for vertex in vertices.chunks_exact(layout.stride()) {
+            assert_eq!(&vertex[12..], &[255, 0, 0, 255]);
+        }
+        num_vertices += vertices.len() / layout.stride();
+    }
+    assert_eq!(num_vertices, NUM_POINTS);
+}
+
+#[test]
+fn test_builds_with_the_same_seed_are_identical() {
+    let options = BuildOptions {
+        seed: 7,
+        ..Default::default()
+    };
+    let directories: Vec<_> = (0..2)
+        .map(|_| {
+            let tmp_dir = TempDir::new("octree").unwrap();
+            build_test_octree_into(tmp_dir.path(), &options);
+            tmp_dir
+        })
+        .collect();
+    let mut file_names: Vec<_> = fs::read_dir(directories[0].path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    file_names.sort();
+    assert_eq!(
+        file_names.len(),
+        fs::read_dir(directories[1].path()).unwrap().count()
+    );
+    for file_name in file_names {
+        assert_eq!(
+            fs::read(directories[0].path().join(&file_name)).unwrap(),
+            fs::read(directories[1].path().join(&file_name)).unwrap(),
+            "{:?}",
+            file_name
+        );
+    }
+}
+
+#[test]
+fn test_crs_is_stored_in_meta() {
+    assert_eq!(None, build_test_octree().crs());
+
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let options = BuildOptions {
+        crs: Some("EPSG:25832".to_string()),
+        ..Default::default()
+    };
+    build_test_octree_into(tmp_dir.path(), &options);
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.path().to_path_buf(),
+    }))
+    .unwrap();
+    assert_eq!(Some("EPSG:25832"), octree.crs());
+}
+
+#[test]
+fn test_frames() {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let file = "src/test_data/xyz_f32_rgb_u8_le.ply";
+    let options = BuildOptions {
+        frame_timestamps: Some(vec![5., 1., 5.]),
+        ..Default::default()
+    };
+    build_octree_from_files(
+        tmp_dir.path(),
+        0.001,
+        &[
+            InputFile::new(file),
+            InputFile::new(file),
+            InputFile::new(file),
+        ],
+        &["color"],
+        &options,
+    );
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.path().to_path_buf(),
+    }))
+    .unwrap();
+    assert_eq!(octree.frame_timestamps(), &[1., 5.]);
+
+    let mut num_points_per_frame = [0; 2];
+    for node_id in octree.nodes(..) {
+        let mut points = octree
+            .points_in_node(&[FRAME_INDEX], node_id, 1000)
+            .unwrap();
+        let mut batch = PointsBatch::default();
+        let mut num_points_in_frame_0 = 0;
+        while points.next_into(&mut batch) {
+            let frame_indices: &Vec<u32> = batch.get_attribute_vec(FRAME_INDEX).unwrap();
+            for frame_index in frame_indices {
+                num_points_per_frame[*frame_index as usize] += 1;
+                if *frame_index == 0 {
+                    num_points_in_frame_0 += 1;
+                }
+            }
+        }
+        let mut node_data = octree.get_node_data(&node_id).unwrap();
+        let bytes_per_point = node_data.position.len() / node_data.meta.num_points.max(1) as usize;
+        octree.retain_frame(&node_id, &mut node_data, 0).unwrap();
+        assert_eq!(node_data.meta.num_points, num_points_in_frame_0);
+        assert_eq!(node_data.color.len(), 3 * num_points_in_frame_0 as usize);
+        assert_eq!(
+            node_data.position.len(),
+            bytes_per_point * num_points_in_frame_0 as usize
+        );
+    }
+    // The second frame has the points of two files.
+    assert_eq!(2 * num_points_per_frame[0], num_points_per_frame[1]);
+}
+
+#[test]
+fn test_y_up_inputs_are_rotated() {
+    let file = "src/test_data/xyz_f32_rgb_u8_le.ply";
+    let build = |input: InputFile, options: &BuildOptions| {
+        let tmp_dir = TempDir::new("octree").unwrap();
+        build_octree_from_files(tmp_dir.path(), 0.001, &[input], &["color"], options);
+        Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: tmp_dir.into_path(),
+        }))
+        .unwrap()
+    };
+    let z_up = build(InputFile::new(file), &BuildOptions::default());
+    let mut input = InputFile::new(file);
+    input.up_axis = UpAxis::Y;
+    let y_up = build(input, &BuildOptions::default());
+    assert_eq!(UpAxis::Z, y_up.view_settings().up_axis);
+
+    // The vertical axis y of the input becomes z, and z becomes -y.
+    let (min, max) = (z_up.bounding_box().min(), z_up.bounding_box().max());
+    let expected_min = Point3::new(min.x, -max.z, min.y);
+    assert!((y_up.bounding_box().min() - expected_min).norm() < 1e-6);
+
+    let options = BuildOptions {
+        up_axis: UpAxis::Y,
+        ..Default::default()
+    };
+    let mut input = InputFile::new(file);
+    input.up_axis = UpAxis::Y;
+    let octree = build(input, &options);
+    assert_eq!(UpAxis::Y, octree.view_settings().up_axis);
+    assert!((octree.bounding_box().min() - min).norm() < 1e-6);
+}
+
+#[test]
+fn test_source_ids() {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let file = "src/test_data/xyz_f32_rgb_u8_le.ply";
+    let options = BuildOptions {
+        source_ids: true,
+        ..Default::default()
+    };
+    build_octree_from_files(
+        tmp_dir.path(),
+        0.001,
+        &[InputFile::new(file), InputFile::new(file)],
+        &["color"],
+        &options,
+    );
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.path().to_path_buf(),
+    }))
+    .unwrap();
+    assert_eq!(octree.sources(), &[file.to_string(), file.to_string()]);
+
+    let mut num_points_per_source = [0; 2];
+    for node_id in octree.nodes(..) {
+        let mut points = octree.points_in_node(&[SOURCE_ID], node_id, 1000).unwrap();
+        let mut batch = PointsBatch::default();
+        while points.next_into(&mut batch) {
+            let source_ids: &Vec<u16> = batch.get_attribute_vec(SOURCE_ID).unwrap();
+            for source_id in source_ids {
+                num_points_per_source[usize::from(*source_id)] += 1;
+            }
+        }
+        let num_points = octree.get_node_meta(&node_id).unwrap().num_points as usize;
+        let colors = octree.get_node_source_colors(&node_id).unwrap();
+        assert_eq!(colors.len(), num_points * 3);
+    }
+    assert!(num_points_per_source[0] > 0);
+    assert_eq!(num_points_per_source[0], num_points_per_source[1]);
+
+    // Octrees without source ids cannot be colored by them.
+    let octree = build_test_octree();
+    assert!(octree.get_node_source_colors(&NodeId::root()).is_err());
+}
+
+#[test]
+fn test_principal_frame() {
+    // A corridor running diagonally in the x-y plane.
+    let position: Vec<_> = (0..1000)
+        .flat_map(|i| {
+            let t = f64::from(i);
+            vec![-1., 1.]
+                .into_iter()
+                .map(move |offset| Point3::new(1000. + t - offset, 2000. + t + offset, t % 7.))
+        })
+        .collect();
+    let batch = PointsBatch {
+        position: position.clone(),
+        attributes: Default::default(),
+    };
+    let world_from_octree = find_principal_frame(vec![batch].into_iter());
+    let octree_from_world = world_from_octree.inverse();
+    let mut bounding_box = Aabb::zero();
+    for p in &position {
+        bounding_box.grow(octree_from_world * p);
+    }
+    // The corridor runs along the x axis of the frame and is centered at its origin.
+    assert!((bounding_box.diag().x - 999. * 2f64.sqrt()).abs() < 1e-6);
+    assert!(bounding_box.diag().y < 3.);
+    assert!((bounding_box.center().x).abs() < 1e-6);
+}
+
+#[test]
+fn test_two_pass_build() {
+    let position: Vec<_> = (0..NUM_POINTS)
+        .map(|i| Point3::new((i % 100) as f64, -((i / 100) as f64), (i % 3) as f64))
+        .collect();
+    let open_input = || {
+        vec![PointsBatch {
+            position: position.clone(),
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); NUM_POINTS]),
+            )]
+            .into_iter()
+            .collect(),
+        }]
+        .into_iter()
+    };
+    let extent = scan_extent(open_input());
+    assert_eq!(NUM_POINTS, extent.num_points);
+    assert_eq!(&Point3::new(0., -1000., 0.), extent.bounding_box.min());
+    assert_eq!(&Point3::new(99., 0., 2.), extent.bounding_box.max());
+
+    let tmp_dir = TempDir::new("octree").unwrap();
+    build_octree_two_pass(
+        tmp_dir.path(),
+        0.01,
+        open_input,
+        &["color"],
+        &BuildOptions::default(),
+    );
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.path().to_path_buf(),
+    }))
+    .unwrap();
+    assert_eq!(extent.bounding_box.min(), octree.bounding_box().min());
+    assert_eq!(extent.bounding_box.max(), octree.bounding_box().max());
+}
+
+#[test]
+fn test_quadtree() {
+    // A flat, wide patch like an airborne scan.
+    let position: Vec<_> = (0..NUM_POINTS * 2)
+        .map(|i| Point3::new((i % 500) as f64, (i / 500) as f64, (i % 7) as f64))
+        .collect();
+    let mut bounding_box = Aabb::new(position[0], position[0]);
+    for p in &position {
+        bounding_box.grow(*p);
+    }
+    let batch = PointsBatch {
+        position,
+        attributes: vec![(
+            "color".to_string(),
+            AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); NUM_POINTS * 2]),
+        )]
+        .into_iter()
+        .collect(),
+    };
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let options = BuildOptions {
+        subdivision: Subdivision::Quadtree,
+        ..Default::default()
+    };
+    build_octree(
+        tmp_dir.path(),
+        0.01,
+        bounding_box.clone(),
+        vec![batch].into_iter(),
+        &["color"],
+        &options,
+    );
+
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.path().to_path_buf(),
+    }))
+    .unwrap();
+    assert_eq!(Subdivision::Quadtree, octree.meta.subdivision);
+    assert!(octree.nodes.len() > 1);
+    let mut num_points = 0;
+    for (node_id, node_meta) in &octree.nodes {
+        // Only the x and y bits are ever set.
+        assert!(node_id
+            .to_string()
+            .chars()
+            .skip(1)
+            .all(|c| "0246".contains(c)));
+        let node_box = node_meta.bounding_cube.to_aabb();
+        assert!(node_box.min().z <= bounding_box.min().z);
+        assert!(node_box.max().z >= bounding_box.max().z);
+        for batch in octree.points_in_node(&[], *node_id, NUM_POINTS).unwrap() {
+            num_points += batch.position.len();
+            // Allow for the rounding of the position encoding.
+            assert!(batch.position.iter().all(|p| {
+                (0..3).all(|axis| {
+                    p[axis] >= node_box.min()[axis] - 0.01 && p[axis] <= node_box.max()[axis] + 0.01
+                })
+            }));
+        }
+    }
+    assert_eq!(NUM_POINTS * 2, num_points);
+}
+
+#[test]
+fn test_node_limits() {
+    let position: Vec<_> = (0..NUM_POINTS * 2)
+        .map(|i| Point3::new((i % 500) as f64, (i / 500) as f64, (i % 7) as f64))
+        .collect();
+    let mut bounding_box = Aabb::new(position[0], position[0]);
+    for p in &position {
+        bounding_box.grow(*p);
+    }
+    let batch = PointsBatch {
+        position,
+        attributes: vec![(
+            "color".to_string(),
+            AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); NUM_POINTS * 2]),
+        )]
+        .into_iter()
+        .collect(),
+    };
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let options = BuildOptions {
+        node_limits: vec![
+            NodeLimits {
+                capacity: Some(1000),
+                ..Default::default()
+            },
+            NodeLimits {
+                min_points_to_split: i64::max_value(),
+                capacity: None,
+            },
+        ],
+        ..Default::default()
+    };
+    build_octree(
+        tmp_dir.path(),
+        0.01,
+        bounding_box,
+        vec![batch].into_iter(),
+        &["color"],
+        &options,
+    );
+
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.path().to_path_buf(),
+    }))
+    .unwrap();
+    // Only the root is split, and it keeps at most its capacity.
+    assert!(octree.nodes.len() > 1);
+    assert!(octree.nodes.keys().all(|node_id| node_id.level() <= 1));
+    assert!(octree.nodes[&NodeId::root()].num_points <= 1000);
+    let num_points: i64 = octree.nodes.values().map(|node| node.num_points).sum();
+    assert_eq!((NUM_POINTS * 2) as i64, num_points);
+}
+
+#[test]
+fn test_kd_index() {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let options = BuildOptions {
+        kd_index: true,
+        ..Default::default()
+    };
+    build_test_octree_into(tmp_dir.path(), &options);
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.path().to_path_buf(),
+    }))
+    .unwrap();
+    let root_id = NodeId::root();
+    assert!(octree.nodes[&root_id].checksums.contains_key(KD_INDEX));
+    let far_point = Point3::new(-199., -40., 30.);
+    let nearest = octree.nearest_in_node(&root_id, &far_point, 3).unwrap();
+    let within_radius = octree
+        .points_within_radius_in_node(&root_id, &Point3::origin(), 1.)
+        .unwrap();
+    assert_eq!(3, nearest.len());
+    assert!(nearest[0].1 <= nearest[1].1 && nearest[1].1 <= nearest[2].1);
+    assert!(!within_radius.is_empty());
+
+    // Without the kd-tree, all points are compared, with the same results.
+    fs::remove_file(tmp_dir.path().join("r").with_extension(KD_INDEX)).unwrap();
+    assert_eq!(
+        nearest,
+        octree.nearest_in_node(&root_id, &far_point, 3).unwrap()
+    );
+    assert_eq!(
+        within_radius,
+        octree
+            .points_within_radius_in_node(&root_id, &Point3::origin(), 1.)
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_detects_corrupt_nodes() {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    build_test_octree_into(tmp_dir.path(), &BuildOptions::default());
+    let root_colors = tmp_dir.path().join("r.rgb");
+    let mut colors = fs::read(&root_colors).unwrap();
+    colors[0] ^= 1;
+    fs::write(&root_colors, colors).unwrap();
+
+    let mut octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: tmp_dir.path().to_path_buf(),
+    }))
+    .unwrap();
+    let root_id = "r".parse().unwrap();
+    match octree.points_in_node(&["color"], root_id, NUM_POINTS) {
+        Err(Error::CorruptNode(node_id, attribute)) => {
+            assert_eq!((node_id.as_str(), attribute.as_str()), ("r", "color"));
+        }
+        other => panic!("Expected a corrupt node, got {:?}.", other.err()),
+    }
+    assert!(octree.get_node_data(&root_id).is_err());
+    for node_id in octree.nodes.keys().filter(|node_id| **node_id != root_id) {
+        assert!(octree.verify_node(node_id).is_ok());
+    }
+
+    octree.set_verify_checksums(false);
+    assert!(octree
+        .points_in_node(&["color"], root_id, NUM_POINTS)
+        .is_ok());
+    assert!(octree.verify_node(&root_id).is_err());
+}
+
+#[test]
+fn test_all_nodes_and_points() {
+    let octree = build_test_octree();
+    assert_eq!(octree.nodes(..).count(), octree.nodes.len());
+    assert_eq!(octree.nodes(..1).collect::<Vec<_>>(), [NodeId::root()]);
+    let deeper: Vec<_> = octree.nodes(1..).collect();
+    assert_eq!(deeper.len(), octree.nodes.len() - 1);
+    assert!(deeper.iter().all(|node_id| node_id.level() >= 1));
+
+    let count = |batches: Vec<Result<PointsBatch>>| -> usize {
+        batches
+            .into_iter()
+            .map(|batch| batch.unwrap().position.len())
+            .sum()
+    };
+    assert_eq!(
+        count(octree.all_points(&["color"], None).collect()),
+        NUM_POINTS
+    );
+    assert_eq!(
+        count(octree.par_all_points(&["color"], None).collect()),
+        NUM_POINTS
+    );
+    assert_eq!(
+        count(octree.all_points(&["color"], Some(0)).collect()),
+        octree.nodes[&NodeId::root()].num_points as usize
+    );
+}
+
+#[test]
+fn test_node_columns() {
+    let octree = build_test_octree();
+    let root = NodeId::root();
+    let all = octree.get_node_data(&root).unwrap();
+    let only = |position, color| {
+        octree
+            .get_nodes_columns(&[root], NodeColumns { position, color })
+            .pop()
+            .unwrap()
+            .unwrap()
+    };
+    let position = only(true, false);
+    assert_eq!(position.position, all.position);
+    assert!(position.color.is_empty());
+    let color = only(false, true);
+    assert!(color.position.is_empty());
+    assert_eq!(color.color, all.color);
+    assert_eq!(color.meta.num_points, all.meta.num_points);
+}
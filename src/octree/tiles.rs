@@ -0,0 +1,422 @@
+//! Exports the whole level of detail pyramid of an octree into the formats of other viewers, so
+//! that the exported data streams like the original instead of being loaded at once. Every node
+//! becomes a tile with the points of the node. Like the nodes, the tiles refine additively: a tile
+//! is drawn together with its ancestors.
+
+use crate::color::ColorSpace;
+use crate::errors::*;
+use crate::geometry::{Aabb, Cube};
+use crate::iterator::PointCloud;
+use crate::octree::{NodeId, Octree, Subdivision};
+use crate::read_write::{Encoding, LasNodeWriter, NodeWriter, OpenMode};
+use crate::utils::create_progress_bar;
+use crate::NUM_POINTS_PER_BATCH;
+use byteorder::{LittleEndian, WriteBytesExt};
+use nalgebra::Vector3;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Number of levels of the Potree hierarchy that are stored in one '.hrc' file.
+const POTREE_HIERARCHY_STEP_SIZE: u8 = 5;
+
+/// Size of the header of a 3D Tiles '.pnts' file.
+const PNTS_HEADER_SIZE: usize = 28;
+
+/// The format of the exported tiles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileFormat {
+    /// Cesium 3D Tiles 1.0: a 'tileset.json' and a point cloud tile ('.pnts') per node.
+    Tiles3d,
+    /// Potree 1.7: a 'cloud.js', a LAS file per node and the hierarchy in '.hrc' files.
+    Potree,
+}
+
+impl FromStr for TileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "3dtiles" => Ok(TileFormat::Tiles3d),
+            "potree" => Ok(TileFormat::Potree),
+            _ => Err(format!(
+                "Unknown tile format '{}', expected '3dtiles' or 'potree'.",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TileExportOptions {
+    pub format: TileFormat,
+    /// Only export the nodes up to this level, which become the leaves of the exported pyramid.
+    pub max_lod: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TileExportStats {
+    pub num_tiles: usize,
+    pub num_points: i64,
+}
+
+/// Writes the nodes of 'octree' up to 'options.max_lod' as tiles in 'options.format' into
+/// 'output_directory'.
+///
+/// The levels of detail map onto the target formats as follows. In 3D Tiles, the geometric error
+/// of a tile is the edge length of the cubes of its children, so that a viewer refines a tile
+/// once its children appear larger than its maximum screen space error, just like the
+/// 'ScreenSpaceError' selects a node once it appears larger than the pixel threshold. Potree
+/// derives the nodes from the bounding cube of the root and the node names, which are the same as
+/// the ids of the nodes, so its levels are the levels of the octree.
+pub fn export_tiles(
+    octree: &Octree,
+    output_directory: impl AsRef<Path>,
+    options: &TileExportOptions,
+) -> Result<TileExportStats> {
+    let output_directory = output_directory.as_ref();
+    if !octree.nodes.contains_key(&NodeId::root()) {
+        return Err(Error::InvalidInput("The octree has no nodes.".to_string()));
+    }
+    if options.format == TileFormat::Potree && octree.meta.subdivision != Subdivision::Octree {
+        return Err(Error::InvalidInput(
+            "Potree needs nodes that are split into eight children, not a quadtree.".to_string(),
+        ));
+    }
+    fs::create_dir_all(output_directory)
+        .with_context(|| format!("Could not create {}.", output_directory.display()))?;
+    let max_level = options.max_lod.unwrap_or(NodeId::MAX_LEVEL);
+    let node_ids: Vec<NodeId> = octree.nodes(..=max_level).collect();
+
+    let mut stats = TileExportStats::default();
+    let mut progress_bar = create_progress_bar(node_ids.len(), "Writing tiles");
+    for node_id in &node_ids {
+        stats.num_points += match options.format {
+            TileFormat::Tiles3d => write_pnts(
+                octree,
+                *node_id,
+                &output_directory.join(format!("{}.pnts", node_id)),
+            )?,
+            TileFormat::Potree => {
+                write_potree_node(octree, *node_id, max_level, &output_directory.join("data"))?
+            }
+        };
+        stats.num_tiles += 1;
+        progress_bar.inc();
+    }
+    progress_bar.finish();
+
+    let (path, index) = match options.format {
+        TileFormat::Tiles3d => (
+            output_directory.join("tileset.json"),
+            json!({
+                "asset": { "version": "1.0", "generator": "point_viewer" },
+                // The error of drawing nothing at all.
+                "geometricError": octree.nodes[&NodeId::root()].bounding_cube.edge_length(),
+                "root": tile_json(octree, NodeId::root(), max_level),
+            }),
+        ),
+        TileFormat::Potree => (
+            output_directory.join("cloud.js"),
+            potree_cloud_json(octree, stats.num_points),
+        ),
+    };
+    fs::write(&path, serde_json::to_string_pretty(&index).unwrap())
+        .with_context(|| format!("Could not write {}.", path.display()))?;
+    Ok(stats)
+}
+
+/// The children of 'node_id' that are exported, in the order of their 'ChildIndex'.
+fn exported_children(octree: &Octree, node_id: NodeId, max_level: u8) -> Vec<NodeId> {
+    if node_id.level() >= max_level {
+        return Vec::new();
+    }
+    node_id
+        .children()
+        .filter(|child_id| octree.nodes.contains_key(child_id))
+        .collect()
+}
+
+fn tile_json(octree: &Octree, node_id: NodeId, max_level: u8) -> serde_json::Value {
+    let cube = &octree.nodes[&node_id].bounding_cube;
+    let children = exported_children(octree, node_id, max_level);
+    let geometric_error = children
+        .iter()
+        .map(|child_id| octree.nodes[child_id].bounding_cube.edge_length())
+        .fold(0., f64::max);
+    let center = cube.center();
+    let half_edge = cube.edge_length() / 2.;
+    let mut tile = json!({
+        "boundingVolume": {
+            "box": [
+                center.x, center.y, center.z,
+                half_edge, 0., 0.,
+                0., half_edge, 0.,
+                0., 0., half_edge,
+            ],
+        },
+        "geometricError": geometric_error,
+        "content": { "uri": format!("{}.pnts", node_id) },
+    });
+    if node_id == NodeId::root() {
+        // Inherited by all tiles.
+        tile["refine"] = json!("ADD");
+    }
+    if !children.is_empty() {
+        tile["children"] = children
+            .into_iter()
+            .map(|child_id| tile_json(octree, child_id, max_level))
+            .collect();
+    }
+    tile
+}
+
+/// Writes the points of 'node_id' as a 3D Tiles point cloud tile with sRGB colors. The positions
+/// are stored as floats relative to the center of the node. Returns the number of points.
+fn write_pnts(octree: &Octree, node_id: NodeId, path: &Path) -> Result<i64> {
+    let center = octree.nodes[&node_id].bounding_cube.center();
+    let mut positions = Vec::new();
+    let mut colors = Vec::new();
+    for batch in octree.points_in_node(&["color"], node_id, NUM_POINTS_PER_BATCH)? {
+        for p in &batch.position {
+            for (value, center) in p.coords.iter().zip(center.iter()) {
+                positions.write_f32::<LittleEndian>((value - center) as f32)?;
+            }
+        }
+        let color: &Vec<Vector3<u8>> = batch
+            .get_attribute_vec("color")
+            .map_err(Error::SchemaMismatch)?;
+        colors.extend(color.iter().flat_map(|c| c.iter().copied()));
+    }
+    octree
+        .color_space()
+        .convert_u8(ColorSpace::Srgb, colors.iter_mut());
+    let num_points = colors.len() / 3;
+
+    // The binary body of the feature table must start and end at multiples of 8 bytes.
+    let mut feature_table = json!({
+        "POINTS_LENGTH": num_points,
+        "RTC_CENTER": [center.x, center.y, center.z],
+        "POSITION": { "byteOffset": 0 },
+        "RGB": { "byteOffset": positions.len() },
+    })
+    .to_string()
+    .into_bytes();
+    while (PNTS_HEADER_SIZE + feature_table.len()) % 8 != 0 {
+        feature_table.push(b' ');
+    }
+    let mut body = positions;
+    body.extend(colors);
+    while body.len() % 8 != 0 {
+        body.push(0);
+    }
+
+    let mut bytes = Vec::with_capacity(PNTS_HEADER_SIZE + feature_table.len() + body.len());
+    bytes.extend_from_slice(b"pnts");
+    for value in &[
+        1,
+        PNTS_HEADER_SIZE + feature_table.len() + body.len(),
+        feature_table.len(),
+        body.len(),
+        // The batch table is empty.
+        0,
+        0,
+    ] {
+        bytes.write_u32::<LittleEndian>(*value as u32)?;
+    }
+    bytes.extend(feature_table);
+    bytes.extend(body);
+    fs::write(path, bytes).with_context(|| format!("Could not write {}.", path.display()))?;
+    Ok(num_points as i64)
+}
+
+/// The directory of a node below the 'data' directory: every 'POTREE_HIERARCHY_STEP_SIZE' levels
+/// start a new one, e.g. 'r/01234' for 'r012345'.
+fn potree_hierarchy_path(node_id: NodeId) -> PathBuf {
+    let name = node_id.to_string();
+    let step_size = usize::from(POTREE_HIERARCHY_STEP_SIZE);
+    let mut path = PathBuf::from("r");
+    for chunk in 0..(name.len() - 1) / step_size {
+        path.push(&name[1 + chunk * step_size..1 + (chunk + 1) * step_size]);
+    }
+    path
+}
+
+/// Writes the points of 'node_id' as LAS file with sRGB colors into its hierarchy directory below
+/// 'data_directory', and its part of the hierarchy if it starts a new one. Returns the number of
+/// points.
+fn write_potree_node(
+    octree: &Octree,
+    node_id: NodeId,
+    max_level: u8,
+    data_directory: &Path,
+) -> Result<i64> {
+    let directory = data_directory.join(potree_hierarchy_path(node_id));
+    fs::create_dir_all(&directory)
+        .with_context(|| format!("Could not create {}.", directory.display()))?;
+    if node_id.level() % POTREE_HIERARCHY_STEP_SIZE == 0 {
+        let path = directory.join(format!("{}.hrc", node_id));
+        fs::write(&path, potree_hierarchy(octree, node_id, max_level))
+            .with_context(|| format!("Could not write {}.", path.display()))?;
+    }
+
+    let attributes: Vec<&str> = ["color", "intensity"]
+        .iter()
+        .copied()
+        .filter(|name| octree.meta.attribute_data_types.contains_key(*name))
+        .collect();
+    let mut num_points = 0;
+    let mut writer = LasNodeWriter::new(
+        directory.join(format!("{}.las", node_id)),
+        Encoding::Plain,
+        OpenMode::Truncate,
+    );
+    for mut batch in octree.points_in_node(&attributes, node_id, NUM_POINTS_PER_BATCH)? {
+        if let Ok(color) = batch.get_attribute_vec_mut::<Vector3<u8>>("color") {
+            octree.color_space().convert_u8(
+                ColorSpace::Srgb,
+                color.iter_mut().flat_map(|c| c.iter_mut()),
+            );
+        }
+        writer.write(&batch)?;
+        num_points += batch.position.len() as i64;
+    }
+    writer.finish()?;
+    Ok(num_points)
+}
+
+/// The '.hrc' file of 'node_id': the nodes of the next 'POTREE_HIERARCHY_STEP_SIZE' levels in
+/// breadth first order, each as the mask of its children and its number of points. The nodes of
+/// the last level start their own files.
+fn potree_hierarchy(octree: &Octree, node_id: NodeId, max_level: u8) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(node_id);
+    while let Some(id) = queue.pop_front() {
+        let children = exported_children(octree, id, max_level);
+        let child_mask = children.iter().fold(0u8, |mask, child_id| {
+            mask | 1 << child_id.child_index().unwrap().as_u8()
+        });
+        bytes.push(child_mask);
+        bytes
+            .write_u32::<LittleEndian>(octree.nodes[&id].num_points as u32)
+            .unwrap();
+        if id.level() - node_id.level() < POTREE_HIERARCHY_STEP_SIZE {
+            queue.extend(children);
+        }
+    }
+    bytes
+}
+
+fn potree_box_json(aabb: &Aabb) -> serde_json::Value {
+    let (min, max) = (aabb.min(), aabb.max());
+    json!({
+        "lx": min.x, "ly": min.y, "lz": min.z,
+        "ux": max.x, "uy": max.y, "uz": max.z,
+    })
+}
+
+fn potree_cloud_json(octree: &Octree, num_points: i64) -> serde_json::Value {
+    let root = &octree.nodes[&NodeId::root()];
+    let root_cube: &Cube = &root.bounding_cube;
+    // Potree halves the spacing with every level. The points of a node cover roughly a surface
+    // through its cube.
+    let spacing = root_cube.edge_length() / (root.num_points.max(1) as f64).sqrt();
+    json!({
+        "version": "1.7",
+        "octreeDir": "data",
+        "projection": "",
+        "points": num_points,
+        "boundingBox": potree_box_json(&root_cube.to_aabb()),
+        "tightBoundingBox": potree_box_json(&octree.meta.bounding_box),
+        "pointAttributes": "LAS",
+        "spacing": spacing,
+        "scale": 0.001,
+        "hierarchyStepSize": POTREE_HIERARCHY_STEP_SIZE,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::Point3;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_potree_hierarchy_path() {
+        let path = |name: &str| potree_hierarchy_path(name.parse().unwrap());
+        assert_eq!(path("r"), PathBuf::from("r"));
+        assert_eq!(path("r0123"), PathBuf::from("r"));
+        assert_eq!(path("r01234"), PathBuf::from("r/01234"));
+        assert_eq!(path("r0123456701"), PathBuf::from("r/01234/56701"));
+    }
+
+    #[test]
+    fn test_export_tiles() {
+        // Enough points at the origin that the root is split, and one far away from them.
+        let mut position = vec![Point3::origin(); 100_000];
+        position.push(Point3::new(-200., -40., 30.));
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let octree_dir = TempDir::new("octree").unwrap();
+        let octree = build_octree_of_batch(octree_dir.path(), 1., batch, &BuildOptions::default());
+        let num_points: i64 = octree.nodes.values().map(|node| node.num_points).sum();
+
+        let tmp_dir = TempDir::new("tiles").unwrap();
+        let options = TileExportOptions {
+            format: TileFormat::Tiles3d,
+            max_lod: None,
+        };
+        let stats = export_tiles(&octree, tmp_dir.path(), &options).unwrap();
+        assert_eq!(stats.num_tiles, octree.nodes.len());
+        assert_eq!(stats.num_points, num_points);
+        let tileset: serde_json::Value =
+            serde_json::from_slice(&fs::read(tmp_dir.path().join("tileset.json")).unwrap())
+                .unwrap();
+        assert_eq!(tileset["root"]["refine"], "ADD");
+        assert_eq!(tileset["root"]["content"]["uri"], "r.pnts");
+        // The root is refined once its children are larger on screen than the maximum error.
+        let child_edge_length = tileset["root"]["children"][0]["boundingVolume"]["box"][3]
+            .as_f64()
+            .unwrap()
+            * 2.;
+        assert_eq!(tileset["root"]["geometricError"], child_edge_length);
+        let pnts = fs::read(tmp_dir.path().join("r.pnts")).unwrap();
+        assert_eq!(&pnts[..4], b"pnts");
+        assert_eq!(pnts.len() % 8, 0);
+        assert_eq!(
+            u32::from_le_bytes([pnts[8], pnts[9], pnts[10], pnts[11]]) as usize,
+            pnts.len()
+        );
+
+        let tmp_dir = TempDir::new("potree").unwrap();
+        let options = TileExportOptions {
+            format: TileFormat::Potree,
+            max_lod: Some(1),
+        };
+        let stats = export_tiles(&octree, tmp_dir.path(), &options).unwrap();
+        assert_eq!(stats.num_tiles, octree.nodes(..=1).count());
+        assert!(tmp_dir.path().join("cloud.js").exists());
+        assert!(tmp_dir.path().join("data/r/r.las").exists());
+        let hierarchy = fs::read(tmp_dir.path().join("data/r/r.hrc")).unwrap();
+        // The root and its children, which are the leaves of the export.
+        assert_eq!(hierarchy.len(), 5 * stats.num_tiles);
+        assert_eq!(
+            u32::from_le_bytes([hierarchy[1], hierarchy[2], hierarchy[3], hierarchy[4]]),
+            octree.nodes[&NodeId::root()].num_points as u32
+        );
+        assert!(hierarchy[5..].chunks(5).all(|node| node[0] == 0));
+    }
+}
@@ -0,0 +1,211 @@
+//! Cuts the part of an octree inside a region out into a new octree, e.g. to ship a subset of a
+//! country-scale dataset to a contractor, without extracting the points and building it again.
+
+use crate::data_provider::OnDiskDataProvider;
+use crate::errors::*;
+use crate::iterator::{PointCloud, PointLocation, PointQuery};
+use crate::octree::{
+    compute_checksums, to_meta_proto, to_node_proto, write_meta, NodeId, NodeSchema, Octree,
+    OctreeLock, KD_INDEX,
+};
+use crate::read_write::{AttributeCompression, NodeWriter, OpenMode, RawNodeWriter};
+use crate::utils::create_progress_bar;
+use crate::NUM_POINTS_PER_BATCH;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrimStats {
+    pub num_nodes: usize,
+    /// Nodes on the boundary of the region, which lost some of their points.
+    pub num_clipped_nodes: usize,
+    pub num_points: i64,
+}
+
+/// Writes the nodes of 'octree' that intersect 'location' into 'output_directory', which must be
+/// different from the directory of the octree. The nodes on the boundary of the region are
+/// clipped to the points inside it, and nodes that are left without points are dropped unless
+/// they have children. The nodes keep their ids and encodings, and the octree keeps its bounding
+/// box, which the bounding cubes of the nodes are derived from.
+pub fn trim_octree(
+    octree: &Octree,
+    output_directory: impl AsRef<Path>,
+    location: &PointLocation,
+) -> Result<TrimStats> {
+    let mut attributes: Vec<&str> = octree
+        .meta
+        .attribute_data_types
+        .keys()
+        .map(String::as_str)
+        .collect();
+    attributes.sort();
+    let query = PointQuery {
+        attributes,
+        location: location.clone(),
+        ..Default::default()
+    };
+
+    fs::create_dir_all(output_directory.as_ref())
+        .with_context(|| "Could not create output directory.")?;
+    let _lock = OctreeLock::write(output_directory.as_ref())?;
+    let output_data_provider = OnDiskDataProvider {
+        directory: output_directory.as_ref().to_path_buf(),
+    };
+
+    // Children are trimmed before their parents, so that a parent without points in the region is
+    // only kept if some of its children are.
+    let mut node_ids = octree.nodes_in_location(location);
+    node_ids.sort_by_key(|node_id| cmp::Reverse(node_id.level()));
+    let mut kept = HashSet::new();
+    let mut stats = TrimStats::default();
+    let mut nodes = Vec::new();
+    let mut progress_bar = create_progress_bar(node_ids.len(), "Trimming nodes");
+    for node_id in node_ids {
+        progress_bar.inc();
+        let node_meta = &octree.nodes[&node_id];
+        let mut batches = Vec::new();
+        let mut num_points = 0;
+        octree.stream_points_for_query_in_node(&query, node_id, NUM_POINTS_PER_BATCH, |batch| {
+            num_points += batch.position.len() as i64;
+            batches.push(batch);
+            Ok(())
+        })?;
+        if num_points == 0 && !node_id.children().any(|child_id| kept.contains(&child_id)) {
+            continue;
+        }
+        kept.insert(node_id);
+
+        let stem = output_data_provider.stem(&node_id.to_string());
+        let attribute_compression = if node_meta.compressed_attributes {
+            AttributeCompression::Compressed(HashMap::new())
+        } else {
+            AttributeCompression::Uncompressed
+        };
+        let mut writer =
+            RawNodeWriter::new(&stem, octree.encoding_for_node(node_id), OpenMode::Truncate)
+                .with_attribute_compression(attribute_compression);
+        for batch in &batches {
+            writer.write(batch)?;
+        }
+        writer.finish()?;
+        // Nodes inside the region keep all points in their order, and with it their kd-tree.
+        let has_kd_index =
+            num_points == node_meta.num_points && copy_kd_index(octree, node_id, &stem)?;
+        let checksums = if num_points > 0 {
+            let schema = NodeSchema {
+                num_points,
+                bounding_cube: node_meta.bounding_cube.clone(),
+                position_encoding: node_meta.position_encoding.clone(),
+                compressed_attributes: node_meta.compressed_attributes,
+                attribute_data_types: octree.meta.attribute_data_types.clone(),
+            };
+            schema.write(&stem)?;
+            let files: Vec<&str> = std::iter::once("position")
+                .chain(query.attributes.iter().copied())
+                .chain(Some(KD_INDEX).filter(|_| has_kd_index))
+                .collect();
+            compute_checksums(&output_data_provider, &node_id.to_string(), &files)?
+        } else {
+            HashMap::new()
+        };
+        nodes.push(to_node_proto(
+            &node_id,
+            num_points,
+            &node_meta.position_encoding,
+            node_meta.compressed_attributes,
+            checksums,
+            node_meta.normal_cone,
+            node_meta.occupancy,
+        ));
+        stats.num_nodes += 1;
+        if num_points < node_meta.num_points {
+            stats.num_clipped_nodes += 1;
+        }
+        stats.num_points += num_points;
+    }
+    progress_bar.finish();
+
+    if nodes.is_empty() {
+        return Err(Error::InvalidInput(
+            "No points of the octree are inside the region.".to_string(),
+        ));
+    }
+    write_meta(output_directory, &to_meta_proto(&octree.meta, nodes))?;
+    Ok(stats)
+}
+
+/// Copies the kd-tree of 'node_id' next to the node at 'stem'. Returns false if it has none.
+fn copy_kd_index(octree: &Octree, node_id: NodeId, stem: &Path) -> Result<bool> {
+    let mut reads = match octree.data_provider.data(&node_id.to_string(), &[KD_INDEX]) {
+        Ok(reads) => reads,
+        Err(Error::NodeNotFound) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let mut bytes = Vec::new();
+    reads.remove(KD_INDEX).unwrap().read_to_end(&mut bytes)?;
+    let path = stem.with_extension(KD_INDEX);
+    fs::write(&path, bytes).with_context(|| format!("Could not write {}.", path.display()))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Aabb;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_trim_octree() {
+        let mut position = vec![Point3::origin(); 16];
+        position.push(Point3::new(-200., -40., 30.));
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let octree_dir = TempDir::new("octree").unwrap();
+        let octree = build_octree_of_batch(octree_dir.path(), 1., batch, &BuildOptions::default());
+
+        // All points but the one at (-200, -40, 30).
+        let location = PointLocation::Aabb(Aabb::new(
+            Point3::new(-1., -1., -1.),
+            Point3::new(1., 1., 1.),
+        ));
+        let tmp_dir = TempDir::new("trimmed").unwrap();
+        let stats = trim_octree(&octree, tmp_dir.path(), &location).unwrap();
+        assert_eq!(stats.num_points, 16);
+        assert!(stats.num_clipped_nodes <= 1);
+
+        let trimmed = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: tmp_dir.path().to_path_buf(),
+        }))
+        .unwrap();
+        assert_eq!(trimmed.nodes.len(), stats.num_nodes);
+        assert_eq!(trimmed.num_points(), 16);
+        for node_id in trimmed.nodes.keys() {
+            assert!(trimmed.verify_node(node_id).unwrap());
+            assert_eq!(
+                trimmed.nodes[node_id].bounding_cube.min(),
+                octree.nodes[node_id].bounding_cube.min()
+            );
+        }
+
+        let outside = PointLocation::Aabb(Aabb::new(
+            Point3::new(10., 10., 10.),
+            Point3::new(11., 11., 11.),
+        ));
+        let tmp_dir = TempDir::new("empty").unwrap();
+        assert!(trim_octree(&octree, tmp_dir.path(), &outside).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Keeps the history of octrees that are changed in place. Every change becomes a new version with
+//! an entry in an append-only log. The node files it replaces or removes are moved into the
+//! directory of the version before, so that every version can still be opened with
+//! 'VersionedDataProvider'. Versioning is opt-in, see 'enable_versioning'.
+
+use crate::data_provider::VERSIONS_DIRECTORY;
+use crate::errors::*;
+use crate::octree::OctreeLock;
+use crate::META_FILENAME;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Append-only log with a JSON 'VersionEntry' per line.
+const LOG_FILENAME: &str = "log.jsonl";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// The state of the octree when versioning was enabled.
+    Import,
+    Compact,
+    RewriteAttributes,
+    SetViewSettings,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Import => write!(formatter, "import"),
+            Operation::Compact => write!(formatter, "compact"),
+            Operation::RewriteAttributes => write!(formatter, "rewrite_attributes"),
+            Operation::SetViewSettings => write!(formatter, "set_view_settings"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VersionEntry {
+    /// Versions are numbered from 1 without gaps.
+    pub version: u64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub operation: Operation,
+    pub description: String,
+}
+
+fn versions_directory(directory: &Path) -> PathBuf {
+    directory.join(VERSIONS_DIRECTORY)
+}
+
+pub fn is_versioned(directory: impl AsRef<Path>) -> bool {
+    versions_directory(directory.as_ref())
+        .join(LOG_FILENAME)
+        .exists()
+}
+
+/// Returns all versions of the octree in 'directory', oldest first.
+pub fn read_version_log(directory: impl AsRef<Path>) -> Result<Vec<VersionEntry>> {
+    let path = versions_directory(directory.as_ref()).join(LOG_FILENAME);
+    let file = File::open(&path).with_context(|| format!("Could not open {}.", path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .map(|line| -> Result<VersionEntry> {
+            serde_json::from_str(&line?).map_err(|e| {
+                Error::InvalidInput(format!("Corrupt version log {}: {}", path.display(), e))
+            })
+        })
+        .collect()
+}
+
+/// Snapshots the meta data and appends the entry for 'version'.
+fn record_version(
+    directory: &Path,
+    version: u64,
+    operation: Operation,
+    description: String,
+) -> Result<()> {
+    let version_directory = versions_directory(directory).join(version.to_string());
+    fs::create_dir_all(&version_directory)
+        .with_context(|| format!("Could not create {}.", version_directory.display()))?;
+    // Readers take the meta data of a version as the sign that it exists, so it must appear
+    // atomically, see 'data_provider::version_exists'.
+    let tmp_path = version_directory
+        .join(META_FILENAME)
+        .with_extension("pb.tmp");
+    fs::copy(directory.join(META_FILENAME), &tmp_path)?;
+    fs::rename(&tmp_path, version_directory.join(META_FILENAME))?;
+    let entry = VersionEntry {
+        version,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0),
+        operation,
+        description,
+    };
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(versions_directory(directory).join(LOG_FILENAME))?;
+    let line = serde_json::to_string(&entry).map_err(std::io::Error::from)?;
+    writeln!(log, "{}", line)?;
+    log.sync_all()?;
+    Ok(())
+}
+
+/// Starts the history of the octree in 'directory' with its current state as version 1.
+pub fn enable_versioning(directory: impl AsRef<Path>) -> Result<()> {
+    let directory = directory.as_ref();
+    let _lock = OctreeLock::write(directory)?;
+    if is_versioned(directory) {
+        return Err(Error::InvalidInput(format!(
+            "The octree in {} is already versioned.",
+            directory.display()
+        )));
+    }
+    record_version(
+        directory,
+        1,
+        Operation::Import,
+        "Enabled versioning.".to_string(),
+    )
+}
+
+/// An in-place change of a versioned octree. Files of the current version must be passed to
+/// 'archive' before they are replaced or removed, and 'commit' must be called after the new meta
+/// data was written. If the change is interrupted, the octree can still be opened as of the
+/// current version.
+pub struct Transaction {
+    directory: PathBuf,
+    /// The version that is being changed.
+    version: u64,
+    operation: Operation,
+}
+
+impl Transaction {
+    /// Starts a change of the octree in 'directory'. Returns None if the octree is not versioned.
+    pub fn begin(directory: impl AsRef<Path>, operation: Operation) -> Result<Option<Self>> {
+        let directory = directory.as_ref();
+        if !is_versioned(directory) {
+            return Ok(None);
+        }
+        let version = read_version_log(directory)?
+            .last()
+            .map_or(0, |entry| entry.version);
+        Ok(Some(Self {
+            directory: directory.to_path_buf(),
+            version,
+            operation,
+        }))
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Moves 'path', a file in the octree directory, into the directory of the current version.
+    pub fn archive(&self, path: &Path) -> Result<()> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| Error::InvalidInput(format!("{} is not a file.", path.display())))?;
+        let archived = versions_directory(&self.directory)
+            .join(self.version.to_string())
+            .join(file_name);
+        if archived.exists() {
+            // An earlier, interrupted change already archived the file of the current version, so
+            // 'path' was written by that change and never belonged to a version.
+            fs::remove_file(path)?;
+        } else {
+            fs::rename(path, &archived)
+                .with_context(|| format!("Could not archive {}.", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Records the change as the next version and returns it.
+    pub fn commit(self, description: impl Into<String>) -> Result<u64> {
+        let version = self.version + 1;
+        record_version(&self.directory, version, self.operation, description.into())?;
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_provider::{latest_version, VersionedDataProvider};
+    use crate::iterator::PointCloud;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::{compact_octree, BuildOptions, Octree};
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_versioned_compaction() {
+        let mut position = vec![Point3::origin(); 16];
+        position.push(Point3::new(-200., -40., 30.));
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        // Without checksums, so that the root can be changed in place.
+        let options = BuildOptions {
+            checksums: false,
+            ..Default::default()
+        };
+        build_octree_of_batch(tmp_dir.path(), 1., batch, &options);
+        enable_versioning(tmp_dir.path()).unwrap();
+        let root_positions = tmp_dir.path().join("r.xyz");
+        let mut positions = fs::read(&root_positions).unwrap();
+        positions.extend_from_slice(&[0; 12]);
+        fs::write(&root_positions, &positions).unwrap();
+        let snapshot = Octree::from_data_provider(Box::new(
+            VersionedDataProvider::latest(tmp_dir.path()).unwrap(),
+        ))
+        .unwrap();
+        let count_points = |octree: &Octree| -> usize {
+            octree
+                .points_in_node(&["color"], "r".parse().unwrap(), 1000)
+                .unwrap()
+                .map(|batch| batch.position.len())
+                .sum()
+        };
+        let num_root_points = count_points(&snapshot);
+
+        assert_eq!(compact_octree(tmp_dir.path()).unwrap().reclaimed_bytes, 12);
+        assert_eq!(latest_version(tmp_dir.path()), Some(2));
+        // The snapshot opened before the compaction still reads the files of its version.
+        assert_eq!(count_points(&snapshot), num_root_points);
+        let log = read_version_log(tmp_dir.path()).unwrap();
+        let operations: Vec<_> = log
+            .iter()
+            .map(|entry| (entry.version, entry.operation))
+            .collect();
+        assert_eq!(
+            operations,
+            [(1, Operation::Import), (2, Operation::Compact)]
+        );
+        assert_eq!(
+            fs::read(tmp_dir.path().join("versions/1/r.xyz")).unwrap(),
+            positions
+        );
+
+        for version in 1..=2 {
+            let data_provider = VersionedDataProvider::new(tmp_dir.path(), version).unwrap();
+            let octree = Octree::from_data_provider(Box::new(data_provider)).unwrap();
+            assert_eq!(count_points(&octree), num_root_points);
+        }
+        assert!(VersionedDataProvider::new(tmp_dir.path(), 3).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+//! Suggested settings for viewers that open an octree, stored in its meta data, so that published
+//! datasets open looking right without manual tweaking.
+
+#[cfg(feature = "native")]
+use crate::data_provider::{DataProvider, OnDiskDataProvider};
+#[cfg(feature = "native")]
+use crate::errors::*;
+#[cfg(feature = "native")]
+use crate::octree::{write_meta, OctreeLock, Operation, Transaction};
+use crate::proto;
+#[cfg(feature = "native")]
+use crate::CURRENT_VERSION;
+use nalgebra::{Isometry3, UnitQuaternion, Vector3};
+#[cfg(feature = "native")]
+use std::path::Path;
+use std::str::FromStr;
+
+/// The axis that points up in the octree frame. Viewers turn the camera around it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpAxis {
+    Z,
+    Y,
+}
+
+impl Default for UpAxis {
+    fn default() -> Self {
+        UpAxis::Z
+    }
+}
+
+impl FromStr for UpAxis {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "z" => Ok(UpAxis::Z),
+            "y" => Ok(UpAxis::Y),
+            _ => Err(format!("Unknown up axis '{}', expected 'z' or 'y'.", s)),
+        }
+    }
+}
+
+impl UpAxis {
+    pub fn from_proto(proto: proto::UpAxis) -> Self {
+        match proto {
+            proto::UpAxis::Z_UP => UpAxis::Z,
+            proto::UpAxis::Y_UP => UpAxis::Y,
+        }
+    }
+
+    pub fn to_proto(self) -> proto::UpAxis {
+        match self {
+            UpAxis::Z => proto::UpAxis::Z_UP,
+            UpAxis::Y => proto::UpAxis::Y_UP,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UpAxis::Z => "z",
+            UpAxis::Y => "y",
+        }
+    }
+
+    /// The index of this axis in points and vectors.
+    pub fn index(self) -> usize {
+        match self {
+            UpAxis::Z => 2,
+            UpAxis::Y => 1,
+        }
+    }
+
+    /// The rotation about the x axis from a frame with this up axis into a frame with 'up_axis'.
+    /// From Y-up, as used by modeling tools, to Z-up, y becomes z and z becomes -y.
+    pub fn rotation_to(self, up_axis: UpAxis) -> UnitQuaternion<f64> {
+        let angle = match (self, up_axis) {
+            (UpAxis::Y, UpAxis::Z) => std::f64::consts::FRAC_PI_2,
+            (UpAxis::Z, UpAxis::Y) => -std::f64::consts::FRAC_PI_2,
+            _ => 0.,
+        };
+        UnitQuaternion::from_axis_angle(&Vector3::x_axis(), angle)
+    }
+}
+
+/// What viewers show as the color of the points.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    /// The attribute 'color'.
+    Color,
+    /// A color per input file, see 'Octree::get_node_source_colors'.
+    SourceId,
+}
+
+impl Default for ColorMode {
+    fn default() -> Self {
+        ColorMode::Color
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "color" => Ok(ColorMode::Color),
+            "source_id" => Ok(ColorMode::SourceId),
+            _ => Err(format!(
+                "Unknown color mode '{}', expected 'color' or 'source_id'.",
+                s
+            )),
+        }
+    }
+}
+
+impl ColorMode {
+    pub fn from_proto(proto: proto::ColorMode) -> Self {
+        match proto {
+            proto::ColorMode::RGB => ColorMode::Color,
+            proto::ColorMode::SOURCE_ID => ColorMode::SourceId,
+        }
+    }
+
+    pub fn to_proto(self) -> proto::ColorMode {
+        match self {
+            ColorMode::Color => proto::ColorMode::RGB,
+            ColorMode::SourceId => proto::ColorMode::SOURCE_ID,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorMode::Color => "color",
+            ColorMode::SourceId => "source_id",
+        }
+    }
+}
+
+/// Settings that viewers start with. Viewers keep their own defaults for what is not set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ViewSettings {
+    /// Pose of the camera in the octree frame. Like in OpenGL, the camera looks along its -z axis
+    /// and its y axis points up on screen.
+    pub octree_from_camera: Option<Isometry3<f64>>,
+    pub up_axis: UpAxis,
+    pub color_mode: ColorMode,
+    /// The maximum number of points that viewers draw at once.
+    pub point_budget: Option<u64>,
+}
+
+impl ViewSettings {
+    pub fn from_proto(proto: &proto::ViewSettings) -> Self {
+        Self {
+            octree_from_camera: if proto.has_octree_from_camera() {
+                Some(Isometry3::from(proto.get_octree_from_camera()))
+            } else {
+                None
+            },
+            up_axis: UpAxis::from_proto(proto.get_up_axis()),
+            color_mode: ColorMode::from_proto(proto.get_color_mode()),
+            point_budget: Some(proto.point_budget).filter(|budget| *budget > 0),
+        }
+    }
+
+    pub fn to_proto(&self) -> proto::ViewSettings {
+        let mut proto = proto::ViewSettings::new();
+        if let Some(octree_from_camera) = &self.octree_from_camera {
+            proto.set_octree_from_camera(proto::Isometry3d::from(octree_from_camera));
+        }
+        proto.set_up_axis(self.up_axis.to_proto());
+        proto.set_color_mode(self.color_mode.to_proto());
+        proto.set_point_budget(self.point_budget.unwrap_or(0));
+        proto
+    }
+}
+
+/// Replaces the view settings in the meta data of the octree in 'directory'. The nodes are not
+/// touched. For versioned octrees, the change becomes a new version.
+#[cfg(feature = "native")]
+pub fn set_view_settings(directory: impl AsRef<Path>, view_settings: &ViewSettings) -> Result<()> {
+    let directory = directory.as_ref();
+    let _lock = OctreeLock::write(directory)?;
+    let data_provider = OnDiskDataProvider {
+        directory: directory.to_path_buf(),
+    };
+    let mut meta = data_provider.meta_proto()?;
+    if meta.version != CURRENT_VERSION {
+        return Err(Error::InvalidVersion(meta.version));
+    }
+    let transaction = Transaction::begin(directory, Operation::SetViewSettings)?;
+    meta.mut_octree()
+        .set_view_settings(view_settings.to_proto());
+    write_meta(directory, &meta)?;
+    if let Some(transaction) = transaction {
+        transaction.commit("Changed the view settings.")?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::{BuildOptions, Octree};
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_up_axis_rotation() {
+        let p = Point3::new(1., 2., 3.);
+        assert_eq!(UpAxis::Z.rotation_to(UpAxis::Z) * p, p);
+        let z_up = UpAxis::Y.rotation_to(UpAxis::Z) * p;
+        assert!((z_up - Point3::new(1., -3., 2.)).norm() < 1e-12);
+        let y_up = UpAxis::Z.rotation_to(UpAxis::Y) * z_up;
+        assert!((y_up - p).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_view_settings_are_stored_in_meta() {
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let batch = PointsBatch {
+            position: vec![Point3::origin(), Point3::new(1., 1., 1.)],
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); 2]),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let octree = build_octree_of_batch(tmp_dir.path(), 0.001, batch, &BuildOptions::default());
+        assert_eq!(&ViewSettings::default(), octree.view_settings());
+
+        let view_settings = ViewSettings {
+            octree_from_camera: Some(Isometry3::translation(1., 2., 3.)),
+            up_axis: UpAxis::Y,
+            color_mode: ColorMode::SourceId,
+            point_budget: Some(500_000),
+        };
+        set_view_settings(tmp_dir.path(), &view_settings).unwrap();
+        let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: tmp_dir.path().to_path_buf(),
+        }))
+        .unwrap();
+        assert_eq!(&view_settings, octree.view_settings());
+    }
+}
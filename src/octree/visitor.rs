@@ -0,0 +1,106 @@
+//! Traversal of the nodes of an octree for custom culling, statistics or exports, so that they do
+//! not have to compute child ids themselves.
+
+use crate::octree::{NodeId, NodeMeta, Octree};
+use std::collections::VecDeque;
+
+/// What to do after visiting a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visit {
+    /// Visit the children of the node.
+    Continue,
+    /// Do not visit the subtree below the node, but continue with the other nodes.
+    SkipChildren,
+    /// End the traversal.
+    Stop,
+}
+
+pub trait NodeVisitor {
+    /// Called with every node that is reached, with its bounding cube and point count in
+    /// 'node_meta'.
+    fn visit_node(&mut self, node_id: &NodeId, node_meta: &NodeMeta) -> Visit;
+}
+
+impl<F: FnMut(&NodeId, &NodeMeta) -> Visit> NodeVisitor for F {
+    fn visit_node(&mut self, node_id: &NodeId, node_meta: &NodeMeta) -> Visit {
+        self(node_id, node_meta)
+    }
+}
+
+impl Octree {
+    /// Calls 'visitor' with the nodes of the octree level by level from the root, children in the
+    /// order of their child index. Returns false if the visitor stopped the traversal.
+    pub fn visit_nodes(&self, visitor: &mut impl NodeVisitor) -> bool {
+        let mut node_ids: VecDeque<NodeId> = Some(NodeId::root())
+            .filter(|root_id| self.nodes.contains_key(root_id))
+            .into_iter()
+            .collect();
+        while let Some(node_id) = node_ids.pop_front() {
+            match visitor.visit_node(&node_id, &self.nodes[&node_id]) {
+                Visit::Continue => node_ids.extend(
+                    node_id
+                        .children()
+                        .filter(|child_id| self.nodes.contains_key(child_id)),
+                ),
+                Visit::SkipChildren => (),
+                Visit::Stop => return false,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::{AttributeData, PointsBatch};
+    use nalgebra::{Point3, Vector3};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_visit_nodes() {
+        // Enough points at the origin that the root and its child at the origin are split, and one
+        // far away from them.
+        let mut position = vec![Point3::origin(); 250_000];
+        position.push(Point3::new(-200., -40., 30.));
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let octree = build_octree_of_batch(tmp_dir.path(), 1., batch, &BuildOptions::default());
+        assert!(octree.nodes.keys().any(|node_id| node_id.level() > 1));
+
+        let mut num_points = 0;
+        assert!(octree.visit_nodes(&mut |_: &NodeId, node_meta: &NodeMeta| {
+            num_points += node_meta.num_points;
+            Visit::Continue
+        }));
+        assert_eq!(num_points, 250_001);
+
+        let mut visited = Vec::new();
+        assert!(octree.visit_nodes(&mut |node_id: &NodeId, _: &NodeMeta| {
+            visited.push(*node_id);
+            if node_id.level() < 1 {
+                Visit::Continue
+            } else {
+                Visit::SkipChildren
+            }
+        }));
+        assert_eq!(visited, octree.nodes(..=1).collect::<Vec<_>>());
+
+        let mut num_visited = 0;
+        assert!(!octree.visit_nodes(&mut |_: &NodeId, _: &NodeMeta| {
+            num_visited += 1;
+            Visit::Stop
+        }));
+        assert_eq!(num_visited, 1);
+    }
+}
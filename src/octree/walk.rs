@@ -0,0 +1,223 @@
+//! Keeps a camera at eye height above the ground of an octree and stops it in front of walls, so
+//! that viewers can walk through a scan, e.g. along a street, instead of flying through it.
+
+use crate::errors::*;
+use crate::geometry::Aabb;
+use crate::iterator::{PointCloud, PointLocation};
+use crate::octree::Octree;
+use crate::{NextInto, PointsBatch, NUM_POINTS_PER_BATCH};
+use nalgebra::{Point3, Vector3};
+
+#[derive(Clone, Debug)]
+pub struct WalkOptions {
+    /// Height of the eyes above the ground.
+    pub eye_height: f64,
+    /// The walker steps onto ground up to this much higher than its feet, e.g. onto curbs and
+    /// stairs. Points higher than this above the feet are obstacles.
+    pub step_height: f64,
+    /// The walker falls onto ground up to this much lower than its feet. Without ground in this
+    /// range, e.g. beyond the edge of the scan, it keeps its height.
+    pub max_drop: f64,
+    /// Horizontal radius of the body of the walker, in which the ground and obstacles are
+    /// searched.
+    pub radius: f64,
+    /// Fewer points than this neither make ground nor obstacles, so that a few stray points, e.g.
+    /// of dust or moving objects, do not block the way.
+    pub min_points: usize,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            eye_height: 1.7,
+            step_height: 0.4,
+            max_drop: 20.,
+            radius: 0.3,
+            min_points: 10,
+        }
+    }
+}
+
+/// The horizontal distance of 'p' from 'position', with 'up' the index of the up axis.
+fn horizontal_distance(p: &Point3<f64>, position: &Point3<f64>, up: usize) -> f64 {
+    let mut offset = p - position;
+    offset[up] = 0.;
+    offset.norm()
+}
+
+/// The height of the ground under the eyes at 'position': the 'min_points'th highest of the
+/// points below the walker that it can step onto.
+fn ground_height(
+    points: &[Point3<f64>],
+    position: &Point3<f64>,
+    up: usize,
+    options: &WalkOptions,
+) -> Option<f64> {
+    let feet = position[up] - options.eye_height;
+    let mut heights: Vec<f64> = points
+        .iter()
+        .filter(|p| {
+            p[up] <= feet + options.step_height
+                && p[up] >= feet - options.max_drop
+                && horizontal_distance(p, position, up) <= options.radius
+        })
+        .map(|p| p[up])
+        .collect();
+    if heights.len() < options.min_points.max(1) {
+        return None;
+    }
+    heights.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    Some(heights[options.min_points.max(1) - 1])
+}
+
+/// Whether enough points are in the body of the walker with the eyes at 'position', between the
+/// step height and the top of the head.
+fn is_blocked(
+    points: &[Point3<f64>],
+    position: &Point3<f64>,
+    up: usize,
+    options: &WalkOptions,
+) -> bool {
+    let feet = position[up] - options.eye_height;
+    let num_points = points
+        .iter()
+        .filter(|p| {
+            p[up] > feet + options.step_height
+                && p[up] <= position[up] + options.radius
+                && horizontal_distance(p, position, up) <= options.radius
+        })
+        .count();
+    num_points >= options.min_points.max(1)
+}
+
+/// Where the eyes of a walker end up among 'points' if it tries to move them from 'from' to 'to'.
+/// They are lifted or lowered to eye height above the ground at 'to', and stay at 'from' if an
+/// obstacle is there. A walker that is already stuck in an obstacle can move freely, so that it
+/// can get out of it.
+fn walk_among(
+    points: &[Point3<f64>],
+    from: &Point3<f64>,
+    to: &Point3<f64>,
+    up: usize,
+    options: &WalkOptions,
+) -> Point3<f64> {
+    let mut target = *to;
+    if let Some(ground) = ground_height(points, to, up, options) {
+        target[up] = ground + options.eye_height;
+    }
+    if is_blocked(points, &target, up, options) && !is_blocked(points, from, up, options) {
+        *from
+    } else {
+        target
+    }
+}
+
+impl Octree {
+    /// Where a walker with its eyes at 'from' ends up if it tries to move them to 'to', following
+    /// the ground and stopping in front of obstacles, see 'WalkOptions'. It reads all nodes
+    /// around both positions, so viewers should only call it when the camera moved.
+    pub fn walk(
+        &self,
+        from: &Point3<f64>,
+        to: &Point3<f64>,
+        options: &WalkOptions,
+    ) -> Result<Point3<f64>> {
+        let up = self.view_settings().up_axis.index();
+        let mut min = from.coords.inf(&to.coords) - Vector3::repeat(options.radius);
+        let mut max = from.coords.sup(&to.coords) + Vector3::repeat(options.radius);
+        min[up] = from[up].min(to[up]) - options.eye_height - options.max_drop;
+        max[up] = from[up].max(to[up]) + options.radius;
+        let aabb = Aabb::new(min.into(), max.into());
+
+        let mut points = Vec::new();
+        let mut batch = PointsBatch::default();
+        for node_id in self.nodes_in_location(&PointLocation::Aabb(aabb.clone())) {
+            let mut node_points = self.points_in_node(&[], node_id, NUM_POINTS_PER_BATCH)?;
+            while node_points.next_into(&mut batch) {
+                points.extend(batch.position.iter().filter(|p| aabb.contains(p)));
+            }
+        }
+        Ok(walk_among(&points, from, to, up, options))
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::octree::tests::build_octree_of_batch;
+    use crate::octree::BuildOptions;
+    use crate::AttributeData;
+    use tempdir::TempDir;
+
+    /// A floor at height 0 with a point every 5 cm, and a wall at x = 2 up to 3 m.
+    fn floor_and_wall() -> Vec<Point3<f64>> {
+        let mut points = Vec::new();
+        for i in 0..80 {
+            for j in 0..80 {
+                points.push(Point3::new(f64::from(i) * 0.05, f64::from(j) * 0.05, 0.));
+            }
+        }
+        for j in 0..80 {
+            for k in 0..60 {
+                points.push(Point3::new(2., f64::from(j) * 0.05, f64::from(k) * 0.05));
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn test_walk_among() {
+        let points = floor_and_wall();
+        let options = WalkOptions::default();
+        let eyes = 0. + options.eye_height;
+
+        // Falls from high above onto the floor.
+        let position = walk_among(
+            &points,
+            &Point3::new(1., 2., 10.),
+            &Point3::new(1., 2., 10.),
+            2,
+            &options,
+        );
+        assert_eq!(Point3::new(1., 2., eyes), position);
+
+        // Walks along the floor, but not into the wall.
+        let from = Point3::new(1., 2., eyes);
+        let to = Point3::new(1.1, 2., eyes + 0.2);
+        assert_eq!(
+            Point3::new(1.1, 2., eyes),
+            walk_among(&points, &from, &to, 2, &options)
+        );
+        let from = Point3::new(1.6, 2., eyes);
+        let to = Point3::new(1.8, 2., eyes);
+        assert_eq!(from, walk_among(&points, &from, &to, 2, &options));
+
+        // Keeps its height beyond the edge of the floor.
+        let from = Point3::new(1., 3.9, eyes);
+        let to = Point3::new(1., 5., eyes);
+        assert_eq!(to, walk_among(&points, &from, &to, 2, &options));
+    }
+
+    #[test]
+    fn test_walk_on_ground() {
+        let position = floor_and_wall();
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        let tmp_dir = TempDir::new("octree").unwrap();
+        let octree = build_octree_of_batch(tmp_dir.path(), 0.001, batch, &BuildOptions::default());
+        let options = WalkOptions::default();
+        let from = Point3::new(1., 2., 10.);
+        let to = Point3::new(1.1, 2., 10.);
+        let position = octree.walk(&from, &to, &options).unwrap();
+        // The positions in the octree are only as precise as its resolution.
+        assert_eq!((1.1, 2.), (position.x, position.y));
+        assert!((position.z - options.eye_height).abs() < 0.01);
+    }
+}
@@ -0,0 +1,401 @@
+//! Vector overlays, e.g. alignments or parcel boundaries, that viewers draw as lines over the
+//! point cloud. They are read from GeoJSON or ASCII DXF files in the coordinate system of the
+//! point cloud.
+
+use crate::errors::*;
+use nalgebra::Point3;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// A line through 'points', or the outline of a polygon if it is closed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Polyline {
+    /// For closed lines, the first point is not repeated at the end.
+    pub points: Vec<Point3<f64>>,
+    pub closed: bool,
+}
+
+/// The lines of one layer, which viewers can show and hide together.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OverlayLayer {
+    pub name: String,
+    pub polylines: Vec<Polyline>,
+}
+
+/// Appends 'polyline' to the layer 'name', which is added after the existing layers if it is new.
+fn add_polyline(layers: &mut Vec<OverlayLayer>, name: &str, polyline: Polyline) {
+    if polyline.points.len() < 2 {
+        return;
+    }
+    match layers.iter_mut().find(|layer| layer.name == name) {
+        Some(layer) => layer.polylines.push(polyline),
+        None => layers.push(OverlayLayer {
+            name: name.to_string(),
+            polylines: vec![polyline],
+        }),
+    }
+}
+
+/// Reads the layers of the GeoJSON ('.geojson' or '.json') or DXF ('.dxf') file at 'path'.
+pub fn read_overlay(path: impl AsRef<Path>) -> Result<Vec<OverlayLayer>> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}.", path.display()))?;
+    match extension.as_deref() {
+        Some("geojson") | Some("json") => {
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let geojson: Value = serde_json::from_str(&contents).map_err(|err| {
+                Error::InvalidInput(format!("{} is no valid JSON: {}", path.display(), err))
+            })?;
+            geojson_layers(&geojson, &name)
+        }
+        Some("dxf") => dxf_layers(&contents),
+        _ => Err(Error::UnsupportedFormat(format!(
+            "Cannot read overlays from {}, use GeoJSON or DXF.",
+            path.display()
+        ))),
+    }
+}
+
+/// The lines of a GeoJSON feature collection, feature or geometry. Features are put into the
+/// layer of their 'layer' property, or into 'default_layer'. Positions without a height are put
+/// at the 'elevation' property of their feature, as written for contour lines, or at 0. Points
+/// are left out.
+pub fn geojson_layers(geojson: &Value, default_layer: &str) -> Result<Vec<OverlayLayer>> {
+    let mut layers = Vec::new();
+    let mut add = |geometry: &Value, layer: &str, elevation: f64| -> Result<()> {
+        let mut polylines = Vec::new();
+        add_geometry(geometry, elevation, &mut polylines)?;
+        for polyline in polylines {
+            add_polyline(&mut layers, layer, polyline);
+        }
+        Ok(())
+    };
+    let features = match geojson["type"].as_str() {
+        Some("FeatureCollection") => geojson["features"]
+            .as_array()
+            .ok_or_else(|| Error::InvalidInput("The collection has no features.".to_string()))?
+            .iter()
+            .collect(),
+        Some("Feature") => vec![geojson],
+        _ => {
+            add(geojson, default_layer, 0.)?;
+            Vec::new()
+        }
+    };
+    for feature in features {
+        let properties = &feature["properties"];
+        add(
+            &feature["geometry"],
+            properties["layer"].as_str().unwrap_or(default_layer),
+            properties["elevation"].as_f64().unwrap_or(0.),
+        )?;
+    }
+    Ok(layers)
+}
+
+fn add_geometry(geometry: &Value, elevation: f64, polylines: &mut Vec<Polyline>) -> Result<()> {
+    let coordinates = &geometry["coordinates"];
+    let arrays = |value: &Value| -> Result<Vec<Value>> {
+        value
+            .as_array()
+            .cloned()
+            .ok_or_else(|| Error::InvalidInput(format!("Invalid coordinates {}.", value)))
+    };
+    match geometry["type"].as_str() {
+        // Features without a location have a null geometry.
+        None | Some("Point") | Some("MultiPoint") => (),
+        Some("LineString") => polylines.push(geojson_polyline(coordinates, elevation, false)?),
+        Some("MultiLineString") => {
+            for line in arrays(coordinates)? {
+                polylines.push(geojson_polyline(&line, elevation, false)?);
+            }
+        }
+        Some("Polygon") => {
+            for ring in arrays(coordinates)? {
+                polylines.push(geojson_polyline(&ring, elevation, true)?);
+            }
+        }
+        Some("MultiPolygon") => {
+            for polygon in arrays(coordinates)? {
+                for ring in arrays(&polygon)? {
+                    polylines.push(geojson_polyline(&ring, elevation, true)?);
+                }
+            }
+        }
+        Some("GeometryCollection") => {
+            for geometry in arrays(&geometry["geometries"])? {
+                add_geometry(&geometry, elevation, polylines)?;
+            }
+        }
+        Some(kind) => {
+            return Err(Error::UnsupportedFormat(format!(
+                "Unknown geometry type '{}'.",
+                kind
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn geojson_polyline(coordinates: &Value, elevation: f64, closed: bool) -> Result<Polyline> {
+    let invalid = || Error::InvalidInput(format!("Invalid coordinates {}.", coordinates));
+    let mut points = coordinates
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|position| {
+            let values: Vec<f64> = position
+                .as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|value| value.as_f64().ok_or_else(invalid))
+                .collect::<Result<_>>()?;
+            match values[..] {
+                [x, y] => Ok(Point3::new(x, y, elevation)),
+                [x, y, z, ..] => Ok(Point3::new(x, y, z)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // The rings of polygons end with their first position.
+    if closed && points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    Ok(Polyline { points, closed })
+}
+
+fn parse_number(value: &str) -> Result<f64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidInput(format!("Invalid number '{}' in DXF entity.", value)))
+}
+
+/// A DXF entity as its type and its group codes with their values.
+struct Entity<'a> {
+    kind: &'a str,
+    groups: Vec<(i32, &'a str)>,
+}
+
+impl<'a> Entity<'a> {
+    fn value(&self, code: i32) -> Option<&'a str> {
+        self.groups
+            .iter()
+            .find(|(group_code, _)| *group_code == code)
+            .map(|(_, value)| *value)
+    }
+
+    fn number(&self, code: i32) -> Result<f64> {
+        self.value(code).map_or(Ok(0.), parse_number)
+    }
+
+    fn layer(&self) -> &'a str {
+        self.value(8).unwrap_or("0")
+    }
+
+    fn is_closed(&self) -> Result<bool> {
+        Ok(self.number(70)? as i32 & 1 != 0)
+    }
+
+    fn point(&self, x_code: i32) -> Result<Point3<f64>> {
+        Ok(Point3::new(
+            self.number(x_code)?,
+            self.number(x_code + 10)?,
+            self.number(x_code + 20)?,
+        ))
+    }
+}
+
+/// The entities of the ENTITIES section of an ASCII DXF.
+fn dxf_entities(dxf: &str) -> Result<Vec<Entity>> {
+    let lines: Vec<&str> = dxf.lines().collect();
+    let mut entities = Vec::new();
+    let mut in_entities = false;
+    for pair in lines.chunks(2) {
+        let (code, value) = match pair {
+            [code, value] => (code.trim(), value.trim()),
+            _ => break,
+        };
+        let code: i32 = code
+            .parse()
+            .map_err(|_| Error::InvalidInput(format!("Invalid DXF group code '{}'.", code)))?;
+        match (code, value) {
+            (2, "ENTITIES") => in_entities = true,
+            (0, "ENDSEC") => in_entities = false,
+            (0, kind) if in_entities => entities.push(Entity {
+                kind,
+                groups: Vec::new(),
+            }),
+            _ if in_entities => {
+                if let Some(entity) = entities.last_mut() {
+                    entity.groups.push((code, value));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(entities)
+}
+
+/// The lines, 2D polylines and 3D polylines of an ASCII DXF on their layers.
+pub fn dxf_layers(dxf: &str) -> Result<Vec<OverlayLayer>> {
+    let mut layers = Vec::new();
+    let entities = dxf_entities(dxf)?;
+    let mut entities = entities.iter();
+    while let Some(entity) = entities.next() {
+        match entity.kind {
+            "LINE" => add_polyline(
+                &mut layers,
+                entity.layer(),
+                Polyline {
+                    points: vec![entity.point(10)?, entity.point(11)?],
+                    closed: false,
+                },
+            ),
+            "LWPOLYLINE" => {
+                let elevation = entity.number(38)?;
+                let mut points = Vec::new();
+                for (code, value) in &entity.groups {
+                    match code {
+                        10 => points.push(Point3::new(parse_number(value)?, 0., elevation)),
+                        20 => {
+                            if let Some(point) = points.last_mut() {
+                                point.y = parse_number(value)?;
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                add_polyline(
+                    &mut layers,
+                    entity.layer(),
+                    Polyline {
+                        points,
+                        closed: entity.is_closed()?,
+                    },
+                );
+            }
+            "POLYLINE" => {
+                // The vertices follow as their own entities up to SEQEND. Those of 2D polylines
+                // are at the elevation of the polyline.
+                let elevation = entity.number(30)?;
+                let is_3d = entity.number(70)? as i32 & 8 != 0;
+                let mut points = Vec::new();
+                for vertex in entities
+                    .by_ref()
+                    .take_while(|vertex| vertex.kind != "SEQEND")
+                {
+                    if vertex.kind == "VERTEX" {
+                        let mut point = vertex.point(10)?;
+                        if !is_3d {
+                            point.z = elevation;
+                        }
+                        points.push(point);
+                    }
+                }
+                add_polyline(
+                    &mut layers,
+                    entity.layer(),
+                    Polyline {
+                        points,
+                        closed: entity.is_closed()?,
+                    },
+                );
+            }
+            _ => (),
+        }
+    }
+    Ok(layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_geojson_layers() {
+        let geojson = json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [[[0., 0.], [1., 0.], [1., 1.], [0., 0.]]],
+                    },
+                    "properties": { "layer": "parcels", "elevation": 5. },
+                },
+                {
+                    "type": "Feature",
+                    "geometry": { "type": "LineString", "coordinates": [[0., 0., 1.], [2., 0., 2.]] },
+                    "properties": {},
+                },
+                {
+                    "type": "Feature",
+                    "geometry": { "type": "Point", "coordinates": [0., 0.] },
+                    "properties": {},
+                },
+            ],
+        });
+        let layers = geojson_layers(&geojson, "alignment").unwrap();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].name, "parcels");
+        let parcel = &layers[0].polylines[0];
+        assert!(parcel.closed);
+        assert_eq!(parcel.points.len(), 3);
+        assert!(parcel.points.iter().all(|p| p.z == 5.));
+        assert_eq!(layers[1].name, "alignment");
+        assert_eq!(
+            layers[1].polylines[0].points,
+            vec![Point3::new(0., 0., 1.), Point3::new(2., 0., 2.)]
+        );
+
+        let invalid = json!({ "type": "LineString", "coordinates": [[0.], [1., 1.]] });
+        assert!(geojson_layers(&invalid, "lines").is_err());
+    }
+
+    #[test]
+    fn test_dxf_layers() {
+        let dxf = "0\nSECTION\n2\nHEADER\n0\nENDSEC\n0\nSECTION\n2\nENTITIES\n\
+                   0\nLINE\n8\naxis\n10\n0.0\n20\n0.0\n30\n0.0\n11\n1.0\n21\n2.0\n31\n3.0\n\
+                   0\nLWPOLYLINE\n8\nparcels\n70\n1\n38\n4.0\n10\n0.0\n20\n0.0\n10\n1.0\n20\n0.0\n\
+                   10\n1.0\n20\n1.0\n\
+                   0\nPOLYLINE\n8\naxis\n66\n1\n70\n8\n10\n0.0\n20\n0.0\n30\n0.0\n\
+                   0\nVERTEX\n8\naxis\n10\n5.0\n20\n5.0\n30\n1.0\n\
+                   0\nVERTEX\n8\naxis\n10\n6.0\n20\n5.0\n30\n2.0\n\
+                   0\nSEQEND\n8\naxis\n0\nENDSEC\n0\nEOF\n";
+        let layers = dxf_layers(dxf).unwrap();
+        let names: Vec<&str> = layers.iter().map(|layer| &layer.name[..]).collect();
+        assert_eq!(names, ["axis", "parcels"]);
+        assert_eq!(layers[0].polylines.len(), 2);
+        assert_eq!(
+            layers[0].polylines[0].points,
+            vec![Point3::new(0., 0., 0.), Point3::new(1., 2., 3.)]
+        );
+        assert_eq!(
+            layers[0].polylines[1].points,
+            vec![Point3::new(5., 5., 1.), Point3::new(6., 5., 2.)]
+        );
+        let parcel = &layers[1].polylines[0];
+        assert!(parcel.closed);
+        assert_eq!(
+            parcel.points,
+            vec![
+                Point3::new(0., 0., 4.),
+                Point3::new(1., 0., 4.),
+                Point3::new(1., 1., 4.)
+            ]
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Cross sections and longitudinal profiles along a corridor, e.g. of a road or rail track. Every
+//! vertical plane through a segment of the corridor's polyline is a section plane, and the points
+//! are projected onto them to get their 2D profile coordinates: the station along the polyline and
+//! the height.
+
+use crate::errors::*;
+use crate::geometry::Corridor;
+use crate::iterator::{PointCloud, PointLocation, PointQuery};
+use crate::{AttributeData, PointsBatch, NUM_POINTS_PER_BATCH};
+
+/// Name of the attribute with the distance along the polyline, see 'StationOffset'.
+pub const STATION_ATTRIBUTE: &str = "station";
+/// Name of the attribute with the signed distance from the section plane, see 'StationOffset'.
+pub const OFFSET_ATTRIBUTE: &str = "offset";
+
+/// Adds the station and offset of every point of 'batch' relative to 'corridor' as f64
+/// attributes, replacing attributes of the same name.
+pub fn add_profile_coordinates(corridor: &Corridor, batch: &mut PointsBatch) {
+    let (stations, offsets) = batch
+        .position
+        .iter()
+        .map(|p| {
+            let station_offset = corridor.station_offset(&p.xy());
+            (station_offset.station, station_offset.offset)
+        })
+        .unzip();
+    batch
+        .attributes
+        .insert(STATION_ATTRIBUTE.to_string(), AttributeData::F64(stations));
+    batch
+        .attributes
+        .insert(OFFSET_ATTRIBUTE.to_string(), AttributeData::F64(offsets));
+}
+
+/// Calls 'func' with the points of 'point_cloud' inside 'corridor', with 'attributes' and their
+/// profile coordinates, see 'add_profile_coordinates'. The points are in the order of the nodes,
+/// not sorted by station.
+pub fn extract_profile<C, F>(
+    point_cloud: &C,
+    corridor: &Corridor,
+    attributes: &[&str],
+    mut func: F,
+) -> Result<()>
+where
+    C: PointCloud,
+    F: FnMut(PointsBatch) -> Result<()>,
+{
+    let query = PointQuery {
+        attributes: attributes.to_vec(),
+        location: PointLocation::Corridor(corridor.clone()),
+        filter_intervals: Default::default(),
+    };
+    for node_id in point_cloud.nodes_in_location(&query.location) {
+        point_cloud.stream_points_for_query_in_node(
+            &query,
+            node_id,
+            NUM_POINTS_PER_BATCH,
+            |mut batch| {
+                add_profile_coordinates(corridor, &mut batch);
+                func(batch)
+            },
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Point2, Point3};
+
+    #[test]
+    fn test_profile_coordinates() {
+        let corridor = Corridor::new(
+            vec![Point2::new(0., 0.), Point2::new(0., 10.)],
+            2.,
+            -10.,
+            10.,
+        )
+        .unwrap();
+        let mut batch = PointsBatch {
+            position: vec![Point3::new(-1., 2., 5.), Point3::new(1.5, 7., -1.)],
+            attributes: Default::default(),
+        };
+        add_profile_coordinates(&corridor, &mut batch);
+        let stations: &Vec<f64> = batch.get_attribute_vec(STATION_ATTRIBUTE).unwrap();
+        let offsets: &Vec<f64> = batch.get_attribute_vec(OFFSET_ATTRIBUTE).unwrap();
+        assert_eq!(stations, &vec![2., 7.]);
+        // Left of the polyline, which points along +y, is -x.
+        assert_eq!(offsets, &vec![1., -1.5]);
+    }
+}
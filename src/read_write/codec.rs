@@ -15,9 +15,11 @@
 use crate::errors::*;
 use crate::geometry::Cube;
 use crate::proto;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use nalgebra::{Point3, Scalar, Vector3};
 use num::clamp;
 use std::fmt::Debug;
+use std::io::{self, Read, Write};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum PositionEncoding {
@@ -25,6 +27,12 @@ pub enum PositionEncoding {
     Uint16,
     Float32,
     Float64,
+    /// Fixpoint with 24 bits per coordinate. Same precision as Float32, but one byte smaller.
+    Uint24,
+    /// Quantized like Uint24, but every coordinate is stored as the zigzag varint encoded
+    /// difference to the previous point. This is small if consecutive points are close, e.g.
+    /// after Morton sorting, but the points can only be decoded in order.
+    Delta,
 }
 
 impl PositionEncoding {
@@ -47,10 +55,11 @@ impl PositionEncoding {
             proto::PositionEncoding::Uint16 => Ok(PositionEncoding::Uint16),
             proto::PositionEncoding::Float32 => Ok(PositionEncoding::Float32),
             proto::PositionEncoding::Float64 => Ok(PositionEncoding::Float64),
-            proto::PositionEncoding::INVALID => Err(ErrorKind::InvalidInput(
+            proto::PositionEncoding::Uint24 => Ok(PositionEncoding::Uint24),
+            proto::PositionEncoding::Delta => Ok(PositionEncoding::Delta),
+            proto::PositionEncoding::INVALID => Err(Error::InvalidInput(
                 "Proto: PositionEncoding is invalid".to_string(),
-            )
-            .into()),
+            )),
         }
     }
 
@@ -60,14 +69,18 @@ impl PositionEncoding {
             PositionEncoding::Uint16 => proto::PositionEncoding::Uint16,
             PositionEncoding::Float32 => proto::PositionEncoding::Float32,
             PositionEncoding::Float64 => proto::PositionEncoding::Float64,
+            PositionEncoding::Uint24 => proto::PositionEncoding::Uint24,
+            PositionEncoding::Delta => proto::PositionEncoding::Delta,
         }
     }
 
+    /// For Delta, whose coordinates differ in size, this is the worst case.
     pub fn bytes_per_coordinate(&self) -> usize {
         match *self {
             PositionEncoding::Uint8 => 1,
             PositionEncoding::Uint16 => 2,
-            PositionEncoding::Float32 => 4,
+            PositionEncoding::Uint24 => 3,
+            PositionEncoding::Float32 | PositionEncoding::Delta => 4,
             PositionEncoding::Float64 => 8,
         }
     }
@@ -138,6 +151,137 @@ where
     nalgebra::convert::<T, f64>(value).mul_add(edge_length, min)
 }
 
+const UINT24_MAX: f64 = ((1 << 24) - 1) as f64;
+
+/// Encode float as 24 bit integer.
+pub fn fixpoint_encode_u24(value: f64, min: f64, edge_length: f64) -> u32 {
+    (clamp((value - min) / edge_length, 0., 1.) * UINT24_MAX).round() as u32
+}
+
+/// Decode 24 bit integer as float.
+pub fn fixpoint_decode_u24(value: u32, min: f64, edge_length: f64) -> f64 {
+    (f64::from(value) / UINT24_MAX).mul_add(edge_length, min)
+}
+
+pub fn vec3_write_u24(
+    value: &Point3<f64>,
+    min: &Point3<f64>,
+    edge_length: f64,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    for i in 0..3 {
+        writer.write_u24::<LittleEndian>(fixpoint_encode_u24(value[i], min[i], edge_length))?;
+    }
+    Ok(())
+}
+
+pub fn vec3_read_u24(
+    reader: &mut impl Read,
+    min: &Point3<f64>,
+    edge_length: f64,
+) -> io::Result<Point3<f64>> {
+    let x = fixpoint_decode_u24(reader.read_u24::<LittleEndian>()?, min.x, edge_length);
+    let y = fixpoint_decode_u24(reader.read_u24::<LittleEndian>()?, min.y, edge_length);
+    let z = fixpoint_decode_u24(reader.read_u24::<LittleEndian>()?, min.z, edge_length);
+    Ok(Point3::new(x, y, z))
+}
+
+/// The previous point of the Delta position encoding. Readers and writers keep one per node,
+/// since every point is relative to the one before it.
+#[derive(Clone, Debug, Default)]
+pub struct DeltaState {
+    previous: [i64; 3],
+    num_points: usize,
+}
+
+impl DeltaState {
+    /// Number of points encoded or decoded so far.
+    pub fn num_points(&self) -> usize {
+        self.num_points
+    }
+
+    pub fn encode(
+        &mut self,
+        value: &Point3<f64>,
+        min: &Point3<f64>,
+        edge_length: f64,
+        writer: &mut impl Write,
+    ) -> io::Result<()> {
+        for i in 0..3 {
+            let quantized = i64::from(fixpoint_encode_u24(value[i], min[i], edge_length));
+            write_zigzag_varint(quantized - self.previous[i], writer)?;
+            self.previous[i] = quantized;
+        }
+        self.num_points += 1;
+        Ok(())
+    }
+
+    pub fn decode(
+        &mut self,
+        reader: &mut impl Read,
+        min: &Point3<f64>,
+        edge_length: f64,
+    ) -> io::Result<Point3<f64>> {
+        let mut decoded = [0.; 3];
+        for i in 0..3 {
+            self.previous[i] += read_zigzag_varint(reader)?;
+            decoded[i] = fixpoint_decode_u24(self.previous[i] as u32, min[i], edge_length);
+        }
+        self.num_points += 1;
+        Ok(Point3::new(decoded[0], decoded[1], decoded[2]))
+    }
+
+    /// Decodes all points in 'reader', so that appending continues after the last of them.
+    pub fn skip_to_end(&mut self, reader: &mut impl Read) -> io::Result<()> {
+        loop {
+            match read_zigzag_varint(reader) {
+                Ok(delta) => self.previous[0] += delta,
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            }
+            self.previous[1] += read_zigzag_varint(reader)?;
+            self.previous[2] += read_zigzag_varint(reader)?;
+            self.num_points += 1;
+        }
+    }
+}
+
+pub(crate) fn write_varint(mut value: u64, writer: &mut impl Write) -> io::Result<()> {
+    while value >= 0x80 {
+        writer.write_u8((value as u8) | 0x80)?;
+        value >>= 7;
+    }
+    writer.write_u8(value as u8)
+}
+
+pub(crate) fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = reader.read_u8()?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Varint is too long.",
+            ));
+        }
+    }
+}
+
+pub(crate) fn write_zigzag_varint(value: i64, writer: &mut impl Write) -> io::Result<()> {
+    write_varint(((value << 1) ^ (value >> 63)) as u64, writer)
+}
+
+pub(crate) fn read_zigzag_varint(reader: &mut impl Read) -> io::Result<i64> {
+    let zigzag = read_varint(reader)?;
+    Ok((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64))
+}
+
 // Careful: num's (or nalgebra's) clamp accepts Vector3 too, but does not work elementwise like this
 fn clamp_elementwise(value: Vector3<f64>, lower: f64, upper: f64) -> Vector3<f64> {
     Vector3::new(
@@ -201,6 +345,15 @@ mod tests {
             value
         );
 
+        let value_u24 = fixpoint_encode_u24(value, min, edge_length);
+        let value_u24_decoded = fixpoint_decode_u24(value_u24, min, edge_length);
+        assert!(
+            (value_u24_decoded - value).abs() < 1e-6,
+            "Reconstructed from u24: {}, original: {}",
+            value_u24_decoded,
+            value
+        );
+
         let value_u32 = fixpoint_encode::<u32>(value, min, edge_length);
         let value_u32_decoded = fixpoint_decode(value_u32, min, edge_length);
         assert!(
@@ -210,4 +363,52 @@ mod tests {
             value
         );
     }
+
+    #[test]
+    fn delta_points() {
+        let min = Point3::new(-1.0, -1.0, -1.0);
+        let edge_length = 2.0;
+        let points = vec![
+            Point3::new(0.5, 0.25, -0.75),
+            Point3::new(0.5001, 0.2501, -0.7499),
+            Point3::new(-1.0, 1.0, 0.0),
+        ];
+
+        let mut buffer = Vec::new();
+        let mut state = DeltaState::default();
+        for point in &points {
+            state.encode(point, &min, edge_length, &mut buffer).unwrap();
+        }
+
+        let mut reader = &buffer[..];
+        let mut state = DeltaState::default();
+        for point in &points {
+            let decoded = state.decode(&mut reader, &min, edge_length).unwrap();
+            assert!((decoded - point).norm() < 1e-6);
+        }
+
+        let mut state = DeltaState::default();
+        state.skip_to_end(&mut &buffer[..]).unwrap();
+        assert_eq!(state.num_points(), points.len());
+    }
+
+    #[test]
+    fn zigzag_varint() {
+        for &value in &[
+            0,
+            1,
+            -1,
+            63,
+            -64,
+            64,
+            1 << 24,
+            -(1 << 24),
+            i64::MAX,
+            i64::MIN,
+        ] {
+            let mut buffer = Vec::new();
+            write_zigzag_varint(value, &mut buffer).unwrap();
+            assert_eq!(read_zigzag_varint(&mut &buffer[..]).unwrap(), value);
+        }
+    }
 }
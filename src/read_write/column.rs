@@ -0,0 +1,456 @@
+//! Lossless compression of attribute columns. A compressed column is a sequence of independent
+//! blocks, each starting with the id of its codec, the number of components and the number of
+//! bytes of the encoded data. Values are split into their components, e.g. the three channels of
+//! a color, and the codecs work on the little endian bit patterns of the components, so every
+//! codec is lossless for every data type.
+
+use crate::read_write::codec::{
+    read_varint, read_zigzag_varint, write_varint, write_zigzag_varint,
+};
+use crate::AttributeDataType;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// Number of components in a full block.
+const BLOCK_SIZE: usize = 65_536;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColumnCodec {
+    /// The plain little endian values.
+    Raw,
+    /// Runs of equal values, e.g. for classifications.
+    Rle,
+    /// The difference to the smallest component of the block, using as many bits as the largest
+    /// difference needs, e.g. for intensities.
+    BitPacked,
+    /// The zigzag varint encoded difference to the same component of the previous value, e.g. for
+    /// timestamps.
+    Delta,
+}
+
+impl ColumnCodec {
+    const ALL: [ColumnCodec; 4] = [
+        ColumnCodec::Raw,
+        ColumnCodec::Rle,
+        ColumnCodec::BitPacked,
+        ColumnCodec::Delta,
+    ];
+
+    fn id(self) -> u8 {
+        match self {
+            ColumnCodec::Raw => 0,
+            ColumnCodec::Rle => 1,
+            ColumnCodec::BitPacked => 2,
+            ColumnCodec::Delta => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> io::Result<Self> {
+        ColumnCodec::ALL
+            .iter()
+            .copied()
+            .find(|codec| codec.id() == id)
+            .ok_or_else(|| invalid_data(format!("Unknown column codec {}.", id)))
+    }
+}
+
+impl FromStr for ColumnCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "raw" => Ok(ColumnCodec::Raw),
+            "rle" => Ok(ColumnCodec::Rle),
+            "bitpacked" => Ok(ColumnCodec::BitPacked),
+            "delta" => Ok(ColumnCodec::Delta),
+            _ => Err(format!("Unknown column codec '{}'.", s)),
+        }
+    }
+}
+
+/// How the attribute columns of a node are stored.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeCompression {
+    /// Plain little endian values, readable by all versions.
+    Uncompressed,
+    /// Compressed with the given codec per attribute. Attributes without one use the codec that
+    /// is smallest for every block.
+    Compressed(HashMap<String, ColumnCodec>),
+}
+
+impl Default for AttributeCompression {
+    fn default() -> Self {
+        AttributeCompression::Uncompressed
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Bytes per component and components per value.
+fn layout(data_type: AttributeDataType) -> (usize, usize) {
+    (data_type.size_of() / data_type.dim(), data_type.dim())
+}
+
+fn encode_block(
+    codec: ColumnCodec,
+    bytes: &[u8],
+    component_size: usize,
+    dim: usize,
+) -> io::Result<Vec<u8>> {
+    let components: Vec<u64> = bytes
+        .chunks_exact(component_size)
+        .map(|chunk| LittleEndian::read_uint(chunk, component_size))
+        .collect();
+    let mut encoded = Vec::new();
+    match codec {
+        ColumnCodec::Raw => encoded.extend_from_slice(bytes),
+        ColumnCodec::Rle => {
+            let value_size = component_size * dim;
+            let mut values = bytes.chunks_exact(value_size).peekable();
+            while let Some(value) = values.next() {
+                let mut run_length = 1;
+                while values.peek() == Some(&value) {
+                    values.next();
+                    run_length += 1;
+                }
+                write_varint(run_length, &mut encoded)?;
+                encoded.extend_from_slice(value);
+            }
+        }
+        ColumnCodec::BitPacked => {
+            let min = components.iter().copied().min().unwrap_or(0);
+            let max = components.iter().copied().max().unwrap_or(0);
+            let bits = 64 - (max - min).leading_zeros();
+            write_varint(min, &mut encoded)?;
+            encoded.write_u8(bits as u8)?;
+            let mut accumulator = 0u128;
+            let mut num_bits = 0;
+            for component in &components {
+                accumulator |= u128::from(component - min) << num_bits;
+                num_bits += bits;
+                while num_bits >= 8 {
+                    encoded.push(accumulator as u8);
+                    accumulator >>= 8;
+                    num_bits -= 8;
+                }
+            }
+            if num_bits > 0 {
+                encoded.push(accumulator as u8);
+            }
+        }
+        ColumnCodec::Delta => {
+            let mut previous = vec![0u64; dim];
+            for (i, component) in components.iter().enumerate() {
+                let lane = i % dim;
+                write_zigzag_varint(component.wrapping_sub(previous[lane]) as i64, &mut encoded)?;
+                previous[lane] = *component;
+            }
+        }
+    }
+    Ok(encoded)
+}
+
+fn decode_block(
+    codec: ColumnCodec,
+    encoded: &[u8],
+    num_components: usize,
+    component_size: usize,
+    dim: usize,
+) -> io::Result<Vec<u8>> {
+    let mut reader = encoded;
+    let mut components = Vec::with_capacity(num_components);
+    match codec {
+        ColumnCodec::Raw => {
+            if encoded.len() != num_components * component_size {
+                return Err(invalid_data(
+                    "Raw column block has the wrong size.".to_string(),
+                ));
+            }
+            return Ok(encoded.to_vec());
+        }
+        ColumnCodec::Rle => {
+            let value_size = component_size * dim;
+            let len = num_components * component_size;
+            let mut decoded = Vec::with_capacity(len);
+            while decoded.len() < len {
+                let run_length = read_varint(&mut reader)?;
+                if reader.len() < value_size {
+                    return Err(invalid_data("Truncated run in column block.".to_string()));
+                }
+                // The run must not reach past the end of the block.
+                let run_size = usize::try_from(run_length)
+                    .ok()
+                    .and_then(|run_length| run_length.checked_mul(value_size))
+                    .filter(|run_size| *run_size <= len - decoded.len())
+                    .ok_or_else(|| invalid_data("Run too long for column block.".to_string()))?;
+                let (value, rest) = reader.split_at(value_size);
+                for _ in 0..run_size / value_size {
+                    decoded.extend_from_slice(value);
+                }
+                reader = rest;
+            }
+            if decoded.len() != len || !reader.is_empty() {
+                return Err(invalid_data(
+                    "Rle column block has the wrong size.".to_string(),
+                ));
+            }
+            return Ok(decoded);
+        }
+        ColumnCodec::BitPacked => {
+            let min = read_varint(&mut reader)?;
+            let bits = u32::from(reader.read_u8()?);
+            if bits > 64 {
+                return Err(invalid_data(
+                    "Invalid bit width in column block.".to_string(),
+                ));
+            }
+            let mask = if bits == 64 {
+                u64::MAX
+            } else {
+                (1u64 << bits) - 1
+            };
+            let mut accumulator = 0u128;
+            let mut num_bits = 0;
+            for _ in 0..num_components {
+                while num_bits < bits {
+                    accumulator |= u128::from(reader.read_u8()?) << num_bits;
+                    num_bits += 8;
+                }
+                components.push((accumulator as u64 & mask).wrapping_add(min));
+                accumulator >>= bits;
+                num_bits -= bits;
+            }
+        }
+        ColumnCodec::Delta => {
+            let mut previous = vec![0u64; dim];
+            for i in 0..num_components {
+                let lane = i % dim;
+                previous[lane] =
+                    previous[lane].wrapping_add(read_zigzag_varint(&mut reader)? as u64);
+                components.push(previous[lane]);
+            }
+        }
+    }
+    let mut decoded = vec![0; num_components * component_size];
+    for (component, chunk) in components
+        .iter()
+        .zip(decoded.chunks_exact_mut(component_size))
+    {
+        LittleEndian::write_uint(chunk, *component, component_size);
+    }
+    Ok(decoded)
+}
+
+/// Compresses the little endian bytes of a column written to it into 'inner'. The last block is
+/// written by 'finish'.
+pub struct ColumnEncoder<W: Write> {
+    inner: W,
+    codec: Option<ColumnCodec>,
+    component_size: usize,
+    dim: usize,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ColumnEncoder<W> {
+    /// Without a 'codec', every block uses the codec that compresses it best.
+    pub fn new(inner: W, data_type: AttributeDataType, codec: Option<ColumnCodec>) -> Self {
+        let (component_size, dim) = layout(data_type);
+        ColumnEncoder {
+            inner,
+            codec,
+            component_size,
+            dim,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Writes the last block and returns 'inner'.
+    pub fn finish(mut self) -> io::Result<W> {
+        // Only whole values are ever written, so the buffer holds whole components.
+        if !self.buffer.is_empty() {
+            let len = self.buffer.len();
+            self.write_block(len)?;
+        }
+        Ok(self.inner)
+    }
+
+    fn write_block(&mut self, len: usize) -> io::Result<()> {
+        let bytes = &self.buffer[..len];
+        let (codec, encoded) = match self.codec {
+            Some(codec) => (
+                codec,
+                encode_block(codec, bytes, self.component_size, self.dim)?,
+            ),
+            None => {
+                let mut best: Option<(ColumnCodec, Vec<u8>)> = None;
+                for codec in ColumnCodec::ALL.iter().copied() {
+                    let encoded = encode_block(codec, bytes, self.component_size, self.dim)?;
+                    if best.as_ref().map_or(true, |(_, b)| encoded.len() < b.len()) {
+                        best = Some((codec, encoded));
+                    }
+                }
+                best.unwrap()
+            }
+        };
+        self.inner.write_u8(codec.id())?;
+        write_varint((len / self.component_size) as u64, &mut self.inner)?;
+        write_varint(encoded.len() as u64, &mut self.inner)?;
+        self.inner.write_all(&encoded)?;
+        self.buffer.drain(..len);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ColumnEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let block_len = BLOCK_SIZE * self.component_size;
+        while self.buffer.len() >= block_len {
+            self.write_block(block_len)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads a column compressed by 'ColumnEncoder' as plain little endian bytes.
+pub struct ColumnDecoder<R: Read> {
+    inner: R,
+    component_size: usize,
+    dim: usize,
+    decoded: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> ColumnDecoder<R> {
+    pub fn new(inner: R, data_type: AttributeDataType) -> Self {
+        let (component_size, dim) = layout(data_type);
+        ColumnDecoder {
+            inner,
+            component_size,
+            dim,
+            decoded: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Returns false at the end of the column.
+    fn read_block(&mut self) -> io::Result<bool> {
+        let codec = match self.inner.read_u8() {
+            Ok(id) => ColumnCodec::from_id(id)?,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let num_components = read_varint(&mut self.inner)? as usize;
+        // 'ColumnEncoder' never writes larger blocks, so this also bounds the allocations below.
+        if num_components > BLOCK_SIZE {
+            return Err(invalid_data(format!(
+                "Column block of {} components is too large.",
+                num_components
+            )));
+        }
+        let mut encoded = vec![0; read_varint(&mut self.inner)? as usize];
+        self.inner.read_exact(&mut encoded)?;
+        self.decoded = decode_block(
+            codec,
+            &encoded,
+            num_components,
+            self.component_size,
+            self.dim,
+        )?;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for ColumnDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.decoded.len() {
+            if !self.read_block()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.decoded.len() - self.position);
+        buf[..len].copy_from_slice(&self.decoded[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(data_type: AttributeDataType, bytes: &[u8], codec: Option<ColumnCodec>) -> usize {
+        let mut encoder = ColumnEncoder::new(Vec::new(), data_type, codec);
+        // Write in uneven pieces to cross value boundaries.
+        for chunk in bytes.chunks(7) {
+            encoder.write_all(chunk).unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        let mut decoded = Vec::new();
+        ColumnDecoder::new(&compressed[..], data_type)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, bytes, "{:?} {:?}", data_type, codec);
+        compressed.len()
+    }
+
+    #[test]
+    fn test_all_codecs_are_lossless() {
+        let classification: Vec<u8> = (0..200_000).map(|i| (i / 1000 % 5) as u8).collect();
+        let mut intensity = Vec::new();
+        for i in 0..100_000u32 {
+            intensity.extend_from_slice(&(1000 + (i * 7919) % 300).to_le_bytes());
+        }
+        let mut timestamps = Vec::new();
+        for i in 0..100_000 {
+            timestamps.extend_from_slice(&(1.6e9 + f64::from(i) * 1e-5).to_le_bytes());
+        }
+        let colors: Vec<u8> = (0..30_000).map(|i| (i * 31 % 256) as u8).collect();
+
+        let columns = [
+            (AttributeDataType::U8, &classification),
+            (AttributeDataType::U32, &intensity),
+            (AttributeDataType::F64, &timestamps),
+            (AttributeDataType::U8Vec3, &colors),
+        ];
+        for (data_type, bytes) in columns.iter() {
+            for codec in ColumnCodec::ALL.iter() {
+                roundtrip(*data_type, bytes, Some(*codec));
+            }
+            let auto = roundtrip(*data_type, bytes, None);
+            assert!(auto <= bytes.len() + 16 * (bytes.len() / BLOCK_SIZE + 1));
+        }
+
+        assert!(
+            roundtrip(AttributeDataType::U8, &classification, None) < classification.len() / 100
+        );
+        assert!(roundtrip(AttributeDataType::U32, &intensity, None) < intensity.len() / 2);
+        assert!(roundtrip(AttributeDataType::F64, &timestamps, None) < timestamps.len() / 2);
+    }
+
+    #[test]
+    fn test_rle_rejects_runs_past_the_block() {
+        let mut encoded = Vec::new();
+        write_varint(3, &mut encoded).unwrap();
+        encoded.push(7);
+        // A run of 3 values cannot fill a block of 2.
+        assert!(decode_block(ColumnCodec::Rle, &encoded, 2, 1, 1).is_err());
+        write_varint(u64::MAX, &mut encoded).unwrap();
+        encoded.push(7);
+        assert!(decode_block(ColumnCodec::Rle, &encoded, 4, 1, 1).is_err());
+        assert_eq!(
+            decode_block(ColumnCodec::Rle, &encoded[..2], 3, 1, 1).unwrap(),
+            vec![7, 7, 7]
+        );
+    }
+}
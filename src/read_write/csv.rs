@@ -0,0 +1,91 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::read_write::{DataWriter, Encoding, NodeWriter, OpenMode};
+use crate::{match_1d_attr_data, AttributeData, PointsBatch};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Writes points as comma separated text, one point per line, starting with a header line that
+/// names the columns. Positions are always written in full precision, so the encoding is ignored.
+/// Vector attributes are split into one column per component, e.g. 'color' becomes 'red', 'green'
+/// and 'blue'.
+pub struct CsvNodeWriter {
+    writer: DataWriter,
+    has_header: bool,
+}
+
+impl NodeWriter<PointsBatch> for CsvNodeWriter {
+    fn new(filename: impl Into<PathBuf>, _encoding: Encoding, open_mode: OpenMode) -> Self {
+        let writer = DataWriter::new(filename, open_mode).unwrap();
+        // When appending to an existing file, it already starts with a header.
+        let has_header = writer.bytes_written() > 0;
+        Self { writer, has_header }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        self.writer.finish()
+    }
+
+    fn write(&mut self, p: &PointsBatch) -> io::Result<()> {
+        if p.position.is_empty() {
+            return Ok(());
+        }
+        if !self.has_header {
+            self.write_header(p)?;
+            self.has_header = true;
+        }
+        for (i, pos) in p.position.iter().enumerate() {
+            write!(self.writer, "{},{},{}", pos.x, pos.y, pos.z)?;
+            for data in p.attributes.values() {
+                write_value(data, i, &mut self.writer)?;
+            }
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl CsvNodeWriter {
+    fn write_header(&mut self, p: &PointsBatch) -> io::Result<()> {
+        self.writer.write_all(b"x,y,z")?;
+        for (name, data) in &p.attributes {
+            match (&name[..], data.dim()) {
+                ("color", 3) => self.writer.write_all(b",red,green,blue")?,
+                (_, 1) => write!(self.writer, ",{}", name)?,
+                (_, dim) => {
+                    for i in 0..dim {
+                        write!(self.writer, ",{}{}", name, i)?;
+                    }
+                }
+            }
+        }
+        self.writer.write_all(b"\n")
+    }
+}
+
+fn write_value(data: &AttributeData, i: usize, writer: &mut DataWriter) -> io::Result<()> {
+    match data {
+        AttributeData::U8Vec3(d) => write!(writer, ",{},{},{}", d[i].x, d[i].y, d[i].z),
+        AttributeData::F64Vec3(d) => write!(writer, ",{},{},{}", d[i].x, d[i].y, d[i].z),
+        _ => {
+            macro_rules! rhs {
+                ($dtype:ident, $data:ident, $writer:ident, $i:ident) => {
+                    write!($writer, ",{}", $data[$i])
+                };
+            }
+            match_1d_attr_data!(data, rhs, writer, i)
+        }
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::geometry::Aabb;
+use crate::read_write::{DataWriter, Encoding, NodeWriter, OpenMode};
+use crate::PointsBatch;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nalgebra::{Point3, Vector3};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+// We write LAS 1.2 with point data record format 2, which has positions, intensity and color.
+const HEADER_SIZE: u16 = 227;
+const POINT_DATA_FORMAT: u8 = 2;
+const POINT_RECORD_LENGTH: u16 = 26;
+// Every point is return 1 of 1: the return number is in bits 0-2, the number of returns in 3-5.
+const RETURN_BITS: u8 = 0b0000_1001;
+// Byte offsets of the header fields we need to read back when appending.
+const NUM_POINTS_OFFSET: u64 = 107;
+const SCALE_OFFSET: u64 = 131;
+// Positions are stored as integer multiples of this, relative to the offset in the header.
+const LAS_SCALE: f64 = 0.001;
+
+/// Writes points into a LAS 1.2 file. LAS has its own fixed-point position representation, so the
+/// encoding is ignored. Besides positions, only the 'intensity' and 'color' attributes are
+/// written; 8 bit colors are scaled to the 16 bit range of LAS.
+pub struct LasNodeWriter {
+    writer: DataWriter,
+    point_count: u32,
+    offset: Vector3<f64>,
+    bounding_box: Option<Aabb>,
+}
+
+impl NodeWriter<PointsBatch> for LasNodeWriter {
+    fn new(filename: impl Into<PathBuf>, _encoding: Encoding, open_mode: OpenMode) -> Self {
+        let filename = filename.into();
+        let mut point_count = 0;
+        let mut offset = Vector3::zeros();
+        let mut bounding_box = None;
+        if open_mode == OpenMode::Append {
+            if let Ok(mut file) = File::open(&filename) {
+                if file.metadata().unwrap().len() >= u64::from(HEADER_SIZE) {
+                    let (count, header_offset, bbox) = read_header(&mut file).unwrap();
+                    point_count = count;
+                    offset = header_offset;
+                    bounding_box = Some(bbox);
+                }
+            }
+        }
+        let writer = DataWriter::new(filename, open_mode).unwrap();
+        Self {
+            writer,
+            point_count,
+            offset,
+            bounding_box,
+        }
+    }
+
+    fn write(&mut self, p: &PointsBatch) -> io::Result<()> {
+        if p.position.is_empty() {
+            return Ok(());
+        }
+        if self.bounding_box.is_none() {
+            // Keep the integer coordinates small by storing them relative to the first point.
+            self.offset = p.position[0].coords.map(f64::floor);
+            self.bounding_box = Some(Aabb::new(p.position[0], p.position[0]));
+            // Placeholder, the real header is written by 'finish' once all points are known.
+            self.write_header()?;
+        }
+        let intensity: Option<&Vec<f32>> = p.get_attribute_vec("intensity").ok();
+        let color: Option<&Vec<Vector3<u8>>> = p.get_attribute_vec("color").ok();
+        for (i, pos) in p.position.iter().enumerate() {
+            for (value, offset) in pos.coords.iter().zip(self.offset.iter()) {
+                self.writer
+                    .write_i32::<LittleEndian>(to_fixpoint(*value - *offset)?)?;
+            }
+            let intensity = intensity.map_or(0, |v| v[i].max(0.).min(f32::from(u16::MAX)) as u16);
+            self.writer.write_u16::<LittleEndian>(intensity)?;
+            self.writer.write_u8(RETURN_BITS)?;
+            // Classification, scan angle, user data and point source ID.
+            self.writer.write_all(&[0; 5])?;
+            let color = color.map_or(Vector3::zeros(), |v| v[i]);
+            for c in color.iter() {
+                self.writer.write_u16::<LittleEndian>(u16::from(*c) * 257)?;
+            }
+            self.bounding_box.as_mut().unwrap().grow(*pos);
+        }
+        self.point_count += p.position.len() as u32;
+        Ok(())
+    }
+
+    /// Writes the header with the number of points and their bounding box, and completes the
+    /// file.
+    fn finish(mut self) -> io::Result<()> {
+        if self.point_count > 0 {
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.write_header()?;
+        }
+        self.writer.finish()
+    }
+}
+
+impl LasNodeWriter {
+    fn write_header(&mut self) -> io::Result<()> {
+        let bbox = self
+            .bounding_box
+            .clone()
+            .unwrap_or_else(|| Aabb::new(Point3::origin(), Point3::origin()));
+        let w = &mut self.writer;
+        w.write_all(b"LASF")?;
+        // File source ID, global encoding and GUID.
+        w.write_all(&[0; 20])?;
+        // Version 1.2.
+        w.write_all(&[1, 2])?;
+        // System identifier and generating software, padded to 32 bytes each.
+        for _ in 0..2 {
+            w.write_all(b"point_viewer")?;
+            w.write_all(&[0; 20])?;
+        }
+        // Creation day of year and year are optional.
+        w.write_all(&[0; 4])?;
+        w.write_u16::<LittleEndian>(HEADER_SIZE)?;
+        w.write_u32::<LittleEndian>(u32::from(HEADER_SIZE))?;
+        // Number of variable length records.
+        w.write_u32::<LittleEndian>(0)?;
+        w.write_u8(POINT_DATA_FORMAT)?;
+        w.write_u16::<LittleEndian>(POINT_RECORD_LENGTH)?;
+        w.write_u32::<LittleEndian>(self.point_count)?;
+        // Number of points by return: we only have first returns.
+        w.write_u32::<LittleEndian>(self.point_count)?;
+        w.write_all(&[0; 16])?;
+        for _ in 0..3 {
+            w.write_f64::<LittleEndian>(LAS_SCALE)?;
+        }
+        for offset in self.offset.iter() {
+            w.write_f64::<LittleEndian>(*offset)?;
+        }
+        for i in 0..3 {
+            w.write_f64::<LittleEndian>(bbox.max()[i])?;
+            w.write_f64::<LittleEndian>(bbox.min()[i])?;
+        }
+        Ok(())
+    }
+}
+
+fn to_fixpoint(value: f64) -> io::Result<i32> {
+    let fixpoint = (value / LAS_SCALE).round();
+    if fixpoint < f64::from(i32::MIN) || f64::from(i32::MAX) < fixpoint {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Point is too far away from the first point to be stored in LAS.",
+        ));
+    }
+    Ok(fixpoint as i32)
+}
+
+/// Returns number of points, offset and bounding box of an existing LAS file written by us.
+fn read_header(file: &mut File) -> io::Result<(u32, Vector3<f64>, Aabb)> {
+    file.seek(SeekFrom::Start(NUM_POINTS_OFFSET))?;
+    let point_count = file.read_u32::<LittleEndian>()?;
+    file.seek(SeekFrom::Start(SCALE_OFFSET + 3 * 8))?;
+    let mut offset = Vector3::zeros();
+    for value in offset.iter_mut() {
+        *value = file.read_f64::<LittleEndian>()?;
+    }
+    let mut min = Point3::origin();
+    let mut max = Point3::origin();
+    for i in 0..3 {
+        max[i] = file.read_f64::<LittleEndian>()?;
+        min[i] = file.read_f64::<LittleEndian>()?;
+    }
+    Ok((point_count, offset, Aabb::new(min, max)))
+}
@@ -14,18 +14,39 @@
 
 mod codec;
 pub use self::codec::{
-    decode, fixpoint_decode, fixpoint_encode, vec3_encode, vec3_fixpoint_encode, Encoding,
+    decode, fixpoint_decode, fixpoint_decode_u24, fixpoint_encode, fixpoint_encode_u24,
+    vec3_encode, vec3_fixpoint_encode, vec3_read_u24, vec3_write_u24, DeltaState, Encoding,
     PositionEncoding,
 };
 
+mod column;
+pub use self::column::{AttributeCompression, ColumnCodec, ColumnDecoder, ColumnEncoder};
+
+mod csv;
+pub use self::csv::CsvNodeWriter;
+
+mod las;
+pub use self::las::LasNodeWriter;
+
+#[cfg(feature = "native")]
+mod morton;
+#[cfg(feature = "native")]
+pub use self::morton::{morton_code, morton_sort, MortonSortOptions};
+
 mod node_iterator;
 pub use self::node_iterator::NodeIterator;
 
 mod node_writer;
-pub use self::node_writer::{DataWriter, NodeWriter, OpenMode, WriteEncoded, WriteLE, WriteLEPos};
+pub use self::node_writer::{
+    set_sync_on_close, write_file_atomically, DataWriter, NodeWriter, OpenMode, WriteEncoded,
+    WriteLE, WriteLEPos,
+};
 
 mod ply;
-pub use self::ply::{PlyIterator, PlyNodeWriter};
+pub use self::ply::{
+    read_header, AttributeFilter, PlyElement, PlyFormat, PlyInfo, PlyIterator, PlyNodeWriter,
+    PlyProperty,
+};
 
 mod raw;
 pub use self::raw::{RawNodeReader, RawNodeWriter};
@@ -33,6 +54,14 @@ pub use self::raw::{RawNodeReader, RawNodeWriter};
 mod s2;
 pub use self::s2::S2Splitter;
 
+mod simd;
+
+mod text;
+pub use self::text::{parse_number, TextFormat, TextIterator, SKIPPED_COLUMN};
+
+mod vertex;
+pub use self::vertex::{VertexAttribute, VertexFormat, VertexLayout};
+
 use std::io::{BufReader, Read};
 
 pub struct AttributeReader {
@@ -43,6 +72,7 @@ pub struct AttributeReader {
 /// We open a lot of files during our work. Sometimes users see errors with 'cannot open more
 /// files'. This utility function attempt to increase the rlimits for the number of open files per
 /// process here, but fails silently if we are not successful.
+#[cfg(feature = "native")]
 pub fn attempt_increasing_rlimit_to_max() {
     unsafe {
         let mut rl = libc::rlimit {
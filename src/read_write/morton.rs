@@ -0,0 +1,345 @@
+//! Reorders the points of a PLY file along a Morton (Z-order) curve in bounded memory. Points that
+//! are close in space are then close in the file, so that building an octree from it keeps only a
+//! few nodes busy at a time instead of spreading every batch over all of them.
+
+use crate::errors::*;
+use crate::geometry::{Aabb, Cube};
+use crate::read_write::{Encoding, NodeWriter, OpenMode, PlyIterator, PlyNodeWriter};
+use crate::utils::create_progress_bar;
+use crate::{AttributeData, NextInto, NumberOfPoints, PointsBatch, NUM_POINTS_PER_BATCH};
+use nalgebra::Point3;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Number of bits per axis of a Morton code.
+const MORTON_BITS: u32 = 21;
+
+#[derive(Clone, Debug)]
+pub struct MortonSortOptions {
+    /// The number of points that are sorted in memory at once. Larger inputs are sorted in runs
+    /// of this size, which are written to temporary files and merged.
+    pub max_points_in_memory: usize,
+    /// Directory for the temporary files of the runs. Defaults to the directory of the output.
+    pub temp_directory: Option<PathBuf>,
+}
+
+impl Default for MortonSortOptions {
+    fn default() -> Self {
+        Self {
+            max_points_in_memory: 50_000_000,
+            temp_directory: None,
+        }
+    }
+}
+
+/// The Morton code of 'p' on a grid of 2^21 cells per axis over 'cube'. Codes of points in the
+/// same octree node of 'cube' share their leading bits.
+pub fn morton_code(cube: &Cube, p: &Point3<f64>) -> u64 {
+    let max_cell = f64::from((1u32 << MORTON_BITS) - 1);
+    let scale = f64::from(1u32 << MORTON_BITS) / cube.edge_length().max(std::f64::EPSILON);
+    let cell = |value: f64, min: f64| ((value - min) * scale).floor().max(0.).min(max_cell) as u64;
+    let min = cube.min();
+    spread_bits(cell(p.x, min.x))
+        | spread_bits(cell(p.y, min.y)) << 1
+        | spread_bits(cell(p.z, min.z)) << 2
+}
+
+/// Moves the lower 21 bits of 'v' to every third bit.
+fn spread_bits(v: u64) -> u64 {
+    let mut v = v & 0x1f_ffff;
+    v = (v | v << 32) & 0x1f_0000_0000_ffff;
+    v = (v | v << 16) & 0x1f_0000_ff00_00ff;
+    v = (v | v << 8) & 0x100f_00f0_0f00_f00f;
+    v = (v | v << 4) & 0x10c3_0c30_c30c_30c3;
+    v = (v | v << 2) & 0x1249_2492_4924_9249;
+    v
+}
+
+/// Sorts the points of 'batch' by their Morton code in 'cube', keeping the order of points with
+/// the same code.
+fn sort_batch(batch: &mut PointsBatch, cube: &Cube) {
+    let codes: Vec<u64> = batch
+        .position
+        .iter()
+        .map(|p| morton_code(cube, p))
+        .collect();
+    let mut order: Vec<usize> = (0..codes.len()).collect();
+    order.sort_by_key(|i| codes[*i]);
+    batch.position = order.iter().map(|i| batch.position[*i]).collect();
+    for data in batch.attributes.values_mut() {
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident, $order:ident) => {
+                *$data = $order.iter().map(|i| $data[*i]).collect()
+            };
+        }
+        match_attr_data!(data, rhs, order)
+    }
+}
+
+/// Appends the point at 'index' of 'batch' to 'out', which has the same attributes.
+fn push_point(out: &mut PointsBatch, batch: &PointsBatch, index: usize) {
+    out.position.push(batch.position[index]);
+    for (out_data, data) in out.attributes.values_mut().zip(batch.attributes.values()) {
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident, $out_data:ident, $index:ident) => {
+                match $out_data {
+                    AttributeData::$dtype(out) => out.push($data[$index]),
+                    _ => unreachable!(),
+                }
+            };
+        }
+        match_attr_data!(data, rhs, out_data, index)
+    }
+}
+
+/// Sorts 'run' and writes it to the next file in 'directory'.
+fn write_run(
+    run: &mut PointsBatch,
+    cube: &Cube,
+    directory: &Path,
+    index: usize,
+) -> Result<PathBuf> {
+    sort_batch(run, cube);
+    let path = directory.join(format!("{}.ply", index));
+    let mut writer = PlyNodeWriter::new(&path, Encoding::Plain, OpenMode::Truncate);
+    writer.write(&*run)?;
+    writer.finish()?;
+    Ok(path)
+}
+
+/// Removes the temporary files of the runs, also if sorting fails.
+struct RunDirectory(PathBuf);
+
+impl Drop for RunDirectory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A sorted run that is being merged, with its next batch and the position in it.
+struct Run {
+    points: PlyIterator,
+    batch: PointsBatch,
+    next: usize,
+}
+
+impl Run {
+    /// Returns the code of the next point, reading the next batch if needed, or None at the end.
+    fn next_code(&mut self, cube: &Cube) -> Option<u64> {
+        if self.next == self.batch.position.len() {
+            if !self.points.next_into(&mut self.batch) {
+                return None;
+            }
+            self.next = 0;
+        }
+        Some(morton_code(cube, &self.batch.position[self.next]))
+    }
+}
+
+/// Writes the points of the PLY file 'input' to 'output' with a writer of type 'W', ordered along
+/// a Morton curve over their bounding cube. Inputs with more than
+/// 'options.max_points_in_memory' points are sorted in runs on disk that are merged. Returns the
+/// number of points written.
+pub fn morton_sort<W: NodeWriter<PointsBatch>>(
+    input: &Path,
+    output: &Path,
+    options: &MortonSortOptions,
+) -> Result<usize> {
+    let max_points = options.max_points_in_memory.max(1);
+    let batch_size = max_points.min(NUM_POINTS_PER_BATCH);
+    let open_input = || {
+        PlyIterator::from_file(input, batch_size)
+            .with_context(|| format!("Could not open {}.", input.display()))
+    };
+
+    let mut points = open_input()?;
+    let num_points = points.num_points();
+    let mut progress_bar = create_progress_bar(num_points, "Determining bounding box");
+    let mut bounding_box: Option<Aabb> = None;
+    let mut batch = PointsBatch::default();
+    while points.next_into(&mut batch) {
+        for p in &batch.position {
+            bounding_box.get_or_insert(Aabb::new(*p, *p)).grow(*p);
+        }
+        progress_bar.add(batch.position.len() as u64);
+    }
+    progress_bar.finish();
+    let cube = Cube::bounding(&bounding_box.unwrap_or_else(Aabb::zero));
+
+    let temp_directory = match &options.temp_directory {
+        Some(temp_directory) => temp_directory.clone(),
+        None => output
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from(".")),
+    };
+    let file_name = output.file_name().unwrap_or_default().to_string_lossy();
+    let run_directory = RunDirectory(temp_directory.join(format!(".{}.runs", file_name)));
+    fs::create_dir_all(&run_directory.0)
+        .with_context(|| format!("Could not create {}.", run_directory.0.display()))?;
+
+    let mut points = open_input()?;
+    let mut progress_bar = create_progress_bar(num_points, "Sorting runs");
+    let mut run = PointsBatch::default();
+    let mut run_paths = Vec::new();
+    while points.next_into(&mut batch) {
+        run.append(&mut batch).map_err(Error::SchemaMismatch)?;
+        if run.position.len() >= max_points {
+            let rest = run.split_off(max_points);
+            run_paths.push(write_run(
+                &mut run,
+                &cube,
+                &run_directory.0,
+                run_paths.len(),
+            )?);
+            progress_bar.add(run.position.len() as u64);
+            run = rest;
+        }
+    }
+    if run_paths.is_empty() {
+        // Everything fits into memory.
+        sort_batch(&mut run, &cube);
+        let mut writer = W::new(output, Encoding::Plain, OpenMode::Truncate);
+        writer.write(&run)?;
+        writer.finish()?;
+        progress_bar.add(run.position.len() as u64);
+        progress_bar.finish();
+        return Ok(run.position.len());
+    }
+    if !run.position.is_empty() {
+        run_paths.push(write_run(
+            &mut run,
+            &cube,
+            &run_directory.0,
+            run_paths.len(),
+        )?);
+        progress_bar.add(run.position.len() as u64);
+    }
+    progress_bar.finish();
+
+    // Every run holds one batch in memory while they are merged.
+    let run_batch_size = (max_points / run_paths.len()).max(1);
+    let mut runs = run_paths
+        .iter()
+        .map(|path| {
+            Ok(Run {
+                points: PlyIterator::from_file(path, run_batch_size)?,
+                batch: PointsBatch::default(),
+                next: 0,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut heap = BinaryHeap::new();
+    for (index, run) in runs.iter_mut().enumerate() {
+        if let Some(code) = run.next_code(&cube) {
+            heap.push(Reverse((code, index)));
+        }
+    }
+    let empty_like = |batch: &PointsBatch| PointsBatch {
+        position: Vec::new(),
+        attributes: batch
+            .attributes
+            .iter()
+            .map(|(name, data)| {
+                let data = AttributeData::with_capacity(data.data_type(), run_batch_size);
+                (name.clone(), data)
+            })
+            .collect(),
+    };
+    let mut writer = W::new(output, Encoding::Plain, OpenMode::Truncate);
+    let mut progress_bar = create_progress_bar(num_points, "Merging runs");
+    let mut out = empty_like(&runs[0].batch);
+    let mut num_written = 0;
+    while let Some(Reverse((_, index))) = heap.pop() {
+        let run = &mut runs[index];
+        push_point(&mut out, &run.batch, run.next);
+        run.next += 1;
+        if let Some(code) = run.next_code(&cube) {
+            heap.push(Reverse((code, index)));
+        }
+        if out.position.len() == run_batch_size || heap.is_empty() {
+            writer.write(&out)?;
+            num_written += out.position.len();
+            progress_bar.add(out.position.len() as u64);
+            out = empty_like(&out);
+        }
+    }
+    writer.finish()?;
+    progress_bar.finish();
+    Ok(num_written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_morton_code() {
+        let cube = Cube::new(Point3::new(0., 0., 0.), 2.);
+        assert_eq!(morton_code(&cube, &Point3::new(0., 0., 0.)), 0);
+        assert_eq!(morton_code(&cube, &Point3::new(2., 2., 2.)), (1 << 63) - 1);
+        // The top bits are the octant.
+        let octant = |p: Point3<f64>| morton_code(&cube, &p) >> 60;
+        assert_eq!(octant(Point3::new(1.5, 0.5, 0.5)), 0b001);
+        assert_eq!(octant(Point3::new(0.5, 1.5, 0.5)), 0b010);
+        assert_eq!(octant(Point3::new(0.5, 0.5, 1.5)), 0b100);
+    }
+
+    #[test]
+    fn test_morton_sort_in_runs() {
+        let tmp_dir = TempDir::new("morton_sort").unwrap();
+        let input = tmp_dir.path().join("input.ply");
+        let output = tmp_dir.path().join("output.ply");
+        let num_points = 1000;
+        // A point pattern that jumps around.
+        let position: Vec<Point3<f64>> = (0..num_points)
+            .map(|i| {
+                let i = (i * 7919 % num_points) as f64;
+                Point3::new(i % 10., (i / 10.).floor() % 10., (i / 100.).floor())
+            })
+            .collect();
+        let color: Vec<Vector3<u8>> = position
+            .iter()
+            .map(|p| Vector3::new(p.x as u8, p.y as u8, p.z as u8))
+            .collect();
+        let batch = PointsBatch {
+            position,
+            attributes: vec![("color".to_string(), AttributeData::U8Vec3(color))]
+                .into_iter()
+                .collect(),
+        };
+        let mut writer = PlyNodeWriter::new(&input, Encoding::Plain, OpenMode::Truncate);
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+
+        let options = MortonSortOptions {
+            max_points_in_memory: 64,
+            temp_directory: None,
+        };
+        let num_written = morton_sort::<PlyNodeWriter>(&input, &output, &options).unwrap();
+        assert_eq!(num_written, num_points);
+        assert!(!tmp_dir.path().join(".output.ply.runs").exists());
+
+        let mut sorted = PointsBatch::default();
+        for mut batch in PlyIterator::from_file(&output, NUM_POINTS_PER_BATCH).unwrap() {
+            sorted.append(&mut batch).unwrap();
+        }
+        assert_eq!(sorted.position.len(), num_points);
+        let cube = Cube::bounding(&Aabb::new(Point3::new(0., 0., 0.), Point3::new(9., 9., 9.)));
+        let codes: Vec<u64> = sorted
+            .position
+            .iter()
+            .map(|p| morton_code(&cube, p))
+            .collect();
+        assert!(codes.windows(2).all(|w| w[0] <= w[1]));
+        // The attributes stay with their points.
+        let colors: &Vec<Vector3<u8>> = sorted.get_attribute_vec("color").unwrap();
+        for (p, c) in sorted.position.iter().zip(colors) {
+            assert_eq!(Vector3::new(p.x as u8, p.y as u8, p.z as u8), *c);
+        }
+    }
+}
@@ -14,11 +14,11 @@
 
 use crate::data_provider::DataProvider;
 use crate::errors::*;
-use crate::read_write::{AttributeReader, Encoding, RawNodeReader};
-use crate::{AttributeDataType, NumberOfPoints, PointsBatch};
+use crate::read_write::{AttributeReader, ColumnDecoder, Encoding, RawNodeReader};
+use crate::{batch_size_for_bytes, AttributeDataType, NextInto, NumberOfPoints, PointsBatch};
 use num_integer::div_ceil;
 use std::collections::HashMap;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 
 /// Streams points from our data provider representation.
 pub struct NodeIterator {
@@ -53,10 +53,22 @@ impl NodeIterator {
         }
     }
 
+    /// Sizes the batches so that they take about 'max_bytes' of memory instead of 'batch_size'
+    /// points, see 'batch_size_for_bytes'.
+    pub fn with_max_batch_bytes(mut self, max_bytes: usize) -> Self {
+        if let Some(reader) = &self.reader {
+            self.batch_size = batch_size_for_bytes(max_bytes, reader.attribute_data_types());
+        }
+        self
+    }
+
+    /// If 'compressed_attributes' is set, the attribute columns were written with a
+    /// 'ColumnEncoder'.
     pub fn from_data_provider<Id: ToString>(
         data_provider: &dyn DataProvider,
         attribute_data_types: &HashMap<String, AttributeDataType>,
         encoding: Encoding,
+        compressed_attributes: bool,
         id: &Id,
         num_points: usize,
         batch_size: usize,
@@ -75,7 +87,13 @@ impl NodeIterator {
             .iter()
             .map(|(attribute, data_type)| {
                 let data_type = *data_type;
-                let reader = BufReader::new(all_reads.remove(attribute).unwrap());
+                let reader = all_reads.remove(attribute).unwrap();
+                let reader: Box<dyn Read + Send> = if compressed_attributes {
+                    Box::new(ColumnDecoder::new(reader, data_type))
+                } else {
+                    reader
+                };
+                let reader = BufReader::new(reader);
                 let attribute_reader = AttributeReader { data_type, reader };
                 (attribute.clone(), attribute_reader)
             })
@@ -103,17 +121,28 @@ impl Iterator for NodeIterator {
         (num_batches, Some(num_batches))
     }
     fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = PointsBatch::default();
+        if self.next_into(&mut batch) {
+            Some(batch)
+        } else {
+            None
+        }
+    }
+}
+
+impl NextInto for NodeIterator {
+    fn next_into(&mut self, batch: &mut PointsBatch) -> bool {
         if let Some(reader) = &mut self.reader {
             if self.point_count < self.num_points {
                 let num_points_to_read =
                     std::cmp::min(self.batch_size, self.num_points - self.point_count);
-                let res = reader
-                    .read_batch(num_points_to_read)
+                reader
+                    .read_batch_into(num_points_to_read, batch)
                     .expect("Couldn't read from node.");
                 self.point_count += num_points_to_read;
-                return Some(res);
+                return true;
             }
         }
-        None
+        false
     }
 }
@@ -13,13 +13,60 @@
 // limitations under the License.
 
 use crate::color::Color;
-use crate::read_write::{vec3_encode, vec3_fixpoint_encode, Encoding, PositionEncoding};
+use crate::read_write::{
+    vec3_encode, vec3_fixpoint_encode, vec3_write_u24, Encoding, PositionEncoding,
+};
 use crate::AttributeData;
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use nalgebra::{Point3, Vector3};
-use std::fs::{remove_file, File, OpenOptions};
-use std::io::{BufWriter, Result, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs::{self, remove_file, File, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SYNC_ON_CLOSE: AtomicBool = AtomicBool::new(false);
+
+/// Makes all files that are written from now on durable on disk before they are closed, at the
+/// cost of write throughput. Off by default.
+pub fn set_sync_on_close(sync: bool) {
+    SYNC_ON_CLOSE.store(sync, Ordering::SeqCst);
+}
+
+/// The name under which 'path' is written until it is complete.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut file_name = path
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_else(OsString::new);
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
+/// Renames the complete file 'tmp_path' to 'path', syncing both to disk before if 'sync' is set.
+fn commit(file: &File, tmp_path: &Path, path: &Path, sync: bool) -> Result<()> {
+    if sync {
+        file.sync_all()?;
+    }
+    fs::rename(tmp_path, path)?;
+    if sync {
+        // The rename is only durable once the directory is synced.
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            File::open(parent)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the file at 'path' by 'bytes' so that readers never see a partially written file,
+/// even if the process dies meanwhile.
+pub fn write_file_atomically(path: impl AsRef<Path>, bytes: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let tmp_path = tmp_path(path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    commit(&file, &tmp_path, path, SYNC_ON_CLOSE.load(Ordering::SeqCst))
+}
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum OpenMode {
@@ -27,32 +74,74 @@ pub enum OpenMode {
     Append,
 }
 
+/// Writes a file of a node. New files are written under a temporary name and only renamed to
+/// 'path' by 'finish', so a crash never leaves a truncated file that readers later choke on.
+/// Appending to an existing file writes it in place.
 pub struct DataWriter {
     inner: BufWriter<File>,
     bytes_written: u64,
     path: PathBuf,
+    // Where the file is written until it is complete, if it is new.
+    tmp_path: Option<PathBuf>,
+    sync: bool,
+    finished: bool,
 }
 
 impl DataWriter {
     pub fn new(path: impl Into<PathBuf>, open_mode: OpenMode) -> Result<Self> {
         let path = path.into();
+        let tmp_path = if open_mode == OpenMode::Truncate || !path.exists() {
+            Some(self::tmp_path(&path))
+        } else {
+            None
+        };
         let mut inner = OpenOptions::new()
             .write(true)
             .create(true)
-            .truncate(open_mode == OpenMode::Truncate)
-            .open(&path)
+            .truncate(tmp_path.is_some())
+            .open(tmp_path.as_ref().unwrap_or(&path))
             .map(BufWriter::new)?;
         let bytes_written = inner.seek(SeekFrom::End(0))?;
         Ok(DataWriter {
             inner,
             bytes_written,
             path,
+            tmp_path,
+            sync: SYNC_ON_CLOSE.load(Ordering::SeqCst),
+            finished: false,
         })
     }
 
     pub fn bytes_written(&self) -> u64 {
         self.bytes_written
     }
+
+    /// Syncs the file to disk in 'finish' even if 'set_sync_on_close' is off, e.g. for the meta
+    /// data that makes the files written before it part of the octree.
+    pub fn with_sync(mut self) -> Self {
+        self.sync = true;
+        self
+    }
+
+    /// Completes the file: flushes it, renames a new file to 'path' and syncs it to disk if
+    /// 'set_sync_on_close' is on or the writer was created 'with_sync'. A file that nothing was
+    /// written into is removed, since the node should not exist.
+    pub fn finish(mut self) -> Result<()> {
+        self.inner.flush()?;
+        if self.bytes_written == 0 {
+            if let Some(tmp_path) = &self.tmp_path {
+                let _ = remove_file(tmp_path);
+            }
+            // We are ignoring deletion errors here in case the file is already gone.
+            let _ = remove_file(&self.path);
+        } else if let Some(tmp_path) = &self.tmp_path {
+            commit(self.inner.get_ref(), tmp_path, &self.path, self.sync)?;
+        } else if self.sync {
+            self.inner.get_ref().sync_all()?;
+        }
+        self.finished = true;
+        Ok(())
+    }
 }
 
 impl Write for DataWriter {
@@ -77,10 +166,12 @@ impl Seek for DataWriter {
 
 impl Drop for DataWriter {
     fn drop(&mut self) {
-        // If we did not write anything into this node, it should not exist.
-        if self.bytes_written == 0 {
-            // We are ignoring deletion errors here in case the file is already gone.
-            let _ = remove_file(&self.path);
+        // A writer that is dropped without 'finish', e.g. while panicking or after an error, did
+        // not complete its file, so a new file is never renamed to 'path'.
+        if !self.finished {
+            if let Some(tmp_path) = &self.tmp_path {
+                let _ = remove_file(tmp_path);
+            }
         }
 
         // TODO(hrapp): Add some sanity checks that we do not have nodes with ridiculously low
@@ -89,13 +180,13 @@ impl Drop for DataWriter {
 }
 
 pub trait WriteLE {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()>;
+    fn write_le(&self, writer: &mut impl Write) -> Result<()>;
 }
 
 macro_rules! derive_write_le {
     ($scalar:ty, $method:ident) => {
         impl WriteLE for $scalar {
-            fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+            fn write_le(&self, writer: &mut impl Write) -> Result<()> {
                 writer.$method::<LittleEndian>(*self)
             }
         }
@@ -105,7 +196,7 @@ macro_rules! derive_write_le {
 macro_rules! derive_write_le_vec {
     ($scalar:ty, $method:ident) => {
         impl WriteLE for Vec<$scalar> {
-            fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+            fn write_le(&self, writer: &mut impl Write) -> Result<()> {
                 let mut bytes = vec![0; std::mem::size_of::<$scalar>() * self.len()];
                 LittleEndian::$method(self, &mut bytes);
                 writer.write_all(&bytes)
@@ -115,26 +206,26 @@ macro_rules! derive_write_le_vec {
 }
 
 impl WriteLE for i8 {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         writer.write_i8(*self)
     }
 }
 
 impl WriteLE for u8 {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         writer.write_u8(*self)
     }
 }
 
 impl WriteLE for Vec<i8> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         let u8slice = unsafe { &*(self.as_slice() as *const [i8] as *const [u8]) };
         writer.write_all(u8slice)
     }
 }
 
 impl WriteLE for Vec<u8> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         writer.write_all(self.as_slice())
     }
 }
@@ -157,13 +248,13 @@ derive_write_le_vec!(u32, write_u32_into);
 derive_write_le_vec!(u64, write_u64_into);
 
 impl WriteLE for Vector3<u8> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         writer.write_all(self.as_slice())
     }
 }
 
 impl WriteLE for Vector3<u16> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         let mut bytes = [0; 6];
         LittleEndian::write_u16_into(self.as_slice(), &mut bytes);
         writer.write_all(&bytes)
@@ -171,7 +262,7 @@ impl WriteLE for Vector3<u16> {
 }
 
 impl WriteLE for Vector3<f32> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         let mut bytes = [0; 12];
         LittleEndian::write_f32_into(self.as_slice(), &mut bytes);
         writer.write_all(&bytes)
@@ -179,7 +270,7 @@ impl WriteLE for Vector3<f32> {
 }
 
 impl WriteLE for Vector3<f64> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         let mut bytes = [0; 24];
         LittleEndian::write_f64_into(self.as_slice(), &mut bytes);
         writer.write_all(&bytes)
@@ -187,7 +278,7 @@ impl WriteLE for Vector3<f64> {
 }
 
 impl WriteLE for Color<u8> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         writer.write_u8(self.red)?;
         writer.write_u8(self.green)?;
         writer.write_u8(self.blue)
@@ -196,7 +287,7 @@ impl WriteLE for Color<u8> {
 }
 
 impl WriteLE for Vec<Vector3<u8>> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         for elem in self {
             elem.write_le(writer)?;
         }
@@ -205,7 +296,7 @@ impl WriteLE for Vec<Vector3<u8>> {
 }
 
 impl WriteLE for Vec<Vector3<f64>> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         for elem in self {
             elem.write_le(writer)?;
         }
@@ -214,7 +305,7 @@ impl WriteLE for Vec<Vector3<f64>> {
 }
 
 impl WriteLE for Vec<Point3<f64>> {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         for elem in self {
             elem.coords.write_le(writer)?;
         }
@@ -223,7 +314,7 @@ impl WriteLE for Vec<Point3<f64>> {
 }
 
 impl WriteLE for AttributeData {
-    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+    fn write_le(&self, writer: &mut impl Write) -> Result<()> {
         macro_rules! rhs {
             ($dtype:ident, $data:ident, $writer:ident) => {
                 WriteLE::write_le($data, $writer)
@@ -248,12 +339,21 @@ impl WriteLEPos for AttributeData {
     }
 }
 
+/// Every point of the Delta position encoding depends on the previous one, so only writers which
+/// keep a 'DeltaState' per node, like 'RawNodeWriter', can use it.
+fn delta_needs_state() -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        "The Delta position encoding is only supported by RawNodeWriter.",
+    )
+}
+
 pub trait WriteEncoded {
-    fn write_encoded(&self, encoding: &Encoding, writer: &mut DataWriter) -> Result<()>;
+    fn write_encoded(&self, encoding: &Encoding, writer: &mut impl Write) -> Result<()>;
 }
 
 impl WriteEncoded for Point3<f64> {
-    fn write_encoded(&self, encoding: &Encoding, writer: &mut DataWriter) -> Result<()> {
+    fn write_encoded(&self, encoding: &Encoding, writer: &mut impl Write) -> Result<()> {
         match encoding {
             Encoding::Plain => self.coords.write_le(writer),
             Encoding::ScaledToCube(min, edge_length, position_encoding) => {
@@ -272,6 +372,8 @@ impl WriteEncoded for Point3<f64> {
                     PositionEncoding::Float64 => {
                         vec3_encode::<f64>(self, min, *edge_length).write_le(writer)
                     }
+                    PositionEncoding::Uint24 => vec3_write_u24(self, min, *edge_length, writer),
+                    PositionEncoding::Delta => Err(delta_needs_state()),
                 }
             }
         }
@@ -279,7 +381,7 @@ impl WriteEncoded for Point3<f64> {
 }
 
 impl WriteEncoded for Vec<Point3<f64>> {
-    fn write_encoded(&self, encoding: &Encoding, writer: &mut DataWriter) -> Result<()> {
+    fn write_encoded(&self, encoding: &Encoding, writer: &mut impl Write) -> Result<()> {
         match encoding {
             Encoding::Plain => self.write_le(writer),
             Encoding::ScaledToCube(min, edge_length, position_encoding) => {
@@ -308,6 +410,12 @@ impl WriteEncoded for Vec<Point3<f64>> {
                             vec3_encode::<f64>(position, min, *edge_length).write_le(writer)?;
                         }
                     }
+                    PositionEncoding::Uint24 => {
+                        for position in self {
+                            vec3_write_u24(position, min, *edge_length, writer)?;
+                        }
+                    }
+                    PositionEncoding::Delta => return Err(delta_needs_state()),
                 }
                 Ok(())
             }
@@ -315,7 +423,66 @@ impl WriteEncoded for Vec<Point3<f64>> {
     }
 }
 
+/// Number of points that 'NodeWriter::write_many' serializes into a buffer before writing them.
+pub(crate) const POINTS_PER_BLOCK: usize = 4096;
+
 pub trait NodeWriter<P> {
     fn new(path: impl Into<PathBuf>, codec: Encoding, open_mode: OpenMode) -> Self;
     fn write(&mut self, p: &P) -> Result<()>;
+
+    /// Completes the files of the node, e.g. by writing a header that depends on all points, and
+    /// reports the errors of doing so, see 'DataWriter::finish'. New files of a writer that is
+    /// dropped without being finished are removed.
+    fn finish(self) -> Result<()>;
+
+    /// Writes all of 'points', as if 'write' was called for each of them. Writers override this
+    /// to check their header once and write the points in blocks, which is a lot faster.
+    fn write_many(&mut self, points: &[P]) -> Result<()> {
+        for p in points {
+            self.write(p)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_data_writer_replaces_on_finish() {
+        let tmp_dir = TempDir::new("test_data_writer_replaces_on_finish").unwrap();
+        let path = tmp_dir.path().join("r0.xyz");
+        fs::write(&path, b"old").unwrap();
+        let mut writer = DataWriter::new(&path, OpenMode::Truncate).unwrap();
+        writer.write_all(b"new data").unwrap();
+        writer.flush().unwrap();
+        // Readers still see the complete old file.
+        assert_eq!(b"old".to_vec(), fs::read(&path).unwrap());
+        writer.finish().unwrap();
+        assert_eq!(b"new data".to_vec(), fs::read(&path).unwrap());
+        assert!(!tmp_path(&path).exists());
+
+        let mut writer = DataWriter::new(&path, OpenMode::Append).unwrap();
+        writer.write_all(b"!").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(b"new data!".to_vec(), fs::read(&path).unwrap());
+
+        // Not finished, so the file is left as it was.
+        {
+            let mut writer = DataWriter::new(&path, OpenMode::Truncate).unwrap();
+            writer.write_all(b"partial").unwrap();
+        }
+        assert_eq!(b"new data!".to_vec(), fs::read(&path).unwrap());
+        assert!(!tmp_path(&path).exists());
+
+        // Nothing written, so the node does not exist.
+        DataWriter::new(&path, OpenMode::Truncate)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert!(!path.exists());
+        assert!(!tmp_path(&path).exists());
+    }
 }
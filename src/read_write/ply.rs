@@ -12,20 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::color::u16_to_u8;
 use crate::errors::*;
+use crate::read_write::node_writer::POINTS_PER_BLOCK;
+use crate::read_write::simd::{decode_xyz_f32_rgb_u8, XYZ_F32_RGB_U8_SIZE};
 use crate::read_write::{
     DataWriter, Encoding, NodeWriter, OpenMode, PositionEncoding, WriteEncoded, WriteLE, WriteLEPos,
 };
-use crate::{AttributeData, NumberOfPoints, Point, PointsBatch};
+use crate::{
+    batch_size_for_bytes, AttributeData, AttributeDataType, NextInto, NumberOfPoints, Point,
+    PointsBatch,
+};
 use byteorder::{ByteOrder, LittleEndian};
 use nalgebra::{Point3, Vector3};
 use num_integer::div_ceil;
 use num_traits::identities::Zero;
-use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
-use std::ops::Index;
+use std::ops::{Index, Range};
 use std::path::{Path, PathBuf};
 use std::str::{from_utf8, FromStr};
 
@@ -33,11 +38,20 @@ const HEADER_START_TO_NUM_VERTICES: &[u8] =
     b"ply\nformat binary_little_endian 1.0\nelement vertex ";
 const HEADER_NUM_VERTICES: &[u8] = b"00000000000000000000";
 
+// The read buffer of 'PlyIterator' holds this many points, but at least 'MIN_BUFFER_BYTES' and at
+// most the configurable maximum, which defaults to 'DEFAULT_MAX_BUFFER_BYTES'.
+const NUM_POINTS_PER_BUFFER: usize = 1024;
+const MIN_BUFFER_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_BUFFER_BYTES: usize = 8 * 1024 * 1024;
+
 #[derive(Debug)]
 struct Header {
-    format: Format,
+    format: PlyFormat,
     elements: Vec<Element>,
     offset: Vector3<f64>,
+    comments: Vec<String>,
+    /// The violations of the format that were tolerated in lenient mode.
+    repairs: Vec<String>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -67,7 +81,7 @@ impl DataType {
             "uint" | "uint32" => Ok(DataType::Uint32),
             "longlong" | "int64" => Ok(DataType::Int64),
             "ulonglong" | "uint64" => Ok(DataType::Uint64),
-            _ => Err(ErrorKind::InvalidInput(format!("Invalid data type: {}", input)).into()),
+            _ => Err(Error::InvalidInput(format!("Invalid data type: {}", input))),
         }
     }
 }
@@ -90,13 +104,45 @@ impl<'a> Index<&'a str> for Header {
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum Format {
+/// The encoding of the data of a PLY file. Only 'BinaryLittleEndianV1' can be read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlyFormat {
     BinaryLittleEndianV1,
     BinaryBigEndianV1,
     AsciiV1,
 }
 
+/// The header of a PLY file, see 'read_header'.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlyInfo {
+    pub format: PlyFormat,
+    pub elements: Vec<PlyElement>,
+    /// The offset that is added to all positions, from the comment 'offset: <x> <y> <z>', or zero.
+    pub offset: Vector3<f64>,
+    /// All comments of the header, without the keyword 'comment'.
+    pub comments: Vec<String>,
+    /// The number of bytes of the header, i.e. where the data starts.
+    pub header_len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlyElement {
+    pub name: String,
+    pub count: i64,
+    pub properties: Vec<PlyProperty>,
+}
+
+/// A property as declared in the header, including list properties, which are not read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlyProperty {
+    pub name: String,
+    /// The type name as written in the header, e.g. 'float' or 'uchar', and for lists the type
+    /// of their entries.
+    pub data_type: String,
+    /// The type of the length of a list property, or 'None' for scalar properties.
+    pub list_count_type: Option<String>,
+}
+
 // TODO(hrapp): Maybe support list properties too?
 #[derive(Debug, Clone)]
 struct ScalarProperty {
@@ -109,6 +155,8 @@ struct Element {
     name: String,
     count: i64,
     properties: Vec<ScalarProperty>,
+    // All properties in the order of the header, for 'read_header'.
+    declared_properties: Vec<PlyProperty>,
 }
 
 impl<'a> Index<&'a str> for Element {
@@ -123,34 +171,63 @@ impl<'a> Index<&'a str> for Element {
     }
 }
 
-fn parse_header<R: BufRead>(reader: &mut R) -> Result<(Header, usize)> {
-    use crate::errors::ErrorKind::InvalidInput;
+/// Parses the header. If 'lenient' is set, lines that violate the format in ways that are common
+/// in real world files are repaired or skipped and recorded in 'Header::repairs'.
+fn parse_header<R: BufRead>(reader: &mut R, lenient: bool) -> Result<(Header, usize)> {
+    use crate::errors::Error::{InvalidInput, UnsupportedFormat};
 
     let mut header_len = 0;
     let mut line = String::new();
     header_len += reader.read_line(&mut line)?;
     if line.trim() != "ply" {
-        return Err(InvalidInput("Not a PLY file".to_string()).into());
+        return Err(InvalidInput("Not a PLY file".to_string()));
     }
 
+    let mut repairs = Vec::new();
+    if lenient && line.ends_with("\r\n") {
+        repairs.push("The header has CRLF line endings.".to_string());
+    }
     let mut format = None;
     let mut current_element = None;
     let mut offset = Vector3::zero();
+    let mut comments = Vec::new();
     let mut elements = Vec::new();
     loop {
         line.clear();
-        header_len += reader.read_line(&mut line)?;
+        let line_len = reader.read_line(&mut line)?;
+        if line_len == 0 {
+            return Err(InvalidInput("The header has no 'end_header'.".to_string()));
+        }
+        header_len += line_len;
         let entries: Vec<&str> = line.trim().split_whitespace().collect();
         match entries.get(0) {
             Some(&"format") if entries.len() == 3 => {
                 if entries[2] != "1.0" {
-                    return Err(InvalidInput(format!("Invalid version: {}", entries[2])).into());
+                    return Err(UnsupportedFormat(format!(
+                        "Invalid version: {}",
+                        entries[2]
+                    )));
                 }
                 format = Some(match entries.get(1) {
-                    Some(&"ascii") => Format::AsciiV1,
-                    Some(&"binary_little_endian") => Format::BinaryLittleEndianV1,
-                    Some(&"binary_big_endian") => Format::BinaryBigEndianV1,
-                    _ => return Err(InvalidInput(format!("Invalid format: {}", entries[1])).into()),
+                    Some(&"ascii") => PlyFormat::AsciiV1,
+                    Some(&"binary_little_endian") => PlyFormat::BinaryLittleEndianV1,
+                    Some(&"binary_big_endian") => PlyFormat::BinaryBigEndianV1,
+                    _ => return Err(UnsupportedFormat(format!("Invalid format: {}", entries[1]))),
+                });
+            }
+            Some(&"element") if lenient && entries.len() == 2 => {
+                if let Some(element) = current_element.take() {
+                    elements.push(element);
+                }
+                repairs.push(format!(
+                    "Element '{}' has no count, assuming 0.",
+                    entries[1]
+                ));
+                current_element = Some(Element {
+                    name: entries[1].to_string(),
+                    count: 0,
+                    properties: Vec::new(),
+                    declared_properties: Vec::new(),
                 });
             }
             Some(&"element") if entries.len() == 3 => {
@@ -161,48 +238,64 @@ fn parse_header<R: BufRead>(reader: &mut R) -> Result<(Header, usize)> {
                     name: entries[1].to_string(),
                     count: entries[2]
                         .parse::<i64>()
-                        .chain_err(|| InvalidInput(format!("Invalid count: {}", entries[2])))?,
+                        .map_err(|_| InvalidInput(format!("Invalid count: {}", entries[2])))?,
                     properties: Vec::new(),
+                    declared_properties: Vec::new(),
                 });
             }
             Some(&"property") => {
                 if current_element.is_none() {
-                    return Err(
-                        InvalidInput(format!("property outside of element: {}", line)).into(),
-                    );
+                    return Err(InvalidInput(format!(
+                        "property outside of element: {}",
+                        line
+                    )));
                 };
-                let property = match entries.get(1) {
+                let element = current_element.as_mut().unwrap();
+                match entries.get(1) {
                     Some(&"list") if entries.len() == 5 => {
                         // We do not support list properties.
-                        continue;
+                        element.declared_properties.push(PlyProperty {
+                            name: entries[4].to_string(),
+                            data_type: entries[3].to_string(),
+                            list_count_type: Some(entries[2].to_string()),
+                        });
                     }
                     Some(data_type_str) if entries.len() == 3 => {
                         let data_type = DataType::from_str(data_type_str)?;
-                        ScalarProperty {
+                        element.properties.push(ScalarProperty {
                             name: entries[2].to_string(),
                             data_type,
-                        }
+                        });
+                        element.declared_properties.push(PlyProperty {
+                            name: entries[2].to_string(),
+                            data_type: data_type_str.to_string(),
+                            list_count_type: None,
+                        });
                     }
-                    _ => return Err(InvalidInput(format!("Invalid line: {}", line)).into()),
-                };
-                current_element.as_mut().unwrap().properties.push(property);
+                    _ => return Err(InvalidInput(format!("Invalid line: {}", line))),
+                }
             }
             Some(&"end_header") => break,
             Some(&"comment") => {
+                comments.push(line.trim()["comment".len()..].trim().to_string());
                 if entries.len() == 5 && entries[1] == "offset:" {
                     let x = entries[2]
                         .parse::<f64>()
-                        .chain_err(|| InvalidInput(format!("Invalid offset: {}", entries[2])))?;
+                        .map_err(|_| InvalidInput(format!("Invalid offset: {}", entries[2])))?;
                     let y = entries[3]
                         .parse::<f64>()
-                        .chain_err(|| InvalidInput(format!("Invalid offset: {}", entries[3])))?;
+                        .map_err(|_| InvalidInput(format!("Invalid offset: {}", entries[3])))?;
                     let z = entries[4]
                         .parse::<f64>()
-                        .chain_err(|| InvalidInput(format!("Invalid offset: {}", entries[4])))?;
+                        .map_err(|_| InvalidInput(format!("Invalid offset: {}", entries[4])))?;
                     offset = Vector3::new(x, y, z)
                 }
             }
-            _ => return Err(InvalidInput(format!("Invalid line: {}", line)).into()),
+            None if lenient => repairs.push("Ignored an empty header line.".to_string()),
+            Some(_) if lenient => {
+                repairs.push(format!("Ignored the header line '{}'.", line.trim()))
+            }
+            _ => return Err(InvalidInput(format!("Invalid line: {}", line))),
         }
     }
 
@@ -210,20 +303,50 @@ fn parse_header<R: BufRead>(reader: &mut R) -> Result<(Header, usize)> {
         elements.push(element);
     }
 
-    if format.is_none() {
-        return Err(InvalidInput("No format specified".into()).into());
-    }
+    let format = match format {
+        Some(format) => format,
+        None if lenient => {
+            repairs.push("No format specified, assuming binary_little_endian.".to_string());
+            PlyFormat::BinaryLittleEndianV1
+        }
+        None => return Err(InvalidInput("No format specified".into())),
+    };
 
     Ok((
         Header {
             elements,
-            format: format.unwrap(),
+            format,
             offset,
+            comments,
+            repairs,
         },
         header_len,
     ))
 }
 
+/// Reads the header of the PLY file at 'path' without reading its data, e.g. to check a delivery
+/// or to show its schema before ingesting it.
+pub fn read_header(path: impl AsRef<Path>) -> Result<PlyInfo> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Could not open {}.", path.display()))?;
+    let (header, header_len) = parse_header(&mut BufReader::new(file), false)?;
+    Ok(PlyInfo {
+        format: header.format,
+        elements: header
+            .elements
+            .into_iter()
+            .map(|element| PlyElement {
+                name: element.name,
+                count: element.count,
+                properties: element.declared_properties,
+            })
+            .collect(),
+        offset: header.offset,
+        comments: header.comments,
+        header_len,
+    })
+}
+
 type ReadingFn = fn(nread: &mut usize, buf: &[u8], data: &mut AttributeData);
 
 // The three macros create a 'ReadingFn' that reads a value of '$data_type' out of a reader, and
@@ -295,7 +418,7 @@ macro_rules! push_reader {
 // pointer.
 macro_rules! push_skip_reader {
     ($prop:expr, &mut $size:ident, $num_bytes:expr) => {{
-        eprintln!("Will ignore property '{}' on 'vertex'.", $prop.name);
+        log::warn!("Will ignore property '{}' on 'vertex'.", $prop.name);
         $size += $num_bytes;
         fn _read_fn(nread: &mut usize, _: &[u8], _: &mut AttributeData) {
             *nread += $num_bytes;
@@ -314,30 +437,143 @@ struct PropertyReader {
     func: ReadingFn,
 }
 
+/// Returns the number of bytes per vertex if the vertices consist of exactly 'x', 'y', 'z' as
+/// float32 and 'red', 'green', 'blue' and optionally 'alpha' as uint8, the layout written by most
+/// scanners, which can be decoded without going through the 'PropertyReader's.
+fn xyz_f32_rgb_u8_stride(vertex: &Element) -> Option<usize> {
+    let layout: &[(&[&str], DataType)] = &[
+        (&["x"], DataType::Float32),
+        (&["y"], DataType::Float32),
+        (&["z"], DataType::Float32),
+        (&["r", "red"], DataType::Uint8),
+        (&["g", "green"], DataType::Uint8),
+        (&["b", "blue"], DataType::Uint8),
+        (&["a", "alpha"], DataType::Uint8),
+    ];
+    let properties = &vertex.properties;
+    let matches = properties.len() >= layout.len() - 1
+        && properties.len() <= layout.len()
+        && properties
+            .iter()
+            .zip(layout)
+            .all(|(prop, (names, data_type))| {
+                names.contains(&&prop.name[..]) && prop.data_type == *data_type
+            });
+    if matches {
+        Some(XYZ_F32_RGB_U8_SIZE + properties.len() - (layout.len() - 1))
+    } else {
+        None
+    }
+}
+
 /// Abstraction to read binary points from ply files into points.
 pub struct PlyIterator {
     reader: BufReader<File>,
     readers: Vec<PropertyReader>,
+    // Set if the vertices have a fixed layout that is decoded in blocks, see
+    // 'xyz_f32_rgb_u8_stride'.
+    xyz_f32_rgb_u8_stride: Option<usize>,
+    record_buffer: Vec<u8>,
+    // Holds a point that is not completely in the buffer of 'reader'.
+    point_buffer: Vec<u8>,
+    xyz_buffer: Vec<f64>,
     pub num_total_points: i64,
     batch_size: usize,
     offset: Vector3<f64>,
     point_count: usize,
+    repairs: Vec<String>,
+    // Where the vertices start in the file and their size, for seeking to a point.
+    data_start: u64,
+    num_bytes_per_point: usize,
 }
 
 impl PlyIterator {
     pub fn from_file<P: AsRef<Path>>(ply_file: P, batch_size: usize) -> Result<Self> {
-        let mut file = File::open(ply_file).chain_err(|| "Could not open input file.")?;
+        Self::open(ply_file.as_ref(), batch_size, false)
+    }
+
+    /// Like 'from_file', but tolerates violations of the format that are common in real world
+    /// files: CRLF line endings, unknown or empty header lines, a missing format or element count,
+    /// and a vertex count or blank lines after 'end_header' that do not match the size of the
+    /// data. 'repairs' describes what was fixed.
+    pub fn from_file_lenient<P: AsRef<Path>>(ply_file: P, batch_size: usize) -> Result<Self> {
+        Self::open(ply_file.as_ref(), batch_size, true)
+    }
+
+    /// The violations of the format that were tolerated, empty unless opened with
+    /// 'from_file_lenient'.
+    pub fn repairs(&self) -> &[String] {
+        &self.repairs
+    }
+
+    /// Moves to the point with index 'n', so that the next batch starts with it. The rows of
+    /// binary PLY files have a fixed size, so this does not read the points before it.
+    pub fn seek_to_point(&mut self, n: usize) -> Result<()> {
+        if n > self.num_total_points as usize {
+            return Err(Error::InvalidInput(format!(
+                "Cannot seek to point {} of a file with {} points.",
+                n, self.num_total_points
+            )));
+        }
+        self.reader.seek(SeekFrom::Start(
+            self.data_start + (n * self.num_bytes_per_point) as u64,
+        ))?;
+        self.point_count = n;
+        Ok(())
+    }
+
+    /// Reads the points with indices in 'range' as one batch, e.g. for samplers or to split a
+    /// file between threads. Iterating continues after the range.
+    pub fn read_range(&mut self, range: Range<usize>) -> Result<PointsBatch> {
+        if range.start > range.end || range.end > self.num_total_points as usize {
+            return Err(Error::InvalidInput(format!(
+                "Points {:?} are not in a file with {} points.",
+                range, self.num_total_points
+            )));
+        }
+        self.seek_to_point(range.start)?;
+        let mut batch = PointsBatch::default();
+        self.read_points_into(range.len(), &mut batch);
+        Ok(batch)
+    }
+
+    /// Sizes the batches so that they take about 'max_bytes' of memory instead of 'batch_size'
+    /// points, see 'batch_size_for_bytes'.
+    pub fn with_max_batch_bytes(mut self, max_bytes: usize) -> Self {
+        let attribute_data_types = self.attribute_data_types();
+        self.batch_size = batch_size_for_bytes(max_bytes, attribute_data_types.values().copied());
+        self
+    }
+
+    /// Limits the read buffer to 'max_bytes' instead of 8 MiB. Points that are larger than the
+    /// buffer are still read correctly, just with more system calls.
+    pub fn with_max_buffer_bytes(mut self, max_bytes: usize) -> Result<Self> {
+        // The position of the next point, which the buffer may already be past.
+        let position = self.reader.seek(SeekFrom::Current(0))?;
+        let mut file = self.reader.into_inner();
+        file.seek(SeekFrom::Start(position))?;
+        self.reader =
+            BufReader::with_capacity(buffer_capacity(self.num_bytes_per_point, max_bytes), file);
+        Ok(self)
+    }
+
+    fn open(ply_file: &Path, batch_size: usize, lenient: bool) -> Result<Self> {
+        let mut file = File::open(ply_file).with_context(|| "Could not open input file.")?;
         let mut reader = BufReader::new(file);
-        let (header, header_len) = parse_header(&mut reader)?;
+        let (mut header, mut header_len) = parse_header(&mut reader, lenient)?;
         file = reader.into_inner();
-        file.seek(SeekFrom::Start(header_len as u64))?;
 
         if !header.has_element("vertex") {
-            panic!("Header does not have element 'vertex'");
+            return Err(Error::InvalidInput(
+                "Header does not have element 'vertex'".to_string(),
+            ));
         }
 
-        if header.format != Format::BinaryLittleEndianV1 {
-            panic!("Unsupported PLY format: {:?}", header.format);
+        if header.format != PlyFormat::BinaryLittleEndianV1 {
+            return Err(Error::UnsupportedFormat(format!(
+                "Unsupported PLY format: {:?}",
+                header.format
+            )));
         }
 
         let vertex = &header["vertex"];
@@ -383,6 +619,18 @@ impl PlyIterator {
                 "a" | "alpha" => {
                     readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 1));
                 }
+                "r" | "red" | "g" | "green" | "b" | "blue"
+                    if prop.data_type == DataType::Uint16 =>
+                {
+                    // Dithered down to 8 bits in 'fill_batch_from_readers'.
+                    push_reader!(
+                        readers,
+                        prop,
+                        AttributeData::U16(Vec::with_capacity(batch_size)),
+                        &mut num_bytes_per_point,
+                        u16
+                    );
+                }
                 other => {
                     // TODO(feuerste): We may need to support multidimensional attributes.
                     assert!(!other.chars().last().unwrap().is_ascii_digit(),
@@ -438,75 +686,213 @@ impl PlyIterator {
         }
 
         if !seen_x || !seen_y || !seen_z {
-            panic!("PLY must contain properties 'x', 'y', 'z' for 'vertex'.");
+            return Err(Error::InvalidInput(
+                "PLY must contain properties 'x', 'y', 'z' for 'vertex'.".to_string(),
+            ));
+        }
+
+        // The size of the data can only be checked if it holds nothing but the vertices.
+        let only_vertices = header
+            .elements
+            .iter()
+            .all(|e| e.name == "vertex" || e.count == 0);
+        if lenient && only_vertices && num_bytes_per_point > 0 {
+            let data_len = file.metadata()?.len().saturating_sub(header_len as u64) as usize;
+            let vertex_count = header["vertex"].count.max(0) as usize;
+            let expected_len = vertex_count * num_bytes_per_point;
+            let extra_len = data_len.saturating_sub(expected_len);
+            if vertex_count == 0 && data_len > 0 {
+                header.repairs.push(format!(
+                    "The vertex count is 0, but the data holds {} vertices.",
+                    data_len / num_bytes_per_point
+                ));
+                set_vertex_count(&mut header, data_len / num_bytes_per_point);
+            } else if data_len < expected_len {
+                header.repairs.push(format!(
+                    "The header announces {} vertices, but the data only holds {}.",
+                    vertex_count,
+                    data_len / num_bytes_per_point
+                ));
+                set_vertex_count(&mut header, data_len / num_bytes_per_point);
+            } else if extra_len > 0 {
+                file.seek(SeekFrom::Start(header_len as u64))?;
+                let mut start = vec![0; extra_len];
+                file.read_exact(&mut start)?;
+                if extra_len < num_bytes_per_point && start.iter().all(u8::is_ascii_whitespace) {
+                    header.repairs.push(format!(
+                        "Skipped {} bytes of line breaks after 'end_header'.",
+                        extra_len
+                    ));
+                    header_len += extra_len;
+                } else {
+                    header.repairs.push(format!(
+                        "Ignored {} bytes after the last vertex.",
+                        extra_len
+                    ));
+                }
+            }
         }
+        file.seek(SeekFrom::Start(header_len as u64))?;
 
-        // We align the buffer of this 'BufReader' to points, so that we can index this buffer and know
-        // that it will always contain full points to parse.
         Ok(PlyIterator {
-            reader: BufReader::with_capacity(num_bytes_per_point * 1024, file),
+            reader: BufReader::with_capacity(
+                buffer_capacity(num_bytes_per_point, DEFAULT_MAX_BUFFER_BYTES),
+                file,
+            ),
             readers,
+            xyz_f32_rgb_u8_stride: xyz_f32_rgb_u8_stride(&header["vertex"]),
+            record_buffer: Vec::new(),
+            point_buffer: Vec::new(),
+            xyz_buffer: Vec::new(),
             num_total_points: header["vertex"].count,
             batch_size,
             offset: header.offset,
             point_count: 0,
+            repairs: header.repairs,
+            data_start: header_len as u64,
+            num_bytes_per_point,
         })
     }
+
+    /// The attributes of the batches and their data types, without the positions.
+    pub fn attribute_data_types(&self) -> BTreeMap<String, AttributeDataType> {
+        self.readers
+            .iter()
+            .filter_map(|reader| {
+                match &reader.prop.name as &str {
+                    // Alpha is not read.
+                    "x" | "y" | "z" | "a" | "alpha" => None,
+                    // 16 bit colors are read as 8 bits.
+                    "r" | "red" | "g" | "green" | "b" | "blue" => {
+                        Some(("color".to_string(), AttributeDataType::U8Vec3))
+                    }
+                    name => match reader.prop.data_type {
+                        DataType::Uint8
+                        | DataType::Uint64
+                        | DataType::Int64
+                        | DataType::Float32
+                        | DataType::Float64 => Some((name.to_string(), reader.data.data_type())),
+                        DataType::Int8
+                        | DataType::Uint16
+                        | DataType::Int16
+                        | DataType::Uint32
+                        | DataType::Int32 => None,
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+fn buffer_capacity(num_bytes_per_point: usize, max_bytes: usize) -> usize {
+    std::cmp::min(
+        std::cmp::max(
+            num_bytes_per_point * NUM_POINTS_PER_BUFFER,
+            MIN_BUFFER_BYTES,
+        ),
+        max_bytes,
+    )
+    .max(1)
+}
+
+fn set_vertex_count(header: &mut Header, count: usize) {
+    for element in &mut header.elements {
+        if element.name == "vertex" {
+            element.count = count as i64;
+        }
+    }
 }
 
-fn batch_from_readers(readers: &mut [PropertyReader], offset: &Vector3<f64>) -> PointsBatch {
-    let (mut x_vec, mut y_vec, mut z_vec) = (Vec::new(), Vec::new(), Vec::new());
-    let (mut r_vec, mut g_vec, mut b_vec) = (Vec::new(), Vec::new(), Vec::new());
-    let mut attributes = BTreeMap::new();
-    for reader in readers {
+/// Moves the values read by 'readers' into 'batch', keeping the allocations of both. The points
+/// start at index 'first_point' of the file, which seeds the dithering of 16 bit colors.
+fn fill_batch_from_readers(
+    readers: &mut [PropertyReader],
+    offset: &Vector3<f64>,
+    first_point: usize,
+    batch: &mut PointsBatch,
+) {
+    let (mut x_vec, mut y_vec, mut z_vec) = (None, None, None);
+    let (mut r_vec, mut g_vec, mut b_vec) = (None, None, None);
+    let mut others = Vec::new();
+    for reader in readers.iter_mut() {
         let data = &mut reader.data;
         match &reader.prop.name as &str {
-            "x" => x_vec = <&mut Vec<f64>>::try_from(data).unwrap().split_off(0),
-            "y" => y_vec = <&mut Vec<f64>>::try_from(data).unwrap().split_off(0),
-            "z" => z_vec = <&mut Vec<f64>>::try_from(data).unwrap().split_off(0),
-            "r" | "red" => r_vec = <&mut Vec<u8>>::try_from(data).unwrap().split_off(0),
-            "g" | "green" => g_vec = <&mut Vec<u8>>::try_from(data).unwrap().split_off(0),
-            "b" | "blue" => b_vec = <&mut Vec<u8>>::try_from(data).unwrap().split_off(0),
+            "x" => x_vec = Some(<&mut Vec<f64>>::try_from(data).unwrap()),
+            "y" => y_vec = Some(<&mut Vec<f64>>::try_from(data).unwrap()),
+            "z" => z_vec = Some(<&mut Vec<f64>>::try_from(data).unwrap()),
+            "r" | "red" => r_vec = Some(data),
+            "g" | "green" => g_vec = Some(data),
+            "b" | "blue" => b_vec = Some(data),
             "a" | "alpha" => {}
-            other => {
-                let other_data = match reader.prop.data_type {
-                    DataType::Uint8
-                    | DataType::Uint64
-                    | DataType::Int64
-                    | DataType::Float32
-                    | DataType::Float64 => data.split_off(0),
-                    DataType::Int8
-                    | DataType::Uint16
-                    | DataType::Int16
-                    | DataType::Uint32
-                    | DataType::Int32 => continue,
-                };
-                attributes.insert(other.to_string(), other_data);
-            }
+            other => match reader.prop.data_type {
+                DataType::Uint8
+                | DataType::Uint64
+                | DataType::Int64
+                | DataType::Float32
+                | DataType::Float64 => others.push((other, data)),
+                DataType::Int8
+                | DataType::Uint16
+                | DataType::Int16
+                | DataType::Uint32
+                | DataType::Int32 => continue,
+            },
         }
     }
-    let position: Vec<Point3<f64>> = x_vec
-        .into_iter()
-        .zip(y_vec.into_iter())
-        .zip(z_vec.into_iter())
-        .map(|((x, y), z)| Point3::new(x, y, z) + offset)
-        .collect();
-    if !r_vec.is_empty() {
-        attributes.insert(
-            "color".to_string(),
-            AttributeData::U8Vec3(
+    batch.reset(
+        r_vec
+            .iter()
+            .map(|_| ("color", AttributeDataType::U8Vec3))
+            .chain(others.iter().map(|(name, data)| (*name, data.data_type()))),
+    );
+
+    let (x_vec, y_vec, z_vec) = (x_vec.unwrap(), y_vec.unwrap(), z_vec.unwrap());
+    batch.position.extend(
+        x_vec
+            .iter()
+            .zip(y_vec.iter())
+            .zip(z_vec.iter())
+            .map(|((x, y), z)| Point3::new(*x, *y, *z) + offset),
+    );
+    x_vec.clear();
+    y_vec.clear();
+    z_vec.clear();
+    if let (Some(r_vec), Some(g_vec), Some(b_vec)) = (r_vec, g_vec, b_vec) {
+        let color: &mut Vec<Vector3<u8>> = batch.get_attribute_vec_mut("color").unwrap();
+        if let (AttributeData::U8(r_vec), AttributeData::U8(g_vec), AttributeData::U8(b_vec)) =
+            (&*r_vec, &*g_vec, &*b_vec)
+        {
+            color.extend(
                 r_vec
-                    .into_iter()
-                    .zip(g_vec.into_iter())
-                    .zip(b_vec.into_iter())
-                    .map(|((r, g), b)| Vector3::new(r, g, b))
-                    .collect(),
-            ),
-        );
+                    .iter()
+                    .zip(g_vec.iter())
+                    .zip(b_vec.iter())
+                    .map(|((r, g), b)| Vector3::new(*r, *g, *b)),
+            );
+        } else {
+            let channel = |data: &AttributeData, i: usize, channel: usize| match data {
+                AttributeData::U8(values) => values[i],
+                AttributeData::U16(values) => u16_to_u8(values[i], 3 * (first_point + i) + channel),
+                _ => unreachable!(),
+            };
+            color.extend((0..r_vec.len()).map(|i| {
+                Vector3::new(
+                    channel(r_vec, i, 0),
+                    channel(g_vec, i, 1),
+                    channel(b_vec, i, 2),
+                )
+            }));
+        }
+        r_vec.clear();
+        g_vec.clear();
+        b_vec.clear();
     }
-    PointsBatch {
-        position,
-        attributes,
+    for (name, data) in others {
+        batch
+            .attributes
+            .get_mut(name)
+            .unwrap()
+            .append(data)
+            .unwrap();
     }
 }
 
@@ -525,34 +911,77 @@ impl Iterator for PlyIterator {
     }
 
     fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = PointsBatch::default();
+        if self.next_into(&mut batch) {
+            Some(batch)
+        } else {
+            None
+        }
+    }
+}
+
+impl NextInto for PlyIterator {
+    fn next_into(&mut self, batch: &mut PointsBatch) -> bool {
         if self.point_count == self.num_total_points as usize {
-            return None;
+            return false;
         }
 
         let cur_batch_size = std::cmp::min(
             self.batch_size,
             self.num_total_points as usize - self.point_count,
         );
+        self.read_points_into(cur_batch_size, batch);
+        true
+    }
+}
+
+impl PlyIterator {
+    /// Reads the next 'cur_batch_size' points, which must be in the file, into 'batch'.
+    fn read_points_into(&mut self, cur_batch_size: usize, batch: &mut PointsBatch) {
+        if let Some(stride) = self.xyz_f32_rgb_u8_stride {
+            self.record_buffer.resize(cur_batch_size * stride, 0);
+            self.reader.read_exact(&mut self.record_buffer).unwrap();
+            self.point_count += cur_batch_size;
+            batch.reset(std::iter::once(("color", AttributeDataType::U8Vec3)));
+            let color =
+                <&mut Vec<Vector3<u8>>>::try_from(batch.attributes.get_mut("color").unwrap())
+                    .unwrap();
+            decode_xyz_f32_rgb_u8(
+                &self.record_buffer,
+                stride,
+                &self.offset,
+                &mut self.xyz_buffer,
+                &mut batch.position,
+                color,
+            );
+            return;
+        }
 
         for _ in 0..cur_batch_size {
             let mut nread = 0;
 
-            // We made sure before that the internal buffer of 'reader' is aligned to the number of
-            // bytes for a single point, therefore we can access it here and know that we can always
-            // read into it and are sure that it contains at least a full point.
-            {
-                let buf = self.reader.fill_buf().unwrap();
+            // Points are parsed straight out of the buffer of 'reader'. A point that is split
+            // across refills, or larger than the buffer, is copied out first.
+            let buf = self.reader.fill_buf().unwrap();
+            if buf.len() >= self.num_bytes_per_point {
                 for r in self.readers.iter_mut() {
                     let cnread = nread;
                     (r.func)(&mut nread, &buf[cnread..], &mut r.data);
                 }
+                self.reader.consume(nread);
+            } else {
+                self.point_buffer.resize(self.num_bytes_per_point, 0);
+                self.reader.read_exact(&mut self.point_buffer).unwrap();
+                for r in self.readers.iter_mut() {
+                    let cnread = nread;
+                    (r.func)(&mut nread, &self.point_buffer[cnread..], &mut r.data);
+                }
             }
-            self.reader.consume(nread);
         }
+        let first_point = self.point_count;
         self.point_count += cur_batch_size;
 
-        let batch = batch_from_readers(&mut self.readers, &self.offset);
-        Some(batch)
+        fill_batch_from_readers(&mut self.readers, &self.offset, first_point, batch);
     }
 }
 
@@ -560,6 +989,37 @@ pub struct PlyNodeWriter {
     writer: DataWriter,
     point_count: usize,
     encoding: Encoding,
+    // The vertex properties of the file that is appended to, until the first write checked them.
+    existing_properties: Option<Vec<PlyProperty>>,
+    attribute_filter: AttributeFilter,
+    /// Reused by 'write_many' to serialize a block of points.
+    buffer: Vec<u8>,
+}
+
+/// Selects the attributes that 'PlyNodeWriter' writes. The positions are always written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeFilter {
+    All,
+    /// All attributes except these.
+    Exclude(Vec<String>),
+    /// Only these attributes, if the points have them.
+    Only(Vec<String>),
+}
+
+impl Default for AttributeFilter {
+    fn default() -> Self {
+        AttributeFilter::All
+    }
+}
+
+impl AttributeFilter {
+    pub fn keeps(&self, name: &str) -> bool {
+        match self {
+            AttributeFilter::All => true,
+            AttributeFilter::Exclude(names) => !names.iter().any(|n| n == name),
+            AttributeFilter::Only(names) => names.iter().any(|n| n == name),
+        }
+    }
 }
 
 impl NodeWriter<PointsBatch> for PlyNodeWriter {
@@ -567,13 +1027,22 @@ impl NodeWriter<PointsBatch> for PlyNodeWriter {
         Self::new(filename, encoding, open_mode)
     }
 
+    fn finish(self) -> io::Result<()> {
+        PlyNodeWriter::finish(self)
+    }
+
     fn write(&mut self, p: &PointsBatch) -> io::Result<()> {
         if p.position.is_empty() {
             return Ok(());
         }
-        if self.point_count == 0 {
-            self.create_header(
-                &p.attributes
+        let attributes: Vec<(&String, &AttributeData)> = p
+            .attributes
+            .iter()
+            .filter(|(name, _)| self.attribute_filter.keeps(name))
+            .collect();
+        if self.point_count == 0 || self.existing_properties.is_some() {
+            self.prepare(
+                &attributes
                     .iter()
                     .map(|(k, data)| {
                         (
@@ -601,7 +1070,7 @@ impl NodeWriter<PointsBatch> for PlyNodeWriter {
 
         for (i, pos) in p.position.iter().enumerate() {
             pos.write_encoded(&self.encoding, &mut self.writer)?;
-            for data in p.attributes.values() {
+            for (_, data) in &attributes {
                 data.write_le_pos(i, &mut self.writer)?;
             }
         }
@@ -617,52 +1086,72 @@ impl NodeWriter<Point> for PlyNodeWriter {
         Self::new(filename, encoding, open_mode)
     }
 
-    fn write(&mut self, p: &Point) -> io::Result<()> {
-        if self.point_count == 0 {
-            let mut attributes = vec![("color", "uchar", 3)];
-            if p.intensity.is_some() {
-                attributes.push(("intensity", "float", 1));
-            }
-            self.create_header(&attributes)?;
-        }
-
-        p.position.write_encoded(&self.encoding, &mut self.writer)?;
-        p.color.write_le(&mut self.writer)?;
-        if let Some(i) = p.intensity {
-            i.write_le(&mut self.writer)?;
-        }
+    fn finish(self) -> io::Result<()> {
+        PlyNodeWriter::finish(self)
+    }
 
+    fn write(&mut self, p: &Point) -> io::Result<()> {
+        let (write_color, write_intensity) = self.prepare_point(p)?;
+        write_point(
+            p,
+            &self.encoding,
+            write_color,
+            write_intensity,
+            &mut self.writer,
+        )?;
         self.point_count += 1;
+        Ok(())
+    }
 
+    /// Checks the header only for the first point, and serializes blocks of points into a buffer.
+    fn write_many(&mut self, points: &[Point]) -> io::Result<()> {
+        let first = match points.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let (write_color, write_intensity) = self.prepare_point(first)?;
+        for block in points.chunks(POINTS_PER_BLOCK) {
+            self.buffer.clear();
+            for p in block {
+                write_point(
+                    p,
+                    &self.encoding,
+                    write_color,
+                    write_intensity,
+                    &mut self.buffer,
+                )?;
+            }
+            self.writer.write_all(&self.buffer)?;
+            self.point_count += block.len();
+        }
         Ok(())
     }
 }
 
-impl Drop for PlyNodeWriter {
-    fn drop(&mut self) {
-        if self.point_count == 0 {
-            return;
-        }
-        self.writer.write_all(b"\n").unwrap();
-        if self
-            .writer
-            .seek(SeekFrom::Start(HEADER_START_TO_NUM_VERTICES.len() as u64))
-            .is_ok()
-        {
-            let _res = write!(
-                &mut self.writer,
-                "{:0width$}",
-                self.point_count,
-                width = HEADER_NUM_VERTICES.len()
-            );
-        }
+/// Writes the vertex of 'p' in the layout of the header that 'PlyNodeWriter::prepare_point'
+/// returned the flags for.
+fn write_point(
+    p: &Point,
+    encoding: &Encoding,
+    write_color: bool,
+    write_intensity: bool,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    p.position.write_encoded(encoding, writer)?;
+    if write_color {
+        p.color.write_le(writer)?;
+    }
+    if let Some(i) = p.intensity.filter(|_| write_intensity) {
+        i.write_le(writer)?;
     }
+    Ok(())
 }
 
 impl PlyNodeWriter {
     pub fn new(filename: impl Into<PathBuf>, encoding: Encoding, open_mode: OpenMode) -> Self {
         let filename = filename.into();
         let mut point_count = 0;
+        let mut existing_properties = None;
         if open_mode == OpenMode::Append {
             if let Ok(mut file) = File::open(&filename) {
                 if file.metadata().unwrap().len()
@@ -673,9 +1162,27 @@ impl PlyNodeWriter {
                     let mut buf = vec![0; HEADER_NUM_VERTICES.len()];
                     file.read_exact(&mut buf).unwrap();
                     point_count = usize::from_str(from_utf8(&buf).unwrap()).unwrap();
+                    file.seek(SeekFrom::Start(0)).unwrap();
+                    // A header that cannot be parsed matches nothing that is written.
+                    existing_properties = Some(
+                        parse_header(&mut BufReader::new(file), false)
+                            .ok()
+                            .filter(|(header, _)| header.has_element("vertex"))
+                            .map(|(header, _)| header["vertex"].declared_properties.clone())
+                            .unwrap_or_default(),
+                    );
                 }
             }
         }
+        // PLY has neither 24 bit nor variable length types, so these are written as floats,
+        // which keep their precision.
+        let encoding = match encoding {
+            Encoding::ScaledToCube(min, edge_length, PositionEncoding::Uint24)
+            | Encoding::ScaledToCube(min, edge_length, PositionEncoding::Delta) => {
+                Encoding::ScaledToCube(min, edge_length, PositionEncoding::Float32)
+            }
+            encoding => encoding,
+        };
         let mut writer = DataWriter::new(filename, open_mode).unwrap();
         if point_count > 0 {
             // Our ply files always have a newline at the end.
@@ -685,13 +1192,104 @@ impl PlyNodeWriter {
             writer,
             point_count,
             encoding,
+            existing_properties: existing_properties.filter(|_| point_count > 0),
+            attribute_filter: AttributeFilter::All,
+            buffer: Vec::new(),
         }
     }
 
+    /// Writes the number of points into the header and completes the file, see
+    /// 'NodeWriter::finish'.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.point_count > 0 {
+            self.writer.write_all(b"\n")?;
+            self.writer
+                .seek(SeekFrom::Start(HEADER_START_TO_NUM_VERTICES.len() as u64))?;
+            write!(
+                &mut self.writer,
+                "{:0width$}",
+                self.point_count,
+                width = HEADER_NUM_VERTICES.len()
+            )?;
+        }
+        self.writer.finish()
+    }
+
+    /// Writes only the attributes that 'attribute_filter' keeps, e.g. to leave out large
+    /// attributes that the consumers of an export do not need.
+    pub fn with_attribute_filter(mut self, attribute_filter: AttributeFilter) -> Self {
+        self.attribute_filter = attribute_filter;
+        self
+    }
+
+    /// Prepares the header for points like 'p', see 'prepare', and returns whether their color
+    /// and intensity are written.
+    fn prepare_point(&mut self, p: &Point) -> io::Result<(bool, bool)> {
+        let write_color = self.attribute_filter.keeps("color");
+        let write_intensity = p.intensity.is_some() && self.attribute_filter.keeps("intensity");
+        if self.point_count == 0 || self.existing_properties.is_some() {
+            let mut attributes = Vec::new();
+            if write_color {
+                attributes.push(("color", "uchar", 3));
+            }
+            if write_intensity {
+                attributes.push(("intensity", "float", 1));
+            }
+            self.prepare(&attributes)?;
+        }
+        Ok((write_color, write_intensity))
+    }
+
+    /// Writes the header before the first point. When appending to an existing file, checks
+    /// instead that its header has the properties that would be written, since appending points
+    /// with other attributes or another position encoding would silently corrupt the file. The
+    /// scale of encoded positions is not part of the header and cannot be checked.
+    fn prepare(&mut self, elements: &[(&str, &str, usize)]) -> io::Result<()> {
+        if self.point_count == 0 {
+            return self.create_header(elements);
+        }
+        if let Some(existing_properties) = self.existing_properties.take() {
+            let properties = self.header_properties(elements);
+            if existing_properties != properties {
+                let describe = |properties: &[PlyProperty]| {
+                    properties
+                        .iter()
+                        .map(|p| format!("{} {}", p.data_type, p.name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Cannot append points with the properties '{}' to a PLY file with the \
+                         properties '{}'.",
+                        describe(&properties),
+                        describe(&existing_properties)
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
     fn create_header(&mut self, elements: &[(&str, &str, usize)]) -> io::Result<()> {
         self.writer.write_all(HEADER_START_TO_NUM_VERTICES)?;
         self.writer.write_all(HEADER_NUM_VERTICES)?;
         self.writer.write_all(b"\n")?;
+        for property in self.header_properties(elements) {
+            let line = format!("property {} {}\n", property.data_type, property.name);
+            self.writer.write_all(line.as_bytes())?;
+        }
+        self.writer.write_all(b"end_header\n")
+    }
+
+    /// The vertex properties of the header for the positions and 'elements'.
+    fn header_properties(&self, elements: &[(&str, &str, usize)]) -> Vec<PlyProperty> {
+        let property = |data_type: &str, name: &str| PlyProperty {
+            name: name.to_string(),
+            data_type: data_type.to_string(),
+            list_count_type: None,
+        };
         let pos_data_str = match &self.encoding {
             Encoding::Plain => "double",
             Encoding::ScaledToCube(_, _, pos_enc) => match pos_enc {
@@ -699,35 +1297,32 @@ impl PlyNodeWriter {
                 PositionEncoding::Uint16 => "ushort",
                 PositionEncoding::Float32 => "float",
                 PositionEncoding::Float64 => "double",
+                PositionEncoding::Uint24 | PositionEncoding::Delta => {
+                    unreachable!("Replaced by Float32 in PlyNodeWriter::new.")
+                }
             },
         };
-        for pos in &["x", "y", "z"] {
-            let prop = &["property", " ", pos_data_str, " ", pos, "\n"].concat();
-            self.writer.write_all(&prop.as_bytes())?;
-        }
+        let mut properties: Vec<PlyProperty> = ["x", "y", "z"]
+            .iter()
+            .map(|pos| property(pos_data_str, pos))
+            .collect();
         for (name, data_str, num_properties) in elements {
             match &name[..] {
                 "color" | "rgb" | "rgba" => {
                     let colors = ["red", "green", "blue", "alpha"];
                     for color in colors.iter().take(*num_properties) {
-                        let prop = &["property", " ", data_str, " ", color, "\n"].concat();
-                        self.writer.write_all(&prop.as_bytes())?;
+                        properties.push(property(data_str, color));
                     }
                 }
                 _ if *num_properties > 1 => {
                     for i in 0..*num_properties {
-                        let prop =
-                            &["property", " ", data_str, " ", name, &i.to_string(), "\n"].concat();
-                        self.writer.write_all(&prop.as_bytes())?;
+                        properties.push(property(data_str, &format!("{}{}", name, i)));
                     }
                 }
-                _ => {
-                    let prop = &["property", " ", data_str, " ", name, "\n"].concat();
-                    self.writer.write_all(&prop.as_bytes())?;
-                }
+                _ => properties.push(property(data_str, name)),
             }
         }
-        self.writer.write_all(b"end_header\n")
+        properties
     }
 }
 
@@ -793,6 +1388,130 @@ mod tests {
         assert_eq!(color_last.last().unwrap().x, 234);
     }
 
+    #[test]
+    fn test_fixed_layout_matches_property_readers() {
+        for file in &[
+            "src/test_data/xyz_f32_rgb_u8_le.ply",
+            "src/test_data/xyz_f32_rgba_u8_le.ply",
+        ] {
+            let fast = PlyIterator::from_file(file, BATCH_SIZE).unwrap();
+            assert!(fast.xyz_f32_rgb_u8_stride.is_some());
+            let mut generic = PlyIterator::from_file(file, BATCH_SIZE).unwrap();
+            generic.xyz_f32_rgb_u8_stride = None;
+            fast.zip(generic).for_each(|(fast, generic)| {
+                assert_eq!(fast.position, generic.position);
+                let fast_color: &Vec<Vector3<u8>> = fast.get_attribute_vec("color").unwrap();
+                let generic_color: &Vec<Vector3<u8>> = generic.get_attribute_vec("color").unwrap();
+                assert_eq!(fast_color, generic_color);
+            });
+        }
+    }
+
+    #[test]
+    fn test_next_into_matches_next() {
+        let file = "src/test_data/xyz_f32_rgb_u8_intensity_f32.ply";
+        let mut iterator = PlyIterator::from_file(file, BATCH_SIZE).unwrap();
+        let mut batch = PointsBatch::default();
+        let mut num_batches = 0;
+        for expected in PlyIterator::from_file(file, BATCH_SIZE).unwrap() {
+            assert!(iterator.next_into(&mut batch));
+            assert_eq!(batch.position, expected.position);
+            assert_eq!(batch.attributes.len(), expected.attributes.len());
+            let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+            let expected_color: &Vec<Vector3<u8>> = expected.get_attribute_vec("color").unwrap();
+            assert_eq!(color, expected_color);
+            num_batches += 1;
+        }
+        assert_eq!(num_batches, NUM_BATCHES);
+        assert!(!iterator.next_into(&mut batch));
+    }
+
+    #[test]
+    fn test_max_batch_bytes() {
+        // A point takes 24 bytes for the position, 3 for the color and 4 for the intensity.
+        let iterator =
+            PlyIterator::from_file("src/test_data/xyz_f32_rgb_u8_intensity_f32.ply", BATCH_SIZE)
+                .unwrap()
+                .with_max_batch_bytes(3 * 31 + 30);
+        let batch_sizes: Vec<usize> = iterator.map(|batch| batch.position.len()).collect();
+        assert_eq!(batch_sizes, vec![3, 3, 2]);
+    }
+
+    #[test]
+    fn test_random_access() {
+        for file in &[
+            "src/test_data/xyz_f32_rgb_u8_le.ply",
+            "src/test_data/xyz_f32_rgb_u8_intensity_f32.ply",
+        ] {
+            let all: Vec<Point3<f64>> = PlyIterator::from_file(file, BATCH_SIZE)
+                .unwrap()
+                .flat_map(|batch| batch.position)
+                .collect();
+            let mut iterator = PlyIterator::from_file(file, BATCH_SIZE).unwrap();
+            assert_eq!(iterator.read_range(3..6).unwrap().position, &all[3..6]);
+            // Iterating continues after the range, also when going backwards.
+            assert_eq!(iterator.next().unwrap().position, &all[6..8]);
+            assert_eq!(iterator.read_range(1..2).unwrap().position, &all[1..2]);
+            assert_eq!(iterator.next().unwrap().position, &all[2..4]);
+            iterator.seek_to_point(7).unwrap();
+            assert_eq!(iterator.next().unwrap().position, &all[7..]);
+            assert!(iterator.next().is_none());
+            assert!(iterator.read_range(0..0).unwrap().position.is_empty());
+            assert!(iterator.read_range(5..9).is_err());
+            assert!(iterator.seek_to_point(9).is_err());
+        }
+    }
+
+    #[test]
+    fn test_small_buffers() {
+        let file = "src/test_data/xyz_f32_rgb_u8_intensity_f32.ply";
+        let all: Vec<Point3<f64>> = PlyIterator::from_file(file, BATCH_SIZE)
+            .unwrap()
+            .flat_map(|batch| batch.position)
+            .collect();
+        // Smaller than a point, and not a multiple of its size.
+        for max_bytes in &[1, 7, 25] {
+            let positions: Vec<Point3<f64>> = PlyIterator::from_file(file, BATCH_SIZE)
+                .unwrap()
+                .with_max_buffer_bytes(*max_bytes)
+                .unwrap()
+                .flat_map(|batch| batch.position)
+                .collect();
+            assert_eq!(all, positions);
+        }
+        // Changing the buffer keeps the position.
+        let mut iterator = PlyIterator::from_file(file, BATCH_SIZE).unwrap();
+        assert_eq!(iterator.next().unwrap().position, &all[..2]);
+        let mut iterator = iterator.with_max_buffer_bytes(7).unwrap();
+        assert_eq!(iterator.next().unwrap().position, &all[2..4]);
+    }
+
+    #[test]
+    fn test_u16_colors() {
+        let tmp_dir = TempDir::new("ply").unwrap();
+        let path = tmp_dir.path().join("rgb_u16.ply");
+        let mut bytes = b"ply\nformat binary_little_endian 1.0\nelement vertex 2\n\
+                          property float x\nproperty float y\nproperty float z\n\
+                          property ushort red\nproperty ushort green\nproperty ushort blue\n\
+                          end_header\n"
+            .to_vec();
+        for (i, color) in [[0u16, 25700, 65535], [257, 514, 771]].iter().enumerate() {
+            for _ in 0..3 {
+                bytes.extend_from_slice(&(i as f32).to_le_bytes());
+            }
+            for channel in color {
+                bytes.extend_from_slice(&channel.to_le_bytes());
+            }
+        }
+        std::fs::write(&path, bytes).unwrap();
+        let batch = PlyIterator::from_file(&path, BATCH_SIZE)
+            .unwrap()
+            .next()
+            .unwrap();
+        let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+        assert_eq!(color, &[Vector3::new(0, 100, 255), Vector3::new(1, 2, 3)]);
+    }
+
     #[test]
     fn test_ply_read_write() {
         let tmp_dir = TempDir::new("test_ply_read_write").unwrap();
@@ -806,6 +1525,7 @@ mod tests {
                 .for_each(|p| {
                     ply_writer.write(&p).unwrap();
                 });
+            ply_writer.finish().unwrap();
         }
         // Now append to the file
         {
@@ -816,6 +1536,7 @@ mod tests {
                 .for_each(|p| {
                     ply_writer.write(&p).unwrap();
                 });
+            ply_writer.finish().unwrap();
         }
         PlyIterator::from_file(file_path_gt, BATCH_SIZE)
             .unwrap()
@@ -834,4 +1555,190 @@ mod tests {
                 assert!(test_intensity.iter().all(|i| i.is_nan()));
             });
     }
+
+    #[test]
+    fn test_write_many() {
+        let tmp_dir = TempDir::new("test_write_many").unwrap();
+        let points: Vec<Point> = (0..2 * POINTS_PER_BLOCK + 1)
+            .map(|i| Point {
+                position: Point3::new(i as f64, 0.5, -(i as f64)),
+                color: crate::color::Color {
+                    red: i as u8,
+                    green: 1,
+                    blue: 2,
+                    alpha: 255,
+                },
+                intensity: Some(i as f32),
+            })
+            .collect();
+        let one_by_one = tmp_dir.path().join("one_by_one.ply");
+        {
+            let mut ply_writer =
+                PlyNodeWriter::new(&one_by_one, Encoding::Plain, OpenMode::Truncate);
+            for p in &points {
+                ply_writer.write(p).unwrap();
+            }
+            ply_writer.finish().unwrap();
+        }
+        let many = tmp_dir.path().join("many.ply");
+        {
+            let mut ply_writer = PlyNodeWriter::new(&many, Encoding::Plain, OpenMode::Truncate);
+            ply_writer.write_many(&points[..1]).unwrap();
+            ply_writer.write_many(&points[1..]).unwrap();
+            ply_writer.finish().unwrap();
+        }
+        assert_eq!(
+            std::fs::read(&one_by_one).unwrap(),
+            std::fs::read(&many).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_attribute_filter() {
+        let tmp_dir = TempDir::new("test_attribute_filter").unwrap();
+        let file_path_test = tmp_dir.path().join("out.ply");
+        let file_path_gt = "src/test_data/xyz_f32_rgb_u8_intensity_f32.ply";
+        for (attribute_filter, expected) in vec![
+            (AttributeFilter::All, vec!["color", "intensity"]),
+            (
+                AttributeFilter::Exclude(vec!["intensity".to_string()]),
+                vec!["color"],
+            ),
+            (
+                AttributeFilter::Only(vec!["intensity".to_string(), "time".to_string()]),
+                vec!["intensity"],
+            ),
+        ] {
+            {
+                let mut ply_writer =
+                    PlyNodeWriter::new(&file_path_test, Encoding::Plain, OpenMode::Truncate)
+                        .with_attribute_filter(attribute_filter);
+                PlyIterator::from_file(file_path_gt, BATCH_SIZE)
+                    .unwrap()
+                    .for_each(|p| ply_writer.write(&p).unwrap());
+                ply_writer.finish().unwrap();
+            }
+            PlyIterator::from_file(file_path_gt, BATCH_SIZE)
+                .unwrap()
+                .zip(PlyIterator::from_file(&file_path_test, BATCH_SIZE).unwrap())
+                .for_each(|(gt, test)| {
+                    assert_eq!(gt.position, test.position);
+                    let mut names: Vec<&str> = test.attributes.keys().map(|k| &k[..]).collect();
+                    names.sort();
+                    assert_eq!(expected, names);
+                });
+        }
+    }
+
+    #[test]
+    fn test_append_mismatch() {
+        let tmp_dir = TempDir::new("test_append_mismatch").unwrap();
+        let file_path_test = tmp_dir.path().join("out.ply");
+        let file_path_gt = "src/test_data/xyz_f32_rgb_u8_intensity_f32.ply";
+        let batch = PlyIterator::from_file(file_path_gt, BATCH_SIZE)
+            .unwrap()
+            .next()
+            .unwrap();
+        {
+            let mut ply_writer =
+                PlyNodeWriter::new(&file_path_test, Encoding::Plain, OpenMode::Truncate);
+            ply_writer.write(&batch).unwrap();
+            ply_writer.finish().unwrap();
+        }
+        // Another position encoding.
+        {
+            let mut ply_writer = PlyNodeWriter::new(
+                &file_path_test,
+                Encoding::ScaledToCube(Point3::origin(), 1., PositionEncoding::Uint16),
+                OpenMode::Append,
+            );
+            assert!(ply_writer.write(&batch).is_err());
+        }
+        // Other attributes.
+        {
+            let mut without_intensity = batch.clone();
+            without_intensity.attributes.remove("intensity");
+            let mut ply_writer =
+                PlyNodeWriter::new(&file_path_test, Encoding::Plain, OpenMode::Append);
+            assert!(ply_writer.write(&without_intensity).is_err());
+        }
+        let num_points = PlyIterator::from_file(&file_path_test, BATCH_SIZE)
+            .unwrap()
+            .map(|batch| batch.position.len())
+            .sum::<usize>();
+        assert_eq!(batch.position.len(), num_points);
+    }
+
+    #[test]
+    fn test_read_header() {
+        let info = read_header("src/test_data/xyz_f32_rgb_u8_le.ply").unwrap();
+        assert_eq!(info.format, PlyFormat::BinaryLittleEndianV1);
+        let names: Vec<(&str, i64)> = info
+            .elements
+            .iter()
+            .map(|e| (e.name.as_str(), e.count))
+            .collect();
+        assert_eq!(names, vec![("vertex", 8), ("face", 0)]);
+        let vertex = &info.elements[0];
+        assert_eq!(vertex.properties.len(), 6);
+        assert_eq!(vertex.properties[0].name, "x");
+        assert_eq!(vertex.properties[0].data_type, "float");
+        assert_eq!(vertex.properties[3].data_type, "uchar");
+        let face = &info.elements[1].properties[0];
+        assert_eq!(face.name, "vertex_indices");
+        assert_eq!(face.list_count_type.as_deref(), Some("uchar"));
+        assert_eq!(info.offset, Vector3::zero());
+        let file_len = std::fs::metadata("src/test_data/xyz_f32_rgb_u8_le.ply")
+            .unwrap()
+            .len() as usize;
+        assert_eq!(file_len - info.header_len, 8 * 15);
+    }
+
+    #[test]
+    fn test_lenient() {
+        let original = "src/test_data/xyz_f32_rgb_u8_le.ply";
+        let bytes = std::fs::read(original).unwrap();
+        let end_header = b"end_header\n";
+        let data_start = bytes
+            .windows(end_header.len())
+            .position(|w| w == end_header)
+            .unwrap()
+            + end_header.len();
+        let data = &bytes[data_start..];
+        let header_start = "ply\nformat binary_little_endian 1.0\n";
+        let properties = "property float x\nproperty float y\nproperty float z\n\
+                          property uchar red\nproperty uchar green\nproperty uchar blue\n";
+        let tmp_dir = TempDir::new("ply").unwrap();
+        let crlf = tmp_dir.path().join("crlf.ply");
+        let header = format!(
+            "{}obj_info scanner 7\nelement vertex 0\n{}end_header\n",
+            header_start, properties
+        );
+        let mut file_bytes = header.replace('\n', "\r\n").into_bytes();
+        file_bytes.extend_from_slice(data);
+        std::fs::write(&crlf, file_bytes).unwrap();
+        let blank_line = tmp_dir.path().join("blank_line.ply");
+        let header = format!(
+            "{}element vertex 8\n{}end_header\n\n",
+            header_start, properties
+        );
+        let mut file_bytes = header.into_bytes();
+        file_bytes.extend_from_slice(data);
+        std::fs::write(&blank_line, file_bytes).unwrap();
+
+        let expected: Vec<Point3<f64>> = PlyIterator::from_file(original, BATCH_SIZE)
+            .unwrap()
+            .flat_map(|batch| batch.position)
+            .collect();
+        for (path, num_repairs) in &[(&crlf, 3), (&blank_line, 1)] {
+            let strict: Option<Vec<Point3<f64>>> = PlyIterator::from_file(path, BATCH_SIZE)
+                .ok()
+                .map(|iterator| iterator.flat_map(|batch| batch.position).collect());
+            assert_ne!(strict.as_ref(), Some(&expected));
+            let iterator = PlyIterator::from_file_lenient(path, BATCH_SIZE).unwrap();
+            assert_eq!(iterator.repairs().len(), *num_repairs);
+            let positions: Vec<Point3<f64>> = iterator.flat_map(|batch| batch.position).collect();
+            assert_eq!(positions, expected);
+        }
+    }
 }
@@ -14,24 +14,39 @@
 
 use crate::color;
 use crate::errors::*;
+use crate::read_write::node_writer::POINTS_PER_BLOCK;
+use crate::read_write::simd::decode_positions;
 use crate::read_write::{
-    decode, fixpoint_decode, AttributeReader, DataWriter, Encoding, NodeWriter, OpenMode,
-    PositionEncoding, WriteEncoded, WriteLE,
+    decode, fixpoint_decode, vec3_read_u24, AttributeCompression, AttributeReader, ColumnEncoder,
+    DataWriter, DeltaState, Encoding, NodeWriter, OpenMode, PositionEncoding, WriteEncoded,
+    WriteLE,
 };
 use crate::{attribute_extension, AttributeData, AttributeDataType, Point, PointsBatch};
 use byteorder::{LittleEndian, ReadBytesExt};
 use nalgebra::{Point3, Vector3};
-use std::collections::{BTreeMap, HashMap};
-use std::io::{self, BufReader, ErrorKind, Read};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
 use std::path::PathBuf;
 
 pub struct RawNodeReader {
     xyz_reader: BufReader<Box<dyn Read + Send>>,
     attribute_readers: HashMap<String, AttributeReader>,
     encoding: Encoding,
+    delta_state: DeltaState,
+    // Scratch space for 'read_batch_into', kept to avoid allocating for every batch.
+    byte_buffer: Vec<u8>,
+    f64_buffer: Vec<f64>,
 }
 
 impl RawNodeReader {
+    /// The data types of the attributes that are read.
+    pub fn attribute_data_types(&self) -> impl Iterator<Item = AttributeDataType> + '_ {
+        self.attribute_readers
+            .values()
+            .map(|reader| reader.data_type)
+    }
+
     pub fn read(&mut self) -> io::Result<Point> {
         let mut point = Point {
             position: Point3::origin(),
@@ -108,6 +123,14 @@ impl RawNodeReader {
                         edge_length,
                     );
                 }
+                PositionEncoding::Uint24 => {
+                    point.position = vec3_read_u24(&mut self.xyz_reader, &min, edge_length)?;
+                }
+                PositionEncoding::Delta => {
+                    point.position =
+                        self.delta_state
+                            .decode(&mut self.xyz_reader, &min, edge_length)?;
+                }
             },
         }
 
@@ -125,10 +148,22 @@ impl RawNodeReader {
     }
 
     pub fn read_batch(&mut self, num_points: usize) -> io::Result<PointsBatch> {
-        let mut batch = PointsBatch {
-            position: vec![],
-            attributes: BTreeMap::new(),
-        };
+        let mut batch = PointsBatch::default();
+        self.read_batch_into(num_points, &mut batch)?;
+        Ok(batch)
+    }
+
+    /// Like 'read_batch', but overwrites 'batch', reusing its allocations.
+    pub fn read_batch_into(
+        &mut self,
+        num_points: usize,
+        batch: &mut PointsBatch,
+    ) -> io::Result<()> {
+        batch.reset(
+            self.attribute_readers
+                .iter()
+                .map(|(key, reader)| (key.as_str(), reader.data_type)),
+        );
 
         match self.encoding {
             Encoding::Plain => (0..num_points).try_for_each(|_| -> io::Result<()> {
@@ -139,192 +174,104 @@ impl RawNodeReader {
                 Ok(())
             })?,
             Encoding::ScaledToCube(min, edge_length, ref pos) => match pos {
-                PositionEncoding::Uint8 => (0..num_points).try_for_each(|_| -> io::Result<()> {
-                    let x = fixpoint_decode(self.xyz_reader.read_u8()?, min.x, edge_length);
-                    let y = fixpoint_decode(self.xyz_reader.read_u8()?, min.y, edge_length);
-                    let z = fixpoint_decode(self.xyz_reader.read_u8()?, min.z, edge_length);
-                    batch.position.push(Point3::new(x, y, z));
-                    Ok(())
-                })?,
-
-                PositionEncoding::Uint16 => {
-                    (0..num_points).try_for_each(|_| -> io::Result<()> {
-                        let x = fixpoint_decode(
-                            self.xyz_reader.read_u16::<LittleEndian>()?,
-                            min.x,
-                            edge_length,
-                        );
-                        let y = fixpoint_decode(
-                            self.xyz_reader.read_u16::<LittleEndian>()?,
-                            min.y,
-                            edge_length,
-                        );
-                        let z = fixpoint_decode(
-                            self.xyz_reader.read_u16::<LittleEndian>()?,
-                            min.z,
-                            edge_length,
-                        );
-                        batch.position.push(Point3::new(x, y, z));
-                        Ok(())
-                    })?
+                PositionEncoding::Uint8
+                | PositionEncoding::Uint16
+                | PositionEncoding::Float32
+                | PositionEncoding::Float64 => {
+                    self.byte_buffer
+                        .resize(3 * num_points * pos.bytes_per_coordinate(), 0);
+                    self.xyz_reader.read_exact(&mut self.byte_buffer)?;
+                    decode_positions(
+                        &self.byte_buffer,
+                        pos,
+                        &min,
+                        edge_length,
+                        &mut self.f64_buffer,
+                        &mut batch.position,
+                    );
                 }
 
-                PositionEncoding::Float32 => {
+                PositionEncoding::Uint24 => {
                     (0..num_points).try_for_each(|_| -> io::Result<()> {
-                        let x = decode(
-                            self.xyz_reader.read_f32::<LittleEndian>()?,
-                            min.x,
-                            edge_length,
-                        );
-                        let y = decode(
-                            self.xyz_reader.read_f32::<LittleEndian>()?,
-                            min.y,
-                            edge_length,
-                        );
-                        let z = decode(
-                            self.xyz_reader.read_f32::<LittleEndian>()?,
-                            min.z,
-                            edge_length,
-                        );
-                        batch.position.push(Point3::new(x, y, z));
+                        let position = vec3_read_u24(&mut self.xyz_reader, &min, edge_length)?;
+                        batch.position.push(position);
                         Ok(())
                     })?
                 }
 
-                PositionEncoding::Float64 => {
-                    (0..num_points).try_for_each(|_| -> io::Result<()> {
-                        let x = decode(
-                            self.xyz_reader.read_f64::<LittleEndian>()?,
-                            min.x,
-                            edge_length,
-                        );
-                        let y = decode(
-                            self.xyz_reader.read_f64::<LittleEndian>()?,
-                            min.y,
-                            edge_length,
-                        );
-                        let z = decode(
-                            self.xyz_reader.read_f64::<LittleEndian>()?,
-                            min.z,
-                            edge_length,
-                        );
-                        batch.position.push(Point3::new(x, y, z));
-                        Ok(())
-                    })?
-                }
+                PositionEncoding::Delta => (0..num_points).try_for_each(|_| -> io::Result<()> {
+                    let position =
+                        self.delta_state
+                            .decode(&mut self.xyz_reader, &min, edge_length)?;
+                    batch.position.push(position);
+                    Ok(())
+                })?,
             },
         };
 
-        // TODO(nnmm): Implement ReadLE trait and rewrite this section with a macro
-        self.attribute_readers.iter_mut().try_for_each(
-            |(key, AttributeReader { data_type, reader })| -> io::Result<()> {
-                match data_type {
-                    AttributeDataType::U8 => {
-                        let mut attr = vec![0; num_points];
-                        reader.read_exact(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::U8(attr));
-                    }
-                    AttributeDataType::U16 => {
-                        let mut attr = vec![0; num_points];
-                        reader.read_u16_into::<LittleEndian>(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::U16(attr));
-                    }
-                    AttributeDataType::U32 => {
-                        let mut attr = vec![0; num_points];
-                        reader.read_u32_into::<LittleEndian>(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::U32(attr));
-                    }
-                    AttributeDataType::U64 => {
-                        let mut attr = vec![0; num_points];
-                        reader.read_u64_into::<LittleEndian>(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::U64(attr));
-                    }
-                    AttributeDataType::I8 => {
-                        let mut attr = vec![0; num_points];
-                        let attr_u8 =
-                            unsafe { &mut *(attr.as_mut_slice() as *mut [i8] as *mut [u8]) };
-                        reader.read_exact(attr_u8)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::I8(attr));
-                    }
-                    AttributeDataType::I16 => {
-                        let mut attr = vec![0; num_points];
-                        reader.read_i16_into::<LittleEndian>(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::I16(attr));
-                    }
-                    AttributeDataType::I32 => {
-                        let mut attr = vec![0; num_points];
-                        reader.read_i32_into::<LittleEndian>(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::I32(attr));
-                    }
-                    AttributeDataType::I64 => {
-                        let mut attr = vec![0; num_points];
-                        reader.read_i64_into::<LittleEndian>(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::I64(attr));
-                    }
-                    AttributeDataType::F32 => {
-                        let mut attr = vec![0.0; num_points];
-                        reader.read_f32_into::<LittleEndian>(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::F32(attr));
-                    }
-                    AttributeDataType::F64 => {
-                        let mut attr = vec![0.0; num_points];
-                        reader.read_f64_into::<LittleEndian>(&mut attr)?;
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::F64(attr));
-                    }
-                    AttributeDataType::U8Vec3 => {
-                        let mut attr = Vec::with_capacity(num_points);
-                        let mut buffer = vec![0; 3 * num_points];
-                        reader.read_exact(&mut buffer)?;
-                        for i in 0..num_points {
-                            attr.push(Vector3::new(
-                                buffer[3 * i],
-                                buffer[3 * i + 1],
-                                buffer[3 * i + 2],
-                            ));
-                        }
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::U8Vec3(attr));
-                    }
-                    AttributeDataType::F64Vec3 => {
-                        let mut attr = Vec::with_capacity(num_points);
-                        let mut buffer = vec![0.0; 3 * num_points];
-                        reader.read_f64_into::<LittleEndian>(&mut buffer)?;
-                        for i in 0..num_points {
-                            attr.push(Vector3::new(
-                                buffer[3 * i],
-                                buffer[3 * i + 1],
-                                buffer[3 * i + 2],
-                            ));
-                        }
-                        batch
-                            .attributes
-                            .insert(key.to_owned(), AttributeData::F64Vec3(attr));
-                    }
-                };
-                Ok(())
-            },
-        )?;
+        for (key, AttributeReader { reader, .. }) in self.attribute_readers.iter_mut() {
+            match batch.attributes.get_mut(key).unwrap() {
+                AttributeData::U8(attr) => {
+                    attr.resize(num_points, 0);
+                    reader.read_exact(attr)?;
+                }
+                AttributeData::U16(attr) => {
+                    attr.resize(num_points, 0);
+                    reader.read_u16_into::<LittleEndian>(attr)?;
+                }
+                AttributeData::U32(attr) => {
+                    attr.resize(num_points, 0);
+                    reader.read_u32_into::<LittleEndian>(attr)?;
+                }
+                AttributeData::U64(attr) => {
+                    attr.resize(num_points, 0);
+                    reader.read_u64_into::<LittleEndian>(attr)?;
+                }
+                AttributeData::I8(attr) => {
+                    attr.resize(num_points, 0);
+                    let attr_u8 = unsafe { &mut *(attr.as_mut_slice() as *mut [i8] as *mut [u8]) };
+                    reader.read_exact(attr_u8)?;
+                }
+                AttributeData::I16(attr) => {
+                    attr.resize(num_points, 0);
+                    reader.read_i16_into::<LittleEndian>(attr)?;
+                }
+                AttributeData::I32(attr) => {
+                    attr.resize(num_points, 0);
+                    reader.read_i32_into::<LittleEndian>(attr)?;
+                }
+                AttributeData::I64(attr) => {
+                    attr.resize(num_points, 0);
+                    reader.read_i64_into::<LittleEndian>(attr)?;
+                }
+                AttributeData::F32(attr) => {
+                    attr.resize(num_points, 0.0);
+                    reader.read_f32_into::<LittleEndian>(attr)?;
+                }
+                AttributeData::F64(attr) => {
+                    attr.resize(num_points, 0.0);
+                    reader.read_f64_into::<LittleEndian>(attr)?;
+                }
+                AttributeData::U8Vec3(attr) => {
+                    self.byte_buffer.resize(3 * num_points, 0);
+                    reader.read_exact(&mut self.byte_buffer)?;
+                    attr.extend(
+                        self.byte_buffer
+                            .chunks_exact(3)
+                            .map(|c| Vector3::new(c[0], c[1], c[2])),
+                    );
+                }
+                AttributeData::F64Vec3(attr) => {
+                    self.f64_buffer.resize(3 * num_points, 0.0);
+                    reader.read_f64_into::<LittleEndian>(&mut self.f64_buffer)?;
+                    attr.extend(
+                        self.f64_buffer
+                            .chunks_exact(3)
+                            .map(|c| Vector3::new(c[0], c[1], c[2])),
+                    );
+                }
+            };
+        }
 
         let num_points = batch.position.len();
 
@@ -334,7 +281,7 @@ impl RawNodeReader {
             .values()
             .all(|attr| attr.len() == num_points)
         {
-            Ok(batch)
+            Ok(())
         } else {
             Err(io::Error::new(
                 ErrorKind::InvalidData,
@@ -354,16 +301,54 @@ impl RawNodeReader {
             xyz_reader,
             attribute_readers,
             encoding,
+            delta_state: DeltaState::default(),
+            byte_buffer: Vec::new(),
+            f64_buffer: Vec::new(),
         })
     }
 }
 
+/// Writes an attribute column, compressed or not, see 'AttributeCompression'.
+enum AttributeWriter {
+    Uncompressed(DataWriter),
+    Compressed(ColumnEncoder<DataWriter>),
+}
+
+impl AttributeWriter {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            AttributeWriter::Uncompressed(writer) => writer.finish(),
+            AttributeWriter::Compressed(encoder) => encoder.finish()?.finish(),
+        }
+    }
+}
+
+impl Write for AttributeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            AttributeWriter::Uncompressed(writer) => writer.write(buf),
+            AttributeWriter::Compressed(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            AttributeWriter::Uncompressed(writer) => writer.flush(),
+            AttributeWriter::Compressed(encoder) => encoder.flush(),
+        }
+    }
+}
+
 pub struct RawNodeWriter {
     xyz_writer: DataWriter,
-    attribute_writers: Vec<DataWriter>,
+    attribute_writers: Vec<AttributeWriter>,
     stem: PathBuf,
     encoding: Encoding,
     open_mode: OpenMode,
+    delta_state: DeltaState,
+    attribute_compression: AttributeCompression,
+    /// Reused by 'write_many' to serialize a block of points.
+    buffer: Vec<u8>,
 }
 
 impl NodeWriter<PointsBatch> for RawNodeWriter {
@@ -371,16 +356,27 @@ impl NodeWriter<PointsBatch> for RawNodeWriter {
         Self::new(path, encoding, open_mode)
     }
 
+    fn finish(self) -> io::Result<()> {
+        RawNodeWriter::finish(self)
+    }
+
     fn write(&mut self, p: &PointsBatch) -> io::Result<()> {
-        p.position
-            .write_encoded(&self.encoding, &mut self.xyz_writer)?;
+        match &self.encoding {
+            Encoding::ScaledToCube(min, edge_length, PositionEncoding::Delta) => {
+                for position in &p.position {
+                    self.delta_state
+                        .encode(position, min, *edge_length, &mut self.xyz_writer)?;
+                }
+            }
+            _ => p
+                .position
+                .write_encoded(&self.encoding, &mut self.xyz_writer)?,
+        }
 
         if self.attribute_writers.is_empty() {
-            for name in p.attributes.keys() {
-                self.attribute_writers.push(DataWriter::new(
-                    &self.stem.with_extension(attribute_extension(&name)),
-                    self.open_mode,
-                )?)
+            for (name, data) in &p.attributes {
+                let writer = self.attribute_writer(name, data.data_type())?;
+                self.attribute_writers.push(writer);
             }
         }
 
@@ -397,22 +393,22 @@ impl NodeWriter<Point> for RawNodeWriter {
         Self::new(path, encoding, open_mode)
     }
 
-    fn write(&mut self, p: &Point) -> io::Result<()> {
-        p.position
-            .write_encoded(&self.encoding, &mut self.xyz_writer)?;
+    fn finish(self) -> io::Result<()> {
+        RawNodeWriter::finish(self)
+    }
 
-        if self.attribute_writers.is_empty() {
-            self.attribute_writers.push(DataWriter::new(
-                &self.stem.with_extension(attribute_extension("color")),
-                self.open_mode,
-            )?);
-            if p.intensity.is_some() {
-                self.attribute_writers.push(DataWriter::new(
-                    &self.stem.with_extension(attribute_extension("intensity")),
-                    self.open_mode,
-                )?);
+    fn write(&mut self, p: &Point) -> io::Result<()> {
+        match &self.encoding {
+            Encoding::ScaledToCube(min, edge_length, PositionEncoding::Delta) => {
+                self.delta_state
+                    .encode(&p.position, min, *edge_length, &mut self.xyz_writer)?
             }
+            _ => p
+                .position
+                .write_encoded(&self.encoding, &mut self.xyz_writer)?,
         }
+
+        self.open_point_attribute_writers(p)?;
         p.color.write_le(&mut self.attribute_writers[0])?;
         if let Some(i) = p.intensity {
             i.write_le(&mut self.attribute_writers[1])?;
@@ -420,16 +416,65 @@ impl NodeWriter<Point> for RawNodeWriter {
 
         Ok(())
     }
+
+    /// Serializes every column of a block of points into a buffer and writes it at once.
+    fn write_many(&mut self, points: &[Point]) -> io::Result<()> {
+        let first = match points.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        self.open_point_attribute_writers(first)?;
+        let mut buffer = std::mem::take(&mut self.buffer);
+        for block in points.chunks(POINTS_PER_BLOCK) {
+            buffer.clear();
+            match &self.encoding {
+                Encoding::ScaledToCube(min, edge_length, PositionEncoding::Delta) => {
+                    for p in block {
+                        self.delta_state
+                            .encode(&p.position, min, *edge_length, &mut buffer)?;
+                    }
+                }
+                _ => {
+                    for p in block {
+                        p.position.write_encoded(&self.encoding, &mut buffer)?;
+                    }
+                }
+            }
+            self.xyz_writer.write_all(&buffer)?;
+
+            buffer.clear();
+            for p in block {
+                p.color.write_le(&mut buffer)?;
+            }
+            self.attribute_writers[0].write_all(&buffer)?;
+
+            buffer.clear();
+            for i in block.iter().filter_map(|p| p.intensity) {
+                i.write_le(&mut buffer)?;
+            }
+            if !buffer.is_empty() {
+                self.attribute_writers[1].write_all(&buffer)?;
+            }
+        }
+        self.buffer = buffer;
+        Ok(())
+    }
 }
 
 impl RawNodeWriter {
     pub fn new(path: impl Into<PathBuf>, encoding: Encoding, open_mode: OpenMode) -> Self {
         let stem: PathBuf = path.into();
-        let xyz_writer = DataWriter::new(
-            &stem.with_extension(attribute_extension("position")),
-            open_mode,
-        )
-        .unwrap();
+        let xyz_path = stem.with_extension(attribute_extension("position"));
+        let mut delta_state = DeltaState::default();
+        if let Encoding::ScaledToCube(_, _, PositionEncoding::Delta) = encoding {
+            // Appended points are relative to the last point already in the file.
+            if open_mode == OpenMode::Append {
+                if let Ok(file) = File::open(&xyz_path) {
+                    delta_state.skip_to_end(&mut BufReader::new(file)).unwrap();
+                }
+            }
+        }
+        let xyz_writer = DataWriter::new(&xyz_path, open_mode).unwrap();
         let attribute_writers = Vec::new();
         Self {
             xyz_writer,
@@ -437,12 +482,66 @@ impl RawNodeWriter {
             stem,
             encoding,
             open_mode,
+            delta_state,
+            attribute_compression: AttributeCompression::Uncompressed,
+            buffer: Vec::new(),
         }
     }
 
+    /// Compresses the attribute columns. Readers must be told, e.g. through the node meta.
+    pub fn with_attribute_compression(
+        mut self,
+        attribute_compression: AttributeCompression,
+    ) -> Self {
+        self.attribute_compression = attribute_compression;
+        self
+    }
+
+    /// Completes the files of the node, see 'NodeWriter::finish'.
+    pub fn finish(self) -> io::Result<()> {
+        self.xyz_writer.finish()?;
+        for writer in self.attribute_writers {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Opens the writers of the color and, if 'p' has one, the intensity before the first point.
+    fn open_point_attribute_writers(&mut self, p: &Point) -> io::Result<()> {
+        if self.attribute_writers.is_empty() {
+            let writer = self.attribute_writer("color", AttributeDataType::U8Vec3)?;
+            self.attribute_writers.push(writer);
+            if p.intensity.is_some() {
+                let writer = self.attribute_writer("intensity", AttributeDataType::F32)?;
+                self.attribute_writers.push(writer);
+            }
+        }
+        Ok(())
+    }
+
+    fn attribute_writer(
+        &self,
+        name: &str,
+        data_type: AttributeDataType,
+    ) -> io::Result<AttributeWriter> {
+        let writer = DataWriter::new(
+            &self.stem.with_extension(attribute_extension(name)),
+            self.open_mode,
+        )?;
+        Ok(match &self.attribute_compression {
+            AttributeCompression::Uncompressed => AttributeWriter::Uncompressed(writer),
+            AttributeCompression::Compressed(codecs) => AttributeWriter::Compressed(
+                ColumnEncoder::new(writer, data_type, codecs.get(name).copied()),
+            ),
+        })
+    }
+
     pub fn num_written(&self) -> i64 {
         let bytes_per_coordinate = match &self.encoding {
             Encoding::Plain => std::mem::size_of::<f64>(),
+            Encoding::ScaledToCube(_, _, PositionEncoding::Delta) => {
+                return self.delta_state.num_points() as i64;
+            }
             Encoding::ScaledToCube(_, _, pos_enc) => pos_enc.bytes_per_coordinate(),
         } as i64;
         self.xyz_writer.bytes_written() as i64 / bytes_per_coordinate / 3
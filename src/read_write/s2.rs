@@ -108,19 +108,29 @@ where
         }
 
         for (cell_id, batch) in &batches_by_s2_cell {
-            self.writer(cell_id).write(batch)?;
+            self.writer(cell_id)?.write(batch)?;
         }
         Ok(())
     }
+
+    fn finish(mut self) -> Result<()> {
+        self.finish_writers()
+    }
 }
 
 impl<W> S2Splitter<W>
 where
     W: NodeWriter<PointsBatch>,
 {
-    fn writer(&mut self, cell_id: &CellID) -> &mut W {
+    fn writer(&mut self, cell_id: &CellID) -> Result<&mut W> {
         let path = self.stem.join(cell_id.to_token());
         if !self.writers.contains(cell_id) {
+            // The evicted writer is opened again for appending if its cell gets more points.
+            if self.writers.len() == self.writers.cap() {
+                if let Some((_, writer)) = self.writers.pop_lru() {
+                    writer.finish()?;
+                }
+            }
             let open_mode = if self.open_mode == OpenMode::Append
                 || self.already_opened_writers.contains(cell_id)
             {
@@ -132,7 +142,14 @@ where
             self.writers
                 .put(*cell_id, W::new(path, self.encoding.clone(), open_mode));
         }
-        self.writers.get_mut(cell_id).unwrap()
+        Ok(self.writers.get_mut(cell_id).unwrap())
+    }
+
+    fn finish_writers(&mut self) -> Result<()> {
+        while let Some((_, writer)) = self.writers.pop_lru() {
+            writer.finish()?;
+        }
+        Ok(())
     }
 
     /// Records the list of attributes seen in the first batch, and checks
@@ -163,12 +180,18 @@ where
         }
     }
 
-    pub fn get_meta(self) -> Option<S2Meta> {
-        let meta = S2Meta::new(
+    /// Completes the files of all cells and returns their meta data, which is None if no points
+    /// were written.
+    pub fn get_meta(mut self) -> Result<Option<S2Meta>> {
+        self.finish_writers()?;
+        let bounding_box = match self.bounding_box {
+            Some(bounding_box) => bounding_box,
+            None => return Ok(None),
+        };
+        Ok(Some(S2Meta::new(
             self.cell_stats,
             self.attributes_seen.into_iter().collect(),
-            self.bounding_box?,
-        );
-        Some(meta)
+            bounding_box,
+        )))
     }
 }
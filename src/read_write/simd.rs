@@ -0,0 +1,285 @@
+//! Vectorized decoding of fixed-layout point records. Every function has a scalar fallback that
+//! is used on other architectures, for CPUs without the required instruction set and for the
+//! remainder that does not fill a full vector. Both paths perform the same floating point
+//! operations, so their results are bit-identical.
+
+use crate::read_write::{decode, fixpoint_decode, PositionEncoding};
+use byteorder::{ByteOrder, LittleEndian};
+use nalgebra::{Point3, Vector3};
+
+/// Number of bytes of 'x', 'y', 'z' as float32 followed by 'red', 'green', 'blue' as uint8.
+pub(crate) const XYZ_F32_RGB_U8_SIZE: usize = 15;
+
+/// Decodes the coordinates of 'buf', which are stored with the fixed-size 'encoding' in the
+/// cube given by 'min' and 'edge_length', e.g. the positions of a node, and appends them to
+/// 'position'. 'xyz' is scratch space that is reused between calls.
+pub(crate) fn decode_positions(
+    buf: &[u8],
+    encoding: &PositionEncoding,
+    min: &Point3<f64>,
+    edge_length: f64,
+    xyz: &mut Vec<f64>,
+    position: &mut Vec<Point3<f64>>,
+) {
+    xyz.clear();
+    xyz.resize(buf.len() / encoding.bytes_per_coordinate(), 0.);
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            let start = unsafe { x86::decode_positions(buf, encoding, min, edge_length, xyz) };
+            decode_positions_scalar(buf, encoding, min, edge_length, xyz, start);
+            extend_points(xyz, position);
+            return;
+        }
+    }
+    decode_positions_scalar(buf, encoding, min, edge_length, xyz, 0);
+    extend_points(xyz, position);
+}
+
+/// Decodes the records of 'buf', which are 'stride' bytes long and start with 'x', 'y', 'z' as
+/// float32 and 'red', 'green', 'blue' as uint8, and appends them to 'position' and 'color'.
+/// Trailing bytes of a record, e.g. alpha, are skipped. 'offset' is added to all positions.
+pub(crate) fn decode_xyz_f32_rgb_u8(
+    buf: &[u8],
+    stride: usize,
+    offset: &Vector3<f64>,
+    xyz: &mut Vec<f64>,
+    position: &mut Vec<Point3<f64>>,
+    color: &mut Vec<Vector3<u8>>,
+) {
+    assert!(stride >= XYZ_F32_RGB_U8_SIZE);
+    let num_points = buf.len() / stride;
+    xyz.clear();
+    // One more coordinate than needed, since the vectorized path writes past the last 'z'.
+    xyz.resize(3 * num_points + 1, 0.);
+    #[cfg(target_arch = "x86_64")]
+    let start = unsafe { x86::decode_xyz_f32(buf, stride, offset, xyz) };
+    #[cfg(not(target_arch = "x86_64"))]
+    let start = 0;
+    decode_xyz_f32_scalar(buf, stride, offset, xyz, start);
+    xyz.pop();
+    extend_points(xyz, position);
+
+    color.extend(
+        buf.chunks_exact(stride)
+            .map(|record| Vector3::new(record[12], record[13], record[14])),
+    );
+}
+
+fn extend_points(xyz: &[f64], position: &mut Vec<Point3<f64>>) {
+    position.extend(xyz.chunks_exact(3).map(|p| Point3::new(p[0], p[1], p[2])));
+}
+
+/// Decodes the coordinates from index 'start' on.
+fn decode_positions_scalar(
+    buf: &[u8],
+    encoding: &PositionEncoding,
+    min: &Point3<f64>,
+    edge_length: f64,
+    xyz: &mut [f64],
+    start: usize,
+) {
+    for (i, coordinate) in xyz.iter_mut().enumerate().skip(start) {
+        let min = min[i % 3];
+        *coordinate = match encoding {
+            PositionEncoding::Uint8 => fixpoint_decode(buf[i], min, edge_length),
+            PositionEncoding::Uint16 => {
+                fixpoint_decode(LittleEndian::read_u16(&buf[2 * i..]), min, edge_length)
+            }
+            PositionEncoding::Float32 => {
+                decode(LittleEndian::read_f32(&buf[4 * i..]), min, edge_length)
+            }
+            PositionEncoding::Float64 => {
+                decode(LittleEndian::read_f64(&buf[8 * i..]), min, edge_length)
+            }
+            PositionEncoding::Uint24 | PositionEncoding::Delta => {
+                unreachable!("{:?} is not a fixed-size encoding.", encoding)
+            }
+        };
+    }
+}
+
+/// Decodes the positions of the records from index 'start' on.
+fn decode_xyz_f32_scalar(
+    buf: &[u8],
+    stride: usize,
+    offset: &Vector3<f64>,
+    xyz: &mut [f64],
+    start: usize,
+) {
+    for (i, record) in buf.chunks_exact(stride).enumerate().skip(start) {
+        for axis in 0..3 {
+            xyz[3 * i + axis] =
+                f64::from(LittleEndian::read_f32(&record[4 * axis..])) + offset[axis];
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    /// Decodes blocks of four points and returns the index of the first coordinate that is left
+    /// for the scalar path.
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn decode_positions(
+        buf: &[u8],
+        encoding: &PositionEncoding,
+        min: &Point3<f64>,
+        edge_length: f64,
+        xyz: &mut [f64],
+    ) -> usize {
+        // A block of 12 coordinates consists of three vectors, which start with x, y and z.
+        let mins = [min.x, min.y, min.z, min.x, min.y, min.z];
+        let min_vectors = [
+            _mm256_loadu_pd(mins.as_ptr()),
+            _mm256_loadu_pd(mins.as_ptr().add(1)),
+            _mm256_loadu_pd(mins.as_ptr().add(2)),
+        ];
+        let edge_length = _mm256_set1_pd(edge_length);
+        let num_blocks = xyz.len() / 12;
+        for block in 0..num_blocks {
+            for (j, min_vector) in min_vectors.iter().enumerate() {
+                let i = 12 * block + 4 * j;
+                let values = match encoding {
+                    PositionEncoding::Uint8 => {
+                        let bytes = _mm_cvtsi32_si128(LittleEndian::read_i32(&buf[i..]));
+                        let ints = _mm256_cvtepi32_pd(_mm_cvtepu8_epi32(bytes));
+                        _mm256_div_pd(ints, _mm256_set1_pd(f64::from(u8::max_value())))
+                    }
+                    PositionEncoding::Uint16 => {
+                        let words = _mm_loadl_epi64(buf[2 * i..].as_ptr() as *const __m128i);
+                        let ints = _mm256_cvtepi32_pd(_mm_cvtepu16_epi32(words));
+                        _mm256_div_pd(ints, _mm256_set1_pd(f64::from(u16::max_value())))
+                    }
+                    PositionEncoding::Float32 => {
+                        _mm256_cvtps_pd(_mm_loadu_ps(buf[4 * i..].as_ptr() as *const f32))
+                    }
+                    PositionEncoding::Float64 => {
+                        _mm256_loadu_pd(buf[8 * i..].as_ptr() as *const f64)
+                    }
+                    PositionEncoding::Uint24 | PositionEncoding::Delta => {
+                        unreachable!("{:?} is not a fixed-size encoding.", encoding)
+                    }
+                };
+                let decoded = _mm256_fmadd_pd(values, edge_length, *min_vector);
+                _mm256_storeu_pd(xyz[i..i + 4].as_mut_ptr(), decoded);
+            }
+        }
+        12 * num_blocks
+    }
+
+    /// Decodes all records that can be loaded as 16 bytes and returns the index of the first
+    /// record that is left for the scalar path. SSE2 is always available on x86_64.
+    pub(super) unsafe fn decode_xyz_f32(
+        buf: &[u8],
+        stride: usize,
+        offset: &Vector3<f64>,
+        xyz: &mut [f64],
+    ) -> usize {
+        let offset_xy = _mm_set_pd(offset.y, offset.x);
+        let offset_z = _mm_set_pd(0., offset.z);
+        let num_points = if buf.len() < 16 {
+            0
+        } else {
+            ((buf.len() - 16) / stride + 1).min(buf.len() / stride)
+        };
+        for i in 0..num_points {
+            let values = _mm_loadu_ps(buf[i * stride..i * stride + 16].as_ptr() as *const f32);
+            let xy = _mm_add_pd(_mm_cvtps_pd(values), offset_xy);
+            let z = _mm_add_pd(_mm_cvtps_pd(_mm_movehl_ps(values, values)), offset_z);
+            _mm_storeu_pd(xyz[3 * i..3 * i + 2].as_mut_ptr(), xy);
+            // Also writes the 'x' of the next point, which is overwritten in the next iteration
+            // or is the spare coordinate at the end.
+            _mm_storeu_pd(xyz[3 * i + 2..3 * i + 4].as_mut_ptr(), z);
+        }
+        num_points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    // Odd sizes leave a remainder for the scalar path.
+    const NUM_POINTS: usize = 1001;
+
+    #[test]
+    fn test_vectorized_positions_match_scalar() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let min = Point3::new(-12.5, 3.25, 100.);
+        let edge_length = 17.3;
+        for encoding in &[
+            PositionEncoding::Uint8,
+            PositionEncoding::Uint16,
+            PositionEncoding::Float32,
+            PositionEncoding::Float64,
+        ] {
+            let mut buf = Vec::new();
+            for _ in 0..3 * NUM_POINTS {
+                match encoding {
+                    PositionEncoding::Float32 => {
+                        buf.extend_from_slice(&rng.gen_range(0f32, 1.).to_le_bytes())
+                    }
+                    PositionEncoding::Float64 => {
+                        buf.extend_from_slice(&rng.gen_range(0f64, 1.).to_le_bytes())
+                    }
+                    _ => {
+                        for _ in 0..encoding.bytes_per_coordinate() {
+                            buf.push(rng.gen());
+                        }
+                    }
+                }
+            }
+            let mut expected = vec![0.; 3 * NUM_POINTS];
+            decode_positions_scalar(&buf, encoding, &min, edge_length, &mut expected, 0);
+            let mut expected_position = Vec::new();
+            extend_points(&expected, &mut expected_position);
+            let mut position = Vec::new();
+            decode_positions(
+                &buf,
+                encoding,
+                &min,
+                edge_length,
+                &mut Vec::new(),
+                &mut position,
+            );
+            assert_eq!(position, expected_position, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_vectorized_records_match_scalar() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let offset = Vector3::new(1000.5, -20., 0.125);
+        for &stride in &[XYZ_F32_RGB_U8_SIZE, XYZ_F32_RGB_U8_SIZE + 1] {
+            let mut buf = Vec::new();
+            for _ in 0..NUM_POINTS {
+                for _ in 0..3 {
+                    buf.extend_from_slice(&rng.gen_range(-1000f32, 1000.).to_le_bytes());
+                }
+                for _ in 12..stride {
+                    buf.push(rng.gen());
+                }
+            }
+            let mut expected = vec![0.; 3 * NUM_POINTS];
+            decode_xyz_f32_scalar(&buf, stride, &offset, &mut expected, 0);
+            let mut expected_position = Vec::new();
+            extend_points(&expected, &mut expected_position);
+            let (mut position, mut color) = (Vec::new(), Vec::new());
+            decode_xyz_f32_rgb_u8(
+                &buf,
+                stride,
+                &offset,
+                &mut Vec::new(),
+                &mut position,
+                &mut color,
+            );
+            assert_eq!(position, expected_position);
+            assert_eq!(color.len(), NUM_POINTS);
+            assert_eq!(color[NUM_POINTS - 1].z, buf[buf.len() - stride + 14]);
+        }
+    }
+}
@@ -0,0 +1,607 @@
+//! Reads points from text files, e.g. the PTS, CSV and XYZ exports of scanner software. Numbers
+//! are parsed with 'fast-float' and may use what these exports contain: exponents like '1.5E+03'
+//! or Fortran's '1.5D+03', a leading '+', a comma as decimal separator and repeated delimiters,
+//! e.g. columns aligned with several spaces. Lines that still cannot be parsed are skipped and
+//! counted, so that a few broken lines do not fail a file with millions of points.
+
+use crate::errors::*;
+use crate::{AttributeData, NextInto, PointsBatch};
+use nalgebra::{Point3, Vector3};
+#[cfg(feature = "native")]
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// At most this many descriptions of invalid lines are kept, see 'TextIterator::errors'.
+const MAX_REPORTED_ERRORS: usize = 10;
+
+/// The size of the chunks that the file is read in, see 'TextIterator::with_chunk_bytes'.
+const DEFAULT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+
+/// Name of columns that are not read, see 'TextFormat::columns'.
+pub const SKIPPED_COLUMN: &str = "-";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextFormat {
+    /// The character between columns. A run of it, with whitespace around it, separates two
+    /// columns, so empty columns cannot be expressed. None separates columns by whitespace.
+    pub delimiter: Option<char>,
+    /// '.', or ',' for files written with e.g. a German or French locale.
+    pub decimal_separator: char,
+    /// Number of lines at the start of the file that are not read, e.g. the point count of PTS
+    /// files.
+    pub skip_lines: usize,
+    /// The names of the columns. 'x', 'y' and 'z' are the position, 'red', 'green' and 'blue' the
+    /// color in [0, 255] and 'intensity' is read as a 32 bit float. Other columns become 64 bit
+    /// float attributes of the same name, except for 'SKIPPED_COLUMN'. Columns after the named
+    /// ones are ignored. None reads the names from the first line after 'skip_lines', like the
+    /// header that 'CsvNodeWriter' writes.
+    pub columns: Option<Vec<String>>,
+}
+
+impl TextFormat {
+    /// PTS files: a line with the point count, then 'x y z intensity red green blue'.
+    pub fn pts() -> Self {
+        Self {
+            delimiter: None,
+            decimal_separator: '.',
+            skip_lines: 1,
+            columns: Some(
+                ["x", "y", "z", "intensity", "red", "green", "blue"]
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// CSV files with a header line that names the columns.
+    pub fn csv() -> Self {
+        Self {
+            delimiter: Some(','),
+            decimal_separator: '.',
+            skip_lines: 0,
+            columns: None,
+        }
+    }
+
+    /// XYZ files: 'x y z' and maybe more columns, which are ignored.
+    pub fn xyz() -> Self {
+        Self {
+            delimiter: None,
+            decimal_separator: '.',
+            skip_lines: 0,
+            columns: Some(vec!["x".to_string(), "y".to_string(), "z".to_string()]),
+        }
+    }
+
+    /// The format for a file with the extension 'pts', 'csv', 'xyz' or 'txt'.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "pts" => Some(Self::pts()),
+            "csv" => Some(Self::csv()),
+            "xyz" | "txt" => Some(Self::xyz()),
+            _ => None,
+        }
+    }
+}
+
+/// Splits 'line' into its columns, see 'TextFormat::delimiter'.
+fn split(line: &str, delimiter: Option<char>) -> impl Iterator<Item = &str> {
+    line.split(move |c: char| match delimiter {
+        Some(delimiter) => c == delimiter,
+        None => c.is_whitespace(),
+    })
+    .map(str::trim)
+    .filter(|column| !column.is_empty())
+}
+
+/// Parses a number in 'field' whose decimal separator is 'decimal_separator'. Besides what Rust
+/// parses, it accepts a leading '+' and 'd' or 'D' as exponent marker.
+pub fn parse_number(field: &str, decimal_separator: char) -> Option<f64> {
+    let needs_rewrite = |c: char| c == decimal_separator || c == 'd' || c == 'D';
+    let field = if decimal_separator != '.' && field.contains('.') {
+        // A point is a thousands separator in these locales, which is too ambiguous to read.
+        return None;
+    } else if field.contains(needs_rewrite) {
+        Cow::Owned(
+            field
+                .chars()
+                .map(|c| match c {
+                    'd' | 'D' => 'e',
+                    c if c == decimal_separator => '.',
+                    c => c,
+                })
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(field)
+    };
+    let field: &str = &field;
+    let field = field.strip_prefix('+').unwrap_or(field);
+    fast_float::parse(field).ok()
+}
+
+/// Where the values of a column go.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Column {
+    Position(usize),
+    Color(usize),
+    Intensity,
+    Attribute(usize),
+    Skipped,
+}
+
+/// The points of a chunk of lines, see 'LineParser::parse_chunk'.
+struct ParsedChunk {
+    batch: PointsBatch,
+    num_lines: usize,
+    num_invalid_lines: usize,
+    // The first of the invalid lines, by their index in the chunk, and what is wrong with them.
+    errors: Vec<(usize, String)>,
+}
+
+/// Turns lines into points. It is shared by the threads that parse the chunks of a file.
+struct LineParser {
+    delimiter: Option<char>,
+    decimal_separator: char,
+    columns: Vec<Column>,
+    // The names of the columns that are read as 'Column::Attribute', by index.
+    attribute_names: Vec<String>,
+    has_color: bool,
+    has_intensity: bool,
+}
+
+impl LineParser {
+    /// Parses 'line' into 'position', 'color', 'intensity' and 'attributes'. Returns an error
+    /// message if it is not a valid point.
+    fn parse_line(
+        &self,
+        line: &str,
+        position: &mut Point3<f64>,
+        color: &mut Vector3<u8>,
+        intensity: &mut f32,
+        attributes: &mut [f64],
+    ) -> std::result::Result<(), String> {
+        let mut fields = split(line, self.delimiter);
+        for column in &self.columns {
+            let field = fields
+                .next()
+                .ok_or_else(|| format!("has fewer than {} columns", self.columns.len()))?;
+            if *column == Column::Skipped {
+                continue;
+            }
+            let value = parse_number(field, self.decimal_separator)
+                .ok_or_else(|| format!("'{}' is not a number", field))?;
+            match column {
+                Column::Position(i) => position[*i] = value,
+                Column::Color(i) => {
+                    if !(0. ..=255.).contains(&value) {
+                        return Err(format!("the color {} is not in [0, 255]", value));
+                    }
+                    color[*i] = value.round() as u8;
+                }
+                Column::Intensity => *intensity = value as f32,
+                Column::Attribute(i) => attributes[*i] = value,
+                Column::Skipped => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the lines of 'chunk', which ends at the end of a line.
+    fn parse_chunk(&self, chunk: &[u8]) -> ParsedChunk {
+        let mut positions = Vec::new();
+        let mut colors = Vec::new();
+        let mut intensities = Vec::new();
+        let mut attributes = vec![Vec::new(); self.attribute_names.len()];
+        let mut values = vec![0.; self.attribute_names.len()];
+        let mut num_lines = 0;
+        let mut num_invalid_lines = 0;
+        let mut errors = Vec::new();
+        for (index, line) in String::from_utf8_lossy(chunk).lines().enumerate() {
+            num_lines += 1;
+            let line = line.trim();
+            // Blank lines and comments are not points.
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut position = Point3::origin();
+            let mut color = Vector3::zeros();
+            let mut intensity = 0.;
+            match self.parse_line(line, &mut position, &mut color, &mut intensity, &mut values) {
+                Ok(()) => {
+                    positions.push(position);
+                    if self.has_color {
+                        colors.push(color);
+                    }
+                    if self.has_intensity {
+                        intensities.push(intensity);
+                    }
+                    for (attribute, value) in attributes.iter_mut().zip(&values) {
+                        attribute.push(*value);
+                    }
+                }
+                Err(message) => {
+                    num_invalid_lines += 1;
+                    if errors.len() < MAX_REPORTED_ERRORS {
+                        errors.push((index, message));
+                    }
+                }
+            }
+        }
+
+        let mut batch = PointsBatch {
+            position: positions,
+            ..Default::default()
+        };
+        if self.has_color {
+            batch
+                .attributes
+                .insert("color".to_string(), AttributeData::U8Vec3(colors));
+        }
+        if self.has_intensity {
+            batch
+                .attributes
+                .insert("intensity".to_string(), AttributeData::F32(intensities));
+        }
+        for (name, values) in self.attribute_names.iter().zip(attributes) {
+            batch
+                .attributes
+                .insert(name.clone(), AttributeData::F64(values));
+        }
+        ParsedChunk {
+            batch,
+            num_lines,
+            num_invalid_lines,
+            errors,
+        }
+    }
+}
+
+/// Parses 'chunks' in parallel on the rayon thread pool, in their order.
+#[cfg(feature = "native")]
+fn parse_chunks(parser: &LineParser, chunks: &[Vec<u8>]) -> Vec<ParsedChunk> {
+    chunks
+        .par_iter()
+        .map(|chunk| parser.parse_chunk(chunk))
+        .collect()
+}
+
+#[cfg(not(feature = "native"))]
+fn parse_chunks(parser: &LineParser, chunks: &[Vec<u8>]) -> Vec<ParsedChunk> {
+    chunks
+        .iter()
+        .map(|chunk| parser.parse_chunk(chunk))
+        .collect()
+}
+
+/// Streams the points of a text file in batches, see 'TextFormat'. The file is read in chunks
+/// that end at line boundaries, which are parsed in parallel and then put back in order, so that
+/// the points come in the order of the file.
+pub struct TextIterator {
+    reader: BufReader<File>,
+    parser: LineParser,
+    batch_size: usize,
+    chunk_bytes: usize,
+    // The parsed points that have not been returned yet.
+    pending: PointsBatch,
+    // The start of a line at the end of the last chunk that was read.
+    remainder: Vec<u8>,
+    end_of_file: bool,
+    line_number: usize,
+    num_invalid_lines: usize,
+    errors: Vec<String>,
+}
+
+impl TextIterator {
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        format: TextFormat,
+        batch_size: usize,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if format.delimiter == Some(format.decimal_separator) {
+            return Err(Error::InvalidInput(format!(
+                "The delimiter and the decimal separator are both '{}'.",
+                format.decimal_separator
+            )));
+        }
+        let file =
+            File::open(path).with_context(|| format!("Could not open {}.", path.display()))?;
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        for _ in 0..format.skip_lines {
+            line.clear();
+            reader.read_until(b'\n', &mut line)?;
+        }
+        let mut line_number = format.skip_lines;
+        let names = match format.columns {
+            Some(names) => names,
+            None => {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    return Err(Error::InvalidInput(format!(
+                        "{} has no header line.",
+                        path.display()
+                    )));
+                }
+                line_number += 1;
+                split(&String::from_utf8_lossy(&line), format.delimiter)
+                    .map(|name| name.trim_matches('"').to_lowercase())
+                    .collect()
+            }
+        };
+
+        let mut columns = Vec::new();
+        let mut attribute_names = Vec::new();
+        for name in &names {
+            let column = match name.as_str() {
+                "x" => Column::Position(0),
+                "y" => Column::Position(1),
+                "z" => Column::Position(2),
+                "red" => Column::Color(0),
+                "green" => Column::Color(1),
+                "blue" => Column::Color(2),
+                "intensity" => Column::Intensity,
+                SKIPPED_COLUMN => Column::Skipped,
+                _ => {
+                    attribute_names.push(name.clone());
+                    Column::Attribute(attribute_names.len() - 1)
+                }
+            };
+            if column != Column::Skipped && columns.contains(&column) {
+                return Err(Error::InvalidInput(format!(
+                    "The column '{}' is given twice.",
+                    name
+                )));
+            }
+            columns.push(column);
+        }
+        for i in 0..3 {
+            if !columns.contains(&Column::Position(i)) {
+                return Err(Error::InvalidInput(format!(
+                    "The columns {:?} have no '{}'.",
+                    names,
+                    ["x", "y", "z"][i]
+                )));
+            }
+        }
+        let has_color = (0..3).any(|i| columns.contains(&Column::Color(i)));
+        if has_color && !(0..3).all(|i| columns.contains(&Column::Color(i))) {
+            return Err(Error::InvalidInput(
+                "A color needs the columns 'red', 'green' and 'blue'.".to_string(),
+            ));
+        }
+        let has_intensity = columns.contains(&Column::Intensity);
+        Ok(Self {
+            reader,
+            parser: LineParser {
+                delimiter: format.delimiter,
+                decimal_separator: format.decimal_separator,
+                columns,
+                attribute_names,
+                has_color,
+                has_intensity,
+            },
+            batch_size,
+            chunk_bytes: DEFAULT_CHUNK_BYTES,
+            pending: PointsBatch::default(),
+            remainder: Vec::new(),
+            end_of_file: false,
+            line_number,
+            num_invalid_lines: 0,
+            errors: Vec::new(),
+        })
+    }
+
+    /// Reads the file in chunks of about 'chunk_bytes' instead of 'DEFAULT_CHUNK_BYTES'. Every
+    /// thread parses one chunk at a time.
+    pub fn with_chunk_bytes(mut self, chunk_bytes: usize) -> Self {
+        self.chunk_bytes = chunk_bytes.max(1);
+        self
+    }
+
+    /// Number of lines with points that could not be parsed and were skipped.
+    pub fn num_invalid_lines(&self) -> usize {
+        self.num_invalid_lines
+    }
+
+    /// Describes the first of the invalid lines, with their line numbers.
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    /// Reads the next chunk of whole lines. Returns None at the end of the file.
+    fn read_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut chunk = std::mem::take(&mut self.remainder);
+        loop {
+            let start = chunk.len();
+            let num_read = (&mut self.reader)
+                .take(self.chunk_bytes as u64)
+                .read_to_end(&mut chunk)?;
+            if num_read == 0 {
+                self.end_of_file = true;
+                return Ok(if chunk.is_empty() { None } else { Some(chunk) });
+            }
+            // Lines longer than a chunk make it grow until the end of the line.
+            if let Some(end) = chunk[start..].iter().rposition(|b| *b == b'\n') {
+                self.remainder = chunk.split_off(start + end + 1);
+                return Ok(Some(chunk));
+            }
+        }
+    }
+
+    /// Parses the next chunks of the file, one for every CPU, into 'pending'.
+    fn parse_next_chunks(&mut self) -> Result<()> {
+        let mut chunks = Vec::new();
+        while chunks.len() < num_cpus::get() {
+            match self.read_chunk()? {
+                Some(chunk) => chunks.push(chunk),
+                None => break,
+            }
+        }
+        for mut parsed in parse_chunks(&self.parser, &chunks) {
+            for (index, message) in parsed.errors {
+                if self.errors.len() < MAX_REPORTED_ERRORS {
+                    self.errors.push(format!(
+                        "Line {}: {}.",
+                        self.line_number + index + 1,
+                        message
+                    ));
+                }
+            }
+            self.line_number += parsed.num_lines;
+            self.num_invalid_lines += parsed.num_invalid_lines;
+            self.pending
+                .append(&mut parsed.batch)
+                .map_err(Error::SchemaMismatch)?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for TextIterator {
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = PointsBatch::default();
+        if self.next_into(&mut batch) {
+            Some(batch)
+        } else {
+            None
+        }
+    }
+}
+
+impl NextInto for TextIterator {
+    fn next_into(&mut self, batch: &mut PointsBatch) -> bool {
+        while self.pending.position.len() < self.batch_size && !self.end_of_file {
+            self.parse_next_chunks().unwrap_or_else(|err| {
+                panic!("Could not read after line {}: {}", self.line_number, err)
+            });
+        }
+        if self.pending.position.is_empty() {
+            return false;
+        }
+        let rest = self
+            .pending
+            .split_off(self.batch_size.min(self.pending.position.len()));
+        *batch = std::mem::replace(&mut self.pending, rest);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("1.5", '.'), Some(1.5));
+        assert_eq!(parse_number("+1.5E+03", '.'), Some(1500.));
+        assert_eq!(parse_number("1.5D-01", '.'), Some(0.15));
+        assert_eq!(parse_number("-.5e2", '.'), Some(-50.));
+        assert_eq!(parse_number("1,5", ','), Some(1.5));
+        assert_eq!(parse_number("1.000,5", ','), None);
+        assert_eq!(parse_number("1,5", '.'), None);
+        assert_eq!(parse_number("abc", '.'), None);
+    }
+
+    #[test]
+    fn test_read_text() {
+        let tmp_dir = TempDir::new("text").unwrap();
+        let path = tmp_dir.path().join("points.csv");
+        fs::write(
+            &path,
+            "x;y;z;red;green;blue;gps_time\r\n\
+             1,5;;2;3;255;0;0;1,0E+01\r\n\
+             # a comment\r\n\
+             4;5;oops;0;0;0;0\r\n\
+             \r\n\
+             -1;-2;-3;0;128;255;2D+00\r\n",
+        )
+        .unwrap();
+        let format = TextFormat {
+            delimiter: Some(';'),
+            decimal_separator: ',',
+            ..TextFormat::csv()
+        };
+        let mut points = TextIterator::from_file(&path, format, 100).unwrap();
+        let batch = points.next().unwrap();
+        assert_eq!(
+            batch.position,
+            vec![Point3::new(1.5, 2., 3.), Point3::new(-1., -2., -3.)]
+        );
+        assert_eq!(
+            batch.get_attribute_vec::<Vector3<u8>>("color").unwrap(),
+            &vec![Vector3::new(255, 0, 0), Vector3::new(0, 128, 255)]
+        );
+        assert_eq!(
+            batch.get_attribute_vec::<f64>("gps_time").unwrap(),
+            &vec![10., 2.]
+        );
+        assert!(points.next().is_none());
+        assert_eq!(points.num_invalid_lines(), 1);
+        assert_eq!(
+            points.errors(),
+            &["Line 4: 'oops' is not a number.".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_small_chunks() {
+        let tmp_dir = TempDir::new("text").unwrap();
+        let path = tmp_dir.path().join("points.pts");
+        let mut text = "1000\n".to_string();
+        for i in 0..1000 {
+            if i == 500 {
+                text.push_str("1 2 3\n");
+            }
+            text.push_str(&format!("{} {}.5   {} -3 10 20 30\n", i, i, i * 2));
+        }
+        fs::write(&path, text).unwrap();
+        let read = |chunk_bytes| {
+            let mut points = TextIterator::from_file(&path, TextFormat::pts(), 7)
+                .unwrap()
+                .with_chunk_bytes(chunk_bytes);
+            let mut all = PointsBatch::default();
+            for mut batch in &mut points {
+                assert!(batch.position.len() <= 7);
+                all.append(&mut batch).unwrap();
+            }
+            (all, points.errors().to_vec())
+        };
+        // Chunks that are shorter than a line read whole lines.
+        let (small, errors) = read(5);
+        assert_eq!(
+            errors,
+            vec!["Line 502: has fewer than 7 columns.".to_string()]
+        );
+        let (large, _) = read(1 << 20);
+        assert_eq!(small.position.len(), 1000);
+        assert_eq!(small.position[999], Point3::new(999., 999.5, 1998.));
+        assert_eq!(small.position, large.position);
+        assert_eq!(
+            small.get_attribute_vec::<Vector3<u8>>("color").unwrap(),
+            large.get_attribute_vec::<Vector3<u8>>("color").unwrap()
+        );
+        assert_eq!(
+            small.get_attribute_vec::<f32>("intensity").unwrap(),
+            large.get_attribute_vec::<f32>("intensity").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_missing_position() {
+        let tmp_dir = TempDir::new("text").unwrap();
+        let path = tmp_dir.path().join("points.csv");
+        fs::write(&path, "x,y,intensity\n1,2,3\n").unwrap();
+        assert!(TextIterator::from_file(&path, TextFormat::csv(), 100).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+//! Interleaved vertex buffers that can be uploaded to the GPU as they are.
+
+use crate::errors::*;
+use crate::{match_1d_attr_data, AttributeData, PointsBatch};
+use byteorder::{LittleEndian, WriteBytesExt};
+use nalgebra::Point3;
+
+/// The format of one vertex attribute, named like the vertex formats of WebGPU.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VertexFormat {
+    /// Three floats, for positions.
+    Float32x3,
+    /// One float, for scalar attributes like intensity.
+    Float32,
+    /// Three bytes that the GPU normalizes to [0, 1], for colors.
+    Unorm8x3,
+    /// Like 'Unorm8x3' with an opaque alpha, since many APIs have no three component byte
+    /// formats.
+    Unorm8x4,
+    /// Three floats in [-1, 1] as bytes that the GPU normalizes, for unit normals, padded with a
+    /// zero to four bytes.
+    Snorm8x4,
+}
+
+impl VertexFormat {
+    pub fn size_of(self) -> usize {
+        match self {
+            VertexFormat::Float32x3 => 12,
+            VertexFormat::Float32 => 4,
+            VertexFormat::Unorm8x3 => 3,
+            VertexFormat::Unorm8x4 => 4,
+            VertexFormat::Snorm8x4 => 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VertexAttribute {
+    pub name: String,
+    pub format: VertexFormat,
+    /// Offset of the attribute in a vertex in bytes.
+    pub offset: usize,
+}
+
+/// The layout of the vertices in an interleaved vertex buffer. The attribute "position" is
+/// written relative to the bounding cube of the node, scaled to [0, 1], i.e. the world position
+/// is 'position * edge_length + min'. Other attributes are read from the point attributes with
+/// the same name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VertexLayout {
+    attributes: Vec<VertexAttribute>,
+    stride: usize,
+}
+
+impl VertexLayout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an attribute after the previous ones. Attributes start at multiples of four bytes,
+    /// as required by most graphics APIs.
+    pub fn with_attribute(mut self, name: impl Into<String>, format: VertexFormat) -> Self {
+        let offset = align_to_4(self.stride);
+        self.attributes.push(VertexAttribute {
+            name: name.into(),
+            format,
+            offset,
+        });
+        self.stride = align_to_4(offset + format.size_of());
+        self
+    }
+
+    /// Position as 'Float32x3' and color as 'Unorm8x4', 16 bytes per vertex.
+    pub fn position_color() -> Self {
+        Self::new()
+            .with_attribute("position", VertexFormat::Float32x3)
+            .with_attribute("color", VertexFormat::Unorm8x4)
+    }
+
+    pub fn attributes(&self) -> &[VertexAttribute] {
+        &self.attributes
+    }
+
+    /// Number of bytes per vertex.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Names of the point attributes that are needed to fill the layout.
+    pub fn point_attributes(&self) -> Vec<&str> {
+        self.attributes
+            .iter()
+            .map(|attribute| attribute.name.as_str())
+            .filter(|name| *name != "position")
+            .collect()
+    }
+
+    /// Appends the points of 'batch' to 'buffer', with positions relative to the cube given by
+    /// 'min' and 'edge_length'.
+    pub fn write_vertices(
+        &self,
+        batch: &PointsBatch,
+        min: &Point3<f64>,
+        edge_length: f64,
+        buffer: &mut Vec<u8>,
+    ) -> Result<()> {
+        let start = buffer.len();
+        buffer.resize(start + batch.position.len() * self.stride, 0);
+        let vertices = &mut buffer[start..];
+        for attribute in &self.attributes {
+            let mut vertices = vertices.chunks_exact_mut(self.stride).map(|vertex| {
+                &mut vertex[attribute.offset..attribute.offset + attribute.format.size_of()]
+            });
+            if attribute.name == "position" {
+                if attribute.format != VertexFormat::Float32x3 {
+                    return Err(format_err(attribute));
+                }
+                for (mut vertex, position) in vertices.zip(&batch.position) {
+                    for i in 0..3 {
+                        let normalized = (position[i] - min[i]) / edge_length;
+                        vertex.write_f32::<LittleEndian>(normalized as f32)?;
+                    }
+                }
+                continue;
+            }
+            let data = batch.attributes.get(&attribute.name).ok_or_else(|| {
+                Error::InvalidInput(format!("Missing attribute '{}'.", attribute.name))
+            })?;
+            match (attribute.format, data) {
+                (VertexFormat::Unorm8x3, AttributeData::U8Vec3(colors)) => {
+                    for (vertex, color) in vertices.zip(colors) {
+                        vertex.copy_from_slice(color.as_slice());
+                    }
+                }
+                (VertexFormat::Unorm8x4, AttributeData::U8Vec3(colors)) => {
+                    for (vertex, color) in vertices.zip(colors) {
+                        vertex[..3].copy_from_slice(color.as_slice());
+                        vertex[3] = u8::max_value();
+                    }
+                }
+                (VertexFormat::Snorm8x4, AttributeData::F64Vec3(vectors)) => {
+                    for (vertex, vector) in vertices.zip(vectors) {
+                        for i in 0..3 {
+                            let snorm = (vector[i].max(-1.).min(1.) * 127.).round() as i8;
+                            vertex[i] = snorm as u8;
+                        }
+                        vertex[3] = 0;
+                    }
+                }
+                (VertexFormat::Float32, AttributeData::U8Vec3(_))
+                | (VertexFormat::Float32, AttributeData::F64Vec3(_)) => {
+                    return Err(format_err(attribute));
+                }
+                (VertexFormat::Float32, data) => {
+                    macro_rules! rhs {
+                        ($dtype:ident, $data:ident) => {
+                            for (mut vertex, value) in vertices.zip($data) {
+                                vertex.write_f32::<LittleEndian>(*value as f32)?;
+                            }
+                        };
+                    }
+                    match_1d_attr_data!(data, rhs)
+                }
+                _ => return Err(format_err(attribute)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn align_to_4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn format_err(attribute: &VertexAttribute) -> Error {
+    Error::UnsupportedFormat(format!(
+        "Attribute '{}' cannot be written as {:?}.",
+        attribute.name, attribute.format
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use nalgebra::Vector3;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_write_vertices() {
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "color".to_string(),
+            AttributeData::U8Vec3(vec![Vector3::new(1, 2, 3), Vector3::new(4, 5, 6)]),
+        );
+        attributes.insert("intensity".to_string(), AttributeData::U16(vec![7, 8]));
+        let batch = PointsBatch {
+            position: vec![Point3::new(1., 2., 3.), Point3::new(3., 4., 5.)],
+            attributes,
+        };
+        let layout =
+            VertexLayout::position_color().with_attribute("intensity", VertexFormat::Float32);
+        assert_eq!(layout.stride(), 20);
+        assert_eq!(layout.point_attributes(), vec!["color", "intensity"]);
+
+        let mut buffer = Vec::new();
+        layout
+            .write_vertices(&batch, &Point3::new(1., 2., 3.), 4., &mut buffer)
+            .unwrap();
+        assert_eq!(buffer.len(), 40);
+        let vertex = &buffer[20..];
+        let mut position = [0.; 3];
+        LittleEndian::read_f32_into(&vertex[..12], &mut position);
+        assert_eq!(position, [0.5, 0.5, 0.5]);
+        assert_eq!(&vertex[12..16], &[4, 5, 6, 255]);
+        assert_eq!(LittleEndian::read_f32(&vertex[16..]), 8.);
+    }
+
+    #[test]
+    fn test_write_normals() {
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "normal".to_string(),
+            AttributeData::F64Vec3(vec![Vector3::new(0., -1., 0.5)]),
+        );
+        let batch = PointsBatch {
+            position: vec![Point3::new(0., 0., 0.)],
+            attributes,
+        };
+        let layout = VertexLayout::new().with_attribute("normal", VertexFormat::Snorm8x4);
+        let mut buffer = Vec::new();
+        layout
+            .write_vertices(&batch, &Point3::new(0., 0., 0.), 1., &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, vec![0, (-127i8) as u8, 64, 0]);
+    }
+
+    #[test]
+    fn test_unaligned_attributes_are_padded() {
+        let layout = VertexLayout::new()
+            .with_attribute("color", VertexFormat::Unorm8x3)
+            .with_attribute("intensity", VertexFormat::Float32);
+        assert_eq!(layout.attributes()[1].offset, 4);
+        assert_eq!(layout.stride(), 8);
+    }
+}
@@ -0,0 +1,467 @@
+//! Aligns points to a reference point cloud with the iterative closest point algorithm (ICP), e.g.
+//! to register a new scan against an existing map.
+
+use crate::errors::*;
+use crate::fitting::Plane;
+use crate::iterator::{PointCloud, PointQuery};
+use crate::{PointsBatch, NUM_POINTS_PER_BATCH};
+use fnv::FnvHashMap;
+use nalgebra::{
+    Isometry3, Matrix3, Matrix6, Point3, Rotation3, Translation3, Unit, UnitQuaternion, Vector3,
+    Vector6,
+};
+use std::str::FromStr;
+
+/// What ICP minimizes in every iteration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IcpMethod {
+    /// The distances between corresponding points.
+    PointToPoint,
+    /// The distances of the points from the tangent planes of their corresponding reference
+    /// points. Converges faster on surfaces, since the points can slide along them.
+    PointToPlane,
+}
+
+impl FromStr for IcpMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "point-to-point" => Ok(IcpMethod::PointToPoint),
+            "point-to-plane" => Ok(IcpMethod::PointToPlane),
+            _ => Err(format!("Unknown ICP method '{}'.", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IcpOptions {
+    pub method: IcpMethod,
+    /// Points farther than this from the closest reference point have no correspondence. It
+    /// needs to be larger than the initial misalignment.
+    pub max_correspondence_distance: f64,
+    /// The normals of the reference points are estimated from their neighbors within this
+    /// radius. Only used for 'IcpMethod::PointToPlane'.
+    pub normal_radius: f64,
+    pub max_iterations: usize,
+    /// Stop when an iteration moves the points by less than this translation and angle.
+    pub convergence_threshold: f64,
+}
+
+impl Default for IcpOptions {
+    fn default() -> Self {
+        Self {
+            method: IcpMethod::PointToPlane,
+            max_correspondence_distance: 1.,
+            normal_radius: 0.5,
+            max_iterations: 30,
+            convergence_threshold: 1e-6,
+        }
+    }
+}
+
+/// Hashes points into cubic cells for radius searches up to the cell size.
+struct VoxelGrid {
+    cell_size: f64,
+    cells: FnvHashMap<[i64; 3], Vec<usize>>,
+}
+
+impl VoxelGrid {
+    fn new(points: &[Point3<f64>], cell_size: f64) -> Self {
+        let mut grid = Self {
+            cell_size,
+            cells: FnvHashMap::default(),
+        };
+        for (i, p) in points.iter().enumerate() {
+            let key = grid.key(p);
+            grid.cells.entry(key).or_default().push(i);
+        }
+        grid
+    }
+
+    fn key(&self, p: &Point3<f64>) -> [i64; 3] {
+        let cell = |v: f64| (v / self.cell_size).floor() as i64;
+        [cell(p.x), cell(p.y), cell(p.z)]
+    }
+
+    /// The indices of the points in the cell of 'p' and the cells around it, which include all
+    /// points within the cell size of 'p'.
+    fn candidates<'a>(&'a self, p: &Point3<f64>) -> impl Iterator<Item = usize> + 'a {
+        let [x, y, z] = self.key(p);
+        (-1..=1)
+            .flat_map(move |dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| (dx, dy, dz))))
+            .filter_map(move |(dx, dy, dz)| self.cells.get(&[x + dx, y + dy, z + dz]))
+            .flat_map(|indices| indices.iter().copied())
+    }
+}
+
+/// The points to align against, indexed for nearest neighbor searches.
+pub struct Reference {
+    points: Vec<Point3<f64>>,
+    /// Only estimated for point-to-plane ICP. None where there were too few neighbors.
+    normals: Vec<Option<Unit<Vector3<f64>>>>,
+    grid: VoxelGrid,
+}
+
+impl Reference {
+    /// Indexes 'points' for aligning with 'options'.
+    pub fn new(points: Vec<Point3<f64>>, options: &IcpOptions) -> Self {
+        let grid = VoxelGrid::new(
+            &points,
+            options
+                .max_correspondence_distance
+                .max(options.normal_radius),
+        );
+        let normals = match options.method {
+            IcpMethod::PointToPoint => Vec::new(),
+            IcpMethod::PointToPlane => {
+                let radius_squared = options.normal_radius * options.normal_radius;
+                points
+                    .iter()
+                    .map(|p| {
+                        let neighbors: Vec<_> = grid
+                            .candidates(p)
+                            .map(|i| points[i])
+                            .filter(|q| nalgebra::distance_squared(p, q) <= radius_squared)
+                            .collect();
+                        Plane::fit(&neighbors).map(|plane| plane.normal)
+                    })
+                    .collect()
+            }
+        };
+        Self {
+            points,
+            normals,
+            grid,
+        }
+    }
+
+    /// Loads the points of 'point_cloud' matching 'query' as the reference.
+    pub fn from_query<C: PointCloud>(
+        point_cloud: &C,
+        query: &PointQuery,
+        options: &IcpOptions,
+    ) -> Result<Self> {
+        Ok(Self::new(positions_in_query(point_cloud, query)?, options))
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The index of the closest point within 'max_distance' of 'p'.
+    fn closest(&self, p: &Point3<f64>, max_distance: f64) -> Option<usize> {
+        self.grid
+            .candidates(p)
+            .map(|i| (i, nalgebra::distance_squared(p, &self.points[i])))
+            .filter(|(_, distance_squared)| *distance_squared <= max_distance * max_distance)
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .map(|(i, _)| i)
+    }
+
+    /// The distance of 'p' from the closest point within 'max_distance', measured along the normal
+    /// of that point if it has one, and positive on the side that 'up' points to. None if no point
+    /// is within 'max_distance', which can be at most the correspondence distance or normal radius
+    /// this was indexed with.
+    pub fn signed_distance(
+        &self,
+        p: &Point3<f64>,
+        max_distance: f64,
+        up: &Vector3<f64>,
+    ) -> Option<f64> {
+        let i = self.closest(p, max_distance)?;
+        let offset = p - self.points[i];
+        let (distance, direction) = match self.normals.get(i).copied().flatten() {
+            Some(normal) => (offset.dot(&normal), normal.into_inner()),
+            None => (offset.norm(), offset),
+        };
+        Some(if direction.dot(up) < 0. {
+            -distance
+        } else {
+            distance
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IcpResult {
+    /// Maps the source points onto the reference.
+    pub transform: Isometry3<f64>,
+    /// The point-to-point or point-to-plane distance of every source point that has a
+    /// correspondence after applying 'transform'.
+    pub residuals: Vec<f64>,
+    /// The root mean square of 'residuals'.
+    pub rmse: f64,
+    pub num_iterations: usize,
+    /// False if 'IcpOptions::max_iterations' were reached first.
+    pub converged: bool,
+}
+
+/// A transformed source point and the index of the corresponding reference point.
+type Correspondence = (Point3<f64>, usize);
+
+fn correspondences(
+    source: &[Point3<f64>],
+    reference: &Reference,
+    transform: &Isometry3<f64>,
+    options: &IcpOptions,
+) -> Vec<Correspondence> {
+    source
+        .iter()
+        .filter_map(|p| {
+            let p = transform * p;
+            let i = reference.closest(&p, options.max_correspondence_distance)?;
+            match options.method {
+                IcpMethod::PointToPoint => Some((p, i)),
+                IcpMethod::PointToPlane => reference.normals[i].map(|_| (p, i)),
+            }
+        })
+        .collect()
+}
+
+/// The rigid transform that best maps the source onto the reference points in the least squares
+/// sense (Kabsch).
+fn point_to_point_step(
+    correspondences: &[Correspondence],
+    reference: &Reference,
+) -> Option<Isometry3<f64>> {
+    let n = correspondences.len() as f64;
+    let (source_sum, reference_sum) = correspondences
+        .iter()
+        .fold((Vector3::zeros(), Vector3::zeros()), |(s, r), (p, i)| {
+            (s + p.coords, r + reference.points[*i].coords)
+        });
+    let (source_centroid, reference_centroid) = (source_sum / n, reference_sum / n);
+    let covariance = correspondences
+        .iter()
+        .fold(Matrix3::zeros(), |sum, (p, i)| {
+            sum + (p.coords - source_centroid)
+                * (reference.points[*i].coords - reference_centroid).transpose()
+        });
+    let svd = covariance.svd(true, true);
+    let (u, v) = (svd.u?, svd.v_t?.transpose());
+    // Avoid reflections.
+    let d = (v * u.transpose()).determinant().signum();
+    let rotation = v * Matrix3::from_diagonal(&Vector3::new(1., 1., d)) * u.transpose();
+    let rotation =
+        UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(rotation));
+    let translation = reference_centroid - rotation * source_centroid;
+    Some(Isometry3::from_parts(
+        Translation3::from(translation),
+        rotation,
+    ))
+}
+
+/// Linearizes the rotation around small angles and solves for the transform that minimizes the
+/// point-to-plane distances.
+fn point_to_plane_step(
+    correspondences: &[Correspondence],
+    reference: &Reference,
+) -> Option<Isometry3<f64>> {
+    let (ata, atb) = correspondences.iter().fold(
+        (Matrix6::zeros(), Vector6::zeros()),
+        |(ata, atb), (p, i)| {
+            let normal = reference.normals[*i].unwrap();
+            let c = p.coords.cross(&normal);
+            let row = Vector6::new(c.x, c.y, c.z, normal.x, normal.y, normal.z);
+            let residual = (p - reference.points[*i]).dot(&normal);
+            (ata + row * row.transpose(), atb + row * residual)
+        },
+    );
+    // Fails if the surfaces do not constrain all degrees of freedom, e.g. a single plane.
+    let x = -ata.cholesky()?.solve(&atb);
+    Some(Isometry3::new(
+        Vector3::new(x[3], x[4], x[5]),
+        Vector3::new(x[0], x[1], x[2]),
+    ))
+}
+
+fn residual(reference: &Reference, method: IcpMethod, (p, i): &Correspondence) -> f64 {
+    match method {
+        IcpMethod::PointToPoint => nalgebra::distance(p, &reference.points[*i]),
+        IcpMethod::PointToPlane => (p - reference.points[*i])
+            .dot(&reference.normals[*i].unwrap())
+            .abs(),
+    }
+}
+
+/// Aligns 'source' to 'reference', starting from 'initial'. Iterations stop early when too few
+/// correspondences are left or the problem is degenerate.
+pub fn icp(
+    source: &[Point3<f64>],
+    reference: &Reference,
+    initial: &Isometry3<f64>,
+    options: &IcpOptions,
+) -> IcpResult {
+    let mut transform = *initial;
+    let mut num_iterations = 0;
+    let mut converged = false;
+    while num_iterations < options.max_iterations {
+        let correspondences = correspondences(source, reference, &transform, options);
+        if correspondences.len() < 3 {
+            break;
+        }
+        let step = match options.method {
+            IcpMethod::PointToPoint => point_to_point_step(&correspondences, reference),
+            IcpMethod::PointToPlane => point_to_plane_step(&correspondences, reference),
+        };
+        let step = match step {
+            Some(step) => step,
+            None => break,
+        };
+        transform = step * transform;
+        num_iterations += 1;
+        if step.translation.vector.norm() < options.convergence_threshold
+            && step.rotation.angle() < options.convergence_threshold
+        {
+            converged = true;
+            break;
+        }
+    }
+    let residuals: Vec<_> = correspondences(source, reference, &transform, options)
+        .iter()
+        .map(|correspondence| residual(reference, options.method, correspondence))
+        .collect();
+    let rmse = if residuals.is_empty() {
+        0.
+    } else {
+        (residuals.iter().map(|r| r * r).sum::<f64>() / residuals.len() as f64).sqrt()
+    };
+    IcpResult {
+        transform,
+        residuals,
+        rmse,
+        num_iterations,
+        converged,
+    }
+}
+
+/// Aligns the positions of 'batches' to 'reference', see 'icp'.
+pub fn align_batches(
+    batches: impl IntoIterator<Item = PointsBatch>,
+    reference: &Reference,
+    initial: &Isometry3<f64>,
+    options: &IcpOptions,
+) -> IcpResult {
+    let source: Vec<_> = batches
+        .into_iter()
+        .flat_map(|batch| batch.position)
+        .collect();
+    icp(&source, reference, initial, options)
+}
+
+/// Aligns the points of 'point_cloud' matching 'query' to 'reference', see 'icp'. All points are
+/// held in memory, so the query should be limited to the region to align.
+pub fn align_query<C: PointCloud>(
+    point_cloud: &C,
+    query: &PointQuery,
+    reference: &Reference,
+    initial: &Isometry3<f64>,
+    options: &IcpOptions,
+) -> Result<IcpResult> {
+    let source = positions_in_query(point_cloud, query)?;
+    Ok(icp(&source, reference, initial, options))
+}
+
+fn positions_in_query<C: PointCloud>(
+    point_cloud: &C,
+    query: &PointQuery,
+) -> Result<Vec<Point3<f64>>> {
+    let mut positions = Vec::new();
+    for node_id in point_cloud.nodes_in_location(&query.location) {
+        point_cloud.stream_points_for_query_in_node(
+            query,
+            node_id,
+            NUM_POINTS_PER_BATCH,
+            |mut batch| {
+                positions.append(&mut batch.position);
+                Ok(())
+            },
+        )?;
+    }
+    Ok(positions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+
+    /// A floor and two walls that are far enough apart to not disturb each other's normals.
+    fn room() -> Vec<Point3<f64>> {
+        let mut points = Vec::new();
+        for i in 0..=10 {
+            for j in 0..=10 {
+                let (a, b) = (f64::from(i) * 0.2, f64::from(j) * 0.2);
+                points.push(Point3::new(a, b, 0.));
+                points.push(Point3::new(3., a, b + 1.));
+                points.push(Point3::new(a, 3., b + 1.));
+            }
+        }
+        points
+    }
+
+    fn check_alignment(method: IcpMethod) {
+        let options = IcpOptions {
+            method,
+            max_correspondence_distance: 0.5,
+            normal_radius: 0.3,
+            ..Default::default()
+        };
+        let reference = Reference::new(room(), &options);
+        let expected = Isometry3::new(Vector3::new(0.03, -0.02, 0.01), Vector3::new(0., 0., 0.01));
+        let source: Vec<_> = room()
+            .iter()
+            .map(|p| expected.inverse_transform_point(p))
+            .collect();
+        let result = icp(&source, &reference, &Isometry3::identity(), &options);
+        assert!(result.converged);
+        assert_eq!(result.residuals.len(), source.len());
+        assert!(result.rmse < 1e-6);
+        assert_abs_diff_eq!(
+            result.transform.translation.vector,
+            expected.translation.vector,
+            epsilon = 1e-5
+        );
+        assert_abs_diff_eq!(
+            result.transform.rotation.angle_to(&expected.rotation),
+            0.,
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn test_signed_distance() {
+        let options = IcpOptions {
+            normal_radius: 0.3,
+            ..Default::default()
+        };
+        let reference = Reference::new(room(), &options);
+        let up = Vector3::z();
+        let distance = |p| reference.signed_distance(&p, 0.5, &up).unwrap();
+        assert_abs_diff_eq!(distance(Point3::new(1., 1., 0.1)), 0.1, epsilon = 1e-9);
+        assert_abs_diff_eq!(distance(Point3::new(1., 1., -0.1)), -0.1, epsilon = 1e-9);
+        assert!(reference
+            .signed_distance(&Point3::new(1., 1., 1.), 0.5, &up)
+            .is_none());
+
+        // Without normals, the distance is signed by the side of 'up' that 'p' is on.
+        let reference = Reference::new(vec![Point3::origin()], &options);
+        let distance = |p| reference.signed_distance(&p, 0.6, &up).unwrap();
+        assert_abs_diff_eq!(distance(Point3::new(0.3, 0., -0.4)), -0.5, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_point_to_point() {
+        check_alignment(IcpMethod::PointToPoint);
+    }
+
+    #[test]
+    fn test_point_to_plane() {
+        check_alignment(IcpMethod::PointToPlane);
+    }
+}
@@ -0,0 +1,280 @@
+//! Reports that document an octree for a delivery: labeled views of it, from camera poses or of
+//! annotated places, and a summary of its extent, size and attributes. A report is a directory
+//! with an 'index.html' and a PNG per view, which browsers also print to PDF.
+
+use crate::color::YELLOW;
+use crate::errors::*;
+use crate::iterator::{PointCloud, PointLocation};
+use crate::octree::{render_snapshot, Octree, SnapshotOptions};
+use crate::stats::PointStats;
+use crate::utils::parse_pose;
+use crate::NUM_POINTS_PER_BATCH;
+use image::RgbaImage;
+use nalgebra::{Isometry3, Point3, Vector3};
+use serde_derive::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Distance of the camera from an annotated place, if the annotation does not give one.
+const DEFAULT_ANNOTATION_DISTANCE: f64 = 20.;
+
+/// Radius in pixels of the circle that marks an annotated place.
+const MARKER_RADIUS: f64 = 12.;
+
+/// One view of a report. It either has a 'camera' pose, given as 'x,y,z,qx,qy,qz,qw' like for
+/// 'point_cloud_tool view-settings', or annotates a 'position', which is then seen obliquely from
+/// above at 'distance' and marked in the image.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReportView {
+    pub label: String,
+    #[serde(default)]
+    pub camera: Option<String>,
+    #[serde(default)]
+    pub position: Option<[f64; 3]>,
+    #[serde(default)]
+    pub distance: Option<f64>,
+}
+
+impl ReportView {
+    /// The pose of the camera for this view of an octree whose up axis has the index 'up'.
+    pub fn octree_from_camera(&self, up: usize) -> Result<Isometry3<f64>> {
+        match (&self.camera, &self.position) {
+            (Some(camera), None) => parse_pose(camera).map_err(Error::InvalidInput),
+            (None, Some([x, y, z])) => {
+                let target = Point3::new(*x, *y, *z);
+                let mut up_vector = Vector3::zeros();
+                up_vector[up] = 1.;
+                // Looks at the place at 45 degrees from the south, or from the front for y up.
+                let mut direction = up_vector;
+                if up == 2 {
+                    direction.y = -1.;
+                } else {
+                    direction.z = 1.;
+                }
+                let distance = self.distance.unwrap_or(DEFAULT_ANNOTATION_DISTANCE);
+                let eye = target + direction.normalize() * distance;
+                Ok(Isometry3::look_at_rh(&eye, &target, &up_vector).inverse())
+            }
+            _ => Err(Error::InvalidInput(format!(
+                "The view '{}' needs either a 'camera' or a 'position'.",
+                self.label
+            ))),
+        }
+    }
+}
+
+/// Reads the views of a report from a JSON file with a list of 'ReportView's.
+pub fn read_report_views(path: impl AsRef<Path>) -> Result<Vec<ReportView>> {
+    let path = path.as_ref();
+    let data =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}.", path.display()))?;
+    serde_json::from_str(&data)
+        .map_err(|e| Error::InvalidInput(format!("Could not parse {}: {}", path.display(), e)))
+}
+
+#[derive(Clone, Debug)]
+pub struct ReportOptions {
+    pub title: String,
+    pub snapshot: SnapshotOptions,
+    /// Attributes to add statistics of to the summary, which takes a pass over all points.
+    /// Without any, the summary is taken from the meta data alone.
+    pub stats_attributes: Vec<String>,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            title: "Point cloud report".to_string(),
+            snapshot: SnapshotOptions::default(),
+            stats_attributes: Vec::new(),
+        }
+    }
+}
+
+/// Replaces the characters that have a meaning in HTML.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Draws a circle around the pixel at 'column', 'row'.
+fn draw_marker(image: &mut RgbaImage, column: f64, row: f64) {
+    let num_steps = (2. * std::f64::consts::PI * MARKER_RADIUS).ceil() as usize * 2;
+    for step in 0..num_steps {
+        let angle = step as f64 / num_steps as f64 * 2. * std::f64::consts::PI;
+        let x = column + MARKER_RADIUS * angle.cos();
+        let y = row + MARKER_RADIUS * angle.sin();
+        if x >= 0. && y >= 0. && x < f64::from(image.width()) && y < f64::from(image.height()) {
+            image.put_pixel(x as u32, y as u32, YELLOW.to_u8().into());
+        }
+    }
+}
+
+/// The rows of the summary table: what the meta data says, and the statistics of
+/// 'stats_attributes'.
+fn summary_rows(octree: &Octree, options: &ReportOptions) -> Result<Vec<(String, String)>> {
+    let bounding_box = &octree.meta.bounding_box;
+    let (min, max) = (bounding_box.min(), bounding_box.max());
+    let mut rows = vec![
+        ("Points".to_string(), octree.num_points().to_string()),
+        ("Nodes".to_string(), octree.nodes.len().to_string()),
+        (
+            "Bounding box".to_string(),
+            format!(
+                "[{:.3}, {:.3}, {:.3}] - [{:.3}, {:.3}, {:.3}]",
+                min.x, min.y, min.z, max.x, max.y, max.z
+            ),
+        ),
+        (
+            "Extent".to_string(),
+            format!(
+                "{:.3} x {:.3} x {:.3}",
+                max.x - min.x,
+                max.y - min.y,
+                max.z - min.z
+            ),
+        ),
+        ("Resolution".to_string(), octree.meta.resolution.to_string()),
+        (
+            "Coordinate system".to_string(),
+            octree
+                .meta
+                .crs
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string()),
+        ),
+        (
+            "Up axis".to_string(),
+            octree.view_settings().up_axis.name().to_string(),
+        ),
+    ];
+    if options.stats_attributes.is_empty() {
+        return Ok(rows);
+    }
+    let attributes: Vec<&str> = options
+        .stats_attributes
+        .iter()
+        .map(String::as_str)
+        .collect();
+    let mut stats = PointStats::default();
+    for node_id in octree.nodes_in_location(&PointLocation::AllPoints) {
+        for batch in octree.points_in_node(&attributes, node_id, NUM_POINTS_PER_BATCH)? {
+            stats.add_batch(&batch);
+        }
+    }
+    for (name, channel) in &stats.channels {
+        rows.push((
+            name.clone(),
+            format!(
+                "min {}, max {}, mean {:.3}, stddev {:.3}",
+                channel.min,
+                channel.max,
+                channel.mean(),
+                channel.stddev()
+            ),
+        ));
+    }
+    Ok(rows)
+}
+
+/// Renders every view of 'octree' into 'directory' and writes the report page with the summary
+/// there. Returns the path of the page.
+pub fn write_report(
+    octree: &Octree,
+    views: &[ReportView],
+    directory: impl AsRef<Path>,
+    options: &ReportOptions,
+) -> Result<PathBuf> {
+    let directory = directory.as_ref();
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Could not create {}.", directory.display()))?;
+    let up = octree.view_settings().up_axis.index();
+
+    let mut figures = String::new();
+    for (index, view) in views.iter().enumerate() {
+        let octree_from_camera = view.octree_from_camera(up)?;
+        let mut image = render_snapshot(octree, &octree_from_camera, &options.snapshot)?;
+        if let Some([x, y, z]) = view.position {
+            let clip_from_octree = options.snapshot.clip_from_octree(&octree_from_camera);
+            if let Some((column, row, _)) = options
+                .snapshot
+                .project(&clip_from_octree, &Point3::new(x, y, z))
+            {
+                draw_marker(&mut image, column, row);
+            }
+        }
+        let file_name = format!("view_{:03}.png", index);
+        let path = directory.join(&file_name);
+        image
+            .save_with_format(&path, image::ImageFormat::Png)
+            .with_context(|| format!("Could not write {}.", path.display()))?;
+        let t = octree_from_camera.translation.vector;
+        figures.push_str(&format!(
+            "<figure><img src=\"{}\" alt=\"{}\"><figcaption><b>{}. {}</b><br>Camera at \
+             [{:.3}, {:.3}, {:.3}]</figcaption></figure>\n",
+            file_name,
+            escape_html(&view.label),
+            index + 1,
+            escape_html(&view.label),
+            t.x,
+            t.y,
+            t.z
+        ));
+    }
+
+    let mut table = String::new();
+    for (name, value) in summary_rows(octree, options)? {
+        table.push_str(&format!(
+            "<tr><th>{}</th><td>{}</td></tr>\n",
+            escape_html(&name),
+            escape_html(&value)
+        ));
+    }
+    let title = escape_html(&options.title);
+    let page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>\n\
+         body {{ font-family: sans-serif; margin: 2em; }}\n\
+         th {{ text-align: left; padding-right: 2em; }}\n\
+         figure {{ margin: 2em 0; page-break-inside: avoid; }}\n\
+         img {{ max-width: 100%; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n<h2>Summary</h2>\n<table>\n{table}</table>\n\
+         <h2>Views</h2>\n{figures}</body>\n</html>\n",
+        title = title,
+        table = table,
+        figures = figures
+    );
+    let path = directory.join("index.html");
+    fs::write(&path, page).with_context(|| format!("Could not write {}.", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotation_camera_looks_at_position() {
+        let view = ReportView {
+            label: "Manhole".to_string(),
+            camera: None,
+            position: Some([10., 20., 5.]),
+            distance: Some(2f64.sqrt()),
+        };
+        let pose = view.octree_from_camera(2).unwrap();
+        let eye = Point3::from(pose.translation.vector);
+        assert!((eye - Point3::new(10., 19., 6.)).norm() < 1e-9);
+        // The camera looks along its negative z axis.
+        let forward = pose.rotation * -Vector3::z();
+        assert!((forward - Vector3::new(0., 1., -1.).normalize()).norm() < 1e-9);
+
+        let both = ReportView {
+            camera: Some("0,0,0,0,0,0,1".to_string()),
+            ..view
+        };
+        assert!(both.octree_from_camera(2).is_err());
+    }
+}
@@ -108,17 +108,15 @@ impl S2Meta {
         // check if the meta is meant to be for S2 point cloud
         if meta_proto.version < 12 {
             // from version 12
-            return Err(ErrorKind::InvalidInput(format!(
+            return Err(Error::UnsupportedFormat(format!(
                 "No S2 point cloud supported with version {}",
                 meta_proto.version
-            ))
-            .into());
+            )));
         }
         if !(meta_proto.version >= 12 && meta_proto.has_s2()) {
-            return Err(ErrorKind::InvalidInput(
+            return Err(Error::InvalidInput(
                 "This meta does not describe S2 point clouds".to_string(),
-            )
-            .into());
+            ));
         }
 
         let bounding_box = Aabb::from(meta_proto.get_bounding_box());
@@ -165,6 +163,15 @@ impl PointCloud for S2Cells {
             PointLocation::Frustum(frustum) => self.cells_in_convex_polyhedron(frustum),
             PointLocation::S2Cells(cell_union) => self.cells_intersecting_region(cell_union),
             PointLocation::WebMercatorRect(wmr) => self.cells_in_convex_polyhedron(wmr),
+            PointLocation::Sphere(sphere) => {
+                self.cells_in_convex_polyhedron(&sphere.bounding_box())
+            }
+            PointLocation::Polygon(polygon) => {
+                self.cells_in_convex_polyhedron(&polygon.bounding_box())
+            }
+            PointLocation::Corridor(corridor) => {
+                self.cells_in_convex_polyhedron(&corridor.bounding_box())
+            }
         }
     }
 
@@ -183,6 +190,7 @@ impl PointCloud for S2Cells {
             &*self.data_provider,
             &self.meta.attribute_data_types_for(&attributes)?,
             self.encoding_for_node(node_id),
+            false,
             &node_id,
             num_points,
             batch_size,
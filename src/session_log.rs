@@ -0,0 +1,260 @@
+//! Logs of how the viewers are used in review sessions, so that teams can see which features they
+//! rely on and how much time goes into navigating. The logs are opt-in and hold nothing about the
+//! user and no positions: datasets are named by the last component of their path only, and the
+//! camera only by how far it moved. A log is a JSON lines file with one 'SessionRecord' per event,
+//! which the SDL viewer writes with '--session-log', the web client exports from its "Session"
+//! folder and 'point_cloud_tool session-summary' summarizes.
+
+use crate::errors::*;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// Moves of the camera that are closer in time than this many seconds are logged as one
+/// navigation.
+const NAVIGATION_GAP_S: f64 = 1.;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// A viewer started the session, e.g. 'sdl_viewer' or 'web_viewer'.
+    Start { viewer: String },
+    /// A dataset was opened.
+    DatasetLoaded { name: String },
+    /// The camera moved 'distance' in the units of the octree during 'duration_s' seconds.
+    Navigation { distance: f64, duration_s: f64 },
+    /// A feature was used, e.g. 'layout' or 'walk_mode'.
+    Feature { name: String },
+    /// The session ended.
+    End,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    /// Seconds since the session started.
+    pub time_s: f64,
+    #[serde(flatten)]
+    pub event: SessionEvent,
+}
+
+/// The name under which a dataset at 'path' is logged: the last component of the path, without
+/// the directories it is in, which may name users or projects.
+pub fn dataset_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Writes the log of a session while a viewer runs. Problems with writing are only warned about,
+/// since the log must not get in the way of the review. The session ends when the log is
+/// dropped.
+pub struct SessionLog {
+    writer: BufWriter<File>,
+    start: Instant,
+    /// The start and end time and the distance of the navigation that is not written yet.
+    navigation: Option<(f64, f64, f64)>,
+}
+
+impl SessionLog {
+    /// Creates the log at 'path' and starts a session of 'viewer'.
+    pub fn create(path: impl AsRef<Path>, viewer: &str) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Could not create {}.", path.display()))?;
+        let mut session_log = SessionLog {
+            writer: BufWriter::new(file),
+            start: Instant::now(),
+            navigation: None,
+        };
+        session_log.record(SessionEvent::Start {
+            viewer: viewer.to_string(),
+        });
+        Ok(session_log)
+    }
+
+    fn now_s(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    pub fn dataset_loaded(&mut self, path: &str) {
+        self.record(SessionEvent::DatasetLoaded {
+            name: dataset_name(path),
+        });
+    }
+
+    pub fn feature(&mut self, name: &str) {
+        self.record(SessionEvent::Feature {
+            name: name.to_string(),
+        });
+    }
+
+    /// Adds a move of the camera by 'distance' to the current navigation.
+    pub fn moved(&mut self, distance: f64) {
+        let now_s = self.now_s();
+        if let Some((_, end_s, sum)) = &mut self.navigation {
+            if now_s - *end_s < NAVIGATION_GAP_S {
+                *end_s = now_s;
+                *sum += distance;
+                return;
+            }
+        }
+        self.write_navigation();
+        self.navigation = Some((now_s, now_s, distance));
+    }
+
+    fn write_navigation(&mut self) {
+        if let Some((start_s, end_s, distance)) = self.navigation.take() {
+            self.write(&SessionRecord {
+                time_s: start_s,
+                event: SessionEvent::Navigation {
+                    distance,
+                    duration_s: end_s - start_s,
+                },
+            });
+        }
+    }
+
+    /// Writes 'event' after the navigation before it. The file is flushed, so the log survives a
+    /// crash of the viewer.
+    fn record(&mut self, event: SessionEvent) {
+        self.write_navigation();
+        let record = SessionRecord {
+            time_s: self.now_s(),
+            event,
+        };
+        self.write(&record);
+    }
+
+    fn write(&mut self, record: &SessionRecord) {
+        if let Err(err) = self.try_write(record) {
+            warn!("Could not write the session log: {}", err);
+        }
+    }
+
+    fn try_write(&mut self, record: &SessionRecord) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record)
+            .map_err(|e| Error::InvalidInput(e.to_string()))?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        self.record(SessionEvent::End);
+    }
+}
+
+/// Reads the records of one or more sessions from a log written by a 'SessionLog' or exported by
+/// the web client.
+pub fn read_session_log(path: impl AsRef<Path>) -> Result<Vec<SessionRecord>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Could not open {}.", path.display()))?;
+    let mut records = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|e| {
+            Error::InvalidInput(format!("Line {} of {}: {}", index + 1, path.display(), e))
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// How a number of sessions used the viewers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionSummary {
+    pub num_sessions: usize,
+    /// Seconds from the start of every session to its last event, summed up.
+    pub duration_s: f64,
+    /// Seconds in which the camera moved.
+    pub navigation_s: f64,
+    /// The distance that the camera moved in the units of the octrees.
+    pub distance: f64,
+    /// How often every dataset was opened.
+    pub datasets: BTreeMap<String, usize>,
+    /// How often every feature was used.
+    pub features: BTreeMap<String, usize>,
+}
+
+/// Sums up the sessions in 'records', e.g. of several logs. Every 'Start' begins a new session.
+pub fn summarize_sessions(records: &[SessionRecord]) -> SessionSummary {
+    let mut summary = SessionSummary::default();
+    let mut session_duration_s = 0.;
+    for record in records {
+        match &record.event {
+            SessionEvent::Start { .. } => {
+                summary.num_sessions += 1;
+                summary.duration_s += session_duration_s;
+                session_duration_s = 0.;
+            }
+            SessionEvent::DatasetLoaded { name } => {
+                *summary.datasets.entry(name.clone()).or_insert(0) += 1;
+            }
+            SessionEvent::Navigation {
+                distance,
+                duration_s,
+            } => {
+                summary.navigation_s += duration_s;
+                summary.distance += distance;
+            }
+            SessionEvent::Feature { name } => {
+                *summary.features.entry(name.clone()).or_insert(0) += 1;
+            }
+            SessionEvent::End => (),
+        }
+        session_duration_s = record.time_s;
+    }
+    summary.duration_s += session_duration_s;
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_session_log() {
+        let tmp_dir = TempDir::new("session_log").unwrap();
+        let path = tmp_dir.path().join("session.jsonl");
+        {
+            let mut session_log = SessionLog::create(&path, "sdl_viewer").unwrap();
+            session_log.dataset_loaded("/home/someone/octrees/city/");
+            session_log.moved(1.5);
+            session_log.moved(2.5);
+            session_log.feature("layout");
+            session_log.feature("layout");
+        }
+        let records = read_session_log(&path).unwrap();
+        let events: Vec<_> = records.iter().map(|r| r.event.clone()).collect();
+        assert_eq!(events.len(), 6);
+        assert_eq!(
+            events[1],
+            SessionEvent::DatasetLoaded {
+                name: "city".to_string()
+            }
+        );
+        // Moves in quick succession are one navigation.
+        match &events[2] {
+            SessionEvent::Navigation { distance, .. } => assert_eq!(*distance, 4.),
+            event => panic!("Expected a navigation, got {:?}.", event),
+        }
+        assert_eq!(events[5], SessionEvent::End);
+
+        let summary = summarize_sessions(&records);
+        assert_eq!(summary.num_sessions, 1);
+        assert_eq!(summary.distance, 4.);
+        assert_eq!(summary.features["layout"], 2);
+        assert_eq!(summary.datasets["city"], 1);
+    }
+}
@@ -0,0 +1,203 @@
+//! Destinations for the points that a query returns. A 'MultiSink' passes every batch to several
+//! sinks, e.g. a PLY file, statistics and a network stream, so that an expensive query only runs
+//! once for all of them.
+
+use crate::errors::*;
+use crate::iterator::{PointCloud, PointQuery};
+use crate::read_write::{Encoding, NodeWriter, OpenMode};
+use crate::stats::PointStats;
+use crate::PointsBatch;
+use std::path::PathBuf;
+
+pub trait QuerySink {
+    /// Consumes the next batch of points.
+    fn write(&mut self, batch: &PointsBatch) -> Result<()>;
+
+    /// Called once after the last batch, e.g. to complete a file.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Lets callers pass a sink that they inspect afterwards, e.g. statistics.
+impl<S: QuerySink + ?Sized> QuerySink for &mut S {
+    fn write(&mut self, batch: &PointsBatch) -> Result<()> {
+        (**self).write(batch)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+impl QuerySink for PointStats {
+    fn write(&mut self, batch: &PointsBatch) -> Result<()> {
+        self.add_batch(batch);
+        Ok(())
+    }
+}
+
+/// Writes the points into a file with a 'NodeWriter', e.g. 'PlyNodeWriter'.
+pub struct WriterSink<W> {
+    /// 'None' once finished.
+    writer: Option<W>,
+}
+
+impl<W: NodeWriter<PointsBatch>> WriterSink<W> {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self::from_writer(W::new(path, Encoding::Plain, OpenMode::Truncate))
+    }
+
+    pub fn from_writer(writer: W) -> Self {
+        WriterSink {
+            writer: Some(writer),
+        }
+    }
+}
+
+impl<W: NodeWriter<PointsBatch>> QuerySink for WriterSink<W> {
+    fn write(&mut self, batch: &PointsBatch) -> Result<()> {
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| Error::InvalidInput("Cannot write to a finished sink.".to_string()))?;
+        writer.write(batch).map_err(Error::from)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.finish().map_err(Error::from),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Calls a function with every batch, e.g. to send the points over a network connection.
+pub struct CallbackSink<F>(pub F);
+
+impl<F: FnMut(&PointsBatch) -> Result<()>> QuerySink for CallbackSink<F> {
+    fn write(&mut self, batch: &PointsBatch) -> Result<()> {
+        (self.0)(batch)
+    }
+}
+
+/// Passes every batch to all of its sinks in the order in which they were added. Writing stops at
+/// the first sink that fails.
+#[derive(Default)]
+pub struct MultiSink<'a> {
+    sinks: Vec<Box<dyn QuerySink + 'a>>,
+}
+
+impl<'a> MultiSink<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sink(mut self, sink: impl QuerySink + 'a) -> Self {
+        self.add_sink(sink);
+        self
+    }
+
+    pub fn add_sink(&mut self, sink: impl QuerySink + 'a) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+impl<'a> QuerySink for MultiSink<'a> {
+    fn write(&mut self, batch: &PointsBatch) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.write(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Finishes all sinks, even if some fail, and returns the first error.
+    fn finish(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for sink in &mut self.sinks {
+            let finished = sink.finish();
+            if result.is_ok() {
+                result = finished;
+            }
+        }
+        result
+    }
+}
+
+/// Runs 'query' on 'point_cloud' once and streams the matching points into 'sink', which is
+/// finished afterwards. Returns the number of points.
+pub fn query_into_sink<C: PointCloud>(
+    point_cloud: &C,
+    query: &PointQuery,
+    batch_size: usize,
+    sink: &mut dyn QuerySink,
+) -> Result<usize> {
+    let mut num_points = 0;
+    for node_id in point_cloud.nodes_in_location(&query.location) {
+        point_cloud.stream_points_for_query_in_node(query, node_id, batch_size, |batch| {
+            num_points += batch.position.len();
+            sink.write(&batch)
+        })?;
+    }
+    sink.finish()?;
+    Ok(num_points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AttributeData;
+    use nalgebra::Point3;
+
+    fn batch() -> PointsBatch {
+        PointsBatch {
+            position: vec![Point3::new(0., 0., 0.), Point3::new(1., 2., 3.)],
+            attributes: vec![("intensity".to_string(), AttributeData::F32(vec![1., 3.]))]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_multi_sink() {
+        let mut stats = PointStats::default();
+        let mut num_batches = 0;
+        {
+            let mut sink = MultiSink::new()
+                .with_sink(&mut stats)
+                .with_sink(CallbackSink(|_: &PointsBatch| {
+                    num_batches += 1;
+                    Ok(())
+                }));
+            assert_eq!(sink.len(), 2);
+            sink.write(&batch()).unwrap();
+            sink.write(&batch()).unwrap();
+            sink.finish().unwrap();
+        }
+        assert_eq!(num_batches, 2);
+        assert_eq!(stats.num_points, 4);
+        assert_eq!(stats.channels["intensity"].mean(), 2.);
+    }
+
+    #[test]
+    fn test_multi_sink_stops_at_failing_sink() {
+        let mut stats = PointStats::default();
+        {
+            let mut sink = MultiSink::new()
+                .with_sink(CallbackSink(|_: &PointsBatch| {
+                    Err(Error::InvalidInput("Connection closed.".to_string()))
+                }))
+                .with_sink(&mut stats);
+            assert!(sink.write(&batch()).is_err());
+        }
+        assert_eq!(stats.num_points, 0);
+    }
+}
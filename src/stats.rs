@@ -0,0 +1,257 @@
+//! Summary statistics over a stream of points, to check data for plausibility, e.g. before
+//! spending hours on building an octree.
+
+use crate::geometry::Aabb;
+use crate::{match_attr_data, AttributeData, PointsBatch};
+use num_traits::ToPrimitive;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Statistics of a single scalar channel. Vector attributes have one per component.
+#[derive(Debug, Clone)]
+pub struct ChannelStats {
+    pub min: f64,
+    pub max: f64,
+    count: u64,
+    mean: f64,
+    // Sum of squared differences from the mean, see Welford's online algorithm.
+    m2: f64,
+    histogram: Vec<u64>,
+}
+
+impl Default for ChannelStats {
+    fn default() -> Self {
+        ChannelStats {
+            min: std::f64::INFINITY,
+            max: std::f64::NEG_INFINITY,
+            count: 0,
+            mean: 0.,
+            m2: 0.,
+            histogram: Vec::new(),
+        }
+    }
+}
+
+impl ChannelStats {
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn add_to_histogram(&mut self, value: f64) {
+        let num_bins = self.histogram.len();
+        if num_bins == 0 {
+            return;
+        }
+        let range = self.max - self.min;
+        let bin = if range > 0. {
+            ((value - self.min) / range * num_bins as f64) as usize
+        } else {
+            0
+        };
+        self.histogram[bin.min(num_bins - 1)] += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    pub fn stddev(&self) -> f64 {
+        if self.count < 2 {
+            return 0.;
+        }
+        (self.m2 / self.count as f64).sqrt()
+    }
+
+    /// Number of values in equally sized bins between `min` and `max`.
+    pub fn histogram(&self) -> &[u64] {
+        &self.histogram
+    }
+}
+
+/// Collects statistics in two passes over the same points: `add_batch()` computes bounding box,
+/// min, max, mean and standard deviation. Since the histogram bins depend on min and max, they are
+/// filled in a second pass with `add_batch_to_histograms()` after calling `init_histograms()`.
+#[derive(Debug, Clone, Default)]
+pub struct PointStats {
+    pub num_points: u64,
+    pub bounding_box: Option<Aabb>,
+    pub channels: BTreeMap<String, ChannelStats>,
+}
+
+impl PointStats {
+    pub fn add_batch(&mut self, batch: &PointsBatch) {
+        for p in &batch.position {
+            match &mut self.bounding_box {
+                Some(bbox) => bbox.grow(*p),
+                None => self.bounding_box = Some(Aabb::new(*p, *p)),
+            }
+        }
+        self.num_points += batch.position.len() as u64;
+        for (name, data) in &batch.attributes {
+            for_each_value(name, data, |channel, value| {
+                // Avoids allocating the channel name for every value, unlike the entry API.
+                match self.channels.get_mut(channel) {
+                    Some(stats) => stats.add(value),
+                    None => {
+                        let mut stats = ChannelStats::default();
+                        stats.add(value);
+                        self.channels.insert(channel.to_string(), stats);
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn init_histograms(&mut self, num_bins: usize) {
+        for channel in self.channels.values_mut() {
+            channel.histogram = vec![0; num_bins];
+        }
+    }
+
+    pub fn add_batch_to_histograms(&mut self, batch: &PointsBatch) {
+        for (name, data) in &batch.attributes {
+            for_each_value(name, data, |channel, value| {
+                if let Some(stats) = self.channels.get_mut(channel) {
+                    stats.add_to_histogram(value);
+                }
+            });
+        }
+    }
+}
+
+/// Calls `func` with the channel name and value of every component of every point.
+fn for_each_value(name: &str, data: &AttributeData, mut func: impl FnMut(&str, f64)) {
+    let component_names: Vec<String> = match (name, data.dim()) {
+        ("color", 3) => ["red", "green", "blue"]
+            .iter()
+            .map(|c| format!("{}.{}", name, c))
+            .collect(),
+        (_, 1) => vec![name.to_string()],
+        (_, dim) => (0..dim).map(|i| format!("{}.{}", name, i)).collect(),
+    };
+    macro_rules! rhs {
+        ($dtype:ident, $data:ident, $component_names:ident, $func:ident) => {
+            for value in $data.iter() {
+                for_each_component(value, |i, v| $func(&$component_names[i], v));
+            }
+        };
+    }
+    match_attr_data!(data, rhs, component_names, func)
+}
+
+/// Abstracts over scalars and vectors, so that `match_attr_data!` can treat all attribute types
+/// the same.
+trait Components {
+    fn for_each_component(&self, func: impl FnMut(usize, f64));
+}
+
+fn for_each_component<T: Components>(value: &T, func: impl FnMut(usize, f64)) {
+    value.for_each_component(func)
+}
+
+macro_rules! impl_components_for_scalar {
+    ($($scalar:ty),*) => {
+        $(
+            impl Components for $scalar {
+                fn for_each_component(&self, mut func: impl FnMut(usize, f64)) {
+                    func(0, self.to_f64().unwrap_or(std::f64::NAN))
+                }
+            }
+        )*
+    };
+}
+
+impl_components_for_scalar!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl<T: ToPrimitive + nalgebra::Scalar> Components for nalgebra::Vector3<T> {
+    fn for_each_component(&self, mut func: impl FnMut(usize, f64)) {
+        for (i, c) in self.iter().enumerate() {
+            func(i, c.to_f64().unwrap_or(std::f64::NAN));
+        }
+    }
+}
+
+impl fmt::Display for PointStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Number of points: {}", self.num_points)?;
+        if let Some(bbox) = &self.bounding_box {
+            let (min, max) = (bbox.min(), bbox.max());
+            writeln!(
+                f,
+                "Bounding box:     [{}, {}, {}] - [{}, {}, {}]",
+                min.x, min.y, min.z, max.x, max.y, max.z
+            )?;
+        }
+        for (name, stats) in &self.channels {
+            writeln!(f)?;
+            writeln!(f, "{}", name)?;
+            writeln!(
+                f,
+                "  min: {}, max: {}, mean: {:.3}, stddev: {:.3}",
+                stats.min,
+                stats.max,
+                stats.mean(),
+                stats.stddev()
+            )?;
+            let bin_width = (stats.max - stats.min) / stats.histogram.len() as f64;
+            for (i, count) in stats.histogram.iter().enumerate() {
+                writeln!(
+                    f,
+                    "  [{:>12.3}, {:>12.3}): {}",
+                    stats.min + i as f64 * bin_width,
+                    stats.min + (i + 1) as f64 * bin_width,
+                    count
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Point3, Vector3};
+
+    #[test]
+    fn test_point_stats() {
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "intensity".to_string(),
+            AttributeData::F32(vec![1., 2., 3., 4.]),
+        );
+        attributes.insert(
+            "color".to_string(),
+            AttributeData::U8Vec3(vec![Vector3::new(0, 10, 255); 4]),
+        );
+        let batch = PointsBatch {
+            position: vec![
+                Point3::new(0., 0., 0.),
+                Point3::new(1., -1., 0.),
+                Point3::new(0., 2., 0.),
+                Point3::new(0., 0., 3.),
+            ],
+            attributes,
+        };
+        let mut stats = PointStats::default();
+        stats.add_batch(&batch);
+        stats.init_histograms(3);
+        stats.add_batch_to_histograms(&batch);
+
+        assert_eq!(stats.num_points, 4);
+        let bbox = stats.bounding_box.as_ref().unwrap();
+        assert_eq!(*bbox.min(), Point3::new(0., -1., 0.));
+        assert_eq!(*bbox.max(), Point3::new(1., 2., 3.));
+        let intensity = &stats.channels["intensity"];
+        assert_eq!((intensity.min, intensity.max), (1., 4.));
+        assert!((intensity.mean() - 2.5).abs() < 1e-9);
+        assert!((intensity.stddev() - 1.25f64.sqrt()).abs() < 1e-9);
+        assert_eq!(intensity.histogram(), &[1, 1, 2]);
+        assert_eq!(stats.channels["color.blue"].histogram(), &[4, 0, 0]);
+    }
+}
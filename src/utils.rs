@@ -1,10 +1,16 @@
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+#[cfg(feature = "native")]
 use pbr::ProgressBar;
 use std::error::Error;
+#[cfg(feature = "native")]
 use std::io;
 use std::str::FromStr;
+#[cfg(feature = "native")]
 use std::sync::{Arc, Mutex};
+#[cfg(feature = "native")]
 use std::time::Duration;
 
+#[cfg(feature = "native")]
 const PROGRESS_REFRESH_RATE: Duration = Duration::from_secs(2);
 
 pub fn parse_key_val<T, U>(s: &str) -> Result<(T, U), Box<dyn Error>>
@@ -20,6 +26,33 @@ where
     Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
 }
 
+/// Parses a pose given as 'x,y,z,qx,qy,qz,qw', i.e. the translation followed by the rotation as a
+/// quaternion.
+pub fn parse_pose(s: &str) -> Result<Isometry3<f64>, String> {
+    let v = s
+        .split(',')
+        .map(|n| n.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|e| format!("Could not parse pose '{}': {}", s, e))?;
+    if v.len() != 7 {
+        return Err(format!(
+            "The pose '{}' must be given as 'x,y,z,qx,qy,qz,qw'.",
+            s
+        ));
+    }
+    Ok(Isometry3::from_parts(
+        Translation3::new(v[0], v[1], v[2]),
+        UnitQuaternion::from_quaternion(Quaternion::new(v[6], v[3], v[4], v[5])),
+    ))
+}
+
+/// Sends log messages to stderr. Shows everything from 'info' upwards, unless 'RUST_LOG' is set.
+#[cfg(feature = "native")]
+pub fn init_logging() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+}
+
+#[cfg(feature = "native")]
 pub fn create_progress_bar(total: usize, message: &str) -> ProgressBar<io::Stderr> {
     let mut progress_bar = ProgressBar::on(io::stderr(), total as u64);
     progress_bar.set_max_refresh_rate(Some(PROGRESS_REFRESH_RATE));
@@ -27,6 +60,7 @@ pub fn create_progress_bar(total: usize, message: &str) -> ProgressBar<io::Stder
     progress_bar
 }
 
+#[cfg(feature = "native")]
 pub fn create_syncable_progress_bar(
     total: usize,
     message: &str,
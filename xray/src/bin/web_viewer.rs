@@ -14,8 +14,10 @@
 
 use iron::mime::Mime;
 use iron::prelude::*;
+use point_viewer::config::load_config;
 use router::Router;
-use std::path::PathBuf;
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
 
 const INDEX_HTML: &str = include_str!("../../client/index.html");
 const APP_BUNDLE: &str = include_str!("../../../target/xray_app_bundle.js");
@@ -40,6 +42,24 @@ fn app_bundle_source_map(_: &mut Request) -> IronResult<Response> {
     )))
 }
 
+/// Settings of the viewer that can be given in the config file. Command line arguments take
+/// precedence.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    quadtree_directory: Option<PathBuf>,
+    port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            quadtree_directory: None,
+            port: 5434,
+        }
+    }
+}
+
 fn main() {
     let matches = clap::App::new("web_viewer")
         .args(&[
@@ -48,14 +68,30 @@ fn main() {
                 .long("port")
                 .takes_value(true),
             clap::Arg::new("quadtree_directory")
-                .about("Input directory of the quadtree directory to serve.")
-                .index(1)
-                .required(true),
+                .about(
+                    "Input directory of the quadtree directory to serve. Required unless the \
+                     config file sets 'quadtree_directory'.",
+                )
+                .index(1),
+            clap::Arg::new("config")
+                .about(
+                    "TOML file with the settings of the viewer. Its values can be overridden by \
+                     environment variables like XRAY_WEB_VIEWER_PORT and are overridden by the \
+                     command line arguments.",
+                )
+                .long("config")
+                .takes_value(true),
         ])
         .get_matches();
 
-    let port = matches.value_of_t("port").unwrap_or(5434);
-    let quadtree_directory = PathBuf::from(matches.value_of("quadtree_directory").unwrap());
+    let config: Config = load_config(matches.value_of("config").map(Path::new), "XRAY_WEB_VIEWER")
+        .unwrap_or_else(|err| panic!("Could not load configuration: {}", err));
+    let port = matches.value_of_t("port").unwrap_or(config.port);
+    let quadtree_directory = matches
+        .value_of("quadtree_directory")
+        .map(PathBuf::from)
+        .or(config.quadtree_directory)
+        .expect("No quadtree directory given. Pass it or set it in the config file.");
 
     let mut router = Router::new();
     router.get("/", index, "index");